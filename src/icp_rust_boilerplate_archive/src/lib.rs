@@ -0,0 +1,215 @@
+#[macro_use]
+extern crate serde;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Archive canister for the marketplace. It keeps terminal records the marketplace no longer
+// needs to hold itself, as opaque Candid-encoded bytes, and only the marketplace canister
+// that owns it can write or read them.
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAX_BATCH_SIZE: usize = 500;
+
+// ArchivedRecord Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ArchivedRecord {
+    data: Vec<u8>,
+    archived_at: u64,
+}
+
+// Storable and BoundedStorable implementations for ArchivedRecord
+impl Storable for ArchivedRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchivedRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// ArchivedEvent Struct
+// A raw event-log entry the marketplace rolled into a checkpoint
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ArchivedEvent {
+    data: Vec<u8>,
+    archived_at: u64,
+}
+
+// Storable and BoundedStorable implementations for ArchivedEvent
+impl Storable for ArchivedEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchivedEvent {
+    const MAX_SIZE: u32 = 4608;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Principal of the marketplace canister this archive serves
+    static OWNER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), Vec::new())
+            .expect("Cannot create the owner cell")
+    );
+
+    static RECORDS_STORAGE: RefCell<StableBTreeMap<u64, ArchivedRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static EVENTS_STORAGE: RefCell<StableBTreeMap<u64, ArchivedEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static LISTINGS_STORAGE: RefCell<StableBTreeMap<u64, ArchivedRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+}
+
+fn is_owner() -> bool {
+    OWNER.with(|cell| cell.borrow().get().as_slice() == ic_cdk::caller().as_slice())
+}
+
+#[ic_cdk::init]
+fn init(owner: Principal) {
+    OWNER
+        .with(|cell| cell.borrow_mut().set(owner.as_slice().to_vec()))
+        .expect("Cannot set the owner");
+}
+
+// Function for the marketplace to store records by ID; a record archived again replaces
+// the earlier copy
+#[ic_cdk::update]
+fn append_records(records: Vec<(u64, Vec<u8>)>) -> Result<(), String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can archive records".to_string());
+    }
+    if records.len() > MAX_BATCH_SIZE {
+        return Err(format!("At most {} records per call", MAX_BATCH_SIZE));
+    }
+    if records
+        .iter()
+        .any(|(_, data)| data.len() > ArchivedRecord::MAX_SIZE as usize - 32)
+    {
+        return Err("Record is too large".to_string());
+    }
+
+    let archived_at = ic_cdk::api::time();
+    RECORDS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, data) in records {
+            storage.insert(id, ArchivedRecord { data, archived_at });
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_record(id: u64) -> Result<Option<Vec<u8>>, String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can read the archive".to_string());
+    }
+    Ok(RECORDS_STORAGE.with(|storage| storage.borrow().get(&id).map(|record| record.data)))
+}
+
+#[ic_cdk::query]
+fn get_record_count() -> u64 {
+    RECORDS_STORAGE.with(|storage| storage.borrow().len())
+}
+
+// Function for the marketplace to store raw events by ID once they are checkpointed
+#[ic_cdk::update]
+fn append_events(events: Vec<(u64, Vec<u8>)>) -> Result<(), String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can archive events".to_string());
+    }
+    if events.len() > MAX_BATCH_SIZE {
+        return Err(format!("At most {} events per call", MAX_BATCH_SIZE));
+    }
+    if events
+        .iter()
+        .any(|(_, data)| data.len() > ArchivedEvent::MAX_SIZE as usize - 32)
+    {
+        return Err("Event is too large".to_string());
+    }
+
+    let archived_at = ic_cdk::api::time();
+    EVENTS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, data) in events {
+            storage.insert(id, ArchivedEvent { data, archived_at });
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_event(id: u64) -> Result<Option<Vec<u8>>, String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can read the archive".to_string());
+    }
+    Ok(EVENTS_STORAGE.with(|storage| storage.borrow().get(&id).map(|event| event.data)))
+}
+
+#[ic_cdk::query]
+fn get_event_count() -> u64 {
+    EVENTS_STORAGE.with(|storage| storage.borrow().len())
+}
+
+// Function for the marketplace to store stale listings by ID
+#[ic_cdk::update]
+fn append_listings(listings: Vec<(u64, Vec<u8>)>) -> Result<(), String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can archive listings".to_string());
+    }
+    if listings.len() > MAX_BATCH_SIZE {
+        return Err(format!("At most {} listings per call", MAX_BATCH_SIZE));
+    }
+    if listings
+        .iter()
+        .any(|(_, data)| data.len() > ArchivedRecord::MAX_SIZE as usize - 32)
+    {
+        return Err("Listing is too large".to_string());
+    }
+
+    let archived_at = ic_cdk::api::time();
+    LISTINGS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (id, data) in listings {
+            storage.insert(id, ArchivedRecord { data, archived_at });
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_listing(id: u64) -> Result<Option<Vec<u8>>, String> {
+    if !is_owner() {
+        return Err("Only the marketplace canister can read the archive".to_string());
+    }
+    Ok(LISTINGS_STORAGE.with(|storage| storage.borrow().get(&id).map(|record| record.data)))
+}
+
+// need this to generate candid
+ic_cdk::export_candid!();
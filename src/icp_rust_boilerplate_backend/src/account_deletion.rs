@@ -0,0 +1,265 @@
+use crate::consumers::anonymize_consumer;
+use crate::devices::{caller_account, remove_account_devices};
+use crate::events::log_event;
+use crate::forum::anonymize_forum_posts;
+use crate::identity::forget_identity;
+use crate::labor::anonymize_applications;
+use crate::notifications::clear_notifications;
+use crate::reviews::anonymize_reviews;
+use crate::{is_admin, Farmer, Memory, PrincipalKey, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Time to change one's mind before personal data is erased
+const GRACE_PERIOD_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_HOLD_REASON_LEN: usize = 500;
+const DELETED_TEXT: &str = "[deleted]";
+
+// DeletionStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DeletionStatus {
+    Scheduled,
+    Cancelled,
+    Completed,
+}
+
+// DeletionRequest Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DeletionRequest {
+    account: Principal,
+    requested_at: u64,
+    execute_after: u64,
+    status: DeletionStatus,
+    completed_at: Option<u64>,
+}
+
+// LegalHold Struct
+// Keeps an account's personal data while a legal matter is open
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LegalHold {
+    account: Principal,
+    reason: String,
+    placed_at: u64,
+}
+
+// Storable and BoundedStorable implementations for DeletionRequest
+impl Storable for DeletionRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeletionRequest {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for LegalHold
+impl Storable for LegalHold {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegalHold {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DELETION_REQUESTS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, DeletionRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(168)))
+    ));
+
+    static LEGAL_HOLDS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, LegalHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(169)))
+    ));
+}
+
+fn deletion_request(account: &Principal) -> Option<DeletionRequest> {
+    DELETION_REQUESTS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*account)))
+}
+
+fn save_request(request: DeletionRequest) {
+    DELETION_REQUESTS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(request.account), request)
+    });
+}
+
+fn is_on_hold(account: &Principal) -> bool {
+    LEGAL_HOLDS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*account)))
+}
+
+// Blanks the name and bio of the account's listings, and its contact address on listings
+// it bid on
+fn anonymize_listings(account: &Principal) {
+    let address = account.to_text();
+    let listings: Vec<(u64, Farmer)> = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, farmer)| {
+                farmer.address == address || farmer.consumer_address.as_ref() == Some(&address)
+            })
+            .collect()
+    });
+
+    for (id, mut farmer) in listings {
+        if farmer.address == address {
+            farmer.name = DELETED_TEXT.to_string();
+            farmer.bio = DELETED_TEXT.to_string();
+        }
+        if farmer.consumer_address.as_ref() == Some(&address) {
+            farmer.consumer_address = Some(DELETED_TEXT.to_string());
+        }
+        FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, farmer));
+    }
+}
+
+// Erases the account's personal data. Orders, payments, disputes and the event log keep
+// the principal so that financial records stay complete.
+fn erase_personal_data(account: &Principal) {
+    anonymize_listings(account);
+    anonymize_consumer(account);
+    anonymize_forum_posts(account);
+    anonymize_applications(account);
+    anonymize_reviews(account);
+    clear_notifications(account);
+    forget_identity(account);
+    remove_account_devices(account);
+}
+
+// Carries out deletions whose grace period has passed, skipping accounts under legal hold
+pub(crate) fn process_account_deletions() {
+    let now = ic_cdk::api::time();
+    let due: Vec<DeletionRequest> = DELETION_REQUESTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                request.status == DeletionStatus::Scheduled && now >= request.execute_after
+            })
+            .collect()
+    });
+
+    for mut request in due {
+        if is_on_hold(&request.account) {
+            continue;
+        }
+        erase_personal_data(&request.account);
+        request.status = DeletionStatus::Completed;
+        request.completed_at = Some(now);
+        log_event("account_deleted", request.account.to_text());
+        save_request(request);
+    }
+}
+
+// Function for a user to have their personal data erased after a 30-day grace period.
+// Orders and payments are kept, linked only to the principal.
+#[ic_cdk::update]
+fn delete_my_account() -> Result<DeletionRequest, String> {
+    let account = caller_account();
+    if account == Principal::anonymous() {
+        return Err("Anonymous users have no account".to_string());
+    }
+    match deletion_request(&account).map(|request| request.status) {
+        Some(DeletionStatus::Scheduled) => return Err("Deletion already scheduled".to_string()),
+        Some(DeletionStatus::Completed) => return Err("Account already deleted".to_string()),
+        _ => {}
+    }
+
+    let now = ic_cdk::api::time();
+    let request = DeletionRequest {
+        account,
+        requested_at: now,
+        execute_after: now + GRACE_PERIOD_NANOS,
+        status: DeletionStatus::Scheduled,
+        completed_at: None,
+    };
+    save_request(request.clone());
+    Ok(request)
+}
+
+// Function for a user to call off their deletion during the grace period
+#[ic_cdk::update]
+fn cancel_account_deletion() -> Result<DeletionRequest, String> {
+    let mut request =
+        deletion_request(&caller_account()).ok_or("No deletion was requested".to_string())?;
+    if request.status != DeletionStatus::Scheduled {
+        return Err("Deletion is not scheduled".to_string());
+    }
+
+    request.status = DeletionStatus::Cancelled;
+    save_request(request.clone());
+    Ok(request)
+}
+
+// The deletion request of an account, for the account itself or an administrator
+#[ic_cdk::query]
+fn get_account_deletion(account: Principal) -> Result<DeletionRequest, String> {
+    if account != caller_account() && !is_admin() {
+        return Err("Only the account or an administrator can view this".to_string());
+    }
+    deletion_request(&account).ok_or("No deletion was requested".to_string())
+}
+
+// Function for an administrator to keep an account's data while a legal matter is open.
+// Scheduled deletions wait until the hold is lifted.
+#[ic_cdk::update]
+fn place_legal_hold(account: Principal, reason: String) -> Result<LegalHold, String> {
+    if !is_admin() {
+        return Err("Only an administrator can place legal holds".to_string());
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_HOLD_REASON_LEN {
+        return Err("Invalid hold reason".to_string());
+    }
+
+    let hold = LegalHold {
+        account,
+        reason,
+        placed_at: ic_cdk::api::time(),
+    };
+    LEGAL_HOLDS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account), hold.clone())
+    });
+    log_event("legal_hold_placed", account.to_text());
+    Ok(hold)
+}
+
+#[ic_cdk::update]
+fn lift_legal_hold(account: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can lift legal holds".to_string());
+    }
+
+    LEGAL_HOLDS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(account)))
+        .ok_or("Account is not on hold".to_string())?;
+    log_event("legal_hold_lifted", account.to_text());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_legal_holds() -> Result<Vec<LegalHold>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can list legal holds".to_string());
+    }
+    Ok(LEGAL_HOLDS_STORAGE.with(|storage| storage.borrow().iter().map(|(_, hold)| hold).collect()))
+}
@@ -0,0 +1,271 @@
+use crate::orders::Order;
+use crate::price_history::category_key;
+use crate::seasons::year_month_of;
+use crate::shipping::{listing_region, normalise_region};
+use crate::{Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// CategoryTotals Struct
+// Running totals for a category, updated as listings are created and sell
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct CategoryTotals {
+    listings: u64,
+    // Listings with at least one completed sale
+    listings_sold: u64,
+    orders: u64,
+    units: u64,
+    revenue: u64,
+}
+
+// SalesVolume Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct SalesVolume {
+    year: u32,
+    month: u8,
+    orders: u64,
+    units: u64,
+    revenue: u64,
+}
+
+// CategoryAnalytics Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CategoryAnalytics {
+    category: String,
+    listings: u64,
+    listings_sold: u64,
+    // Share of listings that sold, in basis points
+    sell_through_bps: u64,
+    orders: u64,
+    units_sold: u64,
+    // Revenue over units sold; None before the first sale
+    average_unit_price: Option<u64>,
+    // Days from listing to first sale, over listings that sold
+    median_days_to_sale: Option<u64>,
+}
+
+// AnalyticsKey Struct
+// "category", "category|days" or "region|year|month", zero padded to sort numerically
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AnalyticsKey(String);
+
+// Storable and BoundedStorable implementations for CategoryTotals
+impl Storable for CategoryTotals {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CategoryTotals {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for SalesVolume
+impl Storable for SalesVolume {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SalesVolume {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for AnalyticsKey
+impl Storable for AnalyticsKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AnalyticsKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for AnalyticsKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CATEGORY_TOTALS_STORAGE: RefCell<StableBTreeMap<AnalyticsKey, CategoryTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119)))
+    ));
+
+    // When each listing that has not sold yet was created
+    static UNSOLD_LISTINGS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120)))
+    ));
+
+    // Listings per category by whole days from listing to first sale
+    static DAYS_TO_SALE_HISTOGRAM: RefCell<StableBTreeMap<AnalyticsKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(121)))
+    ));
+
+    static REGION_VOLUME_STORAGE: RefCell<StableBTreeMap<AnalyticsKey, SalesVolume, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(122)))
+    ));
+}
+
+fn category_totals(category: &str) -> CategoryTotals {
+    CATEGORY_TOTALS_STORAGE
+        .with(|storage| storage.borrow().get(&AnalyticsKey(category.to_string())))
+        .unwrap_or_default()
+}
+
+fn save_category_totals(category: &str, totals: CategoryTotals) {
+    CATEGORY_TOTALS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(AnalyticsKey(category.to_string()), totals)
+    });
+}
+
+fn days_key(category: &str, days: u64) -> AnalyticsKey {
+    AnalyticsKey(format!("{}|{:010}", category, days))
+}
+
+fn region_month_key(region: &str, year: u32, month: u8) -> AnalyticsKey {
+    AnalyticsKey(format!("{}|{:04}|{:02}", region, year, month))
+}
+
+// Counts a new listing towards its category's sell-through rate
+pub(crate) fn record_listing(product_id: u64, category: &str) {
+    let category = category_key(category);
+    let mut totals = category_totals(&category);
+    totals.listings += 1;
+    save_category_totals(&category, totals);
+    UNSOLD_LISTINGS.with(|storage| storage.borrow_mut().insert(product_id, ic_cdk::api::time()));
+}
+
+// Folds a completed order on a listing into the category and regional aggregates
+pub(crate) fn record_order_sale(order: &Order) {
+    let product_id = match order.product_id {
+        Some(product_id) => product_id,
+        None => return,
+    };
+    let farmer = match FARMERS_STORAGE.with(|storage| storage.borrow().get(&product_id)) {
+        Some(farmer) => farmer,
+        None => return,
+    };
+    let now = ic_cdk::api::time();
+    let revenue = order.quantity.saturating_mul(order.unit_price);
+
+    let category = category_key(&farmer.category);
+    let mut totals = category_totals(&category);
+    totals.orders += 1;
+    totals.units = totals.units.saturating_add(order.quantity);
+    totals.revenue = totals.revenue.saturating_add(revenue);
+    if let Some(listed_at) =
+        UNSOLD_LISTINGS.with(|storage| storage.borrow_mut().remove(&product_id))
+    {
+        totals.listings_sold += 1;
+        let key = days_key(&category, now.saturating_sub(listed_at) / NANOS_PER_DAY);
+        DAYS_TO_SALE_HISTOGRAM.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let count = storage.get(&key).unwrap_or(0);
+            storage.insert(key, count + 1);
+        });
+    }
+    save_category_totals(&category, totals);
+
+    if let Ok(region) = listing_region(product_id) {
+        let (year, month) = year_month_of(now);
+        let key = region_month_key(&region, year, month);
+        REGION_VOLUME_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let mut volume = storage.get(&key).unwrap_or(SalesVolume {
+                year,
+                month,
+                ..Default::default()
+            });
+            volume.orders += 1;
+            volume.units = volume.units.saturating_add(order.quantity);
+            volume.revenue = volume.revenue.saturating_add(revenue);
+            storage.insert(key, volume);
+        });
+    }
+}
+
+fn median_days_to_sale(category: &str, sold: u64) -> Option<u64> {
+    if sold == 0 {
+        return None;
+    }
+    let prefix = format!("{}|", category);
+    let middle = (sold + 1) / 2;
+    let mut seen = 0;
+
+    DAYS_TO_SALE_HISTOGRAM.with(|storage| {
+        for (key, count) in storage
+            .borrow()
+            .range(AnalyticsKey(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+        {
+            seen += count;
+            if seen >= middle {
+                return key.0[prefix.len()..].parse().ok();
+            }
+        }
+        None
+    })
+}
+
+// Average sale price, sell-through rate and median time-to-sale for a category
+#[ic_cdk::query]
+fn get_category_analytics(category: String) -> CategoryAnalytics {
+    let category = category_key(&category);
+    let totals = category_totals(&category);
+
+    CategoryAnalytics {
+        sell_through_bps: if totals.listings == 0 {
+            0
+        } else {
+            totals.listings_sold * 10_000 / totals.listings
+        },
+        average_unit_price: if totals.units == 0 {
+            None
+        } else {
+            Some(totals.revenue / totals.units)
+        },
+        median_days_to_sale: median_days_to_sale(&category, totals.listings_sold),
+        listings: totals.listings,
+        listings_sold: totals.listings_sold,
+        orders: totals.orders,
+        units_sold: totals.units,
+        category,
+    }
+}
+
+// Completed sales from listings in a region, one entry per month with sales, oldest first
+#[ic_cdk::query]
+fn get_region_sales_volume(region: String) -> Result<Vec<SalesVolume>, String> {
+    let region = normalise_region(&region)?;
+    let prefix = format!("{}|", region);
+
+    Ok(REGION_VOLUME_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(AnalyticsKey(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .map(|(_, volume)| volume)
+            .collect()
+    }))
+}
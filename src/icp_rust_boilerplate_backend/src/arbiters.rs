@@ -0,0 +1,612 @@
+use crate::devices::caller_account;
+use crate::disputes::{get_dispute_record, settle_dispute};
+use crate::events::log_event;
+use crate::governance::is_governor;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount};
+use crate::notifications::notify;
+use crate::orders::get_order_record;
+use crate::{is_admin, next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_APPEAL_LEN: usize = 1_000;
+// Labels the escrow subaccounts that hold arbiter stakes
+const SUBACCOUNT_TAG: &[u8] = b"arbiter";
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const RULING_DEADLINE_NANOS: u64 = 5 * DAY_NANOS;
+// A ruling takes effect once this passes without an appeal
+const APPEAL_WINDOW_NANOS: u64 = 3 * DAY_NANOS;
+// Time between asking to unstake and withdrawing, so late appeals can still be raised
+const UNSTAKE_COOLDOWN_NANOS: u64 = 14 * DAY_NANOS;
+// Share of the stake taken per penalty, in basis points
+const SLASH_BPS: u64 = 1_000;
+// Assignment weight a new arbiter starts with, and what each penalty takes off it
+const FULL_WEIGHT: u64 = 100;
+const WEIGHT_PENALTY: u64 = 25;
+// Missed deadlines before each further miss is penalised
+const FORGIVEN_MISSES: u64 = 1;
+
+// Arbiter Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Arbiter {
+    id: u64,
+    principal: Principal,
+    // Tokens locked in the arbiter's stake subaccount
+    stake: u64,
+    appointed_at: u64,
+    // Set when the arbiter asks to withdraw; no new disputes are assigned after that
+    unstake_requested_at: Option<u64>,
+    rulings: u64,
+    // Share of assignments relative to other arbiters, out of 100; lowered by penalties
+    weight: u64,
+    overturned_rulings: u64,
+    missed_deadlines: u64,
+    slashed: u64,
+    // Slashed tokens not yet moved to the treasury
+    unswept_slash: u64,
+}
+
+// Appeal Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Appeal {
+    appellant: Principal,
+    reason: String,
+    raised_at: u64,
+    // The administrator's decision; None while the appeal is pending
+    favour_farmer: Option<bool>,
+}
+
+// Assignment Struct
+// A dispute handed to an arbiter, with the arbiter's ruling and any appeal against it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Assignment {
+    dispute_id: u64,
+    arbiter: Principal,
+    assigned_at: u64,
+    deadline: u64,
+    favour_farmer: Option<bool>,
+    ruled_at: Option<u64>,
+    appeal: Option<Appeal>,
+    // Set once the dispute is settled, whoever settled it
+    closed: bool,
+}
+
+impl Assignment {
+    fn appeal_pending(&self) -> bool {
+        matches!(&self.appeal, Some(appeal) if appeal.favour_farmer.is_none())
+    }
+}
+
+// Storable and BoundedStorable implementations for Arbiter
+impl Storable for Arbiter {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Arbiter {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Assignment
+impl Storable for Assignment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Assignment {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ARBITER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(155))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ARBITERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Arbiter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(156)))
+    ));
+
+    // Dispute ID to its assignment
+    static ASSIGNMENTS_STORAGE: RefCell<StableBTreeMap<u64, Assignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(157)))
+    ));
+
+    // Stake an arbiter needs before disputes are assigned to them
+    static MIN_ARBITER_STAKE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(158))), 0)
+            .expect("Cannot create the arbiter stake cell")
+    );
+}
+
+fn min_stake() -> u64 {
+    MIN_ARBITER_STAKE.with(|cell| *cell.borrow().get())
+}
+
+fn arbiter_record(principal: &Principal) -> Option<Arbiter> {
+    ARBITERS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*principal)))
+}
+
+fn save_arbiter(arbiter: Arbiter) {
+    ARBITERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(arbiter.principal), arbiter)
+    });
+}
+
+fn assignment_record(dispute_id: u64) -> Result<Assignment, String> {
+    ASSIGNMENTS_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or("Dispute is not assigned to an arbiter".to_string())
+}
+
+fn save_assignment(assignment: Assignment) {
+    ASSIGNMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(assignment.dispute_id, assignment)
+    });
+}
+
+fn assignments_of(arbiter: &Principal) -> Vec<Assignment> {
+    ASSIGNMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| assignment.arbiter == *arbiter)
+            .collect()
+    })
+}
+
+fn is_eligible(arbiter: &Arbiter) -> bool {
+    let min_stake = min_stake();
+    min_stake > 0
+        && arbiter.stake >= min_stake
+        && arbiter.weight > 0
+        && arbiter.unstake_requested_at.is_none()
+}
+
+// Hands a new dispute to the staked arbiter with the least open work for their weight,
+// leaving out the given principals. Without one, the dispute stays with the administrators.
+pub(crate) fn assign_dispute(dispute_id: u64, excluded: &[Principal]) {
+    let arbiters: Vec<Arbiter> = ARBITERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, arbiter)| arbiter)
+            .filter(|arbiter| is_eligible(arbiter) && !excluded.contains(&arbiter.principal))
+            .collect()
+    });
+    let chosen = arbiters.into_iter().min_by_key(|arbiter| {
+        let open = assignments_of(&arbiter.principal)
+            .iter()
+            .filter(|assignment| !assignment.closed)
+            .count() as u64;
+        ((open + 1) * FULL_WEIGHT / arbiter.weight, arbiter.id)
+    });
+    let arbiter = match chosen {
+        Some(arbiter) => arbiter,
+        None => return,
+    };
+
+    let now = ic_cdk::api::time();
+    save_assignment(Assignment {
+        dispute_id,
+        arbiter: arbiter.principal,
+        assigned_at: now,
+        deadline: now + RULING_DEADLINE_NANOS,
+        favour_farmer: None,
+        ruled_at: None,
+        appeal: None,
+        closed: false,
+    });
+    notify(
+        arbiter.principal,
+        format!("Dispute #{} has been assigned to you", dispute_id),
+    );
+}
+
+// Marks a dispute's assignment settled after an administrator resolves it directly
+pub(crate) fn close_assignment(dispute_id: u64) {
+    if let Ok(mut assignment) = assignment_record(dispute_id) {
+        assignment.closed = true;
+        save_assignment(assignment);
+    }
+}
+
+// Takes an unruled dispute back from its arbiter, e.g. when the parties send it to a jury
+pub(crate) fn release_assignment(dispute_id: u64) -> Result<(), String> {
+    if let Ok(assignment) = assignment_record(dispute_id) {
+        if assignment.ruled_at.is_some() {
+            return Err("An arbiter has already ruled; appeal the ruling instead".to_string());
+        }
+        ASSIGNMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&dispute_id));
+    }
+    Ok(())
+}
+
+// Puts rulings into effect once their appeal window has passed without an appeal
+pub(crate) fn finalize_rulings() {
+    let now = ic_cdk::api::time();
+    let due: Vec<Assignment> = ASSIGNMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| !assignment.closed && assignment.appeal.is_none())
+            .filter(|assignment| {
+                matches!(assignment.ruled_at, Some(at) if now >= at + APPEAL_WINDOW_NANOS)
+            })
+            .collect()
+    });
+    for mut assignment in due {
+        if let Some(favour_farmer) = assignment.favour_farmer {
+            // An administrator may have settled the dispute meanwhile; either way it is done
+            let _ = settle_dispute(assignment.dispute_id, favour_farmer);
+            assignment.closed = true;
+            save_assignment(assignment);
+        }
+    }
+}
+
+// Takes a share of the arbiter's stake for the treasury and lowers their assignment weight,
+// recording the penalty in the event log
+fn penalize(principal: Principal, reason: &str) {
+    let mut arbiter = match arbiter_record(&principal) {
+        Some(arbiter) => arbiter,
+        None => return,
+    };
+    let slash = (arbiter.stake as u128 * SLASH_BPS as u128 / 10_000) as u64;
+    arbiter.stake -= slash;
+    arbiter.slashed += slash;
+    arbiter.unswept_slash += slash;
+    arbiter.weight = arbiter.weight.saturating_sub(WEIGHT_PENALTY);
+    let weight = arbiter.weight;
+    save_arbiter(arbiter);
+
+    log_event(
+        "arbiter_slashed",
+        format!(
+            "arbiter {} slashed {} for {}; weight now {}",
+            principal, slash, reason, weight
+        ),
+    );
+    notify(
+        principal,
+        format!("{} of your arbiter stake was slashed for {}", slash, reason),
+    );
+}
+
+// Hands disputes whose ruling deadline has passed to another arbiter, counting the miss
+// against the one who let it lapse
+pub(crate) fn reassign_overdue_disputes() {
+    let now = ic_cdk::api::time();
+    let overdue: Vec<Assignment> = ASSIGNMENTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| {
+                !assignment.closed && assignment.ruled_at.is_none() && now > assignment.deadline
+            })
+            .collect()
+    });
+
+    for assignment in overdue {
+        let mut excluded = vec![assignment.arbiter];
+        if let Ok(dispute) = get_dispute_record(assignment.dispute_id) {
+            if let Ok(order) = get_order_record(dispute.order_id) {
+                excluded.extend([order.farmer, order.consumer, order.payer()]);
+            }
+        }
+        ASSIGNMENTS_STORAGE.with(|storage| storage.borrow_mut().remove(&assignment.dispute_id));
+        assign_dispute(assignment.dispute_id, &excluded);
+
+        if let Some(mut arbiter) = arbiter_record(&assignment.arbiter) {
+            arbiter.missed_deadlines += 1;
+            let repeated = arbiter.missed_deadlines > FORGIVEN_MISSES;
+            save_arbiter(arbiter);
+            if repeated {
+                penalize(
+                    assignment.arbiter,
+                    &format!("missing the deadline on dispute #{}", assignment.dispute_id),
+                );
+            }
+        }
+    }
+}
+
+// Moves slashed stake from arbiters' subaccounts to the canister's main account, which
+// holds the marketplace treasury
+pub(crate) async fn sweep_slashed_stakes() {
+    let pending: Vec<Principal> = ARBITERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, arbiter)| arbiter.unswept_slash > 0)
+            .map(|(_, arbiter)| arbiter.principal)
+            .collect()
+    });
+
+    for principal in pending {
+        // Re-read after earlier awaits, and claim the amount before awaiting so overlapping
+        // sweeps cannot move it twice
+        let mut arbiter = match arbiter_record(&principal) {
+            Some(arbiter) if arbiter.unswept_slash > 0 => arbiter,
+            _ => continue,
+        };
+        let amount = arbiter.unswept_slash;
+        arbiter.unswept_slash = 0;
+        let subaccount = escrow_subaccount(SUBACCOUNT_TAG, arbiter.id);
+        save_arbiter(arbiter);
+
+        if release_escrow(subaccount, ic_cdk::id(), amount)
+            .await
+            .is_err()
+        {
+            if let Some(mut arbiter) = arbiter_record(&principal) {
+                arbiter.unswept_slash += amount;
+                save_arbiter(arbiter);
+            }
+        }
+    }
+}
+
+// Function for governance to appoint an arbiter. They receive disputes once their stake
+// reaches the minimum.
+#[ic_cdk::update]
+fn appoint_arbiter(principal: Principal) -> Result<Arbiter, String> {
+    if !is_governor() {
+        return Err("Only governance can appoint arbiters".to_string());
+    }
+    if arbiter_record(&principal).is_some() {
+        return Err("Already an arbiter".to_string());
+    }
+
+    let arbiter = Arbiter {
+        id: next_id(&ARBITER_ID_COUNTER),
+        principal,
+        stake: 0,
+        appointed_at: ic_cdk::api::time(),
+        unstake_requested_at: None,
+        rulings: 0,
+        weight: FULL_WEIGHT,
+        overturned_rulings: 0,
+        missed_deadlines: 0,
+        slashed: 0,
+        unswept_slash: 0,
+    };
+    save_arbiter(arbiter.clone());
+    Ok(arbiter)
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_min_arbiter_stake(amount: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the arbiter stake".to_string());
+    }
+    if amount == 0 {
+        return Err("Stake must be positive".to_string());
+    }
+
+    MIN_ARBITER_STAKE
+        .with(|cell| cell.borrow_mut().set(amount))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_min_arbiter_stake() -> u64 {
+    min_stake()
+}
+
+// Function for an arbiter to lock more of their pre-approved ledger balance as stake
+#[ic_cdk::update]
+async fn stake_as_arbiter(amount: u64) -> Result<Arbiter, String> {
+    let caller = caller_account();
+    let arbiter = arbiter_record(&caller).ok_or("Not an arbiter".to_string())?;
+    if arbiter.unstake_requested_at.is_some() {
+        return Err("Stake is being withdrawn".to_string());
+    }
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, arbiter.id);
+    transfer_into_subaccount(caller, subaccount, amount).await?;
+    // Re-read: the record may have changed during the transfer
+    let mut arbiter = arbiter_record(&caller).ok_or("Not an arbiter".to_string())?;
+    arbiter.stake = arbiter.stake.saturating_add(amount);
+    save_arbiter(arbiter.clone());
+    Ok(arbiter)
+}
+
+// Function for an arbiter to stop taking disputes and start the withdrawal cool-down
+#[ic_cdk::update]
+fn request_arbiter_unstake() -> Result<Arbiter, String> {
+    let mut arbiter = arbiter_record(&caller_account()).ok_or("Not an arbiter".to_string())?;
+    if arbiter.unstake_requested_at.is_some() {
+        return Err("Unstake already requested".to_string());
+    }
+
+    arbiter.unstake_requested_at = Some(ic_cdk::api::time());
+    save_arbiter(arbiter.clone());
+    Ok(arbiter)
+}
+
+// Function for an arbiter to withdraw their stake once the cool-down has passed and
+// none of their rulings can still be appealed or is under appeal
+#[ic_cdk::update]
+async fn withdraw_arbiter_stake() -> Result<u64, String> {
+    let caller = caller_account();
+    let mut arbiter = arbiter_record(&caller).ok_or("Not an arbiter".to_string())?;
+    let requested_at = arbiter
+        .unstake_requested_at
+        .ok_or("Request to unstake first".to_string())?;
+    if ic_cdk::api::time() < requested_at + UNSTAKE_COOLDOWN_NANOS {
+        return Err("Stake is still cooling down".to_string());
+    }
+    if assignments_of(&caller)
+        .iter()
+        .any(|assignment| !assignment.closed)
+    {
+        return Err("Disputes assigned to you are still open or under appeal".to_string());
+    }
+    if arbiter.stake == 0 {
+        return Err("No stake to withdraw".to_string());
+    }
+
+    // Claim the stake before awaiting so it cannot be withdrawn twice
+    let amount = arbiter.stake;
+    arbiter.stake = 0;
+    save_arbiter(arbiter.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, arbiter.id);
+    match release_escrow(subaccount, caller, amount).await {
+        Ok(block) => Ok(block),
+        Err(e) => {
+            if let Some(mut arbiter) = arbiter_record(&caller) {
+                arbiter.stake = arbiter.stake.saturating_add(amount);
+                save_arbiter(arbiter);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Function for the assigned arbiter to rule on a dispute. The ruling takes effect after
+// the appeal window unless a party appeals it.
+#[ic_cdk::update]
+fn rule_on_dispute(dispute_id: u64, favour_farmer: bool) -> Result<Assignment, String> {
+    let caller = caller_account();
+    let mut assignment = assignment_record(dispute_id)?;
+    if assignment.arbiter != caller {
+        return Err("Dispute is not assigned to you".to_string());
+    }
+    if assignment.closed || assignment.ruled_at.is_some() {
+        return Err("Dispute has already been ruled on".to_string());
+    }
+
+    assignment.favour_farmer = Some(favour_farmer);
+    assignment.ruled_at = Some(ic_cdk::api::time());
+    save_assignment(assignment.clone());
+    if let Some(mut arbiter) = arbiter_record(&caller) {
+        arbiter.rulings += 1;
+        save_arbiter(arbiter);
+    }
+    Ok(assignment)
+}
+
+// Function for a party to the order to appeal an arbiter's ruling within the appeal window
+#[ic_cdk::update]
+fn appeal_ruling(dispute_id: u64, reason: String) -> Result<Assignment, String> {
+    let caller = caller_account();
+    let mut assignment = assignment_record(dispute_id)?;
+    let ruled_at = assignment
+        .ruled_at
+        .ok_or("Dispute has not been ruled on".to_string())?;
+    if assignment.closed || assignment.appeal.is_some() {
+        return Err("Ruling can no longer be appealed".to_string());
+    }
+    if ic_cdk::api::time() >= ruled_at + APPEAL_WINDOW_NANOS {
+        return Err("Appeal window has closed".to_string());
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_APPEAL_LEN {
+        return Err("Invalid appeal reason".to_string());
+    }
+
+    let dispute = get_dispute_record(dispute_id)?;
+    let order = get_order_record(dispute.order_id)?;
+    if caller != order.farmer && caller != order.consumer && caller != order.payer() {
+        return Err("Only parties to the order can appeal".to_string());
+    }
+
+    assignment.appeal = Some(Appeal {
+        appellant: caller,
+        reason,
+        raised_at: ic_cdk::api::time(),
+        favour_farmer: None,
+    });
+    save_assignment(assignment.clone());
+    Ok(assignment)
+}
+
+// Function for an administrator to decide an appeal, settling the dispute. Overturning the
+// ruling penalises the arbiter who made it.
+#[ic_cdk::update]
+fn decide_appeal(dispute_id: u64, favour_farmer: bool) -> Result<Assignment, String> {
+    if !is_admin() {
+        return Err("Only an administrator can decide appeals".to_string());
+    }
+    let mut assignment = assignment_record(dispute_id)?;
+    if !assignment.appeal_pending() {
+        return Err("No pending appeal".to_string());
+    }
+
+    settle_dispute(dispute_id, favour_farmer)?;
+    if let Some(appeal) = assignment.appeal.as_mut() {
+        appeal.favour_farmer = Some(favour_farmer);
+    }
+    assignment.closed = true;
+    save_assignment(assignment.clone());
+
+    if assignment.favour_farmer != Some(favour_farmer) {
+        if let Some(mut arbiter) = arbiter_record(&assignment.arbiter) {
+            arbiter.overturned_rulings += 1;
+            save_arbiter(arbiter);
+        }
+        penalize(
+            assignment.arbiter,
+            &format!("an overturned ruling on dispute #{}", dispute_id),
+        );
+    }
+    Ok(assignment)
+}
+
+#[ic_cdk::query]
+fn get_arbiter(principal: Principal) -> Result<Arbiter, String> {
+    arbiter_record(&principal).ok_or("Arbiter not found".to_string())
+}
+
+#[ic_cdk::query]
+fn list_arbiters() -> Vec<Arbiter> {
+    ARBITERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, arbiter)| arbiter)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_dispute_assignment(dispute_id: u64) -> Result<Assignment, String> {
+    assignment_record(dispute_id)
+}
+
+// Disputes assigned to the caller that are not yet settled
+#[ic_cdk::query]
+fn get_my_assignments() -> Vec<Assignment> {
+    assignments_of(&caller_account())
+        .into_iter()
+        .filter(|assignment| !assignment.closed)
+        .collect()
+}
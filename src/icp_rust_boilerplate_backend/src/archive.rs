@@ -0,0 +1,197 @@
+use crate::devices::caller_account;
+use crate::governance::is_governor;
+use crate::orders::{get_order_record, Order, OrderStatus, ORDERS_STORAGE};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Terminal orders stay in the marketplace this long before moving to the archive
+const ARCHIVE_AFTER_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_ARCHIVE_BATCH: usize = 100;
+
+// ArchivedOrder Struct
+// What the marketplace keeps of an order once the full record is in the archive canister
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ArchivedOrder {
+    pub(crate) order_id: u64,
+    pub(crate) farmer: Principal,
+    pub(crate) consumer: Principal,
+    // Whoever funded the order
+    pub(crate) payer: Principal,
+    pub(crate) status: OrderStatus,
+    pub(crate) total: u64,
+    pub(crate) created_at: u64,
+    pub(crate) archived_at: u64,
+}
+
+// Storable and BoundedStorable implementations for ArchivedOrder
+impl Storable for ArchivedOrder {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchivedOrder {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Principal of the archive canister; empty until an administrator sets it
+    static ARCHIVE_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(137))), Vec::new())
+            .expect("Cannot create the archive cell")
+    );
+
+    static ARCHIVED_ORDERS_STORAGE: RefCell<StableBTreeMap<u64, ArchivedOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(138)))
+    ));
+}
+
+pub(crate) fn archive_canister() -> Result<Principal, String> {
+    let bytes = ARCHIVE_CANISTER.with(|cell| cell.borrow().get().clone());
+    if bytes.is_empty() {
+        return Err("Archive canister is not configured".to_string());
+    }
+    Principal::try_from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+pub(crate) fn archived_orders() -> Vec<ArchivedOrder> {
+    ARCHIVED_ORDERS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, order)| order).collect())
+}
+
+// When an order moved to the archive, if it has
+pub(crate) fn archived_at(order_id: u64) -> Option<u64> {
+    ARCHIVED_ORDERS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .map(|order| order.archived_at)
+}
+
+fn is_archivable(order: &Order, now: u64) -> bool {
+    matches!(
+        order.status,
+        OrderStatus::Completed | OrderStatus::Refunded | OrderStatus::Cancelled
+    ) && order.updated_at.saturating_add(ARCHIVE_AFTER_NANOS) < now
+}
+
+// Moves a batch of old terminal orders to the archive canister and returns how many moved.
+// An order is only removed here if it did not change while the call was in flight.
+pub(crate) async fn archive_orders() -> Result<u64, String> {
+    let archive = archive_canister()?;
+    let now = ic_cdk::api::time();
+    let batch: Vec<Order> = ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| is_archivable(order, now))
+            .take(MAX_ARCHIVE_BATCH)
+            .collect()
+    });
+    if batch.is_empty() {
+        return Ok(0);
+    }
+
+    let records: Vec<(u64, Vec<u8>)> = batch
+        .iter()
+        .map(|order| (order.id, order.to_bytes().into_owned()))
+        .collect();
+    let (result,): (Result<(), String>,) = ic_cdk::call(archive, "append_records", (records,))
+        .await
+        .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    result?;
+
+    let archived_at = ic_cdk::api::time();
+    let mut moved = 0;
+    for order in batch {
+        let unchanged = get_order_record(order.id)
+            .map_or(false, |current| current.updated_at == order.updated_at);
+        if !unchanged {
+            continue;
+        }
+        let summary = ArchivedOrder {
+            order_id: order.id,
+            farmer: order.farmer,
+            consumer: order.consumer,
+            payer: order.payer(),
+            status: order.status,
+            total: order.total,
+            created_at: order.created_at,
+            archived_at,
+        };
+        ORDERS_STORAGE.with(|storage| storage.borrow_mut().remove(&order.id));
+        ARCHIVED_ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, summary));
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+// Timer job; does nothing until an archive canister is configured
+pub(crate) async fn archive_due_orders() {
+    let _ = archive_orders().await;
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_archive_canister(archive: Principal) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the archive".to_string());
+    }
+
+    ARCHIVE_CANISTER
+        .with(|cell| cell.borrow_mut().set(archive.as_slice().to_vec()))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_archive_canister() -> Option<Principal> {
+    archive_canister().ok()
+}
+
+// Function for an administrator to archive a batch now instead of waiting for the timer
+#[ic_cdk::update]
+async fn archive_old_orders() -> Result<u64, String> {
+    if !is_admin() {
+        return Err("Only an administrator can archive orders".to_string());
+    }
+    archive_orders().await
+}
+
+fn can_view(caller: Principal, farmer: Principal, consumer: Principal, payer: Principal) -> bool {
+    caller == farmer || caller == consumer || caller == payer || is_admin()
+}
+
+// Function for a party to an order to read it, wherever it is kept. Orders moved to the
+// archive canister are fetched from there.
+#[ic_cdk::query(composite = true)]
+async fn get_order(order_id: u64) -> Result<Order, String> {
+    let caller = caller_account();
+    if let Ok(order) = get_order_record(order_id) {
+        if !can_view(caller, order.farmer, order.consumer, order.payer()) {
+            return Err("Only the parties to an order can view it".to_string());
+        }
+        return Ok(order);
+    }
+
+    let summary = ARCHIVED_ORDERS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("Order not found".to_string())?;
+    if !can_view(caller, summary.farmer, summary.consumer, summary.payer) {
+        return Err("Only the parties to an order can view it".to_string());
+    }
+
+    let (result,): (Result<Option<Vec<u8>>, String>,) =
+        ic_cdk::call(archive_canister()?, "get_record", (order_id,))
+            .await
+            .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    let bytes = result?.ok_or("Order is missing from the archive".to_string())?;
+    Decode!(&bytes, Order).map_err(|e| format!("Cannot decode the archived order: {}", e))
+}
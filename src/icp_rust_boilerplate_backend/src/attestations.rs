@@ -0,0 +1,267 @@
+use crate::export::{export_page, ExportCollection};
+use crate::governance::is_governor;
+use crate::raffles::hex;
+use crate::receipts::visible_receipt;
+use crate::traceability::provenance_root;
+use crate::{is_admin, is_farmer_owner, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Every attestation is signed under this path of the canister's threshold key
+const DERIVATION_PATH: &[u8] = b"agrilink-attestations";
+
+// AttestationKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AttestationKind {
+    Receipt,
+    Provenance,
+    Export,
+}
+
+// AttestationRecord Struct
+// What the canister signed: the SHA-256 digest of a document and its ECDSA signature
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AttestationRecord {
+    id: u64,
+    kind: AttestationKind,
+    // Order ID for receipts, listing ID for provenance, cursor for export pages
+    subject_id: u64,
+    digest: Vec<u8>,
+    // 64-byte compact secp256k1 signature over `digest`
+    signature: Vec<u8>,
+    key_name: String,
+    requested_by: Principal,
+    signed_at: u64,
+}
+
+// SignedDocument Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SignedDocument {
+    attestation: AttestationRecord,
+    // JSON text whose SHA-256 digest was signed
+    document: String,
+}
+
+// Storable and BoundedStorable implementations for AttestationRecord
+impl Storable for AttestationRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AttestationRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Name of the threshold ECDSA key, e.g. "key_1"; empty until governance sets it
+    static ATTESTATION_KEY_NAME: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(201))), Vec::new())
+            .expect("Cannot create the attestation key cell")
+    );
+
+    // SEC1-compressed public key of the key above, fetched on first use
+    static ATTESTATION_PUBLIC_KEY: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(202))), Vec::new())
+            .expect("Cannot create the attestation public key cell")
+    );
+
+    static ATTESTATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(203))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ATTESTATIONS_STORAGE: RefCell<StableBTreeMap<u64, AttestationRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(204)))
+    ));
+}
+
+fn key_name() -> Result<String, String> {
+    let name = ATTESTATION_KEY_NAME.with(|cell| cell.borrow().get().clone());
+    if name.is_empty() {
+        return Err("Attestation key is not configured".to_string());
+    }
+    String::from_utf8(name).map_err(|e| e.to_string())
+}
+
+fn key_id(name: String) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name,
+    }
+}
+
+fn digest(document: &str) -> Vec<u8> {
+    Sha256::digest(document.as_bytes()).to_vec()
+}
+
+// Wraps the content in the envelope every attested document shares, so a signature cannot be
+// replayed for another kind of document or another canister
+fn envelope(kind: &str, subject_id: u64, content: serde_json::Value) -> Result<String, String> {
+    serde_json::to_string(&serde_json::json!({
+        "kind": kind,
+        "subject_id": subject_id,
+        "canister_id": ic_cdk::id().to_text(),
+        "issued_at": ic_cdk::api::time(),
+        "content": content,
+    }))
+    .map_err(|e| format!("Cannot encode document: {}", e))
+}
+
+fn to_json<T: Serialize>(content: &T) -> Result<serde_json::Value, String> {
+    serde_json::to_value(content).map_err(|e| format!("Cannot encode document: {}", e))
+}
+
+async fn sign_document(
+    kind: AttestationKind,
+    subject_id: u64,
+    document: String,
+) -> Result<SignedDocument, String> {
+    let key_name = key_name()?;
+    let digest = digest(&document);
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: digest.clone(),
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(key_name.clone()),
+    })
+    .await
+    .map_err(|(code, message)| format!("Signing failed: {:?} {}", code, message))?;
+
+    let attestation = AttestationRecord {
+        id: next_id(&ATTESTATION_ID_COUNTER),
+        kind,
+        subject_id,
+        digest,
+        signature: response.signature,
+        key_name,
+        requested_by: ic_cdk::caller(),
+        signed_at: ic_cdk::api::time(),
+    };
+    ATTESTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(attestation.id, attestation.clone())
+    });
+    Ok(SignedDocument {
+        attestation,
+        document,
+    })
+}
+
+// Function for governance to choose the threshold ECDSA key attestations are signed with
+#[ic_cdk::update]
+fn set_attestation_key(name: String) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the attestation key".to_string());
+    }
+    if name.is_empty() {
+        return Err("Key name cannot be empty".to_string());
+    }
+    ATTESTATION_KEY_NAME
+        .with(|cell| cell.borrow_mut().set(name.into_bytes()))
+        .map_err(|e| format!("{:?}", e))?;
+    // The cached public key belongs to the previous key
+    ATTESTATION_PUBLIC_KEY
+        .with(|cell| cell.borrow_mut().set(Vec::new()))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// Returns the public key attestations verify against, fetching it from the management
+// canister the first time
+#[ic_cdk::update]
+async fn get_attestation_public_key() -> Result<Vec<u8>, String> {
+    let cached = ATTESTATION_PUBLIC_KEY.with(|cell| cell.borrow().get().clone());
+    if !cached.is_empty() {
+        return Ok(cached);
+    }
+
+    let key_name = key_name()?;
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![DERIVATION_PATH.to_vec()],
+        key_id: key_id(key_name.clone()),
+    })
+    .await
+    .map_err(|(code, message)| format!("Cannot fetch public key: {:?} {}", code, message))?;
+
+    // Only cache the key if governance did not switch keys while we were waiting
+    if key_name()? == key_name {
+        ATTESTATION_PUBLIC_KEY
+            .with(|cell| cell.borrow_mut().set(response.public_key.clone()))
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(response.public_key)
+}
+
+// Function for a party to an order to get its receipt signed with the canister's key
+#[ic_cdk::update]
+async fn attest_receipt(order_id: u64) -> Result<SignedDocument, String> {
+    let receipt = visible_receipt(order_id)?;
+    let document = envelope("receipt", order_id, to_json(&receipt)?)?;
+    sign_document(AttestationKind::Receipt, order_id, document).await
+}
+
+// Function for a listing's farmer to get the current head of its provenance chain signed
+#[ic_cdk::update]
+async fn attest_provenance(product_id: u64) -> Result<SignedDocument, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&product_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) && !is_admin() {
+        return Err("Only the farmer can attest a listing's provenance".to_string());
+    }
+    let root =
+        provenance_root(product_id).ok_or("No provenance recorded for product".to_string())?;
+
+    let content = serde_json::json!({
+        "product_id": product_id,
+        "product_name": farmer.name,
+        "provenance_root": hex(&root),
+    });
+    let document = envelope("provenance", product_id, content)?;
+    sign_document(AttestationKind::Provenance, product_id, document).await
+}
+
+// Function for an administrator to export a page of records as a signed document
+#[ic_cdk::update]
+async fn attest_export(
+    collection: ExportCollection,
+    cursor: u64,
+    limit: u64,
+) -> Result<SignedDocument, String> {
+    if !is_admin() {
+        return Err("Only an administrator can export data".to_string());
+    }
+    let page = export_page(collection, cursor, limit)?;
+    let document = envelope("export", cursor, to_json(&page)?)?;
+    sign_document(AttestationKind::Export, cursor, document).await
+}
+
+// Checks a document against the attestation the canister recorded for it. The returned
+// record carries the digest and signature, which can also be checked off-chain against
+// `get_attestation_public_key` with any secp256k1 library.
+#[ic_cdk::query]
+fn verify_attestation(attestation_id: u64, document: String) -> Result<AttestationRecord, String> {
+    let attestation = ATTESTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&attestation_id))
+        .ok_or("Attestation not found".to_string())?;
+    if digest(&document) != attestation.digest {
+        return Err("Document does not match the attested digest".to_string());
+    }
+    Ok(attestation)
+}
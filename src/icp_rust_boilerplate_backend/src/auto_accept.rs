@@ -0,0 +1,121 @@
+use crate::bids::Bid;
+use crate::notifications::notify;
+use crate::orders::{create_order, save_order, OrderDraft};
+use crate::taxes::apply_taxes;
+use crate::traceability::record_provenance;
+use crate::{is_farmer_owner, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// AutoAcceptRule Struct
+// A farmer's standing instruction to accept any produce bid of at least `min_amount`
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AutoAcceptRule {
+    listing_id: u64,
+    min_amount: u64,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for AutoAcceptRule
+impl Storable for AutoAcceptRule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AutoAcceptRule {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static AUTO_ACCEPT_STORAGE: RefCell<StableBTreeMap<u64, AutoAcceptRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(180)))
+    ));
+}
+
+// Whether a bid of `amount` on the listing meets its auto-accept threshold
+pub(crate) fn meets_threshold(listing_id: u64, amount: u64) -> bool {
+    AUTO_ACCEPT_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .map_or(false, |rule| amount >= rule.min_amount)
+}
+
+// Opens the order for a bid that was accepted automatically, one unit at the bid amount
+pub(crate) fn order_accepted_bid(bid: &Bid, farmer: Principal) -> Result<(), String> {
+    let mut order = create_order(OrderDraft {
+        product_id: Some(bid.listing_id),
+        contract_id: None,
+        farmer,
+        consumer: bid.bidder,
+        quantity: 1,
+        unit_price: bid.amount,
+        due_at: None,
+    })?;
+    apply_taxes(&mut order)?;
+    save_order(order.clone());
+    record_provenance(bid.listing_id, "Bid Auto-Accepted");
+    notify(
+        bid.bidder,
+        format!(
+            "Your bid #{} was accepted automatically. Fund order #{} to complete the purchase",
+            bid.id, order.id
+        ),
+    );
+    notify(
+        farmer,
+        format!(
+            "Bid #{} on listing #{} met your auto-accept price and became order #{}",
+            bid.id, bid.listing_id, order.id
+        ),
+    );
+    Ok(())
+}
+
+fn owned_listing(listing_id: u64) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can change auto-accept on this listing".to_string());
+    }
+    Ok(())
+}
+
+// Function for a farmer to accept any future bid of at least `min_amount` on their listing
+#[ic_cdk::update]
+fn set_auto_accept(listing_id: u64, min_amount: u64) -> Result<AutoAcceptRule, String> {
+    owned_listing(listing_id)?;
+    if min_amount == 0 {
+        return Err("Auto-accept price must be greater than zero".to_string());
+    }
+
+    let rule = AutoAcceptRule {
+        listing_id,
+        min_amount,
+        created_at: ic_cdk::api::time(),
+    };
+    AUTO_ACCEPT_STORAGE.with(|storage| storage.borrow_mut().insert(listing_id, rule.clone()));
+    Ok(rule)
+}
+
+#[ic_cdk::update]
+fn clear_auto_accept(listing_id: u64) -> Result<(), String> {
+    owned_listing(listing_id)?;
+    AUTO_ACCEPT_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&listing_id))
+        .map(|_| ())
+        .ok_or("Listing has no auto-accept rule".to_string())
+}
+
+#[ic_cdk::query]
+fn get_auto_accept(listing_id: u64) -> Option<AutoAcceptRule> {
+    AUTO_ACCEPT_STORAGE.with(|storage| storage.borrow().get(&listing_id))
+}
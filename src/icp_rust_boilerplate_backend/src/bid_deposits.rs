@@ -0,0 +1,304 @@
+use crate::bids::{get_bid_record, Bid, BidStatus};
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, transfer_into_subaccount, Asset};
+use crate::notifications::notify;
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{is_admin, reopen_product, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Labels the escrow subaccounts that hold bid deposits
+const SUBACCOUNT_TAG: &[u8] = b"biddep";
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// A winning bidder who has not completed the purchase this long after acceptance has
+// abandoned it
+const ABANDON_AFTER_NANOS: u64 = 14 * DAY_NANOS;
+const MAX_SETTLE_BATCH: usize = 20;
+
+// DepositStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DepositStatus {
+    // Locked while the bid is open
+    Held,
+    // Counted towards the listing's escrow after the bid was accepted
+    Applied,
+    // Going back to the bidder
+    Refunded,
+    // Going to the farmer as part of the sale
+    Released,
+    // Going to the farmer because the winning bidder abandoned the purchase
+    Forfeited,
+}
+
+// BidDeposit Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct BidDeposit {
+    bid_id: u64,
+    listing_id: u64,
+    bidder: Principal,
+    farmer: Principal,
+    amount: u64,
+    status: DepositStatus,
+    // Set once the payout is handed to the retry queue
+    paid_out: bool,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Storable and BoundedStorable implementations for BidDeposit
+impl Storable for BidDeposit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BidDeposit {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Deposit new produce bids must lock; 0 turns deposits off
+    static BID_DEPOSIT_AMOUNT: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(178))), 0)
+            .expect("Cannot create the bid deposit cell")
+    );
+
+    static BID_DEPOSITS_STORAGE: RefCell<StableBTreeMap<u64, BidDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(179)))
+    ));
+}
+
+pub(crate) fn bid_deposit_amount() -> u64 {
+    BID_DEPOSIT_AMOUNT.with(|cell| *cell.borrow().get())
+}
+
+fn deposit_record(bid_id: u64) -> Option<BidDeposit> {
+    BID_DEPOSITS_STORAGE.with(|storage| storage.borrow().get(&bid_id))
+}
+
+fn save_deposit(mut deposit: BidDeposit) {
+    deposit.updated_at = ic_cdk::api::time();
+    BID_DEPOSITS_STORAGE.with(|storage| storage.borrow_mut().insert(deposit.bid_id, deposit));
+}
+
+// Locks the deposit for a new produce bid in its own subaccount. The bid must already be
+// recorded; the caller undoes it if this fails.
+pub(crate) async fn lock_deposit(bid: &Bid, farmer: Principal) -> Result<(), String> {
+    let amount = bid_deposit_amount();
+    if amount == 0 {
+        return Ok(());
+    }
+
+    transfer_into_subaccount(
+        bid.bidder,
+        escrow_subaccount(SUBACCOUNT_TAG, bid.id),
+        amount,
+    )
+    .await?;
+    // A bid decided while the deposit was on its way gets the deposit straight back
+    let status = match get_bid_record(bid.id) {
+        Ok(current) if current.status == BidStatus::Open => DepositStatus::Held,
+        _ => DepositStatus::Refunded,
+    };
+    let now = ic_cdk::api::time();
+    save_deposit(BidDeposit {
+        bid_id: bid.id,
+        listing_id: bid.listing_id,
+        bidder: bid.bidder,
+        farmer,
+        amount,
+        status,
+        paid_out: false,
+        created_at: now,
+        updated_at: now,
+    });
+    Ok(())
+}
+
+// Settles a held deposit once its bid is decided: accepted deposits count towards the
+// listing's escrow, the rest go back to the bidder
+pub(crate) fn on_bid_decided(bid: &Bid) {
+    let mut deposit = match deposit_record(bid.id) {
+        Some(deposit) if deposit.status == DepositStatus::Held => deposit,
+        _ => return,
+    };
+    deposit.status = match bid.status {
+        BidStatus::Open => return,
+        BidStatus::Accepted => DepositStatus::Applied,
+        _ => DepositStatus::Refunded,
+    };
+    save_deposit(deposit);
+}
+
+// The accepted bid's deposit counted towards a listing's escrow, if any
+fn applied_deposit(listing_id: u64) -> Option<BidDeposit> {
+    BID_DEPOSITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .find(|deposit| {
+                deposit.listing_id == listing_id && deposit.status == DepositStatus::Applied
+            })
+    })
+}
+
+pub(crate) fn applied_deposit_amount(listing_id: u64) -> u64 {
+    applied_deposit(listing_id).map_or(0, |deposit| deposit.amount)
+}
+
+// Settles the listing's applied deposit when its sale ends: to the farmer once payment is
+// released, back to the bidder if a dispute went their way
+pub(crate) fn close_listing_deposit(listing_id: u64, to_farmer: bool) {
+    if let Some(mut deposit) = applied_deposit(listing_id) {
+        deposit.status = if to_farmer {
+            DepositStatus::Released
+        } else {
+            DepositStatus::Refunded
+        };
+        save_deposit(deposit);
+    }
+}
+
+// Forfeits deposits of winning bidders who left the purchase unfinished, and reopens their
+// listings
+pub(crate) fn forfeit_abandoned_deposits() {
+    let cutoff = ic_cdk::api::time().saturating_sub(ABANDON_AFTER_NANOS);
+    let stale: Vec<BidDeposit> = BID_DEPOSITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .filter(|deposit| {
+                deposit.status == DepositStatus::Applied && deposit.updated_at < cutoff
+            })
+            .collect()
+    });
+
+    for mut deposit in stale {
+        let mut farmer =
+            match FARMERS_STORAGE.with(|storage| storage.borrow().get(&deposit.listing_id)) {
+                Some(farmer) => farmer,
+                None => continue,
+            };
+        if farmer.is_sold || farmer.dispute_status {
+            continue;
+        }
+
+        farmer.escrow_balance = farmer.escrow_balance.saturating_sub(deposit.amount);
+        FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer.id, farmer));
+        reopen_product(deposit.listing_id, "Bid Abandoned");
+
+        deposit.status = DepositStatus::Forfeited;
+        notify(
+            deposit.bidder,
+            format!(
+                "Your deposit on bid #{} was forfeited to the farmer",
+                deposit.bid_id
+            ),
+        );
+        save_deposit(deposit);
+    }
+}
+
+// Hands settled deposits to the retry queue to be paid out of their subaccounts
+pub(crate) fn queue_deposit_payouts() {
+    let pending: Vec<u64> = BID_DEPOSITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, deposit)| {
+                !deposit.paid_out
+                    && matches!(
+                        deposit.status,
+                        DepositStatus::Refunded
+                            | DepositStatus::Released
+                            | DepositStatus::Forfeited
+                    )
+            })
+            .map(|(bid_id, _)| bid_id)
+            .take(MAX_SETTLE_BATCH)
+            .collect()
+    });
+
+    for bid_id in pending {
+        let mut deposit = match deposit_record(bid_id) {
+            Some(deposit) => deposit,
+            None => continue,
+        };
+        let to = if deposit.status == DepositStatus::Refunded {
+            deposit.bidder
+        } else {
+            deposit.farmer
+        };
+        enqueue(
+            OutboundCall::EscrowPayout {
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, bid_id),
+                to,
+                amount: deposit.amount,
+                asset: None,
+            },
+            format!("Bid #{} deposit {:?}", bid_id, deposit.status),
+        );
+        deposit.paid_out = true;
+        save_deposit(deposit);
+    }
+}
+
+// Deposits still sitting in their subaccounts, for escrow reconciliation
+pub(crate) fn expected_deposit_escrow() -> Vec<EscrowExpectation> {
+    BID_DEPOSITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, deposit)| !deposit.paid_out)
+            .map(|(bid_id, deposit)| EscrowExpectation {
+                asset: Asset::Icp,
+                source: format!("Bid #{} deposit", bid_id),
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, bid_id),
+                expected: deposit.amount,
+            })
+            .collect()
+    })
+}
+
+// Function for an administrator to set the deposit new produce bids lock; 0 turns it off
+#[ic_cdk::update]
+fn set_bid_deposit(amount: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set the bid deposit".to_string());
+    }
+
+    BID_DEPOSIT_AMOUNT
+        .with(|cell| cell.borrow_mut().set(amount))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_bid_deposit() -> u64 {
+    bid_deposit_amount()
+}
+
+// Deposits the caller locked as a bidder
+#[ic_cdk::query]
+fn get_my_bid_deposits() -> Vec<BidDeposit> {
+    let caller = caller_account();
+    BID_DEPOSITS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .filter(|deposit| deposit.bidder == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,312 @@
+use crate::bid_deposits::on_bid_decided;
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::stats::record_bid_change;
+use crate::trending::record_bid;
+use crate::{is_admin, next_id, reopen_product, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NOTE_LEN: usize = 200;
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Open bids lapse after this many days unless an administrator sets otherwise
+const DEFAULT_BID_EXPIRY_DAYS: u64 = 7;
+const MAX_BID_EXPIRY_DAYS: u64 = 90;
+
+// ListingKind Enum
+// The kinds of listing that take bids
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ListingKind {
+    Produce,
+    Transport,
+}
+
+// BidStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BidStatus {
+    Open,
+    Accepted,
+    Rejected,
+    Withdrawn,
+    // Lapsed without an answer
+    Expired,
+}
+
+// Bid Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Bid {
+    pub(crate) id: u64,
+    pub(crate) kind: ListingKind,
+    pub(crate) listing_id: u64,
+    pub(crate) bidder: Principal,
+    pub(crate) amount: u64,
+    note: String,
+    pub(crate) status: BidStatus,
+    created_at: u64,
+    decided_at: Option<u64>,
+    // When the bid lapses if still open; none for bids placed before expiry existed
+    expires_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for Bid
+impl Storable for Bid {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Bid {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static BID_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static BIDS_STORAGE: RefCell<StableBTreeMap<u64, Bid, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    static BID_EXPIRY_DAYS: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(174))), DEFAULT_BID_EXPIRY_DAYS)
+            .expect("Cannot create the bid expiry cell")
+    );
+}
+
+pub(crate) fn get_bid_record(bid_id: u64) -> Result<Bid, String> {
+    BIDS_STORAGE
+        .with(|storage| storage.borrow().get(&bid_id))
+        .ok_or("Bid not found".to_string())
+}
+
+fn save_bid(bid: Bid) {
+    let is_open = bid.status == BidStatus::Open;
+    if !is_open {
+        on_bid_decided(&bid);
+    }
+    let previous = BIDS_STORAGE.with(|storage| storage.borrow_mut().insert(bid.id, bid));
+    record_bid_change(
+        previous.map_or(false, |previous| previous.status == BidStatus::Open),
+        is_open,
+    );
+}
+
+pub(crate) fn open_bid_count() -> u64 {
+    BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, bid)| bid.status == BidStatus::Open)
+            .count() as u64
+    })
+}
+
+pub(crate) fn listing_bids(kind: ListingKind, listing_id: u64) -> Vec<Bid> {
+    BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| bid.kind == kind && bid.listing_id == listing_id)
+            .collect()
+    })
+}
+
+// Records a bid on a listing; a bidder holds at most one open bid per listing
+pub(crate) fn place_bid(
+    kind: ListingKind,
+    listing_id: u64,
+    bidder: Principal,
+    amount: u64,
+    note: String,
+) -> Result<Bid, String> {
+    if note.len() > MAX_NOTE_LEN {
+        return Err("Note is too long".to_string());
+    }
+    if listing_bids(kind, listing_id)
+        .iter()
+        .any(|bid| bid.bidder == bidder && bid.status == BidStatus::Open)
+    {
+        return Err("You already have an open bid on this listing".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    let bid = Bid {
+        id: next_id(&BID_ID_COUNTER),
+        kind,
+        listing_id,
+        bidder,
+        amount,
+        note,
+        status: BidStatus::Open,
+        created_at: now,
+        decided_at: None,
+        expires_at: Some(now + bid_expiry_days() * DAY_NANOS),
+    };
+    save_bid(bid.clone());
+    if kind == ListingKind::Produce {
+        record_bid(listing_id);
+    }
+    Ok(bid)
+}
+
+// Accepts an open bid and turns down every other open bid on the same listing
+pub(crate) fn accept_bid_record(mut bid: Bid) -> Result<Bid, String> {
+    if bid.status != BidStatus::Open {
+        return Err("Bid is no longer open".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    bid.status = BidStatus::Accepted;
+    bid.decided_at = Some(now);
+    save_bid(bid.clone());
+    notify(bid.bidder, format!("Your bid #{} was accepted", bid.id));
+
+    reject_open_bids(bid.kind, bid.listing_id);
+    Ok(bid)
+}
+
+// Turns down one open bid, telling the bidder why
+pub(crate) fn reject_bid_record(mut bid: Bid, reason: &str) -> Result<Bid, String> {
+    if bid.status != BidStatus::Open {
+        return Err("Bid is no longer open".to_string());
+    }
+    if reason.len() > MAX_NOTE_LEN {
+        return Err("Reason is too long".to_string());
+    }
+
+    bid.status = BidStatus::Rejected;
+    bid.decided_at = Some(ic_cdk::api::time());
+    save_bid(bid.clone());
+    let message = if reason.trim().is_empty() {
+        format!("Your bid #{} was rejected", bid.id)
+    } else {
+        format!("Your bid #{} was rejected: {}", bid.id, reason)
+    };
+    notify(bid.bidder, message);
+    Ok(bid)
+}
+
+// Turns down every open bid on a listing, e.g. once it is awarded or withdrawn
+pub(crate) fn reject_open_bids(kind: ListingKind, listing_id: u64) {
+    let now = ic_cdk::api::time();
+    for mut other in listing_bids(kind, listing_id) {
+        if other.status != BidStatus::Open {
+            continue;
+        }
+        other.status = BidStatus::Rejected;
+        other.decided_at = Some(now);
+        notify(
+            other.bidder,
+            format!("Your bid #{} was not accepted", other.id),
+        );
+        save_bid(other);
+    }
+}
+
+// Marks an open bid as withdrawn by its bidder
+pub(crate) fn withdraw_bid_record(mut bid: Bid) -> Result<Bid, String> {
+    if bid.status != BidStatus::Open {
+        return Err("Bid is no longer open".to_string());
+    }
+
+    bid.status = BidStatus::Withdrawn;
+    bid.decided_at = Some(ic_cdk::api::time());
+    save_bid(bid.clone());
+    Ok(bid)
+}
+
+fn bid_expiry_days() -> u64 {
+    BID_EXPIRY_DAYS.with(|cell| *cell.borrow().get())
+}
+
+// Lapses open bids past their expiry and reopens the products they held
+pub(crate) fn expire_bids() {
+    let now = ic_cdk::api::time();
+    let due: Vec<Bid> = BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| {
+                bid.status == BidStatus::Open && bid.expires_at.map_or(false, |at| at <= now)
+            })
+            .collect()
+    });
+
+    for mut bid in due {
+        bid.status = BidStatus::Expired;
+        bid.decided_at = Some(now);
+        notify(
+            bid.bidder,
+            format!("Your bid #{} expired without an answer", bid.id),
+        );
+        if bid.kind == ListingKind::Produce {
+            reopen_product(bid.listing_id, "Bid Expired");
+        }
+        save_bid(bid);
+    }
+}
+
+// Function for an administrator to set how many days new bids stay open
+#[ic_cdk::update]
+fn set_bid_expiry_days(days: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set bid expiry".to_string());
+    }
+    if days == 0 || days > MAX_BID_EXPIRY_DAYS {
+        return Err(format!(
+            "Bid expiry must be 1 to {} days",
+            MAX_BID_EXPIRY_DAYS
+        ));
+    }
+
+    BID_EXPIRY_DAYS
+        .with(|cell| cell.borrow_mut().set(days))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_bid_expiry_days() -> u64 {
+    bid_expiry_days()
+}
+
+// Function for a bidder to take back a bid that has not been decided yet
+#[ic_cdk::update]
+fn withdraw_bid(bid_id: u64) -> Result<(), String> {
+    let bid = get_bid_record(bid_id)?;
+
+    if bid.bidder != caller_account() {
+        return Err("Only the bidder can withdraw this bid".to_string());
+    }
+    // A produce bid also reserves the listing through its consumer address
+    if bid.kind == ListingKind::Produce {
+        return Err("Use cancel_bid to withdraw a produce bid".to_string());
+    }
+
+    withdraw_bid_record(bid).map(|_| ())
+}
+
+#[ic_cdk::query]
+fn get_my_bids() -> Vec<Bid> {
+    let caller = caller_account();
+    BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| bid.bidder == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,404 @@
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, transfer_into_subaccount, Asset};
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::verification::is_verified_farmer;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 1_000;
+const MAX_TRANCHES: u8 = 5;
+// Minimum wait between two tranche releases
+const TRANCHE_INTERVAL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// Labels the escrow subaccounts that hold campaign contributions
+const SUBACCOUNT_TAG: &[u8] = b"campaign";
+
+// InputType Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum InputType {
+    Seeds,
+    Fertilizer,
+    Feed,
+    Other(String),
+}
+
+// CampaignStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CampaignStatus {
+    Active,
+    Funded,
+    Failed,
+    Completed,
+}
+
+// Campaign Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Campaign {
+    id: u64,
+    farmer: Principal,
+    title: String,
+    description: String,
+    input_type: InputType,
+    target: u64,
+    // Contributions received into the campaign's escrow
+    raised: u64,
+    // Contributions whose transfer is still in flight, counted against the target
+    pending: Option<u64>,
+    deadline: u64,
+    tranches: u8,
+    tranches_released: u8,
+    released: u64,
+    last_release_at: Option<u64>,
+    status: CampaignStatus,
+    created_at: u64,
+}
+
+// Contribution Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Contribution {
+    id: u64,
+    campaign_id: u64,
+    contributor: Principal,
+    amount: u64,
+    refunded: bool,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Campaign
+impl Storable for Campaign {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Campaign {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Contribution
+impl Storable for Contribution {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Contribution {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CAMPAIGN_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CAMPAIGNS_STORAGE: RefCell<StableBTreeMap<u64, Campaign, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static CONTRIBUTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CONTRIBUTIONS_STORAGE: RefCell<StableBTreeMap<u64, Contribution, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+}
+
+// Campaign Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CampaignPayload {
+    title: String,
+    description: String,
+    input_type: InputType,
+    target: u64,
+    deadline: u64,
+    tranches: u8,
+}
+
+fn get_campaign_record(campaign_id: u64) -> Result<Campaign, String> {
+    CAMPAIGNS_STORAGE
+        .with(|storage| storage.borrow().get(&campaign_id))
+        .ok_or("Campaign not found".to_string())
+}
+
+fn save_campaign(campaign: Campaign) {
+    CAMPAIGNS_STORAGE.with(|storage| storage.borrow_mut().insert(campaign.id, campaign));
+}
+
+// Function for a verified farmer to start raising funds for farm inputs
+#[ic_cdk::update]
+fn create_campaign(payload: CampaignPayload) -> Result<Campaign, String> {
+    let farmer = caller_account();
+    let now = ic_cdk::api::time();
+
+    if !is_verified_farmer(&farmer) {
+        return Err("Only verified farmers can start campaigns".to_string());
+    }
+    if payload.title.is_empty() || payload.title.len() > MAX_TITLE_LEN {
+        return Err("Invalid title".to_string());
+    }
+    if payload.description.len() > MAX_DESCRIPTION_LEN {
+        return Err("Description is too long".to_string());
+    }
+    if payload.target == 0 || payload.deadline <= now {
+        return Err("Campaigns need a target and a future deadline".to_string());
+    }
+    if payload.tranches == 0 || payload.tranches > MAX_TRANCHES {
+        return Err("Invalid number of tranches".to_string());
+    }
+
+    let campaign = Campaign {
+        id: next_id(&CAMPAIGN_ID_COUNTER),
+        farmer,
+        title: payload.title,
+        description: payload.description,
+        input_type: payload.input_type,
+        target: payload.target,
+        raised: 0,
+        pending: None,
+        deadline: payload.deadline,
+        tranches: payload.tranches,
+        tranches_released: 0,
+        released: 0,
+        last_release_at: None,
+        status: CampaignStatus::Active,
+        created_at: now,
+    };
+
+    save_campaign(campaign.clone());
+    Ok(campaign)
+}
+
+fn escrow_payout(campaign_id: u64, to: Principal, amount: u64, purpose: String) {
+    enqueue(
+        OutboundCall::EscrowPayout {
+            subaccount: escrow_subaccount(SUBACCOUNT_TAG, campaign_id),
+            to,
+            amount,
+            asset: None,
+        },
+        purpose,
+    );
+}
+
+// Function for a supporter to contribute to a campaign from their ICRC-2 allowance. The funds
+// are held in an escrow subaccount for the campaign until it settles.
+#[ic_cdk::update]
+async fn contribute_to_campaign(campaign_id: u64, amount: u64) -> Result<Contribution, String> {
+    let mut campaign = get_campaign_record(campaign_id)?;
+    let contributor = caller_account();
+    let now = ic_cdk::api::time();
+
+    if campaign.status != CampaignStatus::Active || campaign.deadline <= now {
+        return Err("Campaign is not accepting contributions".to_string());
+    }
+    if contributor == campaign.farmer {
+        return Err("Farmers cannot fund their own campaigns".to_string());
+    }
+    let pending = campaign.pending.unwrap_or(0);
+    if amount == 0 || campaign.raised + pending + amount > campaign.target {
+        return Err("Invalid contribution amount".to_string());
+    }
+
+    // Hold the amount against the target before awaiting so concurrent pledges cannot overshoot
+    campaign.pending = Some(pending + amount);
+    save_campaign(campaign);
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, campaign_id);
+    let result = transfer_into_subaccount(contributor, subaccount, amount).await;
+
+    let mut campaign = get_campaign_record(campaign_id)?;
+    campaign.pending = Some(campaign.pending.unwrap_or(0).saturating_sub(amount));
+    if let Err(e) = result {
+        save_campaign(campaign);
+        return Err(e);
+    }
+    // The campaign may have failed at its deadline while the transfer was in flight
+    if campaign.status != CampaignStatus::Active {
+        save_campaign(campaign);
+        escrow_payout(
+            campaign_id,
+            contributor,
+            amount,
+            format!("Campaign #{} late contribution refund", campaign_id),
+        );
+        return Err("Campaign closed before the contribution arrived; it is refunded".to_string());
+    }
+
+    let contribution = Contribution {
+        id: next_id(&CONTRIBUTION_ID_COUNTER),
+        campaign_id,
+        contributor,
+        amount,
+        refunded: false,
+        created_at: ic_cdk::api::time(),
+    };
+
+    campaign.raised += amount;
+    if campaign.raised == campaign.target {
+        campaign.status = CampaignStatus::Funded;
+    }
+
+    CONTRIBUTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(contribution.id, contribution.clone())
+    });
+    save_campaign(campaign);
+    Ok(contribution)
+}
+
+// Function for the farmer of a funded campaign to draw the next tranche, paid out of the
+// campaign's escrow less the ledger fee
+#[ic_cdk::update]
+fn release_campaign_tranche(campaign_id: u64) -> Result<u64, String> {
+    let mut campaign = get_campaign_record(campaign_id)?;
+    let now = ic_cdk::api::time();
+
+    if campaign.farmer != caller_account() {
+        return Err("Only the farmer can draw campaign funds".to_string());
+    }
+    if campaign.status != CampaignStatus::Funded {
+        return Err("Campaign is not funded".to_string());
+    }
+    if let Some(last) = campaign.last_release_at {
+        if now < last + TRANCHE_INTERVAL_NANOS {
+            return Err("Next tranche is not yet available".to_string());
+        }
+    }
+
+    // The last tranche also carries any remainder of the integer division
+    campaign.tranches_released += 1;
+    let amount = if campaign.tranches_released == campaign.tranches {
+        campaign.raised - campaign.released
+    } else {
+        campaign.raised / campaign.tranches as u64
+    };
+
+    campaign.released += amount;
+    campaign.last_release_at = Some(now);
+    if campaign.tranches_released == campaign.tranches {
+        campaign.status = CampaignStatus::Completed;
+    }
+
+    escrow_payout(
+        campaign_id,
+        campaign.farmer,
+        amount,
+        format!(
+            "Campaign #{} tranche {} of {}",
+            campaign_id, campaign.tranches_released, campaign.tranches
+        ),
+    );
+    save_campaign(campaign);
+    Ok(amount)
+}
+
+#[ic_cdk::query]
+fn get_campaign(campaign_id: u64) -> Result<Campaign, String> {
+    get_campaign_record(campaign_id)
+}
+
+#[ic_cdk::query]
+fn get_campaign_contributions(campaign_id: u64) -> Vec<Contribution> {
+    CONTRIBUTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, contribution)| contribution)
+            .filter(|contribution| contribution.campaign_id == campaign_id)
+            .collect()
+    })
+}
+
+// Fails campaigns that missed their target by the deadline and refunds their contributions out
+// of the campaign's escrow, less the ledger fee
+pub(crate) fn settle_campaigns() {
+    let now = ic_cdk::api::time();
+    let failed: Vec<Campaign> = CAMPAIGNS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, campaign)| campaign)
+            .filter(|campaign| {
+                campaign.status == CampaignStatus::Active && campaign.deadline <= now
+            })
+            .collect()
+    });
+
+    for mut campaign in failed {
+        let refunds: Vec<Contribution> = CONTRIBUTIONS_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, contribution)| contribution)
+                .filter(|contribution| {
+                    contribution.campaign_id == campaign.id && !contribution.refunded
+                })
+                .collect()
+        });
+
+        for contribution in &refunds {
+            escrow_payout(
+                campaign.id,
+                contribution.contributor,
+                contribution.amount,
+                format!(
+                    "Campaign #{} contribution #{} refund",
+                    campaign.id, contribution.id
+                ),
+            );
+        }
+        CONTRIBUTIONS_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for mut contribution in refunds {
+                contribution.refunded = true;
+                storage.insert(contribution.id, contribution);
+            }
+        });
+
+        campaign.status = CampaignStatus::Failed;
+        save_campaign(campaign);
+    }
+}
+
+// What each campaign's escrow should hold: contributions received less tranches released, for
+// escrow reconciliation. Refunds of failed campaigns are covered by the retry queue.
+pub(crate) fn expected_campaign_escrow() -> Vec<EscrowExpectation> {
+    CAMPAIGNS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, campaign)| {
+                matches!(
+                    campaign.status,
+                    CampaignStatus::Active | CampaignStatus::Funded
+                )
+            })
+            .map(|(campaign_id, campaign)| EscrowExpectation {
+                asset: Asset::Icp,
+                source: format!("Campaign #{} escrow", campaign_id),
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, campaign_id),
+                expected: campaign.raised - campaign.released,
+            })
+            .collect()
+    })
+}
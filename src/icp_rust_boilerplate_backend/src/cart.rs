@@ -0,0 +1,465 @@
+use crate::devices::caller_account;
+use crate::governance::is_governor;
+use crate::idempotency::{begin, finish};
+use crate::inventory::{available_stock, reserve_stock};
+use crate::ledger::{escrow_subaccount, transfer_into_subaccount};
+use crate::order_escrow::record_order_escrow;
+use crate::orders::{create_order, save_order, Order, OrderDraft, OrderStatus};
+use crate::pricing::unit_price_for;
+use crate::receipts::record_payment;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::sagas::{compensate, complete_saga, record_step, start_saga, SagaStep};
+use crate::taxes::{apply_taxes, tax_lines, tax_total};
+use crate::{
+    farmer_principal, is_admin, is_off_market, next_id, IdCell, Memory, PrincipalKey,
+    FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CART_ITEMS: usize = 20;
+const SUBACCOUNT_TAG: &[u8] = b"checkout";
+// Checkout fees cannot exceed 10%
+const MAX_CHECKOUT_FEE_BPS: u64 = 1_000;
+
+// CartItem Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CartItem {
+    farmer_id: u64,
+    quantity: u64,
+}
+
+// Cart Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct Cart {
+    items: Vec<CartItem>,
+}
+
+// CartLine Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CartLine {
+    farmer_id: u64,
+    quantity: u64,
+    unit_price: u64,
+    line_total: u64,
+    tax: u64,
+}
+
+// CartQuote Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CartQuote {
+    lines: Vec<CartLine>,
+    subtotal: u64,
+    fee: u64,
+    tax: u64,
+    total: u64,
+}
+
+// CheckoutStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CheckoutStatus {
+    Pending,
+    Funded,
+    Failed,
+}
+
+// Checkout Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Checkout {
+    id: u64,
+    consumer: Principal,
+    order_ids: Vec<u64>,
+    subtotal: u64,
+    fee: u64,
+    tax: Option<u64>,
+    total: u64,
+    status: CheckoutStatus,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Cart
+impl Storable for Cart {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Cart {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Checkout
+impl Storable for Checkout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Checkout {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CARTS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Cart, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // Marketplace fee on checkouts, in basis points of the subtotal
+    static CHECKOUT_FEE_BPS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))), 0)
+            .expect("Cannot create the checkout fee")
+    );
+
+    static CHECKOUT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CHECKOUTS_STORAGE: RefCell<StableBTreeMap<u64, Checkout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+}
+
+fn get_cart_record(consumer: &Principal) -> Cart {
+    CARTS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(*consumer)))
+        .unwrap_or_default()
+}
+
+fn save_cart(consumer: Principal, cart: Cart) {
+    CARTS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if cart.items.is_empty() {
+            storage.remove(&PrincipalKey(consumer));
+        } else {
+            storage.insert(PrincipalKey(consumer), cart);
+        }
+    });
+}
+
+fn save_checkout(checkout: Checkout) {
+    CHECKOUTS_STORAGE.with(|storage| storage.borrow_mut().insert(checkout.id, checkout));
+}
+
+// Prices every cart line against current listings, tiers and sales, plus the checkout fee
+fn price_cart(consumer: Principal, cart: &Cart) -> Result<CartQuote, String> {
+    if cart.items.is_empty() {
+        return Err("Cart is empty".to_string());
+    }
+
+    let mut lines = Vec::with_capacity(cart.items.len());
+    let mut subtotal: u64 = 0;
+    let mut tax: u64 = 0;
+    for item in &cart.items {
+        let farmer = FARMERS_STORAGE
+            .with(|storage| storage.borrow().get(&item.farmer_id))
+            .ok_or(format!("Listing {} not found", item.farmer_id))?;
+
+        if farmer.is_sold {
+            return Err(format!("Listing {} is already sold", item.farmer_id));
+        }
+        if is_off_market(&farmer) {
+            return Err(format!("Listing {} is no longer available", item.farmer_id));
+        }
+        if farmer_principal(&farmer)? == consumer {
+            return Err("Farmers cannot order their own products".to_string());
+        }
+
+        if available_stock(item.farmer_id).map_or(false, |stock| stock < item.quantity) {
+            return Err(format!("Not enough stock for listing {}", item.farmer_id));
+        }
+
+        let unit_price = unit_price_for(&farmer, item.quantity)?;
+        let line_total = unit_price
+            .checked_mul(item.quantity)
+            .ok_or("Order total overflows".to_string())?;
+        subtotal = subtotal
+            .checked_add(line_total)
+            .ok_or("Cart total overflows".to_string())?;
+        let line_tax = tax_total(&tax_lines(item.farmer_id, line_total));
+        tax = tax
+            .checked_add(line_tax)
+            .ok_or("Cart total overflows".to_string())?;
+        lines.push(CartLine {
+            farmer_id: item.farmer_id,
+            quantity: item.quantity,
+            unit_price,
+            line_total,
+            tax: line_tax,
+        });
+    }
+
+    let fee_bps = CHECKOUT_FEE_BPS.with(|cell| *cell.borrow().get());
+    let fee = (subtotal as u128 * fee_bps as u128 / 10_000) as u64;
+    let total = subtotal
+        .checked_add(fee)
+        .and_then(|total| total.checked_add(tax))
+        .ok_or("Cart total overflows".to_string())?;
+
+    Ok(CartQuote {
+        lines,
+        subtotal,
+        fee,
+        tax,
+        total,
+    })
+}
+
+// Function for a consumer to add a listing to their cart, or change its quantity
+#[ic_cdk::update]
+fn add_to_cart(farmer_id: u64, quantity: u64) -> Result<Cart, String> {
+    let consumer = caller_account();
+    if quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    if FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .map_or(true, |farmer| is_off_market(&farmer))
+    {
+        return Err("Farmer not found".to_string());
+    }
+
+    let mut cart = get_cart_record(&consumer);
+    match cart
+        .items
+        .iter_mut()
+        .find(|item| item.farmer_id == farmer_id)
+    {
+        Some(item) => item.quantity = quantity,
+        None if cart.items.len() >= MAX_CART_ITEMS => return Err("Cart is full".to_string()),
+        None => cart.items.push(CartItem {
+            farmer_id,
+            quantity,
+        }),
+    }
+
+    save_cart(consumer, cart.clone());
+    Ok(cart)
+}
+
+#[ic_cdk::update]
+fn remove_from_cart(farmer_id: u64) -> Result<Cart, String> {
+    let consumer = caller_account();
+    let mut cart = get_cart_record(&consumer);
+
+    let before = cart.items.len();
+    cart.items.retain(|item| item.farmer_id != farmer_id);
+    if cart.items.len() == before {
+        return Err("Listing is not in the cart".to_string());
+    }
+
+    save_cart(consumer, cart.clone());
+    Ok(cart)
+}
+
+#[ic_cdk::update]
+fn clear_cart() {
+    save_cart(caller_account(), Cart::default());
+}
+
+#[ic_cdk::query]
+fn get_cart() -> Cart {
+    get_cart_record(&caller_account())
+}
+
+#[ic_cdk::query]
+fn quote_cart() -> Result<CartQuote, String> {
+    let consumer = caller_account();
+    price_cart(consumer, &get_cart_record(&consumer))
+}
+
+// Function for a consumer to check out the whole cart with a single ICRC-2 transfer; the
+// orders, one per listing, are only created once that transfer succeeds. If any step fails,
+// the reserved stock goes back, created orders are cancelled and the payment is refunded. A retry with the
+// same idempotency key gets the original checkout back.
+#[ic_cdk::update]
+async fn checkout_cart(idempotency_key: Option<String>) -> Result<Checkout, String> {
+    if let Some(previous) = begin("checkout_cart", &idempotency_key)? {
+        return previous;
+    }
+    let result = check_out().await;
+    finish("checkout_cart", &idempotency_key, &result);
+    result
+}
+
+async fn check_out() -> Result<Checkout, String> {
+    let consumer = caller_account();
+    let cart = get_cart_record(&consumer);
+    let quote = price_cart(consumer, &cart)?;
+
+    let mut owners = Vec::with_capacity(quote.lines.len());
+    for line in &quote.lines {
+        let farmer = FARMERS_STORAGE
+            .with(|storage| storage.borrow().get(&line.farmer_id))
+            .ok_or("Farmer not found".to_string())?;
+        owners.push(farmer_principal(&farmer)?);
+    }
+    // Each step is recorded in a saga, so a failure further on undoes the earlier ones
+    let checkout_id = next_id(&CHECKOUT_ID_COUNTER);
+    let saga_id = start_saga("checkout", checkout_id);
+    for line in &quote.lines {
+        if let Err(e) = reserve_stock(line.farmer_id, line.quantity) {
+            compensate(saga_id, &e);
+            return Err(e);
+        }
+        record_step(
+            saga_id,
+            SagaStep::StockReserved {
+                farmer_id: line.farmer_id,
+                quantity: line.quantity,
+            },
+        );
+    }
+
+    let mut checkout = Checkout {
+        id: checkout_id,
+        consumer,
+        order_ids: Vec::new(),
+        subtotal: quote.subtotal,
+        fee: quote.fee,
+        tax: Some(quote.tax),
+        total: quote.total,
+        status: CheckoutStatus::Pending,
+        created_at: ic_cdk::api::time(),
+    };
+    save_checkout(checkout.clone());
+    // Empty the cart before awaiting so a second checkout cannot charge for it again
+    save_cart(consumer, Cart::default());
+
+    // One transfer pays for every order; each order's escrow is then held in this subaccount
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, checkout_id);
+    // Recorded before awaiting; if the callback traps, the sweep refunds whatever arrived
+    record_step(
+        saga_id,
+        SagaStep::PaymentPending {
+            payer: consumer,
+            amount: quote.total,
+            subaccount: subaccount.clone(),
+        },
+    );
+    let block_index =
+        match transfer_into_subaccount(consumer, subaccount.clone(), quote.total).await {
+            Ok(block_index) => block_index,
+            Err(e) => return Err(fail_checkout(saga_id, checkout, cart, e)),
+        };
+    record_step(
+        saga_id,
+        SagaStep::PaymentTaken {
+            payer: consumer,
+            amount: quote.total,
+            block_index,
+            subaccount: Some(subaccount.clone()),
+        },
+    );
+
+    let mut orders: Vec<Order> = Vec::with_capacity(quote.lines.len());
+
+    for (line, farmer) in quote.lines.iter().zip(owners) {
+        // A listing may have been sold or withdrawn while the transfer was in flight
+        let still_listed = FARMERS_STORAGE
+            .with(|storage| storage.borrow().get(&line.farmer_id))
+            .map_or(false, |listing| {
+                !listing.is_sold && !is_off_market(&listing)
+            });
+        if !still_listed {
+            let e = format!("Listing {} is no longer available", line.farmer_id);
+            return Err(fail_checkout(saga_id, checkout, cart, e));
+        }
+
+        let mut order = match create_order(OrderDraft {
+            product_id: Some(line.farmer_id),
+            contract_id: None,
+            farmer,
+            consumer,
+            quantity: line.quantity,
+            unit_price: line.unit_price,
+            due_at: None,
+        }) {
+            Ok(order) => order,
+            Err(e) => return Err(fail_checkout(saga_id, checkout, cart, e)),
+        };
+        record_step(saga_id, SagaStep::OrderCreated { order_id: order.id });
+        // The payment covered the tax quoted for the line, so a rule changed since then fails
+        // the checkout rather than leaving the order short
+        if let Err(e) = apply_taxes(&mut order) {
+            return Err(fail_checkout(saga_id, checkout, cart, e));
+        }
+        if order.total != line.line_total.saturating_add(line.tax) {
+            let e = "Tax rules changed during checkout".to_string();
+            return Err(fail_checkout(saga_id, checkout, cart, e));
+        }
+        order.escrowed = order.total;
+        order.status = OrderStatus::Funded;
+        checkout.order_ids.push(order.id);
+        orders.push(order);
+    }
+
+    for order in orders {
+        record_order_escrow(&order, subaccount.clone(), block_index);
+        record_payment(order.id, checkout.id, block_index);
+        save_order(order);
+    }
+    // The marketplace fee goes to the canister's main account
+    if quote.fee > 0 {
+        enqueue(
+            OutboundCall::EscrowPayout {
+                subaccount,
+                to: ic_cdk::id(),
+                amount: quote.fee,
+                asset: None,
+            },
+            format!("Checkout #{} fee", checkout.id),
+        );
+    }
+    complete_saga(saga_id);
+    checkout.status = CheckoutStatus::Funded;
+    save_checkout(checkout.clone());
+    Ok(checkout)
+}
+
+// Undoes a checkout's saga, marks it failed and gives the consumer their cart back
+fn fail_checkout(saga_id: u64, mut checkout: Checkout, cart: Cart, error: String) -> String {
+    compensate(saga_id, &error);
+    checkout.status = CheckoutStatus::Failed;
+    let consumer = checkout.consumer;
+    save_checkout(checkout);
+    save_cart(consumer, cart);
+    error
+}
+
+#[ic_cdk::query]
+fn get_checkout(checkout_id: u64) -> Result<Checkout, String> {
+    CHECKOUTS_STORAGE
+        .with(|storage| storage.borrow().get(&checkout_id))
+        .filter(|checkout| checkout.consumer == caller_account() || is_admin())
+        .ok_or("Checkout not found".to_string())
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_checkout_fee(fee_bps: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the checkout fee".to_string());
+    }
+    if fee_bps > MAX_CHECKOUT_FEE_BPS {
+        return Err("Checkout fee is too high".to_string());
+    }
+
+    CHECKOUT_FEE_BPS
+        .with(|cell| cell.borrow_mut().set(fee_bps))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
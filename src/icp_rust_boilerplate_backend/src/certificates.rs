@@ -0,0 +1,398 @@
+use crate::governance::is_governor;
+use crate::ledger::Account;
+use crate::notifications::notify;
+use crate::orders::Order;
+use crate::traceability::provenance_root;
+use crate::verification::is_verified_farmer;
+use crate::{next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const COLLECTION_NAME: &str = "Agrilink Certificates of Origin";
+const COLLECTION_SYMBOL: &str = "AGRO";
+const COLLECTION_DESCRIPTION: &str =
+    "Certificates for traceable produce sales, each linked to the listing's provenance chain";
+const DEFAULT_TAKE: usize = 100;
+const MAX_TAKE: usize = 500;
+const MAX_TRANSFER_BATCH: usize = 20;
+
+// Value Enum (ICRC-7 metadata)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum Value {
+    Nat(Nat),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+// Certificate Struct
+// A certificate of origin for one sale, minted to the buyer
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct Certificate {
+    token_id: u64,
+    order_id: u64,
+    listing_id: u64,
+    product_name: String,
+    farmer: Principal,
+    quantity: u64,
+    // Head of the listing's provenance chain when the sale settled
+    provenance_root: Vec<u8>,
+    owner: Account,
+    minted_at: u64,
+}
+
+// CertificateTransferArg Struct (ICRC-7)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CertificateTransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// CertificateTransferError Enum (ICRC-7)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum CertificateTransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+// SupportedStandard Struct (ICRC-10)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+// Storable and BoundedStorable implementations for Certificate
+impl Storable for Certificate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Certificate {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CERTIFICATE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(197))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CERTIFICATES_STORAGE: RefCell<StableBTreeMap<u64, Certificate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(198)))
+    ));
+
+    static CERTIFICATE_TX_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(199))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // Sales of at least this total by a verified farmer earn a certificate; 0 turns them off
+    static CERTIFICATE_MIN_TOTAL: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(200))), 0)
+            .expect("Cannot create the certificate threshold")
+    );
+}
+
+fn certificate_min_total() -> u64 {
+    CERTIFICATE_MIN_TOTAL.with(|cell| *cell.borrow().get())
+}
+
+// Accounts without a subaccount are the same as those with the all-zero one
+fn same_account(a: &Account, b: &Account) -> bool {
+    let default = vec![0u8; 32];
+    a.owner == b.owner
+        && a.subaccount.as_ref().unwrap_or(&default) == b.subaccount.as_ref().unwrap_or(&default)
+}
+
+fn token_id(id: &Nat) -> Option<u64> {
+    u64::try_from(id.0.clone()).ok()
+}
+
+fn certificate(id: &Nat) -> Option<Certificate> {
+    token_id(id).and_then(|id| CERTIFICATES_STORAGE.with(|storage| storage.borrow().get(&id)))
+}
+
+fn take_limit(take: Option<Nat>) -> usize {
+    take.and_then(|take| usize::try_from(take.0).ok())
+        .unwrap_or(DEFAULT_TAKE)
+        .min(MAX_TAKE)
+}
+
+// Mints a certificate to the consumer of a settled sale of a verified farmer's traceable
+// listing, if the sale is large enough
+pub(crate) fn mint_certificate(order: &Order) {
+    let min_total = certificate_min_total();
+    if min_total == 0 || order.total < min_total || !is_verified_farmer(&order.farmer) {
+        return;
+    }
+    let listing_id = match order.product_id {
+        Some(listing_id) => listing_id,
+        None => return,
+    };
+    let provenance_root = match provenance_root(listing_id) {
+        Some(root) => root,
+        None => return,
+    };
+    let product_name = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .map(|farmer| farmer.name)
+        .unwrap_or_default();
+
+    let certificate = Certificate {
+        token_id: next_id(&CERTIFICATE_ID_COUNTER),
+        order_id: order.id,
+        listing_id,
+        product_name,
+        farmer: order.farmer,
+        quantity: order.quantity,
+        provenance_root,
+        owner: order.consumer.into(),
+        minted_at: ic_cdk::api::time(),
+    };
+    notify(
+        order.consumer,
+        format!(
+            "You received certificate of origin #{} for order #{}",
+            certificate.token_id, order.id
+        ),
+    );
+    CERTIFICATES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(certificate.token_id, certificate)
+    });
+}
+
+fn metadata(certificate: &Certificate) -> Vec<(String, Value)> {
+    vec![
+        (
+            "icrc7:name".to_string(),
+            Value::Text(format!("Certificate #{}", certificate.token_id)),
+        ),
+        (
+            "icrc7:description".to_string(),
+            Value::Text(format!(
+                "{} units of {} from order #{}",
+                certificate.quantity, certificate.product_name, certificate.order_id
+            )),
+        ),
+        (
+            "agrilink:order_id".to_string(),
+            Value::Nat(Nat::from(certificate.order_id)),
+        ),
+        (
+            "agrilink:listing_id".to_string(),
+            Value::Nat(Nat::from(certificate.listing_id)),
+        ),
+        (
+            "agrilink:farmer".to_string(),
+            Value::Text(certificate.farmer.to_text()),
+        ),
+        (
+            "agrilink:provenance_root".to_string(),
+            Value::Blob(certificate.provenance_root.clone()),
+        ),
+        (
+            "agrilink:minted_at".to_string(),
+            Value::Nat(Nat::from(certificate.minted_at)),
+        ),
+    ]
+}
+
+// Function for governance to set the sale total that earns a certificate; 0 stops minting
+#[ic_cdk::update]
+fn set_certificate_threshold(min_total: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the certificate threshold".to_string());
+    }
+    CERTIFICATE_MIN_TOTAL
+        .with(|cell| cell.borrow_mut().set(min_total))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_certificate_threshold() -> u64 {
+    certificate_min_total()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    COLLECTION_NAME.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    COLLECTION_SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some(COLLECTION_DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(CERTIFICATES_STORAGE.with(|storage| storage.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        (
+            "icrc7:name".to_string(),
+            Value::Text(COLLECTION_NAME.to_string()),
+        ),
+        (
+            "icrc7:symbol".to_string(),
+            Value::Text(COLLECTION_SYMBOL.to_string()),
+        ),
+        (
+            "icrc7:description".to_string(),
+            Value::Text(COLLECTION_DESCRIPTION.to_string()),
+        ),
+        (
+            "icrc7:total_supply".to_string(),
+            Value::Nat(icrc7_total_supply()),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .map(|id| certificate(id).map(|certificate| metadata(&certificate)))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .map(|id| certificate(id).map(|certificate| certificate.owner))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    CERTIFICATES_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        accounts
+            .iter()
+            .map(|account| {
+                Nat::from(
+                    storage
+                        .iter()
+                        .filter(|(_, certificate)| same_account(&certificate.owner, account))
+                        .count() as u64,
+                )
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(token_id).map_or(0, |prev| prev + 1);
+    CERTIFICATES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .take(take_limit(take))
+            .map(|(id, _)| Nat::from(id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(token_id).map_or(0, |prev| prev + 1);
+    CERTIFICATES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .filter(|(_, certificate)| same_account(&certificate.owner, &account))
+            .take(take_limit(take))
+            .map(|(id, _)| Nat::from(id))
+            .collect()
+    })
+}
+
+// Function for a holder to pass certificates on, e.g. when reselling the produce
+#[ic_cdk::update]
+fn icrc7_transfer(
+    args: Vec<CertificateTransferArg>,
+) -> Vec<Option<Result<Nat, CertificateTransferError>>> {
+    if args.len() > MAX_TRANSFER_BATCH {
+        return vec![Some(Err(CertificateTransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("At most {} transfers per call", MAX_TRANSFER_BATCH),
+        }))];
+    }
+
+    let caller = ic_cdk::caller();
+    args.into_iter()
+        .map(|arg| {
+            let mut certificate = match certificate(&arg.token_id) {
+                Some(certificate) => certificate,
+                None => return Some(Err(CertificateTransferError::NonExistingTokenId)),
+            };
+            let from = Account {
+                owner: caller,
+                subaccount: arg.from_subaccount,
+            };
+            if !same_account(&certificate.owner, &from) {
+                return Some(Err(CertificateTransferError::Unauthorized));
+            }
+            if same_account(&arg.to, &from) || arg.to.owner == Principal::anonymous() {
+                return Some(Err(CertificateTransferError::InvalidRecipient));
+            }
+
+            certificate.owner = arg.to;
+            CERTIFICATES_STORAGE.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(certificate.token_id, certificate)
+            });
+            Some(Ok(Nat::from(next_id(&CERTIFICATE_TX_COUNTER))))
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
@@ -0,0 +1,98 @@
+use crate::credit::has_listed;
+use crate::devices::caller_account;
+use crate::extension::is_extension_advisor;
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const DELETED_NAME: &str = "Deleted user";
+
+// Consumer Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Consumer {
+    principal: Principal,
+    display_name: String,
+    registered_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Consumer
+impl Storable for Consumer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Consumer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CONSUMERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Consumer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+}
+
+pub(crate) fn is_registered_consumer(principal: &Principal) -> bool {
+    CONSUMERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Registered consumers, farmers who have listed and extension advisors
+pub(crate) fn is_registered_user(principal: &Principal) -> bool {
+    is_registered_consumer(principal) || has_listed(principal) || is_extension_advisor(principal)
+}
+
+// Replaces the consumer's display name when their account is deleted
+pub(crate) fn anonymize_consumer(principal: &Principal) {
+    CONSUMERS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut consumer) = storage.get(&PrincipalKey(*principal)) {
+            consumer.display_name = DELETED_NAME.to_string();
+            storage.insert(PrincipalKey(*principal), consumer);
+        }
+    });
+}
+
+// Function for a user to register or rename their consumer profile
+#[ic_cdk::update]
+fn register_consumer(display_name: String) -> Result<Consumer, String> {
+    let principal = caller_account();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous users cannot register".to_string());
+    }
+    if display_name.trim().is_empty() || display_name.len() > MAX_NAME_LEN {
+        return Err("Invalid display name".to_string());
+    }
+
+    let registered_at = CONSUMERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .map_or(ic_cdk::api::time(), |existing| existing.registered_at);
+    let consumer = Consumer {
+        principal,
+        display_name,
+        registered_at,
+    };
+
+    CONSUMERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), consumer.clone())
+    });
+    Ok(consumer)
+}
+
+#[ic_cdk::query]
+fn get_consumer(principal: Principal) -> Result<Consumer, String> {
+    CONSUMERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Consumer not found".to_string())
+}
@@ -0,0 +1,311 @@
+use crate::devices::caller_account;
+use crate::disputes::{check_disputable, open_dispute};
+use crate::orders::{
+    create_order, get_order_record, Order, OrderDraft, OrderStatus, ORDERS_STORAGE,
+};
+use crate::streams::open_stream;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_MILESTONES: usize = 24;
+const MAX_CROP_LEN: usize = 100;
+
+// ContractStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ContractStatus {
+    Proposed,
+    Active,
+    Completed,
+    Breached,
+    Cancelled,
+}
+
+// Milestone Struct
+// One scheduled delivery; its order is generated once the contract is active
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Milestone {
+    due_at: u64,
+    quantity: u64,
+    order_id: Option<u64>,
+}
+
+// FarmingContract Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FarmingContract {
+    id: u64,
+    farmer: Principal,
+    buyer: Principal,
+    crop: String,
+    quantity: u64,
+    unit_price: u64,
+    milestones: Vec<Milestone>,
+    farmer_accepted: bool,
+    buyer_accepted: bool,
+    status: ContractStatus,
+    dispute_id: Option<u64>,
+    created_at: u64,
+    activated_at: Option<u64>,
+    // Whether each milestone's payment is released pro rata up to its due date
+    streamed: Option<bool>,
+}
+
+// Storable and BoundedStorable implementations for FarmingContract
+impl Storable for FarmingContract {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FarmingContract {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CONTRACT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CONTRACTS_STORAGE: RefCell<StableBTreeMap<u64, FarmingContract, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+}
+
+// Milestone Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct MilestonePayload {
+    due_at: u64,
+    quantity: u64,
+}
+
+// Contract Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ContractPayload {
+    farmer: Principal,
+    buyer: Principal,
+    crop: String,
+    quantity: u64,
+    unit_price: u64,
+    schedule: Vec<MilestonePayload>,
+    streamed: Option<bool>,
+}
+
+fn get_contract_record(contract_id: u64) -> Result<FarmingContract, String> {
+    CONTRACTS_STORAGE
+        .with(|storage| storage.borrow().get(&contract_id))
+        .ok_or("Contract not found".to_string())
+}
+
+fn save_contract(contract: FarmingContract) {
+    CONTRACTS_STORAGE.with(|storage| storage.borrow_mut().insert(contract.id, contract));
+}
+
+// Marks an active contract completed once every milestone order is completed
+pub(crate) fn sync_contract_status(contract_id: u64) {
+    let mut contract = match get_contract_record(contract_id) {
+        Ok(contract) => contract,
+        Err(_) => return,
+    };
+    if contract.status != ContractStatus::Active {
+        return;
+    }
+
+    let all_completed = contract.milestones.iter().all(|milestone| {
+        milestone.order_id.map_or(false, |order_id| {
+            get_order_record(order_id).map_or(false, |order| order.status == OrderStatus::Completed)
+        })
+    });
+
+    if all_completed {
+        contract.status = ContractStatus::Completed;
+        save_contract(contract);
+    }
+}
+
+// Function for a buyer or farmer to propose a contract; the proposer accepts it implicitly
+#[ic_cdk::update]
+fn propose_contract(payload: ContractPayload) -> Result<FarmingContract, String> {
+    let caller = caller_account();
+    if caller != payload.farmer && caller != payload.buyer {
+        return Err("Only a party to the contract can propose it".to_string());
+    }
+    if payload.farmer == payload.buyer {
+        return Err("Farmer and buyer must be different".to_string());
+    }
+    if payload.crop.is_empty() || payload.crop.len() > MAX_CROP_LEN {
+        return Err("Invalid crop".to_string());
+    }
+    if payload.schedule.is_empty() || payload.schedule.len() > MAX_MILESTONES {
+        return Err("Invalid delivery schedule".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    if payload
+        .schedule
+        .iter()
+        .any(|m| m.quantity == 0 || m.due_at <= now)
+    {
+        return Err("Milestones need a quantity and a future due date".to_string());
+    }
+    let scheduled: u64 = payload.schedule.iter().map(|m| m.quantity).sum();
+    if scheduled != payload.quantity {
+        return Err("Schedule does not add up to the contract quantity".to_string());
+    }
+    if payload.quantity.checked_mul(payload.unit_price).is_none() {
+        return Err("Contract value overflows".to_string());
+    }
+
+    let contract = FarmingContract {
+        id: next_id(&CONTRACT_ID_COUNTER),
+        farmer: payload.farmer,
+        buyer: payload.buyer,
+        crop: payload.crop,
+        quantity: payload.quantity,
+        unit_price: payload.unit_price,
+        milestones: payload
+            .schedule
+            .into_iter()
+            .map(|m| Milestone {
+                due_at: m.due_at,
+                quantity: m.quantity,
+                order_id: None,
+            })
+            .collect(),
+        farmer_accepted: caller == payload.farmer,
+        buyer_accepted: caller == payload.buyer,
+        status: ContractStatus::Proposed,
+        dispute_id: None,
+        created_at: now,
+        activated_at: None,
+        streamed: payload.streamed.filter(|streamed| *streamed),
+    };
+
+    save_contract(contract.clone());
+    Ok(contract)
+}
+
+// Function for the counterparty to accept a proposal, generating the milestone orders
+#[ic_cdk::update]
+fn accept_contract(contract_id: u64) -> Result<FarmingContract, String> {
+    let mut contract = get_contract_record(contract_id)?;
+    let caller = caller_account();
+
+    if contract.status != ContractStatus::Proposed {
+        return Err("Contract is not awaiting acceptance".to_string());
+    }
+    if caller == contract.farmer {
+        contract.farmer_accepted = true;
+    } else if caller == contract.buyer {
+        contract.buyer_accepted = true;
+    } else {
+        return Err("Only a party to the contract can accept it".to_string());
+    }
+
+    if contract.farmer_accepted && contract.buyer_accepted {
+        let now = ic_cdk::api::time();
+        let due_dates: Vec<u64> = contract.milestones.iter().map(|m| m.due_at).collect();
+        for milestone in contract.milestones.iter_mut() {
+            let order = create_order(OrderDraft {
+                product_id: None,
+                contract_id: Some(contract.id),
+                farmer: contract.farmer,
+                consumer: contract.buyer,
+                quantity: milestone.quantity,
+                unit_price: contract.unit_price,
+                due_at: Some(milestone.due_at),
+            })?;
+            milestone.order_id = Some(order.id);
+            if contract.streamed == Some(true) {
+                // Each milestone streams from the previous due date, or from activation
+                let starts_at = due_dates
+                    .iter()
+                    .copied()
+                    .filter(|due_at| *due_at < milestone.due_at)
+                    .fold(now, u64::max);
+                open_stream(&order, starts_at, milestone.due_at);
+            }
+        }
+        contract.status = ContractStatus::Active;
+        contract.activated_at = Some(now);
+    }
+
+    save_contract(contract.clone());
+    Ok(contract)
+}
+
+// Function for either party to withdraw a proposal before it becomes active
+#[ic_cdk::update]
+fn cancel_contract(contract_id: u64) -> Result<(), String> {
+    let mut contract = get_contract_record(contract_id)?;
+    let caller = caller_account();
+
+    if caller != contract.farmer && caller != contract.buyer {
+        return Err("Only a party to the contract can cancel it".to_string());
+    }
+    if contract.status != ContractStatus::Proposed {
+        return Err("Only proposed contracts can be cancelled".to_string());
+    }
+
+    contract.status = ContractStatus::Cancelled;
+    save_contract(contract);
+    Ok(())
+}
+
+// Function for either party to report a breach, opening a dispute on the next funded milestone
+#[ic_cdk::update]
+fn report_contract_breach(contract_id: u64, reason: String) -> Result<u64, String> {
+    let mut contract = get_contract_record(contract_id)?;
+    let caller = caller_account();
+
+    if caller != contract.farmer && caller != contract.buyer {
+        return Err("Only a party to the contract can report a breach".to_string());
+    }
+    if contract.status != ContractStatus::Active {
+        return Err("Contract is not active".to_string());
+    }
+
+    let order_id = contract
+        .milestones
+        .iter()
+        .filter_map(|milestone| milestone.order_id)
+        .find(|order_id| {
+            get_order_record(*order_id).map_or(false, |order| check_disputable(&order).is_ok())
+        })
+        .ok_or("No open milestone to dispute".to_string())?;
+
+    let dispute_id = open_dispute(order_id, caller, reason)?;
+    contract.status = ContractStatus::Breached;
+    contract.dispute_id = Some(dispute_id);
+    save_contract(contract);
+
+    Ok(dispute_id)
+}
+
+#[ic_cdk::query]
+fn get_contract(contract_id: u64) -> Result<FarmingContract, String> {
+    get_contract_record(contract_id)
+}
+
+#[ic_cdk::query]
+fn get_contract_orders(contract_id: u64) -> Result<Vec<Order>, String> {
+    let contract = get_contract_record(contract_id)?;
+
+    Ok(ORDERS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        contract
+            .milestones
+            .iter()
+            .filter_map(|milestone| milestone.order_id)
+            .filter_map(|order_id| storage.get(&order_id))
+            .collect()
+    }))
+}
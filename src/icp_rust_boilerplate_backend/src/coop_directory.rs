@@ -0,0 +1,195 @@
+use crate::cooperatives::{get_cooperative_record, Cooperative};
+use crate::delivery_radius::delivers_to;
+use crate::shipping::normalise_region;
+use crate::wasm_store::{create_child_canister, install_module};
+use crate::{
+    is_admin, is_off_market, Farmer, Memory, PrincipalKey, FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const COOP_CANISTER_CYCLES: u128 = 2_000_000_000_000;
+const MAX_SEARCH_RESULTS: u64 = 50;
+
+// CoopInstance Struct
+// A marketplace canister deployed for one cooperative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CoopInstance {
+    cooperative: Principal,
+    canister: Principal,
+    // Set once the module is installed and the cooperative registered on it
+    ready: bool,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for CoopInstance
+impl Storable for CoopInstance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CoopInstance {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// DirectoryListing Struct
+// A search hit and the canister that holds the listing
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DirectoryListing {
+    canister: Principal,
+    listing: Farmer,
+}
+
+thread_local! {
+    static COOP_INSTANCES_STORAGE: RefCell<StableBTreeMap<PrincipalKey, CoopInstance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(144)))
+    ));
+}
+
+fn save_instance(instance: CoopInstance) {
+    COOP_INSTANCES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(instance.cooperative), instance)
+    });
+}
+
+// Function for an administrator to deploy a dedicated marketplace for a large cooperative.
+// The cooperative co-controls the new canister and is registered on it; calling again
+// finishes a deployment that failed part way.
+#[ic_cdk::update]
+async fn deploy_cooperative_instance(cooperative: Principal) -> Result<CoopInstance, String> {
+    if !is_admin() {
+        return Err("Only an administrator can deploy cooperative instances".to_string());
+    }
+    let record = get_cooperative_record(&cooperative).ok_or("Cooperative not found".to_string())?;
+
+    let existing =
+        COOP_INSTANCES_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(cooperative)));
+    let (mut instance, mode) = match existing {
+        Some(instance) if instance.ready => {
+            return Err("Cooperative already has an instance".to_string())
+        }
+        Some(instance) => (instance, CanisterInstallMode::Reinstall),
+        None => {
+            let canister = create_child_canister(vec![cooperative], COOP_CANISTER_CYCLES).await?;
+            let instance = CoopInstance {
+                cooperative,
+                canister,
+                ready: false,
+                created_at: ic_cdk::api::time(),
+            };
+            // Recorded before installing so a failed deployment resumes on this canister
+            save_instance(instance.clone());
+            (instance, CanisterInstallMode::Install)
+        }
+    };
+
+    install_module(instance.canister, mode).await?;
+    let (registered,): (Result<Cooperative, String>,) = ic_cdk::call(
+        instance.canister,
+        "register_cooperative",
+        (cooperative, record.name, record.region),
+    )
+    .await
+    .map_err(|(code, message)| format!("Instance call failed: {:?} {}", code, message))?;
+    registered?;
+
+    instance.ready = true;
+    save_instance(instance.clone());
+    Ok(instance)
+}
+
+#[ic_cdk::query]
+fn list_cooperative_instances() -> Vec<CoopInstance> {
+    COOP_INSTANCES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, instance)| instance)
+            .collect()
+    })
+}
+
+// Unsold listings on this canister whose name, description or category contains the query,
+// optionally only those whose farmer delivers to a region
+#[ic_cdk::query]
+fn search_listings(query: String, limit: u64, deliver_to: Option<String>) -> Vec<Farmer> {
+    let query = query.trim().to_lowercase();
+    let deliver_to = match deliver_to.map(|region| normalise_region(&region)) {
+        Some(Ok(region)) => Some(region),
+        Some(Err(_)) => return Vec::new(),
+        None => None,
+    };
+    FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .filter(|farmer| !farmer.is_sold && !is_off_market(farmer))
+            .filter(|farmer| {
+                farmer.name.to_lowercase().contains(&query)
+                    || farmer.bio.to_lowercase().contains(&query)
+                    || farmer.category.to_lowercase().contains(&query)
+            })
+            .filter(|farmer| {
+                deliver_to
+                    .as_ref()
+                    .map_or(true, |region| delivers_to(farmer, region))
+            })
+            .take(limit.min(MAX_SEARCH_RESULTS) as usize)
+            .collect()
+    })
+}
+
+// Searches this canister and every cooperative instance, this canister's hits first.
+// Instances that cannot be reached are left out.
+#[ic_cdk::query(composite = true)]
+async fn search_all_cooperatives(
+    query: String,
+    limit: u64,
+    deliver_to: Option<String>,
+) -> Vec<DirectoryListing> {
+    let limit = limit.min(MAX_SEARCH_RESULTS) as usize;
+    let mut results: Vec<DirectoryListing> =
+        search_listings(query.clone(), limit as u64, deliver_to.clone())
+            .into_iter()
+            .map(|listing| DirectoryListing {
+                canister: ic_cdk::id(),
+                listing,
+            })
+            .collect();
+
+    for instance in list_cooperative_instances() {
+        if results.len() >= limit {
+            break;
+        }
+        if !instance.ready {
+            continue;
+        }
+        let remaining = (limit - results.len()) as u64;
+        if let Ok((listings,)) = ic_cdk::call::<_, (Vec<Farmer>,)>(
+            instance.canister,
+            "search_listings",
+            (query.clone(), remaining, deliver_to.clone()),
+        )
+        .await
+        {
+            results.extend(listings.into_iter().map(|listing| DirectoryListing {
+                canister: instance.canister,
+                listing,
+            }));
+        }
+    }
+    results
+}
@@ -0,0 +1,101 @@
+use crate::shipping::normalise_region;
+use crate::{is_admin, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+
+// Cooperative Struct
+// A farmers' cooperative recognised by the administrators
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Cooperative {
+    principal: Principal,
+    pub(crate) name: String,
+    pub(crate) region: String,
+    registered_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Cooperative
+impl Storable for Cooperative {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Cooperative {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static COOPERATIVES_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Cooperative, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+}
+
+pub(crate) fn is_cooperative(principal: &Principal) -> bool {
+    COOPERATIVES_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+pub(crate) fn get_cooperative_record(principal: &Principal) -> Option<Cooperative> {
+    COOPERATIVES_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*principal)))
+}
+
+// Function for an administrator to recognise a cooperative, or update its details
+#[ic_cdk::update]
+fn register_cooperative(
+    principal: Principal,
+    name: String,
+    region: String,
+) -> Result<Cooperative, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register cooperatives".to_string());
+    }
+    if principal == Principal::anonymous() {
+        return Err("Invalid cooperative principal".to_string());
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err("Invalid cooperative name".to_string());
+    }
+
+    let cooperative = Cooperative {
+        principal,
+        name,
+        region: normalise_region(&region)?,
+        registered_at: ic_cdk::api::time(),
+    };
+
+    COOPERATIVES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), cooperative.clone())
+    });
+    Ok(cooperative)
+}
+
+// Function for an administrator to stop recognising a cooperative
+#[ic_cdk::update]
+fn remove_cooperative(principal: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can remove cooperatives".to_string());
+    }
+
+    COOPERATIVES_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(principal)))
+        .map(|_| ())
+        .ok_or("Cooperative not found".to_string())
+}
+
+#[ic_cdk::query]
+fn get_cooperative(principal: Principal) -> Result<Cooperative, String> {
+    COOPERATIVES_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Cooperative not found".to_string())
+}
@@ -0,0 +1,263 @@
+use crate::devices::caller_account;
+use crate::{is_admin, next_id, CodeKey, Farmer, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MIN_CODE_LEN: usize = 4;
+const MAX_CODE_LEN: usize = 32;
+const MAX_CATEGORIES: usize = 10;
+
+// DiscountType Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug)]
+pub(crate) enum DiscountType {
+    Percentage(u8),
+    Fixed(u64),
+}
+
+// Coupon Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Coupon {
+    code: String,
+    issuer: Principal,
+    // Administrator coupons apply to every listing, farmer coupons only to the issuer's own
+    marketplace_wide: bool,
+    discount: DiscountType,
+    usage_limit: u32,
+    redemptions: u32,
+    categories: Vec<String>,
+    expires_at: u64,
+    active: bool,
+    created_at: u64,
+}
+
+// CouponRedemption Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CouponRedemption {
+    id: u64,
+    code: String,
+    consumer: Principal,
+    order_id: u64,
+    discount: u64,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Coupon
+impl Storable for Coupon {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Coupon {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for CouponRedemption
+impl Storable for CouponRedemption {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CouponRedemption {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static COUPONS_STORAGE: RefCell<StableBTreeMap<CodeKey, Coupon, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    static REDEMPTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static REDEMPTIONS_STORAGE: RefCell<StableBTreeMap<u64, CouponRedemption, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+}
+
+// Coupon Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct CouponPayload {
+    code: String,
+    discount: DiscountType,
+    usage_limit: u32,
+    categories: Vec<String>,
+    expires_at: u64,
+}
+
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn get_coupon_record(code: &str) -> Result<Coupon, String> {
+    COUPONS_STORAGE
+        .with(|storage| storage.borrow().get(&CodeKey(normalize_code(code))))
+        .ok_or("Coupon not found".to_string())
+}
+
+fn save_coupon(coupon: Coupon) {
+    COUPONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(CodeKey(coupon.code.clone()), coupon)
+    });
+}
+
+// Checks that the coupon can be used on this listing and returns the discount on `subtotal`
+pub(crate) fn coupon_discount(
+    code: &str,
+    farmer: &Farmer,
+    consumer: Principal,
+    subtotal: u64,
+) -> Result<u64, String> {
+    let coupon = get_coupon_record(code)?;
+
+    if !coupon.active || coupon.expires_at <= ic_cdk::api::time() {
+        return Err("Coupon has expired".to_string());
+    }
+    if coupon.redemptions >= coupon.usage_limit {
+        return Err("Coupon usage limit reached".to_string());
+    }
+    if !coupon.marketplace_wide && farmer.address != coupon.issuer.to_text() {
+        return Err("Coupon does not apply to this listing".to_string());
+    }
+    if !coupon.categories.is_empty()
+        && !coupon
+            .categories
+            .iter()
+            .any(|category| category.eq_ignore_ascii_case(&farmer.category))
+    {
+        return Err("Coupon does not apply to this category".to_string());
+    }
+    let already_redeemed = REDEMPTIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, redemption)| {
+            redemption.code == coupon.code && redemption.consumer == consumer
+        })
+    });
+    if already_redeemed {
+        return Err("Coupon was already redeemed".to_string());
+    }
+
+    let discount = match coupon.discount {
+        DiscountType::Percentage(percent) => (subtotal as u128 * percent as u128 / 100) as u64,
+        DiscountType::Fixed(amount) => amount,
+    };
+    if discount >= subtotal {
+        return Err("Discount cannot cover the whole order".to_string());
+    }
+    Ok(discount)
+}
+
+// Counts a redemption against the coupon's limit once the discounted order exists
+pub(crate) fn record_redemption(code: &str, consumer: Principal, order_id: u64, discount: u64) {
+    if let Ok(mut coupon) = get_coupon_record(code) {
+        let redemption = CouponRedemption {
+            id: next_id(&REDEMPTION_ID_COUNTER),
+            code: coupon.code.clone(),
+            consumer,
+            order_id,
+            discount,
+            created_at: ic_cdk::api::time(),
+        };
+        REDEMPTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(redemption.id, redemption));
+
+        coupon.redemptions += 1;
+        save_coupon(coupon);
+    }
+}
+
+// Function for an administrator or farmer to issue a coupon code
+#[ic_cdk::update]
+fn create_coupon(payload: CouponPayload) -> Result<Coupon, String> {
+    let code = normalize_code(&payload.code);
+
+    if code.len() < MIN_CODE_LEN
+        || code.len() > MAX_CODE_LEN
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err("Invalid coupon code".to_string());
+    }
+    if COUPONS_STORAGE.with(|storage| storage.borrow().contains_key(&CodeKey(code.clone()))) {
+        return Err("Coupon code already exists".to_string());
+    }
+    match payload.discount {
+        DiscountType::Percentage(percent) if percent == 0 || percent >= 100 => {
+            return Err("Percentage discounts must be between 1 and 99".to_string())
+        }
+        DiscountType::Fixed(0) => return Err("Fixed discounts must be positive".to_string()),
+        _ => {}
+    }
+    if payload.usage_limit == 0 || payload.expires_at <= ic_cdk::api::time() {
+        return Err("Coupons need a usage limit and a future expiry".to_string());
+    }
+    if payload.categories.len() > MAX_CATEGORIES {
+        return Err("Too many categories".to_string());
+    }
+
+    let coupon = Coupon {
+        code,
+        issuer: caller_account(),
+        marketplace_wide: is_admin(),
+        discount: payload.discount,
+        usage_limit: payload.usage_limit,
+        redemptions: 0,
+        categories: payload.categories,
+        expires_at: payload.expires_at,
+        active: true,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_coupon(coupon.clone());
+    Ok(coupon)
+}
+
+#[ic_cdk::update]
+fn deactivate_coupon(code: String) -> Result<(), String> {
+    let mut coupon = get_coupon_record(&code)?;
+    if coupon.issuer != caller_account() && !is_admin() {
+        return Err("Only the issuer can deactivate this coupon".to_string());
+    }
+
+    coupon.active = false;
+    save_coupon(coupon);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_coupon(code: String) -> Result<Coupon, String> {
+    get_coupon_record(&code)
+}
+
+#[ic_cdk::query]
+fn get_coupon_redemptions(code: String) -> Result<Vec<CouponRedemption>, String> {
+    let coupon = get_coupon_record(&code)?;
+    if coupon.issuer != caller_account() && !is_admin() {
+        return Err("Only the issuer can view redemptions".to_string());
+    }
+
+    Ok(REDEMPTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, redemption)| redemption)
+            .filter(|redemption| redemption.code == coupon.code)
+            .collect()
+    }))
+}
@@ -0,0 +1,227 @@
+use crate::archive::archived_orders;
+use crate::delivery_deadlines::missed_deliveries;
+use crate::devices::caller_account;
+use crate::disputes::disputes_against;
+use crate::loans::{is_lender, repayment_history};
+use crate::orders::{OrderStatus, ORDERS_STORAGE};
+use crate::stats::record_farmer_counted;
+use crate::{farmer_principal, is_admin, Memory, PrincipalKey, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Points each factor contributes to the 0-1000 score
+const SALES_WEIGHT: u64 = 300;
+const REPAYMENT_WEIGHT: u64 = 300;
+const DISPUTE_WEIGHT: u64 = 200;
+const AGE_WEIGHT: u64 = 200;
+// Completed orders and account days at which those factors max out
+const FULL_SALES_ORDERS: u64 = 50;
+const FULL_AGE_DAYS: u64 = 365;
+
+// CreditAccount Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct CreditAccount {
+    joined_at: u64,
+    // Whether lenders may read this farmer's credit profile
+    consent: bool,
+}
+
+// CreditProfile Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CreditProfile {
+    farmer_id: u64,
+    farmer: Principal,
+    score: u64,
+    completed_orders: u64,
+    sales_volume: u64,
+    loans_repaid: u64,
+    loans_defaulted: u64,
+    disputes_lost: u64,
+    // Funded orders refunded because they were never shipped in time
+    missed_deliveries: u64,
+    // Lost disputes and missed deliveries over orders that were paid for
+    dispute_rate_bps: u64,
+    account_age_days: u64,
+    computed_at: u64,
+}
+
+// Storable and BoundedStorable implementations for CreditAccount
+impl Storable for CreditAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CreditAccount {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CREDIT_ACCOUNTS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, CreditAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+}
+
+fn get_account(farmer: &Principal) -> Option<CreditAccount> {
+    CREDIT_ACCOUNTS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*farmer)))
+}
+
+fn save_account(farmer: Principal, account: CreditAccount) {
+    CREDIT_ACCOUNTS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(PrincipalKey(farmer), account));
+}
+
+// Whether the principal has ever listed a product
+pub(crate) fn has_listed(farmer: &Principal) -> bool {
+    get_account(farmer).is_some()
+}
+
+// Starts the farmer's account age on their first listing
+pub(crate) fn record_farmer_joined(farmer: Principal) {
+    if get_account(&farmer).is_none() {
+        save_account(
+            farmer,
+            CreditAccount {
+                joined_at: ic_cdk::api::time(),
+                consent: false,
+            },
+        );
+        record_farmer_counted();
+    }
+}
+
+pub(crate) fn farmer_count() -> u64 {
+    CREDIT_ACCOUNTS_STORAGE.with(|storage| storage.borrow().len())
+}
+
+// Scales `value` linearly into 0..=weight, saturating at `full`
+fn scaled(value: u64, full: u64, weight: u64) -> u64 {
+    value.min(full) * weight / full
+}
+
+fn compute_profile(farmer_id: u64, farmer: Principal) -> CreditProfile {
+    let now = ic_cdk::api::time();
+
+    // Status, total and creation time of the farmer's orders, archived ones included
+    let mut orders: Vec<(OrderStatus, u64, u64)> = ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.farmer == farmer)
+            .map(|order| (order.status, order.total, order.created_at))
+            .collect()
+    });
+    orders.extend(
+        archived_orders()
+            .into_iter()
+            .filter(|order| order.farmer == farmer)
+            .map(|order| (order.status, order.total, order.created_at)),
+    );
+
+    let mut completed_orders = 0;
+    let mut sales_volume: u64 = 0;
+    // Orders that were ever paid for count towards the dispute rate
+    let mut settled_orders = 0;
+    let mut first_order_at = now;
+    for (status, total, created_at) in orders {
+        first_order_at = first_order_at.min(created_at);
+        match status {
+            OrderStatus::Pending | OrderStatus::Cancelled => continue,
+            OrderStatus::Completed => {
+                completed_orders += 1;
+                sales_volume = sales_volume.saturating_add(total);
+            }
+            _ => {}
+        }
+        settled_orders += 1;
+    }
+
+    let (loans_repaid, loans_defaulted) = repayment_history(&farmer);
+    let disputes_lost = disputes_against(&farmer);
+    let missed_deliveries = missed_deliveries(&farmer);
+    let dispute_rate_bps = if settled_orders == 0 {
+        0
+    } else {
+        ((disputes_lost + missed_deliveries) * 10_000 / settled_orders).min(10_000)
+    };
+    // Farmers who sold before accounts were tracked date from their first order
+    let joined_at = get_account(&farmer).map_or(first_order_at, |account| {
+        account.joined_at.min(first_order_at)
+    });
+    let account_age_days = now.saturating_sub(joined_at) / NANOS_PER_DAY;
+
+    // Farmers with no loan history get half the repayment points
+    let closed_loans = loans_repaid + loans_defaulted;
+    let repayment_points = if closed_loans == 0 {
+        REPAYMENT_WEIGHT / 2
+    } else {
+        loans_repaid * REPAYMENT_WEIGHT / closed_loans
+    };
+
+    let score = scaled(completed_orders, FULL_SALES_ORDERS, SALES_WEIGHT)
+        + repayment_points
+        + DISPUTE_WEIGHT * (10_000 - dispute_rate_bps) / 10_000
+        + scaled(account_age_days, FULL_AGE_DAYS, AGE_WEIGHT);
+
+    CreditProfile {
+        farmer_id,
+        farmer,
+        score,
+        completed_orders,
+        sales_volume,
+        loans_repaid,
+        loans_defaulted,
+        disputes_lost,
+        missed_deliveries,
+        dispute_rate_bps,
+        account_age_days,
+        computed_at: now,
+    }
+}
+
+// Function for a farmer to allow or stop lenders reading their credit profile
+#[ic_cdk::update]
+fn set_credit_consent(consent: bool) -> Result<(), String> {
+    let farmer = caller_account();
+    let account = match get_account(&farmer) {
+        Some(account) => CreditAccount { consent, ..account },
+        None => CreditAccount {
+            joined_at: ic_cdk::api::time(),
+            consent,
+        },
+    };
+    save_account(farmer, account);
+    Ok(())
+}
+
+// Function for the farmer, an administrator or, with the farmer's consent, a lender to
+// read the credit profile of a listing's owner
+#[ic_cdk::query]
+fn get_credit_profile(farmer_id: u64) -> Result<CreditProfile, String> {
+    let listing = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+    let farmer = farmer_principal(&listing)?;
+    let caller = caller_account();
+
+    if caller != farmer && !is_admin() {
+        if !is_lender(&caller) {
+            return Err("Only registered lenders can read credit profiles".to_string());
+        }
+        if !get_account(&farmer).map_or(false, |account| account.consent) {
+            return Err("Farmer has not consented to credit checks".to_string());
+        }
+    }
+
+    Ok(compute_profile(farmer_id, farmer))
+}
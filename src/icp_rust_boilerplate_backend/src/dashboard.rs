@@ -0,0 +1,102 @@
+use crate::bids::{listing_bids, Bid, BidStatus, ListingKind};
+use crate::devices::caller_account;
+use crate::ledger::{add_asset_amount, AssetAmount};
+use crate::notifications::{notifications_for, Notification};
+use crate::orders::{Order, OrderStatus, ORDERS_STORAGE};
+use crate::{Farmer, FARMERS_STORAGE};
+use candid::Principal;
+
+// DashboardListing Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DashboardListing {
+    listing: Farmer,
+    open_bids: Vec<Bid>,
+}
+
+// Dashboard Struct
+// The caller's home screen: their listings, what needs doing and what is held for them
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Dashboard {
+    listings: Vec<DashboardListing>,
+    // Orders to ship as the farmer, fund as the payer or confirm as the consumer
+    orders_awaiting_action: Vec<Order>,
+    // Escrow balances of the caller's listings
+    listing_escrow: u64,
+    // Funds escrowed on the caller's sales that are not yet settled, per asset
+    sales_escrow: Vec<AssetAmount>,
+    // Funds the caller escrowed on purchases that are not yet settled, per asset
+    purchases_escrow: Vec<AssetAmount>,
+    unread_notifications: Vec<Notification>,
+}
+
+fn is_settled(order: &Order) -> bool {
+    matches!(
+        order.status,
+        OrderStatus::Completed | OrderStatus::Refunded | OrderStatus::Cancelled
+    )
+}
+
+fn awaits_action(order: &Order, account: Principal) -> bool {
+    match order.status {
+        OrderStatus::Pending => order.payer() == account,
+        OrderStatus::Funded => order.farmer == account,
+        OrderStatus::Shipped => order.consumer == account,
+        _ => false,
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_dashboard() -> Dashboard {
+    let account = caller_account();
+    let address = account.to_text();
+
+    let owned: Vec<Farmer> = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .filter(|farmer| farmer.address == address)
+            .collect()
+    });
+    let listing_escrow = owned.iter().fold(0u64, |total, farmer| {
+        total.saturating_add(farmer.escrow_balance)
+    });
+    let listings = owned
+        .into_iter()
+        .map(|listing| DashboardListing {
+            open_bids: listing_bids(ListingKind::Produce, listing.id)
+                .into_iter()
+                .filter(|bid| bid.status == BidStatus::Open)
+                .collect(),
+            listing,
+        })
+        .collect();
+
+    let mut orders_awaiting_action = Vec::new();
+    let mut sales_escrow = Vec::new();
+    let mut purchases_escrow = Vec::new();
+    ORDERS_STORAGE.with(|storage| {
+        for (_, order) in storage.borrow().iter() {
+            if !is_settled(&order) {
+                if order.farmer == account {
+                    add_asset_amount(&mut sales_escrow, order.currency(), order.escrowed);
+                }
+                if order.payer() == account {
+                    add_asset_amount(&mut purchases_escrow, order.currency(), order.escrowed);
+                }
+            }
+            if awaits_action(&order, account) {
+                orders_awaiting_action.push(order);
+            }
+        }
+    });
+
+    Dashboard {
+        listings,
+        orders_awaiting_action,
+        listing_escrow,
+        sales_escrow,
+        purchases_escrow,
+        unread_notifications: notifications_for(account, true),
+    }
+}
@@ -0,0 +1,551 @@
+use crate::devices::caller_account;
+use crate::inspections::inspection_pending;
+use crate::logistics::get_partner_record;
+use crate::notifications::notify;
+use crate::orders::{complete_order, get_order_record, save_order, Order, OrderStatus};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CARRIER_LEN: usize = 100;
+const MAX_NOTE_LEN: usize = 200;
+const MAX_DELIVERY_UPDATES: usize = 20;
+// Orders marked delivered are completed automatically after three days unless disputed
+const AUTO_RELEASE_DELAY_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+// Wrong codes allowed before the consumer has to issue a new one
+const MAX_CODE_ATTEMPTS: u8 = 5;
+// Transit estimate when the order has no shipping route: three days
+const DEFAULT_TRANSIT_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+// Route-based estimate: twelve hours of handling plus 90 seconds per km (40 km/h)
+const HANDLING_NANOS: u64 = 12 * 60 * 60 * 1_000_000_000;
+const TRANSIT_NANOS_PER_KM: u64 = 90 * 1_000_000_000;
+
+// DeliveryStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DeliveryStatus {
+    AwaitingPickup,
+    PickedUp,
+    InTransit,
+    OutForDelivery,
+    Delivered,
+    Failed,
+}
+
+// DeliveryProof Enum
+// How a delivery was shown to have reached the consumer
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum DeliveryProof {
+    Code,
+    PhotoHash(String),
+}
+
+// DeliveryProofPayload Enum
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) enum DeliveryProofPayload {
+    Code(String),
+    PhotoHash(String),
+}
+
+// DeliveryCode Struct
+// Hash of the one-time code the consumer hands the carrier; never returned by queries
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct DeliveryCode {
+    hash: Vec<u8>,
+    failed_attempts: u8,
+}
+
+// DeliveryUpdate Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DeliveryUpdate {
+    status: DeliveryStatus,
+    note: String,
+    recorded_by: Principal,
+    recorded_at: u64,
+}
+
+// Delivery Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Delivery {
+    pub(crate) order_id: u64,
+    pub(crate) carrier: String,
+    pub(crate) partner: Option<Principal>,
+    pub(crate) pickup_at: Option<u64>,
+    pub(crate) expected_delivery_at: Option<u64>,
+    pub(crate) status: DeliveryStatus,
+    pub(crate) updates: Vec<DeliveryUpdate>,
+    pub(crate) delivered_at: Option<u64>,
+    pub(crate) proof: Option<DeliveryProof>,
+    // Computed when the goods are dispatched
+    pub(crate) eta: Option<u64>,
+    pub(crate) late_since: Option<u64>,
+}
+
+impl Delivery {
+    pub(crate) fn is_closed(&self) -> bool {
+        self.status == DeliveryStatus::Delivered || self.status == DeliveryStatus::Failed
+    }
+}
+
+// DeliveryPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct DeliveryPayload {
+    carrier: String,
+    partner: Option<Principal>,
+    pickup_at: Option<u64>,
+    expected_delivery_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for Delivery
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Delivery {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for DeliveryCode
+impl Storable for DeliveryCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeliveryCode {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // One delivery per order, keyed by the order id
+    static DELIVERIES_STORAGE: RefCell<StableBTreeMap<u64, Delivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+
+    static DELIVERY_CODES_STORAGE: RefCell<StableBTreeMap<u64, DeliveryCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+}
+
+pub(crate) fn get_delivery_record(order_id: u64) -> Option<Delivery> {
+    DELIVERIES_STORAGE.with(|storage| storage.borrow().get(&order_id))
+}
+
+fn save_delivery(delivery: Delivery) {
+    DELIVERIES_STORAGE.with(|storage| storage.borrow_mut().insert(delivery.order_id, delivery));
+}
+
+// The farmer and the assigned logistics partner maintain a delivery
+fn can_update(delivery: &Delivery, order: &Order, caller: Principal) -> bool {
+    caller == order.farmer || delivery.partner == Some(caller)
+}
+
+// Parties to the order, its carrier and administrators may follow a delivery
+pub(crate) fn can_view(delivery: &Delivery, order: &Order, caller: Principal) -> bool {
+    can_update(delivery, order, caller)
+        || caller == order.consumer
+        || caller == order.payer()
+        || is_admin()
+}
+
+// Deliveries that have been picked up but not yet closed
+pub(crate) fn in_transit_deliveries() -> Vec<Delivery> {
+    DELIVERIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                delivery.status != DeliveryStatus::AwaitingPickup && !delivery.is_closed()
+            })
+            .collect()
+    })
+}
+
+// The farmer's expected delivery time if still ahead, otherwise an estimate from the route
+fn estimate_arrival(delivery: &Delivery, order: &Order, dispatched_at: u64) -> u64 {
+    if let Some(expected_at) = delivery.expected_delivery_at {
+        if expected_at > dispatched_at {
+            return expected_at;
+        }
+    }
+
+    let transit = order
+        .shipping
+        .as_ref()
+        .map_or(DEFAULT_TRANSIT_NANOS, |shipping| {
+            HANDLING_NANOS.saturating_add(shipping.distance_km.saturating_mul(TRANSIT_NANOS_PER_KM))
+        });
+    dispatched_at.saturating_add(transit)
+}
+
+// Summary of a late delivery, attached to disputes raised while it is late
+pub(crate) fn lateness_context(order_id: u64) -> Option<String> {
+    let delivery = get_delivery_record(order_id)?;
+    let late_since = delivery.late_since?;
+    Some(format!(
+        "Delivery by {} was due at {} and flagged late at {}; last status {:?}",
+        delivery.carrier,
+        delivery.eta.unwrap_or(late_since),
+        late_since,
+        delivery.status
+    ))
+}
+
+// Flags open deliveries whose ETA has passed and notifies both parties once
+pub(crate) fn flag_late_deliveries() {
+    let now = ic_cdk::api::time();
+    let late: Vec<Delivery> = DELIVERIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                !delivery.is_closed()
+                    && delivery.late_since.is_none()
+                    && delivery.eta.map_or(false, |eta| eta < now)
+            })
+            .collect()
+    });
+
+    for mut delivery in late {
+        delivery.late_since = Some(now);
+        if let Ok(order) = get_order_record(delivery.order_id) {
+            let message = format!("Delivery of order #{} is late", order.id);
+            notify(order.farmer, message.clone());
+            notify(order.consumer, message);
+        }
+        save_delivery(delivery);
+    }
+}
+
+// Checks that a partner is registered and can carry the order
+fn check_partner(partner: &Principal, order: &Order) -> Result<(), String> {
+    let partner = get_partner_record(partner).ok_or("Logistics partner not found".to_string())?;
+    if order.quantity > partner.vehicle_capacity {
+        return Err("Order exceeds the partner's vehicle capacity".to_string());
+    }
+    Ok(())
+}
+
+// Completes shipped orders whose delivery was marked delivered long enough ago
+pub(crate) fn release_delivered_orders() {
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = DELIVERIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                delivery.delivered_at.map_or(false, |delivered_at| {
+                    delivered_at.saturating_add(AUTO_RELEASE_DELAY_NANOS) <= now
+                })
+            })
+            .map(|delivery| delivery.order_id)
+            .collect()
+    });
+
+    for order_id in due {
+        // Disputed, confirmed or awaiting-inspection orders are left alone
+        if let Ok(order) = get_order_record(order_id) {
+            if order.status == OrderStatus::Shipped && !inspection_pending(order_id) {
+                complete_order(order);
+            }
+        }
+    }
+}
+
+// Function for the farmer to open delivery tracking for a funded order
+#[ic_cdk::update]
+fn create_delivery(order_id: u64, payload: DeliveryPayload) -> Result<Delivery, String> {
+    let order = get_order_record(order_id)?;
+
+    if order.farmer != caller_account() {
+        return Err("Only the farmer can create a delivery".to_string());
+    }
+    if order.status != OrderStatus::Funded && order.status != OrderStatus::Shipped {
+        return Err("Order is not ready for delivery".to_string());
+    }
+    if get_delivery_record(order_id).is_some() {
+        return Err("Order already has a delivery".to_string());
+    }
+    if payload.carrier.trim().is_empty() || payload.carrier.len() > MAX_CARRIER_LEN {
+        return Err("Invalid carrier".to_string());
+    }
+    // Default to the partner the payer chose shipping with
+    let partner = payload
+        .partner
+        .or(order.shipping.as_ref().map(|shipping| shipping.partner));
+    if let Some(partner) = &partner {
+        check_partner(partner, &order)?;
+    }
+    if let (Some(pickup_at), Some(expected_at)) = (payload.pickup_at, payload.expected_delivery_at)
+    {
+        if expected_at < pickup_at {
+            return Err("Expected delivery is before pickup".to_string());
+        }
+    }
+
+    let delivery = Delivery {
+        order_id,
+        carrier: payload.carrier,
+        partner,
+        pickup_at: payload.pickup_at,
+        expected_delivery_at: payload.expected_delivery_at,
+        status: DeliveryStatus::AwaitingPickup,
+        updates: Vec::new(),
+        delivered_at: None,
+        proof: None,
+        eta: None,
+        late_since: None,
+    };
+
+    save_delivery(delivery.clone());
+    Ok(delivery)
+}
+
+// Function for the farmer or logistics partner to record a delivery status change
+#[ic_cdk::update]
+fn update_delivery_status(
+    order_id: u64,
+    status: DeliveryStatus,
+    note: String,
+) -> Result<Delivery, String> {
+    let caller = caller_account();
+    let mut delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let mut order = get_order_record(order_id)?;
+
+    if !can_update(&delivery, &order, caller) {
+        return Err("Only the farmer or logistics partner can update this delivery".to_string());
+    }
+    if delivery.is_closed() {
+        return Err("Delivery is already closed".to_string());
+    }
+    if status == DeliveryStatus::AwaitingPickup {
+        return Err("Invalid delivery status".to_string());
+    }
+    if status == DeliveryStatus::Delivered {
+        return Err("Use confirm_delivery with a proof of delivery".to_string());
+    }
+    if note.len() > MAX_NOTE_LEN {
+        return Err("Note is too long".to_string());
+    }
+    if delivery.updates.len() >= MAX_DELIVERY_UPDATES {
+        return Err("Too many delivery updates".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    delivery.status = status;
+    delivery.updates.push(DeliveryUpdate {
+        status,
+        note,
+        recorded_by: caller,
+        recorded_at: now,
+    });
+    if status == DeliveryStatus::PickedUp {
+        delivery.pickup_at = Some(now);
+    }
+    if delivery.eta.is_none() && status != DeliveryStatus::Failed {
+        delivery.eta = Some(estimate_arrival(&delivery, &order, now));
+    }
+
+    // Handing the goods to the carrier ships the order
+    if order.status == OrderStatus::Funded && status != DeliveryStatus::Failed {
+        order.status = OrderStatus::Shipped;
+        save_order(order.clone());
+    }
+    save_delivery(delivery.clone());
+    Ok(delivery)
+}
+
+fn hash_code(order_id: u64, code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(order_id.to_be_bytes());
+    hasher.update(code.trim().as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Checks a code handed over at the door, burning it after too many wrong tries
+fn check_code(order_id: u64, code: &str) -> Result<(), String> {
+    let mut stored = DELIVERY_CODES_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("No delivery code has been issued".to_string())?;
+
+    if stored.hash == hash_code(order_id, code) {
+        DELIVERY_CODES_STORAGE.with(|storage| storage.borrow_mut().remove(&order_id));
+        return Ok(());
+    }
+
+    stored.failed_attempts += 1;
+    if stored.failed_attempts >= MAX_CODE_ATTEMPTS {
+        DELIVERY_CODES_STORAGE.with(|storage| storage.borrow_mut().remove(&order_id));
+        return Err("Too many wrong codes; the consumer must issue a new one".to_string());
+    }
+    DELIVERY_CODES_STORAGE.with(|storage| storage.borrow_mut().insert(order_id, stored));
+    Err("Wrong delivery code".to_string())
+}
+
+pub(crate) fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Function for the consumer to get a one-time code to hand the carrier on receipt;
+// issuing a new code replaces the previous one
+#[ic_cdk::update]
+async fn issue_delivery_code(order_id: u64) -> Result<String, String> {
+    let delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let order = get_order_record(order_id)?;
+
+    if order.consumer != caller_account() {
+        return Err("Only the consumer can issue a delivery code".to_string());
+    }
+    if delivery.is_closed() {
+        return Err("Delivery is already closed".to_string());
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, msg)| format!("Cannot generate delivery code: {}", msg))?;
+    let seed = u32::from_be_bytes([random[0], random[1], random[2], random[3]]);
+    let code = format!("{:06}", seed % 1_000_000);
+
+    DELIVERY_CODES_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(
+            order_id,
+            DeliveryCode {
+                hash: hash_code(order_id, &code),
+                failed_attempts: 0,
+            },
+        )
+    });
+    Ok(code)
+}
+
+// Function for the farmer or logistics partner to mark a delivery delivered. The consumer's
+// code completes the order at once; a photo hash leaves the consumer the auto-release window
+// to dispute
+#[ic_cdk::update]
+fn confirm_delivery(order_id: u64, proof: DeliveryProofPayload) -> Result<Delivery, String> {
+    let caller = caller_account();
+    let mut delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let mut order = get_order_record(order_id)?;
+
+    if !can_update(&delivery, &order, caller) {
+        return Err("Only the farmer or logistics partner can confirm this delivery".to_string());
+    }
+    if delivery.is_closed() {
+        return Err("Delivery is already closed".to_string());
+    }
+    if order.status != OrderStatus::Funded && order.status != OrderStatus::Shipped {
+        return Err("Order is not out for delivery".to_string());
+    }
+    if delivery.updates.len() >= MAX_DELIVERY_UPDATES {
+        return Err("Too many delivery updates".to_string());
+    }
+
+    let proof = match proof {
+        DeliveryProofPayload::Code(code) => {
+            check_code(order_id, &code)?;
+            DeliveryProof::Code
+        }
+        DeliveryProofPayload::PhotoHash(hash) if is_sha256_hex(&hash) => {
+            DeliveryProof::PhotoHash(hash.to_lowercase())
+        }
+        DeliveryProofPayload::PhotoHash(_) => {
+            return Err("Photo hash must be a hex SHA-256 digest".to_string())
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    delivery.status = DeliveryStatus::Delivered;
+    delivery.delivered_at = Some(now);
+    delivery.updates.push(DeliveryUpdate {
+        status: DeliveryStatus::Delivered,
+        note: String::new(),
+        recorded_by: caller,
+        recorded_at: now,
+    });
+    delivery.proof = Some(proof.clone());
+    save_delivery(delivery.clone());
+
+    order.status = OrderStatus::Shipped;
+    // Orders flagged for inspection wait for the inspector's report instead
+    match proof {
+        DeliveryProof::Code if !inspection_pending(order_id) => complete_order(order),
+        _ => {
+            notify(
+                order.consumer,
+                format!(
+                    "Order #{} was delivered; confirm receipt or raise a dispute",
+                    order_id
+                ),
+            );
+            save_order(order);
+        }
+    }
+    Ok(delivery)
+}
+
+// Function for the farmer to hand an open delivery to a registered logistics partner
+#[ic_cdk::update]
+fn assign_delivery_partner(order_id: u64, partner: Principal) -> Result<Delivery, String> {
+    let mut delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let order = get_order_record(order_id)?;
+
+    if order.farmer != caller_account() {
+        return Err("Only the farmer can assign a logistics partner".to_string());
+    }
+    if delivery.is_closed() {
+        return Err("Delivery is already closed".to_string());
+    }
+    check_partner(&partner, &order)?;
+
+    delivery.partner = Some(partner);
+    save_delivery(delivery.clone());
+    notify(
+        partner,
+        format!("You were assigned the delivery of order #{}", order_id),
+    );
+    Ok(delivery)
+}
+
+// Lists the open deliveries assigned to the calling logistics partner
+#[ic_cdk::query]
+fn get_assigned_deliveries() -> Vec<Delivery> {
+    let caller = caller_account();
+    DELIVERIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| delivery.partner == Some(caller) && !delivery.is_closed())
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_delivery(order_id: u64) -> Result<Delivery, String> {
+    let delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let order = get_order_record(order_id)?;
+
+    if !can_view(&delivery, &order, caller_account()) {
+        return Err("Delivery not found".to_string());
+    }
+    Ok(delivery)
+}
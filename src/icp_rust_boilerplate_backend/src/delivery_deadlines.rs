@@ -0,0 +1,124 @@
+use crate::contracts::sync_contract_status;
+use crate::events::log_event;
+use crate::governance::is_governor;
+use crate::inventory::release_stock;
+use crate::notifications::notify;
+use crate::order_escrow::refund_order_escrow;
+use crate::orders::{funded_at, save_order, OrderStatus, ORDERS_STORAGE};
+use crate::supplies::release_supply_stock;
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_DEADLINE_DAYS: u64 = 180;
+// Orders refunded per maintenance run
+const MAX_REFUND_BATCH: usize = 50;
+
+thread_local! {
+    // Days a funded order has to be shipped before it is refunded; 0 turns refunds off
+    static DELIVERY_DEADLINE_DAYS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(223))), 0)
+            .expect("Cannot create the delivery deadline cell")
+    );
+
+    // Orders each farmer let run past the deadline
+    static MISSED_DELIVERIES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(224)))
+    ));
+}
+
+pub(crate) fn missed_deliveries(farmer: &Principal) -> u64 {
+    MISSED_DELIVERIES.with(|storage| storage.borrow().get(&PrincipalKey(*farmer)).unwrap_or(0))
+}
+
+fn record_missed_delivery(farmer: Principal) {
+    MISSED_DELIVERIES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let missed = storage.get(&PrincipalKey(farmer)).unwrap_or(0);
+        storage.insert(PrincipalKey(farmer), missed + 1);
+    });
+}
+
+// Refunds funded orders the farmer never shipped within the deadline, counted from when the
+// order was funded or, for scheduled deliveries, from the delivery date
+pub(crate) fn refund_undelivered_orders() {
+    let days = DELIVERY_DEADLINE_DAYS.with(|cell| *cell.borrow().get());
+    if days == 0 {
+        return;
+    }
+    let window = days * NANOS_PER_DAY;
+    let now = ic_cdk::api::time();
+
+    let overdue: Vec<_> = ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| order.status == OrderStatus::Funded)
+            .filter(|order| {
+                let start = funded_at(order).max(order.due_at.unwrap_or(0));
+                now.saturating_sub(start) > window
+            })
+            .take(MAX_REFUND_BATCH)
+            .collect()
+    });
+
+    for mut order in overdue {
+        if let Some(farmer_id) = order.product_id {
+            release_stock(farmer_id, order.quantity);
+        }
+        release_supply_stock(order.id, order.quantity);
+        order.status = OrderStatus::Refunded;
+        save_order(order.clone());
+        refund_order_escrow(&order);
+        record_missed_delivery(order.farmer);
+
+        log_event(
+            "delivery_deadline_missed",
+            format!("Order #{} refunded after {} days unshipped", order.id, days),
+        );
+        notify(
+            order.payer(),
+            format!(
+                "Order #{} was never shipped and has been refunded",
+                order.id
+            ),
+        );
+        notify(
+            order.farmer,
+            format!(
+                "Order #{} was refunded because it was not shipped within {} days",
+                order.id, days
+            ),
+        );
+        if let Some(contract_id) = order.contract_id {
+            sync_contract_status(contract_id);
+        }
+    }
+}
+
+// Function for governance to set how many days a funded order has to be shipped before it is
+// refunded automatically; 0 turns automatic refunds off
+#[ic_cdk::update]
+fn set_delivery_deadline(days: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the delivery deadline".to_string());
+    }
+    if days > MAX_DEADLINE_DAYS {
+        return Err("Delivery deadline is too long".to_string());
+    }
+
+    DELIVERY_DEADLINE_DAYS
+        .with(|cell| cell.borrow_mut().set(days))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_delivery_deadline() -> u64 {
+    DELIVERY_DEADLINE_DAYS.with(|cell| *cell.borrow().get())
+}
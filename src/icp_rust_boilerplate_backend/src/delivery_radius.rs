@@ -0,0 +1,64 @@
+use crate::devices::caller_account;
+use crate::shipping::{listing_region, route_distance};
+use crate::{farmer_principal, Farmer, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_DELIVERY_RADIUS_KM: u64 = 2_000;
+
+thread_local! {
+    // How far from their listings' regions each farmer delivers, in km
+    static DELIVERY_RADII: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(231)))
+    ));
+}
+
+fn delivery_radius(farmer: &Principal) -> Option<u64> {
+    DELIVERY_RADII.with(|storage| storage.borrow().get(&PrincipalKey(*farmer)))
+}
+
+// Whether a listing's farmer delivers to a region, going by the road distance from the
+// listing's region. Listings without a region, a declared radius or a known distance to the
+// region do not count as delivering there.
+pub(crate) fn delivers_to(farmer: &Farmer, region: &str) -> bool {
+    let radius = match farmer_principal(farmer)
+        .ok()
+        .and_then(|owner| delivery_radius(&owner))
+    {
+        Some(radius) => radius,
+        None => return false,
+    };
+    listing_region(farmer.id)
+        .and_then(|origin| route_distance(&origin, region))
+        .map_or(false, |distance| distance <= radius)
+}
+
+// Function for a farmer to declare how far from their listings' regions they deliver, or to
+// withdraw it with None
+#[ic_cdk::update]
+fn set_delivery_radius(km: Option<u64>) -> Result<(), String> {
+    let farmer = caller_account();
+    if farmer == Principal::anonymous() {
+        return Err("Anonymous users cannot set a delivery radius".to_string());
+    }
+
+    match km {
+        Some(km) if km > MAX_DELIVERY_RADIUS_KM => Err("Delivery radius is too large".to_string()),
+        Some(km) => {
+            DELIVERY_RADII.with(|storage| storage.borrow_mut().insert(PrincipalKey(farmer), km));
+            Ok(())
+        }
+        None => {
+            DELIVERY_RADII.with(|storage| storage.borrow_mut().remove(&PrincipalKey(farmer)));
+            Ok(())
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_delivery_radius(farmer: Principal) -> Option<u64> {
+    delivery_radius(&farmer)
+}
@@ -0,0 +1,181 @@
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_LABEL_LEN: usize = 50;
+const MAX_DEVICES: usize = 10;
+
+// DeviceLink Struct
+// A device principal acting for an account. It counts once a device already on the account
+// confirms it.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DeviceLink {
+    device: Principal,
+    account: Principal,
+    label: String,
+    requested_at: u64,
+    confirmed_at: Option<u64>,
+    // The device that confirmed this one
+    confirmed_by: Option<Principal>,
+}
+
+// Storable and BoundedStorable implementations for DeviceLink
+impl Storable for DeviceLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeviceLink {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Device principal to its link, pending or confirmed
+    static DEVICES_STORAGE: RefCell<StableBTreeMap<PrincipalKey, DeviceLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(167)))
+    ));
+}
+
+fn device_link(device: &Principal) -> Option<DeviceLink> {
+    DEVICES_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*device)))
+}
+
+// The account a principal acts for: the account of a confirmed device, or the principal itself
+pub(crate) fn account_of(principal: Principal) -> Principal {
+    match device_link(&principal) {
+        Some(link) if link.confirmed_at.is_some() => link.account,
+        _ => principal,
+    }
+}
+
+// The account the caller acts for. Endpoints use this rather than the raw caller so that
+// any of an account's devices can act for it.
+pub(crate) fn caller_account() -> Principal {
+    account_of(ic_cdk::caller())
+}
+
+fn account_devices(account: &Principal) -> Vec<DeviceLink> {
+    DEVICES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, link)| link)
+            .filter(|link| link.account == *account)
+            .collect()
+    })
+}
+
+// Removes every device of a deleted account
+pub(crate) fn remove_account_devices(account: &Principal) {
+    for link in account_devices(account) {
+        DEVICES_STORAGE.with(|storage| storage.borrow_mut().remove(&PrincipalKey(link.device)));
+    }
+}
+
+// Function for a new device to ask to act for an account. A device already on the account
+// must confirm it with `confirm_device`.
+#[ic_cdk::update]
+fn request_device_link(account: Principal, label: String) -> Result<DeviceLink, String> {
+    let device = ic_cdk::caller();
+    if device == Principal::anonymous() {
+        return Err("Anonymous principals cannot be devices".to_string());
+    }
+    if device == account {
+        return Err("A principal cannot be a device of itself".to_string());
+    }
+    if label.trim().is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err("Invalid device label".to_string());
+    }
+    if matches!(device_link(&device), Some(link) if link.confirmed_at.is_some()) {
+        return Err("Device already acts for an account".to_string());
+    }
+    // Accounts are never devices themselves, and devices never have devices of their own
+    if account_of(account) != account {
+        return Err("That principal is a device, not an account".to_string());
+    }
+    if account_devices(&device)
+        .iter()
+        .any(|link| link.confirmed_at.is_some())
+    {
+        return Err("This principal is an account with devices of its own".to_string());
+    }
+
+    let link = DeviceLink {
+        device,
+        account,
+        label,
+        requested_at: ic_cdk::api::time(),
+        confirmed_at: None,
+        confirmed_by: None,
+    };
+    DEVICES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(device), link.clone())
+    });
+    Ok(link)
+}
+
+// Function for the account, or one of its confirmed devices, to confirm a pending device
+#[ic_cdk::update]
+fn confirm_device(device: Principal) -> Result<DeviceLink, String> {
+    let caller = ic_cdk::caller();
+    let account = account_of(caller);
+    let mut link = device_link(&device).ok_or("No link was requested".to_string())?;
+    if link.account != account {
+        return Err("Device asked to join another account".to_string());
+    }
+    if link.confirmed_at.is_some() {
+        return Err("Device already confirmed".to_string());
+    }
+    let confirmed = account_devices(&account)
+        .iter()
+        .filter(|link| link.confirmed_at.is_some())
+        .count();
+    if confirmed >= MAX_DEVICES {
+        return Err(format!("Accounts can have at most {} devices", MAX_DEVICES));
+    }
+
+    link.confirmed_at = Some(ic_cdk::api::time());
+    link.confirmed_by = Some(caller);
+    DEVICES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(device), link.clone())
+    });
+    Ok(link)
+}
+
+// Function for any device of an account to remove one of its devices, pending or confirmed,
+// including itself
+#[ic_cdk::update]
+fn remove_account_device(device: Principal) -> Result<(), String> {
+    let link = device_link(&device).ok_or("Device not found".to_string())?;
+    if link.account != account_of(ic_cdk::caller()) && device != ic_cdk::caller() {
+        return Err("Device belongs to another account".to_string());
+    }
+
+    DEVICES_STORAGE.with(|storage| storage.borrow_mut().remove(&PrincipalKey(device)));
+    Ok(())
+}
+
+// Devices of the caller's account, pending requests included
+#[ic_cdk::query]
+fn list_my_devices() -> Vec<DeviceLink> {
+    account_devices(&caller_account())
+}
+
+// The account the caller acts for
+#[ic_cdk::query]
+fn whoami() -> Principal {
+    caller_account()
+}
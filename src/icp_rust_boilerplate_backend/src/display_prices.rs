@@ -0,0 +1,336 @@
+use crate::governance::is_governor;
+use crate::shards::{call_shard, listing_shard};
+use crate::{is_farmer_owner, tombstones, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::call_with_payment128;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Listing prices are in e8s of the default currency
+const BASE_SYMBOL: &str = "ICP";
+const BASE_DECIMALS: u32 = 8;
+// Display prices are in hundredths of the display currency, e.g. cents
+const DISPLAY_DECIMALS: u32 = 2;
+// The exchange rate canister charges up to this much per call and refunds the rest
+const XRC_CALL_CYCLES: u128 = 1_000_000_000;
+// Currencies refreshed per maintenance run
+const MAX_REFRESH_BATCH: usize = 20;
+
+// XrcAssetClass Enum (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+enum XrcAssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+// XrcAsset Struct (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct XrcAsset {
+    symbol: String,
+    #[serde(rename = "class")]
+    asset_class: XrcAssetClass,
+}
+
+// GetExchangeRateRequest Struct (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct GetExchangeRateRequest {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: Option<u64>,
+}
+
+// ExchangeRateMetadata Struct (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+// ExchangeRate Struct (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+// ExchangeRateError Enum (exchange rate canister)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+enum ExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+// CachedRate Struct
+// Units of a display currency per unit of the default currency, scaled by 10^decimals
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct CachedRate {
+    rate: u64,
+    decimals: u32,
+    // When the exchange rate canister sampled the rate, in seconds since the epoch
+    rate_timestamp: u64,
+    fetched_at: u64,
+}
+
+// DisplayCurrency Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct DisplayCurrency {
+    code: String,
+}
+
+// PriceDisplay Struct
+// A listing's price in the default currency and converted into a display currency
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PriceDisplay {
+    product_id: u64,
+    // The canonical price, in e8s of the default currency
+    price: u64,
+    currency: String,
+    // In hundredths of `currency`
+    display_price: u64,
+    rate: u64,
+    rate_decimals: u32,
+    rate_timestamp: u64,
+}
+
+// Storable and BoundedStorable implementations for CachedRate
+impl Storable for CachedRate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CachedRate {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for DisplayCurrency
+impl Storable for DisplayCurrency {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DisplayCurrency {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Principal of the exchange rate canister; empty until governance sets it
+    static XRC_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(214))), Vec::new())
+            .expect("Cannot create the exchange rate canister cell")
+    );
+
+    // Keyed by listing ID
+    static DISPLAY_CURRENCY_STORAGE: RefCell<StableBTreeMap<u64, DisplayCurrency, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(215)))
+    ));
+
+    // Keyed by currency code
+    static RATES_STORAGE: RefCell<StableBTreeMap<u64, CachedRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(216)))
+    ));
+}
+
+fn xrc_canister() -> Result<Principal, String> {
+    let bytes = XRC_CANISTER.with(|cell| cell.borrow().get().clone());
+    if bytes.is_empty() {
+        return Err("Exchange rate canister is not configured".to_string());
+    }
+    Principal::try_from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+// ISO 4217 codes are three letters, so they pack into a map key
+fn rate_key(code: &str) -> u64 {
+    code.bytes()
+        .fold(0u64, |key, byte| key << 8 | u64::from(byte))
+}
+
+fn normalize_code(code: &str) -> Result<String, String> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err("Currency must be a three-letter code, e.g. KES".to_string());
+    }
+    Ok(code)
+}
+
+fn cached_rate(code: &str) -> Option<CachedRate> {
+    RATES_STORAGE.with(|storage| storage.borrow().get(&rate_key(code)))
+}
+
+async fn fetch_rate(code: &str) -> Result<CachedRate, String> {
+    let request = GetExchangeRateRequest {
+        base_asset: XrcAsset {
+            symbol: BASE_SYMBOL.to_string(),
+            asset_class: XrcAssetClass::Cryptocurrency,
+        },
+        quote_asset: XrcAsset {
+            symbol: code.to_string(),
+            asset_class: XrcAssetClass::FiatCurrency,
+        },
+        timestamp: None,
+    };
+    let (result,): (Result<ExchangeRate, ExchangeRateError>,) = call_with_payment128(
+        xrc_canister()?,
+        "get_exchange_rate",
+        (request,),
+        XRC_CALL_CYCLES,
+    )
+    .await
+    .map_err(|(code, message)| format!("Exchange rate call failed: {:?} {}", code, message))?;
+    let rate = result.map_err(|e| format!("No exchange rate for {}: {:?}", code, e))?;
+
+    let cached = CachedRate {
+        rate: rate.rate,
+        decimals: rate.metadata.decimals,
+        rate_timestamp: rate.timestamp,
+        fetched_at: ic_cdk::api::time(),
+    };
+    RATES_STORAGE.with(|storage| storage.borrow_mut().insert(rate_key(code), cached.clone()));
+    Ok(cached)
+}
+
+// Refreshes the cached rate of every currency a listing displays in
+pub(crate) async fn refresh_display_rates() {
+    if xrc_canister().is_err() {
+        return;
+    }
+    let mut codes: Vec<String> = DISPLAY_CURRENCY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, currency)| currency.code)
+            .collect()
+    });
+    codes.sort();
+    codes.dedup();
+    // Stalest first, so every currency gets its turn when there are more than a batch
+    codes.sort_by_key(|code| cached_rate(code).map_or(0, |rate| rate.fetched_at));
+
+    // A failed lookup keeps the previous rate until the next run
+    for code in codes.into_iter().take(MAX_REFRESH_BATCH) {
+        let _ = fetch_rate(&code).await;
+    }
+}
+
+// Converts e8s of the default currency into hundredths of the display currency
+fn convert(price: u64, rate: &CachedRate) -> Result<u64, String> {
+    let scale = 10u128
+        .checked_pow(rate.decimals + BASE_DECIMALS - DISPLAY_DECIMALS)
+        .ok_or("Exchange rate has too many decimals".to_string())?;
+    u64::try_from(u128::from(price) * u128::from(rate.rate) / scale)
+        .map_err(|_| "Converted price overflows".to_string())
+}
+
+// Function for governance to set the exchange rate canister display prices are converted with
+#[ic_cdk::update]
+fn set_exchange_rate_canister(canister: Principal) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the exchange rate canister".to_string());
+    }
+    XRC_CANISTER
+        .with(|cell| cell.borrow_mut().set(canister.as_slice().to_vec()))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// Function for a farmer to show a listing's price in a local currency, e.g. KES, or to stop
+// with None. A currency without a cached rate is looked up right away, which also checks that
+// the exchange rate canister knows it.
+#[ic_cdk::update]
+async fn set_listing_display_currency(
+    farmer_id: u64,
+    currency: Option<String>,
+) -> Result<Option<String>, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can set the display currency".to_string());
+    }
+
+    let code = match currency {
+        Some(currency) => normalize_code(&currency)?,
+        None => {
+            DISPLAY_CURRENCY_STORAGE.with(|storage| storage.borrow_mut().remove(&farmer_id));
+            return Ok(None);
+        }
+    };
+    if cached_rate(&code).is_none() {
+        fetch_rate(&code).await?;
+    }
+
+    DISPLAY_CURRENCY_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(farmer_id, DisplayCurrency { code: code.clone() })
+    });
+    Ok(Some(code))
+}
+
+#[ic_cdk::query]
+fn get_listing_display_currency(farmer_id: u64) -> Option<String> {
+    DISPLAY_CURRENCY_STORAGE.with(|storage| storage.borrow().get(&farmer_id).map(|c| c.code))
+}
+
+// Returns a listing's price alongside its value in a display currency, using the listing's own
+// display currency when none is given. Rates come from the cache the maintenance timer keeps
+// fresh; `rate_timestamp` tells how old the figure is.
+#[ic_cdk::query(composite = true)]
+async fn get_product_price_display(
+    farmer_id: u64,
+    currency: Option<String>,
+) -> Result<PriceDisplay, String> {
+    if let Some(shard) = listing_shard(farmer_id) {
+        return call_shard(&shard, "get_product_price_display", (farmer_id, currency)).await;
+    }
+
+    let price = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .filter(tombstones::is_visible)
+        .ok_or("Farmer not found".to_string())?
+        .price;
+    let code = match currency {
+        Some(currency) => normalize_code(&currency)?,
+        None => get_listing_display_currency(farmer_id)
+            .ok_or("Listing has no display currency".to_string())?,
+    };
+    let rate = cached_rate(&code).ok_or(format!("No exchange rate cached for {}", code))?;
+
+    Ok(PriceDisplay {
+        product_id: farmer_id,
+        price,
+        display_price: convert(price, &rate)?,
+        currency: code,
+        rate: rate.rate,
+        rate_decimals: rate.decimals,
+        rate_timestamp: rate.rate_timestamp,
+    })
+}
@@ -0,0 +1,384 @@
+use crate::arbiters::{assign_dispute, close_assignment};
+use crate::contracts::sync_contract_status;
+use crate::deliveries::{get_delivery_record, lateness_context, Delivery};
+use crate::devices::caller_account;
+use crate::inspections::{get_inspection_record, Inspection};
+use crate::jury::withdraw_jury_case;
+use crate::notifications::notify;
+use crate::order_escrow::refund_order_escrow;
+use crate::orders::{
+    cancel_unfunded_order, get_order_record, on_order_completed, save_order, Order, OrderStatus,
+};
+use crate::publisher::{publish, EventTopic};
+use crate::stats::{record_dispute_opened, record_dispute_resolved};
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REASON_LEN: usize = 1_000;
+
+// DisputeStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DisputeStatus {
+    Open,
+    ResolvedForFarmer,
+    ResolvedForConsumer,
+}
+
+// Dispute Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Dispute {
+    pub(crate) id: u64,
+    pub(crate) order_id: u64,
+    pub(crate) raised_by: Principal,
+    pub(crate) reason: String,
+    pub(crate) status: DisputeStatus,
+    pub(crate) created_at: u64,
+    pub(crate) resolved_at: Option<u64>,
+    // Filled in from the order's delivery record when the dispute is opened
+    pub(crate) context: Option<String>,
+}
+
+// DisputeEvidence Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DisputeEvidence {
+    dispute: Dispute,
+    delivery: Option<Delivery>,
+    inspection: Option<Inspection>,
+}
+
+// Storable and BoundedStorable implementations for Dispute
+impl Storable for Dispute {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Dispute {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DISPUTE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DISPUTES_STORAGE: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+}
+
+// DisputeSettlement Enum
+// What resolving a dispute does to its order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DisputeSettlement {
+    // Nothing was paid, so the order is simply cancelled
+    Cancel,
+    Complete,
+    Refund,
+}
+
+// Whether an order can be disputed: it must be in flight and hold funds in escrow
+pub(crate) fn check_disputable(order: &Order) -> Result<(), String> {
+    match order.status {
+        OrderStatus::Funded | OrderStatus::Shipped | OrderStatus::Returning => {}
+        // Its payment may still be in flight, with nothing in escrow to settle yet
+        OrderStatus::Pending => return Err("Order is not funded yet".to_string()),
+        OrderStatus::Disputed => return Err("Order already disputed".to_string()),
+        _ => return Err("Order can no longer be disputed".to_string()),
+    }
+    // With nothing in escrow there is nothing to settle; the payer can cancel instead
+    if order.escrowed == 0 {
+        return Err("Only orders with funds in escrow can be disputed".to_string());
+    }
+    Ok(())
+}
+
+fn settlement(order: &Order, favour_farmer: bool) -> (DisputeStatus, DisputeSettlement) {
+    let status = if favour_farmer {
+        DisputeStatus::ResolvedForFarmer
+    } else {
+        DisputeStatus::ResolvedForConsumer
+    };
+    // Opened before anything was paid, so there is no sale to complete or refund
+    if order.escrowed == 0 {
+        (status, DisputeSettlement::Cancel)
+    } else if favour_farmer {
+        (status, DisputeSettlement::Complete)
+    } else {
+        (status, DisputeSettlement::Refund)
+    }
+}
+
+// Opens a dispute on an order and freezes the order until it is resolved
+pub(crate) fn open_dispute(
+    order_id: u64,
+    raised_by: Principal,
+    reason: String,
+) -> Result<u64, String> {
+    if reason.len() > MAX_REASON_LEN {
+        return Err("Reason is too long".to_string());
+    }
+
+    let mut order = get_order_record(order_id)?;
+    check_disputable(&order)?;
+
+    let dispute = Dispute {
+        id: next_id(&DISPUTE_ID_COUNTER),
+        order_id,
+        raised_by,
+        reason,
+        status: DisputeStatus::Open,
+        created_at: ic_cdk::api::time(),
+        resolved_at: None,
+        context: lateness_context(order_id),
+    };
+
+    let order_parties = [order.farmer, order.consumer, order.payer()];
+    order.status = OrderStatus::Disputed;
+    save_order(order);
+    DISPUTES_STORAGE.with(|storage| storage.borrow_mut().insert(dispute.id, dispute.clone()));
+    record_dispute_opened();
+    assign_dispute(dispute.id, &order_parties);
+
+    Ok(dispute.id)
+}
+
+// Open and resolved dispute totals
+pub(crate) fn dispute_counts() -> (u64, u64) {
+    DISPUTES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .fold((0, 0), |(open, resolved), (_, dispute)| {
+                if dispute.status == DisputeStatus::Open {
+                    (open + 1, resolved)
+                } else {
+                    (open, resolved + 1)
+                }
+            })
+    })
+}
+
+// Counts disputes on the farmer's orders that were not settled in their favour
+pub(crate) fn disputes_against(farmer: &Principal) -> u64 {
+    DISPUTES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| dispute.status != DisputeStatus::ResolvedForFarmer)
+            .filter(|dispute| {
+                get_order_record(dispute.order_id).map_or(false, |order| order.farmer == *farmer)
+            })
+            .count() as u64
+    })
+}
+
+// Function for either party of an order to raise a dispute
+#[ic_cdk::update]
+fn raise_order_dispute(order_id: u64, reason: String) -> Result<u64, String> {
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+
+    if caller != order.farmer && caller != order.consumer && caller != order.payer() {
+        return Err("Only parties to the order can raise a dispute".to_string());
+    }
+
+    open_dispute(order_id, caller, reason)
+}
+
+// Function for an administrator to settle a dispute in favour of one party, taking it over
+// from any arbiter it was assigned to
+#[ic_cdk::update]
+fn resolve_order_dispute(dispute_id: u64, favour_farmer: bool) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can resolve disputes".to_string());
+    }
+
+    settle_dispute(dispute_id, favour_farmer)?;
+    close_assignment(dispute_id);
+    withdraw_jury_case(dispute_id);
+    Ok(())
+}
+
+// Settles an open dispute in favour of one party and moves the order on accordingly
+pub(crate) fn settle_dispute(dispute_id: u64, favour_farmer: bool) -> Result<(), String> {
+    let mut dispute = DISPUTES_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or("Dispute not found".to_string())?;
+
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute already resolved".to_string());
+    }
+
+    let mut order = get_order_record(dispute.order_id)?;
+    let (status, settlement) = settlement(&order, favour_farmer);
+    dispute.status = status;
+    match settlement {
+        DisputeSettlement::Cancel => cancel_unfunded_order(&mut order),
+        DisputeSettlement::Complete => {
+            order.status = OrderStatus::Completed;
+            on_order_completed(&order);
+        }
+        DisputeSettlement::Refund => {
+            order.status = OrderStatus::Refunded;
+            refund_order_escrow(&order);
+        }
+    }
+    // Refunds of gift orders go back to whoever paid
+    if let Some(payer) = order.payer {
+        notify(
+            payer,
+            format!(
+                "Dispute on gift order #{} was resolved: {:?}",
+                order.id, dispute.status
+            ),
+        );
+    }
+    dispute.resolved_at = Some(ic_cdk::api::time());
+
+    let contract_id = order.contract_id;
+    save_order(order);
+    let outcome = format!("{:?}", dispute.status);
+    DISPUTES_STORAGE.with(|storage| storage.borrow_mut().insert(dispute_id, dispute));
+    record_dispute_resolved();
+    publish(EventTopic::DisputeResolved, dispute_id, &outcome);
+
+    if let Some(contract_id) = contract_id {
+        sync_contract_status(contract_id);
+    }
+    Ok(())
+}
+
+pub(crate) fn get_dispute_record(dispute_id: u64) -> Result<Dispute, String> {
+    DISPUTES_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or("Dispute not found".to_string())
+}
+
+#[ic_cdk::query]
+fn get_dispute(dispute_id: u64) -> Result<Dispute, String> {
+    DISPUTES_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or("Dispute not found".to_string())
+}
+
+// Function for an administrator or a party to the order to review a dispute with its delivery record
+#[ic_cdk::query]
+fn get_dispute_evidence(dispute_id: u64) -> Result<DisputeEvidence, String> {
+    let dispute = DISPUTES_STORAGE
+        .with(|storage| storage.borrow().get(&dispute_id))
+        .ok_or("Dispute not found".to_string())?;
+    let order = get_order_record(dispute.order_id)?;
+    let caller = caller_account();
+
+    let is_party = caller == order.farmer || caller == order.consumer || caller == order.payer();
+    if !is_party && !is_admin() {
+        return Err("Only parties to the order can view dispute evidence".to_string());
+    }
+
+    Ok(DisputeEvidence {
+        delivery: get_delivery_record(dispute.order_id),
+        inspection: get_inspection_record(dispute.order_id),
+        dispute,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::test_order;
+
+    #[test]
+    fn only_funded_orders_in_flight_can_be_disputed() {
+        let mut order = test_order(1, 100);
+        order.status = OrderStatus::Funded;
+        assert_eq!(
+            check_disputable(&order),
+            Err("Only orders with funds in escrow can be disputed".to_string())
+        );
+
+        // Claimed by a payment still in flight
+        order.escrowed = order.total;
+        order.status = OrderStatus::Pending;
+        assert_eq!(
+            check_disputable(&order),
+            Err("Order is not funded yet".to_string())
+        );
+
+        for status in [
+            OrderStatus::Funded,
+            OrderStatus::Shipped,
+            OrderStatus::Returning,
+        ] {
+            order.status = status;
+            assert!(check_disputable(&order).is_ok());
+        }
+
+        order.status = OrderStatus::Disputed;
+        assert_eq!(
+            check_disputable(&order),
+            Err("Order already disputed".to_string())
+        );
+        for status in [
+            OrderStatus::Completed,
+            OrderStatus::Refunded,
+            OrderStatus::Cancelled,
+        ] {
+            order.status = status;
+            assert!(check_disputable(&order).is_err());
+        }
+    }
+
+    #[test]
+    fn funded_disputes_complete_or_refund_the_order() {
+        let order = Order {
+            escrowed: 100,
+            status: OrderStatus::Disputed,
+            ..test_order(2, 100)
+        };
+        assert_eq!(
+            settlement(&order, true),
+            (
+                DisputeStatus::ResolvedForFarmer,
+                DisputeSettlement::Complete
+            )
+        );
+        assert_eq!(
+            settlement(&order, false),
+            (
+                DisputeStatus::ResolvedForConsumer,
+                DisputeSettlement::Refund
+            )
+        );
+    }
+
+    #[test]
+    fn unfunded_disputes_only_cancel_the_order() {
+        let order = Order {
+            status: OrderStatus::Disputed,
+            ..test_order(3, 100)
+        };
+        assert_eq!(
+            settlement(&order, true),
+            (DisputeStatus::ResolvedForFarmer, DisputeSettlement::Cancel)
+        );
+        assert_eq!(
+            settlement(&order, false),
+            (
+                DisputeStatus::ResolvedForConsumer,
+                DisputeSettlement::Cancel
+            )
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{is_off_market, Farmer, FARMERS_STORAGE};
+use sha2::{Digest, Sha256};
+
+// Lower-cases text and keeps only its words, so "Red  Onions!" and "red onions" match
+fn normalise(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Hash over a listing's normalised name, category, price and owner address
+pub(crate) fn listing_fingerprint(
+    address: &str,
+    name: &str,
+    category: &str,
+    price: u64,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(address.trim().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(normalise(name).as_bytes());
+    hasher.update(b"\n");
+    hasher.update(normalise(category).as_bytes());
+    hasher.update(b"\n");
+    hasher.update(price.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+pub(crate) fn fingerprint_of(farmer: &Farmer) -> Vec<u8> {
+    listing_fingerprint(
+        &farmer.address,
+        &farmer.name,
+        &farmer.category,
+        farmer.price,
+    )
+}
+
+// An unsold listing still on the market with the same fingerprint, if any
+pub(crate) fn find_duplicate(fingerprint: &[u8]) -> Option<u64> {
+    FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .find(|farmer| {
+                !farmer.is_sold
+                    && !is_off_market(farmer)
+                    && fingerprint_of(farmer).as_slice() == fingerprint
+            })
+            .map(|farmer| farmer.id)
+    })
+}
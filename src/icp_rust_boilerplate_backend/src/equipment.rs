@@ -0,0 +1,610 @@
+use crate::deliveries::is_sha256_hex;
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount};
+use crate::notifications::notify;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TEXT_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const MAX_RENTAL_DAYS: u64 = 30;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Owners have three days after a rental ends to report damage before the deposit goes back
+const DAMAGE_REPORT_DAYS: u64 = 3;
+// Labels the escrow subaccounts that hold rental payments and deposits
+const SUBACCOUNT_TAG: &[u8] = b"equipment";
+
+// EquipmentKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EquipmentKind {
+    Tractor,
+    IrrigationPump,
+    Harvester,
+    Sprayer,
+    Other,
+}
+
+// EquipmentItem Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct EquipmentItem {
+    id: u64,
+    owner: Principal,
+    kind: EquipmentKind,
+    name: String,
+    description: String,
+    location: String,
+    daily_rate: u64,
+    deposit: u64,
+    active: bool,
+    created_at: u64,
+}
+
+// EquipmentItemPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct EquipmentItemPayload {
+    kind: EquipmentKind,
+    name: String,
+    description: String,
+    location: String,
+    daily_rate: u64,
+    deposit: u64,
+}
+
+// RentalStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RentalStatus {
+    AwaitingPayment,
+    Booked,
+    // The owner reported damage and an administrator has to rule on the deposit
+    Disputed,
+    Settled,
+    Cancelled,
+}
+
+// EquipmentRental Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct EquipmentRental {
+    id: u64,
+    item_id: u64,
+    owner: Principal,
+    renter: Principal,
+    // Days since the Unix epoch, in UTC
+    start_day: u64,
+    days: u64,
+    rental_total: u64,
+    deposit: u64,
+    status: RentalStatus,
+    damage_report: Option<String>,
+    damage_evidence_hash: Option<String>,
+    // How the escrow is split once settled, and whether each share has gone out
+    owner_share: u64,
+    renter_share: u64,
+    owner_paid: bool,
+    renter_paid: bool,
+    created_at: u64,
+    settled_at: Option<u64>,
+}
+
+impl EquipmentRental {
+    fn end_day(&self) -> u64 {
+        self.start_day + self.days
+    }
+}
+
+// CalendarKey Struct
+// One day of one item's booking calendar
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CalendarKey {
+    item_id: u64,
+    day: u64,
+}
+
+// Storable and BoundedStorable implementations for EquipmentItem
+impl Storable for EquipmentItem {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EquipmentItem {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for EquipmentRental
+impl Storable for EquipmentRental {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EquipmentRental {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for CalendarKey
+// Big-endian so that the days of one item sort in order
+impl Storable for CalendarKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.item_id.to_be_bytes());
+        bytes.extend_from_slice(&self.day.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (item_id, day) = bytes.split_at(8);
+        CalendarKey {
+            item_id: u64::from_be_bytes(item_id.try_into().unwrap()),
+            day: u64::from_be_bytes(day.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for CalendarKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static EQUIPMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EQUIPMENT_STORAGE: RefCell<StableBTreeMap<u64, EquipmentItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    static RENTAL_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static RENTALS_STORAGE: RefCell<StableBTreeMap<u64, EquipmentRental, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+
+    // Booked days per item, pointing at the rental holding them
+    static EQUIPMENT_CALENDAR: RefCell<StableBTreeMap<CalendarKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+}
+
+fn get_item_record(item_id: u64) -> Result<EquipmentItem, String> {
+    EQUIPMENT_STORAGE
+        .with(|storage| storage.borrow().get(&item_id))
+        .ok_or("Equipment not found".to_string())
+}
+
+fn save_item(item: EquipmentItem) {
+    EQUIPMENT_STORAGE.with(|storage| storage.borrow_mut().insert(item.id, item));
+}
+
+fn get_rental_record(rental_id: u64) -> Result<EquipmentRental, String> {
+    RENTALS_STORAGE
+        .with(|storage| storage.borrow().get(&rental_id))
+        .ok_or("Rental not found".to_string())
+}
+
+fn save_rental(rental: EquipmentRental) {
+    RENTALS_STORAGE.with(|storage| storage.borrow_mut().insert(rental.id, rental));
+}
+
+fn today() -> u64 {
+    ic_cdk::api::time() / NANOS_PER_DAY
+}
+
+// Booked days of an item in [from_day, to_day)
+fn booked_days(item_id: u64, from_day: u64, to_day: u64) -> Vec<u64> {
+    EQUIPMENT_CALENDAR.with(|calendar| {
+        calendar
+            .borrow()
+            .range(
+                CalendarKey {
+                    item_id,
+                    day: from_day,
+                }..CalendarKey {
+                    item_id,
+                    day: to_day,
+                },
+            )
+            .map(|(key, _)| key.day)
+            .collect()
+    })
+}
+
+fn free_calendar(rental: &EquipmentRental) {
+    EQUIPMENT_CALENDAR.with(|calendar| {
+        let mut calendar = calendar.borrow_mut();
+        for day in rental.start_day..rental.end_day() {
+            calendar.remove(&CalendarKey {
+                item_id: rental.item_id,
+                day,
+            });
+        }
+    });
+}
+
+// Splits the escrow between owner and renter and pays out whichever shares are still owed
+async fn settle_rental(mut rental: EquipmentRental, deposit_to_owner: u64) -> EquipmentRental {
+    if rental.status != RentalStatus::Settled {
+        rental.status = RentalStatus::Settled;
+        rental.owner_share = rental.rental_total + deposit_to_owner;
+        rental.renter_share = rental.deposit - deposit_to_owner;
+        rental.settled_at = Some(ic_cdk::api::time());
+    }
+    pay_out_shares(rental).await
+}
+
+async fn pay_out_shares(mut rental: EquipmentRental) -> EquipmentRental {
+    // Mark shares paid before awaiting so a retry cannot pay them twice
+    let pay_owner = !rental.owner_paid && rental.owner_share > 0;
+    let pay_renter = !rental.renter_paid && rental.renter_share > 0;
+    rental.owner_paid = true;
+    rental.renter_paid = true;
+    save_rental(rental.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, rental.id);
+    if pay_owner {
+        let result = release_escrow(subaccount.clone(), rental.owner, rental.owner_share).await;
+        rental.owner_paid = result.is_ok();
+    }
+    if pay_renter {
+        let result = release_escrow(subaccount, rental.renter, rental.renter_share).await;
+        rental.renter_paid = result.is_ok();
+    }
+    save_rental(rental.clone());
+    rental
+}
+
+// Returns deposits on rentals whose damage report window has passed without a report
+pub(crate) async fn settle_finished_rentals() {
+    let cutoff = today().saturating_sub(DAMAGE_REPORT_DAYS);
+    let finished: Vec<EquipmentRental> = RENTALS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, rental)| rental)
+            .filter(|rental| rental.status == RentalStatus::Booked && rental.end_day() <= cutoff)
+            .collect()
+    });
+
+    for rental in finished {
+        settle_rental(rental, 0).await;
+    }
+}
+
+// Function for an owner to list a piece of equipment for daily hire
+#[ic_cdk::update]
+fn list_equipment(payload: EquipmentItemPayload) -> Result<EquipmentItem, String> {
+    if payload.name.trim().is_empty()
+        || payload.name.len() > MAX_TEXT_LEN
+        || payload.location.trim().is_empty()
+        || payload.location.len() > MAX_TEXT_LEN
+        || payload.description.len() > MAX_DESCRIPTION_LEN
+    {
+        return Err("Invalid equipment details".to_string());
+    }
+    if payload.daily_rate == 0 {
+        return Err("Daily rate must be positive".to_string());
+    }
+
+    let item = EquipmentItem {
+        id: next_id(&EQUIPMENT_ID_COUNTER),
+        owner: caller_account(),
+        kind: payload.kind,
+        name: payload.name,
+        description: payload.description,
+        location: payload.location,
+        daily_rate: payload.daily_rate,
+        deposit: payload.deposit,
+        active: true,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_item(item.clone());
+    Ok(item)
+}
+
+// Function for the owner to stop or resume taking bookings; existing bookings are kept
+#[ic_cdk::update]
+fn set_equipment_active(item_id: u64, active: bool) -> Result<(), String> {
+    let mut item = get_item_record(item_id)?;
+
+    if item.owner != caller_account() {
+        return Err("Only the owner can manage this equipment".to_string());
+    }
+
+    item.active = active;
+    save_item(item);
+    Ok(())
+}
+
+// Function for a farmer to hire equipment for whole days, paying hire and deposit into escrow
+#[ic_cdk::update]
+async fn book_equipment(
+    item_id: u64,
+    start_day: u64,
+    days: u64,
+) -> Result<EquipmentRental, String> {
+    let item = get_item_record(item_id)?;
+    let renter = caller_account();
+
+    if !item.active {
+        return Err("Equipment is not available for hire".to_string());
+    }
+    if renter == item.owner {
+        return Err("Owners cannot hire their own equipment".to_string());
+    }
+    if start_day < today() {
+        return Err("Hire cannot start in the past".to_string());
+    }
+    if days == 0 || days > MAX_RENTAL_DAYS {
+        return Err("Invalid hire length".to_string());
+    }
+    if !booked_days(item_id, start_day, start_day + days).is_empty() {
+        return Err("Equipment is already booked on some of those days".to_string());
+    }
+    let rental_total = item
+        .daily_rate
+        .checked_mul(days)
+        .ok_or("Hire total overflows".to_string())?;
+    let amount = rental_total
+        .checked_add(item.deposit)
+        .ok_or("Hire total overflows".to_string())?;
+
+    let mut rental = EquipmentRental {
+        id: next_id(&RENTAL_ID_COUNTER),
+        item_id,
+        owner: item.owner,
+        renter,
+        start_day,
+        days,
+        rental_total,
+        deposit: item.deposit,
+        status: RentalStatus::AwaitingPayment,
+        damage_report: None,
+        damage_evidence_hash: None,
+        owner_share: 0,
+        renter_share: 0,
+        owner_paid: false,
+        renter_paid: false,
+        created_at: ic_cdk::api::time(),
+        settled_at: None,
+    };
+
+    // Hold the days before awaiting so no one else can book them meanwhile
+    EQUIPMENT_CALENDAR.with(|calendar| {
+        let mut calendar = calendar.borrow_mut();
+        for day in start_day..start_day + days {
+            calendar.insert(CalendarKey { item_id, day }, rental.id);
+        }
+    });
+    save_rental(rental.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, rental.id);
+    if let Err(e) = transfer_into_subaccount(renter, subaccount, amount).await {
+        free_calendar(&rental);
+        rental.status = RentalStatus::Cancelled;
+        save_rental(rental);
+        return Err(e);
+    }
+
+    rental.status = RentalStatus::Booked;
+    save_rental(rental.clone());
+    notify(
+        item.owner,
+        format!(
+            "{} was booked for {} days from day {} (rental #{})",
+            item.name, days, start_day, rental.id
+        ),
+    );
+    Ok(rental)
+}
+
+// Function for the renter to cancel before the hire starts, refunding hire and deposit
+#[ic_cdk::update]
+async fn cancel_equipment_rental(rental_id: u64) -> Result<EquipmentRental, String> {
+    let mut rental = get_rental_record(rental_id)?;
+
+    if rental.renter != caller_account() {
+        return Err("Only the renter can cancel this rental".to_string());
+    }
+    if rental.status != RentalStatus::Booked || rental.start_day <= today() {
+        return Err("Rental can no longer be cancelled".to_string());
+    }
+
+    free_calendar(&rental);
+    rental.status = RentalStatus::Settled;
+    rental.renter_share = rental.rental_total + rental.deposit;
+    rental.settled_at = Some(ic_cdk::api::time());
+    let mut rental = pay_out_shares(rental).await;
+    rental.status = RentalStatus::Cancelled;
+    save_rental(rental.clone());
+    notify(
+        rental.owner,
+        format!("Rental #{} was cancelled by the renter", rental.id),
+    );
+    Ok(rental)
+}
+
+// Function for the owner to confirm the equipment came back undamaged, settling at once
+#[ic_cdk::update]
+async fn confirm_equipment_return(rental_id: u64) -> Result<EquipmentRental, String> {
+    let rental = get_rental_record(rental_id)?;
+
+    if rental.owner != caller_account() {
+        return Err("Only the owner can confirm the return".to_string());
+    }
+    if rental.status != RentalStatus::Booked || rental.start_day > today() {
+        return Err("Rental is not in progress".to_string());
+    }
+
+    Ok(settle_rental(rental, 0).await)
+}
+
+// Function for the owner to report damage, holding the deposit for an administrator to rule on
+#[ic_cdk::update]
+fn report_equipment_damage(
+    rental_id: u64,
+    description: String,
+    evidence_hash: Option<String>,
+) -> Result<EquipmentRental, String> {
+    let mut rental = get_rental_record(rental_id)?;
+
+    if rental.owner != caller_account() {
+        return Err("Only the owner can report damage".to_string());
+    }
+    if rental.status != RentalStatus::Booked || rental.start_day > today() {
+        return Err("Rental is not in progress".to_string());
+    }
+    if rental.deposit == 0 {
+        return Err("Rental has no deposit to claim".to_string());
+    }
+    if description.trim().is_empty() || description.len() > MAX_DESCRIPTION_LEN {
+        return Err("Invalid damage description".to_string());
+    }
+    if evidence_hash
+        .as_ref()
+        .map_or(false, |hash| !is_sha256_hex(hash))
+    {
+        return Err("Evidence must be a hex SHA-256 digest".to_string());
+    }
+
+    rental.status = RentalStatus::Disputed;
+    rental.damage_report = Some(description);
+    rental.damage_evidence_hash = evidence_hash.map(|hash| hash.to_lowercase());
+    save_rental(rental.clone());
+    notify(
+        rental.renter,
+        format!(
+            "The owner reported damage on rental #{}; your deposit is held until an administrator rules",
+            rental.id
+        ),
+    );
+    Ok(rental)
+}
+
+// Function for an administrator to rule how much of the deposit covers the damage
+#[ic_cdk::update]
+async fn resolve_equipment_damage(
+    rental_id: u64,
+    deposit_to_owner: u64,
+) -> Result<EquipmentRental, String> {
+    if !is_admin() {
+        return Err("Only an administrator can resolve damage disputes".to_string());
+    }
+
+    let rental = get_rental_record(rental_id)?;
+    if rental.status != RentalStatus::Disputed {
+        return Err("Rental is not disputed".to_string());
+    }
+    if deposit_to_owner > rental.deposit {
+        return Err("Award exceeds the deposit".to_string());
+    }
+
+    let rental = settle_rental(rental, deposit_to_owner).await;
+    for party in [rental.owner, rental.renter] {
+        notify(
+            party,
+            format!(
+                "Damage dispute on rental #{} resolved: {} of the deposit to the owner",
+                rental.id, deposit_to_owner
+            ),
+        );
+    }
+    Ok(rental)
+}
+
+// Function for either party to retry a share of a settled rental whose transfer failed
+#[ic_cdk::update]
+async fn retry_equipment_payout(rental_id: u64) -> Result<EquipmentRental, String> {
+    let rental = get_rental_record(rental_id)?;
+    let caller = caller_account();
+
+    if caller != rental.owner && caller != rental.renter {
+        return Err("Only parties to the rental can retry its payout".to_string());
+    }
+    if rental.status != RentalStatus::Settled && rental.status != RentalStatus::Cancelled {
+        return Err("Rental has not been settled".to_string());
+    }
+    if rental.owner_paid && rental.renter_paid {
+        return Err("Nothing left to pay out".to_string());
+    }
+
+    Ok(pay_out_shares(rental).await)
+}
+
+#[ic_cdk::query]
+fn get_equipment(item_id: u64) -> Result<EquipmentItem, String> {
+    get_item_record(item_id)
+}
+
+// Lists hireable equipment, optionally of one kind or at one location
+#[ic_cdk::query]
+fn list_available_equipment(
+    kind: Option<EquipmentKind>,
+    location: Option<String>,
+) -> Vec<EquipmentItem> {
+    EQUIPMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| item.active)
+            .filter(|item| kind.map_or(true, |kind| item.kind == kind))
+            .filter(|item| {
+                location.as_ref().map_or(true, |location| {
+                    item.location.eq_ignore_ascii_case(location)
+                })
+            })
+            .collect()
+    })
+}
+
+// Days already booked for an item in [from_day, to_day)
+#[ic_cdk::query]
+fn get_equipment_calendar(item_id: u64, from_day: u64, to_day: u64) -> Vec<u64> {
+    booked_days(item_id, from_day, to_day)
+}
+
+#[ic_cdk::query]
+fn get_equipment_rental(rental_id: u64) -> Result<EquipmentRental, String> {
+    let rental = get_rental_record(rental_id)?;
+    let caller = caller_account();
+
+    if caller != rental.owner && caller != rental.renter && !is_admin() {
+        return Err("Rental not found".to_string());
+    }
+    Ok(rental)
+}
+
+// Lists rentals the caller made or hosts
+#[ic_cdk::query]
+fn get_my_equipment_rentals() -> Vec<EquipmentRental> {
+    let caller = caller_account();
+    RENTALS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, rental)| rental)
+            .filter(|rental| rental.owner == caller || rental.renter == caller)
+            .collect()
+    })
+}
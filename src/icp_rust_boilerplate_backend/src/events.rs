@@ -0,0 +1,381 @@
+use crate::archive::archive_canister;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_PAGE_SIZE: u64 = 100;
+// Events stay in the live log this long before being rolled into a checkpoint
+const CHECKPOINT_AFTER_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_CHECKPOINT_EVENTS: usize = 200;
+
+// LoggedEvent Struct
+// An entry in the append-only event log. Each entry's hash covers the previous entry's hash,
+// so the log cannot be rewritten without breaking the chain.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LoggedEvent {
+    pub(crate) id: u64,
+    pub(crate) kind: String,
+    pub(crate) actor: Principal,
+    pub(crate) detail: String,
+    pub(crate) at: u64,
+    pub(crate) hash: Vec<u8>,
+}
+
+// Storable and BoundedStorable implementations for LoggedEvent
+impl Storable for LoggedEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LoggedEvent {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// EventCheckpoint Struct
+// A summary of a run of old events whose raw entries moved to the archive canister. Each
+// checkpoint's hash covers the previous checkpoint's hash, and `last_event_hash` is the hash
+// the next raw event chains from.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct EventCheckpoint {
+    id: u64,
+    first_event_id: u64,
+    last_event_id: u64,
+    event_count: u64,
+    from_at: u64,
+    to_at: u64,
+    // Number of events of each kind in the run
+    kind_counts: Vec<(String, u64)>,
+    last_event_hash: Vec<u8>,
+    previous_hash: Vec<u8>,
+    hash: Vec<u8>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for EventCheckpoint
+impl Storable for EventCheckpoint {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EventCheckpoint {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static EVENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(127))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EVENT_LOG_STORAGE: RefCell<StableBTreeMap<u64, LoggedEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(128)))
+    ));
+
+    static CHECKPOINT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(171))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CHECKPOINTS_STORAGE: RefCell<StableBTreeMap<u64, EventCheckpoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(172)))
+    ));
+}
+
+pub(crate) fn event_hash(
+    previous: &[u8],
+    id: u64,
+    kind: &str,
+    actor: &Principal,
+    detail: &str,
+    at: u64,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update(id.to_be_bytes());
+    hasher.update(kind.as_bytes());
+    hasher.update(actor.as_slice());
+    hasher.update(detail.as_bytes());
+    hasher.update(at.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn checkpoint_hash(previous: &[u8], checkpoint: &EventCheckpoint) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous);
+    hasher.update(checkpoint.id.to_be_bytes());
+    hasher.update(checkpoint.first_event_id.to_be_bytes());
+    hasher.update(checkpoint.last_event_id.to_be_bytes());
+    hasher.update(checkpoint.event_count.to_be_bytes());
+    hasher.update(checkpoint.from_at.to_be_bytes());
+    hasher.update(checkpoint.to_at.to_be_bytes());
+    for (kind, count) in &checkpoint.kind_counts {
+        hasher.update(kind.as_bytes());
+        hasher.update(count.to_be_bytes());
+    }
+    hasher.update(&checkpoint.last_event_hash);
+    hasher.update(checkpoint.created_at.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn latest_checkpoint() -> Option<EventCheckpoint> {
+    CHECKPOINTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .next()
+            .map(|(_, checkpoint)| checkpoint)
+    })
+}
+
+// The hash the next event chains from: the newest live event's, or the last compacted one's
+fn chain_head() -> Vec<u8> {
+    EVENT_LOG_STORAGE
+        .with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .rev()
+                .next()
+                .map(|(_, event)| event.hash)
+        })
+        .or_else(|| latest_checkpoint().map(|checkpoint| checkpoint.last_event_hash))
+        .unwrap_or_default()
+}
+
+fn recomputed_hash(previous: &[u8], event: &LoggedEvent) -> Vec<u8> {
+    event_hash(
+        previous,
+        event.id,
+        &event.kind,
+        &event.actor,
+        &event.detail,
+        event.at,
+    )
+}
+
+// Appends an event on behalf of the caller and returns its ID
+pub(crate) fn log_event(kind: &str, detail: String) -> u64 {
+    let previous = chain_head();
+    let id = next_id(&EVENT_ID_COUNTER);
+    let actor = ic_cdk::caller();
+    let at = ic_cdk::api::time();
+
+    let event = LoggedEvent {
+        id,
+        hash: event_hash(&previous, id, kind, &actor, &detail, at),
+        kind: kind.to_string(),
+        actor,
+        detail,
+        at,
+    };
+    EVENT_LOG_STORAGE.with(|storage| storage.borrow_mut().insert(id, event));
+    id
+}
+
+// Rolls the oldest run of events past the checkpoint age into a checkpoint, after copying
+// them to the archive canister. Returns how many events were compacted.
+pub(crate) async fn compact_events() -> Result<u64, String> {
+    let archive = archive_canister()?;
+    let cutoff = ic_cdk::api::time().saturating_sub(CHECKPOINT_AFTER_NANOS);
+    let batch: Vec<LoggedEvent> = EVENT_LOG_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .take_while(|event| event.at < cutoff)
+            .take(MAX_CHECKPOINT_EVENTS)
+            .collect()
+    });
+    let (first, last) = match (batch.first(), batch.last()) {
+        (Some(first), Some(last)) => (first.clone(), last.clone()),
+        _ => return Ok(0),
+    };
+
+    // Never compact a run that does not chain from the previous checkpoint
+    let previous = latest_checkpoint();
+    let mut head = previous
+        .as_ref()
+        .map(|checkpoint| checkpoint.last_event_hash.clone())
+        .unwrap_or_default();
+    for event in &batch {
+        if recomputed_hash(&head, event) != event.hash {
+            return Err(format!("Event log chain is broken at event {}", event.id));
+        }
+        head = event.hash.clone();
+    }
+
+    let records: Vec<(u64, Vec<u8>)> = batch
+        .iter()
+        .map(|event| (event.id, event.to_bytes().into_owned()))
+        .collect();
+    let (result,): (Result<(), String>,) = ic_cdk::call(archive, "append_events", (records,))
+        .await
+        .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    result?;
+
+    // Another run may have compacted the same events while the call was in flight
+    let still_live = EVENT_LOG_STORAGE.with(|storage| storage.borrow().contains_key(&first.id));
+    let unchanged = latest_checkpoint().map(|checkpoint| checkpoint.id)
+        == previous.as_ref().map(|checkpoint| checkpoint.id);
+    if !still_live || !unchanged {
+        return Ok(0);
+    }
+
+    let mut kind_counts: Vec<(String, u64)> = Vec::new();
+    for event in &batch {
+        match kind_counts.iter_mut().find(|(kind, _)| *kind == event.kind) {
+            Some((_, count)) => *count += 1,
+            None => kind_counts.push((event.kind.clone(), 1)),
+        }
+    }
+    kind_counts.sort();
+
+    let previous_hash = previous
+        .map(|checkpoint| checkpoint.hash)
+        .unwrap_or_default();
+    let mut checkpoint = EventCheckpoint {
+        id: next_id(&CHECKPOINT_ID_COUNTER),
+        first_event_id: first.id,
+        last_event_id: last.id,
+        event_count: batch.len() as u64,
+        from_at: first.at,
+        to_at: last.at,
+        kind_counts,
+        last_event_hash: last.hash,
+        previous_hash: previous_hash.clone(),
+        hash: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+    checkpoint.hash = checkpoint_hash(&previous_hash, &checkpoint);
+    CHECKPOINTS_STORAGE.with(|storage| storage.borrow_mut().insert(checkpoint.id, checkpoint));
+    EVENT_LOG_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for event in &batch {
+            storage.remove(&event.id);
+        }
+    });
+    Ok(batch.len() as u64)
+}
+
+// Timer job; does nothing until an archive canister is configured
+pub(crate) async fn compact_due_events() {
+    let _ = compact_events().await;
+}
+
+// Function for an administrator to compact a run of old events now instead of waiting for
+// the timer
+#[ic_cdk::update]
+async fn compact_event_log() -> Result<u64, String> {
+    if !is_admin() {
+        return Err("Only an administrator can compact the event log".to_string());
+    }
+    compact_events().await
+}
+
+// Function to read an event, wherever it is kept. Compacted events are fetched from the
+// archive canister.
+#[ic_cdk::query(composite = true)]
+async fn get_event(event_id: u64) -> Result<LoggedEvent, String> {
+    if let Some(event) = EVENT_LOG_STORAGE.with(|storage| storage.borrow().get(&event_id)) {
+        return Ok(event);
+    }
+    let compacted =
+        latest_checkpoint().map_or(false, |checkpoint| event_id <= checkpoint.last_event_id);
+    if !compacted {
+        return Err("Event not found".to_string());
+    }
+
+    let (result,): (Result<Option<Vec<u8>>, String>,) =
+        ic_cdk::call(archive_canister()?, "get_event", (event_id,))
+            .await
+            .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    let bytes = result?.ok_or("Event is missing from the archive".to_string())?;
+    Decode!(&bytes, LoggedEvent).map_err(|e| format!("Cannot decode the archived event: {}", e))
+}
+
+// Checkpoints in order from `from_id`, at most 100 per call
+#[ic_cdk::query]
+fn get_event_checkpoints(from_id: u64, limit: u64) -> Vec<EventCheckpoint> {
+    CHECKPOINTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(from_id..)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, checkpoint)| checkpoint)
+            .collect()
+    })
+}
+
+// Checks the checkpoint chain, then the live events chaining on from the last checkpoint.
+// Returns the number of checkpoints and events checked.
+#[ic_cdk::query]
+fn verify_event_log() -> Result<u64, String> {
+    let mut previous_hash: Vec<u8> = Vec::new();
+    let mut head: Vec<u8> = Vec::new();
+    let mut next_event_id = 0;
+    let mut checked = 0;
+    for (_, checkpoint) in
+        CHECKPOINTS_STORAGE.with(|storage| storage.borrow().iter().collect::<Vec<_>>())
+    {
+        if checkpoint.previous_hash != previous_hash
+            || checkpoint_hash(&previous_hash, &checkpoint) != checkpoint.hash
+        {
+            return Err(format!(
+                "Checkpoint chain is broken at checkpoint {}",
+                checkpoint.id
+            ));
+        }
+        if checkpoint.first_event_id != next_event_id {
+            return Err(format!(
+                "Events are missing before checkpoint {}",
+                checkpoint.id
+            ));
+        }
+        previous_hash = checkpoint.hash;
+        head = checkpoint.last_event_hash;
+        next_event_id = checkpoint.last_event_id + 1;
+        checked += 1;
+    }
+
+    for (_, event) in EVENT_LOG_STORAGE.with(|storage| storage.borrow().iter().collect::<Vec<_>>())
+    {
+        if event.id != next_event_id || recomputed_hash(&head, &event) != event.hash {
+            return Err(format!("Event log chain is broken at event {}", event.id));
+        }
+        head = event.hash;
+        next_event_id += 1;
+        checked += 1;
+    }
+    Ok(checked)
+}
+
+// Reads the log in order from `from_id`, at most 100 events per call
+#[ic_cdk::query]
+fn get_event_log(from_id: u64, limit: u64) -> Vec<LoggedEvent> {
+    EVENT_LOG_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(from_id..)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, event)| event)
+            .collect()
+    })
+}
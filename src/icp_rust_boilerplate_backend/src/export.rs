@@ -0,0 +1,89 @@
+use crate::orders::ORDERS_STORAGE;
+use crate::{is_admin, FARMERS_STORAGE};
+use serde::Serialize;
+
+const MAX_PAGE_RECORDS: u64 = 500;
+// Stop filling a page past this many bytes of JSON, well under the reply size limit
+const MAX_PAGE_BYTES: usize = 1_000_000;
+
+// ExportCollection Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ExportCollection {
+    // Farmer product listings
+    Listings,
+    Orders,
+}
+
+// ExportPage Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ExportPage {
+    collection: ExportCollection,
+    // JSON array of the records on this page
+    data: String,
+    count: u64,
+    // Cursor for the next page; None once the collection is exhausted
+    next_cursor: Option<u64>,
+}
+
+// Serialises records in ID order from the iterator into a JSON array until a page limit is
+// reached, returning the array, the record count and the first ID left over
+fn fill_page<T: Serialize>(
+    records: impl Iterator<Item = (u64, T)>,
+    limit: u64,
+) -> Result<(String, u64, Option<u64>), String> {
+    let mut data = String::from("[");
+    let mut count = 0;
+
+    for (id, record) in records {
+        if count == limit || data.len() >= MAX_PAGE_BYTES {
+            return Ok((data + "]", count, Some(id)));
+        }
+        if count > 0 {
+            data.push(',');
+        }
+        data.push_str(
+            &serde_json::to_string(&record)
+                .map_err(|e| format!("Cannot export record {}: {}", id, e))?,
+        );
+        count += 1;
+    }
+    Ok((data + "]", count, None))
+}
+
+// Function for an administrator to export a collection as JSON pages for off-chain backup or
+// analysis. Start with cursor 0 and pass each page's `next_cursor` until it is None.
+#[ic_cdk::query]
+fn export_records(
+    collection: ExportCollection,
+    cursor: u64,
+    limit: u64,
+) -> Result<ExportPage, String> {
+    if !is_admin() {
+        return Err("Only an administrator can export data".to_string());
+    }
+    export_page(collection, cursor, limit)
+}
+
+// Builds one page of an export; callers check that the caller may export
+pub(crate) fn export_page(
+    collection: ExportCollection,
+    cursor: u64,
+    limit: u64,
+) -> Result<ExportPage, String> {
+    let limit = limit.clamp(1, MAX_PAGE_RECORDS);
+    let (data, count, next_cursor) = match collection {
+        ExportCollection::Listings => {
+            FARMERS_STORAGE.with(|storage| fill_page(storage.borrow().range(cursor..), limit))?
+        }
+        ExportCollection::Orders => {
+            ORDERS_STORAGE.with(|storage| fill_page(storage.borrow().range(cursor..), limit))?
+        }
+    };
+
+    Ok(ExportPage {
+        collection,
+        data,
+        count,
+        next_cursor,
+    })
+}
@@ -0,0 +1,470 @@
+use crate::devices::caller_account;
+use crate::identity::check_can_rate;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount};
+use crate::notifications::notify;
+use crate::shipping::normalise_region;
+use crate::{next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_SPECIALTIES: usize = 10;
+const MAX_SPECIALTY_LEN: usize = 50;
+const MAX_TOPIC_LEN: usize = 300;
+// How long the farmer has after a session to confirm it before the advisor may
+const CONFIRMATION_WINDOW_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// Labels the escrow subaccounts that hold session fees
+const SUBACCOUNT_TAG: &[u8] = b"advisory";
+
+// AdvisorKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AdvisorKind {
+    Agronomist,
+    Veterinarian,
+    Other,
+}
+
+// ExtensionAdvisor Struct
+// An agronomist or vet farmers can book for advice
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ExtensionAdvisor {
+    principal: Principal,
+    name: String,
+    kind: AdvisorKind,
+    specialties: Vec<String>,
+    region: String,
+    session_rate: u64,
+    active: bool,
+    rating_total: u64,
+    rating_count: u64,
+    registered_at: u64,
+}
+
+// ExtensionAdvisorPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ExtensionAdvisorPayload {
+    name: String,
+    kind: AdvisorKind,
+    specialties: Vec<String>,
+    region: String,
+    session_rate: u64,
+    active: bool,
+}
+
+// SessionStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SessionStatus {
+    AwaitingPayment,
+    Requested,
+    Accepted,
+    Completed,
+    Declined,
+    Cancelled,
+}
+
+// AdvisorySession Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AdvisorySession {
+    id: u64,
+    advisor: Principal,
+    farmer: Principal,
+    topic: String,
+    scheduled_at: u64,
+    fee: u64,
+    status: SessionStatus,
+    rating: Option<u8>,
+    created_at: u64,
+    closed_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for ExtensionAdvisor
+impl Storable for ExtensionAdvisor {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExtensionAdvisor {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for AdvisorySession
+impl Storable for AdvisorySession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AdvisorySession {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ADVISORS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, ExtensionAdvisor, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+
+    static SESSION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SESSIONS_STORAGE: RefCell<StableBTreeMap<u64, AdvisorySession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))
+    ));
+}
+
+fn get_advisor_record(principal: &Principal) -> Result<ExtensionAdvisor, String> {
+    ADVISORS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(*principal)))
+        .ok_or("Advisor not found".to_string())
+}
+
+fn save_advisor(advisor: ExtensionAdvisor) {
+    ADVISORS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(advisor.principal), advisor)
+    });
+}
+
+pub(crate) fn is_extension_advisor(principal: &Principal) -> bool {
+    ADVISORS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+fn get_session_record(session_id: u64) -> Result<AdvisorySession, String> {
+    SESSIONS_STORAGE
+        .with(|storage| storage.borrow().get(&session_id))
+        .ok_or("Session not found".to_string())
+}
+
+fn save_session(session: AdvisorySession) {
+    SESSIONS_STORAGE.with(|storage| storage.borrow_mut().insert(session.id, session));
+}
+
+// Closes a session as `status` and pays its escrowed fee to `to`, reopening it as `previous`
+// if the transfer fails
+async fn close_session(
+    mut session: AdvisorySession,
+    status: SessionStatus,
+    to: Principal,
+) -> Result<AdvisorySession, String> {
+    let previous = session.status;
+    // Close before awaiting so the escrow cannot be released twice
+    session.status = status;
+    session.closed_at = Some(ic_cdk::api::time());
+    save_session(session.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, session.id);
+    if let Err(e) = release_escrow(subaccount, to, session.fee).await {
+        session.status = previous;
+        session.closed_at = None;
+        save_session(session);
+        return Err(e);
+    }
+    Ok(session)
+}
+
+// Function for an agronomist or vet to join the directory, or update their entry
+#[ic_cdk::update]
+fn register_extension_advisor(
+    payload: ExtensionAdvisorPayload,
+) -> Result<ExtensionAdvisor, String> {
+    let principal = caller_account();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous users cannot register".to_string());
+    }
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err("Invalid advisor name".to_string());
+    }
+    if payload.specialties.is_empty()
+        || payload.specialties.len() > MAX_SPECIALTIES
+        || payload
+            .specialties
+            .iter()
+            .any(|specialty| specialty.trim().is_empty() || specialty.len() > MAX_SPECIALTY_LEN)
+    {
+        return Err("Invalid specialties".to_string());
+    }
+    if payload.session_rate == 0 {
+        return Err("Session rate must be positive".to_string());
+    }
+    let region = normalise_region(&payload.region)?;
+
+    // Re-registering keeps the advisor's ratings
+    let (rating_total, rating_count, registered_at) = match get_advisor_record(&principal) {
+        Ok(existing) => (
+            existing.rating_total,
+            existing.rating_count,
+            existing.registered_at,
+        ),
+        Err(_) => (0, 0, ic_cdk::api::time()),
+    };
+    let advisor = ExtensionAdvisor {
+        principal,
+        name: payload.name,
+        kind: payload.kind,
+        specialties: payload.specialties,
+        region,
+        session_rate: payload.session_rate,
+        active: payload.active,
+        rating_total,
+        rating_count,
+        registered_at,
+    };
+
+    save_advisor(advisor.clone());
+    Ok(advisor)
+}
+
+#[ic_cdk::query]
+fn get_extension_advisor(principal: Principal) -> Result<ExtensionAdvisor, String> {
+    get_advisor_record(&principal)
+}
+
+// Searches active advisors, optionally by kind, region and specialty, best rated first
+#[ic_cdk::query]
+fn search_extension_advisors(
+    kind: Option<AdvisorKind>,
+    region: Option<String>,
+    specialty: Option<String>,
+) -> Vec<ExtensionAdvisor> {
+    let region = region.map(|region| region.trim().to_lowercase());
+    let mut advisors: Vec<ExtensionAdvisor> = ADVISORS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, advisor)| advisor)
+            .filter(|advisor| advisor.active)
+            .filter(|advisor| kind.map_or(true, |kind| advisor.kind == kind))
+            .filter(|advisor| {
+                region
+                    .as_ref()
+                    .map_or(true, |region| advisor.region == *region)
+            })
+            .filter(|advisor| {
+                specialty.as_ref().map_or(true, |specialty| {
+                    advisor
+                        .specialties
+                        .iter()
+                        .any(|offered| offered.eq_ignore_ascii_case(specialty.trim()))
+                })
+            })
+            .collect()
+    });
+    // Compare averages as fractions to avoid rounding
+    advisors.sort_by(|a, b| {
+        (b.rating_total * a.rating_count.max(1)).cmp(&(a.rating_total * b.rating_count.max(1)))
+    });
+    advisors
+}
+
+// Function for a farmer to request a session, paying the advisor's rate into escrow
+#[ic_cdk::update]
+async fn request_advisory_session(
+    advisor: Principal,
+    topic: String,
+    scheduled_at: u64,
+) -> Result<AdvisorySession, String> {
+    let advisor = get_advisor_record(&advisor)?;
+    let farmer = caller_account();
+
+    if !advisor.active {
+        return Err("Advisor is not taking bookings".to_string());
+    }
+    if farmer == advisor.principal {
+        return Err("Advisors cannot book themselves".to_string());
+    }
+    if topic.trim().is_empty() || topic.len() > MAX_TOPIC_LEN {
+        return Err("Invalid topic".to_string());
+    }
+    if scheduled_at <= ic_cdk::api::time() {
+        return Err("Session must be in the future".to_string());
+    }
+
+    let mut session = AdvisorySession {
+        id: next_id(&SESSION_ID_COUNTER),
+        advisor: advisor.principal,
+        farmer,
+        topic,
+        scheduled_at,
+        fee: advisor.session_rate,
+        status: SessionStatus::AwaitingPayment,
+        rating: None,
+        created_at: ic_cdk::api::time(),
+        closed_at: None,
+    };
+    save_session(session.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, session.id);
+    if let Err(e) = transfer_into_subaccount(farmer, subaccount, session.fee).await {
+        session.status = SessionStatus::Cancelled;
+        session.closed_at = Some(ic_cdk::api::time());
+        save_session(session);
+        return Err(e);
+    }
+
+    session.status = SessionStatus::Requested;
+    save_session(session.clone());
+    notify(
+        advisor.principal,
+        format!("New session request #{}: {}", session.id, session.topic),
+    );
+    Ok(session)
+}
+
+// Function for the advisor to accept a requested session
+#[ic_cdk::update]
+fn accept_advisory_session(session_id: u64) -> Result<AdvisorySession, String> {
+    let mut session = get_session_record(session_id)?;
+
+    if session.advisor != caller_account() {
+        return Err("Only the advisor can accept this session".to_string());
+    }
+    if session.status != SessionStatus::Requested {
+        return Err("Session is not awaiting acceptance".to_string());
+    }
+
+    session.status = SessionStatus::Accepted;
+    save_session(session.clone());
+    notify(
+        session.farmer,
+        format!("Session #{} was accepted", session.id),
+    );
+    Ok(session)
+}
+
+// Function for the advisor to turn down a requested session, refunding the farmer
+#[ic_cdk::update]
+async fn decline_advisory_session(session_id: u64) -> Result<AdvisorySession, String> {
+    let session = get_session_record(session_id)?;
+
+    if session.advisor != caller_account() {
+        return Err("Only the advisor can decline this session".to_string());
+    }
+    if session.status != SessionStatus::Requested {
+        return Err("Session is not awaiting acceptance".to_string());
+    }
+
+    let farmer = session.farmer;
+    let session = close_session(session, SessionStatus::Declined, farmer).await?;
+    notify(
+        farmer,
+        format!("Session #{} was declined and refunded", session.id),
+    );
+    Ok(session)
+}
+
+// Function for the farmer to cancel a session the advisor has not accepted yet
+#[ic_cdk::update]
+async fn cancel_advisory_session(session_id: u64) -> Result<AdvisorySession, String> {
+    let session = get_session_record(session_id)?;
+
+    if session.farmer != caller_account() {
+        return Err("Only the farmer can cancel this session".to_string());
+    }
+    if session.status != SessionStatus::Requested {
+        return Err("Only sessions not yet accepted can be cancelled".to_string());
+    }
+
+    let farmer = session.farmer;
+    close_session(session, SessionStatus::Cancelled, farmer).await
+}
+
+// Function for the farmer, or the advisor once the confirmation window has passed, to confirm
+// the session took place, releasing the fee to the advisor
+#[ic_cdk::update]
+async fn confirm_advisory_session(session_id: u64) -> Result<AdvisorySession, String> {
+    let session = get_session_record(session_id)?;
+    let caller = caller_account();
+
+    let window_over = session
+        .scheduled_at
+        .saturating_add(CONFIRMATION_WINDOW_NANOS)
+        <= ic_cdk::api::time();
+    if caller != session.farmer && !(caller == session.advisor && window_over) {
+        return Err(
+            "Only the farmer, or the advisor a week after the session, can confirm it".to_string(),
+        );
+    }
+    if session.status != SessionStatus::Accepted {
+        return Err("Session has not been accepted".to_string());
+    }
+
+    let advisor = session.advisor;
+    let session = close_session(session, SessionStatus::Completed, advisor).await?;
+    notify(
+        advisor,
+        format!("Fee for session #{} was released", session.id),
+    );
+    Ok(session)
+}
+
+// Function for the farmer to rate a completed session once, adding to the advisor's rating
+#[ic_cdk::update]
+fn rate_advisory_session(session_id: u64, rating: u8) -> Result<(), String> {
+    let mut session = get_session_record(session_id)?;
+
+    if session.farmer != caller_account() {
+        return Err("Only the farmer can rate this session".to_string());
+    }
+    if session.status != SessionStatus::Completed {
+        return Err("Only completed sessions can be rated".to_string());
+    }
+    if session.rating.is_some() {
+        return Err("Session already rated".to_string());
+    }
+    if !(1..=5).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+    check_can_rate(&session.farmer)?;
+
+    let mut advisor = get_advisor_record(&session.advisor)?;
+    advisor.rating_total += rating as u64;
+    advisor.rating_count += 1;
+    save_advisor(advisor);
+
+    session.rating = Some(rating);
+    save_session(session);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_advisory_session(session_id: u64) -> Result<AdvisorySession, String> {
+    let session = get_session_record(session_id)?;
+    let caller = caller_account();
+
+    if caller != session.farmer && caller != session.advisor {
+        return Err("Session not found".to_string());
+    }
+    Ok(session)
+}
+
+// Lists sessions the caller booked or, as an advisor, was booked for
+#[ic_cdk::query]
+fn get_my_advisory_sessions() -> Vec<AdvisorySession> {
+    let caller = caller_account();
+    SESSIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.farmer == caller || session.advisor == caller)
+            .collect()
+    })
+}
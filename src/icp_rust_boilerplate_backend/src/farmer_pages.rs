@@ -0,0 +1,91 @@
+use crate::reviews::{rating_summary, recent_reviews, RatingSummary, Review};
+use crate::shards::{call_shard, listing_shard, ready_shards};
+use crate::tombstones::is_visible;
+use crate::verification::is_verified_farmer;
+use crate::{farmer_principal, is_off_market, Farmer, FARMERS_STORAGE};
+use candid::Principal;
+
+const RECENT_REVIEWS: usize = 10;
+
+// FarmerPage Struct
+// Everything a farmer's profile view shows, in one response
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FarmerPage {
+    farmer: Principal,
+    // The listing the page was opened from, which carries the farmer's name and bio
+    listing: Farmer,
+    verified: bool,
+    // The farmer's listings still on the market, on this canister and every shard
+    active_listings: Vec<Farmer>,
+    rating: RatingSummary,
+    recent_reviews: Vec<Review>,
+}
+
+fn local_listing(farmer_id: u64) -> Result<Farmer, String> {
+    FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .filter(is_visible)
+        .ok_or("Farmer not found".to_string())
+}
+
+fn active_listings_here(farmer: Principal) -> Vec<Farmer> {
+    let address = farmer.to_text();
+    FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| {
+                listing.address == address
+                    && !listing.is_sold
+                    && !is_off_market(listing)
+                    && is_visible(listing)
+            })
+            .collect()
+    })
+}
+
+// A listing with all its fields, fetched from the shard that holds it if need be
+#[ic_cdk::query(composite = true)]
+async fn get_listing(farmer_id: u64) -> Result<Farmer, String> {
+    if let Some(shard) = listing_shard(farmer_id) {
+        return call_shard(&shard, "get_listing", (farmer_id,)).await;
+    }
+    local_listing(farmer_id)
+}
+
+// A farmer's listings on the market on this canister; the router gathers them from each shard
+#[ic_cdk::query]
+fn get_farmer_listings(farmer: Principal) -> Result<Vec<Farmer>, String> {
+    Ok(active_listings_here(farmer))
+}
+
+// Function to load a farmer's profile page from any of their listings: the listing, their
+// verification, every active listing, their rating summary and latest reviews. A shard that
+// cannot be reached leaves its listings out rather than failing the page.
+#[ic_cdk::query(composite = true)]
+async fn get_farmer_page(farmer_id: u64) -> Result<FarmerPage, String> {
+    let listing = match listing_shard(farmer_id) {
+        Some(shard) => call_shard(&shard, "get_listing", (farmer_id,)).await?,
+        None => local_listing(farmer_id)?,
+    };
+    let farmer = farmer_principal(&listing)?;
+
+    let mut active_listings = active_listings_here(farmer);
+    for shard in ready_shards() {
+        if let Ok(mut listings) =
+            call_shard::<_, Vec<Farmer>>(&shard, "get_farmer_listings", (farmer,)).await
+        {
+            active_listings.append(&mut listings);
+        }
+    }
+
+    Ok(FarmerPage {
+        farmer,
+        listing,
+        verified: is_verified_farmer(&farmer),
+        active_listings,
+        rating: rating_summary(farmer),
+        recent_reviews: recent_reviews(farmer, RECENT_REVIEWS),
+    })
+}
@@ -0,0 +1,106 @@
+use crate::verification::is_verified_farmer;
+use crate::{farmer_principal, is_off_market, Farmer, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Listings drawn into each rotation
+const ROTATION_SIZE: usize = 24;
+
+// FeaturedRotation Struct
+// The current random draw of featured listings
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct FeaturedRotation {
+    listing_ids: Vec<u64>,
+    drawn_at: u64,
+}
+
+// Storable implementation for FeaturedRotation
+impl Storable for FeaturedRotation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static FEATURED_ROTATION: RefCell<Cell<FeaturedRotation, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(126))), FeaturedRotation::default())
+            .expect("Cannot create the featured rotation")
+    );
+}
+
+// Unsold, undisputed listings from verified farmers
+fn is_eligible(farmer: &Farmer) -> bool {
+    !farmer.is_sold
+        && !farmer.dispute_status
+        && !is_off_market(farmer)
+        && farmer_principal(farmer).map_or(false, |owner| is_verified_farmer(&owner))
+}
+
+// The i-th pseudo-random number derived from the seed
+fn random_u64(seed: &[u8], i: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(i.to_be_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// Draws a fresh rotation with randomness from the management canister, so every eligible
+// listing has the same chance of being featured whatever its ID
+pub(crate) async fn refresh_featured() {
+    let seed = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((seed,)) => seed,
+        // Keep the current rotation until the next run
+        Err(_) => return,
+    };
+
+    let mut eligible: Vec<u64> = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, farmer)| is_eligible(farmer))
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    // Partial Fisher-Yates shuffle of the first ROTATION_SIZE places
+    let picks = eligible.len().min(ROTATION_SIZE);
+    for i in 0..picks {
+        let remaining = (eligible.len() - i) as u64;
+        let j = i + (random_u64(&seed, i as u64) % remaining) as usize;
+        eligible.swap(i, j);
+    }
+    eligible.truncate(picks);
+
+    FEATURED_ROTATION.with(|cell| {
+        let _ = cell.borrow_mut().set(FeaturedRotation {
+            listing_ids: eligible,
+            drawn_at: ic_cdk::api::time(),
+        });
+    });
+}
+
+// Up to `n` listings from the current random rotation, skipping any that stopped being
+// eligible since the draw
+#[ic_cdk::query]
+fn get_featured_products(n: u64) -> Vec<Farmer> {
+    let rotation = FEATURED_ROTATION.with(|cell| cell.borrow().get().clone());
+
+    FARMERS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        rotation
+            .listing_ids
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .filter(is_eligible)
+            .take(n as usize)
+            .collect()
+    })
+}
@@ -0,0 +1,224 @@
+use crate::guard::is_paused;
+use crate::{is_farmer_owner, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+const MAX_DISCOUNT_PERCENT: u8 = 90;
+const MAX_SALE_DURATION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// FlashSaleStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FlashSaleStatus {
+    Scheduled,
+    Active,
+    Ended,
+    Cancelled,
+}
+
+// FlashSale Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FlashSale {
+    id: u64,
+    farmer_id: u64,
+    discount_percent: u8,
+    starts_at: u64,
+    ends_at: u64,
+    status: FlashSaleStatus,
+}
+
+// Storable and BoundedStorable implementations for FlashSale
+impl Storable for FlashSale {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FlashSale {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static FLASH_SALE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // At most one sale per listing, keyed by the listing id
+    static FLASH_SALES_STORAGE: RefCell<StableBTreeMap<u64, FlashSale, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+}
+
+fn get_flash_sale_record(farmer_id: u64) -> Option<FlashSale> {
+    FLASH_SALES_STORAGE.with(|storage| storage.borrow().get(&farmer_id))
+}
+
+fn save_flash_sale(sale: FlashSale) {
+    FLASH_SALES_STORAGE.with(|storage| storage.borrow_mut().insert(sale.farmer_id, sale));
+}
+
+// Moves the sale to `to` if it is still the same sale and in the `from` state
+fn transition(farmer_id: u64, sale_id: u64, from: FlashSaleStatus, to: FlashSaleStatus) {
+    // Skipped while paused; the timers are armed again on resume
+    if is_paused() {
+        return;
+    }
+    if let Some(mut sale) = get_flash_sale_record(farmer_id) {
+        if sale.id == sale_id && sale.status == from {
+            sale.status = to;
+            save_flash_sale(sale);
+        }
+    }
+}
+
+// Arms one-shot timers for the sale's pending start and end
+fn arm_timers(sale: &FlashSale) {
+    let now = ic_cdk::api::time();
+    let (farmer_id, sale_id) = (sale.farmer_id, sale.id);
+
+    if sale.status == FlashSaleStatus::Scheduled {
+        let delay = Duration::from_nanos(sale.starts_at.saturating_sub(now));
+        ic_cdk_timers::set_timer(delay, move || {
+            transition(
+                farmer_id,
+                sale_id,
+                FlashSaleStatus::Scheduled,
+                FlashSaleStatus::Active,
+            )
+        });
+    }
+    if sale.status == FlashSaleStatus::Scheduled || sale.status == FlashSaleStatus::Active {
+        let delay = Duration::from_nanos(sale.ends_at.saturating_sub(now));
+        ic_cdk_timers::set_timer(delay, move || {
+            transition(
+                farmer_id,
+                sale_id,
+                FlashSaleStatus::Active,
+                FlashSaleStatus::Ended,
+            )
+        });
+    }
+}
+
+// Re-arms the timers of every pending sale; timers are lost on upgrade
+pub(crate) fn arm_flash_sale_timers() {
+    let pending: Vec<FlashSale> = FLASH_SALES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, sale)| sale)
+            .filter(|sale| {
+                sale.status == FlashSaleStatus::Scheduled || sale.status == FlashSaleStatus::Active
+            })
+            .collect()
+    });
+
+    for sale in pending.iter() {
+        arm_timers(sale);
+    }
+}
+
+// Applies the listing's active flash sale, if any, to a unit price
+pub(crate) fn sale_price(farmer_id: u64, unit_price: u64) -> u64 {
+    match get_flash_sale_record(farmer_id) {
+        Some(sale) if sale.status == FlashSaleStatus::Active => {
+            (unit_price as u128 * (100 - sale.discount_percent) as u128 / 100) as u64
+        }
+        _ => unit_price,
+    }
+}
+
+// Function for a farmer to schedule a time-boxed discount on their listing
+#[ic_cdk::update]
+fn schedule_flash_sale(
+    farmer_id: u64,
+    discount_percent: u8,
+    starts_at: u64,
+    ends_at: u64,
+) -> Result<FlashSale, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can schedule a flash sale".to_string());
+    }
+    if farmer.is_sold {
+        return Err("Product already sold".to_string());
+    }
+    if discount_percent == 0 || discount_percent > MAX_DISCOUNT_PERCENT {
+        return Err("Invalid discount".to_string());
+    }
+    if starts_at < ic_cdk::api::time()
+        || ends_at <= starts_at
+        || ends_at - starts_at > MAX_SALE_DURATION_NANOS
+    {
+        return Err("Invalid sale window".to_string());
+    }
+    if let Some(existing) = get_flash_sale_record(farmer_id) {
+        if existing.status == FlashSaleStatus::Scheduled
+            || existing.status == FlashSaleStatus::Active
+        {
+            return Err("Listing already has a flash sale".to_string());
+        }
+    }
+
+    let sale = FlashSale {
+        id: next_id(&FLASH_SALE_ID_COUNTER),
+        farmer_id,
+        discount_percent,
+        starts_at,
+        ends_at,
+        status: FlashSaleStatus::Scheduled,
+    };
+
+    save_flash_sale(sale.clone());
+    arm_timers(&sale);
+    Ok(sale)
+}
+
+#[ic_cdk::update]
+fn cancel_flash_sale(farmer_id: u64) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can cancel a flash sale".to_string());
+    }
+
+    let mut sale = get_flash_sale_record(farmer_id).ok_or("Flash sale not found".to_string())?;
+    if sale.status != FlashSaleStatus::Scheduled && sale.status != FlashSaleStatus::Active {
+        return Err("Flash sale has already ended".to_string());
+    }
+
+    // Pending timers find the sale cancelled and leave it alone
+    sale.status = FlashSaleStatus::Cancelled;
+    save_flash_sale(sale);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_flash_sale(farmer_id: u64) -> Result<FlashSale, String> {
+    get_flash_sale_record(farmer_id).ok_or("Flash sale not found".to_string())
+}
+
+#[ic_cdk::query]
+fn get_active_flash_sales() -> Vec<FlashSale> {
+    FLASH_SALES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, sale)| sale)
+            .filter(|sale| sale.status == FlashSaleStatus::Active)
+            .collect()
+    })
+}
@@ -0,0 +1,350 @@
+use crate::consumers::is_registered_user;
+use crate::devices::caller_account;
+use crate::moderation::{flag_content, ContentKind};
+use crate::notifications::notify;
+use crate::shipping::normalise_region;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_BODY_LEN: usize = 5_000;
+const MAX_CATEGORY_LEN: usize = 50;
+const DELETED_TEXT: &str = "[deleted]";
+
+// ForumScope Enum
+// Where a question is asked: a produce category, or a region
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) enum ForumScope {
+    Category(String),
+    Region(String),
+}
+
+// ForumQuestion Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ForumQuestion {
+    id: u64,
+    asker: Principal,
+    scope: ForumScope,
+    title: String,
+    body: String,
+    accepted_answer: Option<u64>,
+    answer_count: u64,
+    // Hidden by moderation
+    hidden: bool,
+    created_at: u64,
+}
+
+// ForumAnswer Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ForumAnswer {
+    id: u64,
+    question_id: u64,
+    author: Principal,
+    body: String,
+    hidden: bool,
+    created_at: u64,
+}
+
+// ForumThread Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ForumThread {
+    question: ForumQuestion,
+    answers: Vec<ForumAnswer>,
+}
+
+// Storable and BoundedStorable implementations for ForumQuestion
+impl Storable for ForumQuestion {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ForumQuestion {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ForumAnswer
+impl Storable for ForumAnswer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ForumAnswer {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static QUESTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static QUESTIONS_STORAGE: RefCell<StableBTreeMap<u64, ForumQuestion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+
+    static ANSWER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ANSWERS_STORAGE: RefCell<StableBTreeMap<u64, ForumAnswer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))
+    ));
+}
+
+fn get_question_record(question_id: u64) -> Result<ForumQuestion, String> {
+    QUESTIONS_STORAGE
+        .with(|storage| storage.borrow().get(&question_id))
+        .ok_or("Question not found".to_string())
+}
+
+fn save_question(question: ForumQuestion) {
+    QUESTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(question.id, question));
+}
+
+fn get_answer_record(answer_id: u64) -> Result<ForumAnswer, String> {
+    ANSWERS_STORAGE
+        .with(|storage| storage.borrow().get(&answer_id))
+        .ok_or("Answer not found".to_string())
+}
+
+fn save_answer(answer: ForumAnswer) {
+    ANSWERS_STORAGE.with(|storage| storage.borrow_mut().insert(answer.id, answer));
+}
+
+fn require_registered(principal: &Principal) -> Result<(), String> {
+    if !is_registered_user(principal) {
+        return Err("Only registered users can post in the forum".to_string());
+    }
+    Ok(())
+}
+
+fn validate_body(body: &str) -> Result<(), String> {
+    if body.trim().is_empty() || body.len() > MAX_BODY_LEN {
+        return Err("Invalid post body".to_string());
+    }
+    Ok(())
+}
+
+fn normalise_scope(scope: ForumScope) -> Result<ForumScope, String> {
+    match scope {
+        ForumScope::Category(category) => {
+            let category = category.trim().to_lowercase();
+            if category.is_empty() || category.len() > MAX_CATEGORY_LEN {
+                return Err("Invalid category".to_string());
+            }
+            Ok(ForumScope::Category(category))
+        }
+        ForumScope::Region(region) => Ok(ForumScope::Region(normalise_region(&region)?)),
+    }
+}
+
+// Hides or restores a question or answer on the moderation queue's behalf
+pub(crate) fn set_post_hidden(kind: ContentKind, content_id: u64, hidden: bool) {
+    match kind {
+        ContentKind::ForumQuestion => {
+            if let Ok(mut question) = get_question_record(content_id) {
+                question.hidden = hidden;
+                save_question(question);
+            }
+        }
+        ContentKind::ForumAnswer => {
+            if let Ok(mut answer) = get_answer_record(content_id) {
+                answer.hidden = hidden;
+                save_answer(answer);
+            }
+        }
+    }
+}
+
+// Blanks the questions and answers of a deleted account, keeping threads in place
+pub(crate) fn anonymize_forum_posts(author: &Principal) {
+    let questions: Vec<ForumQuestion> = QUESTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, question)| question)
+            .filter(|question| question.asker == *author)
+            .collect()
+    });
+    for mut question in questions {
+        question.title = DELETED_TEXT.to_string();
+        question.body = DELETED_TEXT.to_string();
+        save_question(question);
+    }
+
+    let answers: Vec<ForumAnswer> = ANSWERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, answer)| answer)
+            .filter(|answer| answer.author == *author)
+            .collect()
+    });
+    for mut answer in answers {
+        answer.body = DELETED_TEXT.to_string();
+        save_answer(answer);
+    }
+}
+
+// Function for a registered user to ask a question in a category or region
+#[ic_cdk::update]
+fn ask_question(scope: ForumScope, title: String, body: String) -> Result<ForumQuestion, String> {
+    let asker = caller_account();
+    require_registered(&asker)?;
+
+    if title.trim().is_empty() || title.len() > MAX_TITLE_LEN {
+        return Err("Invalid title".to_string());
+    }
+    validate_body(&body)?;
+
+    let question = ForumQuestion {
+        id: next_id(&QUESTION_ID_COUNTER),
+        asker,
+        scope: normalise_scope(scope)?,
+        title,
+        body,
+        accepted_answer: None,
+        answer_count: 0,
+        hidden: false,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_question(question.clone());
+    Ok(question)
+}
+
+// Function for a registered user to answer a question
+#[ic_cdk::update]
+fn answer_question(question_id: u64, body: String) -> Result<ForumAnswer, String> {
+    let mut question = get_question_record(question_id)?;
+    let author = caller_account();
+    require_registered(&author)?;
+
+    if question.hidden {
+        return Err("Question not found".to_string());
+    }
+    validate_body(&body)?;
+
+    let answer = ForumAnswer {
+        id: next_id(&ANSWER_ID_COUNTER),
+        question_id,
+        author,
+        body,
+        hidden: false,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_answer(answer.clone());
+    question.answer_count += 1;
+    save_question(question.clone());
+    if question.asker != author {
+        notify(
+            question.asker,
+            format!("Your question \"{}\" has a new answer", question.title),
+        );
+    }
+    Ok(answer)
+}
+
+// Function for the asker to mark the answer that solved their question
+#[ic_cdk::update]
+fn accept_answer(answer_id: u64) -> Result<ForumQuestion, String> {
+    let answer = get_answer_record(answer_id)?;
+    let mut question = get_question_record(answer.question_id)?;
+
+    if question.asker != caller_account() {
+        return Err("Only the asker can accept an answer".to_string());
+    }
+    if answer.hidden {
+        return Err("Answer not found".to_string());
+    }
+
+    question.accepted_answer = Some(answer.id);
+    save_question(question.clone());
+    if answer.author != question.asker {
+        notify(
+            answer.author,
+            format!("Your answer to \"{}\" was accepted", question.title),
+        );
+    }
+    Ok(question)
+}
+
+// Function for a registered user to flag a question as spam or abuse
+#[ic_cdk::update]
+fn flag_question(question_id: u64, reason: String) -> Result<(), String> {
+    let question = get_question_record(question_id)?;
+    let flagger = caller_account();
+    require_registered(&flagger)?;
+
+    if question.asker == flagger {
+        return Err("You cannot flag your own post".to_string());
+    }
+    flag_content(ContentKind::ForumQuestion, question_id, flagger, reason)
+}
+
+// Function for a registered user to flag an answer as spam or abuse
+#[ic_cdk::update]
+fn flag_answer(answer_id: u64, reason: String) -> Result<(), String> {
+    let answer = get_answer_record(answer_id)?;
+    let flagger = caller_account();
+    require_registered(&flagger)?;
+
+    if answer.author == flagger {
+        return Err("You cannot flag your own post".to_string());
+    }
+    flag_content(ContentKind::ForumAnswer, answer_id, flagger, reason)
+}
+
+// Lists visible questions in a category or region, newest first
+#[ic_cdk::query]
+fn list_questions(scope: ForumScope) -> Result<Vec<ForumQuestion>, String> {
+    let scope = normalise_scope(scope)?;
+    Ok(QUESTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, question)| question)
+            .filter(|question| !question.hidden && question.scope == scope)
+            .collect()
+    }))
+}
+
+// A question with its visible answers, the accepted answer first
+#[ic_cdk::query]
+fn get_question_thread(question_id: u64) -> Result<ForumThread, String> {
+    let question = get_question_record(question_id)?;
+    if question.hidden {
+        return Err("Question not found".to_string());
+    }
+
+    let mut answers: Vec<ForumAnswer> = ANSWERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, answer)| answer)
+            .filter(|answer| answer.question_id == question_id && !answer.hidden)
+            .collect()
+    });
+    answers.sort_by_key(|answer| Some(answer.id) != question.accepted_answer);
+    Ok(ForumThread { question, answers })
+}
@@ -0,0 +1,134 @@
+use crate::arbiters::set_min_arbiter_stake;
+use crate::archive::set_archive_canister;
+use crate::cart::set_checkout_fee;
+use crate::events::log_event;
+use crate::guard::set_paused;
+use crate::ledger::{set_asset_ledger, set_ledger_canister, Asset};
+use crate::loyalty::set_loyalty_rates;
+use crate::referrals::set_referral_rewards;
+use crate::shards::set_listing_capacity;
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+// GovernanceAction Enum
+// A governance-sensitive change, as carried by a DAO proposal
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum GovernanceAction {
+    SetCheckoutFee {
+        fee_bps: u64,
+    },
+    SetLoyaltyRates {
+        earn_rate_bps: u64,
+        point_value: u64,
+    },
+    SetReferralRewards {
+        referrer_points: u64,
+        referee_points: u64,
+    },
+    SetPaused {
+        paused: bool,
+    },
+    SetLedgerCanister {
+        ledger: Principal,
+    },
+    SetArchiveCanister {
+        archive: Principal,
+    },
+    SetListingCapacity {
+        capacity: u64,
+    },
+    SetMinArbiterStake {
+        amount: u64,
+    },
+    SetAssetLedger {
+        asset: Asset,
+        ledger: Principal,
+    },
+}
+
+thread_local! {
+    // Principal of the governance canister, e.g. an SNS; empty while controllers govern
+    static GOVERNANCE_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(149))), Vec::new())
+            .expect("Cannot create the governance cell")
+    );
+}
+
+fn governance_canister() -> Option<Principal> {
+    let bytes = GOVERNANCE_CANISTER.with(|cell| cell.borrow().get().clone());
+    if bytes.is_empty() {
+        return None;
+    }
+    Principal::try_from_slice(&bytes).ok()
+}
+
+// Whether the caller may make governance-sensitive changes: the governance canister once
+// one is set, the controllers until then
+pub(crate) fn is_governor() -> bool {
+    match governance_canister() {
+        Some(governance) => ic_cdk::caller() == governance,
+        None => is_admin(),
+    }
+}
+
+// Function to hand governance to a DAO canister, or pass it on to another. Controllers
+// make the first hand-off; after that only the current governance canister can, and
+// passing None returns governance to the controllers.
+#[ic_cdk::update]
+fn set_governance_canister(governance: Option<Principal>) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can hand over governance".to_string());
+    }
+
+    let bytes = governance.map_or(Vec::new(), |g| g.as_slice().to_vec());
+    GOVERNANCE_CANISTER
+        .with(|cell| cell.borrow_mut().set(bytes))
+        .map_err(|e| format!("{:?}", e))?;
+    log_event("governance_handover", format!("{:?}", governance));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_governance_canister() -> Option<Principal> {
+    governance_canister()
+}
+
+// Proposal validator: checks the caller and renders the action for voters
+#[ic_cdk::update]
+fn validate_governance_action(action: GovernanceAction) -> Result<String, String> {
+    if !is_governor() {
+        return Err("Only governance can propose governance actions".to_string());
+    }
+    Ok(format!("{:?}", action))
+}
+
+// Proposal execution callback: applies an adopted action and records it in the event log
+#[ic_cdk::update]
+fn execute_governance_action(action: GovernanceAction) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can execute governance actions".to_string());
+    }
+
+    match action.clone() {
+        GovernanceAction::SetCheckoutFee { fee_bps } => set_checkout_fee(fee_bps),
+        GovernanceAction::SetLoyaltyRates {
+            earn_rate_bps,
+            point_value,
+        } => set_loyalty_rates(earn_rate_bps, point_value),
+        GovernanceAction::SetReferralRewards {
+            referrer_points,
+            referee_points,
+        } => set_referral_rewards(referrer_points, referee_points),
+        GovernanceAction::SetPaused { paused } => set_paused(paused),
+        GovernanceAction::SetLedgerCanister { ledger } => set_ledger_canister(ledger),
+        GovernanceAction::SetArchiveCanister { archive } => set_archive_canister(archive),
+        GovernanceAction::SetListingCapacity { capacity } => set_listing_capacity(capacity),
+        GovernanceAction::SetMinArbiterStake { amount } => set_min_arbiter_stake(amount),
+        GovernanceAction::SetAssetLedger { asset, ledger } => set_asset_ledger(asset, ledger),
+    }?;
+    log_event("governance_action", format!("{:?}", action));
+    Ok(())
+}
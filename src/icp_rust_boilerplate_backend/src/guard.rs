@@ -0,0 +1,187 @@
+use crate::devices::caller_account;
+use crate::events::log_event;
+use crate::flash_sales::arm_flash_sale_timers;
+use crate::governance::is_governor;
+use crate::{is_admin, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+
+pub(crate) const PAUSE_MEMORY_ID: u8 = 135;
+const MAX_REASON_LEN: usize = 200;
+
+// Calls blacklisted principals cannot make: bidding, listing and messaging
+const BLACKLISTED_METHODS: &[&str] = &[
+    "product_bid",
+    "commit_sealed_bid",
+    "bid_on_transport_job",
+    "add_product",
+    "list_equipment",
+    "create_supply_listing",
+    "create_storage_listing",
+    "post_transport_job",
+    "post_labor_job",
+    "ask_question",
+    "answer_question",
+    "submit_review",
+];
+
+// BlacklistEntry Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct BlacklistEntry {
+    principal: Principal,
+    reason: String,
+    added_by: Principal,
+    added_at: u64,
+}
+
+// Storable and BoundedStorable implementations for BlacklistEntry
+impl Storable for BlacklistEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BlacklistEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Non-zero while the marketplace is paused
+    static PAUSED: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(PAUSE_MEMORY_ID))), 0)
+            .expect("Cannot create the pause switch")
+    );
+
+    // Set after a restore; kept on the heap so that the upgrade that reloads the maps clears it
+    static AWAITING_UPGRADE: Cell<bool> = Cell::new(false);
+
+    static BLACKLIST_STORAGE: RefCell<StableBTreeMap<PrincipalKey, BlacklistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(185)))
+    ));
+}
+
+pub(crate) fn is_paused() -> bool {
+    awaiting_upgrade() || PAUSED.with(|cell| *cell.borrow().get() != 0)
+}
+
+fn awaiting_upgrade() -> bool {
+    AWAITING_UPGRADE.with(|flag| flag.get())
+}
+
+pub(crate) fn require_upgrade() {
+    AWAITING_UPGRADE.with(|flag| flag.set(true));
+}
+
+fn is_blacklisted(principal: Principal) -> bool {
+    BLACKLIST_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(principal)))
+}
+
+// Whether the call is one a blacklisted caller is barred from
+fn is_blacklisted_call() -> bool {
+    let method = ic_cdk::api::call::method_name();
+    BLACKLISTED_METHODS.contains(&method.as_str()) && is_blacklisted(caller_account())
+}
+
+// Rejects update calls from anyone but a controller while the marketplace is paused, and
+// from everyone while a restore waits for the upgrade. Blacklisted principals cannot bid,
+// list or post messages.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if !awaiting_upgrade() && (!is_paused() || is_admin()) && !is_blacklisted_call() {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+// Function for a controller to pause or resume the marketplace. While paused, only
+// controllers can make update calls and the maintenance timers stand still.
+#[ic_cdk::update]
+pub(crate) fn set_paused(paused: bool) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can pause the marketplace".to_string());
+    }
+    if awaiting_upgrade() {
+        return Err("Upgrade the canister to finish the restore first".to_string());
+    }
+
+    PAUSED
+        .with(|cell| cell.borrow_mut().set(paused as u64))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))?;
+    if !paused {
+        // Flash sale transitions skipped while paused fire now
+        arm_flash_sale_timers();
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn is_marketplace_paused() -> bool {
+    is_paused()
+}
+
+// Function for an administrator to bar a principal from bidding, listing and messaging
+#[ic_cdk::update]
+fn add_to_blacklist(principal: Principal, reason: String) -> Result<BlacklistEntry, String> {
+    if !is_admin() {
+        return Err("Only an administrator can blacklist principals".to_string());
+    }
+    blacklist(principal, reason)
+}
+
+// Adds a principal to the blacklist and logs why; callers check permissions
+pub(crate) fn blacklist(principal: Principal, reason: String) -> Result<BlacklistEntry, String> {
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err("A reason of up to 200 characters is required".to_string());
+    }
+    if is_blacklisted(principal) {
+        return Err("Principal is already blacklisted".to_string());
+    }
+
+    let entry = BlacklistEntry {
+        principal,
+        reason: reason.clone(),
+        added_by: ic_cdk::caller(),
+        added_at: ic_cdk::api::time(),
+    };
+    BLACKLIST_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), entry.clone())
+    });
+    log_event("blacklist_add", format!("{}: {}", principal, reason));
+    Ok(entry)
+}
+
+#[ic_cdk::update]
+fn remove_from_blacklist(principal: Principal, reason: String) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can blacklist principals".to_string());
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err("A reason of up to 200 characters is required".to_string());
+    }
+
+    BLACKLIST_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(principal)))
+        .ok_or("Principal is not blacklisted".to_string())?;
+    log_event("blacklist_remove", format!("{}: {}", principal, reason));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_blacklist() -> Result<Vec<BlacklistEntry>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view the blacklist".to_string());
+    }
+
+    Ok(BLACKLIST_STORAGE.with(|storage| storage.borrow().iter().map(|(_, entry)| entry).collect()))
+}
@@ -0,0 +1,175 @@
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_KEY_LEN: usize = 64;
+// Replays within this window get the original result back
+const RETENTION_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// A call still marked in progress after this long trapped; its key may be used again
+const IN_PROGRESS_TIMEOUT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_PRUNE_BATCH: usize = 500;
+// Larger results are not kept, leaving room in the record for the rest of its fields
+const MAX_RESPONSE_LEN: usize = 3072;
+
+// IdempotencyRecord Struct
+// The outcome of one keyed call, replayed to retries of it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct IdempotencyRecord {
+    caller: Principal,
+    method: String,
+    key: String,
+    // Candid-encoded result; none while the call is still running
+    response: Option<Vec<u8>>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for IdempotencyRecord
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<u64, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(187)))
+    ));
+}
+
+fn record_id(caller: Principal, method: &str, key: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update(b"\n");
+    hasher.update(method.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(key.as_bytes());
+    let digest = hasher.finalize();
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(id)
+}
+
+fn live_record(id: u64, caller: Principal, method: &str, key: &str) -> Option<IdempotencyRecord> {
+    let now = ic_cdk::api::time();
+    IDEMPOTENCY_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .filter(|record| {
+            record.caller == caller
+                && record.method == method
+                && record.key == key
+                && now < record.created_at + RETENTION_NANOS
+                && (record.response.is_some()
+                    || now < record.created_at + IN_PROGRESS_TIMEOUT_NANOS)
+        })
+}
+
+// Starts a keyed call. Returns the original result for a replay, or none after claiming the
+// key, in which case the caller runs the call and hands its result to `finish`. Calls without
+// a key always run.
+pub(crate) fn begin<T: CandidType + DeserializeOwned>(
+    method: &str,
+    key: &Option<String>,
+) -> Result<Option<T>, String> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "Idempotency key must be 1 to {} characters",
+            MAX_KEY_LEN
+        ));
+    }
+
+    let caller = ic_cdk::caller();
+    let id = record_id(caller, method, key);
+    if let Some(record) = live_record(id, caller, method, key) {
+        return match record.response {
+            Some(bytes) => candid::decode_one(&bytes)
+                .map(Some)
+                .map_err(|e| format!("Cannot decode the stored result: {}", e)),
+            None => Err("A call with this idempotency key is still in progress".to_string()),
+        };
+    }
+
+    let record = IdempotencyRecord {
+        caller,
+        method: method.to_string(),
+        key: key.clone(),
+        response: None,
+        created_at: ic_cdk::api::time(),
+    };
+    IDEMPOTENCY_STORAGE.with(|storage| storage.borrow_mut().insert(id, record));
+    Ok(None)
+}
+
+// Stores the result of a keyed call for its replays
+pub(crate) fn finish<T: CandidType>(method: &str, key: &Option<String>, result: &T) {
+    let key = match key {
+        Some(key) => key,
+        None => return,
+    };
+    let id = record_id(ic_cdk::caller(), method, key);
+    let mut record = match IDEMPOTENCY_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(record) => record,
+        None => return,
+    };
+    match candid::encode_one(result) {
+        Ok(bytes) if bytes.len() <= MAX_RESPONSE_LEN => {
+            record.response = Some(bytes);
+            IDEMPOTENCY_STORAGE.with(|storage| storage.borrow_mut().insert(id, record));
+        }
+        // Too large to keep; the key is released rather than left in progress
+        _ => {
+            IDEMPOTENCY_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+        }
+    }
+}
+
+// Runs a synchronous call once per key
+pub(crate) fn run_once<T: CandidType + DeserializeOwned>(
+    method: &str,
+    key: Option<String>,
+    call: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    if let Some(previous) = begin(method, &key)? {
+        return previous;
+    }
+    let result = call();
+    finish(method, &key, &result);
+    result
+}
+
+// Drops records past the retention window
+pub(crate) fn prune_idempotency_keys() {
+    let cutoff = ic_cdk::api::time().saturating_sub(RETENTION_NANOS);
+    let expired: Vec<u64> = IDEMPOTENCY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.created_at < cutoff)
+            .map(|(id, _)| id)
+            .take(MAX_PRUNE_BATCH)
+            .collect()
+    });
+    IDEMPOTENCY_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in expired {
+            storage.remove(&id);
+        }
+    });
+}
@@ -0,0 +1,427 @@
+use crate::devices::caller_account;
+use crate::{is_admin, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_PROVIDER_NAME_LEN: usize = 100;
+const MAX_EVIDENCE_LEN: usize = 200;
+
+// IdentityLink Struct
+// An Internet Identity principal linked to an account. The link is confirmed only when the
+// Internet Identity principal itself accepts it.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct IdentityLink {
+    account: Principal,
+    identity: Principal,
+    requested_at: u64,
+    confirmed_at: Option<u64>,
+}
+
+// PersonhoodProvider Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PersonhoodProvider {
+    principal: Principal,
+    name: String,
+    added_at: u64,
+}
+
+// PersonhoodAttestation Struct
+// A provider's statement that an account belongs to a unique person
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PersonhoodAttestation {
+    account: Principal,
+    provider: Principal,
+    // The provider's own reference for the check, never personal data
+    evidence_ref: String,
+    attested_at: u64,
+    expires_at: Option<u64>,
+}
+
+// SybilPolicy Struct
+// Actions that need a linked Internet Identity or a personhood attestation
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SybilPolicy {
+    ratings: bool,
+    dispute_votes: bool,
+    referral_rewards: bool,
+}
+
+impl Default for SybilPolicy {
+    fn default() -> Self {
+        Self {
+            ratings: true,
+            dispute_votes: true,
+            referral_rewards: true,
+        }
+    }
+}
+
+// Storable and BoundedStorable implementations for IdentityLink
+impl Storable for IdentityLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdentityLink {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for PersonhoodProvider
+impl Storable for PersonhoodProvider {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PersonhoodProvider {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for PersonhoodAttestation
+impl Storable for PersonhoodAttestation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PersonhoodAttestation {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable implementation for SybilPolicy
+impl Storable for SybilPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Account to its Internet Identity link, pending or confirmed
+    static IDENTITY_LINKS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, IdentityLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(162)))
+    ));
+
+    // Confirmed Internet Identity principal to its account, so each links only one account
+    static LINKED_IDENTITIES_STORAGE: RefCell<StableBTreeMap<PrincipalKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(163)))
+    ));
+
+    static PERSONHOOD_PROVIDERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, PersonhoodProvider, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(164)))
+    ));
+
+    static ATTESTATIONS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, PersonhoodAttestation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(165)))
+    ));
+
+    static SYBIL_POLICY: RefCell<Cell<SybilPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(166))), SybilPolicy::default())
+            .expect("Cannot create the sybil policy")
+    );
+}
+
+fn sybil_policy() -> SybilPolicy {
+    SYBIL_POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+fn identity_link(account: &Principal) -> Option<IdentityLink> {
+    IDENTITY_LINKS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*account)))
+}
+
+fn has_linked_identity(account: &Principal) -> bool {
+    identity_link(account).map_or(false, |link| link.confirmed_at.is_some())
+}
+
+fn has_valid_attestation(account: &Principal) -> bool {
+    let now = ic_cdk::api::time();
+    ATTESTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(*account)))
+        .map_or(false, |attestation| {
+            attestation
+                .expires_at
+                .map_or(true, |expires_at| now < expires_at)
+        })
+}
+
+// Whether the account has a confirmed Internet Identity link or a current attestation
+pub(crate) fn is_sybil_resistant(account: &Principal) -> bool {
+    has_linked_identity(account) || has_valid_attestation(account)
+}
+
+fn require_sybil_resistant(account: &Principal, required: bool) -> Result<(), String> {
+    if required && !is_sybil_resistant(account) {
+        return Err("Link an Internet Identity or get a personhood attestation first".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn check_can_rate(account: &Principal) -> Result<(), String> {
+    require_sybil_resistant(account, sybil_policy().ratings)
+}
+
+pub(crate) fn check_can_vote_on_disputes(account: &Principal) -> Result<(), String> {
+    require_sybil_resistant(account, sybil_policy().dispute_votes)
+}
+
+pub(crate) fn can_earn_referral_rewards(account: &Principal) -> bool {
+    !sybil_policy().referral_rewards || is_sybil_resistant(account)
+}
+
+// Function for an account to start linking an Internet Identity principal, replacing any
+// earlier link. The link counts once `confirm_identity_link` is called from that identity.
+#[ic_cdk::update]
+fn link_internet_identity(identity: Principal) -> Result<IdentityLink, String> {
+    let account = caller_account();
+    if account == Principal::anonymous() || identity == Principal::anonymous() {
+        return Err("Anonymous principals cannot be linked".to_string());
+    }
+    if identity == account {
+        return Err("Link a different principal from this account".to_string());
+    }
+    if LINKED_IDENTITIES_STORAGE
+        .with(|storage| storage.borrow().contains_key(&PrincipalKey(identity)))
+    {
+        return Err("Identity is already linked to an account".to_string());
+    }
+
+    unlink(&account);
+    let link = IdentityLink {
+        account,
+        identity,
+        requested_at: ic_cdk::api::time(),
+        confirmed_at: None,
+    };
+    IDENTITY_LINKS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account), link.clone())
+    });
+    Ok(link)
+}
+
+// Function for an Internet Identity principal to accept a pending link from an account
+#[ic_cdk::update]
+fn confirm_identity_link(account: Principal) -> Result<IdentityLink, String> {
+    let identity = ic_cdk::caller();
+    let mut link = identity_link(&account).ok_or("No link was requested".to_string())?;
+    if link.identity != identity {
+        return Err("The link was requested for another identity".to_string());
+    }
+    if link.confirmed_at.is_some() {
+        return Err("Link already confirmed".to_string());
+    }
+    if LINKED_IDENTITIES_STORAGE
+        .with(|storage| storage.borrow().contains_key(&PrincipalKey(identity)))
+    {
+        return Err("Identity is already linked to an account".to_string());
+    }
+
+    link.confirmed_at = Some(ic_cdk::api::time());
+    IDENTITY_LINKS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account), link.clone())
+    });
+    LINKED_IDENTITIES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(identity), PrincipalKey(account))
+    });
+    Ok(link)
+}
+
+fn unlink(account: &Principal) {
+    if let Some(link) =
+        IDENTITY_LINKS_STORAGE.with(|storage| storage.borrow_mut().remove(&PrincipalKey(*account)))
+    {
+        if link.confirmed_at.is_some() {
+            LINKED_IDENTITIES_STORAGE
+                .with(|storage| storage.borrow_mut().remove(&PrincipalKey(link.identity)));
+        }
+    }
+}
+
+// Drops the identity link and attestation of a deleted account
+pub(crate) fn forget_identity(account: &Principal) {
+    unlink(account);
+    ATTESTATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&PrincipalKey(*account)));
+}
+
+// Function for an account to remove its Internet Identity link
+#[ic_cdk::update]
+fn unlink_internet_identity() -> Result<(), String> {
+    let account = caller_account();
+    identity_link(&account).ok_or("No identity is linked".to_string())?;
+    unlink(&account);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_identity_link(account: Principal) -> Option<IdentityLink> {
+    identity_link(&account)
+}
+
+// Function for an administrator to trust a proof-of-personhood service to attest accounts
+#[ic_cdk::update]
+fn add_personhood_provider(
+    principal: Principal,
+    name: String,
+) -> Result<PersonhoodProvider, String> {
+    if !is_admin() {
+        return Err("Only an administrator can add personhood providers".to_string());
+    }
+    if name.trim().is_empty() || name.len() > MAX_PROVIDER_NAME_LEN {
+        return Err("Invalid provider name".to_string());
+    }
+
+    let provider = PersonhoodProvider {
+        principal,
+        name,
+        added_at: ic_cdk::api::time(),
+    };
+    PERSONHOOD_PROVIDERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), provider.clone())
+    });
+    Ok(provider)
+}
+
+// Function for an administrator to stop trusting a provider; its attestations stop counting
+#[ic_cdk::update]
+fn remove_personhood_provider(principal: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can remove personhood providers".to_string());
+    }
+
+    PERSONHOOD_PROVIDERS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(principal)))
+        .ok_or("Provider not found".to_string())?;
+    let attested: Vec<PrincipalKey> = ATTESTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, attestation)| attestation.provider == principal)
+            .map(|(account, _)| account)
+            .collect()
+    });
+    ATTESTATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for account in attested {
+            storage.remove(&account);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_personhood_providers() -> Vec<PersonhoodProvider> {
+    PERSONHOOD_PROVIDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, provider)| provider)
+            .collect()
+    })
+}
+
+// Function for a trusted provider to attest that an account is a unique person
+#[ic_cdk::update]
+fn attest_personhood(
+    account: Principal,
+    evidence_ref: String,
+    expires_at: Option<u64>,
+) -> Result<PersonhoodAttestation, String> {
+    let provider = ic_cdk::caller();
+    if !PERSONHOOD_PROVIDERS_STORAGE
+        .with(|storage| storage.borrow().contains_key(&PrincipalKey(provider)))
+    {
+        return Err("Only a trusted personhood provider can attest accounts".to_string());
+    }
+    if account == provider {
+        return Err("Providers cannot attest themselves".to_string());
+    }
+    if evidence_ref.len() > MAX_EVIDENCE_LEN {
+        return Err("Evidence reference is too long".to_string());
+    }
+    let now = ic_cdk::api::time();
+    if matches!(expires_at, Some(expires_at) if expires_at <= now) {
+        return Err("Expiry must be in the future".to_string());
+    }
+
+    let attestation = PersonhoodAttestation {
+        account,
+        provider,
+        evidence_ref,
+        attested_at: now,
+        expires_at,
+    };
+    ATTESTATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account), attestation.clone())
+    });
+    Ok(attestation)
+}
+
+// Function for the attesting provider or an administrator to withdraw an attestation
+#[ic_cdk::update]
+fn revoke_personhood(account: Principal) -> Result<(), String> {
+    let attestation = ATTESTATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(account)))
+        .ok_or("Account has no attestation".to_string())?;
+    if attestation.provider != ic_cdk::caller() && !is_admin() {
+        return Err("Only the provider or an administrator can revoke attestations".to_string());
+    }
+
+    ATTESTATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&PrincipalKey(account)));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_personhood_attestation(account: Principal) -> Option<PersonhoodAttestation> {
+    ATTESTATIONS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(account)))
+}
+
+#[ic_cdk::update]
+fn set_sybil_policy(policy: SybilPolicy) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set the sybil policy".to_string());
+    }
+
+    SYBIL_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_sybil_policy() -> SybilPolicy {
+    sybil_policy()
+}
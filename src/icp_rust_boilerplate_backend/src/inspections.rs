@@ -0,0 +1,252 @@
+use crate::deliveries::{get_delivery_record, is_sha256_hex};
+use crate::devices::caller_account;
+use crate::disputes::open_dispute;
+use crate::notifications::notify;
+use crate::orders::{complete_order, get_order_record, OrderStatus};
+use crate::{is_admin, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_REPORT_LEN: usize = 500;
+
+// Inspector Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Inspector {
+    principal: Principal,
+    name: String,
+    registered_at: u64,
+}
+
+// InspectionStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InspectionStatus {
+    Requested,
+    Passed,
+    Failed,
+}
+
+// Inspection Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Inspection {
+    order_id: u64,
+    inspector: Principal,
+    requested_by: Principal,
+    status: InspectionStatus,
+    report: Option<String>,
+    evidence_hash: Option<String>,
+    dispute_id: Option<u64>,
+    requested_at: u64,
+    reported_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for Inspector
+impl Storable for Inspector {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Inspector {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Inspection
+impl Storable for Inspection {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Inspection {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static INSPECTORS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Inspector, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // At most one inspection per order, keyed by the order id
+    static INSPECTIONS_STORAGE: RefCell<StableBTreeMap<u64, Inspection, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+}
+
+pub(crate) fn get_inspection_record(order_id: u64) -> Option<Inspection> {
+    INSPECTIONS_STORAGE.with(|storage| storage.borrow().get(&order_id))
+}
+
+fn save_inspection(inspection: Inspection) {
+    INSPECTIONS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(inspection.order_id, inspection));
+}
+
+// Flagged orders are held back from automatic release until the inspector reports
+pub(crate) fn inspection_pending(order_id: u64) -> bool {
+    get_inspection_record(order_id).map_or(false, |inspection| {
+        inspection.status == InspectionStatus::Requested
+    })
+}
+
+// Function for an administrator to register a third-party quality inspector
+#[ic_cdk::update]
+fn register_inspector(principal: Principal, name: String) -> Result<Inspector, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register inspectors".to_string());
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err("Invalid inspector name".to_string());
+    }
+
+    let inspector = Inspector {
+        principal,
+        name,
+        registered_at: ic_cdk::api::time(),
+    };
+    INSPECTORS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), inspector.clone())
+    });
+    Ok(inspector)
+}
+
+#[ic_cdk::query]
+fn get_inspector(principal: Principal) -> Result<Inspector, String> {
+    INSPECTORS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Inspector not found".to_string())
+}
+
+// Function for the payer to flag an order for inspection before its escrow is released
+#[ic_cdk::update]
+fn request_inspection(order_id: u64, inspector: Principal) -> Result<Inspection, String> {
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+
+    if order.payer() != caller {
+        return Err("Only the payer can request an inspection".to_string());
+    }
+    if order.status != OrderStatus::Funded && order.status != OrderStatus::Shipped {
+        return Err("Only funded orders awaiting delivery can be inspected".to_string());
+    }
+    if get_inspection_record(order_id).is_some() {
+        return Err("Order already has an inspection".to_string());
+    }
+    if !INSPECTORS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(inspector))) {
+        return Err("Inspector not found".to_string());
+    }
+
+    let inspection = Inspection {
+        order_id,
+        inspector,
+        requested_by: caller,
+        status: InspectionStatus::Requested,
+        report: None,
+        evidence_hash: None,
+        dispute_id: None,
+        requested_at: ic_cdk::api::time(),
+        reported_at: None,
+    };
+
+    save_inspection(inspection.clone());
+    notify(
+        inspector,
+        format!("You were asked to inspect order #{}", order_id),
+    );
+    Ok(inspection)
+}
+
+// Function for the inspector to file a pass/fail report. A pass on delivered goods releases the
+// order; a fail opens a dispute that carries the report
+#[ic_cdk::update]
+fn submit_inspection_report(
+    order_id: u64,
+    passed: bool,
+    report: String,
+    evidence_hash: Option<String>,
+) -> Result<Inspection, String> {
+    let mut inspection =
+        get_inspection_record(order_id).ok_or("Inspection not found".to_string())?;
+    let inspector = caller_account();
+
+    if inspection.inspector != inspector {
+        return Err("Only the assigned inspector can report".to_string());
+    }
+    if inspection.status != InspectionStatus::Requested {
+        return Err("Inspection already reported".to_string());
+    }
+    if report.trim().is_empty() || report.len() > MAX_REPORT_LEN {
+        return Err("Invalid report".to_string());
+    }
+    if evidence_hash
+        .as_ref()
+        .map_or(false, |hash| !is_sha256_hex(hash))
+    {
+        return Err("Evidence must be a hex SHA-256 digest".to_string());
+    }
+
+    let order = get_order_record(order_id)?;
+    if passed {
+        inspection.status = InspectionStatus::Passed;
+        let delivered =
+            get_delivery_record(order_id).map_or(false, |delivery| delivery.delivered_at.is_some());
+        if delivered && order.status == OrderStatus::Shipped {
+            complete_order(order);
+        }
+    } else {
+        let dispute_id = open_dispute(
+            order_id,
+            inspector,
+            format!("Quality inspection failed: {}", report),
+        )?;
+        inspection.status = InspectionStatus::Failed;
+        inspection.dispute_id = Some(dispute_id);
+        notify(
+            order.farmer,
+            format!("Order #{} failed quality inspection", order_id),
+        );
+    }
+
+    inspection.report = Some(report);
+    inspection.evidence_hash = evidence_hash.map(|hash| hash.to_lowercase());
+    inspection.reported_at = Some(ic_cdk::api::time());
+    save_inspection(inspection.clone());
+    notify(
+        inspection.requested_by,
+        format!("Inspection of order #{}: {:?}", order_id, inspection.status),
+    );
+    Ok(inspection)
+}
+
+#[ic_cdk::query]
+fn get_inspection(order_id: u64) -> Result<Inspection, String> {
+    let inspection = get_inspection_record(order_id).ok_or("Inspection not found".to_string())?;
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+
+    if caller != inspection.inspector
+        && caller != order.farmer
+        && caller != order.consumer
+        && caller != order.payer()
+        && !is_admin()
+    {
+        return Err("Inspection not found".to_string());
+    }
+    Ok(inspection)
+}
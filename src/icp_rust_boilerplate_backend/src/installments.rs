@@ -0,0 +1,374 @@
+use crate::devices::caller_account;
+use crate::ledger::Asset;
+use crate::notifications::notify;
+use crate::order_escrow::{collect_order_payment, refund_order_escrow};
+use crate::orders::{get_order_record, save_order, Order, OrderStatus};
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_INSTALLMENTS: usize = 12;
+// How long after its due date an unpaid installment counts as missed
+const GRACE_PERIOD_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+// A payment still claimed after this long, well past any ledger round trip, trapped mid-way
+const STALLED_PAYMENT_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+// Installment Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Installment {
+    amount: u64,
+    due_at: u64,
+    paid_at: Option<u64>,
+    missed: bool,
+}
+
+// InstallmentPlan Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct InstallmentPlan {
+    installments: Vec<Installment>,
+    delivered_quantity: u64,
+    // When the payment of the next unpaid installment started, while it is in flight
+    paying_since: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for InstallmentPlan
+impl Storable for InstallmentPlan {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InstallmentPlan {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by order ID; kept out of the order so it stays within its size bound
+    static INSTALLMENT_PLANS: RefCell<StableBTreeMap<u64, InstallmentPlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(235)))
+    ));
+}
+
+fn installment_plan(order_id: u64) -> Option<InstallmentPlan> {
+    INSTALLMENT_PLANS.with(|storage| storage.borrow().get(&order_id))
+}
+
+fn save_installment_plan(order_id: u64, plan: InstallmentPlan) {
+    INSTALLMENT_PLANS.with(|storage| storage.borrow_mut().insert(order_id, plan));
+}
+
+pub(crate) fn has_installment_plan(order_id: u64) -> bool {
+    INSTALLMENT_PLANS.with(|storage| storage.borrow().contains_key(&order_id))
+}
+
+// Installment Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct InstallmentPayload {
+    amount: u64,
+    due_at: u64,
+}
+
+// Quantity the farmer may deliver given what has been escrowed so far
+fn deliverable_quantity(order: &Order) -> u64 {
+    if order.total == 0 {
+        return order.quantity;
+    }
+    (order.quantity as u128 * order.escrowed as u128 / order.total as u128) as u64
+}
+
+// Unpaid, past its grace period and not yet flagged
+fn is_overdue(installment: &Installment, now: u64) -> bool {
+    installment.paid_at.is_none()
+        && !installment.missed
+        && installment.due_at + GRACE_PERIOD_NANOS <= now
+}
+
+// Claims the next unpaid installment for a payment about to be awaited, so a concurrent call
+// cannot pay it as well. A claim that stalled belongs to a call whose callback trapped and is
+// given up.
+fn claim_next_installment(plan: &mut InstallmentPlan, now: u64) -> Result<(usize, u64), String> {
+    if let Some(since) = plan.paying_since {
+        if now < since.saturating_add(STALLED_PAYMENT_NANOS) {
+            return Err("An installment payment is already in progress".to_string());
+        }
+    }
+    let index = plan
+        .installments
+        .iter()
+        .position(|installment| installment.paid_at.is_none())
+        .ok_or("All installments are paid".to_string())?;
+    plan.paying_since = Some(now);
+    Ok((index, plan.installments[index].amount))
+}
+
+// Books an installment whose payment arrived. Only an order still waiting for it is credited;
+// it may have been cancelled while the transfer was in flight.
+fn credit_installment(
+    plan: &mut InstallmentPlan,
+    order: &mut Order,
+    index: usize,
+    now: u64,
+) -> bool {
+    plan.paying_since = None;
+    plan.installments[index].paid_at = Some(now);
+    if order.status != OrderStatus::Pending {
+        return false;
+    }
+    order.escrowed += plan.installments[index].amount;
+    if order.escrowed == order.total {
+        order.status = OrderStatus::Funded;
+    }
+    true
+}
+
+// Function for the payer to split a pending order into scheduled installments
+#[ic_cdk::update]
+fn set_installment_plan(
+    order_id: u64,
+    schedule: Vec<InstallmentPayload>,
+) -> Result<InstallmentPlan, String> {
+    let order = get_order_record(order_id)?;
+    let now = ic_cdk::api::time();
+
+    if order.payer() != caller_account() {
+        return Err("Only the payer can set an installment plan".to_string());
+    }
+    if order.status != OrderStatus::Pending || order.escrowed > 0 {
+        return Err("Only unfunded orders can be paid in installments".to_string());
+    }
+    if order.currency() != Asset::Icp {
+        return Err("Only orders in the default currency can be paid in installments".to_string());
+    }
+    if has_installment_plan(order_id) {
+        return Err("Order already has an installment plan".to_string());
+    }
+    if schedule.len() < 2 || schedule.len() > MAX_INSTALLMENTS {
+        return Err("Invalid number of installments".to_string());
+    }
+
+    let mut sum: u64 = 0;
+    let mut previous_due = now;
+    for installment in &schedule {
+        if installment.amount == 0 || installment.due_at <= previous_due {
+            return Err(
+                "Installments need positive amounts and increasing future due dates".to_string(),
+            );
+        }
+        sum = sum
+            .checked_add(installment.amount)
+            .ok_or("Installment total overflows".to_string())?;
+        previous_due = installment.due_at;
+    }
+    if sum != order.total {
+        return Err("Installments must add up to the order total".to_string());
+    }
+
+    let plan = InstallmentPlan {
+        installments: schedule
+            .into_iter()
+            .map(|installment| Installment {
+                amount: installment.amount,
+                due_at: installment.due_at,
+                paid_at: None,
+                missed: false,
+            })
+            .collect(),
+        delivered_quantity: 0,
+        paying_since: None,
+    };
+
+    save_installment_plan(order_id, plan.clone());
+    Ok(plan)
+}
+
+// Function for a party to an order to see its installment plan
+#[ic_cdk::query]
+fn get_installment_plan(order_id: u64) -> Result<InstallmentPlan, String> {
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+    if caller != order.farmer && caller != order.consumer && caller != order.payer() {
+        return Err("Only the parties to an order can view it".to_string());
+    }
+    installment_plan(order_id).ok_or("Order has no installment plan".to_string())
+}
+
+// Function for the payer to pay the next installment from an ICRC-2 allowance into the order's
+// escrow
+#[ic_cdk::update]
+async fn pay_installment(order_id: u64) -> Result<Order, String> {
+    let order = get_order_record(order_id)?;
+
+    if order.payer() != caller_account() {
+        return Err("Only the payer can pay installments".to_string());
+    }
+    if order.status != OrderStatus::Pending {
+        return Err("Order is not awaiting payment".to_string());
+    }
+
+    let mut plan = installment_plan(order_id).ok_or("Order has no installment plan".to_string())?;
+    let (index, amount) = claim_next_installment(&mut plan, ic_cdk::api::time())?;
+    save_installment_plan(order_id, plan);
+
+    let payment = collect_order_payment(&order, amount).await;
+
+    // Re-read; the order is only credited once the funds are in escrow, and may have been
+    // cancelled meanwhile
+    let mut order = get_order_record(order_id)?;
+    let mut plan = installment_plan(order_id).ok_or("Order has no installment plan".to_string())?;
+    if let Err(e) = payment {
+        plan.paying_since = None;
+        save_installment_plan(order_id, plan);
+        return Err(e);
+    }
+    let credited = credit_installment(&mut plan, &mut order, index, ic_cdk::api::time());
+    save_installment_plan(order_id, plan);
+    if !credited {
+        refund_order_escrow(&order);
+        return Err(
+            "Order was cancelled while the installment was being paid; the payment is refunded"
+                .to_string(),
+        );
+    }
+    save_order(order.clone());
+    Ok(order)
+}
+
+// Function for the farmer to deliver part of an installment order, up to the share already paid
+#[ic_cdk::update]
+fn deliver_installment_quantity(order_id: u64, quantity: u64) -> Result<Order, String> {
+    let order = get_order_record(order_id)?;
+
+    if order.farmer != caller_account() {
+        return Err("Only the farmer can deliver this order".to_string());
+    }
+    if order.status != OrderStatus::Pending && order.status != OrderStatus::Funded {
+        return Err("Order is not open for delivery".to_string());
+    }
+
+    let deliverable = deliverable_quantity(&order);
+    let mut plan = installment_plan(order_id).ok_or("Order has no installment plan".to_string())?;
+    if quantity == 0 || plan.delivered_quantity + quantity > deliverable {
+        return Err("Quantity exceeds what has been paid for".to_string());
+    }
+
+    plan.delivered_quantity += quantity;
+    save_installment_plan(order_id, plan);
+    Ok(order)
+}
+
+// Flags unpaid installments past their grace period and warns both parties once per installment
+pub(crate) fn check_missed_installments() {
+    let now = ic_cdk::api::time();
+    let overdue: Vec<(Order, InstallmentPlan)> = INSTALLMENT_PLANS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, plan)| {
+                plan.installments
+                    .iter()
+                    .any(|installment| is_overdue(installment, now))
+            })
+            .filter_map(|(order_id, plan)| {
+                get_order_record(order_id).ok().map(|order| (order, plan))
+            })
+            .filter(|(order, _)| order.status == OrderStatus::Pending)
+            .collect()
+    });
+
+    for (order, mut plan) in overdue {
+        for installment in plan.installments.iter_mut() {
+            if is_overdue(installment, now) {
+                installment.missed = true;
+            }
+        }
+        save_installment_plan(order.id, plan);
+
+        let message = format!("An installment on order #{} was missed", order.id);
+        notify(order.payer(), message.clone());
+        notify(order.farmer, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::test_order;
+
+    fn plan(amounts: &[u64]) -> InstallmentPlan {
+        InstallmentPlan {
+            installments: amounts
+                .iter()
+                .enumerate()
+                .map(|(index, amount)| Installment {
+                    amount: *amount,
+                    due_at: 100 * (index as u64 + 1),
+                    paid_at: None,
+                    missed: false,
+                })
+                .collect(),
+            delivered_quantity: 0,
+            paying_since: None,
+        }
+    }
+
+    #[test]
+    fn one_installment_is_paid_at_a_time() {
+        let mut plan = plan(&[40, 60]);
+        assert_eq!(claim_next_installment(&mut plan, 10), Ok((0, 40)));
+        assert_eq!(
+            claim_next_installment(&mut plan, 11),
+            Err("An installment payment is already in progress".to_string())
+        );
+        // Given up once it stalled
+        assert_eq!(
+            claim_next_installment(&mut plan, 10 + STALLED_PAYMENT_NANOS),
+            Ok((0, 40))
+        );
+    }
+
+    #[test]
+    fn the_order_is_credited_only_once_the_payment_arrived() {
+        let mut order = test_order(1, 100);
+        let mut plan = plan(&[40, 60]);
+
+        let (index, _) = claim_next_installment(&mut plan, 10).unwrap();
+        // Nothing is escrowed or deliverable while the transfer is in flight
+        assert_eq!(order.escrowed, 0);
+        assert_eq!(deliverable_quantity(&order), 0);
+
+        assert!(credit_installment(&mut plan, &mut order, index, 20));
+        assert_eq!(order.escrowed, 40);
+        assert_eq!(order.status, OrderStatus::Pending);
+        assert_eq!(plan.paying_since, None);
+
+        let (index, _) = claim_next_installment(&mut plan, 30).unwrap();
+        assert_eq!(index, 1);
+        assert!(credit_installment(&mut plan, &mut order, index, 40));
+        assert_eq!(order.escrowed, 100);
+        assert_eq!(order.status, OrderStatus::Funded);
+        assert_eq!(
+            claim_next_installment(&mut plan, 50),
+            Err("All installments are paid".to_string())
+        );
+    }
+
+    #[test]
+    fn a_payment_for_a_cancelled_order_is_not_credited() {
+        let mut order = test_order(2, 100);
+        order.status = OrderStatus::Cancelled;
+        let mut plan = plan(&[40, 60]);
+
+        let (index, _) = claim_next_installment(&mut plan, 10).unwrap();
+        assert!(!credit_installment(&mut plan, &mut order, index, 20));
+        assert_eq!(order.escrowed, 0);
+        assert_eq!(order.status, OrderStatus::Cancelled);
+    }
+}
@@ -0,0 +1,537 @@
+use crate::deliveries::is_sha256_hex;
+use crate::devices::caller_account;
+use crate::ledger::transfer_between;
+use crate::notifications::notify;
+use crate::{is_admin, next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_CROP_LEN: usize = 50;
+const MAX_DESCRIPTION_LEN: usize = 1_000;
+const MAX_NOTE_LEN: usize = 500;
+const MAX_EVIDENCE: usize = 5;
+
+// Insurer Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Insurer {
+    principal: Principal,
+    name: String,
+    registered_at: u64,
+}
+
+// PolicyStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PolicyStatus {
+    Offered,
+    Active,
+    Declined,
+}
+
+// InsurancePolicy Struct
+// Crop cover an insurer offers a farmer, in force once the farmer pays the premium
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct InsurancePolicy {
+    id: u64,
+    farmer: Principal,
+    insurer: Principal,
+    crop: String,
+    coverage_amount: u64,
+    premium: u64,
+    starts_at: u64,
+    ends_at: u64,
+    status: PolicyStatus,
+    // Total of approved claims paid under this policy
+    paid_out: u64,
+    created_at: u64,
+}
+
+// LossCause Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LossCause {
+    Drought,
+    Flood,
+    Storm,
+    Frost,
+    Pests,
+    Disease,
+    Other,
+}
+
+// ClaimStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ClaimStatus {
+    Submitted,
+    Paid,
+    Rejected,
+}
+
+// InsuranceClaim Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct InsuranceClaim {
+    id: u64,
+    policy_id: u64,
+    farmer: Principal,
+    cause: LossCause,
+    description: String,
+    // Hex SHA-256 digests of photos, reports or other off-chain evidence
+    evidence_hashes: Vec<String>,
+    amount_claimed: u64,
+    amount_paid: Option<u64>,
+    status: ClaimStatus,
+    insurer_note: Option<String>,
+    block_index: Option<u64>,
+    submitted_at: u64,
+    decided_at: Option<u64>,
+}
+
+// PolicyPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PolicyPayload {
+    farmer: Principal,
+    crop: String,
+    coverage_amount: u64,
+    premium: u64,
+    starts_at: u64,
+    ends_at: u64,
+}
+
+// ClaimPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ClaimPayload {
+    policy_id: u64,
+    cause: LossCause,
+    description: String,
+    evidence_hashes: Vec<String>,
+    amount: u64,
+}
+
+impl InsurancePolicy {
+    fn remaining_cover(&self) -> u64 {
+        self.coverage_amount.saturating_sub(self.paid_out)
+    }
+}
+
+// Storable and BoundedStorable implementations for Insurer
+impl Storable for Insurer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Insurer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for InsurancePolicy
+impl Storable for InsurancePolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InsurancePolicy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for InsuranceClaim
+impl Storable for InsuranceClaim {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InsuranceClaim {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static INSURERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Insurer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    static POLICY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static POLICIES_STORAGE: RefCell<StableBTreeMap<u64, InsurancePolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    static CLAIM_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static CLAIMS_STORAGE: RefCell<StableBTreeMap<u64, InsuranceClaim, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+}
+
+fn is_insurer(principal: &Principal) -> bool {
+    INSURERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+fn get_policy_record(policy_id: u64) -> Result<InsurancePolicy, String> {
+    POLICIES_STORAGE
+        .with(|storage| storage.borrow().get(&policy_id))
+        .ok_or("Policy not found".to_string())
+}
+
+fn save_policy(policy: InsurancePolicy) {
+    POLICIES_STORAGE.with(|storage| storage.borrow_mut().insert(policy.id, policy));
+}
+
+fn get_claim_record(claim_id: u64) -> Result<InsuranceClaim, String> {
+    CLAIMS_STORAGE
+        .with(|storage| storage.borrow().get(&claim_id))
+        .ok_or("Claim not found".to_string())
+}
+
+fn save_claim(claim: InsuranceClaim) {
+    CLAIMS_STORAGE.with(|storage| storage.borrow_mut().insert(claim.id, claim));
+}
+
+// Loads a claim the caller, as the policy's insurer, may adjudicate
+fn insurer_claim(claim_id: u64) -> Result<(InsuranceClaim, InsurancePolicy), String> {
+    let claim = get_claim_record(claim_id)?;
+    let policy = get_policy_record(claim.policy_id)?;
+
+    if policy.insurer != caller_account() {
+        return Err("Only the insurer can adjudicate this claim".to_string());
+    }
+    if claim.status != ClaimStatus::Submitted {
+        return Err("Claim has already been decided".to_string());
+    }
+    Ok((claim, policy))
+}
+
+// Function for an administrator to register an insurer
+#[ic_cdk::update]
+fn register_insurer(principal: Principal, name: String) -> Result<Insurer, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register insurers".to_string());
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err("Invalid insurer name".to_string());
+    }
+
+    let insurer = Insurer {
+        principal,
+        name,
+        registered_at: ic_cdk::api::time(),
+    };
+    INSURERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), insurer.clone())
+    });
+    Ok(insurer)
+}
+
+#[ic_cdk::query]
+fn get_insurer(principal: Principal) -> Result<Insurer, String> {
+    INSURERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Insurer not found".to_string())
+}
+
+// Function for a registered insurer to offer a farmer crop cover
+#[ic_cdk::update]
+fn offer_policy(payload: PolicyPayload) -> Result<InsurancePolicy, String> {
+    let insurer = caller_account();
+
+    if !is_insurer(&insurer) {
+        return Err("Only a registered insurer can offer policies".to_string());
+    }
+    if payload.farmer == insurer {
+        return Err("Insurers cannot insure themselves".to_string());
+    }
+    if payload.crop.trim().is_empty() || payload.crop.len() > MAX_CROP_LEN {
+        return Err("Invalid crop".to_string());
+    }
+    if payload.coverage_amount == 0 {
+        return Err("Coverage must be positive".to_string());
+    }
+    if payload.ends_at <= payload.starts_at || payload.ends_at <= ic_cdk::api::time() {
+        return Err("Invalid policy period".to_string());
+    }
+
+    let policy = InsurancePolicy {
+        id: next_id(&POLICY_ID_COUNTER),
+        farmer: payload.farmer,
+        insurer,
+        crop: payload.crop,
+        coverage_amount: payload.coverage_amount,
+        premium: payload.premium,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        status: PolicyStatus::Offered,
+        paid_out: 0,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_policy(policy.clone());
+    notify(
+        policy.farmer,
+        format!(
+            "You were offered crop cover #{} for {}: accept it by paying the premium",
+            policy.id, policy.crop
+        ),
+    );
+    Ok(policy)
+}
+
+// Function for the farmer to take up an offered policy, paying the premium straight to the insurer
+#[ic_cdk::update]
+async fn accept_policy(policy_id: u64) -> Result<InsurancePolicy, String> {
+    let mut policy = get_policy_record(policy_id)?;
+
+    if policy.farmer != caller_account() {
+        return Err("Only the insured farmer can accept this policy".to_string());
+    }
+    if policy.status != PolicyStatus::Offered {
+        return Err("Policy is not on offer".to_string());
+    }
+    if policy.ends_at <= ic_cdk::api::time() {
+        return Err("Policy period has ended".to_string());
+    }
+
+    // Activate before awaiting so the premium cannot be paid twice
+    policy.status = PolicyStatus::Active;
+    save_policy(policy.clone());
+
+    if policy.premium > 0 {
+        if let Err(e) = transfer_between(policy.farmer, policy.insurer, policy.premium).await {
+            policy.status = PolicyStatus::Offered;
+            save_policy(policy);
+            return Err(e);
+        }
+    }
+
+    notify(
+        policy.insurer,
+        format!("Policy #{} was accepted and is now in force", policy.id),
+    );
+    Ok(policy)
+}
+
+#[ic_cdk::update]
+fn decline_policy(policy_id: u64) -> Result<(), String> {
+    let mut policy = get_policy_record(policy_id)?;
+
+    if policy.farmer != caller_account() {
+        return Err("Only the insured farmer can decline this policy".to_string());
+    }
+    if policy.status != PolicyStatus::Offered {
+        return Err("Policy is not on offer".to_string());
+    }
+
+    policy.status = PolicyStatus::Declined;
+    save_policy(policy);
+    Ok(())
+}
+
+// Function for the farmer to claim for a loss suffered while the policy was in force
+#[ic_cdk::update]
+fn submit_claim(payload: ClaimPayload) -> Result<InsuranceClaim, String> {
+    let policy = get_policy_record(payload.policy_id)?;
+    let farmer = caller_account();
+    let now = ic_cdk::api::time();
+
+    if policy.farmer != farmer {
+        return Err("Only the insured farmer can claim".to_string());
+    }
+    if policy.status != PolicyStatus::Active || now < policy.starts_at || now > policy.ends_at {
+        return Err("Policy is not in force".to_string());
+    }
+    if payload.description.trim().is_empty() || payload.description.len() > MAX_DESCRIPTION_LEN {
+        return Err("Invalid loss description".to_string());
+    }
+    if payload.evidence_hashes.is_empty() || payload.evidence_hashes.len() > MAX_EVIDENCE {
+        return Err(format!(
+            "Attach between 1 and {} pieces of evidence",
+            MAX_EVIDENCE
+        ));
+    }
+    if payload
+        .evidence_hashes
+        .iter()
+        .any(|hash| !is_sha256_hex(hash))
+    {
+        return Err("Evidence must be hex SHA-256 digests".to_string());
+    }
+    if payload.amount == 0 || payload.amount > policy.remaining_cover() {
+        return Err("Claim exceeds the remaining cover".to_string());
+    }
+
+    let claim = InsuranceClaim {
+        id: next_id(&CLAIM_ID_COUNTER),
+        policy_id: policy.id,
+        farmer,
+        cause: payload.cause,
+        description: payload.description,
+        evidence_hashes: payload
+            .evidence_hashes
+            .iter()
+            .map(|hash| hash.to_lowercase())
+            .collect(),
+        amount_claimed: payload.amount,
+        amount_paid: None,
+        status: ClaimStatus::Submitted,
+        insurer_note: None,
+        block_index: None,
+        submitted_at: now,
+        decided_at: None,
+    };
+
+    save_claim(claim.clone());
+    notify(
+        policy.insurer,
+        format!("Claim #{} was filed under policy #{}", claim.id, policy.id),
+    );
+    Ok(claim)
+}
+
+// Function for the insurer to approve a claim, in full or in part, and pay it to the farmer
+#[ic_cdk::update]
+async fn approve_claim(
+    claim_id: u64,
+    amount: u64,
+    note: Option<String>,
+) -> Result<InsuranceClaim, String> {
+    let (mut claim, mut policy) = insurer_claim(claim_id)?;
+
+    if note
+        .as_ref()
+        .map_or(false, |note| note.len() > MAX_NOTE_LEN)
+    {
+        return Err("Note is too long".to_string());
+    }
+    if amount == 0 || amount > claim.amount_claimed || amount > policy.remaining_cover() {
+        return Err("Invalid payout amount".to_string());
+    }
+
+    // Settle the claim and draw down the cover before awaiting so neither can be paid twice
+    claim.status = ClaimStatus::Paid;
+    claim.amount_paid = Some(amount);
+    claim.insurer_note = note;
+    claim.decided_at = Some(ic_cdk::api::time());
+    policy.paid_out += amount;
+    save_claim(claim.clone());
+    save_policy(policy.clone());
+
+    match transfer_between(policy.insurer, claim.farmer, amount).await {
+        Ok(block) => {
+            claim.block_index = Some(block);
+            save_claim(claim.clone());
+            notify(
+                claim.farmer,
+                format!("Claim #{} was approved and {} paid out", claim.id, amount),
+            );
+            Ok(claim)
+        }
+        Err(e) => {
+            // Re-read so other claims paid meanwhile stay drawn down
+            let mut policy = get_policy_record(policy.id).unwrap_or(policy);
+            policy.paid_out -= amount;
+            save_policy(policy);
+            claim.status = ClaimStatus::Submitted;
+            claim.amount_paid = None;
+            claim.decided_at = None;
+            save_claim(claim);
+            Err(e)
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn reject_claim(claim_id: u64, note: String) -> Result<InsuranceClaim, String> {
+    let (mut claim, _) = insurer_claim(claim_id)?;
+
+    if note.trim().is_empty() || note.len() > MAX_NOTE_LEN {
+        return Err("Give a reason for the rejection".to_string());
+    }
+
+    claim.status = ClaimStatus::Rejected;
+    claim.insurer_note = Some(note);
+    claim.decided_at = Some(ic_cdk::api::time());
+    save_claim(claim.clone());
+    notify(claim.farmer, format!("Claim #{} was rejected", claim.id));
+    Ok(claim)
+}
+
+#[ic_cdk::query]
+fn get_policy(policy_id: u64) -> Result<InsurancePolicy, String> {
+    let policy = get_policy_record(policy_id)?;
+    let caller = caller_account();
+
+    if caller != policy.farmer && caller != policy.insurer && !is_admin() {
+        return Err("Policy not found".to_string());
+    }
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_claim(claim_id: u64) -> Result<InsuranceClaim, String> {
+    let claim = get_claim_record(claim_id)?;
+    let policy = get_policy_record(claim.policy_id)?;
+    let caller = caller_account();
+
+    if caller != policy.farmer && caller != policy.insurer && !is_admin() {
+        return Err("Claim not found".to_string());
+    }
+    Ok(claim)
+}
+
+// Lists policies the caller holds or underwrites
+#[ic_cdk::query]
+fn get_my_policies() -> Vec<InsurancePolicy> {
+    let caller = caller_account();
+    POLICIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, policy)| policy)
+            .filter(|policy| policy.farmer == caller || policy.insurer == caller)
+            .collect()
+    })
+}
+
+// Lists claims awaiting a decision from the calling insurer
+#[ic_cdk::query]
+fn get_pending_claims() -> Vec<InsuranceClaim> {
+    let caller = caller_account();
+    CLAIMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, claim)| claim)
+            .filter(|claim| claim.status == ClaimStatus::Submitted)
+            .filter(|claim| {
+                get_policy_record(claim.policy_id).map_or(false, |policy| policy.insurer == caller)
+            })
+            .collect()
+    })
+}
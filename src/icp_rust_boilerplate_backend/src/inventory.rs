@@ -0,0 +1,67 @@
+use crate::warehouses::backing_receipt;
+use crate::{is_farmer_owner, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+thread_local! {
+    // Units available per listing; listings without an entry do not track stock
+    static STOCK_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+}
+
+pub(crate) fn available_stock(farmer_id: u64) -> Option<u64> {
+    STOCK_STORAGE.with(|storage| storage.borrow().get(&farmer_id))
+}
+
+pub(crate) fn set_stock(farmer_id: u64, quantity: u64) {
+    STOCK_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, quantity));
+}
+
+// Takes `quantity` units out of a listing's stock, failing if not enough are left
+pub(crate) fn reserve_stock(farmer_id: u64, quantity: u64) -> Result<(), String> {
+    match available_stock(farmer_id) {
+        None => Ok(()),
+        Some(stock) if stock < quantity => Err("Not enough stock".to_string()),
+        Some(stock) => {
+            STOCK_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, stock - quantity));
+            Ok(())
+        }
+    }
+}
+
+// Puts units back, e.g. when an unfunded order is cancelled
+pub(crate) fn release_stock(farmer_id: u64, quantity: u64) {
+    if let Some(stock) = available_stock(farmer_id) {
+        STOCK_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(farmer_id, stock.saturating_add(quantity))
+        });
+    }
+}
+
+// Function for a farmer to set how many units the listing has available
+#[ic_cdk::update]
+fn set_listing_stock(farmer_id: u64, quantity: u64) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can set stock".to_string());
+    }
+    if backing_receipt(farmer_id).is_some() {
+        return Err("Stock of this listing follows its warehouse receipt".to_string());
+    }
+
+    set_stock(farmer_id, quantity);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_listing_stock(farmer_id: u64) -> Option<u64> {
+    available_stock(farmer_id)
+}
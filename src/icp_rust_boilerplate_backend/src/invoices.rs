@@ -0,0 +1,205 @@
+use crate::devices::caller_account;
+use crate::ledger::Asset;
+use crate::orders::Order;
+use crate::price_history::TimeRange;
+use crate::taxes::{order_taxes, tax_total, TaxLine};
+use crate::{is_admin, Memory, PrincipalKey, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_DESCRIPTION_LEN: usize = 100;
+const MAX_INVOICES_PER_QUERY: usize = 1000;
+
+// Invoice Struct
+// A farmer's invoice for a settled order, numbered 1, 2, 3... per farmer with no gaps
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Invoice {
+    farmer: Principal,
+    number: u64,
+    order_id: u64,
+    buyer: Principal,
+    // Set when someone other than the buyer paid
+    payer: Option<Principal>,
+    listing_id: Option<u64>,
+    description: String,
+    quantity: u64,
+    unit_price: u64,
+    subtotal: u64,
+    discount: u64,
+    shipping_fee: u64,
+    taxes: Option<Vec<TaxLine>>,
+    total: u64,
+    // Asset the amounts are in; None for the default ledger
+    currency: Option<Asset>,
+    issued_at: u64,
+}
+
+// InvoiceKey Struct
+// A farmer's invoices sort together, by number
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InvoiceKey {
+    farmer: Principal,
+    number: u64,
+}
+
+// Storable and BoundedStorable implementations for Invoice
+impl Storable for Invoice {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Invoice {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for InvoiceKey
+impl Storable for InvoiceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        // Length-prefixed so no principal's keys run into another's
+        let principal = self.farmer.as_slice();
+        let mut bytes = Vec::with_capacity(1 + principal.len() + 8);
+        bytes.push(principal.len() as u8);
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(&self.number.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let (principal, number) = bytes[1..].split_at(len);
+        InvoiceKey {
+            farmer: Principal::from_slice(principal),
+            number: u64::from_be_bytes(number.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for InvoiceKey {
+    const MAX_SIZE: u32 = 38;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Last invoice number issued per farmer
+    static INVOICE_NUMBERS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(220)))
+    ));
+
+    static INVOICES_STORAGE: RefCell<StableBTreeMap<InvoiceKey, Invoice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(221)))
+    ));
+
+    // Invoice of each invoiced order, so an order is never invoiced twice
+    static ORDER_INVOICES: RefCell<StableBTreeMap<u64, InvoiceKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(222)))
+    ));
+}
+
+// Invoices a settled order under the farmer's next number. The number is taken and the
+// invoice stored in the same message, so a number is never skipped.
+pub(crate) fn issue_invoice(order: &Order) {
+    if ORDER_INVOICES.with(|storage| storage.borrow().contains_key(&order.id)) {
+        return;
+    }
+
+    let description: String = order
+        .product_id
+        .and_then(|id| FARMERS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .map_or_else(|| format!("Order #{}", order.id), |farmer| farmer.name)
+        .chars()
+        .take(MAX_DESCRIPTION_LEN)
+        .collect();
+    let subtotal = order.quantity.saturating_mul(order.unit_price);
+    let shipping_fee = order.shipping.as_ref().map_or(0, |line| line.cost);
+    let taxes = order_taxes(order.id);
+    let tax = taxes.as_deref().map_or(0, tax_total);
+
+    let number = INVOICE_NUMBERS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let number = storage.get(&PrincipalKey(order.farmer)).unwrap_or(0) + 1;
+        storage.insert(PrincipalKey(order.farmer), number);
+        number
+    });
+    let invoice = Invoice {
+        farmer: order.farmer,
+        number,
+        order_id: order.id,
+        buyer: order.consumer,
+        payer: order.payer,
+        listing_id: order.product_id,
+        description,
+        quantity: order.quantity,
+        unit_price: order.unit_price,
+        subtotal,
+        discount: subtotal
+            .saturating_add(shipping_fee)
+            .saturating_add(tax)
+            .saturating_sub(order.total),
+        shipping_fee,
+        taxes,
+        total: order.total,
+        currency: order.currency,
+        issued_at: ic_cdk::api::time(),
+    };
+    let key = InvoiceKey {
+        farmer: order.farmer,
+        number,
+    };
+    INVOICES_STORAGE.with(|storage| storage.borrow_mut().insert(key, invoice));
+    ORDER_INVOICES.with(|storage| storage.borrow_mut().insert(order.id, key));
+}
+
+// Function for a farmer, or an administrator, to list the farmer's invoices in number order,
+// optionally within a time range. At most 1000 come back; narrow the range to see the rest.
+#[ic_cdk::query]
+fn get_invoices(farmer: Principal, range: Option<TimeRange>) -> Result<Vec<Invoice>, String> {
+    if farmer != caller_account() && !is_admin() {
+        return Err("Only the farmer can view their invoices".to_string());
+    }
+
+    let start = InvoiceKey { farmer, number: 0 };
+    Ok(INVOICES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.farmer == farmer)
+            .map(|(_, invoice)| invoice)
+            .filter(|invoice| {
+                range.map_or(true, |range| {
+                    range.from <= invoice.issued_at && invoice.issued_at <= range.to
+                })
+            })
+            .take(MAX_INVOICES_PER_QUERY)
+            .collect()
+    }))
+}
+
+// Function for a party to an order, or an administrator, to look up the order's invoice
+#[ic_cdk::query]
+fn get_order_invoice(order_id: u64) -> Result<Invoice, String> {
+    let invoice = ORDER_INVOICES
+        .with(|storage| storage.borrow().get(&order_id))
+        .and_then(|key| INVOICES_STORAGE.with(|storage| storage.borrow().get(&key)))
+        .ok_or("Invoice not found".to_string())?;
+
+    let caller = caller_account();
+    if caller != invoice.farmer
+        && caller != invoice.buyer
+        && Some(caller) != invoice.payer
+        && !is_admin()
+    {
+        return Err("Invoice not found".to_string());
+    }
+    Ok(invoice)
+}
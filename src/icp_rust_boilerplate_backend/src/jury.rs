@@ -0,0 +1,406 @@
+use crate::arbiters::release_assignment;
+use crate::consumers::is_registered_user;
+use crate::devices::caller_account;
+use crate::disputes::{disputes_against, get_dispute_record, settle_dispute, DisputeStatus};
+use crate::identity::check_can_vote_on_disputes;
+use crate::notifications::notify;
+use crate::orders::{get_order_record, Order, OrderStatus, ORDERS_STORAGE};
+use crate::{is_admin, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const JURY_SIZE: usize = 5;
+const VOTING_PERIOD_NANOS: u64 = 3 * 24 * 60 * 60 * 1_000_000_000;
+// Completed orders, bought or sold, a juror needs behind them
+const MIN_JUROR_ORDERS: usize = 3;
+
+// JuryStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum JuryStatus {
+    Voting,
+    Decided,
+    // The vote tied or nobody voted; the dispute went back to the administrators
+    Hung,
+    // An administrator settled the dispute before the jury did
+    Withdrawn,
+}
+
+// Juror Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Juror {
+    principal: Principal,
+    joined_at: u64,
+    cases_served: u64,
+}
+
+// JuryVote Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct JuryVote {
+    juror: Principal,
+    favour_farmer: bool,
+    cast_at: u64,
+}
+
+// JuryCase Struct
+// A dispute put to a panel of community jurors
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct JuryCase {
+    dispute_id: u64,
+    requested_by: Principal,
+    panel: Vec<Principal>,
+    votes: Vec<JuryVote>,
+    deadline: u64,
+    status: JuryStatus,
+    favour_farmer: Option<bool>,
+    created_at: u64,
+}
+
+impl JuryCase {
+    fn tally(&self) -> (usize, usize) {
+        let for_farmer = self.votes.iter().filter(|vote| vote.favour_farmer).count();
+        (for_farmer, self.votes.len() - for_farmer)
+    }
+}
+
+// Storable and BoundedStorable implementations for Juror
+impl Storable for Juror {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Juror {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for JuryCase
+impl Storable for JuryCase {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JuryCase {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static JURORS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Juror, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(159)))
+    ));
+
+    // Dispute ID to its jury case
+    static JURY_CASES_STORAGE: RefCell<StableBTreeMap<u64, JuryCase, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(160)))
+    ));
+
+    // Largest order total a jury may decide; 0 turns juries off
+    static JURY_MAX_ORDER_TOTAL: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(161))), 0)
+            .expect("Cannot create the jury threshold cell")
+    );
+}
+
+fn max_order_total() -> u64 {
+    JURY_MAX_ORDER_TOTAL.with(|cell| *cell.borrow().get())
+}
+
+fn jury_case(dispute_id: u64) -> Option<JuryCase> {
+    JURY_CASES_STORAGE.with(|storage| storage.borrow().get(&dispute_id))
+}
+
+fn save_case(case: JuryCase) {
+    JURY_CASES_STORAGE.with(|storage| storage.borrow_mut().insert(case.dispute_id, case));
+}
+
+// The k-th pseudo-random number derived from the seed
+fn random_u64(seed: &[u8], k: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(k.to_be_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+fn order_parties(order: &Order) -> [Principal; 3] {
+    [order.farmer, order.consumer, order.payer()]
+}
+
+// Reputable users are registered, have completed enough orders as buyer or seller, and have
+// never lost a dispute as a farmer
+fn is_reputable(principal: &Principal) -> bool {
+    if !is_registered_user(principal) || disputes_against(principal) > 0 {
+        return false;
+    }
+    ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.status == OrderStatus::Completed)
+            .filter(|(_, order)| order_parties(order).contains(principal))
+            .take(MIN_JUROR_ORDERS)
+            .count()
+            >= MIN_JUROR_ORDERS
+    })
+}
+
+// Checks that a party may send this dispute to a jury, returning the order's parties
+fn check_jury_request(dispute_id: u64, caller: &Principal) -> Result<[Principal; 3], String> {
+    let dispute = get_dispute_record(dispute_id)?;
+    let order = get_order_record(dispute.order_id)?;
+    let parties = order_parties(&order);
+    if !parties.contains(caller) {
+        return Err("Only parties to the order can ask for a jury".to_string());
+    }
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute already resolved".to_string());
+    }
+    let max_total = max_order_total();
+    if max_total == 0 || order.total > max_total {
+        return Err("Only low-value disputes can go to a jury".to_string());
+    }
+    if jury_case(dispute_id).is_some() {
+        return Err("Dispute has already gone to a jury".to_string());
+    }
+    Ok(parties)
+}
+
+// Draws the panel at random from reputable volunteers who are not parties to the order
+fn draw_panel(seed: &[u8], excluded: &[Principal]) -> Vec<Principal> {
+    let mut candidates: Vec<Principal> = JURORS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, juror)| juror.principal)
+            .filter(|principal| !excluded.contains(principal))
+            .collect()
+    });
+    candidates.retain(|juror| is_reputable(juror) && check_can_vote_on_disputes(juror).is_ok());
+
+    let mut panel = Vec::new();
+    for k in 0..JURY_SIZE as u64 {
+        if candidates.is_empty() {
+            break;
+        }
+        let index = (random_u64(seed, k) % candidates.len() as u64) as usize;
+        panel.push(candidates.swap_remove(index));
+    }
+    panel
+}
+
+// Settles the dispute by the jury's majority and credits the panel
+fn decide(mut case: JuryCase, favour_farmer: bool) {
+    case.status = JuryStatus::Decided;
+    case.favour_farmer = Some(favour_farmer);
+    if settle_dispute(case.dispute_id, favour_farmer).is_err() {
+        // Settled some other way while the jury sat
+        case.status = JuryStatus::Withdrawn;
+        case.favour_farmer = None;
+    }
+    for principal in &case.panel {
+        if let Some(mut juror) =
+            JURORS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*principal)))
+        {
+            juror.cases_served += 1;
+            JURORS_STORAGE
+                .with(|storage| storage.borrow_mut().insert(PrincipalKey(*principal), juror));
+        }
+    }
+    save_case(case);
+}
+
+// Marks a jury case withdrawn after an administrator settles its dispute directly
+pub(crate) fn withdraw_jury_case(dispute_id: u64) {
+    if let Some(mut case) = jury_case(dispute_id) {
+        if case.status == JuryStatus::Voting {
+            case.status = JuryStatus::Withdrawn;
+            save_case(case);
+        }
+    }
+}
+
+// Closes jury votes whose deadline has passed, by majority of the votes cast
+pub(crate) fn close_jury_votes() {
+    let now = ic_cdk::api::time();
+    let due: Vec<JuryCase> = JURY_CASES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, case)| case)
+            .filter(|case| case.status == JuryStatus::Voting && now > case.deadline)
+            .collect()
+    });
+
+    for mut case in due {
+        let (for_farmer, for_consumer) = case.tally();
+        if for_farmer == for_consumer {
+            case.status = JuryStatus::Hung;
+            save_case(case);
+            continue;
+        }
+        decide(case, for_farmer > for_consumer);
+    }
+}
+
+// Function for a reputable user to volunteer for jury panels
+#[ic_cdk::update]
+fn join_jury_pool() -> Result<Juror, String> {
+    let caller = caller_account();
+    check_can_vote_on_disputes(&caller)?;
+    if !is_reputable(&caller) {
+        return Err(format!(
+            "Jurors need {} completed orders and no lost disputes",
+            MIN_JUROR_ORDERS
+        ));
+    }
+    if JURORS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(caller))) {
+        return Err("Already in the jury pool".to_string());
+    }
+
+    let juror = Juror {
+        principal: caller,
+        joined_at: ic_cdk::api::time(),
+        cases_served: 0,
+    };
+    JURORS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(caller), juror.clone())
+    });
+    Ok(juror)
+}
+
+// Function for a juror to stop being drawn for new panels
+#[ic_cdk::update]
+fn leave_jury_pool() -> Result<(), String> {
+    JURORS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(caller_account())))
+        .map(|_| ())
+        .ok_or("Not in the jury pool".to_string())
+}
+
+#[ic_cdk::update]
+fn set_jury_threshold(max_order_total: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set the jury threshold".to_string());
+    }
+
+    JURY_MAX_ORDER_TOTAL
+        .with(|cell| cell.borrow_mut().set(max_order_total))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_jury_threshold() -> u64 {
+    max_order_total()
+}
+
+// Function for a party to a low-value dispute to have it decided by a random panel of
+// community jurors instead of an arbiter
+#[ic_cdk::update]
+async fn request_jury(dispute_id: u64) -> Result<JuryCase, String> {
+    let caller = caller_account();
+    check_jury_request(dispute_id, &caller)?;
+
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("Randomness failed: {:?} {}", code, message))?;
+
+    // Check again: the dispute may have moved on while randomness was fetched
+    let parties = check_jury_request(dispute_id, &caller)?;
+    let panel = draw_panel(&seed, &parties);
+    if panel.len() < JURY_SIZE {
+        return Err("Not enough jurors are available".to_string());
+    }
+    release_assignment(dispute_id)?;
+
+    let now = ic_cdk::api::time();
+    let case = JuryCase {
+        dispute_id,
+        requested_by: caller,
+        panel,
+        votes: Vec::new(),
+        deadline: now + VOTING_PERIOD_NANOS,
+        status: JuryStatus::Voting,
+        favour_farmer: None,
+        created_at: now,
+    };
+    save_case(case.clone());
+    for juror in &case.panel {
+        notify(
+            *juror,
+            format!(
+                "You have been drawn for the jury on dispute #{}",
+                dispute_id
+            ),
+        );
+    }
+    Ok(case)
+}
+
+// Function for a panel member to vote once; the dispute is settled as soon as one side
+// has a majority of the panel
+#[ic_cdk::update]
+fn cast_jury_vote(dispute_id: u64, favour_farmer: bool) -> Result<JuryCase, String> {
+    let caller = caller_account();
+    let mut case = jury_case(dispute_id).ok_or("Dispute has no jury".to_string())?;
+    if !case.panel.contains(&caller) {
+        return Err("You are not on this jury".to_string());
+    }
+    if case.status != JuryStatus::Voting || ic_cdk::api::time() > case.deadline {
+        return Err("Voting has closed".to_string());
+    }
+    if case.votes.iter().any(|vote| vote.juror == caller) {
+        return Err("You have already voted".to_string());
+    }
+    check_can_vote_on_disputes(&caller)?;
+
+    case.votes.push(JuryVote {
+        juror: caller,
+        favour_farmer,
+        cast_at: ic_cdk::api::time(),
+    });
+    let (for_farmer, for_consumer) = case.tally();
+    let majority = case.panel.len() / 2 + 1;
+    if for_farmer >= majority || for_consumer >= majority {
+        decide(case, for_farmer >= majority);
+    } else {
+        save_case(case);
+    }
+    jury_case(dispute_id).ok_or("Dispute has no jury".to_string())
+}
+
+#[ic_cdk::query]
+fn get_jury_case(dispute_id: u64) -> Result<JuryCase, String> {
+    jury_case(dispute_id).ok_or("Dispute has no jury".to_string())
+}
+
+// Jury cases the caller has been drawn for that are still open for votes
+#[ic_cdk::query]
+fn get_my_jury_cases() -> Vec<JuryCase> {
+    let caller = caller_account();
+    JURY_CASES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, case)| case)
+            .filter(|case| case.status == JuryStatus::Voting && case.panel.contains(&caller))
+            .collect()
+    })
+}
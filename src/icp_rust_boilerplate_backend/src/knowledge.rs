@@ -0,0 +1,245 @@
+use crate::devices::caller_account;
+use crate::extension::is_extension_advisor;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_BODY_LEN: usize = 20_000;
+const MAX_CATEGORY_LEN: usize = 50;
+const MAX_LANGUAGE_LEN: usize = 10;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 30;
+const MAX_PAGE_SIZE: u64 = 50;
+
+// Article Struct
+// Advice written by administrators or extension advisors
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Article {
+    id: u64,
+    author: Principal,
+    title: String,
+    body: String,
+    category: String,
+    // Language code, e.g. "en" or "sw"
+    language: String,
+    tags: Vec<String>,
+    views: u64,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// ArticlePayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ArticlePayload {
+    title: String,
+    body: String,
+    category: String,
+    language: String,
+    tags: Vec<String>,
+}
+
+// ArticlePage Struct
+// One page of a listing, with the number of matches across all pages
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ArticlePage {
+    articles: Vec<Article>,
+    total: u64,
+}
+
+// Storable and BoundedStorable implementations for Article
+impl Storable for Article {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Article {
+    const MAX_SIZE: u32 = 24 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ARTICLE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static ARTICLES_STORAGE: RefCell<StableBTreeMap<u64, Article, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))
+    ));
+}
+
+fn get_article_record(article_id: u64) -> Result<Article, String> {
+    ARTICLES_STORAGE
+        .with(|storage| storage.borrow().get(&article_id))
+        .ok_or("Article not found".to_string())
+}
+
+fn save_article(article: Article) {
+    ARTICLES_STORAGE.with(|storage| storage.borrow_mut().insert(article.id, article));
+}
+
+// Checks a payload, returning its category, language and tags normalised to lower case
+fn validate_article(payload: &ArticlePayload) -> Result<(String, String, Vec<String>), String> {
+    if payload.title.trim().is_empty() || payload.title.len() > MAX_TITLE_LEN {
+        return Err("Invalid title".to_string());
+    }
+    if payload.body.trim().is_empty() || payload.body.len() > MAX_BODY_LEN {
+        return Err("Invalid body".to_string());
+    }
+    let category = payload.category.trim().to_lowercase();
+    if category.is_empty() || category.len() > MAX_CATEGORY_LEN {
+        return Err("Invalid category".to_string());
+    }
+    let language = payload.language.trim().to_lowercase();
+    if language.is_empty() || language.len() > MAX_LANGUAGE_LEN {
+        return Err("Invalid language".to_string());
+    }
+    if payload.tags.len() > MAX_TAGS {
+        return Err("Too many tags".to_string());
+    }
+    let mut tags = Vec::with_capacity(payload.tags.len());
+    for tag in &payload.tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err("Invalid tag".to_string());
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok((category, language, tags))
+}
+
+// Loads an article the caller, as its author or an administrator, may edit
+fn editable_article(article_id: u64) -> Result<Article, String> {
+    let article = get_article_record(article_id)?;
+    if article.author != caller_account() && !is_admin() {
+        return Err("Only the author or an administrator can edit this article".to_string());
+    }
+    Ok(article)
+}
+
+// Newest first, skipping `offset` matches and returning at most `limit`
+fn page_of(filter: impl Fn(&Article) -> bool, offset: u64, limit: u64) -> ArticlePage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    ARTICLES_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let matching = storage
+            .iter()
+            .rev()
+            .map(|(_, article)| article)
+            .filter(|article| filter(article));
+        let mut total = 0;
+        let mut articles = Vec::new();
+        for article in matching {
+            if total >= offset && articles.len() < limit {
+                articles.push(article);
+            }
+            total += 1;
+        }
+        ArticlePage { articles, total }
+    })
+}
+
+// Function for an administrator or extension advisor to publish an article
+#[ic_cdk::update]
+fn publish_article(payload: ArticlePayload) -> Result<Article, String> {
+    let author = caller_account();
+
+    if !is_admin() && !is_extension_advisor(&author) {
+        return Err("Only administrators and extension advisors can publish articles".to_string());
+    }
+    let (category, language, tags) = validate_article(&payload)?;
+
+    let now = ic_cdk::api::time();
+    let article = Article {
+        id: next_id(&ARTICLE_ID_COUNTER),
+        author,
+        title: payload.title,
+        body: payload.body,
+        category,
+        language,
+        tags,
+        views: 0,
+        created_at: now,
+        updated_at: now,
+    };
+
+    save_article(article.clone());
+    Ok(article)
+}
+
+// Function for the author or an administrator to revise an article; its views are kept
+#[ic_cdk::update]
+fn update_article(article_id: u64, payload: ArticlePayload) -> Result<Article, String> {
+    let mut article = editable_article(article_id)?;
+    let (category, language, tags) = validate_article(&payload)?;
+
+    article.title = payload.title;
+    article.body = payload.body;
+    article.category = category;
+    article.language = language;
+    article.tags = tags;
+    article.updated_at = ic_cdk::api::time();
+
+    save_article(article.clone());
+    Ok(article)
+}
+
+// Function for the author or an administrator to take an article down
+#[ic_cdk::update]
+fn delete_article(article_id: u64) -> Result<(), String> {
+    editable_article(article_id)?;
+    ARTICLES_STORAGE.with(|storage| storage.borrow_mut().remove(&article_id));
+    Ok(())
+}
+
+// Function to read an article in full, counting the view
+#[ic_cdk::update]
+fn read_article(article_id: u64) -> Result<Article, String> {
+    let mut article = get_article_record(article_id)?;
+
+    article.views += 1;
+    save_article(article.clone());
+    Ok(article)
+}
+
+// Lists articles newest first, optionally of one category and language
+#[ic_cdk::query]
+fn list_articles(
+    category: Option<String>,
+    language: Option<String>,
+    offset: u64,
+    limit: u64,
+) -> ArticlePage {
+    let category = category.map(|category| category.trim().to_lowercase());
+    let language = language.map(|language| language.trim().to_lowercase());
+    page_of(
+        |article| {
+            category
+                .as_ref()
+                .map_or(true, |category| article.category == *category)
+                && language
+                    .as_ref()
+                    .map_or(true, |language| article.language == *language)
+        },
+        offset,
+        limit,
+    )
+}
+
+// Lists articles carrying a tag, newest first
+#[ic_cdk::query]
+fn search_articles_by_tag(tag: String, offset: u64, limit: u64) -> ArticlePage {
+    let tag = tag.trim().to_lowercase();
+    page_of(|article| article.tags.contains(&tag), offset, limit)
+}
@@ -0,0 +1,459 @@
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::orders::{
+    complete_order, create_order, get_order_record, save_order, Order, OrderDraft, OrderStatus,
+};
+use crate::streams::open_stream;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TASK_LEN: usize = 200;
+const MAX_LOCATION_LEN: usize = 100;
+const MAX_MESSAGE_LEN: usize = 300;
+const MAX_POSITIONS: u32 = 100;
+
+// LaborJobStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LaborJobStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+// LaborJob Struct
+// Seasonal work a farmer needs done, such as planting or harvest, for one or more workers
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LaborJob {
+    id: u64,
+    farmer: Principal,
+    task: String,
+    location: String,
+    starts_at: u64,
+    ends_at: u64,
+    // Paid to each hired worker for the whole job
+    wage: u64,
+    positions: u32,
+    hired: u32,
+    // Whether wages are released pro rata over the job instead of on completion
+    streamed: Option<bool>,
+    status: LaborJobStatus,
+    created_at: u64,
+}
+
+// LaborJobPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct LaborJobPayload {
+    task: String,
+    location: String,
+    starts_at: u64,
+    ends_at: u64,
+    wage: u64,
+    positions: u32,
+    streamed: Option<bool>,
+}
+
+// ApplicationStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ApplicationStatus {
+    Pending,
+    Accepted,
+    Rejected,
+    Withdrawn,
+}
+
+// JobApplication Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct JobApplication {
+    id: u64,
+    job_id: u64,
+    worker: Principal,
+    message: String,
+    status: ApplicationStatus,
+    // The wage order opened when the worker is hired
+    order_id: Option<u64>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for LaborJob
+impl Storable for LaborJob {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LaborJob {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for JobApplication
+impl Storable for JobApplication {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JobApplication {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LABOR_JOB_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static LABOR_JOBS_STORAGE: RefCell<StableBTreeMap<u64, LaborJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+
+    static APPLICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static APPLICATIONS_STORAGE: RefCell<StableBTreeMap<u64, JobApplication, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+}
+
+fn get_job_record(job_id: u64) -> Result<LaborJob, String> {
+    LABOR_JOBS_STORAGE
+        .with(|storage| storage.borrow().get(&job_id))
+        .ok_or("Job not found".to_string())
+}
+
+fn save_job(job: LaborJob) {
+    LABOR_JOBS_STORAGE.with(|storage| storage.borrow_mut().insert(job.id, job));
+}
+
+fn get_application_record(application_id: u64) -> Result<JobApplication, String> {
+    APPLICATIONS_STORAGE
+        .with(|storage| storage.borrow().get(&application_id))
+        .ok_or("Application not found".to_string())
+}
+
+fn save_application(application: JobApplication) {
+    APPLICATIONS_STORAGE.with(|storage| storage.borrow_mut().insert(application.id, application));
+}
+
+fn job_applications(job_id: u64) -> Vec<JobApplication> {
+    APPLICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, application)| application)
+            .filter(|application| application.job_id == job_id)
+            .collect()
+    })
+}
+
+// Loads the wage order of a hired worker's application
+fn wage_order(application: &JobApplication) -> Result<Order, String> {
+    let order_id = application
+        .order_id
+        .ok_or("Worker has not been hired".to_string())?;
+    get_order_record(order_id)
+}
+
+// Turns down every pending application on a job, e.g. once it is filled or cancelled
+fn reject_pending_applications(job_id: u64) {
+    for mut application in job_applications(job_id) {
+        if application.status != ApplicationStatus::Pending {
+            continue;
+        }
+        application.status = ApplicationStatus::Rejected;
+        notify(
+            application.worker,
+            format!("Your application #{} was not accepted", application.id),
+        );
+        save_application(application);
+    }
+}
+
+// Blanks the messages on a deleted account's job applications
+pub(crate) fn anonymize_applications(worker: &Principal) {
+    let applications: Vec<JobApplication> = APPLICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, application)| application)
+            .filter(|application| application.worker == *worker)
+            .collect()
+    });
+    for mut application in applications {
+        application.message = String::new();
+        save_application(application);
+    }
+}
+
+// Function for a farmer to post seasonal work
+#[ic_cdk::update]
+fn post_labor_job(payload: LaborJobPayload) -> Result<LaborJob, String> {
+    if payload.task.trim().is_empty()
+        || payload.task.len() > MAX_TASK_LEN
+        || payload.location.trim().is_empty()
+        || payload.location.len() > MAX_LOCATION_LEN
+    {
+        return Err("Invalid job details".to_string());
+    }
+    if payload.starts_at <= ic_cdk::api::time() || payload.ends_at <= payload.starts_at {
+        return Err("Invalid job dates".to_string());
+    }
+    if payload.wage == 0 {
+        return Err("Wage must be positive".to_string());
+    }
+    if payload.positions == 0 || payload.positions > MAX_POSITIONS {
+        return Err("Invalid number of positions".to_string());
+    }
+
+    let job = LaborJob {
+        id: next_id(&LABOR_JOB_ID_COUNTER),
+        farmer: caller_account(),
+        task: payload.task,
+        location: payload.location,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        wage: payload.wage,
+        positions: payload.positions,
+        hired: 0,
+        streamed: payload.streamed.filter(|streamed| *streamed),
+        status: LaborJobStatus::Open,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_job(job.clone());
+    Ok(job)
+}
+
+// Function for a worker to apply for an open job
+#[ic_cdk::update]
+fn apply_for_job(job_id: u64, message: String) -> Result<JobApplication, String> {
+    let job = get_job_record(job_id)?;
+    let worker = caller_account();
+
+    if job.status != LaborJobStatus::Open || job.starts_at <= ic_cdk::api::time() {
+        return Err("Job is not taking applications".to_string());
+    }
+    if worker == job.farmer {
+        return Err("Farmers cannot apply to their own jobs".to_string());
+    }
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err("Message is too long".to_string());
+    }
+    if job_applications(job_id).iter().any(|application| {
+        application.worker == worker
+            && matches!(
+                application.status,
+                ApplicationStatus::Pending | ApplicationStatus::Accepted
+            )
+    }) {
+        return Err("You have already applied for this job".to_string());
+    }
+
+    let application = JobApplication {
+        id: next_id(&APPLICATION_ID_COUNTER),
+        job_id,
+        worker,
+        message,
+        status: ApplicationStatus::Pending,
+        order_id: None,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_application(application.clone());
+    notify(
+        job.farmer,
+        format!("New application #{} for job #{}", application.id, job.id),
+    );
+    Ok(application)
+}
+
+// Function for a worker to take back an application that has not been decided
+#[ic_cdk::update]
+fn withdraw_job_application(application_id: u64) -> Result<(), String> {
+    let mut application = get_application_record(application_id)?;
+
+    if application.worker != caller_account() {
+        return Err("Only the applicant can withdraw this application".to_string());
+    }
+    if application.status != ApplicationStatus::Pending {
+        return Err("Application is no longer pending".to_string());
+    }
+
+    application.status = ApplicationStatus::Withdrawn;
+    save_application(application);
+    Ok(())
+}
+
+// Function for the farmer to hire an applicant. Hiring opens a wage order with the worker as
+// seller, which the farmer funds into escrow with `pay_order`.
+#[ic_cdk::update]
+fn accept_job_application(application_id: u64) -> Result<JobApplication, String> {
+    let mut application = get_application_record(application_id)?;
+    let mut job = get_job_record(application.job_id)?;
+
+    if job.farmer != caller_account() {
+        return Err("Only the farmer who posted this job can hire".to_string());
+    }
+    if job.status != LaborJobStatus::Open {
+        return Err("Job is not taking applications".to_string());
+    }
+    if application.status != ApplicationStatus::Pending {
+        return Err("Application is no longer pending".to_string());
+    }
+
+    let order = create_order(OrderDraft {
+        product_id: None,
+        contract_id: None,
+        farmer: application.worker,
+        consumer: job.farmer,
+        quantity: 1,
+        unit_price: job.wage,
+        due_at: Some(job.ends_at),
+    })?;
+    if job.streamed == Some(true) {
+        open_stream(&order, job.starts_at, job.ends_at);
+    }
+
+    application.status = ApplicationStatus::Accepted;
+    application.order_id = Some(order.id);
+    save_application(application.clone());
+    notify(
+        application.worker,
+        format!(
+            "You were hired for job #{}; wages are paid through order #{}",
+            job.id, order.id
+        ),
+    );
+
+    job.hired += 1;
+    if job.hired == job.positions {
+        job.status = LaborJobStatus::Filled;
+        reject_pending_applications(job.id);
+    }
+    save_job(job);
+    Ok(application)
+}
+
+// Function for the farmer to withdraw a job; hired workers keep their wage orders
+#[ic_cdk::update]
+fn cancel_labor_job(job_id: u64) -> Result<(), String> {
+    let mut job = get_job_record(job_id)?;
+
+    if job.farmer != caller_account() {
+        return Err("Only the farmer who posted this job can cancel it".to_string());
+    }
+    if job.status != LaborJobStatus::Open {
+        return Err("Job is no longer open".to_string());
+    }
+
+    reject_pending_applications(job_id);
+    job.status = LaborJobStatus::Cancelled;
+    save_job(job);
+    Ok(())
+}
+
+// Function for a hired worker to report the work done once the wage is escrowed
+#[ic_cdk::update]
+fn mark_work_done(application_id: u64) -> Result<(), String> {
+    let application = get_application_record(application_id)?;
+    let mut order = wage_order(&application)?;
+
+    if application.worker != caller_account() {
+        return Err("Only the hired worker can report the work done".to_string());
+    }
+    if order.status != OrderStatus::Funded {
+        return Err("Wage has not been escrowed".to_string());
+    }
+
+    order.status = OrderStatus::Shipped;
+    save_order(order.clone());
+    notify(
+        order.consumer,
+        format!(
+            "Worker reported application #{} done; confirm to release the wage",
+            application.id
+        ),
+    );
+    Ok(())
+}
+
+// Function for the farmer to confirm the work, releasing the escrowed wage to the worker
+#[ic_cdk::update]
+fn confirm_work_completed(application_id: u64) -> Result<(), String> {
+    let application = get_application_record(application_id)?;
+    let order = wage_order(&application)?;
+
+    if order.consumer != caller_account() {
+        return Err("Only the farmer can confirm the work".to_string());
+    }
+    if order.status != OrderStatus::Shipped {
+        return Err("Worker has not reported the work done".to_string());
+    }
+
+    complete_order(order);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_labor_job(job_id: u64) -> Result<LaborJob, String> {
+    get_job_record(job_id)
+}
+
+// Lists jobs still hiring, optionally only at one location
+#[ic_cdk::query]
+fn list_open_labor_jobs(location: Option<String>) -> Vec<LaborJob> {
+    LABOR_JOBS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| job.status == LaborJobStatus::Open)
+            .filter(|job| {
+                location
+                    .as_ref()
+                    .map_or(true, |location| job.location.eq_ignore_ascii_case(location))
+            })
+            .collect()
+    })
+}
+
+// The farmer sees every application on their job; a worker sees only their own
+#[ic_cdk::query]
+fn get_job_applications(job_id: u64) -> Result<Vec<JobApplication>, String> {
+    let job = get_job_record(job_id)?;
+    let caller = caller_account();
+
+    Ok(job_applications(job_id)
+        .into_iter()
+        .filter(|application| job.farmer == caller || application.worker == caller)
+        .collect())
+}
+
+#[ic_cdk::query]
+fn get_my_job_applications() -> Vec<JobApplication> {
+    let caller = caller_account();
+    APPLICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, application)| application)
+            .filter(|application| application.worker == caller)
+            .collect()
+    })
+}
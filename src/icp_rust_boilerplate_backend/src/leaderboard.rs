@@ -0,0 +1,211 @@
+use crate::orders::Order;
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Longest window a leaderboard covers; older buckets are pruned
+const MAX_WINDOW_DAYS: u64 = 365;
+const LEADERBOARD_SIZE: usize = 20;
+
+// LeaderboardMetric Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LeaderboardMetric {
+    // Completed orders
+    Sales,
+    // Value of completed orders
+    Volume,
+    // Average rating received, in hundredths
+    Rating,
+}
+
+// LeaderboardPeriod Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LeaderboardPeriod {
+    Week,
+    Month,
+    Year,
+}
+
+impl LeaderboardPeriod {
+    fn days(self) -> u64 {
+        match self {
+            LeaderboardPeriod::Week => 7,
+            LeaderboardPeriod::Month => 30,
+            LeaderboardPeriod::Year => MAX_WINDOW_DAYS,
+        }
+    }
+}
+
+// LeaderboardEntry Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LeaderboardEntry {
+    rank: u64,
+    farmer: Principal,
+    value: u64,
+}
+
+// DailyStats Struct
+// One farmer's activity on one day
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct DailyStats {
+    sales: u64,
+    volume: u64,
+    rating_total: u64,
+    rating_count: u64,
+}
+
+// DayFarmerKey Struct
+// Day number first, so a window is one contiguous range
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DayFarmerKey {
+    day: u64,
+    farmer: Principal,
+}
+
+// Storable and BoundedStorable implementations for DailyStats
+impl Storable for DailyStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DailyStats {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for DayFarmerKey
+impl Storable for DayFarmerKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.day.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.farmer.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (day, farmer) = bytes.split_at(8);
+        DayFarmerKey {
+            day: u64::from_be_bytes(day.try_into().unwrap()),
+            farmer: Principal::from_slice(farmer),
+        }
+    }
+}
+
+impl BoundedStorable for DayFarmerKey {
+    const MAX_SIZE: u32 = 37;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static DAILY_STATS_STORAGE: RefCell<StableBTreeMap<DayFarmerKey, DailyStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(123)))
+    ));
+}
+
+fn today() -> u64 {
+    ic_cdk::api::time() / NANOS_PER_DAY
+}
+
+fn update_today(farmer: Principal, update: impl FnOnce(&mut DailyStats)) {
+    let key = DayFarmerKey {
+        day: today(),
+        farmer,
+    };
+    DAILY_STATS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut stats = storage.get(&key).unwrap_or_default();
+        update(&mut stats);
+        storage.insert(key, stats);
+    });
+}
+
+// Credits a completed produce order to the selling farmer
+pub(crate) fn record_completed_sale(order: &Order) {
+    if order.product_id.is_none() {
+        return;
+    }
+    let volume = order.quantity.saturating_mul(order.unit_price);
+    update_today(order.farmer, |stats| {
+        stats.sales += 1;
+        stats.volume = stats.volume.saturating_add(volume);
+    });
+}
+
+pub(crate) fn record_rating(farmer: Principal, rating: u8) {
+    update_today(farmer, |stats| {
+        stats.rating_total += rating as u64;
+        stats.rating_count += 1;
+    });
+}
+
+// Drops daily buckets that have aged out of the longest window
+pub(crate) fn prune_leaderboard() {
+    let cutoff = today().saturating_sub(MAX_WINDOW_DAYS);
+    DAILY_STATS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let expired: Vec<DayFarmerKey> = storage
+            .iter()
+            .take_while(|(key, _)| key.day < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            storage.remove(&key);
+        }
+    });
+}
+
+// Top farmers by a metric over the trailing week, month or year
+#[ic_cdk::query]
+fn get_leaderboard(metric: LeaderboardMetric, period: LeaderboardPeriod) -> Vec<LeaderboardEntry> {
+    let start = DayFarmerKey {
+        day: (today() + 1).saturating_sub(period.days()),
+        farmer: Principal::management_canister(),
+    };
+
+    let mut totals: BTreeMap<Principal, DailyStats> = BTreeMap::new();
+    DAILY_STATS_STORAGE.with(|storage| {
+        for (key, stats) in storage.borrow().range(start..) {
+            let total = totals.entry(key.farmer).or_default();
+            total.sales += stats.sales;
+            total.volume = total.volume.saturating_add(stats.volume);
+            total.rating_total += stats.rating_total;
+            total.rating_count += stats.rating_count;
+        }
+    });
+
+    let mut ranked: Vec<(Principal, u64, u64)> = totals
+        .into_iter()
+        .filter_map(|(farmer, total)| match metric {
+            LeaderboardMetric::Sales if total.sales > 0 => Some((farmer, total.sales, 0)),
+            LeaderboardMetric::Volume if total.volume > 0 => Some((farmer, total.volume, 0)),
+            // Ties on the average go to the farmer with more ratings
+            LeaderboardMetric::Rating if total.rating_count > 0 => Some((
+                farmer,
+                total.rating_total * 100 / total.rating_count,
+                total.rating_count,
+            )),
+            _ => None,
+        })
+        .collect();
+    ranked.sort_by(|a, b| (b.1, b.2).cmp(&(a.1, a.2)));
+
+    ranked
+        .into_iter()
+        .take(LEADERBOARD_SIZE)
+        .enumerate()
+        .map(|(index, (farmer, value, _))| LeaderboardEntry {
+            rank: index as u64 + 1,
+            farmer,
+            value,
+        })
+        .collect()
+}
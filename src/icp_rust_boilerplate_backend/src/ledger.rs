@@ -0,0 +1,377 @@
+use crate::governance::is_governor;
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Cell;
+use std::{cell::RefCell, thread::LocalKey};
+
+// Asset Enum
+// A token the marketplace can escrow and settle in, each on its own ICRC ledger
+#[derive(
+    candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum Asset {
+    // The ledger set with `set_ledger_canister`, normally ICP
+    Icp,
+    CkBtc,
+    CkUsdc,
+}
+
+// AssetAmount Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AssetAmount {
+    pub(crate) asset: Asset,
+    pub(crate) amount: u64,
+}
+
+// Account Struct (ICRC-1)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Account {
+    pub(crate) owner: Principal,
+    pub(crate) subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+// TransferDedup Struct
+// Lets the ledger recognise a transfer that is made again, e.g. by a retry after a call whose
+// outcome was unknown: a repeat with the same time and memo is rejected as a duplicate of the
+// first rather than paid twice
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TransferDedup {
+    pub(crate) created_at: u64,
+    pub(crate) memo: u64,
+}
+
+impl TransferDedup {
+    fn memo(self) -> Vec<u8> {
+        self.memo.to_be_bytes().to_vec()
+    }
+}
+
+// TransferFromArgs Struct (ICRC-2)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// TransferArg Struct (ICRC-1)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// TransferError Enum (ICRC-1)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// TransferFromError Enum (ICRC-2)
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+thread_local! {
+    // Principal of the ICRC ledger used for escrow; empty until an administrator sets it
+    static LEDGER_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), Vec::new())
+            .expect("Cannot create the ledger cell")
+    );
+
+    // Principal of the ckBTC ledger; empty until governance sets it
+    static CKBTC_LEDGER_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(205))), Vec::new())
+            .expect("Cannot create the ckBTC ledger cell")
+    );
+
+    // Principal of the ckUSDC ledger; empty until governance sets it
+    static CKUSDC_LEDGER_CANISTER: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(208))), Vec::new())
+            .expect("Cannot create the ckUSDC ledger cell")
+    );
+}
+
+fn ledger_cell(asset: Asset) -> &'static LocalKey<RefCell<Cell<Vec<u8>, Memory>>> {
+    match asset {
+        Asset::Icp => &LEDGER_CANISTER,
+        Asset::CkBtc => &CKBTC_LEDGER_CANISTER,
+        Asset::CkUsdc => &CKUSDC_LEDGER_CANISTER,
+    }
+}
+
+// Adds to the running total of one asset in a list of per-asset totals
+pub(crate) fn add_asset_amount(totals: &mut Vec<AssetAmount>, asset: Asset, amount: u64) {
+    match totals.iter_mut().find(|total| total.asset == asset) {
+        Some(total) => total.amount = total.amount.saturating_add(amount),
+        None if amount > 0 => totals.push(AssetAmount { asset, amount }),
+        None => {}
+    }
+}
+
+// Takes from the running total of one asset, dropping totals that reach zero
+pub(crate) fn sub_asset_amount(totals: &mut Vec<AssetAmount>, asset: Asset, amount: u64) {
+    if let Some(total) = totals.iter_mut().find(|total| total.asset == asset) {
+        total.amount = total.amount.saturating_sub(amount);
+    }
+    totals.retain(|total| total.amount > 0);
+}
+
+fn ledger_canister(asset: Asset) -> Result<Principal, String> {
+    let bytes = ledger_cell(asset).with(|cell| cell.borrow().get().clone());
+    if bytes.is_empty() {
+        return Err(format!("{:?} ledger canister is not configured", asset));
+    }
+    Principal::try_from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+fn block_index(block: Nat) -> Result<u64, String> {
+    u64::try_from(block.0).map_err(|_| "Block index overflows".to_string())
+}
+
+// Subaccount of this canister that escrows funds for one record, e.g. a savings group;
+// `tag` is a short ASCII label kept apart from the big-endian id
+pub(crate) fn escrow_subaccount(tag: &[u8], id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0u8; 32];
+    subaccount[..tag.len()].copy_from_slice(tag);
+    subaccount[24..].copy_from_slice(&id.to_be_bytes());
+    subaccount
+}
+
+// Pulls funds the owner pre-approved for this canister into its own account
+pub(crate) async fn transfer_from(from: Principal, amount: u64) -> Result<u64, String> {
+    transfer_between(from, ic_cdk::id(), amount).await
+}
+
+// Moves funds the owner pre-approved for this canister straight to another account
+pub(crate) async fn transfer_between(
+    from: Principal,
+    to: Principal,
+    amount: u64,
+) -> Result<u64, String> {
+    transfer_from_into(Asset::Icp, from, to.into(), amount, None).await
+}
+
+// Pulls funds the owner pre-approved for this canister into one of its escrow subaccounts
+pub(crate) async fn transfer_into_subaccount(
+    from: Principal,
+    subaccount: Vec<u8>,
+    amount: u64,
+) -> Result<u64, String> {
+    transfer_asset_into_subaccount(Asset::Icp, from, subaccount, amount).await
+}
+
+// Same as `transfer_into_subaccount`, on the ledger of the given asset
+pub(crate) async fn transfer_asset_into_subaccount(
+    asset: Asset,
+    from: Principal,
+    subaccount: Vec<u8>,
+    amount: u64,
+) -> Result<u64, String> {
+    let to = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    };
+    transfer_from_into(asset, from, to, amount, None).await
+}
+
+// A transfer made with `dedup` that the ledger already recorded returns that transfer's block
+async fn transfer_from_into(
+    asset: Asset,
+    from: Principal,
+    to: Account,
+    amount: u64,
+    dedup: Option<TransferDedup>,
+) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: dedup.map(TransferDedup::memo),
+        created_at_time: dedup.map(|dedup| dedup.created_at),
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger_canister(asset)?, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+
+    match result {
+        Ok(block) => block_index(block),
+        Err(TransferFromError::Duplicate { duplicate_of }) => block_index(duplicate_of),
+        Err(TransferFromError::InsufficientAllowance { allowance }) => {
+            Err(format!("Insufficient allowance: {}", allowance))
+        }
+        Err(TransferFromError::InsufficientFunds { balance }) => {
+            Err(format!("Insufficient funds: {}", balance))
+        }
+        Err(e) => Err(format!("Transfer failed: {:?}", e)),
+    }
+}
+
+// Pays out of one of this canister's escrow subaccounts; the ledger fee comes out of
+// the subaccount on top of `amount`. A transfer made with `dedup` that the ledger already
+// recorded returns that transfer's block.
+async fn transfer_out(
+    asset: Asset,
+    from_subaccount: Vec<u8>,
+    to: Account,
+    amount: u64,
+    dedup: Option<TransferDedup>,
+) -> Result<u64, String> {
+    let args = TransferArg {
+        from_subaccount: Some(from_subaccount),
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: dedup.map(TransferDedup::memo),
+        created_at_time: dedup.map(|dedup| dedup.created_at),
+    };
+
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger_canister(asset)?, "icrc1_transfer", (args,))
+            .await
+            .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+
+    match result {
+        Ok(block) => block_index(block),
+        Err(TransferError::Duplicate { duplicate_of }) => block_index(duplicate_of),
+        Err(TransferError::InsufficientFunds { balance }) => {
+            Err(format!("Insufficient funds: {}", balance))
+        }
+        Err(e) => Err(format!("Transfer failed: {:?}", e)),
+    }
+}
+
+// Pays an escrowed amount out of a subaccount, the ledger fee coming out of the amount
+pub(crate) async fn release_escrow(
+    subaccount: Vec<u8>,
+    to: Principal,
+    amount: u64,
+) -> Result<u64, String> {
+    release_asset_escrow(Asset::Icp, subaccount, to, amount).await
+}
+
+// Same as `release_escrow`, on the ledger of the given asset
+pub(crate) async fn release_asset_escrow(
+    asset: Asset,
+    subaccount: Vec<u8>,
+    to: Principal,
+    amount: u64,
+) -> Result<u64, String> {
+    release_asset_escrow_to_account(asset, subaccount, to.into(), amount, None)
+        .await
+        .map(|(block_index, _)| block_index)
+}
+
+// Same as `release_asset_escrow`, to any account, e.g. a farmer's payout subaccount, and
+// deduplicated by the ledger when made again with the same `dedup`. Returns the block index
+// and the amount that arrived after the fee.
+pub(crate) async fn release_asset_escrow_to_account(
+    asset: Asset,
+    subaccount: Vec<u8>,
+    to: Account,
+    amount: u64,
+    dedup: Option<TransferDedup>,
+) -> Result<(u64, u64), String> {
+    let fee = ledger_fee(asset).await?;
+    if amount <= fee {
+        return Err("Amount does not cover the ledger fee".to_string());
+    }
+    let delivered = amount - fee;
+    transfer_out(asset, subaccount, to, delivered, dedup)
+        .await
+        .map(|block_index| (block_index, delivered))
+}
+
+// Balance the ledger holds for one of this canister's subaccounts
+pub(crate) async fn subaccount_balance(subaccount: Vec<u8>) -> Result<u64, String> {
+    asset_subaccount_balance(Asset::Icp, subaccount).await
+}
+
+// Same as `subaccount_balance`, on the ledger of the given asset
+pub(crate) async fn asset_subaccount_balance(
+    asset: Asset,
+    subaccount: Vec<u8>,
+) -> Result<u64, String> {
+    let account = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    };
+    let (balance,): (Nat,) = ic_cdk::call(ledger_canister(asset)?, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+    u64::try_from(balance.0).map_err(|_| "Balance overflows".to_string())
+}
+
+// Fee the ledger charges per transfer
+pub(crate) async fn ledger_fee(asset: Asset) -> Result<u64, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger_canister(asset)?, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {:?} {}", code, message))?;
+    u64::try_from(fee.0).map_err(|_| "Ledger fee overflows".to_string())
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_ledger_canister(ledger: Principal) -> Result<(), String> {
+    set_asset_ledger(Asset::Icp, ledger)
+}
+
+#[ic_cdk::query]
+fn get_ledger_canister() -> Option<Principal> {
+    ledger_canister(Asset::Icp).ok()
+}
+
+// Function for governance to set the ledger canister of a settlement asset
+#[ic_cdk::update]
+pub(crate) fn set_asset_ledger(asset: Asset, ledger: Principal) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the ledger".to_string());
+    }
+
+    ledger_cell(asset)
+        .with(|cell| cell.borrow_mut().set(ledger.as_slice().to_vec()))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_asset_ledger(asset: Asset) -> Option<Principal> {
+    ledger_canister(asset).ok()
+}
@@ -1,10 +1,206 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
 // use ic_cdk::api::time;
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, thread::LocalKey, time::Duration};
+
+mod account_deletion;
+mod analytics;
+mod arbiters;
+mod archive;
+mod attestations;
+mod auto_accept;
+mod bid_deposits;
+mod bids;
+mod campaigns;
+mod cart;
+mod certificates;
+mod consumers;
+mod contracts;
+mod coop_directory;
+mod cooperatives;
+mod coupons;
+mod credit;
+mod dashboard;
+mod deliveries;
+mod delivery_deadlines;
+mod delivery_radius;
+mod devices;
+mod display_prices;
+mod disputes;
+mod duplicates;
+mod equipment;
+mod events;
+mod export;
+mod extension;
+mod farmer_pages;
+mod featured;
+mod flash_sales;
+mod forum;
+mod governance;
+mod guard;
+mod idempotency;
+mod identity;
+mod inspections;
+mod installments;
+mod insurance;
+mod inventory;
+mod invoices;
+mod jury;
+mod knowledge;
+mod labor;
+mod leaderboard;
+mod ledger;
+mod listing_assets;
+mod loans;
+mod logistics;
+mod loyalty;
+mod market_days;
+mod mobile_money;
+mod moderation;
+mod notifications;
+mod offtake;
+mod order_escrow;
+mod orders;
+mod payout_accounts;
+mod payout_schedules;
+mod pools;
+mod price_history;
+mod pricing;
+mod publisher;
+mod raffles;
+mod receipts;
+mod reconciliation;
+mod referrals;
+mod retention;
+mod retry_queue;
+mod returns;
+mod reviews;
+mod risk;
+mod sagas;
+mod sales_history;
+mod savings;
+mod sealed_auctions;
+mod seasons;
+mod sensors;
+mod shards;
+mod shipping;
+mod snapshots;
+mod split_orders;
+mod stale_listings;
+mod stats;
+mod storage_space;
+mod streams;
+mod subscriptions;
+mod supplies;
+mod taxes;
+mod tombstones;
+mod traceability;
+mod transport;
+mod tracking;
+mod trending;
+mod verification;
+mod warehouses;
+mod wasm_store;
+
+use account_deletion::{DeletionRequest, LegalHold};
+use analytics::{CategoryAnalytics, SalesVolume};
+use arbiters::{Arbiter, Assignment};
+use attestations::{AttestationRecord, SignedDocument};
+use auto_accept::AutoAcceptRule;
+use bid_deposits::BidDeposit;
+use bids::{
+    accept_bid_record, get_bid_record, listing_bids, place_bid, reject_bid_record,
+    reject_open_bids, withdraw_bid_record, Bid, BidStatus, ListingKind,
+};
+use campaigns::{Campaign, CampaignPayload, Contribution};
+use cart::{Cart, CartQuote, Checkout};
+use certificates::{CertificateTransferArg, CertificateTransferError, SupportedStandard, Value};
+use consumers::Consumer;
+use contracts::{ContractPayload, FarmingContract};
+use coop_directory::{CoopInstance, DirectoryListing};
+use cooperatives::Cooperative;
+use coupons::{Coupon, CouponPayload, CouponRedemption};
+use credit::CreditProfile;
+use dashboard::Dashboard;
+use deliveries::{Delivery, DeliveryPayload, DeliveryProofPayload, DeliveryStatus};
+use devices::DeviceLink;
+use display_prices::PriceDisplay;
+use disputes::{Dispute, DisputeEvidence};
+use equipment::{EquipmentItem, EquipmentItemPayload, EquipmentKind, EquipmentRental};
+use events::{EventCheckpoint, LoggedEvent};
+use export::{ExportCollection, ExportPage};
+use extension::{AdvisorKind, AdvisorySession, ExtensionAdvisor, ExtensionAdvisorPayload};
+use farmer_pages::FarmerPage;
+use flash_sales::FlashSale;
+use forum::{ForumAnswer, ForumQuestion, ForumScope, ForumThread};
+use governance::GovernanceAction;
+use guard::BlacklistEntry;
+use identity::{IdentityLink, PersonhoodAttestation, PersonhoodProvider, SybilPolicy};
+use inspections::{Inspection, Inspector};
+use installments::InstallmentPayload;
+use insurance::{ClaimPayload, InsuranceClaim, InsurancePolicy, Insurer, PolicyPayload};
+use invoices::Invoice;
+use jury::{JuryCase, Juror};
+use knowledge::{Article, ArticlePage, ArticlePayload};
+use labor::{JobApplication, LaborJob, LaborJobPayload};
+use leaderboard::{LeaderboardEntry, LeaderboardMetric, LeaderboardPeriod};
+use ledger::{Account, Asset};
+use listing_assets::AssetPrice;
+use loans::{Lender, Loan, LoanRequestPayload};
+use logistics::{LogisticsPartner, LogisticsPartnerPayload};
+use loyalty::{LoyaltyRates, PointsEntry};
+use market_days::{MarketDay, MarketDayPayload, MarketRsvp};
+use mobile_money::{MobileMoneyPayout, MobileMoneyProvider, MobileMoneySettlement};
+use moderation::ModerationItem;
+use notifications::Notification;
+use offtake::{OfftakeAgreement, OfftakePayload, WholesaleBuyer};
+use orders::{Order, OrderStatus, OrderStatusChange, TrackedOrder};
+use payout_accounts::{MobileMoneyReference, PayoutAccount};
+use payout_schedules::{PayoutFrequency, PayoutSchedule, ScheduledPayout};
+use pools::{PoolPayload, PurchasePool};
+use price_history::{
+    price_warning, record_price_change, suggest_price, PriceBand, PricePoint, TimeRange,
+};
+use pricing::PriceTier;
+use publisher::{EventTopic, OutboxEntry, Subscriber};
+use raffles::{Raffle, RaffleEntry, RafflePayload};
+use receipts::Receipt;
+use reconciliation::ReconciliationReport;
+use referrals::{ReferralRewards, ReferralStats};
+use retention::{RetentionPolicy, RetentionReport, RetentionTarget};
+use retry_queue::{CallStatus, QueuedCall};
+use returns::ReturnRequest;
+use reviews::{Review, ReviewRewardPool};
+use risk::{RiskAction, RiskProfile};
+use sagas::{Saga, SagaStatus};
+use sales_history::SalesHistory;
+use savings::{SavingsEntry, SavingsGroup, SavingsGroupPayload};
+use sealed_auctions::{SealedAuction, SealedBid};
+use seasons::{CropSeason, SeasonCheck};
+use sensors::{SensorBatchPayload, SensorReading};
+use shards::Shard;
+use shipping::ShippingQuote;
+use snapshots::SnapshotManifest;
+use split_orders::{SplitOrder, SplitOrderSummary};
+use stale_listings::{ArchivedListing, StaleListing};
+use stats::MarketplaceStats;
+use storage_space::{StorageBooking, StorageListing, StorageListingPayload};
+use streams::PaymentStream;
+use subscriptions::{Subscription, SubscriptionPayload};
+use supplies::{InputCategory, InputSupplier, SupplyListing, SupplyListingPayload};
+use taxes::{TaxPeriodTotal, TaxRule, TaxRulePayload};
+use tombstones::{DeletedListing, Tombstone};
+use traceability::{record_provenance, QrPayload};
+use tracking::DeliveryTrack;
+use trending::TrendingListing;
+use transport::{TransportJob, TransportJobPayload};
+use verification::Verification;
+use warehouses::{ReceiptPayload, WarehouseOperator, WarehouseReceipt};
+use wasm_store::WasmModuleInfo;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
@@ -33,6 +229,17 @@ struct ProductRecord {
     farmer_address: String,
 }
 
+// ListedProduct Struct
+// A new listing with the price band recent sales suggest for it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ListedProduct {
+    product: Farmer,
+    suggested_price: Option<PriceBand>,
+    price_warning: Option<String>,
+    // Near-identical active listing the farmer chose to repeat
+    duplicate_of: Option<u64>,
+}
+
 // Storable and BoundedStorable implementations for Farmer
 impl Storable for Farmer {
     fn to_bytes(&self) -> Cow<[u8]> {
@@ -65,6 +272,48 @@ impl BoundedStorable for ProductRecord {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// PrincipalKey Struct
+// Lets principals key stable maps
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(Principal);
+
+// Storable and BoundedStorable implementations for PrincipalKey
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// CodeKey Struct
+// Lets short user-facing codes (referral, coupon) key stable maps
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CodeKey(String);
+
+// Storable and BoundedStorable implementations for CodeKey
+impl Storable for CodeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        CodeKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for CodeKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -95,6 +344,10 @@ struct FarmerPayload {
     category: String,
     price: u64,
     product_status: String,
+    // Region the listing ships from, also used to suggest a price
+    region: Option<String>,
+    // Lists the product even when a near-identical active listing exists
+    allow_duplicate: Option<bool>,
 }
 
 // Product_bid Payload
@@ -102,6 +355,8 @@ struct FarmerPayload {
 struct ProductBidPayload {
     farmer_id: u64,
     consumer_address: String,
+    // Offered price; the listing price when left out
+    amount: Option<u64>,
 }
 
 // Mark_Product_Sold Payload
@@ -118,48 +373,176 @@ struct WithdrawFromEscrowPayload {
     amount: u64,
 }
 
+// Helper Functions
+
+// Controllers act as marketplace administrators
+fn is_admin() -> bool {
+    ic_cdk::api::is_controller(&ic_cdk::caller())
+}
+
+fn next_id(counter: &'static LocalKey<RefCell<IdCell>>) -> u64 {
+    counter
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ID counter")
+}
+
+// The farmer's `address` holds the principal text of the listing owner
+fn is_farmer_owner(farmer: &Farmer) -> bool {
+    farmer.address == devices::caller_account().to_text()
+}
+
+// Status of a listing its farmer took off the market
+const WITHDRAWN_STATUS: &str = "Withdrawn";
+
+// Withdrawn and deleted listings are left out of the catalog and cannot be bought
+fn is_off_market(farmer: &Farmer) -> bool {
+    farmer.product_status == WITHDRAWN_STATUS || tombstones::is_deleted(farmer.id)
+}
+
+fn farmer_principal(farmer: &Farmer) -> Result<Principal, String> {
+    Principal::from_text(&farmer.address)
+        .map_err(|_| "Farmer address is not a valid principal".to_string())
+}
+
 // Accessor Functions
 
-#[ic_cdk::query]
-fn get_product_description(farmer_id: u64) -> Result<String, String> {
+#[ic_cdk::query(composite = true)]
+async fn get_product_description(farmer_id: u64) -> Result<String, String> {
+    if let Some(shard) = shards::listing_shard(farmer_id) {
+        return shards::call_shard(&shard, "get_product_description", (farmer_id,)).await;
+    }
     FARMERS_STORAGE.with(|storage| {
-        storage.borrow().get(&farmer_id).map_or_else(
-            || Err("Farmer not found".to_string()),
-            |farmer| Ok(farmer.bio.clone()),
-        )
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .filter(tombstones::is_visible)
+            .map_or_else(
+                || Err("Farmer not found".to_string()),
+                |farmer| Ok(farmer.bio.clone()),
+            )
     })
 }
 
-#[ic_cdk::query]
-fn get_product_price(farmer_id: u64) -> Result<u64, String> {
+#[ic_cdk::query(composite = true)]
+async fn get_product_price(farmer_id: u64) -> Result<u64, String> {
+    if let Some(shard) = shards::listing_shard(farmer_id) {
+        return shards::call_shard(&shard, "get_product_price", (farmer_id,)).await;
+    }
     FARMERS_STORAGE.with(|storage| {
-        storage.borrow().get(&farmer_id).map_or_else(
-            || Err("Farmer not found".to_string()),
-            |farmer| Ok(farmer.price),
-        )
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .filter(tombstones::is_visible)
+            .map_or_else(
+                || Err("Farmer not found".to_string()),
+                |farmer| Ok(farmer.price),
+            )
     })
 }
 
-#[ic_cdk::query]
-fn get_product_status(farmer_id: u64) -> Result<String, String> {
+#[ic_cdk::query(composite = true)]
+async fn get_product_status(farmer_id: u64) -> Result<String, String> {
+    if let Some(shard) = shards::listing_shard(farmer_id) {
+        return shards::call_shard(&shard, "get_product_status", (farmer_id,)).await;
+    }
     FARMERS_STORAGE.with(|storage| {
-        storage.borrow().get(&farmer_id).map_or_else(
-            || Err("Farmer not found".to_string()),
-            |farmer| Ok(farmer.product_status.clone()),
-        )
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .filter(tombstones::is_visible)
+            .map_or_else(
+                || Err("Farmer not found".to_string()),
+                |farmer| Ok(farmer.product_status.clone()),
+            )
     })
 }
 
 // Public Entry Functions
 
+// Most items one batched call (imports, bulk updates) may carry
+const MAX_BATCH_SIZE: usize = 200;
+
+// New listings go to a shard canister once this one is full. A retry with the same
+// idempotency key gets the original listing back.
 #[ic_cdk::update]
-fn add_product(payload: FarmerPayload) -> Result<Farmer, String> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
+async fn add_product(
+    payload: FarmerPayload,
+    idempotency_key: Option<String>,
+) -> Result<ListedProduct, String> {
+    if let Some(previous) = idempotency::begin("add_product", &idempotency_key)? {
+        return previous;
+    }
+    let result = if shards::routes_new_listings() {
+        shards::route_listing(payload).await
+    } else {
+        list_product(payload)
+    };
+    idempotency::finish("add_product", &idempotency_key, &result);
+    result
+}
+
+// Function for an administrator or registered cooperative to list many products in one call,
+// e.g. when onboarding members. Each payload is validated and listed on its own; the results
+// come back in the same order.
+#[ic_cdk::update]
+fn import_products(
+    payloads: Vec<FarmerPayload>,
+) -> Result<Vec<Result<ListedProduct, String>>, String> {
+    if !is_admin() && !cooperatives::is_cooperative(&devices::caller_account()) {
+        return Err("Only administrators and cooperatives can import products".to_string());
+    }
+    validate_batch_size(payloads.len())?;
+
+    Ok(payloads
+        .into_iter()
+        .map(|payload| {
+            validate_farmer_payload(&payload)?;
+            list_product(payload)
         })
-        .expect("Cannot increment ID counter");
+        .collect())
+}
+
+fn validate_farmer_payload(payload: &FarmerPayload) -> Result<(), String> {
+    if payload.name.trim().is_empty() {
+        return Err("Name is required".to_string());
+    }
+    if payload.category.trim().is_empty() {
+        return Err("Category is required".to_string());
+    }
+    if payload.price == 0 {
+        return Err("Price must be greater than zero".to_string());
+    }
+    Principal::from_text(&payload.address)
+        .map_err(|_| "Farmer address is not a valid principal".to_string())?;
+    Ok(())
+}
+
+fn list_product(payload: FarmerPayload) -> Result<ListedProduct, String> {
+    if !shards::has_local_capacity() {
+        return Err("Listing storage is full".to_string());
+    }
+    let region = payload
+        .region
+        .map(|region| shipping::normalise_region(&region))
+        .transpose()?;
+    let duplicate_of = duplicates::find_duplicate(&duplicates::listing_fingerprint(
+        &payload.address,
+        &payload.name,
+        &payload.category,
+        payload.price,
+    ));
+    if let Some(existing) = duplicate_of {
+        if !payload.allow_duplicate.unwrap_or(false) {
+            return Err(format!(
+                "Listing #{} is nearly identical; set allow_duplicate to list it anyway",
+                existing
+            ));
+        }
+    }
+    let id = next_id(&ID_COUNTER);
 
     let farmer = Farmer {
         id,
@@ -177,37 +560,193 @@ fn add_product(payload: FarmerPayload) -> Result<Farmer, String> {
     };
 
     FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(id, farmer.clone()));
+    record_provenance(id, "Listed");
+    record_price_change(id, &farmer.category, farmer.price);
+    analytics::record_listing(id, &farmer.category);
+    stats::record_listing_created();
+    if let Some(region) = &region {
+        shipping::save_listing_region(id, region.clone());
+    }
+    if let Ok(owner) = farmer_principal(&farmer) {
+        credit::record_farmer_joined(owner);
+        risk::check_new_listing(&farmer, owner);
+    }
 
-    Ok(farmer)
+    let suggested_price = suggest_price(&farmer.category, region.as_deref());
+    let price_warning = suggested_price
+        .as_ref()
+        .and_then(|band| price_warning(farmer.price, band));
+    Ok(ListedProduct {
+        product: farmer,
+        suggested_price,
+        price_warning,
+        duplicate_of,
+    })
 }
 
-// Function for a consumer to bid on a product
+// Function for a consumer to bid on a product, locking the bid deposit if one is set
 #[ic_cdk::update]
-fn product_bid(payload: ProductBidPayload) -> Result<(), String> {
+async fn product_bid(payload: ProductBidPayload) -> Result<(), String> {
     let mut farmer = FARMERS_STORAGE
         .with(|storage| storage.borrow_mut().get(&payload.farmer_id))
         .ok_or("Farmer not found".to_string())?;
+    if is_off_market(&farmer) {
+        return Err("Listing is no longer available".to_string());
+    }
+    if farmer.consumer_address.is_some() {
+        return Err("Product already bid on".to_string());
+    }
+    if sealed_auctions::is_in_auction(payload.farmer_id) {
+        return Err("Listing is in a sealed-bid auction".to_string());
+    }
+    let owner = farmer_principal(&farmer)?;
+    let bidder = devices::caller_account();
+    if bidder == owner || payload.consumer_address == farmer.address {
+        risk::flag_risk(
+            owner,
+            risk::RiskKind::SelfDealing,
+            format!("Bid on own listing #{}", payload.farmer_id),
+        );
+    }
+    let amount = payload.amount.unwrap_or(farmer.price);
+    if amount == 0 {
+        return Err("Bid amount must be greater than zero".to_string());
+    }
 
-    if farmer.consumer_address.is_none() {
-        farmer.consumer_address = Some(payload.consumer_address);
-        farmer.product_status = "Bid Placed".to_string();
-        FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(payload.farmer_id, farmer));
-        Ok(())
-    } else {
-        Err("Product already bid on".to_string())
+    // Hold the product for this bid before awaiting the deposit
+    let bid = place_bid(
+        ListingKind::Produce,
+        payload.farmer_id,
+        bidder,
+        amount,
+        String::new(),
+    )?;
+    farmer.consumer_address = Some(payload.consumer_address);
+    farmer.product_status = "Bid Placed".to_string();
+    FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(payload.farmer_id, farmer));
+    record_provenance(payload.farmer_id, "Bid Placed");
+
+    if let Err(e) = bid_deposits::lock_deposit(&bid, owner).await {
+        if withdraw_bid_record(bid).is_ok() {
+            reopen_product(payload.farmer_id, "Bid Cancelled");
+        }
+        return Err(e);
     }
+
+    // A bid at the farmer's auto-accept price is taken straight away, unless it was decided
+    // while the deposit was in flight
+    if auto_accept::meets_threshold(payload.farmer_id, bid.amount)
+        && get_bid_record(bid.id)?.status == BidStatus::Open
+    {
+        accept_open_bid(payload.farmer_id)?;
+        auto_accept::order_accepted_bid(&bid, owner)?;
+    }
+    Ok(())
+}
+
+// Clears the bidder's hold on a product once its bid is gone, so others can bid
+fn reopen_product(farmer_id: u64, event: &str) {
+    let mut farmer = match FARMERS_STORAGE.with(|storage| storage.borrow().get(&farmer_id)) {
+        Some(farmer) => farmer,
+        None => return,
+    };
+    farmer.consumer_address = None;
+    farmer.product_status = "Available".to_string();
+    FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+    record_provenance(farmer_id, event);
+}
+
+// Function for a consumer to take back their bid before the farmer accepts it, leaving the
+// product open to other bids
+#[ic_cdk::update]
+fn cancel_bid(farmer_id: u64) -> Result<(), String> {
+    if !FARMERS_STORAGE.with(|storage| storage.borrow().contains_key(&farmer_id)) {
+        return Err("Farmer not found".to_string());
+    }
+    let bidder = devices::caller_account();
+    let bid = listing_bids(ListingKind::Produce, farmer_id)
+        .into_iter()
+        .find(|bid| bid.bidder == bidder && bid.status == BidStatus::Open)
+        .ok_or("You have no open bid on this product".to_string())?;
+
+    withdraw_bid_record(bid)?;
+    reopen_product(farmer_id, "Bid Cancelled");
+    Ok(())
+}
+
+// Function for a farmer to turn down the bid on their product, reopening it to other bids
+#[ic_cdk::update]
+fn reject_bid(bid_id: u64, reason: String) -> Result<(), String> {
+    let bid = get_bid_record(bid_id)?;
+    if bid.kind != ListingKind::Produce {
+        return Err("Not a produce bid".to_string());
+    }
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&bid.listing_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can reject bids on this product".to_string());
+    }
+
+    let farmer_id = bid.listing_id;
+    reject_bid_record(bid, &reason)?;
+    reopen_product(farmer_id, "Bid Rejected");
+    Ok(())
+}
+
+// Function for a farmer to take their product off the market. Open bids on it are turned
+// down and their bidders notified.
+#[ic_cdk::update]
+fn delist_product(farmer_id: u64) -> Result<(), String> {
+    let mut farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can delist this product".to_string());
+    }
+    if farmer.product_status == WITHDRAWN_STATUS {
+        return Err("Product already delisted".to_string());
+    }
+    if farmer.is_sold || farmer.product_status == "Bid Accepted" {
+        return Err("Product already sold".to_string());
+    }
+    if farmer.dispute_status {
+        return Err("Product is under dispute".to_string());
+    }
+
+    reject_open_bids(ListingKind::Produce, farmer_id);
+    farmer.consumer_address = None;
+    farmer.product_status = WITHDRAWN_STATUS.to_string();
+    FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+    record_provenance(farmer_id, WITHDRAWN_STATUS);
+    Ok(())
 }
 
 // Function for a farmer to accept a bid on their product
 #[ic_cdk::update]
 fn accept_bid(farmer_id: u64) -> Result<(), String> {
+    accept_open_bid(farmer_id)
+}
+
+// Accepts the open bid on a product, whether the farmer or their auto-accept rule decided
+fn accept_open_bid(farmer_id: u64) -> Result<(), String> {
     let mut farmer = FARMERS_STORAGE
         .with(|storage| storage.borrow_mut().get(&farmer_id))
         .ok_or("Farmer not found".to_string())?;
     
     if farmer.consumer_address.is_some() {
+        let open_bid = listing_bids(ListingKind::Produce, farmer_id)
+            .into_iter()
+            .find(|bid| bid.status == BidStatus::Open);
+        if let Some(bid) = open_bid {
+            accept_bid_record(bid)?;
+            // The winning bidder's deposit counts towards what they owe
+            farmer.escrow_balance += bid_deposits::applied_deposit_amount(farmer_id);
+        }
         farmer.product_status = "Bid Accepted".to_string();
         FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+        record_provenance(farmer_id, "Bid Accepted");
         Ok(())
     } else {
         Err("No bid to accept".to_string())
@@ -222,9 +761,13 @@ fn mark_product_sold(payload: MarkProductSoldPayload) -> Result<(), String> {
         .ok_or("Farmer not found".to_string())?;
 
     if farmer.consumer_address.is_some() {
+        if !farmer.is_sold {
+            stats::record_listing_sold();
+        }
         farmer.is_sold = true;
         farmer.product_status = "Product Sold".to_string();
         FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(payload.farmer_id, farmer));
+        record_provenance(payload.farmer_id, "Product Sold");
         Ok(())
     } else {
         Err("No consumer to sell to".to_string())
@@ -241,6 +784,7 @@ fn dispute_product(farmer_id: u64) -> Result<(), String> {
     farmer.dispute_status = true;
     farmer.product_status = "Dispute Raised".to_string();
     FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+    record_provenance(farmer_id, "Dispute Raised");
     Ok(())
 }
 
@@ -265,7 +809,10 @@ fn resolve_dispute(farmer_id: u64, resolution: bool) -> Result<(), String> {
     };
 
     // Insert the updated farmer back into the storage
+    record_provenance(farmer_id, &farmer.product_status);
     FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+    bid_deposits::close_listing_deposit(farmer_id, resolution);
+    sealed_auctions::close_listing_deposit(farmer_id, resolution);
 
     Ok(())
 }
@@ -279,6 +826,10 @@ fn release_payment(farmer_id: u64) -> Result<(), String> {
 
     // Check if the product is sold and no dispute is unresolved
     if farmer.is_sold && !farmer.dispute_status {
+        // A payout account the farmer registered must be verified before it is paid
+        if let Ok(owner) = farmer_principal(&farmer) {
+            payout_accounts::payout_target(&owner)?;
+        }
         farmer.escrow_balance = 0;
         let product_record = ProductRecord {
             id: farmer.id,
@@ -290,6 +841,10 @@ fn release_payment(farmer_id: u64) -> Result<(), String> {
 
         // Insert the updated farmer back into the FARMERS_STORAGE
         FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+        record_provenance(farmer_id, "Payment Released");
+        bid_deposits::close_listing_deposit(farmer_id, true);
+        sealed_auctions::close_listing_deposit(farmer_id, true);
+        publisher::publish(EventTopic::PaymentReleased, farmer_id, "listing");
 
         Ok(())
     } else {
@@ -298,7 +853,17 @@ fn release_payment(farmer_id: u64) -> Result<(), String> {
 }
 
 #[ic_cdk::update]
-fn add_to_escrow(farmer_id: u64, amount: u64) -> Result<(), String> {
+fn add_to_escrow(
+    farmer_id: u64,
+    amount: u64,
+    idempotency_key: Option<String>,
+) -> Result<(), String> {
+    idempotency::run_once("add_to_escrow", idempotency_key, || {
+        credit_escrow(farmer_id, amount)
+    })
+}
+
+fn credit_escrow(farmer_id: u64, amount: u64) -> Result<(), String> {
     // Retrieve and update the farmer within a single borrow scope
     let mut farmer = FARMERS_STORAGE
         .with(|storage| storage.borrow_mut().get(&farmer_id))
@@ -363,6 +928,7 @@ fn update_product_price(farmer_id: u64, price: u64) -> Result<(), String> {
         if let Some(mut farmer) = storage.borrow_mut().get(&farmer_id) {
             farmer.price = price;
             storage.borrow_mut().insert(farmer_id, farmer.clone());
+            record_price_change(farmer_id, &farmer.category, price);
             Ok(())
         } else {
             Err("Farmer not found".to_string())
@@ -374,6 +940,7 @@ fn update_product_price(farmer_id: u64, price: u64) -> Result<(), String> {
 fn update_product_status(farmer_id: u64, status: String) -> Result<(), String> {
     FARMERS_STORAGE.with(|storage| {
         if let Some(mut farmer) = storage.borrow_mut().get(&farmer_id) {
+            record_provenance(farmer_id, &status);
             farmer.product_status = status;
             storage.borrow_mut().insert(farmer_id, farmer.clone());
             Ok(())
@@ -383,17 +950,169 @@ fn update_product_status(farmer_id: u64, status: String) -> Result<(), String> {
     })
 }
 
+fn validate_batch_size(len: usize) -> Result<(), String> {
+    if len == 0 || len > MAX_BATCH_SIZE {
+        return Err(format!("Send between 1 and {} items at a time", MAX_BATCH_SIZE));
+    }
+    Ok(())
+}
+
+// Loads a listing for a batched update, checking the caller owns it and that it appears only
+// once in the batch
+fn batch_listing(farmer_id: u64, seen: &mut Vec<u64>) -> Result<Farmer, String> {
+    if seen.contains(&farmer_id) {
+        return Err("Listing appears more than once in the batch".to_string());
+    }
+    seen.push(farmer_id);
+
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can update this listing".to_string());
+    }
+    Ok(farmer)
+}
+
+// Function for a farmer to reprice many of their listings in one call. Each update is
+// validated on its own and the results come back in the same order.
+#[ic_cdk::update]
+fn update_prices(updates: Vec<(u64, u64)>) -> Result<Vec<Result<(), String>>, String> {
+    validate_batch_size(updates.len())?;
+
+    let mut seen = Vec::with_capacity(updates.len());
+    Ok(updates
+        .into_iter()
+        .map(|(farmer_id, price)| {
+            let mut farmer = batch_listing(farmer_id, &mut seen)?;
+            if price == 0 {
+                return Err("Price must be greater than zero".to_string());
+            }
+
+            farmer.price = price;
+            record_price_change(farmer_id, &farmer.category, price);
+            FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+            Ok(())
+        })
+        .collect())
+}
+
+// Function for a farmer to set the status of many of their listings in one call
+#[ic_cdk::update]
+fn update_statuses(updates: Vec<(u64, String)>) -> Result<Vec<Result<(), String>>, String> {
+    validate_batch_size(updates.len())?;
+
+    let mut seen = Vec::with_capacity(updates.len());
+    Ok(updates
+        .into_iter()
+        .map(|(farmer_id, status)| {
+            let mut farmer = batch_listing(farmer_id, &mut seen)?;
+            if status.trim().is_empty() {
+                return Err("Status is required".to_string());
+            }
+
+            record_provenance(farmer_id, &status);
+            farmer.product_status = status;
+            FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
+            Ok(())
+        })
+        .collect())
+}
+
+// Listings of the caller's account, whichever of its devices asks
+#[ic_cdk::query]
+fn get_my_listings() -> Vec<Farmer> {
+    let address = devices::caller_account().to_text();
+    FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .filter(|farmer| farmer.address == address)
+            .collect()
+    })
+}
+
 #[ic_cdk::update]
 fn rate_farmer(farmer_id: u64, rating: u8) -> Result<(), String> {
+    identity::check_can_rate(&devices::caller_account())?;
     let mut farmer = FARMERS_STORAGE
         .with(|storage| storage.borrow_mut().get(&farmer_id))
         .ok_or("Farmer not found".to_string())?;
 
     farmer.rating = rating;
+    if let Ok(owner) = farmer_principal(&farmer) {
+        leaderboard::record_rating(owner, rating);
+    }
     FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, farmer));
     Ok(())
 }
 
+// Periodic Jobs
+
+// How often time-based jobs (expiries, refunds, recurring billing) run
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn start_timers() {
+    flash_sales::arm_flash_sale_timers();
+    // Draw the first featured rotation without waiting for the interval
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(featured::refresh_featured()));
+    ic_cdk_timers::set_timer_interval(MAINTENANCE_INTERVAL, || {
+        if guard::is_paused() {
+            return;
+        }
+        pools::expire_pools();
+        deliveries::release_delivered_orders();
+        deliveries::flag_late_deliveries();
+        tracking::flag_stalled_deliveries();
+        campaigns::settle_campaigns();
+        installments::check_missed_installments();
+        loans::check_loan_defaults();
+        leaderboard::prune_leaderboard();
+        trending::prune_trending();
+        publisher::flush_outbox();
+        arbiters::finalize_rulings();
+        arbiters::reassign_overdue_disputes();
+        jury::close_jury_votes();
+        account_deletion::process_account_deletions();
+        retention::apply_retention_rules();
+        bids::expire_bids();
+        bid_deposits::forfeit_abandoned_deposits();
+        bid_deposits::queue_deposit_payouts();
+        stale_listings::flag_stale_listings();
+        sealed_auctions::advance_sealed_auctions();
+        sealed_auctions::queue_sealed_payouts();
+        idempotency::prune_idempotency_keys();
+        sagas::sweep_sagas();
+        delivery_deadlines::refund_undelivered_orders();
+        payout_schedules::sweep_scheduled_payouts();
+        ic_cdk::spawn(subscriptions::run_subscriptions());
+        ic_cdk::spawn(streams::accrue_payment_streams());
+        ic_cdk::spawn(savings::close_due_rounds());
+        ic_cdk::spawn(equipment::settle_finished_rentals());
+        ic_cdk::spawn(featured::refresh_featured());
+        ic_cdk::spawn(raffles::draw_due_raffles());
+        ic_cdk::spawn(archive::archive_due_orders());
+        ic_cdk::spawn(stale_listings::archive_due_listings());
+        ic_cdk::spawn(arbiters::sweep_slashed_stakes());
+        ic_cdk::spawn(events::compact_due_events());
+        ic_cdk::spawn(retry_queue::process_retry_queue());
+        ic_cdk::spawn(mobile_money::process_mobile_money_payouts());
+        ic_cdk::spawn(display_prices::refresh_display_rates());
+    });
+}
+
+#[ic_cdk::init]
+fn init() {
+    start_timers();
+}
+
+// Timers do not survive upgrades and must be registered again
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    start_timers();
+}
+
 // Error types
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
@@ -0,0 +1,212 @@
+use crate::ledger::Asset;
+use crate::{is_farmer_owner, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// AssetPrice Struct
+// Unit price of a listing in the smallest unit of another asset, e.g. satoshis for ckBTC or
+// millionths of a dollar for ckUSDC
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AssetPrice {
+    asset: Asset,
+    unit_price: u64,
+}
+
+// AssetPriceTable Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct AssetPriceTable {
+    prices: Vec<AssetPrice>,
+}
+
+// AcceptedAssets Struct
+// The assets a listing can be bought in, when the farmer narrowed them down
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct AcceptedAssets {
+    assets: Vec<Asset>,
+}
+
+// Storable and BoundedStorable implementations for AssetPriceTable
+impl Storable for AssetPriceTable {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AssetPriceTable {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for AcceptedAssets
+impl Storable for AcceptedAssets {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AcceptedAssets {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ASSET_PRICES_STORAGE: RefCell<StableBTreeMap<u64, AssetPriceTable, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(206)))
+    ));
+
+    static ACCEPTED_ASSETS_STORAGE: RefCell<StableBTreeMap<u64, AcceptedAssets, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(209)))
+    ));
+}
+
+fn priced_assets(farmer_id: u64) -> Vec<Asset> {
+    ASSET_PRICES_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .map(|table| table.prices.into_iter().map(|price| price.asset).collect())
+        .unwrap_or_default()
+}
+
+// The assets a listing accepts: the farmer's own choice, or else the default currency plus
+// every asset it is priced in
+fn accepted_assets(farmer_id: u64) -> Vec<Asset> {
+    match ACCEPTED_ASSETS_STORAGE.with(|storage| storage.borrow().get(&farmer_id)) {
+        Some(accepted) => accepted.assets,
+        None => {
+            let mut assets = vec![Asset::Icp];
+            assets.extend(priced_assets(farmer_id));
+            assets
+        }
+    }
+}
+
+pub(crate) fn accepts_asset(farmer_id: u64, asset: Asset) -> bool {
+    accepted_assets(farmer_id).contains(&asset)
+}
+
+// Unit price of a listing in an asset other than the default currency; price tiers and flash
+// sales only apply to the listing's own price
+pub(crate) fn listing_asset_price(farmer_id: u64, asset: Asset) -> Result<u64, String> {
+    if !accepts_asset(farmer_id, asset) {
+        return Err(format!("Listing does not accept {:?}", asset));
+    }
+    ASSET_PRICES_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .and_then(|table| table.prices.into_iter().find(|price| price.asset == asset))
+        .map(|price| price.unit_price)
+        .ok_or(format!("Listing is not priced in {:?}", asset))
+}
+
+// Function for a farmer to price a listing in another asset, or to stop accepting it with None
+#[ic_cdk::update]
+fn set_listing_asset_price(
+    farmer_id: u64,
+    asset: Asset,
+    unit_price: Option<u64>,
+) -> Result<Vec<AssetPrice>, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can price the listing".to_string());
+    }
+    if asset == Asset::Icp {
+        return Err("The default currency price is the listing price".to_string());
+    }
+    if unit_price == Some(0) {
+        return Err("Price must be positive".to_string());
+    }
+    if unit_price.is_none()
+        && ACCEPTED_ASSETS_STORAGE
+            .with(|storage| storage.borrow().get(&farmer_id))
+            .map_or(false, |accepted| accepted.assets.contains(&asset))
+    {
+        return Err("Stop accepting the asset before removing its price".to_string());
+    }
+
+    let mut table = ASSET_PRICES_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .unwrap_or_default();
+    table.prices.retain(|price| price.asset != asset);
+    if let Some(unit_price) = unit_price {
+        table.prices.push(AssetPrice { asset, unit_price });
+    }
+
+    let prices = table.prices.clone();
+    ASSET_PRICES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if table.prices.is_empty() {
+            storage.remove(&farmer_id);
+        } else {
+            storage.insert(farmer_id, table);
+        }
+    });
+    Ok(prices)
+}
+
+#[ic_cdk::query]
+fn get_listing_asset_prices(farmer_id: u64) -> Vec<AssetPrice> {
+    ASSET_PRICES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .map(|table| table.prices)
+            .unwrap_or_default()
+    })
+}
+
+// Function for a farmer to choose which assets a listing can be bought in, e.g. only ckUSDC;
+// an empty list goes back to the default currency plus every priced asset
+#[ic_cdk::update]
+fn set_accepted_assets(farmer_id: u64, assets: Vec<Asset>) -> Result<Vec<Asset>, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can choose accepted assets".to_string());
+    }
+
+    if assets.is_empty() {
+        ACCEPTED_ASSETS_STORAGE.with(|storage| storage.borrow_mut().remove(&farmer_id));
+        return Ok(accepted_assets(farmer_id));
+    }
+
+    let priced = priced_assets(farmer_id);
+    let mut accepted: Vec<Asset> = Vec::new();
+    for asset in assets {
+        if asset != Asset::Icp && !priced.contains(&asset) {
+            return Err(format!("Price the listing in {:?} first", asset));
+        }
+        if !accepted.contains(&asset) {
+            accepted.push(asset);
+        }
+    }
+
+    ACCEPTED_ASSETS_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(
+            farmer_id,
+            AcceptedAssets {
+                assets: accepted.clone(),
+            },
+        )
+    });
+    Ok(accepted)
+}
+
+#[ic_cdk::query]
+fn get_accepted_assets(farmer_id: u64) -> Vec<Asset> {
+    accepted_assets(farmer_id)
+}
@@ -0,0 +1,392 @@
+use crate::devices::caller_account;
+use crate::ledger::transfer_between;
+use crate::notifications::notify;
+use crate::warehouses::{get_receipt_record, save_receipt};
+use crate::{is_admin, next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_INTEREST_BPS: u64 = 10_000;
+const MAX_TERM_DAYS: u64 = 365;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Lender Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Lender {
+    principal: Principal,
+    name: String,
+    registered_at: u64,
+}
+
+// LoanStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LoanStatus {
+    Requested,
+    Active,
+    Repaid,
+    Defaulted,
+    Cancelled,
+}
+
+// Loan Struct
+// A micro-loan collateralised by a pledged warehouse receipt
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Loan {
+    pub(crate) id: u64,
+    pub(crate) receipt_id: u64,
+    pub(crate) borrower: Principal,
+    pub(crate) lender: Option<Principal>,
+    pub(crate) amount: u64,
+    pub(crate) interest_bps: u64,
+    pub(crate) term_days: u64,
+    pub(crate) status: LoanStatus,
+    pub(crate) requested_at: u64,
+    pub(crate) funded_at: Option<u64>,
+    pub(crate) due_at: Option<u64>,
+    pub(crate) closed_at: Option<u64>,
+}
+
+// LoanRequestPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct LoanRequestPayload {
+    receipt_id: u64,
+    amount: u64,
+    interest_bps: u64,
+    term_days: u64,
+}
+
+impl Loan {
+    // Principal plus simple interest over the term
+    pub(crate) fn amount_due(&self) -> u64 {
+        let interest = (self.amount as u128 * self.interest_bps as u128 / 10_000) as u64;
+        self.amount.saturating_add(interest)
+    }
+}
+
+// Storable and BoundedStorable implementations for Lender
+impl Storable for Lender {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Lender {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Loan
+impl Storable for Loan {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Loan {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LENDERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Lender, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    static LOAN_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static LOANS_STORAGE: RefCell<StableBTreeMap<u64, Loan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+}
+
+fn get_loan_record(loan_id: u64) -> Result<Loan, String> {
+    LOANS_STORAGE
+        .with(|storage| storage.borrow().get(&loan_id))
+        .ok_or("Loan not found".to_string())
+}
+
+fn save_loan(loan: Loan) {
+    LOANS_STORAGE.with(|storage| storage.borrow_mut().insert(loan.id, loan));
+}
+
+pub(crate) fn is_lender(principal: &Principal) -> bool {
+    LENDERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Counts the borrower's closed loans as (repaid, defaulted)
+pub(crate) fn repayment_history(borrower: &Principal) -> (u64, u64) {
+    LOANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, loan)| loan)
+            .filter(|loan| loan.borrower == *borrower)
+            .fold((0, 0), |(repaid, defaulted), loan| match loan.status {
+                LoanStatus::Repaid if loan.closed_at.is_some() => (repaid + 1, defaulted),
+                LoanStatus::Defaulted => (repaid, defaulted + 1),
+                _ => (repaid, defaulted),
+            })
+    })
+}
+
+// Frees the collateral, handing the receipt to `holder`
+fn release_collateral(loan: &Loan, holder: Principal) {
+    if let Ok(mut receipt) = get_receipt_record(loan.receipt_id) {
+        if receipt.pledged_loan == Some(loan.id) {
+            receipt.pledged_loan = None;
+            receipt.holder = holder;
+            save_receipt(receipt);
+        }
+    }
+}
+
+// Defaults overdue loans, transferring the pledged receipt to the lender
+pub(crate) fn check_loan_defaults() {
+    let now = ic_cdk::api::time();
+    let overdue: Vec<Loan> = LOANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, loan)| loan)
+            .filter(|loan| {
+                loan.status == LoanStatus::Active && loan.due_at.map_or(false, |due| due < now)
+            })
+            .collect()
+    });
+
+    for mut loan in overdue {
+        let lender = match loan.lender {
+            Some(lender) => lender,
+            None => continue,
+        };
+        release_collateral(&loan, lender);
+        loan.status = LoanStatus::Defaulted;
+        loan.closed_at = Some(now);
+        notify(
+            loan.borrower,
+            format!(
+                "Loan #{} defaulted; warehouse receipt #{} went to the lender",
+                loan.id, loan.receipt_id
+            ),
+        );
+        notify(
+            lender,
+            format!(
+                "Loan #{} defaulted; warehouse receipt #{} is now yours",
+                loan.id, loan.receipt_id
+            ),
+        );
+        save_loan(loan);
+    }
+}
+
+// Function for an administrator to register a lender
+#[ic_cdk::update]
+fn register_lender(principal: Principal, name: String) -> Result<Lender, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register lenders".to_string());
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err("Invalid lender name".to_string());
+    }
+
+    let lender = Lender {
+        principal,
+        name,
+        registered_at: ic_cdk::api::time(),
+    };
+    LENDERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), lender.clone())
+    });
+    Ok(lender)
+}
+
+// Function for a receipt holder to pledge it and ask for a loan
+#[ic_cdk::update]
+fn request_loan(payload: LoanRequestPayload) -> Result<Loan, String> {
+    let borrower = caller_account();
+    let mut receipt = get_receipt_record(payload.receipt_id)?;
+
+    if receipt.holder != borrower {
+        return Err("Only the receipt holder can pledge it".to_string());
+    }
+    if !receipt.is_usable() {
+        return Err("Receipt is redeemed or expired".to_string());
+    }
+    if receipt.backed_listing.is_some() || receipt.pledged_loan.is_some() {
+        return Err("Receipt is already in use".to_string());
+    }
+    if payload.amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+    if payload.interest_bps > MAX_INTEREST_BPS {
+        return Err("Interest is too high".to_string());
+    }
+    if payload.term_days == 0 || payload.term_days > MAX_TERM_DAYS {
+        return Err("Invalid loan term".to_string());
+    }
+
+    let loan = Loan {
+        id: next_id(&LOAN_ID_COUNTER),
+        receipt_id: payload.receipt_id,
+        borrower,
+        lender: None,
+        amount: payload.amount,
+        interest_bps: payload.interest_bps,
+        term_days: payload.term_days,
+        status: LoanStatus::Requested,
+        requested_at: ic_cdk::api::time(),
+        funded_at: None,
+        due_at: None,
+        closed_at: None,
+    };
+
+    receipt.pledged_loan = Some(loan.id);
+    save_receipt(receipt);
+    save_loan(loan.clone());
+    Ok(loan)
+}
+
+#[ic_cdk::update]
+fn cancel_loan_request(loan_id: u64) -> Result<(), String> {
+    let mut loan = get_loan_record(loan_id)?;
+
+    if loan.borrower != caller_account() {
+        return Err("Only the borrower can cancel this loan".to_string());
+    }
+    if loan.status != LoanStatus::Requested {
+        return Err("Loan is no longer a request".to_string());
+    }
+
+    release_collateral(&loan, loan.borrower);
+    loan.status = LoanStatus::Cancelled;
+    loan.closed_at = Some(ic_cdk::api::time());
+    save_loan(loan);
+    Ok(())
+}
+
+// Function for a registered lender to fund a request straight into the borrower's account
+#[ic_cdk::update]
+async fn fund_loan(loan_id: u64) -> Result<Loan, String> {
+    let lender = caller_account();
+    let mut loan = get_loan_record(loan_id)?;
+
+    if !is_lender(&lender) {
+        return Err("Only a registered lender can fund loans".to_string());
+    }
+    if loan.status != LoanStatus::Requested {
+        return Err("Loan is not open for funding".to_string());
+    }
+
+    // Claim the loan before awaiting so no other lender can fund it too
+    loan.status = LoanStatus::Active;
+    loan.lender = Some(lender);
+    save_loan(loan.clone());
+
+    if let Err(e) = transfer_between(lender, loan.borrower, loan.amount).await {
+        loan.status = LoanStatus::Requested;
+        loan.lender = None;
+        save_loan(loan);
+        return Err(e);
+    }
+
+    let now = ic_cdk::api::time();
+    loan.funded_at = Some(now);
+    loan.due_at = Some(now.saturating_add(loan.term_days * NANOS_PER_DAY));
+    save_loan(loan.clone());
+    notify(
+        loan.borrower,
+        format!("Loan #{} was funded with {}", loan.id, loan.amount),
+    );
+    Ok(loan)
+}
+
+// Function for the borrower to repay principal and interest, releasing the receipt
+#[ic_cdk::update]
+async fn repay_loan(loan_id: u64) -> Result<Loan, String> {
+    let mut loan = get_loan_record(loan_id)?;
+
+    if loan.borrower != caller_account() {
+        return Err("Only the borrower can repay this loan".to_string());
+    }
+    if loan.status != LoanStatus::Active || loan.funded_at.is_none() {
+        return Err("Loan is not active".to_string());
+    }
+    let lender = loan.lender.ok_or("Loan has no lender".to_string())?;
+
+    // Mark repaid before awaiting so the default sweep cannot seize the receipt meanwhile
+    loan.status = LoanStatus::Repaid;
+    save_loan(loan.clone());
+
+    if let Err(e) = transfer_between(loan.borrower, lender, loan.amount_due()).await {
+        loan.status = LoanStatus::Active;
+        save_loan(loan);
+        return Err(e);
+    }
+
+    release_collateral(&loan, loan.borrower);
+    loan.closed_at = Some(ic_cdk::api::time());
+    save_loan(loan.clone());
+    notify(lender, format!("Loan #{} was repaid", loan.id));
+    Ok(loan)
+}
+
+#[ic_cdk::query]
+fn get_loan(loan_id: u64) -> Result<Loan, String> {
+    let loan = get_loan_record(loan_id)?;
+    let caller = caller_account();
+
+    let is_party = caller == loan.borrower || loan.lender == Some(caller);
+    if !is_party && !is_lender(&caller) && !is_admin() {
+        return Err("Loan not found".to_string());
+    }
+    Ok(loan)
+}
+
+// Lists open loan requests for lenders to review
+#[ic_cdk::query]
+fn get_loan_requests() -> Result<Vec<Loan>, String> {
+    if !is_lender(&caller_account()) {
+        return Err("Only registered lenders can browse loan requests".to_string());
+    }
+
+    Ok(LOANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, loan)| loan)
+            .filter(|loan| loan.status == LoanStatus::Requested)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_my_loans() -> Vec<Loan> {
+    let caller = caller_account();
+    LOANS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, loan)| loan)
+            .filter(|loan| loan.borrower == caller || loan.lender == Some(caller))
+            .collect()
+    })
+}
@@ -0,0 +1,147 @@
+use crate::devices::caller_account;
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_REGIONS: usize = 20;
+const MAX_REGION_LEN: usize = 50;
+
+// RateCard Struct
+// What a partner charges for one delivery
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RateCard {
+    pub(crate) base_fee: u64,
+    pub(crate) per_km: u64,
+    pub(crate) per_unit: u64,
+}
+
+// LogisticsPartner Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LogisticsPartner {
+    pub(crate) principal: Principal,
+    pub(crate) name: String,
+    pub(crate) coverage_regions: Vec<String>,
+    pub(crate) vehicle_capacity: u64,
+    pub(crate) rates: RateCard,
+    pub(crate) registered_at: u64,
+}
+
+// LogisticsPartnerPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct LogisticsPartnerPayload {
+    name: String,
+    coverage_regions: Vec<String>,
+    vehicle_capacity: u64,
+    rates: RateCard,
+}
+
+impl LogisticsPartner {
+    pub(crate) fn covers(&self, region: &str) -> bool {
+        self.coverage_regions
+            .iter()
+            .any(|covered| covered.eq_ignore_ascii_case(region))
+    }
+}
+
+// Storable and BoundedStorable implementations for LogisticsPartner
+impl Storable for LogisticsPartner {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LogisticsPartner {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PARTNERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, LogisticsPartner, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+}
+
+pub(crate) fn get_partner_record(principal: &Principal) -> Option<LogisticsPartner> {
+    PARTNERS_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(*principal)))
+}
+
+pub(crate) fn partners() -> Vec<LogisticsPartner> {
+    PARTNERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, partner)| partner)
+            .collect()
+    })
+}
+
+// Function for a transport provider to register, or update, their partner profile
+#[ic_cdk::update]
+fn register_logistics_partner(
+    payload: LogisticsPartnerPayload,
+) -> Result<LogisticsPartner, String> {
+    let principal = caller_account();
+
+    if principal == Principal::anonymous() {
+        return Err("Anonymous users cannot register".to_string());
+    }
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err("Invalid partner name".to_string());
+    }
+    if payload.coverage_regions.is_empty()
+        || payload.coverage_regions.len() > MAX_REGIONS
+        || payload
+            .coverage_regions
+            .iter()
+            .any(|region| region.trim().is_empty() || region.len() > MAX_REGION_LEN)
+    {
+        return Err("Invalid coverage regions".to_string());
+    }
+    if payload.vehicle_capacity == 0 {
+        return Err("Vehicle capacity must be positive".to_string());
+    }
+
+    let registered_at = get_partner_record(&principal)
+        .map_or(ic_cdk::api::time(), |existing| existing.registered_at);
+    let partner = LogisticsPartner {
+        principal,
+        name: payload.name,
+        coverage_regions: payload.coverage_regions,
+        vehicle_capacity: payload.vehicle_capacity,
+        rates: payload.rates,
+        registered_at,
+    };
+
+    PARTNERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), partner.clone())
+    });
+    Ok(partner)
+}
+
+#[ic_cdk::query]
+fn get_logistics_partner(principal: Principal) -> Result<LogisticsPartner, String> {
+    get_partner_record(&principal).ok_or("Logistics partner not found".to_string())
+}
+
+// Lists partners, optionally only those covering a region
+#[ic_cdk::query]
+fn list_logistics_partners(region: Option<String>) -> Vec<LogisticsPartner> {
+    partners()
+        .into_iter()
+        .filter(|partner| {
+            region
+                .as_ref()
+                .map_or(true, |region| partner.covers(region))
+        })
+        .collect()
+}
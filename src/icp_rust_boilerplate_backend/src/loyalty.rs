@@ -0,0 +1,255 @@
+use crate::devices::caller_account;
+use crate::governance::is_governor;
+use crate::installments::has_installment_plan;
+use crate::ledger::Asset;
+use crate::orders::{get_order_record, save_order, Order, OrderStatus};
+use crate::{next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// LoyaltyRates Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LoyaltyRates {
+    // Points earned per 10,000 units spent
+    earn_rate_bps: u64,
+    // Discount, in order currency units, that one point is worth
+    point_value: u64,
+}
+
+impl Default for LoyaltyRates {
+    fn default() -> Self {
+        LoyaltyRates {
+            earn_rate_bps: 100,
+            point_value: 1,
+        }
+    }
+}
+
+// PointsEntryKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PointsEntryKind {
+    Earned,
+    Redeemed,
+    ReferralBonus,
+    RafflePrize,
+    // Redeemed points given back when their order was cancelled or refunded
+    Returned,
+}
+
+// PointsEntry Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PointsEntry {
+    id: u64,
+    consumer: Principal,
+    order_id: u64,
+    kind: PointsEntryKind,
+    points: u64,
+    created_at: u64,
+}
+
+// Storable implementation for LoyaltyRates
+impl Storable for LoyaltyRates {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Storable and BoundedStorable implementations for PointsEntry
+impl Storable for PointsEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PointsEntry {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LOYALTY_RATES: RefCell<Cell<LoyaltyRates, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))), LoyaltyRates::default())
+            .expect("Cannot create the loyalty rates")
+    );
+
+    static POINTS_BALANCES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static POINTS_ENTRY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static POINTS_HISTORY_STORAGE: RefCell<StableBTreeMap<u64, PointsEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // Points redeemed on each order that has not settled yet, keyed by order ID
+    static REDEEMED_POINTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(237)))
+    ));
+}
+
+fn points_balance(consumer: &Principal) -> u64 {
+    POINTS_BALANCES
+        .with(|storage| storage.borrow().get(&PrincipalKey(*consumer)))
+        .unwrap_or(0)
+}
+
+fn record_points(consumer: Principal, order_id: u64, kind: PointsEntryKind, points: u64) {
+    let balance = match kind {
+        PointsEntryKind::Earned
+        | PointsEntryKind::ReferralBonus
+        | PointsEntryKind::RafflePrize
+        | PointsEntryKind::Returned => points_balance(&consumer).saturating_add(points),
+        PointsEntryKind::Redeemed => points_balance(&consumer) - points,
+    };
+    POINTS_BALANCES.with(|storage| storage.borrow_mut().insert(PrincipalKey(consumer), balance));
+
+    let entry = PointsEntry {
+        id: next_id(&POINTS_ENTRY_ID_COUNTER),
+        consumer,
+        order_id,
+        kind,
+        points,
+        created_at: ic_cdk::api::time(),
+    };
+    POINTS_HISTORY_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+}
+
+// Credits whoever paid for a completed order with points for what they spent
+pub(crate) fn award_points(order: &Order) {
+    // Earn rates are set against the default currency
+    if order.currency() != Asset::Icp {
+        return;
+    }
+    let rates = LOYALTY_RATES.with(|cell| cell.borrow().get().clone());
+    let points = (order.total as u128 * rates.earn_rate_bps as u128 / 10_000) as u64;
+    if points > 0 {
+        record_points(order.payer(), order.id, PointsEntryKind::Earned, points);
+    }
+}
+
+// Gives the payer back the points redeemed on an order that was cancelled or refunded
+pub(crate) fn return_redeemed_points(order: &Order) {
+    if let Some(points) = REDEEMED_POINTS.with(|storage| storage.borrow_mut().remove(&order.id)) {
+        record_points(order.payer(), order.id, PointsEntryKind::Returned, points);
+    }
+}
+
+// Forgets the points redeemed on an order that completed; they were spent
+pub(crate) fn settle_redeemed_points(order_id: u64) {
+    REDEEMED_POINTS.with(|storage| storage.borrow_mut().remove(&order_id));
+}
+
+// Credits bonus points for a referral that converted on the given order
+pub(crate) fn grant_referral_points(recipient: Principal, order_id: u64, points: u64) {
+    if points > 0 {
+        record_points(recipient, order_id, PointsEntryKind::ReferralBonus, points);
+    }
+}
+
+// Credits a raffle winner's prize; the entry's order ID holds the raffle ID
+pub(crate) fn grant_raffle_points(winner: Principal, raffle_id: u64, points: u64) {
+    if points > 0 {
+        record_points(winner, raffle_id, PointsEntryKind::RafflePrize, points);
+    }
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_loyalty_rates(earn_rate_bps: u64, point_value: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set loyalty rates".to_string());
+    }
+    if earn_rate_bps > 10_000 || point_value == 0 {
+        return Err("Invalid loyalty rates".to_string());
+    }
+
+    LOYALTY_RATES
+        .with(|cell| {
+            cell.borrow_mut().set(LoyaltyRates {
+                earn_rate_bps,
+                point_value,
+            })
+        })
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_loyalty_rates() -> LoyaltyRates {
+    LOYALTY_RATES.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_points_balance() -> u64 {
+    points_balance(&caller_account())
+}
+
+#[ic_cdk::query]
+fn get_points_history() -> Vec<PointsEntry> {
+    let caller = caller_account();
+    POINTS_HISTORY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.consumer == caller)
+            .collect()
+    })
+}
+
+// Function for a consumer to spend points as a discount on an order before paying for it
+#[ic_cdk::update]
+fn redeem_points(order_id: u64, points: u64) -> Result<Order, String> {
+    let mut order = get_order_record(order_id)?;
+    let consumer = caller_account();
+
+    if order.payer() != consumer {
+        return Err("Only the payer can redeem points on this order".to_string());
+    }
+    if order.status != OrderStatus::Pending || order.escrowed > 0 {
+        return Err("Points can only be redeemed before payment".to_string());
+    }
+    if order.currency() != Asset::Icp {
+        return Err("Points only apply to orders in the default currency".to_string());
+    }
+    if has_installment_plan(order.id) {
+        return Err("Points cannot be redeemed on installment orders".to_string());
+    }
+    if points == 0 || points > points_balance(&consumer) {
+        return Err("Insufficient points".to_string());
+    }
+
+    let rates = LOYALTY_RATES.with(|cell| cell.borrow().get().clone());
+    let discount = points
+        .checked_mul(rates.point_value)
+        .ok_or("Discount overflows".to_string())?;
+    if discount >= order.total {
+        return Err("Discount cannot cover the whole order".to_string());
+    }
+
+    order.total -= discount;
+    record_points(consumer, order_id, PointsEntryKind::Redeemed, points);
+    let redeemed = REDEEMED_POINTS
+        .with(|storage| storage.borrow().get(&order_id))
+        .unwrap_or(0)
+        .saturating_add(points);
+    REDEEMED_POINTS.with(|storage| storage.borrow_mut().insert(order_id, redeemed));
+    save_order(order.clone());
+    Ok(order)
+}
@@ -0,0 +1,326 @@
+use crate::cooperatives::is_cooperative;
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::shipping::{normalise_region, route_distance};
+use crate::{is_admin, is_farmer_owner, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_CATEGORIES: usize = 20;
+const MAX_CATEGORY_LEN: usize = 50;
+const MAX_LINKED_LISTINGS: usize = 20;
+
+// MarketDay Struct
+// A physical market organised by the administrators or a cooperative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct MarketDay {
+    id: u64,
+    organizer: Principal,
+    name: String,
+    // Venue, e.g. "Kisumu municipal market"
+    location: String,
+    region: String,
+    starts_at: u64,
+    ends_at: u64,
+    // Produce categories traded; empty means any
+    categories: Vec<String>,
+    cancelled: bool,
+    attendees: u64,
+    created_at: u64,
+}
+
+// MarketDayPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct MarketDayPayload {
+    name: String,
+    location: String,
+    region: String,
+    starts_at: u64,
+    ends_at: u64,
+    categories: Vec<String>,
+}
+
+// MarketRsvp Struct
+// A farmer's attendance at a market day, with the listings they will bring
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct MarketRsvp {
+    id: u64,
+    event_id: u64,
+    farmer: Principal,
+    listing_ids: Vec<u64>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for MarketDay
+impl Storable for MarketDay {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MarketDay {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for MarketRsvp
+impl Storable for MarketRsvp {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MarketRsvp {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MARKET_DAY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static MARKET_DAYS_STORAGE: RefCell<StableBTreeMap<u64, MarketDay, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+
+    static RSVP_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static RSVPS_STORAGE: RefCell<StableBTreeMap<u64, MarketRsvp, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113)))
+    ));
+}
+
+fn get_event_record(event_id: u64) -> Result<MarketDay, String> {
+    MARKET_DAYS_STORAGE
+        .with(|storage| storage.borrow().get(&event_id))
+        .ok_or("Market day not found".to_string())
+}
+
+fn save_event(event: MarketDay) {
+    MARKET_DAYS_STORAGE.with(|storage| storage.borrow_mut().insert(event.id, event));
+}
+
+fn save_rsvp(rsvp: MarketRsvp) {
+    RSVPS_STORAGE.with(|storage| storage.borrow_mut().insert(rsvp.id, rsvp));
+}
+
+fn event_rsvps(event_id: u64) -> Vec<MarketRsvp> {
+    RSVPS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, rsvp)| rsvp)
+            .filter(|rsvp| rsvp.event_id == event_id)
+            .collect()
+    })
+}
+
+fn find_rsvp(event_id: u64, farmer: Principal) -> Option<MarketRsvp> {
+    event_rsvps(event_id)
+        .into_iter()
+        .find(|rsvp| rsvp.farmer == farmer)
+}
+
+// Loads an event still ahead that farmers may sign up to
+fn upcoming_event(event_id: u64) -> Result<MarketDay, String> {
+    let event = get_event_record(event_id)?;
+    if event.cancelled || event.ends_at <= ic_cdk::api::time() {
+        return Err("Market day is not upcoming".to_string());
+    }
+    Ok(event)
+}
+
+// Function for an administrator or registered cooperative to announce a market day
+#[ic_cdk::update]
+fn publish_market_day(payload: MarketDayPayload) -> Result<MarketDay, String> {
+    let organizer = caller_account();
+
+    if !is_admin() && !is_cooperative(&organizer) {
+        return Err("Only administrators and cooperatives can publish market days".to_string());
+    }
+    if payload.name.trim().is_empty()
+        || payload.name.len() > MAX_NAME_LEN
+        || payload.location.trim().is_empty()
+        || payload.location.len() > MAX_LOCATION_LEN
+    {
+        return Err("Invalid market day details".to_string());
+    }
+    if payload.starts_at <= ic_cdk::api::time() || payload.ends_at <= payload.starts_at {
+        return Err("Invalid market day dates".to_string());
+    }
+    if payload.categories.len() > MAX_CATEGORIES {
+        return Err("Too many categories".to_string());
+    }
+    let mut categories = Vec::with_capacity(payload.categories.len());
+    for category in &payload.categories {
+        let category = category.trim().to_lowercase();
+        if category.is_empty() || category.len() > MAX_CATEGORY_LEN {
+            return Err("Invalid category".to_string());
+        }
+        if !categories.contains(&category) {
+            categories.push(category);
+        }
+    }
+
+    let event = MarketDay {
+        id: next_id(&MARKET_DAY_ID_COUNTER),
+        organizer,
+        name: payload.name,
+        location: payload.location,
+        region: normalise_region(&payload.region)?,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        categories,
+        cancelled: false,
+        attendees: 0,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_event(event.clone());
+    Ok(event)
+}
+
+// Function for the organizer or an administrator to call off a market day
+#[ic_cdk::update]
+fn cancel_market_day(event_id: u64) -> Result<(), String> {
+    let mut event = upcoming_event(event_id)?;
+
+    if event.organizer != caller_account() && !is_admin() {
+        return Err("Only the organizer can cancel this market day".to_string());
+    }
+
+    event.cancelled = true;
+    save_event(event);
+    for rsvp in event_rsvps(event_id) {
+        notify(
+            rsvp.farmer,
+            format!("Market day #{} was cancelled", event_id),
+        );
+    }
+    Ok(())
+}
+
+// Function for a farmer to say they will attend a market day
+#[ic_cdk::update]
+fn rsvp_market_day(event_id: u64) -> Result<MarketRsvp, String> {
+    let mut event = upcoming_event(event_id)?;
+    let farmer = caller_account();
+
+    if find_rsvp(event_id, farmer).is_some() {
+        return Err("You are already attending".to_string());
+    }
+
+    let rsvp = MarketRsvp {
+        id: next_id(&RSVP_ID_COUNTER),
+        event_id,
+        farmer,
+        listing_ids: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_rsvp(rsvp.clone());
+    event.attendees += 1;
+    save_event(event);
+    Ok(rsvp)
+}
+
+// Function for a farmer to withdraw their attendance
+#[ic_cdk::update]
+fn cancel_market_rsvp(event_id: u64) -> Result<(), String> {
+    let mut event = upcoming_event(event_id)?;
+    let rsvp = find_rsvp(event_id, caller_account()).ok_or("You are not attending".to_string())?;
+
+    RSVPS_STORAGE.with(|storage| storage.borrow_mut().remove(&rsvp.id));
+    event.attendees = event.attendees.saturating_sub(1);
+    save_event(event);
+    Ok(())
+}
+
+// Function for an attending farmer to link one of their listings to the market day
+#[ic_cdk::update]
+fn link_listing_to_market_day(event_id: u64, farmer_id: u64) -> Result<MarketRsvp, String> {
+    let event = upcoming_event(event_id)?;
+    let mut rsvp = find_rsvp(event_id, caller_account())
+        .ok_or("RSVP to the market day before linking listings".to_string())?;
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can link this listing".to_string());
+    }
+    if !event.categories.is_empty()
+        && !event
+            .categories
+            .contains(&farmer.category.trim().to_lowercase())
+    {
+        return Err("This category is not traded at the market day".to_string());
+    }
+    if rsvp.listing_ids.contains(&farmer_id) {
+        return Err("Listing is already linked".to_string());
+    }
+    if rsvp.listing_ids.len() >= MAX_LINKED_LISTINGS {
+        return Err("Too many linked listings".to_string());
+    }
+
+    rsvp.listing_ids.push(farmer_id);
+    save_rsvp(rsvp.clone());
+    Ok(rsvp)
+}
+
+#[ic_cdk::query]
+fn get_market_day(event_id: u64) -> Result<MarketDay, String> {
+    get_event_record(event_id)
+}
+
+// Farmers attending a market day and the listings they are bringing
+#[ic_cdk::query]
+fn get_market_day_attendees(event_id: u64) -> Result<Vec<MarketRsvp>, String> {
+    get_event_record(event_id)?;
+    Ok(event_rsvps(event_id))
+}
+
+// Lists upcoming market days in a region or within `max_distance_km` of it, soonest first.
+// Distances come from the region distance table used for shipping.
+#[ic_cdk::query]
+fn get_upcoming_market_days(
+    region: String,
+    max_distance_km: u64,
+) -> Result<Vec<MarketDay>, String> {
+    let region = normalise_region(&region)?;
+    let now = ic_cdk::api::time();
+
+    let mut events: Vec<MarketDay> = MARKET_DAYS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| !event.cancelled && event.ends_at > now)
+            .filter(|event| {
+                route_distance(&region, &event.region)
+                    .map_or(false, |distance| distance <= max_distance_km)
+            })
+            .collect()
+    });
+    events.sort_by_key(|event| event.starts_at);
+    Ok(events)
+}
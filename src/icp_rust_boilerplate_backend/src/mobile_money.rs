@@ -0,0 +1,726 @@
+use crate::devices::caller_account;
+use crate::events::log_event;
+use crate::governance::is_governor;
+use crate::ledger::Account;
+use crate::notifications::notify;
+use crate::payout_schedules::{
+    balances_subaccount, credit_held_balance, debit_held_balance, held_balance,
+};
+use crate::price_history::TimeRange;
+use crate::raffles::hex;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAX_RESPONSE_BYTES: u64 = 4096;
+// Attached to every outcall; whatever the subnet does not charge is refunded
+const HTTP_CALL_CYCLES: u128 = 2_000_000_000;
+const MAX_PROCESS_BATCH: usize = 10;
+// Failed calls after which a payout waits for an administrator
+const MAX_ATTEMPTS: u32 = 6;
+const RETRY_BASE_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// A payout is pushed this far out while its call is in flight so overlapping runs skip it
+const IN_FLIGHT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_ERROR_LEN: usize = 200;
+const MAX_PAYOUTS_PER_PAGE: usize = 100;
+
+// MobileMoneyProvider Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MobileMoneyProvider {
+    MPesa,
+    MtnMoMo,
+}
+
+// MobileMoneyStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MobileMoneyStatus {
+    // Waiting to be sent to the aggregator
+    Queued,
+    // Accepted by the aggregator and polled until it settles
+    Submitted,
+    // Paid by the aggregator; the amount is moved from the balances subaccount to the main
+    // account, which funds the aggregator
+    Paid,
+    // Rejected; the amount is back in the farmer's held balance
+    Failed,
+    // The aggregator kept failing; an administrator retries or fails the payout
+    NeedsReview,
+}
+
+// MobileMoneyConfig Struct
+// The aggregator the bridge calls. The API key signs each request and is never sent, but
+// canister state is readable by node providers, so it should only be allowed to create and
+// read payouts, and the aggregator should only accept it from this canister's requests.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct MobileMoneyConfig {
+    base_url: String,
+    api_key: String,
+}
+
+// MobileMoneyPayout Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct MobileMoneyPayout {
+    id: u64,
+    farmer: Principal,
+    amount: u64,
+    provider: MobileMoneyProvider,
+    phone_number: String,
+    status: MobileMoneyStatus,
+    // The aggregator's ID for the payout, once it accepted it
+    aggregator_ref: Option<String>,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// MobileMoneySettlement Struct
+// Reconciliation record of a payout the aggregator confirmed, to match against its statements
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct MobileMoneySettlement {
+    payout_id: u64,
+    farmer: Principal,
+    amount: u64,
+    provider: MobileMoneyProvider,
+    aggregator_ref: String,
+    // The operator's transaction code, e.g. an M-Pesa receipt number
+    provider_reference: Option<String>,
+    // What the farmer received, as reported by the aggregator, e.g. "1250.00" "KES"
+    local_amount: Option<String>,
+    local_currency: Option<String>,
+    settled_at: u64,
+}
+
+// Storable implementation for MobileMoneyConfig
+impl Storable for MobileMoneyConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Storable and BoundedStorable implementations for MobileMoneyPayout
+impl Storable for MobileMoneyPayout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MobileMoneyPayout {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for MobileMoneySettlement
+impl Storable for MobileMoneySettlement {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MobileMoneySettlement {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MOBILE_MONEY_CONFIG: RefCell<Cell<MobileMoneyConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(210))), MobileMoneyConfig::default())
+            .expect("Cannot create the mobile money config")
+    );
+
+    static MOBILE_MONEY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(211))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static MOBILE_MONEY_STORAGE: RefCell<StableBTreeMap<u64, MobileMoneyPayout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(212)))
+    ));
+
+    // Keyed by payout ID
+    static MOBILE_MONEY_SETTLEMENTS: RefCell<StableBTreeMap<u64, MobileMoneySettlement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(213)))
+    ));
+}
+
+fn config() -> Result<MobileMoneyConfig, String> {
+    let config = MOBILE_MONEY_CONFIG.with(|cell| cell.borrow().get().clone());
+    if config.base_url.is_empty() {
+        return Err("Mobile money aggregator is not configured".to_string());
+    }
+    Ok(config)
+}
+
+fn payout_record(payout_id: u64) -> Result<MobileMoneyPayout, String> {
+    MOBILE_MONEY_STORAGE
+        .with(|storage| storage.borrow().get(&payout_id))
+        .ok_or("Payout not found".to_string())
+}
+
+fn save_payout(mut payout: MobileMoneyPayout) {
+    payout.updated_at = ic_cdk::api::time();
+    MOBILE_MONEY_STORAGE.with(|storage| storage.borrow_mut().insert(payout.id, payout));
+}
+
+// Taken from held balances by payouts the aggregator has not paid or failed yet; the funds are
+// still in the balances subaccount
+pub(crate) fn unsettled_mobile_money() -> u64 {
+    MOBILE_MONEY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payout)| {
+                !matches!(
+                    payout.status,
+                    MobileMoneyStatus::Paid | MobileMoneyStatus::Failed
+                )
+            })
+            .fold(0u64, |total, (_, payout)| {
+                total.saturating_add(payout.amount)
+            })
+    })
+}
+
+// Phone numbers are sent in international format, e.g. +254712345678
+pub(crate) fn is_valid_phone_number(phone_number: &str) -> bool {
+    let digits = phone_number.strip_prefix('+').unwrap_or("");
+    (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+fn provider_code(provider: MobileMoneyProvider) -> &'static str {
+    match provider {
+        MobileMoneyProvider::MPesa => "mpesa",
+        MobileMoneyProvider::MtnMoMo => "mtn_momo",
+    }
+}
+
+// The reference the aggregator deduplicates on; every replica sends the same request
+fn payout_reference(payout_id: u64) -> String {
+    format!("agrilink-payout-{}", payout_id)
+}
+
+// HMAC-SHA256 under the API key over the method, path, timestamp, idempotency key and the
+// SHA-256 of the body, one per line, hex-encoded. Sent instead of the key, so a request seen on
+// the way, e.g. in the aggregator's logs, neither gives the key away nor can be replayed for
+// another payout.
+fn request_signature(
+    api_key: &str,
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    idempotency_key: &str,
+    body: &[u8],
+) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(api_key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            timestamp,
+            idempotency_key,
+            hex(&Sha256::digest(body))
+        )
+        .as_bytes(),
+    );
+    hex(&mac.finalize().into_bytes())
+}
+
+async fn call_aggregator(
+    config: &MobileMoneyConfig,
+    method: HttpMethod,
+    path: String,
+    idempotency_key: &str,
+    body: Option<Vec<u8>>,
+) -> Result<serde_json::Value, String> {
+    // Every replica signs with the same consensus time, so they all send the same request
+    let timestamp = ic_cdk::api::time();
+    let method_name = match method {
+        HttpMethod::GET => "GET",
+        HttpMethod::POST => "POST",
+        HttpMethod::HEAD => "HEAD",
+    };
+    let signature = request_signature(
+        &config.api_key,
+        method_name,
+        &path,
+        timestamp,
+        idempotency_key,
+        body.as_deref().unwrap_or(&[]),
+    );
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}{}", config.base_url.trim_end_matches('/'), path),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method,
+        headers: vec![
+            HttpHeader {
+                name: "X-Agrilink-Timestamp".to_string(),
+                value: timestamp.to_string(),
+            },
+            HttpHeader {
+                name: "X-Agrilink-Signature".to_string(),
+                value: signature,
+            },
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key.to_string(),
+            },
+        ],
+        body,
+        transform: Some(TransformContext::from_name(
+            "transform_mobile_money_response".to_string(),
+            vec![],
+        )),
+    };
+
+    let (response,) = http_request(request, HTTP_CALL_CYCLES)
+        .await
+        .map_err(|(code, message)| format!("Outcall failed: {:?} {}", code, message))?;
+    let status = u64::try_from(response.status.0).unwrap_or(0);
+    let body = String::from_utf8_lossy(&response.body).to_string();
+    if (400..500).contains(&status) {
+        return Err(format!("Rejected ({}): {}", status, body));
+    }
+    if !(200..300).contains(&status) {
+        return Err(format!("Aggregator error ({}): {}", status, body));
+    }
+    serde_json::from_str(&body).map_err(|e| format!("Unreadable aggregator response: {}", e))
+}
+
+fn json_text(value: &serde_json::Value, field: &str) -> Option<String> {
+    match &value[field] {
+        serde_json::Value::String(text) => Some(text.clone()),
+        serde_json::Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+// Books a call that did not go through: a rejection fails the payout, anything else is retried
+// with a growing delay until it needs review
+fn record_failure(payout: &mut MobileMoneyPayout, error: String) {
+    payout.attempts += 1;
+    payout.last_error = Some(error.chars().take(MAX_ERROR_LEN).collect());
+    if error.starts_with("Rejected") {
+        fail_payout(payout);
+    } else if payout.attempts >= MAX_ATTEMPTS {
+        payout.status = MobileMoneyStatus::NeedsReview;
+        log_event(
+            "mobile_money_review",
+            format!("Payout #{}: {}", payout.id, error),
+        );
+    } else {
+        payout.next_attempt_at =
+            ic_cdk::api::time() + RETRY_BASE_NANOS * 2u64.pow(payout.attempts - 1);
+    }
+}
+
+fn fail_payout(payout: &mut MobileMoneyPayout) {
+    payout.status = MobileMoneyStatus::Failed;
+    credit_held_balance(&payout.farmer, payout.amount);
+    notify(
+        payout.farmer,
+        format!(
+            "Mobile money payout #{} failed; {} is back in your balance",
+            payout.id, payout.amount
+        ),
+    );
+}
+
+async fn submit_payout(config: &MobileMoneyConfig, payout: &mut MobileMoneyPayout) {
+    let reference = payout_reference(payout.id);
+    let body = serde_json::json!({
+        "reference": reference,
+        "amount": payout.amount,
+        "provider": provider_code(payout.provider),
+        "phone_number": payout.phone_number,
+    })
+    .to_string();
+
+    let result = call_aggregator(
+        config,
+        HttpMethod::POST,
+        "/payouts".to_string(),
+        &reference,
+        Some(body.into_bytes()),
+    )
+    .await;
+    match result.and_then(|response| {
+        json_text(&response, "id").ok_or("Aggregator did not return a payout ID".to_string())
+    }) {
+        Ok(aggregator_ref) => {
+            payout.status = MobileMoneyStatus::Submitted;
+            payout.aggregator_ref = Some(aggregator_ref);
+            payout.attempts = 0;
+            payout.last_error = None;
+            payout.next_attempt_at = ic_cdk::api::time();
+        }
+        Err(e) => record_failure(payout, e),
+    }
+}
+
+async fn poll_payout(config: &MobileMoneyConfig, payout: &mut MobileMoneyPayout) {
+    let aggregator_ref = payout.aggregator_ref.clone().unwrap_or_default();
+    let response = match call_aggregator(
+        config,
+        HttpMethod::GET,
+        format!("/payouts/{}", aggregator_ref),
+        &payout_reference(payout.id),
+        None,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => return record_failure(payout, e),
+    };
+
+    payout.attempts = 0;
+    payout.last_error = None;
+    match json_text(&response, "status").as_deref() {
+        Some("completed") => {
+            payout.status = MobileMoneyStatus::Paid;
+            let settlement = MobileMoneySettlement {
+                payout_id: payout.id,
+                farmer: payout.farmer,
+                amount: payout.amount,
+                provider: payout.provider,
+                aggregator_ref,
+                provider_reference: json_text(&response, "provider_reference"),
+                local_amount: json_text(&response, "local_amount"),
+                local_currency: json_text(&response, "local_currency"),
+                settled_at: ic_cdk::api::time(),
+            };
+            MOBILE_MONEY_SETTLEMENTS
+                .with(|storage| storage.borrow_mut().insert(payout.id, settlement));
+            enqueue(
+                OutboundCall::AccountPayout {
+                    subaccount: balances_subaccount(),
+                    to: Account::from(ic_cdk::id()),
+                    amount: payout.amount,
+                    asset: None,
+                },
+                format!("Mobile money payout #{} reimbursement", payout.id),
+            );
+            log_event(
+                "mobile_money_paid",
+                format!("Payout #{} of {}", payout.id, payout.amount),
+            );
+            notify(
+                payout.farmer,
+                format!("Mobile money payout #{} was paid", payout.id),
+            );
+        }
+        Some("failed") => {
+            payout.last_error = json_text(&response, "failure_reason");
+            fail_payout(payout);
+        }
+        // Still pending; look again on the next run
+        _ => payout.next_attempt_at = ic_cdk::api::time(),
+    }
+}
+
+// Sends queued payouts to the aggregator and polls submitted ones, oldest first
+pub(crate) async fn process_mobile_money_payouts() {
+    let config = match config() {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = MOBILE_MONEY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payout)| {
+                matches!(
+                    payout.status,
+                    MobileMoneyStatus::Queued | MobileMoneyStatus::Submitted
+                ) && payout.next_attempt_at <= now
+            })
+            .map(|(id, _)| id)
+            .take(MAX_PROCESS_BATCH)
+            .collect()
+    });
+
+    for payout_id in due {
+        // Claim the payout before awaiting so overlapping runs cannot call twice
+        let mut payout = match payout_record(payout_id) {
+            Ok(payout) if payout.next_attempt_at <= ic_cdk::api::time() => payout,
+            _ => continue,
+        };
+        payout.next_attempt_at = ic_cdk::api::time() + IN_FLIGHT_NANOS;
+        save_payout(payout.clone());
+
+        match payout.status {
+            MobileMoneyStatus::Queued => submit_payout(&config, &mut payout).await,
+            MobileMoneyStatus::Submitted => poll_payout(&config, &mut payout).await,
+            _ => continue,
+        }
+        save_payout(payout);
+    }
+}
+
+// Strips the headers from aggregator responses so every replica sees the same reply
+#[ic_cdk::query]
+fn transform_mobile_money_response(raw: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: raw.response.status,
+        headers: Vec::new(),
+        body: raw.response.body,
+    }
+}
+
+// Function for governance to point the bridge at a mobile money aggregator
+#[ic_cdk::update]
+fn set_mobile_money_aggregator(base_url: String, api_key: String) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the mobile money aggregator".to_string());
+    }
+    if !base_url.starts_with("https://") {
+        return Err("Aggregator URL must use HTTPS".to_string());
+    }
+
+    MOBILE_MONEY_CONFIG
+        .with(|cell| {
+            cell.borrow_mut()
+                .set(MobileMoneyConfig { base_url, api_key })
+        })
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_mobile_money_aggregator() -> Option<String> {
+    config().ok().map(|config| config.base_url)
+}
+
+#[ic_cdk::query]
+fn get_mobile_money_balance() -> u64 {
+    held_balance(&caller_account())
+}
+
+// Function for a farmer to cash out part of their held balance to a mobile money wallet
+#[ic_cdk::update]
+fn request_mobile_money_payout(
+    amount: u64,
+    provider: MobileMoneyProvider,
+    phone_number: String,
+) -> Result<MobileMoneyPayout, String> {
+    config()?;
+    let farmer = caller_account();
+
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+    if !is_valid_phone_number(&phone_number) {
+        return Err("Phone number must be in international format, e.g. +254712345678".to_string());
+    }
+    debit_held_balance(&farmer, amount)?;
+
+    let now = ic_cdk::api::time();
+    let payout = MobileMoneyPayout {
+        id: next_id(&MOBILE_MONEY_ID_COUNTER),
+        farmer,
+        amount,
+        provider,
+        phone_number,
+        status: MobileMoneyStatus::Queued,
+        aggregator_ref: None,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    save_payout(payout.clone());
+    Ok(payout)
+}
+
+#[ic_cdk::query]
+fn get_my_mobile_money_payouts() -> Vec<MobileMoneyPayout> {
+    let farmer = caller_account();
+    let mut payouts: Vec<MobileMoneyPayout> = MOBILE_MONEY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, payout)| payout)
+            .filter(|payout| payout.farmer == farmer)
+            .collect()
+    });
+    payouts.reverse();
+    payouts.truncate(MAX_PAYOUTS_PER_PAGE);
+    payouts
+}
+
+// Function for an administrator to settle a payout waiting for review: retry it, or fail it
+// and return the amount to the farmer's balance
+#[ic_cdk::update]
+fn review_mobile_money_payout(payout_id: u64, retry: bool) -> Result<MobileMoneyPayout, String> {
+    if !is_admin() {
+        return Err("Only an administrator can review payouts".to_string());
+    }
+
+    let mut payout = payout_record(payout_id)?;
+    if payout.status != MobileMoneyStatus::NeedsReview {
+        return Err("Payout is not waiting for review".to_string());
+    }
+
+    if retry {
+        payout.status = if payout.aggregator_ref.is_some() {
+            MobileMoneyStatus::Submitted
+        } else {
+            MobileMoneyStatus::Queued
+        };
+        payout.attempts = 0;
+        payout.next_attempt_at = ic_cdk::api::time();
+    } else {
+        fail_payout(&mut payout);
+    }
+    save_payout(payout.clone());
+    Ok(payout)
+}
+
+// Function for an administrator to list confirmed payouts for reconciliation against the
+// aggregator's statements, optionally within a time range
+#[ic_cdk::query]
+fn get_mobile_money_settlements(
+    range: Option<TimeRange>,
+) -> Result<Vec<MobileMoneySettlement>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view payout settlements".to_string());
+    }
+
+    Ok(MOBILE_MONEY_SETTLEMENTS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, settlement)| settlement)
+            .filter(|settlement| {
+                range.map_or(true, |range| {
+                    range.from <= settlement.settled_at && settlement.settled_at <= range.to
+                })
+            })
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payout_schedules::expected_held_escrow;
+
+    fn store_payout(id: u64, amount: u64, status: MobileMoneyStatus) {
+        let payout = MobileMoneyPayout {
+            id,
+            farmer: Principal::from_slice(&[1]),
+            amount,
+            provider: MobileMoneyProvider::MPesa,
+            phone_number: "+254712345678".to_string(),
+            status,
+            aggregator_ref: None,
+            attempts: 0,
+            next_attempt_at: 0,
+            last_error: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+        MOBILE_MONEY_STORAGE.with(|storage| storage.borrow_mut().insert(id, payout));
+    }
+
+    #[test]
+    fn only_payouts_still_in_flight_are_unsettled() {
+        store_payout(1, 100, MobileMoneyStatus::Queued);
+        store_payout(2, 200, MobileMoneyStatus::Submitted);
+        store_payout(3, 400, MobileMoneyStatus::NeedsReview);
+        store_payout(4, 800, MobileMoneyStatus::Paid);
+        store_payout(5, 1_600, MobileMoneyStatus::Failed);
+        assert_eq!(unsettled_mobile_money(), 700);
+    }
+
+    #[test]
+    fn unsettled_payouts_are_expected_in_the_balances_subaccount() {
+        credit_held_balance(&Principal::from_slice(&[1]), 1_000);
+        store_payout(1, 250, MobileMoneyStatus::Submitted);
+
+        let expected = expected_held_escrow();
+        assert_eq!(expected.len(), 1);
+        assert_eq!(expected[0].subaccount, balances_subaccount());
+        assert_eq!(expected[0].expected, 1_250);
+    }
+
+    #[test]
+    fn phone_numbers_must_be_in_international_format() {
+        assert!(is_valid_phone_number("+254712345678"));
+        assert!(is_valid_phone_number("+23350123456"));
+        assert!(!is_valid_phone_number("254712345678"));
+        assert!(!is_valid_phone_number("+2547123"));
+        assert!(!is_valid_phone_number("+2547123456789012"));
+        assert!(!is_valid_phone_number("+254 712 345 678"));
+    }
+
+    #[test]
+    fn requests_are_signed_without_the_key() {
+        let body = br#"{"amount":100}"#;
+        let signature = request_signature(
+            "test-key",
+            "POST",
+            "/payouts",
+            1_700_000_000_000_000_000,
+            "agrilink-payout-1",
+            body,
+        );
+        // Computed independently, as an aggregator would to check it
+        assert_eq!(
+            signature,
+            "33463ee2d5e0a036c8fe61df3bfeb077d7915ab44c7203d59d72563f82238295"
+        );
+        assert!(!signature.contains("test-key"));
+    }
+
+    #[test]
+    fn a_signature_only_covers_its_own_request() {
+        let sign = |path: &str, timestamp: u64, body: &[u8]| {
+            request_signature(
+                "test-key",
+                "POST",
+                path,
+                timestamp,
+                "agrilink-payout-1",
+                body,
+            )
+        };
+        let original = sign("/payouts", 1, b"{}");
+        assert_ne!(original, sign("/payouts/2", 1, b"{}"));
+        assert_ne!(original, sign("/payouts", 2, b"{}"));
+        assert_ne!(original, sign("/payouts", 1, b"{ }"));
+    }
+}
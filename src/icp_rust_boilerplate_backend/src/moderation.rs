@@ -0,0 +1,193 @@
+use crate::forum::set_post_hidden;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REASON_LEN: usize = 200;
+// Flags from this many users hide the content until an administrator reviews it
+const AUTO_HIDE_FLAGS: usize = 3;
+const MAX_FLAGS_KEPT: usize = 20;
+
+// ContentKind Enum
+// User content that can be flagged for moderation
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ContentKind {
+    ForumQuestion,
+    ForumAnswer,
+}
+
+// ModerationStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ModerationStatus {
+    Pending,
+    // Reviewed and left up
+    Dismissed,
+    Removed,
+}
+
+// ContentFlag Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ContentFlag {
+    flagger: Principal,
+    reason: String,
+    flagged_at: u64,
+}
+
+// ModerationItem Struct
+// A piece of flagged content waiting for, or given, an administrator's review
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ModerationItem {
+    id: u64,
+    kind: ContentKind,
+    content_id: u64,
+    flags: Vec<ContentFlag>,
+    status: ModerationStatus,
+    hidden: bool,
+    created_at: u64,
+    reviewed_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for ModerationItem
+impl Storable for ModerationItem {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ModerationItem {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MODERATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static MODERATION_STORAGE: RefCell<StableBTreeMap<u64, ModerationItem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))
+    ));
+}
+
+fn save_item(item: ModerationItem) {
+    MODERATION_STORAGE.with(|storage| storage.borrow_mut().insert(item.id, item));
+}
+
+fn find_item(kind: ContentKind, content_id: u64) -> Option<ModerationItem> {
+    MODERATION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .find(|item| item.kind == kind && item.content_id == content_id)
+    })
+}
+
+fn set_hidden(kind: ContentKind, content_id: u64, hidden: bool) {
+    match kind {
+        ContentKind::ForumQuestion | ContentKind::ForumAnswer => {
+            set_post_hidden(kind, content_id, hidden)
+        }
+    }
+}
+
+// Records a user's flag on a piece of content, queueing it for review. The caller checks
+// that the content exists and that the flagger may flag it.
+pub(crate) fn flag_content(
+    kind: ContentKind,
+    content_id: u64,
+    flagger: Principal,
+    reason: String,
+) -> Result<(), String> {
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err("Invalid reason".to_string());
+    }
+
+    let mut item = find_item(kind, content_id).unwrap_or_else(|| ModerationItem {
+        id: next_id(&MODERATION_ID_COUNTER),
+        kind,
+        content_id,
+        flags: Vec::new(),
+        status: ModerationStatus::Pending,
+        hidden: false,
+        created_at: ic_cdk::api::time(),
+        reviewed_at: None,
+    });
+
+    if item.status != ModerationStatus::Pending {
+        return Err("Content has already been reviewed".to_string());
+    }
+    if item.flags.iter().any(|flag| flag.flagger == flagger) {
+        return Err("You have already flagged this content".to_string());
+    }
+    if item.flags.len() < MAX_FLAGS_KEPT {
+        item.flags.push(ContentFlag {
+            flagger,
+            reason,
+            flagged_at: ic_cdk::api::time(),
+        });
+    }
+    if !item.hidden && item.flags.len() >= AUTO_HIDE_FLAGS {
+        item.hidden = true;
+        set_hidden(kind, content_id, true);
+    }
+
+    save_item(item);
+    Ok(())
+}
+
+// Lists content awaiting review, most flagged first
+#[ic_cdk::query]
+fn get_moderation_queue() -> Result<Vec<ModerationItem>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view the moderation queue".to_string());
+    }
+
+    let mut items: Vec<ModerationItem> = MODERATION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| item.status == ModerationStatus::Pending)
+            .collect()
+    });
+    items.sort_by(|a, b| b.flags.len().cmp(&a.flags.len()));
+    Ok(items)
+}
+
+// Function for an administrator to remove flagged content or dismiss the flags
+#[ic_cdk::update]
+fn review_flagged_content(item_id: u64, remove: bool) -> Result<ModerationItem, String> {
+    if !is_admin() {
+        return Err("Only an administrator can review flagged content".to_string());
+    }
+
+    let mut item = MODERATION_STORAGE
+        .with(|storage| storage.borrow().get(&item_id))
+        .ok_or("Moderation item not found".to_string())?;
+    if item.status != ModerationStatus::Pending {
+        return Err("Content has already been reviewed".to_string());
+    }
+
+    item.status = if remove {
+        ModerationStatus::Removed
+    } else {
+        ModerationStatus::Dismissed
+    };
+    if item.hidden != remove {
+        item.hidden = remove;
+        set_hidden(item.kind, item.content_id, remove);
+    }
+    item.reviewed_at = Some(ic_cdk::api::time());
+
+    save_item(item.clone());
+    Ok(item)
+}
@@ -0,0 +1,133 @@
+use crate::devices::caller_account;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_MESSAGE_LEN: usize = 500;
+
+// Notification Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Notification {
+    id: u64,
+    recipient: Principal,
+    message: String,
+    read: bool,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Notification
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static NOTIFICATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static NOTIFICATIONS_STORAGE: RefCell<StableBTreeMap<u64, Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+}
+
+// Queues a message for a user; messages are truncated to fit the storage bound
+pub(crate) fn notify(recipient: Principal, message: impl Into<String>) {
+    let mut message = message.into();
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+
+    let notification = Notification {
+        id: next_id(&NOTIFICATION_ID_COUNTER),
+        recipient,
+        message,
+        read: false,
+        created_at: ic_cdk::api::time(),
+    };
+    NOTIFICATIONS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(notification.id, notification));
+}
+
+// Drops every notification for a deleted account
+pub(crate) fn clear_notifications(recipient: &Principal) {
+    let ids: Vec<u64> = NOTIFICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, notification)| notification.recipient == *recipient)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    remove_notifications(&ids);
+}
+
+// IDs of read notifications created before the cutoff, for retention
+pub(crate) fn read_notifications_before(cutoff: u64) -> Vec<u64> {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, n)| n.read && n.created_at < cutoff)
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
+
+pub(crate) fn remove_notifications(ids: &[u64]) {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in ids {
+            storage.remove(id);
+        }
+    });
+}
+
+pub(crate) fn notifications_for(recipient: Principal, unread_only: bool) -> Vec<Notification> {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, notification)| notification)
+            .filter(|n| n.recipient == recipient && (!unread_only || !n.read))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_notifications(unread_only: bool) -> Vec<Notification> {
+    notifications_for(caller_account(), unread_only)
+}
+
+#[ic_cdk::update]
+fn mark_notification_read(notification_id: u64) -> Result<(), String> {
+    NOTIFICATIONS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut notification = storage
+            .get(&notification_id)
+            .filter(|n| n.recipient == caller_account())
+            .ok_or("Notification not found".to_string())?;
+
+        notification.read = true;
+        storage.insert(notification_id, notification);
+        Ok(())
+    })
+}
@@ -0,0 +1,275 @@
+use crate::devices::caller_account;
+use crate::orders::{create_order, Order, OrderDraft};
+use crate::{next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_BUSINESS_NAME_LEN: usize = 100;
+const MAX_CATEGORY_LEN: usize = 50;
+const MAX_HARVESTS_PER_AGREEMENT: usize = 100;
+
+// WholesaleBuyer Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct WholesaleBuyer {
+    principal: Principal,
+    business_name: String,
+    registered_at: u64,
+}
+
+// OfftakeStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OfftakeStatus {
+    Proposed,
+    Active,
+    Cancelled,
+}
+
+// OfftakeAgreement Struct
+// A buyer's standing commitment to take all of a farmer's output of a category for a season
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct OfftakeAgreement {
+    id: u64,
+    buyer: Principal,
+    farmer: Principal,
+    category: String,
+    season_start: u64,
+    season_end: u64,
+    min_price: u64,
+    max_price: u64,
+    status: OfftakeStatus,
+    order_ids: Vec<u64>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for WholesaleBuyer
+impl Storable for WholesaleBuyer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WholesaleBuyer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for OfftakeAgreement
+impl Storable for OfftakeAgreement {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OfftakeAgreement {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static WHOLESALE_BUYERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, WholesaleBuyer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static OFFTAKE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static OFFTAKES_STORAGE: RefCell<StableBTreeMap<u64, OfftakeAgreement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+}
+
+// Offtake Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct OfftakePayload {
+    farmer: Principal,
+    category: String,
+    season_start: u64,
+    season_end: u64,
+    min_price: u64,
+    max_price: u64,
+}
+
+fn get_offtake_record(offtake_id: u64) -> Result<OfftakeAgreement, String> {
+    OFFTAKES_STORAGE
+        .with(|storage| storage.borrow().get(&offtake_id))
+        .ok_or("Offtake agreement not found".to_string())
+}
+
+fn save_offtake(offtake: OfftakeAgreement) {
+    OFFTAKES_STORAGE.with(|storage| storage.borrow_mut().insert(offtake.id, offtake));
+}
+
+#[ic_cdk::update]
+fn register_wholesale_buyer(business_name: String) -> Result<WholesaleBuyer, String> {
+    if business_name.is_empty() || business_name.len() > MAX_BUSINESS_NAME_LEN {
+        return Err("Invalid business name".to_string());
+    }
+
+    let buyer = WholesaleBuyer {
+        principal: caller_account(),
+        business_name,
+        registered_at: ic_cdk::api::time(),
+    };
+
+    WHOLESALE_BUYERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(buyer.principal), buyer.clone())
+    });
+    Ok(buyer)
+}
+
+#[ic_cdk::query]
+fn get_wholesale_buyer(principal: Principal) -> Result<WholesaleBuyer, String> {
+    WHOLESALE_BUYERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Wholesale buyer not found".to_string())
+}
+
+// Function for a registered wholesale buyer to propose an offtake agreement to a farmer
+#[ic_cdk::update]
+fn propose_offtake(payload: OfftakePayload) -> Result<OfftakeAgreement, String> {
+    let buyer = caller_account();
+
+    if !WHOLESALE_BUYERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(buyer)))
+    {
+        return Err("Only registered wholesale buyers can propose offtake agreements".to_string());
+    }
+    if buyer == payload.farmer {
+        return Err("Farmer and buyer must be different".to_string());
+    }
+    if payload.category.is_empty() || payload.category.len() > MAX_CATEGORY_LEN {
+        return Err("Invalid category".to_string());
+    }
+    if payload.season_start >= payload.season_end || payload.season_end <= ic_cdk::api::time() {
+        return Err("Invalid season".to_string());
+    }
+    if payload.min_price == 0 || payload.min_price > payload.max_price {
+        return Err("Invalid price band".to_string());
+    }
+
+    let offtake = OfftakeAgreement {
+        id: next_id(&OFFTAKE_ID_COUNTER),
+        buyer,
+        farmer: payload.farmer,
+        category: payload.category,
+        season_start: payload.season_start,
+        season_end: payload.season_end,
+        min_price: payload.min_price,
+        max_price: payload.max_price,
+        status: OfftakeStatus::Proposed,
+        order_ids: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_offtake(offtake.clone());
+    Ok(offtake)
+}
+
+// Function for the farmer to accept an offtake agreement
+#[ic_cdk::update]
+fn accept_offtake(offtake_id: u64) -> Result<(), String> {
+    let mut offtake = get_offtake_record(offtake_id)?;
+
+    if offtake.farmer != caller_account() {
+        return Err("Only the farmer can accept this agreement".to_string());
+    }
+    if offtake.status != OfftakeStatus::Proposed {
+        return Err("Agreement is not awaiting acceptance".to_string());
+    }
+
+    // A farmer's output of a category can only be committed to one buyer at a time
+    let overlapping = OFFTAKES_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, other)| {
+            other.status == OfftakeStatus::Active
+                && other.farmer == offtake.farmer
+                && other.category == offtake.category
+                && other.season_start < offtake.season_end
+                && offtake.season_start < other.season_end
+        })
+    });
+    if overlapping {
+        return Err("An active agreement already covers this category and season".to_string());
+    }
+
+    offtake.status = OfftakeStatus::Active;
+    save_offtake(offtake);
+    Ok(())
+}
+
+// Function for either party to cancel an agreement before it is accepted
+#[ic_cdk::update]
+fn cancel_offtake(offtake_id: u64) -> Result<(), String> {
+    let mut offtake = get_offtake_record(offtake_id)?;
+    let caller = caller_account();
+
+    if caller != offtake.farmer && caller != offtake.buyer {
+        return Err("Only a party to the agreement can cancel it".to_string());
+    }
+    if offtake.status != OfftakeStatus::Proposed {
+        return Err("Only proposed agreements can be cancelled".to_string());
+    }
+
+    offtake.status = OfftakeStatus::Cancelled;
+    save_offtake(offtake);
+    Ok(())
+}
+
+// Function for the farmer to record a harvested lot, creating the buyer's order automatically
+#[ic_cdk::update]
+fn record_harvest(offtake_id: u64, quantity: u64, unit_price: u64) -> Result<Order, String> {
+    let mut offtake = get_offtake_record(offtake_id)?;
+    let now = ic_cdk::api::time();
+
+    if offtake.farmer != caller_account() {
+        return Err("Only the farmer can record harvests".to_string());
+    }
+    if offtake.status != OfftakeStatus::Active {
+        return Err("Agreement is not active".to_string());
+    }
+    if now < offtake.season_start || now > offtake.season_end {
+        return Err("Outside the agreed season".to_string());
+    }
+    if quantity == 0 {
+        return Err("Harvest quantity must be positive".to_string());
+    }
+    if unit_price < offtake.min_price || unit_price > offtake.max_price {
+        return Err("Price outside the agreed band".to_string());
+    }
+    if offtake.order_ids.len() >= MAX_HARVESTS_PER_AGREEMENT {
+        return Err("Harvest limit reached for this agreement".to_string());
+    }
+
+    let order = create_order(OrderDraft {
+        product_id: None,
+        contract_id: None,
+        farmer: offtake.farmer,
+        consumer: offtake.buyer,
+        quantity,
+        unit_price,
+        due_at: None,
+    })?;
+
+    offtake.order_ids.push(order.id);
+    save_offtake(offtake);
+    Ok(order)
+}
+
+#[ic_cdk::query]
+fn get_offtake(offtake_id: u64) -> Result<OfftakeAgreement, String> {
+    get_offtake_record(offtake_id)
+}
@@ -0,0 +1,465 @@
+use crate::devices::caller_account;
+use crate::installments::has_installment_plan;
+use crate::ledger::{escrow_subaccount, transfer_asset_into_subaccount, Asset};
+use crate::notifications::notify;
+use crate::orders::{get_order_record, save_order, Order, OrderStatus};
+use crate::payout_schedules::{balances_account, holds_sales};
+use crate::pools::pool_order_refunds;
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, enqueue_with_effect, CallEffect, OutboundCall};
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const SUBACCOUNT_TAG: &[u8] = b"order";
+
+// OrderEscrow Struct
+// Funds an order holds on the ledger of its asset until it settles
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct OrderEscrow {
+    order_id: u64,
+    asset: Asset,
+    payer: Principal,
+    amount: u64,
+    // Latest payment into the escrow
+    block_index: u64,
+    funded_at: u64,
+}
+
+// EscrowSubaccount Struct
+// Where an order's escrow is held when it is not the order's own subaccount, e.g. that of the
+// checkout that paid for several orders with one transfer
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct EscrowSubaccount {
+    subaccount: Vec<u8>,
+}
+
+// Storable and BoundedStorable implementations for OrderEscrow
+impl Storable for OrderEscrow {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderEscrow {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for EscrowSubaccount
+impl Storable for EscrowSubaccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for EscrowSubaccount {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by order ID; an entry is removed once its payout or refund is queued
+    static ORDER_ESCROW_STORAGE: RefCell<StableBTreeMap<u64, OrderEscrow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(207)))
+    ));
+
+    // Keyed by order ID, only for escrow held outside the order's own subaccount
+    static ORDER_ESCROW_SUBACCOUNTS: RefCell<StableBTreeMap<u64, EscrowSubaccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(232)))
+    ));
+}
+
+fn escrow_location(order_id: u64) -> Vec<u8> {
+    ORDER_ESCROW_SUBACCOUNTS
+        .with(|storage| storage.borrow().get(&order_id))
+        .map_or_else(
+            || escrow_subaccount(SUBACCOUNT_TAG, order_id),
+            |held| held.subaccount,
+        )
+}
+
+// Removes an order's escrow record, with the subaccount its funds are in
+fn take_escrow(order_id: u64) -> Option<(OrderEscrow, Vec<u8>)> {
+    let escrow = ORDER_ESCROW_STORAGE.with(|storage| storage.borrow_mut().remove(&order_id))?;
+    let subaccount = escrow_location(order_id);
+    ORDER_ESCROW_SUBACCOUNTS.with(|storage| storage.borrow_mut().remove(&order_id));
+    Some((escrow, subaccount))
+}
+
+fn save_escrow(escrow: OrderEscrow) {
+    ORDER_ESCROW_STORAGE.with(|storage| storage.borrow_mut().insert(escrow.order_id, escrow));
+}
+
+// Adds a payment made at `now` to what an order holds in escrow
+fn credit_escrow(order: &Order, amount: u64, block_index: u64, now: u64) {
+    let existing = ORDER_ESCROW_STORAGE.with(|storage| storage.borrow().get(&order.id));
+    let escrow = match existing {
+        Some(mut escrow) => {
+            escrow.amount += amount;
+            escrow.block_index = block_index;
+            escrow
+        }
+        None => OrderEscrow {
+            order_id: order.id,
+            asset: order.currency(),
+            payer: order.payer(),
+            amount,
+            block_index,
+            funded_at: now,
+        },
+    };
+    save_escrow(escrow);
+}
+
+// Takes `amount` out of an escrow record, returning the part taken out
+fn split_escrow(escrow: &mut OrderEscrow, amount: u64) -> Result<OrderEscrow, String> {
+    if amount == 0 || amount > escrow.amount {
+        return Err("Amount exceeds the order's escrow".to_string());
+    }
+    escrow.amount -= amount;
+    Ok(OrderEscrow {
+        amount,
+        ..escrow.clone()
+    })
+}
+
+// Pulls `amount` from the order's payer into the order's escrow subaccount, on the ledger of
+// the order's asset. The caller claims the payment before awaiting so it cannot be made twice.
+pub(crate) async fn collect_order_payment(order: &Order, amount: u64) -> Result<u64, String> {
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, order.id);
+    let block_index =
+        transfer_asset_into_subaccount(order.currency(), order.payer(), subaccount, amount).await?;
+    credit_escrow(order, amount, block_index, ic_cdk::api::time());
+    Ok(block_index)
+}
+
+// Records an order's total as escrowed in a subaccount it was already paid into, e.g. by a
+// checkout covering several orders with one transfer
+pub(crate) fn record_order_escrow(order: &Order, subaccount: Vec<u8>, block_index: u64) {
+    ORDER_ESCROW_SUBACCOUNTS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(order.id, EscrowSubaccount { subaccount })
+    });
+    credit_escrow(order, order.total, block_index, ic_cdk::api::time());
+}
+
+fn pay_out(escrow: OrderEscrow, subaccount: Vec<u8>, to: Principal, purpose: String) {
+    enqueue(
+        OutboundCall::EscrowPayout {
+            subaccount,
+            to,
+            amount: escrow.amount,
+            asset: Some(escrow.asset),
+        },
+        purpose,
+    );
+}
+
+// Pays a completed order's escrow to the farmer in the asset it was paid in; the payout's
+// block is noted on the sale once it goes through. Default-currency sales of a farmer on a
+// payout schedule are moved to the balances subaccount instead, and credited to the farmer's
+// held balance once they arrive.
+pub(crate) fn release_order_escrow(order: &Order) {
+    if let Some((escrow, subaccount)) = take_escrow(order.id) {
+        if escrow.asset == Asset::Icp && holds_sales(&order.farmer) {
+            enqueue_with_effect(
+                OutboundCall::AccountPayout {
+                    subaccount,
+                    to: balances_account(),
+                    amount: escrow.amount,
+                    asset: Some(escrow.asset),
+                },
+                format!("Order #{} held for {}", order.id, order.farmer),
+                CallEffect::HeldSale {
+                    order_id: order.id,
+                    farmer: order.farmer,
+                },
+            );
+            return;
+        }
+        enqueue_with_effect(
+            OutboundCall::EscrowPayout {
+                subaccount,
+                to: order.farmer,
+                amount: escrow.amount,
+                asset: Some(escrow.asset),
+            },
+            format!("Order #{} payout in {:?}", order.id, escrow.asset),
+            CallEffect::SalePayout { order_id: order.id },
+        );
+    }
+}
+
+// Pays part of a funded order's escrow to the farmer ahead of completion, e.g. what a payment
+// stream has accrued; the rest stays in escrow for the completion or a refund
+pub(crate) fn release_partial_order_escrow(order: &Order, amount: u64) -> Result<(), String> {
+    let mut escrow = ORDER_ESCROW_STORAGE
+        .with(|storage| storage.borrow().get(&order.id))
+        .ok_or("Order has no escrow".to_string())?;
+    let partial = split_escrow(&mut escrow, amount)?;
+
+    let subaccount = escrow_location(order.id);
+    let purpose = format!("Order #{} partial payout in {:?}", order.id, escrow.asset);
+    if escrow.amount == 0 {
+        take_escrow(order.id);
+    } else {
+        save_escrow(escrow);
+    }
+    pay_out(partial, subaccount, order.farmer, purpose);
+    Ok(())
+}
+
+// Returns a refunded order's escrow to whoever paid it. A pool's combined order goes back to
+// each contributor in proportion to what they paid, not to the organizer who placed it.
+pub(crate) fn refund_order_escrow(order: &Order) {
+    if let Some((escrow, subaccount)) = take_escrow(order.id) {
+        let purpose = format!("Order #{} refund in {:?}", order.id, escrow.asset);
+        if let Some(shares) = pool_order_refunds(order.id, escrow.amount) {
+            for (consumer, amount) in shares.into_iter().filter(|(_, amount)| *amount > 0) {
+                let share = OrderEscrow {
+                    amount,
+                    ..escrow.clone()
+                };
+                pay_out(share, subaccount.clone(), consumer, purpose.clone());
+            }
+            return;
+        }
+        let payer = escrow.payer;
+        pay_out(escrow, subaccount, payer, purpose);
+    }
+}
+
+// Order escrow not yet paid out or refunded, for escrow reconciliation
+pub(crate) fn expected_order_escrow() -> Vec<EscrowExpectation> {
+    ORDER_ESCROW_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(order_id, escrow)| EscrowExpectation {
+                asset: escrow.asset,
+                source: format!("Order #{} escrow", order_id),
+                subaccount: escrow_location(order_id),
+                expected: escrow.amount,
+            })
+            .collect()
+    })
+}
+
+// Whether `payer` can pay an order's total in one go
+fn check_payable(order: &Order, payer: Principal) -> Result<(), String> {
+    if order.payer() != payer {
+        return Err("Only the payer can fund this order".to_string());
+    }
+    if order.status != OrderStatus::Pending {
+        return Err("Order is not awaiting payment".to_string());
+    }
+    if has_installment_plan(order.id) {
+        return Err("Order is paid in installments".to_string());
+    }
+    if order.escrowed > 0 {
+        return Err("Payment is already in progress".to_string());
+    }
+    Ok(())
+}
+
+// Moves an order whose payment arrived to Funded, as long as it is still waiting for it
+fn fund_paid_order(order: &mut Order) -> bool {
+    if order.status != OrderStatus::Pending {
+        return false;
+    }
+    order.status = OrderStatus::Funded;
+    true
+}
+
+// Function for the payer to fund an order in whatever asset it is priced in. The total is
+// pulled from the allowance the payer granted this canister on that asset's ledger into an
+// escrow subaccount for the order.
+#[ic_cdk::update]
+async fn pay_order(order_id: u64) -> Result<Order, String> {
+    let mut order = get_order_record(order_id)?;
+    check_payable(&order, caller_account())?;
+
+    // Claim the order before awaiting so a second call cannot pay it twice
+    order.escrowed = order.total;
+    save_order(order.clone());
+
+    if let Err(e) = collect_order_payment(&order, order.total).await {
+        if let Ok(mut order) = get_order_record(order_id) {
+            order.escrowed = 0;
+            save_order(order);
+        }
+        return Err(e);
+    }
+
+    // Re-read; the order may have been cancelled or refunded while the transfer was in flight
+    let mut order = get_order_record(order_id)?;
+    if !fund_paid_order(&mut order) {
+        refund_order_escrow(&order);
+        return Err(
+            "Order was cancelled while it was being paid; the payment is refunded".to_string(),
+        );
+    }
+    save_order(order.clone());
+    if let Some(payer) = order.payer {
+        notify(
+            payer,
+            format!(
+                "Receipt: you paid {} for gift order #{}",
+                order.total, order.id
+            ),
+        );
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::test_order;
+
+    fn stored_escrow(order_id: u64) -> Option<OrderEscrow> {
+        ORDER_ESCROW_STORAGE.with(|storage| storage.borrow().get(&order_id))
+    }
+
+    fn hold_elsewhere(order_id: u64, subaccount: Vec<u8>) {
+        ORDER_ESCROW_SUBACCOUNTS.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(order_id, EscrowSubaccount { subaccount })
+        });
+    }
+
+    #[test]
+    fn payments_add_up_in_one_escrow_record() {
+        let order = test_order(1, 300);
+        credit_escrow(&order, 100, 7, 10);
+        credit_escrow(&order, 200, 8, 20);
+
+        let escrow = stored_escrow(1).unwrap();
+        assert_eq!(escrow.amount, 300);
+        assert_eq!(escrow.block_index, 8);
+        assert_eq!(escrow.funded_at, 10);
+        assert_eq!(escrow.payer, order.consumer);
+        assert_eq!(escrow.asset, Asset::Icp);
+    }
+
+    #[test]
+    fn escrow_sits_in_the_order_subaccount_unless_recorded_elsewhere() {
+        assert_eq!(escrow_location(2), escrow_subaccount(SUBACCOUNT_TAG, 2));
+
+        hold_elsewhere(3, escrow_subaccount(b"checkout", 9));
+        assert_eq!(escrow_location(3), escrow_subaccount(b"checkout", 9));
+    }
+
+    #[test]
+    fn taking_escrow_removes_the_record_and_its_location() {
+        let order = test_order(4, 50);
+        credit_escrow(&order, 50, 1, 0);
+        hold_elsewhere(4, escrow_subaccount(b"pool", 2));
+
+        let (escrow, subaccount) = take_escrow(4).unwrap();
+        assert_eq!(escrow.amount, 50);
+        assert_eq!(subaccount, escrow_subaccount(b"pool", 2));
+        assert!(take_escrow(4).is_none());
+        assert_eq!(escrow_location(4), escrow_subaccount(SUBACCOUNT_TAG, 4));
+        assert!(expected_order_escrow().is_empty());
+    }
+
+    #[test]
+    fn reconciliation_expects_each_escrow_where_it_is_held() {
+        credit_escrow(&test_order(5, 80), 80, 1, 0);
+        credit_escrow(&test_order(6, 40), 40, 2, 0);
+        hold_elsewhere(6, escrow_subaccount(b"checkout", 1));
+
+        let expected = expected_order_escrow();
+        assert_eq!(expected.len(), 2);
+        assert_eq!(expected[0].subaccount, escrow_subaccount(SUBACCOUNT_TAG, 5));
+        assert_eq!(expected[0].expected, 80);
+        assert_eq!(expected[1].subaccount, escrow_subaccount(b"checkout", 1));
+        assert_eq!(expected[1].expected, 40);
+    }
+
+    #[test]
+    fn splitting_escrow_leaves_the_rest_behind() {
+        let order = test_order(7, 100);
+        credit_escrow(&order, 100, 1, 0);
+        let mut escrow = stored_escrow(7).unwrap();
+
+        let partial = split_escrow(&mut escrow, 30).unwrap();
+        assert_eq!(partial.amount, 30);
+        assert_eq!(partial.payer, escrow.payer);
+        assert_eq!(escrow.amount, 70);
+
+        assert!(split_escrow(&mut escrow, 0).is_err());
+        assert!(split_escrow(&mut escrow, 71).is_err());
+        assert_eq!(escrow.amount, 70);
+
+        split_escrow(&mut escrow, 70).unwrap();
+        assert_eq!(escrow.amount, 0);
+    }
+
+    #[test]
+    fn only_the_payer_can_pay_a_pending_unpaid_order() {
+        let mut order = test_order(8, 100);
+        assert!(check_payable(&order, order.consumer).is_ok());
+        assert_eq!(
+            check_payable(&order, order.farmer),
+            Err("Only the payer can fund this order".to_string())
+        );
+
+        order.escrowed = order.total;
+        assert_eq!(
+            check_payable(&order, order.consumer),
+            Err("Payment is already in progress".to_string())
+        );
+
+        order.status = OrderStatus::Funded;
+        assert_eq!(
+            check_payable(&order, order.consumer),
+            Err("Order is not awaiting payment".to_string())
+        );
+    }
+
+    #[test]
+    fn a_gift_order_is_paid_by_the_gift_payer() {
+        let payer = Principal::from_slice(&[3]);
+        let order = Order {
+            payer: Some(payer),
+            ..test_order(9, 100)
+        };
+        assert!(check_payable(&order, payer).is_ok());
+        assert!(check_payable(&order, order.consumer).is_err());
+    }
+
+    #[test]
+    fn a_payment_funds_only_an_order_still_waiting_for_it() {
+        let mut order = test_order(9, 100);
+        order.escrowed = order.total;
+        assert!(fund_paid_order(&mut order));
+        assert_eq!(order.status, OrderStatus::Funded);
+
+        for status in [
+            OrderStatus::Cancelled,
+            OrderStatus::Refunded,
+            OrderStatus::Disputed,
+        ] {
+            order.status = status;
+            assert!(!fund_paid_order(&mut order));
+            assert_eq!(order.status, status);
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use crate::analytics::record_order_sale;
+use crate::certificates::mint_certificate;
+use crate::consumers::is_registered_consumer;
+use crate::contracts::sync_contract_status;
+use crate::coupons::{coupon_discount, record_redemption};
+use crate::devices::caller_account;
+use crate::inventory::{release_stock, reserve_stock};
+use crate::invoices::issue_invoice;
+use crate::leaderboard::record_completed_sale;
+use crate::ledger::Asset;
+use crate::listing_assets::listing_asset_price;
+use crate::loyalty::{award_points, return_redeemed_points, settle_redeemed_points};
+use crate::notifications::notify;
+use crate::order_escrow::release_order_escrow;
+use crate::price_history::record_sale;
+use crate::pricing::unit_price_for;
+use crate::publisher::{publish, EventTopic};
+use crate::raffles::record_raffle_entries;
+use crate::receipts::issue_receipt;
+use crate::referrals::reward_referral;
+use crate::risk::{flag_risk, RiskKind};
+use crate::sales_history::book_sale;
+use crate::sealed_auctions::is_in_auction;
+use crate::shipping::ShippingLine;
+use crate::stats::record_order_change;
+use crate::supplies::release_supply_stock;
+use crate::taxes::{apply_taxes, record_collected_tax};
+use crate::{
+    farmer_principal, is_off_market, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TIMELINE_CHANGES: usize = 20;
+const ORDERS_PER_PAGE: usize = 20;
+
+// OrderStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OrderStatus {
+    Pending,
+    Funded,
+    Shipped,
+    // Goods rejected by the consumer and on their way back
+    Returning,
+    Completed,
+    Disputed,
+    Refunded,
+    Cancelled,
+}
+
+// Order Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Order {
+    pub(crate) id: u64,
+    pub(crate) product_id: Option<u64>,
+    pub(crate) contract_id: Option<u64>,
+    pub(crate) farmer: Principal,
+    pub(crate) consumer: Principal,
+    // Set when someone other than the consumer pays for the order
+    pub(crate) payer: Option<Principal>,
+    pub(crate) quantity: u64,
+    pub(crate) unit_price: u64,
+    pub(crate) total: u64,
+    pub(crate) escrowed: u64,
+    pub(crate) status: OrderStatus,
+    pub(crate) due_at: Option<u64>,
+    // Delivery charge included in `total`, if the payer chose one
+    pub(crate) shipping: Option<ShippingLine>,
+    // Asset the order is priced and paid in; None for the default ledger
+    pub(crate) currency: Option<Asset>,
+    pub(crate) created_at: u64,
+    pub(crate) updated_at: u64,
+}
+
+// OrderStatusChange Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct OrderStatusChange {
+    status: OrderStatus,
+    at: u64,
+}
+
+// OrderTimeline Struct
+// When an order reached each of its statuses, oldest first
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct OrderTimeline {
+    changes: Vec<OrderStatusChange>,
+}
+
+// TrackedOrder Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TrackedOrder {
+    order: Order,
+    timeline: Vec<OrderStatusChange>,
+}
+
+impl Order {
+    // Whoever funds the order: the consumer, or the gift payer
+    pub(crate) fn payer(&self) -> Principal {
+        self.payer.unwrap_or(self.consumer)
+    }
+
+    pub(crate) fn currency(&self) -> Asset {
+        self.currency.unwrap_or(Asset::Icp)
+    }
+}
+
+// A pending, unpaid order in the default currency, for unit tests of the money paths
+#[cfg(test)]
+pub(crate) fn test_order(id: u64, total: u64) -> Order {
+    Order {
+        id,
+        product_id: None,
+        contract_id: None,
+        farmer: Principal::from_slice(&[1]),
+        consumer: Principal::from_slice(&[2]),
+        payer: None,
+        quantity: 1,
+        unit_price: total,
+        total,
+        escrowed: 0,
+        status: OrderStatus::Pending,
+        due_at: None,
+        shipping: None,
+        currency: None,
+        created_at: 0,
+        updated_at: 0,
+    }
+}
+
+// Storable and BoundedStorable implementations for Order
+impl Storable for Order {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Order {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for OrderTimeline
+impl Storable for OrderTimeline {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderTimeline {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    pub(crate) static ORDERS_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static ORDER_TIMELINES_STORAGE: RefCell<StableBTreeMap<u64, OrderTimeline, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(192)))
+    ));
+}
+
+// Order_Draft Payload
+// Internal description of an order other modules ask to be created
+pub(crate) struct OrderDraft {
+    pub(crate) product_id: Option<u64>,
+    pub(crate) contract_id: Option<u64>,
+    pub(crate) farmer: Principal,
+    pub(crate) consumer: Principal,
+    pub(crate) quantity: u64,
+    pub(crate) unit_price: u64,
+    pub(crate) due_at: Option<u64>,
+}
+
+pub(crate) fn create_order(draft: OrderDraft) -> Result<Order, String> {
+    let total = draft
+        .quantity
+        .checked_mul(draft.unit_price)
+        .ok_or("Order total overflows".to_string())?;
+
+    let now = ic_cdk::api::time();
+    let order = Order {
+        id: next_id(&ORDER_ID_COUNTER),
+        product_id: draft.product_id,
+        contract_id: draft.contract_id,
+        farmer: draft.farmer,
+        consumer: draft.consumer,
+        payer: None,
+        quantity: draft.quantity,
+        unit_price: draft.unit_price,
+        total,
+        escrowed: 0,
+        status: OrderStatus::Pending,
+        due_at: draft.due_at,
+        shipping: None,
+        currency: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    save_order(order.clone());
+    publish(EventTopic::OrderCreated, order.id, "order");
+    Ok(order)
+}
+
+pub(crate) fn get_order_record(order_id: u64) -> Result<Order, String> {
+    ORDERS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("Order not found".to_string())
+}
+
+pub(crate) fn save_order(mut order: Order) {
+    order.updated_at = ic_cdk::api::time();
+    let previous = ORDERS_STORAGE.with(|storage| storage.borrow().get(&order.id));
+    record_order_change(previous.as_ref(), &order);
+    if previous.map_or(true, |previous| previous.status != order.status) {
+        record_status_change(&order);
+        // Every cancel and refund path ends here, so points redeemed on the order go back once
+        if matches!(order.status, OrderStatus::Cancelled | OrderStatus::Refunded) {
+            return_redeemed_points(&order);
+        }
+    }
+    ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, order));
+}
+
+fn record_status_change(order: &Order) {
+    ORDER_TIMELINES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut timeline = storage.get(&order.id).unwrap_or_default();
+        if timeline.changes.len() >= MAX_TIMELINE_CHANGES {
+            // Keep the first entry, when the order was placed
+            timeline.changes.remove(1);
+        }
+        timeline.changes.push(OrderStatusChange {
+            status: order.status,
+            at: order.updated_at,
+        });
+        storage.insert(order.id, timeline);
+    });
+}
+
+fn order_timeline(order_id: u64) -> Vec<OrderStatusChange> {
+    ORDER_TIMELINES_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .map(|timeline| timeline.changes)
+        .unwrap_or_default()
+}
+
+// When the order last became Funded, or its last update if the timeline no longer says
+pub(crate) fn funded_at(order: &Order) -> u64 {
+    order_timeline(order.id)
+        .iter()
+        .rev()
+        .find(|change| change.status == OrderStatus::Funded)
+        .map_or(order.updated_at, |change| change.at)
+}
+
+// Creates an order on a listing for `consumer`, paid by `payer`; the total is priced
+// server-side and an optional coupon of the payer's is applied to it
+fn order_listing(
+    farmer_id: u64,
+    quantity: u64,
+    consumer: Principal,
+    payer: Principal,
+    coupon_code: Option<String>,
+    currency: Asset,
+) -> Result<Order, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if farmer.is_sold {
+        return Err("Product already sold".to_string());
+    }
+    if is_off_market(&farmer) {
+        return Err("Listing is no longer available".to_string());
+    }
+    if is_in_auction(farmer_id) {
+        return Err("Listing is in a sealed-bid auction".to_string());
+    }
+
+    let owner = farmer_principal(&farmer)?;
+    if consumer == owner || payer == owner {
+        flag_risk(
+            owner,
+            RiskKind::SelfDealing,
+            format!("Tried to order own listing #{}", farmer_id),
+        );
+        return Err("Farmers cannot order their own products".to_string());
+    }
+
+    let unit_price = if currency == Asset::Icp {
+        unit_price_for(&farmer, quantity)?
+    } else if coupon_code.is_some() {
+        return Err("Coupons only apply to orders in the default currency".to_string());
+    } else {
+        listing_asset_price(farmer_id, currency)?
+    };
+    let subtotal = quantity
+        .checked_mul(unit_price)
+        .ok_or("Order total overflows".to_string())?;
+    // Validate the coupon before anything is stored so a rejected code leaves no order behind
+    let discount = match &coupon_code {
+        Some(code) => coupon_discount(code, &farmer, payer, subtotal)?,
+        None => 0,
+    };
+    reserve_stock(farmer_id, quantity)?;
+
+    let mut order = create_order(OrderDraft {
+        product_id: Some(farmer_id),
+        contract_id: None,
+        farmer: owner,
+        consumer,
+        quantity,
+        unit_price,
+        due_at: None,
+    })?;
+    apply_taxes(&mut order)?;
+
+    if let Some(code) = coupon_code {
+        order.total -= discount;
+        record_redemption(&code, payer, order.id, discount);
+    }
+    if payer != consumer {
+        order.payer = Some(payer);
+    }
+    if currency != Asset::Icp {
+        order.currency = Some(currency);
+    }
+    save_order(order.clone());
+    Ok(order)
+}
+
+// Function for a consumer to order a quantity of a listing for themselves
+#[ic_cdk::update]
+fn place_order(
+    farmer_id: u64,
+    quantity: u64,
+    coupon_code: Option<String>,
+) -> Result<Order, String> {
+    let consumer = caller_account();
+    order_listing(
+        farmer_id,
+        quantity,
+        consumer,
+        consumer,
+        coupon_code,
+        Asset::Icp,
+    )
+}
+
+// Function for a consumer to order a listing priced in another asset, e.g. ckBTC; the order
+// is then paid with `pay_order`
+#[ic_cdk::update]
+fn place_order_in_asset(farmer_id: u64, quantity: u64, asset: Asset) -> Result<Order, String> {
+    let consumer = caller_account();
+    order_listing(farmer_id, quantity, consumer, consumer, None, asset)
+}
+
+// Function for a payer to buy a listing for another registered consumer, who receives it
+#[ic_cdk::update]
+fn place_gift_order(
+    farmer_id: u64,
+    quantity: u64,
+    recipient: Principal,
+    coupon_code: Option<String>,
+) -> Result<Order, String> {
+    let payer = caller_account();
+    if recipient == payer {
+        return Err("Use place_order to buy for yourself".to_string());
+    }
+    if !is_registered_consumer(&recipient) {
+        return Err("Recipient is not a registered consumer".to_string());
+    }
+
+    let order = order_listing(
+        farmer_id,
+        quantity,
+        recipient,
+        payer,
+        coupon_code,
+        Asset::Icp,
+    )?;
+    notify(
+        recipient,
+        format!("Order #{} was placed for you as a gift", order.id),
+    );
+    Ok(order)
+}
+
+// Side effects of an order reaching Completed, whichever path got it there
+pub(crate) fn on_order_completed(order: &Order) {
+    award_points(order);
+    settle_redeemed_points(order.id);
+    reward_referral(order);
+    // Price history is kept in the default currency
+    if let (Some(product_id), Asset::Icp) = (order.product_id, order.currency()) {
+        record_sale(product_id, order.unit_price, order.quantity);
+    }
+    record_order_sale(order);
+    record_completed_sale(order);
+    book_sale(order);
+    record_collected_tax(order);
+    issue_receipt(order);
+    issue_invoice(order);
+    mint_certificate(order);
+    release_order_escrow(order);
+    record_raffle_entries(order);
+    publish(EventTopic::PaymentReleased, order.id, "order");
+    if let Some(payer) = order.payer {
+        notify(payer, format!("Gift order #{} was delivered", order.id));
+    }
+}
+
+// Moves an order to Completed and runs the completion side effects
+pub(crate) fn complete_order(mut order: Order) {
+    order.status = OrderStatus::Completed;
+    let contract_id = order.contract_id;
+    on_order_completed(&order);
+    save_order(order);
+
+    if let Some(contract_id) = contract_id {
+        sync_contract_status(contract_id);
+    }
+}
+
+// Function for the farmer to mark a funded order as shipped
+#[ic_cdk::update]
+fn mark_order_shipped(order_id: u64) -> Result<(), String> {
+    let mut order = get_order_record(order_id)?;
+
+    if order.farmer != caller_account() {
+        return Err("Only the farmer can ship this order".to_string());
+    }
+    if order.status != OrderStatus::Funded {
+        return Err("Order is not funded".to_string());
+    }
+
+    order.status = OrderStatus::Shipped;
+    save_order(order);
+    Ok(())
+}
+
+// Function for the consumer to confirm delivery, completing the order
+#[ic_cdk::update]
+fn confirm_order_delivery(order_id: u64) -> Result<(), String> {
+    let order = get_order_record(order_id)?;
+
+    if order.consumer != caller_account() {
+        return Err("Only the consumer can confirm delivery".to_string());
+    }
+    if order.status != OrderStatus::Shipped {
+        return Err("Order has not been shipped".to_string());
+    }
+
+    complete_order(order);
+    Ok(())
+}
+
+// Cancels an order nothing was paid for, putting its stock back; the caller saves it
+pub(crate) fn cancel_unfunded_order(order: &mut Order) {
+    if let Some(farmer_id) = order.product_id {
+        release_stock(farmer_id, order.quantity);
+    }
+    release_supply_stock(order.id, order.quantity);
+    order.status = OrderStatus::Cancelled;
+}
+
+// Function for the payer to cancel an order before it is funded
+#[ic_cdk::update]
+fn cancel_order(order_id: u64) -> Result<(), String> {
+    let mut order = get_order_record(order_id)?;
+
+    if order.payer() != caller_account() {
+        return Err("Only the payer can cancel this order".to_string());
+    }
+    if order.status != OrderStatus::Pending || order.escrowed > 0 {
+        return Err("Only unfunded orders can be cancelled".to_string());
+    }
+
+    cancel_unfunded_order(&mut order);
+    save_order(order);
+    Ok(())
+}
+
+// Function for a party to an order to see when it reached each status
+#[ic_cdk::query]
+fn get_order_timeline(order_id: u64) -> Result<Vec<OrderStatusChange>, String> {
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+    if caller != order.farmer && caller != order.consumer && caller != order.payer() {
+        return Err("Only the parties to an order can view it".to_string());
+    }
+    Ok(order_timeline(order_id))
+}
+
+// Function for a consumer to page through their purchases, newest first, 20 per page,
+// optionally only those in one status. Gift orders count for both the payer and the recipient.
+#[ic_cdk::query]
+fn get_my_orders(status: Option<OrderStatus>, page: u64) -> Vec<TrackedOrder> {
+    let caller = caller_account();
+    ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, order)| order)
+            .filter(|order| order.consumer == caller || order.payer() == caller)
+            .filter(|order| status.map_or(true, |status| order.status == status))
+            .skip((page as usize).saturating_mul(ORDERS_PER_PAGE))
+            .take(ORDERS_PER_PAGE)
+            .map(|order| TrackedOrder {
+                timeline: order_timeline(order.id),
+                order,
+            })
+            .collect()
+    })
+}
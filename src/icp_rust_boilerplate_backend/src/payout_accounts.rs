@@ -0,0 +1,210 @@
+use crate::devices::caller_account;
+use crate::ledger::{ledger_fee, Account, Asset};
+use crate::mobile_money::{is_valid_phone_number, MobileMoneyProvider};
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Confirmation transfers are between these amounts before the ledger fee, in e8s
+const MIN_CONFIRMATION_AMOUNT: u64 = 100_000;
+const CONFIRMATION_AMOUNT_SPREAD: u64 = 100_000;
+// Wrong amounts after which the farmer has to register the account again
+const MAX_CONFIRMATION_ATTEMPTS: u32 = 3;
+
+// MobileMoneyReference Struct
+// Wallet mobile money payouts for the farmer go to
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct MobileMoneyReference {
+    provider: MobileMoneyProvider,
+    phone_number: String,
+}
+
+// PayoutAccount Struct
+// Where a farmer's payouts are sent, once the farmer confirmed the transfer that reached it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PayoutAccount {
+    farmer: Principal,
+    account: Account,
+    mobile_money: Option<MobileMoneyReference>,
+    verified_at: Option<u64>,
+    // Wrong amounts entered for the current confirmation transfer
+    failed_attempts: u32,
+    // When the current confirmation transfer was queued
+    requested_at: u64,
+}
+
+// Storable and BoundedStorable implementations for PayoutAccount
+impl Storable for PayoutAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PayoutAccount {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PAYOUT_ACCOUNTS: RefCell<StableBTreeMap<PrincipalKey, PayoutAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(229)))
+    ));
+
+    // Amount sent by each confirmation transfer still waiting to be confirmed. Kept out of
+    // the account so it is never returned by a query.
+    static PAYOUT_CONFIRMATIONS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(230)))
+    ));
+}
+
+fn payout_account(farmer: &Principal) -> Option<PayoutAccount> {
+    PAYOUT_ACCOUNTS.with(|storage| storage.borrow().get(&PrincipalKey(*farmer)))
+}
+
+fn save_payout_account(account: PayoutAccount) {
+    PAYOUT_ACCOUNTS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(account.farmer), account)
+    });
+}
+
+// Where a farmer's payouts go: their verified payout account, or None to pay their principal.
+// A farmer who registered an account that is not verified yet cannot be paid until it is.
+pub(crate) fn payout_target(farmer: &Principal) -> Result<Option<Account>, String> {
+    match payout_account(farmer) {
+        None => Ok(None),
+        Some(account) if account.verified_at.is_some() => Ok(Some(account.account)),
+        Some(_) => Err("Payout account is not verified".to_string()),
+    }
+}
+
+// An amount only someone watching the account can know before the transfer shows up in it
+fn confirmation_amount(farmer: &Principal, account: &Account, now: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(farmer.as_slice());
+    hasher.update(account.owner.as_slice());
+    hasher.update(account.subaccount.as_deref().unwrap_or(&[]));
+    hasher.update(now.to_be_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    MIN_CONFIRMATION_AMOUNT + u64::from_be_bytes(bytes) % CONFIRMATION_AMOUNT_SPREAD
+}
+
+// Function for a farmer to register the account their payouts go to, and optionally the mobile
+// money wallet they use. A small confirmation transfer is sent to the account; the farmer
+// verifies it by entering the amount that arrived.
+#[ic_cdk::update]
+fn register_payout_account(
+    account: Account,
+    mobile_money: Option<MobileMoneyReference>,
+) -> Result<PayoutAccount, String> {
+    let farmer = caller_account();
+    if farmer == Principal::anonymous() {
+        return Err("Anonymous users cannot register a payout account".to_string());
+    }
+    if account.owner == Principal::anonymous() || account.owner == ic_cdk::id() {
+        return Err("Invalid payout account".to_string());
+    }
+    if account
+        .subaccount
+        .as_ref()
+        .map_or(false, |subaccount| subaccount.len() != 32)
+    {
+        return Err("Subaccount must be 32 bytes".to_string());
+    }
+    if let Some(reference) = &mobile_money {
+        if !is_valid_phone_number(&reference.phone_number) {
+            return Err(
+                "Phone number must be in international format, e.g. +254712345678".to_string(),
+            );
+        }
+    }
+
+    // Each confirmation transfer is paid by the marketplace, so they are limited to one a day
+    let now = ic_cdk::api::time();
+    if let Some(existing) = payout_account(&farmer) {
+        if now < existing.requested_at + NANOS_PER_DAY {
+            return Err("A payout account can be registered once a day".to_string());
+        }
+    }
+
+    let amount = confirmation_amount(&farmer, &account, now);
+    enqueue(
+        OutboundCall::AccountPayout {
+            subaccount: vec![0u8; 32],
+            to: account.clone(),
+            amount,
+            asset: None,
+        },
+        format!("Payout account confirmation for {}", farmer),
+    );
+    PAYOUT_CONFIRMATIONS.with(|storage| storage.borrow_mut().insert(PrincipalKey(farmer), amount));
+
+    let payout_account = PayoutAccount {
+        farmer,
+        account,
+        mobile_money,
+        verified_at: None,
+        failed_attempts: 0,
+        requested_at: now,
+    };
+    save_payout_account(payout_account.clone());
+    Ok(payout_account)
+}
+
+// Function for a farmer to verify their payout account with the amount the confirmation
+// transfer delivered, after the ledger fee
+#[ic_cdk::update]
+async fn verify_payout_account(amount: u64) -> Result<PayoutAccount, String> {
+    let farmer = caller_account();
+    if PAYOUT_CONFIRMATIONS
+        .with(|storage| storage.borrow().get(&PrincipalKey(farmer)))
+        .is_none()
+    {
+        return Err("No payout account is waiting for verification".to_string());
+    }
+    let fee = ledger_fee(Asset::Icp).await?;
+
+    // Read again after the call; the account may have changed meanwhile
+    let sent = PAYOUT_CONFIRMATIONS
+        .with(|storage| storage.borrow().get(&PrincipalKey(farmer)))
+        .ok_or("No payout account is waiting for verification".to_string())?;
+    let mut account = payout_account(&farmer)
+        .ok_or("No payout account is waiting for verification".to_string())?;
+
+    if amount.saturating_add(fee) != sent {
+        account.failed_attempts += 1;
+        if account.failed_attempts >= MAX_CONFIRMATION_ATTEMPTS {
+            PAYOUT_CONFIRMATIONS.with(|storage| storage.borrow_mut().remove(&PrincipalKey(farmer)));
+            save_payout_account(account);
+            return Err(
+                "Amount does not match; register the payout account again to retry".to_string(),
+            );
+        }
+        save_payout_account(account);
+        return Err("Amount does not match the confirmation transfer".to_string());
+    }
+
+    PAYOUT_CONFIRMATIONS.with(|storage| storage.borrow_mut().remove(&PrincipalKey(farmer)));
+    account.verified_at = Some(ic_cdk::api::time());
+    save_payout_account(account.clone());
+    Ok(account)
+}
+
+#[ic_cdk::query]
+fn get_payout_account() -> Option<PayoutAccount> {
+    payout_account(&caller_account())
+}
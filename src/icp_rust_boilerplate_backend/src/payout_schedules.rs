@@ -0,0 +1,387 @@
+use crate::devices::caller_account;
+use crate::events::log_event;
+use crate::ledger::{escrow_subaccount, Account, Asset};
+use crate::mobile_money::unsettled_mobile_money;
+use crate::notifications::notify;
+use crate::payout_accounts::payout_target;
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Subaccount holding the sales of every farmer on a payout schedule
+const BALANCES_SUBACCOUNT_TAG: &[u8] = b"balances";
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Smaller balances wait for the next sweep rather than lose most of themselves to the fee
+const MIN_SWEEP_AMOUNT: u64 = 1_000_000;
+// Farmers swept per maintenance run; the rest are picked up on the next one
+const MAX_SWEEP_BATCH: usize = 50;
+const MAX_PAYOUTS_PER_PAGE: usize = 100;
+
+// PayoutFrequency Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PayoutFrequency {
+    Daily,
+    Weekly,
+    // Never swept; the farmer cashes the balance out, e.g. to mobile money
+    Manual,
+}
+
+impl PayoutFrequency {
+    fn period(self) -> Option<u64> {
+        match self {
+            PayoutFrequency::Daily => Some(NANOS_PER_DAY),
+            PayoutFrequency::Weekly => Some(7 * NANOS_PER_DAY),
+            PayoutFrequency::Manual => None,
+        }
+    }
+}
+
+// PayoutSchedule Struct
+// A farmer who opted to have their sales held in a balance and paid out in batches
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PayoutSchedule {
+    farmer: Principal,
+    frequency: PayoutFrequency,
+    next_sweep_at: u64,
+    updated_at: u64,
+}
+
+// ScheduledPayout Struct
+// One sweep of a farmer's held balance to their account
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ScheduledPayout {
+    id: u64,
+    farmer: Principal,
+    // Taken from the balance; the farmer receives it less the ledger fee
+    amount: u64,
+    // The retry queue call making the transfer
+    call_id: u64,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for PayoutSchedule
+impl Storable for PayoutSchedule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PayoutSchedule {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ScheduledPayout
+impl Storable for ScheduledPayout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScheduledPayout {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PAYOUT_SCHEDULES: RefCell<StableBTreeMap<PrincipalKey, PayoutSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(226)))
+    ));
+
+    static SCHEDULED_PAYOUT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(227))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SCHEDULED_PAYOUTS: RefCell<StableBTreeMap<u64, ScheduledPayout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(228)))
+    ));
+
+    // What each farmer has in the balances subaccount, credited once a held sale arrives there
+    static HELD_BALANCES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(233)))
+    ));
+}
+
+pub(crate) fn balances_subaccount() -> Vec<u8> {
+    escrow_subaccount(BALANCES_SUBACCOUNT_TAG, 0)
+}
+
+// The balances subaccount as a transfer destination
+pub(crate) fn balances_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(balances_subaccount()),
+    }
+}
+
+// Whether a farmer's default-currency sales are held in their balance rather than paid out
+// order by order
+pub(crate) fn holds_sales(farmer: &Principal) -> bool {
+    PAYOUT_SCHEDULES.with(|storage| storage.borrow().contains_key(&PrincipalKey(*farmer)))
+}
+
+pub(crate) fn held_balance(farmer: &Principal) -> u64 {
+    HELD_BALANCES.with(|storage| storage.borrow().get(&PrincipalKey(*farmer)).unwrap_or(0))
+}
+
+// Adds funds that reached the balances subaccount to a farmer's balance
+pub(crate) fn credit_held_balance(farmer: &Principal, amount: u64) {
+    let balance = held_balance(farmer).saturating_add(amount);
+    HELD_BALANCES.with(|storage| storage.borrow_mut().insert(PrincipalKey(*farmer), balance));
+}
+
+// Takes an amount out of a farmer's balance before it leaves the balances subaccount
+pub(crate) fn debit_held_balance(farmer: &Principal, amount: u64) -> Result<(), String> {
+    let balance = held_balance(farmer);
+    if amount > balance {
+        return Err("Amount exceeds your held balance".to_string());
+    }
+    HELD_BALANCES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if balance == amount {
+            storage.remove(&PrincipalKey(*farmer));
+        } else {
+            storage.insert(PrincipalKey(*farmer), balance - amount);
+        }
+    });
+    Ok(())
+}
+
+// What the balances subaccount should hold, for escrow reconciliation: every farmer's balance
+// and the mobile money payouts taken from it that the aggregator has not settled yet
+pub(crate) fn expected_held_escrow() -> Vec<EscrowExpectation> {
+    let held = HELD_BALANCES.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .fold(0u64, |total, (_, balance)| total.saturating_add(balance))
+    });
+    let expected = held.saturating_add(unsettled_mobile_money());
+    if expected == 0 {
+        return Vec::new();
+    }
+    vec![EscrowExpectation {
+        asset: Asset::Icp,
+        source: "Held farmer balances".to_string(),
+        subaccount: balances_subaccount(),
+        expected,
+    }]
+}
+
+// Pays a farmer's whole held balance to their payout account, or their principal without one
+fn pay_out_balance(farmer: Principal, target: Option<Account>, now: u64) {
+    let amount = held_balance(&farmer);
+    if debit_held_balance(&farmer, amount).is_err() {
+        return;
+    }
+    let id = next_id(&SCHEDULED_PAYOUT_ID_COUNTER);
+    let call = match target {
+        Some(account) => OutboundCall::AccountPayout {
+            subaccount: balances_subaccount(),
+            to: account,
+            amount,
+            asset: None,
+        },
+        None => OutboundCall::EscrowPayout {
+            subaccount: balances_subaccount(),
+            to: farmer,
+            amount,
+            asset: None,
+        },
+    };
+    let call_id = enqueue(call, format!("Scheduled payout #{} to {}", id, farmer));
+    SCHEDULED_PAYOUTS.with(|storage| {
+        storage.borrow_mut().insert(
+            id,
+            ScheduledPayout {
+                id,
+                farmer,
+                amount,
+                call_id,
+                created_at: now,
+            },
+        )
+    });
+    log_event(
+        "scheduled_payout",
+        format!("#{} of {} to {}", id, amount, farmer),
+    );
+    notify(
+        farmer,
+        format!("Your held balance of {} is on its way", amount),
+    );
+}
+
+// When a sweep that was due at `next_sweep_at` is next due. A sweep that was missed is not made
+// up; the next one pays the whole balance anyway.
+fn next_sweep_after(next_sweep_at: u64, now: u64, period: u64) -> u64 {
+    next_sweep_at + period * ((now - next_sweep_at) / period + 1)
+}
+
+// Pays each farmer whose sweep is due their whole held balance in one ledger transfer from the
+// balances subaccount
+pub(crate) fn sweep_scheduled_payouts() {
+    let now = ic_cdk::api::time();
+    let due: Vec<PayoutSchedule> = PAYOUT_SCHEDULES.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, schedule)| schedule)
+            .filter(|schedule| schedule.next_sweep_at <= now)
+            .take(MAX_SWEEP_BATCH)
+            .collect()
+    });
+
+    for mut schedule in due {
+        // Manual schedules are never due; their next sweep is never reached
+        let period = match schedule.frequency.period() {
+            Some(period) => period,
+            None => continue,
+        };
+        // Balances waiting on a payout account to be verified are swept once it is
+        if held_balance(&schedule.farmer) >= MIN_SWEEP_AMOUNT {
+            if let Ok(target) = payout_target(&schedule.farmer) {
+                pay_out_balance(schedule.farmer, target, now);
+            }
+        }
+
+        schedule.next_sweep_at = next_sweep_after(schedule.next_sweep_at, now, period);
+        schedule.updated_at = now;
+        PAYOUT_SCHEDULES.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(PrincipalKey(schedule.farmer), schedule)
+        });
+    }
+}
+
+// Function for a farmer to have their sales held and paid out daily or weekly in one transfer,
+// or held until cashed out with Manual. None stops holding sales and pays out what is held.
+#[ic_cdk::update]
+fn set_payout_schedule(
+    frequency: Option<PayoutFrequency>,
+) -> Result<Option<PayoutSchedule>, String> {
+    let farmer = caller_account();
+    if farmer == Principal::anonymous() {
+        return Err("Anonymous users cannot schedule payouts".to_string());
+    }
+
+    let frequency = match frequency {
+        Some(frequency) => frequency,
+        None => {
+            if held_balance(&farmer) > 0 {
+                let target = payout_target(&farmer)?;
+                pay_out_balance(farmer, target, ic_cdk::api::time());
+            }
+            PAYOUT_SCHEDULES.with(|storage| storage.borrow_mut().remove(&PrincipalKey(farmer)));
+            return Ok(None);
+        }
+    };
+    let now = ic_cdk::api::time();
+    let schedule = PayoutSchedule {
+        farmer,
+        frequency,
+        next_sweep_at: frequency.period().map_or(u64::MAX, |period| now + period),
+        updated_at: now,
+    };
+    PAYOUT_SCHEDULES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(farmer), schedule.clone())
+    });
+    Ok(Some(schedule))
+}
+
+#[ic_cdk::query]
+fn get_payout_schedule() -> Option<PayoutSchedule> {
+    PAYOUT_SCHEDULES.with(|storage| storage.borrow().get(&PrincipalKey(caller_account())))
+}
+
+#[ic_cdk::query]
+fn get_held_balance() -> u64 {
+    held_balance(&caller_account())
+}
+
+// Function for a farmer to list their scheduled payouts, newest first
+#[ic_cdk::query]
+fn get_my_scheduled_payouts() -> Vec<ScheduledPayout> {
+    let farmer = caller_account();
+    let mut payouts: Vec<ScheduledPayout> = SCHEDULED_PAYOUTS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, payout)| payout)
+            .filter(|payout| payout.farmer == farmer)
+            .collect()
+    });
+    payouts.reverse();
+    payouts.truncate(MAX_PAYOUTS_PER_PAGE);
+    payouts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_balances_add_up_and_empty_out() {
+        let farmer = Principal::from_slice(&[1]);
+        credit_held_balance(&farmer, 300);
+        credit_held_balance(&farmer, 200);
+        assert_eq!(held_balance(&farmer), 500);
+
+        assert_eq!(
+            debit_held_balance(&farmer, 501),
+            Err("Amount exceeds your held balance".to_string())
+        );
+        assert_eq!(held_balance(&farmer), 500);
+
+        debit_held_balance(&farmer, 100).unwrap();
+        assert_eq!(held_balance(&farmer), 400);
+        debit_held_balance(&farmer, 400).unwrap();
+        assert!(!HELD_BALANCES.with(|storage| storage.borrow().contains_key(&PrincipalKey(farmer))));
+    }
+
+    #[test]
+    fn the_balances_subaccount_should_hold_every_balance() {
+        assert!(expected_held_escrow().is_empty());
+
+        credit_held_balance(&Principal::from_slice(&[1]), 300);
+        credit_held_balance(&Principal::from_slice(&[2]), 700);
+        let expected = expected_held_escrow();
+        assert_eq!(expected.len(), 1);
+        assert_eq!(expected[0].subaccount, balances_subaccount());
+        assert_eq!(expected[0].expected, 1_000);
+    }
+
+    #[test]
+    fn missed_sweeps_are_not_made_up() {
+        let day = NANOS_PER_DAY;
+        assert_eq!(next_sweep_after(day, day, day), 2 * day);
+        assert_eq!(next_sweep_after(day, day + 1, day), 2 * day);
+        // Three sweeps were missed; the next one is still a period ahead of now at most
+        assert_eq!(next_sweep_after(day, 4 * day + 5, day), 5 * day);
+    }
+
+    #[test]
+    fn manual_schedules_are_never_swept() {
+        assert_eq!(PayoutFrequency::Manual.period(), None);
+        assert_eq!(PayoutFrequency::Weekly.period(), Some(7 * NANOS_PER_DAY));
+    }
+}
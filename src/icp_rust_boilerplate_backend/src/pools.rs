@@ -0,0 +1,488 @@
+use crate::devices::caller_account;
+use crate::inventory::reserve_stock;
+use crate::ledger::{escrow_subaccount, transfer_into_subaccount, Asset};
+use crate::order_escrow::record_order_escrow;
+use crate::orders::{create_order, save_order, OrderDraft, OrderStatus};
+use crate::pricing::unit_price_for;
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{
+    farmer_principal, is_off_market, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_POOL_PARTICIPANTS: usize = 50;
+// Labels the escrow subaccounts that hold pool contributions
+const SUBACCOUNT_TAG: &[u8] = b"pool";
+
+// PoolStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PoolStatus {
+    Open,
+    Filled,
+    Expired,
+}
+
+// PoolContribution Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PoolContribution {
+    consumer: Principal,
+    quantity: u64,
+    amount: u64,
+    refunded: bool,
+}
+
+// PurchasePool Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PurchasePool {
+    id: u64,
+    farmer_id: u64,
+    organizer: Principal,
+    min_quantity: u64,
+    unit_price: u64,
+    total_quantity: u64,
+    contributions: Vec<PoolContribution>,
+    status: PoolStatus,
+    order_id: Option<u64>,
+    expires_at: u64,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for PurchasePool
+impl Storable for PurchasePool {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PurchasePool {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static POOL_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static POOLS_STORAGE: RefCell<StableBTreeMap<u64, PurchasePool, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Pool each combined order was placed for, keyed by order ID
+    static POOL_ORDERS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(238)))
+    ));
+}
+
+// Pool Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct PoolPayload {
+    farmer_id: u64,
+    min_quantity: u64,
+    quantity: u64,
+    expires_at: u64,
+}
+
+fn get_pool_record(pool_id: u64) -> Result<PurchasePool, String> {
+    POOLS_STORAGE
+        .with(|storage| storage.borrow().get(&pool_id))
+        .ok_or("Pool not found".to_string())
+}
+
+fn save_pool(pool: PurchasePool) {
+    POOLS_STORAGE.with(|storage| storage.borrow_mut().insert(pool.id, pool));
+}
+
+// What a contribution of `quantity` units costs, checked before any payment is taken
+fn contribution_amount(pool: &PurchasePool, quantity: u64) -> Result<u64, String> {
+    if quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    if pool.contributions.len() >= MAX_POOL_PARTICIPANTS {
+        return Err("Pool is full".to_string());
+    }
+    quantity
+        .checked_mul(pool.unit_price)
+        .ok_or("Contribution overflows".to_string())
+}
+
+// Pulls a contribution from the consumer's allowance into the pool's escrow subaccount
+async fn collect_contribution(
+    pool_id: u64,
+    consumer: Principal,
+    amount: u64,
+) -> Result<u64, String> {
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, pool_id);
+    transfer_into_subaccount(consumer, subaccount, amount).await
+}
+
+// Sends a contribution back out of the pool's escrow through the retry queue
+fn refund_contribution(pool_id: u64, consumer: Principal, amount: u64) {
+    enqueue(
+        OutboundCall::EscrowPayout {
+            subaccount: escrow_subaccount(SUBACCOUNT_TAG, pool_id),
+            to: consumer,
+            amount,
+            asset: None,
+        },
+        format!("Pool #{} contribution refund to {}", pool_id, consumer),
+    );
+}
+
+// Adds a paid contribution and, once the minimum is reached, creates the combined order
+fn contribute(
+    pool: &mut PurchasePool,
+    consumer: Principal,
+    quantity: u64,
+    block_index: u64,
+) -> Result<(), String> {
+    let amount = contribution_amount(pool, quantity)?;
+    pool.total_quantity = pool
+        .total_quantity
+        .checked_add(quantity)
+        .ok_or("Pool quantity overflows".to_string())?;
+    pool.contributions.push(PoolContribution {
+        consumer,
+        quantity,
+        amount,
+        refunded: false,
+    });
+
+    if pool.total_quantity >= pool.min_quantity {
+        let farmer = FARMERS_STORAGE
+            .with(|storage| storage.borrow().get(&pool.farmer_id))
+            .ok_or("Farmer not found".to_string())?;
+        let owner = farmer_principal(&farmer)?;
+        pool.total_quantity
+            .checked_mul(pool.unit_price)
+            .ok_or("Order total overflows".to_string())?;
+        reserve_stock(pool.farmer_id, pool.total_quantity)?;
+
+        let mut order = create_order(OrderDraft {
+            product_id: Some(pool.farmer_id),
+            contract_id: None,
+            farmer: owner,
+            consumer: pool.organizer,
+            quantity: pool.total_quantity,
+            unit_price: pool.unit_price,
+            due_at: None,
+        })?;
+
+        // Contributions already sit in the pool's escrow, which the combined order takes over
+        record_order_escrow(
+            &order,
+            escrow_subaccount(SUBACCOUNT_TAG, pool.id),
+            block_index,
+        );
+        order.escrowed = order.total;
+        order.status = OrderStatus::Funded;
+        pool.order_id = Some(order.id);
+        pool.status = PoolStatus::Filled;
+        POOL_ORDERS.with(|storage| storage.borrow_mut().insert(order.id, pool.id));
+        save_order(order);
+    }
+
+    Ok(())
+}
+
+// Splits `amount` over the contributions in proportion to what each paid; the last one takes
+// what rounding leaves over so the shares add up to `amount`
+fn refund_shares(contributions: &[PoolContribution], amount: u64) -> Vec<(Principal, u64)> {
+    let paid = contributions.iter().fold(0u64, |total, contribution| {
+        total.saturating_add(contribution.amount)
+    });
+    if paid == 0 {
+        return Vec::new();
+    }
+    let mut left = amount;
+    contributions
+        .iter()
+        .enumerate()
+        .map(|(index, contribution)| {
+            let share = if index + 1 == contributions.len() {
+                left
+            } else {
+                (amount as u128 * contribution.amount as u128 / paid as u128) as u64
+            };
+            left -= share;
+            (contribution.consumer, share)
+        })
+        .collect()
+}
+
+// What each contributor gets back of `amount` refunded from a pool's combined order, which the
+// organizer placed on everyone's behalf. None for orders no pool placed.
+pub(crate) fn pool_order_refunds(order_id: u64, amount: u64) -> Option<Vec<(Principal, u64)>> {
+    let pool_id = POOL_ORDERS.with(|storage| storage.borrow().get(&order_id))?;
+    let mut pool = get_pool_record(pool_id).ok()?;
+    let shares = refund_shares(&pool.contributions, amount);
+    for contribution in pool.contributions.iter_mut() {
+        contribution.refunded = true;
+    }
+    save_pool(pool);
+    Some(shares)
+}
+
+// Function for a consumer to open a purchase pool on a bulk listing with their own quantity,
+// paid from their ICRC-2 allowance into the pool's escrow
+#[ic_cdk::update]
+async fn open_pool(payload: PoolPayload) -> Result<PurchasePool, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    let organizer = caller_account();
+    if farmer.is_sold {
+        return Err("Product already sold".to_string());
+    }
+    if is_off_market(&farmer) {
+        return Err("Listing is no longer available".to_string());
+    }
+    if organizer == farmer_principal(&farmer)? {
+        return Err("Farmers cannot pool on their own products".to_string());
+    }
+    if payload.min_quantity == 0 || payload.expires_at <= ic_cdk::api::time() {
+        return Err("Invalid pool parameters".to_string());
+    }
+
+    // Everyone pays the unit price of the pool's target quantity
+    let mut pool = PurchasePool {
+        id: next_id(&POOL_ID_COUNTER),
+        farmer_id: payload.farmer_id,
+        organizer,
+        min_quantity: payload.min_quantity,
+        unit_price: unit_price_for(&farmer, payload.min_quantity)?,
+        total_quantity: 0,
+        contributions: Vec::new(),
+        status: PoolStatus::Open,
+        order_id: None,
+        expires_at: payload.expires_at,
+        created_at: ic_cdk::api::time(),
+    };
+
+    let amount = contribution_amount(&pool, payload.quantity)?;
+    let block_index = collect_contribution(pool.id, organizer, amount).await?;
+    if let Err(e) = contribute(&mut pool, organizer, payload.quantity, block_index) {
+        refund_contribution(pool.id, organizer, amount);
+        return Err(e);
+    }
+    save_pool(pool.clone());
+    Ok(pool)
+}
+
+// Function for a consumer to join an open pool with their quantity, paid from their ICRC-2
+// allowance into the pool's escrow
+#[ic_cdk::update]
+async fn join_pool(pool_id: u64, quantity: u64) -> Result<PurchasePool, String> {
+    let pool = get_pool_record(pool_id)?;
+    let consumer = caller_account();
+
+    if pool.status != PoolStatus::Open || pool.expires_at <= ic_cdk::api::time() {
+        return Err("Pool is not open".to_string());
+    }
+
+    let amount = contribution_amount(&pool, quantity)?;
+    let block_index = collect_contribution(pool_id, consumer, amount).await?;
+
+    // Read again; the pool may have filled or expired while the transfer was in flight
+    let mut pool = get_pool_record(pool_id)?;
+    let result = if pool.status != PoolStatus::Open {
+        Err("Pool is not open".to_string())
+    } else {
+        contribute(&mut pool, consumer, quantity, block_index)
+    };
+    if let Err(e) = result {
+        refund_contribution(pool_id, consumer, amount);
+        return Err(e);
+    }
+    save_pool(pool.clone());
+    Ok(pool)
+}
+
+#[ic_cdk::query]
+fn get_pool(pool_id: u64) -> Result<PurchasePool, String> {
+    get_pool_record(pool_id)
+}
+
+// Refunds every contribution of open pools that passed their expiry without filling, out of
+// the pool's escrow and less the ledger fee
+pub(crate) fn expire_pools() {
+    let now = ic_cdk::api::time();
+    let expired: Vec<PurchasePool> = POOLS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, pool)| pool)
+            .filter(|pool| pool.status == PoolStatus::Open && pool.expires_at <= now)
+            .collect()
+    });
+
+    for mut pool in expired {
+        pool.status = PoolStatus::Expired;
+        for contribution in pool
+            .contributions
+            .iter_mut()
+            .filter(|contribution| !contribution.refunded)
+        {
+            refund_contribution(pool.id, contribution.consumer, contribution.amount);
+            contribution.refunded = true;
+        }
+        save_pool(pool);
+    }
+}
+
+// What each open pool's escrow should hold: its contributions so far, for escrow
+// reconciliation. A filled pool's escrow belongs to its combined order.
+pub(crate) fn expected_pool_escrow() -> Vec<EscrowExpectation> {
+    POOLS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, pool)| pool.status == PoolStatus::Open)
+            .map(|(pool_id, pool)| EscrowExpectation {
+                asset: Asset::Icp,
+                source: format!("Pool #{} contributions", pool_id),
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, pool_id),
+                expected: pool.contributions.iter().fold(0u64, |total, contribution| {
+                    total.saturating_add(contribution.amount)
+                }),
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_pool_record(id: u64, min_quantity: u64, unit_price: u64) -> PurchasePool {
+        PurchasePool {
+            id,
+            farmer_id: 1,
+            organizer: Principal::from_slice(&[2]),
+            min_quantity,
+            unit_price,
+            total_quantity: 0,
+            contributions: Vec::new(),
+            status: PoolStatus::Open,
+            order_id: None,
+            expires_at: 1_000,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn contributions_cost_quantity_times_unit_price() {
+        let pool = open_pool_record(1, 100, 250);
+        assert_eq!(contribution_amount(&pool, 4), Ok(1_000));
+        assert_eq!(
+            contribution_amount(&pool, 0),
+            Err("Quantity must be positive".to_string())
+        );
+        assert_eq!(
+            contribution_amount(&pool, u64::MAX),
+            Err("Contribution overflows".to_string())
+        );
+    }
+
+    #[test]
+    fn a_full_pool_takes_no_more_contributions() {
+        let mut pool = open_pool_record(2, 1_000, 10);
+        for consumer in 0..MAX_POOL_PARTICIPANTS as u8 {
+            contribute(&mut pool, Principal::from_slice(&[consumer]), 1, 0).unwrap();
+        }
+        assert_eq!(
+            contribution_amount(&pool, 1),
+            Err("Pool is full".to_string())
+        );
+        assert_eq!(pool.total_quantity, MAX_POOL_PARTICIPANTS as u64);
+        assert_eq!(pool.status, PoolStatus::Open);
+    }
+
+    #[test]
+    fn contributions_below_the_minimum_keep_the_pool_open() {
+        let mut pool = open_pool_record(3, 10, 100);
+        contribute(&mut pool, Principal::from_slice(&[3]), 4, 7).unwrap();
+        contribute(&mut pool, Principal::from_slice(&[4]), 5, 8).unwrap();
+
+        assert_eq!(pool.total_quantity, 9);
+        assert_eq!(pool.contributions.len(), 2);
+        assert_eq!(pool.contributions[1].amount, 500);
+        assert_eq!(pool.status, PoolStatus::Open);
+        assert_eq!(pool.order_id, None);
+    }
+
+    #[test]
+    fn open_pools_expect_their_contributions_in_escrow() {
+        let mut open = open_pool_record(4, 10, 100);
+        contribute(&mut open, Principal::from_slice(&[3]), 2, 0).unwrap();
+        contribute(&mut open, Principal::from_slice(&[4]), 3, 0).unwrap();
+        save_pool(open);
+        let mut filled = open_pool_record(5, 10, 100);
+        filled.status = PoolStatus::Filled;
+        save_pool(filled);
+
+        let expected = expected_pool_escrow();
+        assert_eq!(expected.len(), 1);
+        assert_eq!(expected[0].subaccount, escrow_subaccount(SUBACCOUNT_TAG, 4));
+        assert_eq!(expected[0].expected, 500);
+    }
+
+    #[test]
+    fn refunds_are_shared_in_proportion_to_contributions() {
+        let mut pool = open_pool_record(6, 100, 10);
+        contribute(&mut pool, Principal::from_slice(&[3]), 1, 0).unwrap();
+        contribute(&mut pool, Principal::from_slice(&[4]), 2, 0).unwrap();
+        contribute(&mut pool, Principal::from_slice(&[5]), 3, 0).unwrap();
+
+        assert_eq!(
+            refund_shares(&pool.contributions, 60),
+            vec![
+                (Principal::from_slice(&[3]), 10),
+                (Principal::from_slice(&[4]), 20),
+                (Principal::from_slice(&[5]), 30),
+            ]
+        );
+        // Rounding left over goes to the last contributor
+        let shares = refund_shares(&pool.contributions, 7);
+        assert_eq!(shares.iter().map(|(_, share)| share).sum::<u64>(), 7);
+        assert_eq!(shares[2].1, 4);
+    }
+
+    #[test]
+    fn only_pool_orders_are_refunded_to_contributors() {
+        assert_eq!(pool_order_refunds(1, 100), None);
+
+        let mut pool = open_pool_record(7, 100, 10);
+        contribute(&mut pool, Principal::from_slice(&[3]), 1, 0).unwrap();
+        contribute(&mut pool, Principal::from_slice(&[4]), 3, 0).unwrap();
+        pool.status = PoolStatus::Filled;
+        pool.order_id = Some(70);
+        save_pool(pool);
+        POOL_ORDERS.with(|storage| storage.borrow_mut().insert(70, 7));
+
+        assert_eq!(
+            pool_order_refunds(70, 40),
+            Some(vec![
+                (Principal::from_slice(&[3]), 10),
+                (Principal::from_slice(&[4]), 30),
+            ])
+        );
+        let pool = get_pool_record(7).unwrap();
+        assert!(pool
+            .contributions
+            .iter()
+            .all(|contribution| contribution.refunded));
+    }
+}
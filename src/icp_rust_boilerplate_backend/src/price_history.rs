@@ -0,0 +1,341 @@
+use crate::shipping::{listing_region, normalise_region};
+use crate::{next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CATEGORY_KEY_LEN: usize = 50;
+// Most points returned by one category query, the latest kept
+const MAX_CATEGORY_POINTS: usize = 1_000;
+// Sales this recent feed the suggested price band
+const SUGGESTION_WINDOW_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+// Fewest sales a band is computed from
+const MIN_BAND_SAMPLES: usize = 3;
+
+// PricePointKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PricePointKind {
+    // The farmer set the listing's asking price
+    Listed,
+    // An order on the listing completed at this unit price
+    Sale,
+}
+
+// PricePoint Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PricePoint {
+    id: u64,
+    pub(crate) product_id: u64,
+    pub(crate) category: String,
+    pub(crate) kind: PricePointKind,
+    pub(crate) price: u64,
+    // Units sold; zero for asking price changes
+    pub(crate) quantity: u64,
+    pub(crate) at: u64,
+}
+
+// TimeRange Struct
+// Inclusive bounds in nanoseconds since the epoch
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Copy, Debug)]
+pub(crate) struct TimeRange {
+    pub(crate) from: u64,
+    pub(crate) to: u64,
+}
+
+// PriceBand Struct
+// Spread of recent sale prices: lower quartile, median and upper quartile
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PriceBand {
+    pub(crate) low: u64,
+    pub(crate) median: u64,
+    pub(crate) high: u64,
+    pub(crate) sample_size: u64,
+    // Whether the band comes from the listing's region alone or the whole category
+    pub(crate) regional: bool,
+}
+
+// ProductPointKey Struct
+// A listing's points in the order they were recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProductPointKey {
+    product_id: u64,
+    point_id: u64,
+}
+
+// CategoryPointKey Struct
+// "category|at|point id", zero padded so a category's points sort by time
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CategoryPointKey(String);
+
+// Storable and BoundedStorable implementations for PricePoint
+impl Storable for PricePoint {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PricePoint {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ProductPointKey
+impl Storable for ProductPointKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.product_id.to_be_bytes());
+        bytes.extend_from_slice(&self.point_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (product_id, point_id) = bytes.split_at(8);
+        ProductPointKey {
+            product_id: u64::from_be_bytes(product_id.try_into().unwrap()),
+            point_id: u64::from_be_bytes(point_id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ProductPointKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Storable and BoundedStorable implementations for CategoryPointKey
+impl Storable for CategoryPointKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        CategoryPointKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for CategoryPointKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PRICE_POINT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static PRICE_POINTS_STORAGE: RefCell<StableBTreeMap<u64, PricePoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116)))
+    ));
+
+    static PRODUCT_POINTS_INDEX: RefCell<StableBTreeMap<ProductPointKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117)))
+    ));
+
+    static CATEGORY_POINTS_INDEX: RefCell<StableBTreeMap<CategoryPointKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118)))
+    ));
+}
+
+// Categories are compared case-insensitively and trimmed to fit the index key
+pub(crate) fn category_key(category: &str) -> String {
+    let mut key = category.trim().to_lowercase().replace('|', "/");
+    while key.len() > MAX_CATEGORY_KEY_LEN {
+        key.pop();
+    }
+    key
+}
+
+fn category_point_key(category: &str, at: u64, point_id: u64) -> CategoryPointKey {
+    CategoryPointKey(format!("{}|{:020}|{:020}", category, at, point_id))
+}
+
+fn record_point(product_id: u64, category: &str, kind: PricePointKind, price: u64, quantity: u64) {
+    let point = PricePoint {
+        id: next_id(&PRICE_POINT_ID_COUNTER),
+        product_id,
+        category: category_key(category),
+        kind,
+        price,
+        quantity,
+        at: ic_cdk::api::time(),
+    };
+
+    PRODUCT_POINTS_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            ProductPointKey {
+                product_id,
+                point_id: point.id,
+            },
+            point.id,
+        )
+    });
+    CATEGORY_POINTS_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            category_point_key(&point.category, point.at, point.id),
+            point.id,
+        )
+    });
+    PRICE_POINTS_STORAGE.with(|storage| storage.borrow_mut().insert(point.id, point));
+}
+
+// Records a listing's asking price when it is listed or repriced
+pub(crate) fn record_price_change(product_id: u64, category: &str, price: u64) {
+    record_point(product_id, category, PricePointKind::Listed, price, 0);
+}
+
+// Records the unit price of a completed order on a listing
+pub(crate) fn record_sale(product_id: u64, unit_price: u64, quantity: u64) {
+    if let Some(farmer) = FARMERS_STORAGE.with(|storage| storage.borrow().get(&product_id)) {
+        record_point(
+            product_id,
+            &farmer.category,
+            PricePointKind::Sale,
+            unit_price,
+            quantity,
+        );
+    }
+}
+
+// Points of one category within a time range, oldest first
+pub(crate) fn category_points(category: &str, range: TimeRange) -> Vec<PricePoint> {
+    let category = category_key(category);
+    let start = category_point_key(&category, range.from, 0);
+    let end = category_point_key(&category, range.to, u64::MAX);
+
+    let point_ids: Vec<u64> = CATEGORY_POINTS_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..=end)
+            .map(|(_, point_id)| point_id)
+            .collect()
+    });
+    PRICE_POINTS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        point_ids
+            .into_iter()
+            .filter_map(|point_id| storage.get(&point_id))
+            .collect()
+    })
+}
+
+fn band_of(mut prices: Vec<u64>, regional: bool) -> Option<PriceBand> {
+    if prices.len() < MIN_BAND_SAMPLES {
+        return None;
+    }
+    prices.sort_unstable();
+    let last = prices.len() - 1;
+    Some(PriceBand {
+        low: prices[last / 4],
+        median: prices[last / 2],
+        high: prices[last * 3 / 4],
+        sample_size: prices.len() as u64,
+        regional,
+    })
+}
+
+// Price band from the category's recent sales, narrowed to listings from `region` when there
+// are enough of them
+pub(crate) fn suggest_price(category: &str, region: Option<&str>) -> Option<PriceBand> {
+    let now = ic_cdk::api::time();
+    let sales: Vec<PricePoint> = category_points(
+        category,
+        TimeRange {
+            from: now.saturating_sub(SUGGESTION_WINDOW_NANOS),
+            to: now,
+        },
+    )
+    .into_iter()
+    .filter(|point| point.kind == PricePointKind::Sale)
+    .collect();
+
+    if let Some(region) = region {
+        let regional: Vec<u64> = sales
+            .iter()
+            .filter(|point| listing_region(point.product_id).ok().as_deref() == Some(region))
+            .map(|point| point.price)
+            .collect();
+        if let Some(band) = band_of(regional, true) {
+            return Some(band);
+        }
+    }
+    band_of(sales.into_iter().map(|point| point.price).collect(), false)
+}
+
+// Warns when a price is under half the band's low end or over twice its high end
+pub(crate) fn price_warning(price: u64, band: &PriceBand) -> Option<String> {
+    if price.saturating_mul(2) < band.low {
+        Some(format!(
+            "Price {} is far below recent sales, which mostly ranged {} to {}",
+            price, band.low, band.high
+        ))
+    } else if price > band.high.saturating_mul(2) {
+        Some(format!(
+            "Price {} is far above recent sales, which mostly ranged {} to {}",
+            price, band.low, band.high
+        ))
+    } else {
+        None
+    }
+}
+
+// Every asking price and sale price recorded for a listing, oldest first
+#[ic_cdk::query]
+fn get_price_history(product_id: u64) -> Vec<PricePoint> {
+    let point_ids: Vec<u64> = PRODUCT_POINTS_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(
+                ProductPointKey {
+                    product_id,
+                    point_id: 0,
+                }..=ProductPointKey {
+                    product_id,
+                    point_id: u64::MAX,
+                },
+            )
+            .map(|(_, point_id)| point_id)
+            .collect()
+    });
+    PRICE_POINTS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        point_ids
+            .into_iter()
+            .filter_map(|point_id| storage.get(&point_id))
+            .collect()
+    })
+}
+
+// Prices recorded across a category within a time range, oldest first; at most the latest
+// 1000 points are returned
+#[ic_cdk::query]
+fn get_category_price_history(
+    category: String,
+    range: TimeRange,
+) -> Result<Vec<PricePoint>, String> {
+    if range.from > range.to {
+        return Err("Invalid time range".to_string());
+    }
+
+    let mut points = category_points(&category, range);
+    if points.len() > MAX_CATEGORY_POINTS {
+        points.drain(..points.len() - MAX_CATEGORY_POINTS);
+    }
+    Ok(points)
+}
+
+// Suggested price band for a category, optionally for listings from a region, before listing
+#[ic_cdk::query]
+fn get_suggested_price(category: String, region: Option<String>) -> Result<PriceBand, String> {
+    let region = region.map(|region| normalise_region(&region)).transpose()?;
+    suggest_price(&category, region.as_deref())
+        .ok_or("Not enough recent sales to suggest a price".to_string())
+}
@@ -0,0 +1,144 @@
+use crate::flash_sales::sale_price;
+use crate::ledger::Asset;
+use crate::listing_assets::accepts_asset;
+use crate::{is_farmer_owner, Farmer, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TIERS: usize = 10;
+
+// PriceTier Struct
+// Unit price applying to orders of `min_quantity..=max_quantity` units
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PriceTier {
+    min_quantity: u64,
+    max_quantity: u64,
+    unit_price: u64,
+}
+
+// PriceTierTable Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct PriceTierTable {
+    tiers: Vec<PriceTier>,
+}
+
+// Storable and BoundedStorable implementations for PriceTierTable
+impl Storable for PriceTierTable {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PriceTierTable {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PRICE_TIERS_STORAGE: RefCell<StableBTreeMap<u64, PriceTierTable, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+}
+
+// Unit price for an order of `quantity` units: the matching tier, or the listing price without
+// tiers, less any flash sale running right now
+pub(crate) fn unit_price_for(farmer: &Farmer, quantity: u64) -> Result<u64, String> {
+    if quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    if !accepts_asset(farmer.id, Asset::Icp) {
+        return Err("Listing is not sold in the default currency".to_string());
+    }
+
+    let unit_price = match PRICE_TIERS_STORAGE.with(|storage| storage.borrow().get(&farmer.id)) {
+        None => farmer.price,
+        Some(table) => table
+            .tiers
+            .iter()
+            .find(|tier| tier.min_quantity <= quantity && quantity <= tier.max_quantity)
+            .map(|tier| tier.unit_price)
+            .ok_or("Quantity outside the listing's price tiers".to_string())?,
+    };
+    Ok(sale_price(farmer.id, unit_price))
+}
+
+// Order total for `quantity` units, always computed from the stored tiers
+pub(crate) fn order_total(farmer: &Farmer, quantity: u64) -> Result<u64, String> {
+    unit_price_for(farmer, quantity)?
+        .checked_mul(quantity)
+        .ok_or("Order total overflows".to_string())
+}
+
+// Tiers must start at one unit and cover a contiguous range of quantities
+fn validate_tiers(tiers: &[PriceTier]) -> Result<(), String> {
+    if tiers.len() > MAX_TIERS {
+        return Err("Too many price tiers".to_string());
+    }
+
+    let mut next_min = 1;
+    for tier in tiers {
+        if tier.min_quantity != next_min {
+            return Err("Price tiers must be contiguous and start at one unit".to_string());
+        }
+        if tier.max_quantity < tier.min_quantity || tier.unit_price == 0 {
+            return Err("Invalid price tier".to_string());
+        }
+        next_min = tier
+            .max_quantity
+            .checked_add(1)
+            .ok_or("Invalid price tier".to_string())?;
+    }
+    Ok(())
+}
+
+// Function for a farmer to replace the quantity-break pricing of a listing; empty tiers clear it
+#[ic_cdk::update]
+fn set_price_tiers(farmer_id: u64, tiers: Vec<PriceTier>) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can set price tiers".to_string());
+    }
+
+    if tiers.is_empty() {
+        PRICE_TIERS_STORAGE.with(|storage| storage.borrow_mut().remove(&farmer_id));
+        return Ok(());
+    }
+
+    validate_tiers(&tiers)?;
+    PRICE_TIERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(farmer_id, PriceTierTable { tiers })
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_price_tiers(farmer_id: u64) -> Vec<PriceTier> {
+    PRICE_TIERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .map(|table| table.tiers)
+            .unwrap_or_default()
+    })
+}
+
+#[ic_cdk::query]
+fn quote_order_total(farmer_id: u64, quantity: u64) -> Result<u64, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    order_total(&farmer, quantity)
+}
@@ -0,0 +1,278 @@
+use crate::retry_queue::{dead_letter, OutboundCall};
+use crate::{is_admin, next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_METHOD_LEN: usize = 64;
+const MAX_DELIVERY_ATTEMPTS: u32 = 10;
+const RETRY_BASE_NANOS: u64 = 60 * 1_000_000_000;
+const MAX_FLUSH_BATCH: usize = 200;
+
+// EventTopic Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventTopic {
+    OrderCreated,
+    // Escrow paid out to the seller
+    PaymentReleased,
+    DisputeResolved,
+}
+
+// DomainEvent Struct
+// What subscribers receive, as the single argument of their method
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DomainEvent {
+    id: u64,
+    topic: EventTopic,
+    // The order, listing or dispute the event is about
+    subject_id: u64,
+    detail: String,
+    at: u64,
+}
+
+// Subscriber Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Subscriber {
+    canister: Principal,
+    method: String,
+    topics: Vec<EventTopic>,
+    registered_at: u64,
+}
+
+// OutboxEntry Struct
+// An event waiting to be sent to one subscriber
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct OutboxEntry {
+    id: u64,
+    canister: Principal,
+    method: String,
+    event: DomainEvent,
+    attempts: u32,
+    next_attempt_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Subscriber
+impl Storable for Subscriber {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscriber {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for OutboxEntry
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OutboxEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SUBSCRIBERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Subscriber, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(145)))
+    ));
+
+    static DOMAIN_EVENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(146))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static OUTBOX_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(147))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static OUTBOX_STORAGE: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(148)))
+    ));
+}
+
+// Queues the event for every subscriber to its topic and tries to send it straight away
+pub(crate) fn publish(topic: EventTopic, subject_id: u64, detail: &str) {
+    let subscribers: Vec<Subscriber> = SUBSCRIBERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscriber)| subscriber)
+            .filter(|subscriber| subscriber.topics.contains(&topic))
+            .collect()
+    });
+    if subscribers.is_empty() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let event = DomainEvent {
+        id: next_id(&DOMAIN_EVENT_ID_COUNTER),
+        topic,
+        subject_id,
+        detail: detail.to_string(),
+        at: now,
+    };
+    for subscriber in subscribers {
+        let entry = OutboxEntry {
+            id: next_id(&OUTBOX_ID_COUNTER),
+            canister: subscriber.canister,
+            method: subscriber.method,
+            event: event.clone(),
+            attempts: 0,
+            next_attempt_at: now,
+        };
+        deliver(entry);
+    }
+}
+
+// Sends the entry as a one-way call. It leaves the outbox once the call is handed to the
+// system; a failure is retried with exponential backoff until the attempts run out, and then
+// dead-lettered.
+fn deliver(mut entry: OutboxEntry) {
+    let sent =
+        ic_cdk::api::call::notify(entry.canister, &entry.method, (entry.event.clone(),)).is_ok();
+    entry.attempts += 1;
+
+    if sent || entry.attempts >= MAX_DELIVERY_ATTEMPTS {
+        OUTBOX_STORAGE.with(|storage| storage.borrow_mut().remove(&entry.id));
+        if !sent {
+            // Kept in the retry queue so an administrator can send it again
+            dead_letter(
+                OutboundCall::Notify {
+                    canister: entry.canister,
+                    method: entry.method,
+                    args: Encode!(&entry.event).unwrap(),
+                },
+                format!("Domain event #{}", entry.event.id),
+                "Subscriber could not be reached".to_string(),
+            );
+        }
+        return;
+    }
+    entry.next_attempt_at =
+        ic_cdk::api::time().saturating_add(RETRY_BASE_NANOS << entry.attempts.min(16));
+    OUTBOX_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+}
+
+// Retries outbox entries that are due, oldest first
+pub(crate) fn flush_outbox() {
+    let now = ic_cdk::api::time();
+    let due: Vec<OutboxEntry> = OUTBOX_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.next_attempt_at <= now)
+            .take(MAX_FLUSH_BATCH)
+            .collect()
+    });
+    for entry in due {
+        deliver(entry);
+    }
+}
+
+// Function for an administrator to let another canister receive domain events. The
+// canister's `method` is called one-way with a DomainEvent for each event on its topics.
+#[ic_cdk::update]
+fn register_event_subscriber(
+    canister: Principal,
+    method: String,
+    topics: Vec<EventTopic>,
+) -> Result<Subscriber, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register subscribers".to_string());
+    }
+    if method.trim().is_empty() || method.len() > MAX_METHOD_LEN {
+        return Err("Invalid method name".to_string());
+    }
+    if topics.is_empty() {
+        return Err("Subscribe to at least one topic".to_string());
+    }
+
+    let subscriber = Subscriber {
+        canister,
+        method,
+        topics,
+        registered_at: ic_cdk::api::time(),
+    };
+    SUBSCRIBERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(canister), subscriber.clone())
+    });
+    Ok(subscriber)
+}
+
+// Function for an administrator to stop sending events to a canister; queued events for it
+// are dropped
+#[ic_cdk::update]
+fn remove_event_subscriber(canister: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can remove subscribers".to_string());
+    }
+
+    SUBSCRIBERS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(canister)))
+        .ok_or("Subscriber not found".to_string())?;
+    let queued: Vec<u64> = OUTBOX_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.canister == canister)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    OUTBOX_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in queued {
+            storage.remove(&id);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_event_subscribers() -> Result<Vec<Subscriber>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can list subscribers".to_string());
+    }
+    Ok(SUBSCRIBERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscriber)| subscriber)
+            .collect()
+    }))
+}
+
+// The oldest events still waiting to be sent, for monitoring delivery
+#[ic_cdk::query]
+fn get_event_outbox() -> Result<Vec<OutboxEntry>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can read the outbox".to_string());
+    }
+    Ok(OUTBOX_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .take(MAX_FLUSH_BATCH)
+            .collect()
+    }))
+}
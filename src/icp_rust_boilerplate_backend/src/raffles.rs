@@ -0,0 +1,398 @@
+use crate::devices::caller_account;
+use crate::events::log_event;
+use crate::loyalty::grant_raffle_points;
+use crate::notifications::notify;
+use crate::orders::Order;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_WINNERS: u64 = 20;
+
+// RaffleStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RaffleStatus {
+    Open,
+    // Waiting on randomness for the draw
+    Drawing,
+    Drawn,
+    Cancelled,
+}
+
+// Raffle Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Raffle {
+    id: u64,
+    name: String,
+    // Orders completed in [starts_at, ends_at) earn entries
+    starts_at: u64,
+    ends_at: u64,
+    entries_per_order: u64,
+    winner_count: u64,
+    // Loyalty points credited to each winner
+    prize_points: u64,
+    status: RaffleStatus,
+    total_entries: u64,
+    winners: Vec<Principal>,
+    // Event log entry recording the draw
+    draw_event_id: Option<u64>,
+    created_at: u64,
+}
+
+// RafflePayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct RafflePayload {
+    name: String,
+    starts_at: u64,
+    ends_at: u64,
+    entries_per_order: u64,
+    winner_count: u64,
+    prize_points: u64,
+}
+
+// RaffleEntry Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RaffleEntry {
+    participant: Principal,
+    entries: u64,
+}
+
+// RaffleEntryKey Struct
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RaffleEntryKey {
+    raffle_id: u64,
+    participant: Principal,
+}
+
+// Storable and BoundedStorable implementations for Raffle
+impl Storable for Raffle {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Raffle {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for RaffleEntryKey
+impl Storable for RaffleEntryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.raffle_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.participant.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (raffle_id, participant) = bytes.split_at(8);
+        RaffleEntryKey {
+            raffle_id: u64::from_be_bytes(raffle_id.try_into().unwrap()),
+            participant: Principal::from_slice(participant),
+        }
+    }
+}
+
+impl BoundedStorable for RaffleEntryKey {
+    const MAX_SIZE: u32 = 37;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RAFFLE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(129))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static RAFFLES_STORAGE: RefCell<StableBTreeMap<u64, Raffle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(130)))
+    ));
+
+    static RAFFLE_ENTRIES_STORAGE: RefCell<StableBTreeMap<RaffleEntryKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(131)))
+    ));
+}
+
+fn get_raffle_record(raffle_id: u64) -> Result<Raffle, String> {
+    RAFFLES_STORAGE
+        .with(|storage| storage.borrow().get(&raffle_id))
+        .ok_or("Raffle not found".to_string())
+}
+
+fn save_raffle(raffle: Raffle) {
+    RAFFLES_STORAGE.with(|storage| storage.borrow_mut().insert(raffle.id, raffle));
+}
+
+// Entries in a raffle, ordered by participant principal bytes
+fn raffle_entries(raffle_id: u64) -> Vec<RaffleEntry> {
+    RAFFLE_ENTRIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(
+                RaffleEntryKey {
+                    raffle_id,
+                    participant: Principal::management_canister(),
+                }..,
+            )
+            .take_while(|(key, _)| key.raffle_id == raffle_id)
+            .map(|(key, entries)| RaffleEntry {
+                participant: key.participant,
+                entries,
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Commits to the exact entry list the winners were drawn from
+fn entries_hash(entries: &[RaffleEntry]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        hasher.update(entry.participant.as_slice());
+        hasher.update(entry.entries.to_be_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+// The k-th pseudo-random number derived from the seed: the first 8 bytes, big-endian, of
+// sha256(seed || k)
+fn random_u64(seed: &[u8], k: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(k.to_be_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// Draws up to `winner_count` distinct participants, each with odds proportional to their
+// remaining entries. Draw k takes random_u64(seed, k) modulo the entries left and walks the
+// list in order.
+fn pick_winners(seed: &[u8], mut entries: Vec<RaffleEntry>, winner_count: u64) -> Vec<Principal> {
+    let mut remaining: u64 = entries.iter().map(|entry| entry.entries).sum();
+    let mut winners = Vec::new();
+
+    for k in 0..winner_count {
+        if remaining == 0 {
+            break;
+        }
+        let mut ticket = random_u64(seed, k) % remaining;
+        let index = entries
+            .iter()
+            .position(|entry| {
+                if ticket < entry.entries {
+                    true
+                } else {
+                    ticket -= entry.entries;
+                    false
+                }
+            })
+            .unwrap();
+        let winner = entries.remove(index);
+        remaining -= winner.entries;
+        winners.push(winner.participant);
+    }
+    winners
+}
+
+// Gives whoever paid for a completed order entries in every raffle running now
+pub(crate) fn record_raffle_entries(order: &Order) {
+    let now = ic_cdk::api::time();
+    let running: Vec<Raffle> = RAFFLES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, raffle)| raffle)
+            .filter(|raffle| {
+                raffle.status == RaffleStatus::Open
+                    && raffle.starts_at <= now
+                    && now < raffle.ends_at
+            })
+            .collect()
+    });
+
+    for mut raffle in running {
+        let key = RaffleEntryKey {
+            raffle_id: raffle.id,
+            participant: order.payer(),
+        };
+        RAFFLE_ENTRIES_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let entries = storage.get(&key).unwrap_or(0);
+            storage.insert(key, entries.saturating_add(raffle.entries_per_order));
+        });
+        raffle.total_entries = raffle
+            .total_entries
+            .saturating_add(raffle.entries_per_order);
+        save_raffle(raffle);
+    }
+}
+
+async fn draw_raffle(mut raffle: Raffle) {
+    // Claim the raffle so an overlapping run cannot draw it twice
+    raffle.status = RaffleStatus::Drawing;
+    save_raffle(raffle.clone());
+
+    let seed = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((seed,)) => seed,
+        Err(_) => {
+            raffle.status = RaffleStatus::Open;
+            save_raffle(raffle);
+            return;
+        }
+    };
+
+    let entries = raffle_entries(raffle.id);
+    let entries_digest = entries_hash(&entries);
+    let winners = pick_winners(&seed, entries, raffle.winner_count);
+    let winner_list: Vec<String> = winners.iter().map(|winner| winner.to_text()).collect();
+
+    raffle.draw_event_id = Some(log_event(
+        "raffle_draw",
+        format!(
+            "raffle={} seed={} entries_sha256={} total_entries={} winners=[{}]",
+            raffle.id,
+            hex(&seed),
+            hex(&entries_digest),
+            raffle.total_entries,
+            winner_list.join(",")
+        ),
+    ));
+    for winner in &winners {
+        grant_raffle_points(*winner, raffle.id, raffle.prize_points);
+        notify(
+            *winner,
+            format!(
+                "You won {} points in the \"{}\" raffle",
+                raffle.prize_points, raffle.name
+            ),
+        );
+    }
+    raffle.winners = winners;
+    raffle.status = RaffleStatus::Drawn;
+    save_raffle(raffle);
+}
+
+// Draws every raffle whose entry period has ended
+pub(crate) async fn draw_due_raffles() {
+    let now = ic_cdk::api::time();
+    let due: Vec<Raffle> = RAFFLES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, raffle)| raffle)
+            .filter(|raffle| raffle.status == RaffleStatus::Open && raffle.ends_at <= now)
+            .collect()
+    });
+
+    for raffle in due {
+        draw_raffle(raffle).await;
+    }
+}
+
+// Function for an administrator to announce a raffle
+#[ic_cdk::update]
+fn create_raffle(payload: RafflePayload) -> Result<Raffle, String> {
+    if !is_admin() {
+        return Err("Only an administrator can run raffles".to_string());
+    }
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err("Invalid raffle name".to_string());
+    }
+    if payload.ends_at <= payload.starts_at || payload.ends_at <= ic_cdk::api::time() {
+        return Err("Invalid raffle period".to_string());
+    }
+    if payload.entries_per_order == 0 {
+        return Err("Orders must earn at least one entry".to_string());
+    }
+    if payload.winner_count == 0 || payload.winner_count > MAX_WINNERS {
+        return Err("Invalid number of winners".to_string());
+    }
+    if payload.prize_points == 0 {
+        return Err("Prize must be greater than zero".to_string());
+    }
+
+    let raffle = Raffle {
+        id: next_id(&RAFFLE_ID_COUNTER),
+        name: payload.name,
+        starts_at: payload.starts_at,
+        ends_at: payload.ends_at,
+        entries_per_order: payload.entries_per_order,
+        winner_count: payload.winner_count,
+        prize_points: payload.prize_points,
+        status: RaffleStatus::Open,
+        total_entries: 0,
+        winners: Vec::new(),
+        draw_event_id: None,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_raffle(raffle.clone());
+    Ok(raffle)
+}
+
+// Function for an administrator to call off a raffle before it is drawn
+#[ic_cdk::update]
+fn cancel_raffle(raffle_id: u64) -> Result<Raffle, String> {
+    if !is_admin() {
+        return Err("Only an administrator can run raffles".to_string());
+    }
+    let mut raffle = get_raffle_record(raffle_id)?;
+    if raffle.status != RaffleStatus::Open {
+        return Err("Raffle can no longer be cancelled".to_string());
+    }
+
+    raffle.status = RaffleStatus::Cancelled;
+    save_raffle(raffle.clone());
+    Ok(raffle)
+}
+
+#[ic_cdk::query]
+fn get_raffle(raffle_id: u64) -> Result<Raffle, String> {
+    get_raffle_record(raffle_id)
+}
+
+// Raffles, newest first
+#[ic_cdk::query]
+fn list_raffles() -> Vec<Raffle> {
+    RAFFLES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, raffle)| raffle)
+            .collect()
+    })
+}
+
+// Every participant's entries in the order the draw walks them, so anyone can check the
+// logged entries hash and replay the draw from the logged seed
+#[ic_cdk::query]
+fn get_raffle_entries(raffle_id: u64) -> Result<Vec<RaffleEntry>, String> {
+    get_raffle_record(raffle_id)?;
+    Ok(raffle_entries(raffle_id))
+}
+
+#[ic_cdk::query]
+fn get_my_raffle_entries(raffle_id: u64) -> u64 {
+    RAFFLE_ENTRIES_STORAGE
+        .with(|storage| {
+            storage.borrow().get(&RaffleEntryKey {
+                raffle_id,
+                participant: caller_account(),
+            })
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,178 @@
+use crate::devices::caller_account;
+use crate::ledger::Asset;
+use crate::orders::Order;
+use crate::taxes::{order_taxes, tax_total, TaxLine};
+use crate::{is_admin, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// PaymentRef Struct
+// The ledger payment that funded an order
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct PaymentRef {
+    checkout_id: u64,
+    block_index: u64,
+}
+
+// ReceiptItem Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ReceiptItem {
+    listing_id: Option<u64>,
+    description: String,
+    quantity: u64,
+    unit_price: u64,
+    line_total: u64,
+}
+
+// Receipt Struct
+// Issued once when an order settles and never changed afterwards
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Receipt {
+    order_id: u64,
+    farmer: Principal,
+    consumer: Principal,
+    // Set when someone other than the consumer paid
+    payer: Option<Principal>,
+    items: Vec<ReceiptItem>,
+    subtotal: u64,
+    discount: u64,
+    // Delivery charge passed on to the shipping partner
+    shipping_fee: u64,
+    taxes: Option<Vec<TaxLine>>,
+    total: u64,
+    // Asset the amounts are in; None for the default ledger
+    currency: Option<Asset>,
+    // Checkout and ledger block of the payment, for orders paid through a checkout
+    checkout_id: Option<u64>,
+    payment_block: Option<u64>,
+    ordered_at: u64,
+    settled_at: u64,
+}
+
+// Storable and BoundedStorable implementations for PaymentRef
+impl Storable for PaymentRef {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PaymentRef {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for Receipt
+impl Storable for Receipt {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Receipt {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by order ID
+    static RECEIPTS_STORAGE: RefCell<StableBTreeMap<u64, Receipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(195)))
+    ));
+
+    // Order ID to the payment that funded it, until its receipt is issued
+    static PAYMENT_REFS_STORAGE: RefCell<StableBTreeMap<u64, PaymentRef, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(196)))
+    ));
+}
+
+// Remembers the ledger payment behind an order for its receipt
+pub(crate) fn record_payment(order_id: u64, checkout_id: u64, block_index: u64) {
+    PAYMENT_REFS_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(
+            order_id,
+            PaymentRef {
+                checkout_id,
+                block_index,
+            },
+        )
+    });
+}
+
+// Issues the receipt of a settled order; an order that already has one keeps it
+pub(crate) fn issue_receipt(order: &Order) {
+    if RECEIPTS_STORAGE.with(|storage| storage.borrow().contains_key(&order.id)) {
+        return;
+    }
+
+    let description = order
+        .product_id
+        .and_then(|id| FARMERS_STORAGE.with(|storage| storage.borrow().get(&id)))
+        .map_or_else(|| format!("Order #{}", order.id), |farmer| farmer.name);
+    let subtotal = order.quantity.saturating_mul(order.unit_price);
+    let shipping_fee = order.shipping.as_ref().map_or(0, |line| line.cost);
+    let taxes = order_taxes(order.id);
+    let tax = taxes.as_deref().map_or(0, tax_total);
+    let payment = PAYMENT_REFS_STORAGE.with(|storage| storage.borrow_mut().remove(&order.id));
+
+    let receipt = Receipt {
+        order_id: order.id,
+        farmer: order.farmer,
+        consumer: order.consumer,
+        payer: order.payer,
+        items: vec![ReceiptItem {
+            listing_id: order.product_id,
+            description,
+            quantity: order.quantity,
+            unit_price: order.unit_price,
+            line_total: subtotal,
+        }],
+        subtotal,
+        discount: subtotal
+            .saturating_add(shipping_fee)
+            .saturating_add(tax)
+            .saturating_sub(order.total),
+        shipping_fee,
+        taxes,
+        total: order.total,
+        currency: order.currency,
+        checkout_id: payment.as_ref().map(|payment| payment.checkout_id),
+        payment_block: payment.map(|payment| payment.block_index),
+        ordered_at: order.created_at,
+        settled_at: ic_cdk::api::time(),
+    };
+    RECEIPTS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, receipt));
+}
+
+// The receipt of an order, if the caller is a party to it or an administrator
+pub(crate) fn visible_receipt(order_id: u64) -> Result<Receipt, String> {
+    let receipt = RECEIPTS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("Receipt not found".to_string())?;
+    let caller = caller_account();
+    if caller != receipt.farmer
+        && caller != receipt.consumer
+        && Some(caller) != receipt.payer
+        && !is_admin()
+    {
+        return Err("Only the parties to an order can view its receipt".to_string());
+    }
+    Ok(receipt)
+}
+
+// Function for a party to a settled order to fetch its receipt
+#[ic_cdk::query]
+fn get_receipt(order_id: u64) -> Result<Receipt, String> {
+    visible_receipt(order_id)
+}
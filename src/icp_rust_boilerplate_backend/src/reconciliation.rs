@@ -0,0 +1,184 @@
+use crate::bid_deposits::expected_deposit_escrow;
+use crate::campaigns::expected_campaign_escrow;
+use crate::events::log_event;
+use crate::ledger::{asset_subaccount_balance, Asset};
+use crate::order_escrow::expected_order_escrow;
+use crate::payout_schedules::expected_held_escrow;
+use crate::pools::expected_pool_escrow;
+use crate::retry_queue::expected_queued_escrow;
+use crate::reviews::expected_review_escrow;
+use crate::savings::expected_savings_escrow;
+use crate::sealed_auctions::expected_sealed_escrow;
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Ledger calls one run makes at most; the rest are reported as skipped
+const MAX_CHECKS: usize = 100;
+
+// EscrowExpectation Struct
+// What the marketplace's own records say one escrow subaccount should hold
+pub(crate) struct EscrowExpectation {
+    pub(crate) asset: Asset,
+    pub(crate) source: String,
+    pub(crate) subaccount: Vec<u8>,
+    pub(crate) expected: u64,
+}
+
+// EscrowDiscrepancy Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct EscrowDiscrepancy {
+    // Ledger of the subaccount; None for the default ledger
+    asset: Option<Asset>,
+    subaccount: Vec<u8>,
+    // The records that expect funds in the subaccount, e.g. "Bid #4 deposit"
+    sources: Vec<String>,
+    expected: u64,
+    actual: u64,
+}
+
+// AssetReconciliation Struct
+// What one asset's checked subaccounts should hold against what they do
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AssetReconciliation {
+    asset: Asset,
+    expected: u64,
+    actual: u64,
+}
+
+// ReconciliationReport Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct ReconciliationReport {
+    checked: u64,
+    skipped: u64,
+    // Subaccounts whose balance the ledger did not return
+    failed: u64,
+    // Totals on the default ledger
+    expected_total: u64,
+    actual_total: u64,
+    // Totals per asset, the default ledger included
+    asset_totals: Option<Vec<AssetReconciliation>>,
+    discrepancies: Vec<EscrowDiscrepancy>,
+    run_at: u64,
+}
+
+// Storable implementation for ReconciliationReport
+impl Storable for ReconciliationReport {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static LAST_RECONCILIATION: RefCell<Cell<ReconciliationReport, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(194))), ReconciliationReport::default())
+            .expect("Cannot create the reconciliation report")
+    );
+}
+
+// Every subaccount the records expect to hold funds, by ledger, with the records behind it
+fn expected_balances() -> BTreeMap<(Asset, Vec<u8>), (Vec<String>, u64)> {
+    let mut balances: BTreeMap<(Asset, Vec<u8>), (Vec<String>, u64)> = BTreeMap::new();
+    let expectations = expected_deposit_escrow()
+        .into_iter()
+        .chain(expected_sealed_escrow())
+        .chain(expected_savings_escrow())
+        .chain(expected_review_escrow())
+        .chain(expected_order_escrow())
+        .chain(expected_pool_escrow())
+        .chain(expected_campaign_escrow())
+        .chain(expected_held_escrow())
+        .chain(expected_queued_escrow());
+    for expectation in expectations {
+        let entry = balances
+            .entry((expectation.asset, expectation.subaccount))
+            .or_default();
+        entry.0.push(expectation.source);
+        entry.1 = entry.1.saturating_add(expectation.expected);
+    }
+    balances
+}
+
+// Function for an administrator to compare escrow records with the ledgers. It covers bid and
+// sealed-bid deposits, savings group pots, the review rewards pool, orders paid in other
+// assets and payouts waiting in the retry queue, each on its asset's ledger. A deposit whose transfer is still in flight can show up as a discrepancy; a
+// second run tells it apart from a real one.
+#[ic_cdk::update]
+async fn reconcile_escrow() -> Result<ReconciliationReport, String> {
+    if !is_admin() {
+        return Err("Only an administrator can reconcile escrow".to_string());
+    }
+
+    let balances = expected_balances();
+    let mut report = ReconciliationReport {
+        skipped: balances.len().saturating_sub(MAX_CHECKS) as u64,
+        ..Default::default()
+    };
+    let mut asset_totals: Vec<AssetReconciliation> = Vec::new();
+    for ((asset, subaccount), (sources, expected)) in balances.into_iter().take(MAX_CHECKS) {
+        let actual = match asset_subaccount_balance(asset, subaccount.clone()).await {
+            Ok(actual) => actual,
+            Err(_) => {
+                report.failed += 1;
+                continue;
+            }
+        };
+        report.checked += 1;
+        if asset == Asset::Icp {
+            report.expected_total = report.expected_total.saturating_add(expected);
+            report.actual_total = report.actual_total.saturating_add(actual);
+        }
+        match asset_totals.iter_mut().find(|totals| totals.asset == asset) {
+            Some(totals) => {
+                totals.expected = totals.expected.saturating_add(expected);
+                totals.actual = totals.actual.saturating_add(actual);
+            }
+            None => asset_totals.push(AssetReconciliation {
+                asset,
+                expected,
+                actual,
+            }),
+        }
+        if actual != expected {
+            log_event(
+                "escrow_discrepancy",
+                format!(
+                    "{}: expected {} {:?}, found {}",
+                    sources.join(", "),
+                    expected,
+                    asset,
+                    actual
+                ),
+            );
+            report.discrepancies.push(EscrowDiscrepancy {
+                asset: Some(asset),
+                subaccount,
+                sources,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    report.asset_totals = Some(asset_totals);
+    report.run_at = ic_cdk::api::time();
+    LAST_RECONCILIATION
+        .with(|cell| cell.borrow_mut().set(report.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(report)
+}
+
+#[ic_cdk::query]
+fn get_last_reconciliation() -> Result<ReconciliationReport, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view reconciliation reports".to_string());
+    }
+    Ok(LAST_RECONCILIATION.with(|cell| cell.borrow().get().clone()))
+}
@@ -0,0 +1,275 @@
+use crate::archive::archived_orders;
+use crate::devices::caller_account;
+use crate::governance::is_governor;
+use crate::identity::can_earn_referral_rewards;
+use crate::loyalty::grant_referral_points;
+use crate::orders::{Order, OrderStatus, ORDERS_STORAGE};
+use crate::{CodeKey, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// ReferralRewards Struct
+// Loyalty points granted to each side once a referred user completes a first purchase
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ReferralRewards {
+    referrer_points: u64,
+    referee_points: u64,
+}
+
+impl Default for ReferralRewards {
+    fn default() -> Self {
+        ReferralRewards {
+            referrer_points: 100,
+            referee_points: 50,
+        }
+    }
+}
+
+// Referral Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Referral {
+    referrer: Principal,
+    referee: Principal,
+    code: String,
+    rewarded_order_id: Option<u64>,
+    created_at: u64,
+}
+
+// ReferralStats Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ReferralStats {
+    code: Option<String>,
+    referred: u64,
+    converted: u64,
+}
+
+// Storable implementation for ReferralRewards
+impl Storable for ReferralRewards {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Storable and BoundedStorable implementations for Referral
+impl Storable for Referral {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Referral {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static REFERRAL_REWARDS: RefCell<Cell<ReferralRewards, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))), ReferralRewards::default())
+            .expect("Cannot create the referral rewards")
+    );
+
+    static REFERRAL_CODES: RefCell<StableBTreeMap<CodeKey, PrincipalKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static REFERRER_CODES: RefCell<StableBTreeMap<PrincipalKey, CodeKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    // Keyed by the referee, who can only ever be referred once
+    static REFERRALS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Referral, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+// Derives an 8-character code from the principal, salting until it is unused
+fn generate_code(owner: &Principal) -> String {
+    let mut salt: u32 = 0;
+    loop {
+        let mut hasher = Sha256::new();
+        hasher.update(owner.as_slice());
+        hasher.update(salt.to_be_bytes());
+        let code: String = hasher.finalize()[..4]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        if !REFERRAL_CODES.with(|storage| storage.borrow().contains_key(&CodeKey(code.clone()))) {
+            return code;
+        }
+        salt += 1;
+    }
+}
+
+fn referral_code_of(owner: &Principal) -> Option<String> {
+    REFERRER_CODES
+        .with(|storage| storage.borrow().get(&PrincipalKey(*owner)))
+        .map(|code| code.0)
+}
+
+// Function for any user to get their referral code, creating it on first use
+#[ic_cdk::update]
+fn create_referral_code() -> Result<String, String> {
+    let owner = caller_account();
+    if owner == Principal::anonymous() {
+        return Err("Anonymous users cannot refer others".to_string());
+    }
+    if let Some(code) = referral_code_of(&owner) {
+        return Ok(code);
+    }
+
+    let code = generate_code(&owner);
+    REFERRAL_CODES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(CodeKey(code.clone()), PrincipalKey(owner))
+    });
+    REFERRER_CODES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(owner), CodeKey(code.clone()))
+    });
+    Ok(code)
+}
+
+// Function for a new user to record who referred them, before their first purchase
+#[ic_cdk::update]
+fn apply_referral_code(code: String) -> Result<(), String> {
+    let referee = caller_account();
+    let code = code.trim().to_uppercase();
+
+    if referee == Principal::anonymous() {
+        return Err("Anonymous users cannot be referred".to_string());
+    }
+    let referrer = REFERRAL_CODES
+        .with(|storage| storage.borrow().get(&CodeKey(code.clone())))
+        .map(|key| key.0)
+        .ok_or("Referral code not found".to_string())?;
+
+    if referrer == referee {
+        return Err("You cannot use your own referral code".to_string());
+    }
+    if REFERRALS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(referee))) {
+        return Err("A referral code was already applied".to_string());
+    }
+    // Two users referring each other would farm rewards in both directions
+    let referrer_was_referred_by_referee = REFERRALS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(referrer)))
+        .map_or(false, |referral| referral.referrer == referee);
+    if referrer_was_referred_by_referee {
+        return Err("You cannot use your own referral code".to_string());
+    }
+    let has_purchased = ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, order)| order.payer() == referee && order.status == OrderStatus::Completed)
+    }) || archived_orders()
+        .iter()
+        .any(|order| order.payer == referee && order.status == OrderStatus::Completed);
+    if has_purchased {
+        return Err("Referral codes only apply before a first purchase".to_string());
+    }
+
+    let referral = Referral {
+        referrer,
+        referee,
+        code,
+        rewarded_order_id: None,
+        created_at: ic_cdk::api::time(),
+    };
+    REFERRALS_STORAGE.with(|storage| storage.borrow_mut().insert(PrincipalKey(referee), referral));
+    Ok(())
+}
+
+// Rewards both sides of a referral the first time the referee completes a purchase
+pub(crate) fn reward_referral(order: &Order) {
+    let mut referral = match REFERRALS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(order.payer())))
+    {
+        Some(referral) if referral.rewarded_order_id.is_none() => referral,
+        _ => return,
+    };
+    // Left unrewarded until both sides pass the sybil checks, so a later purchase can pay out
+    if !can_earn_referral_rewards(&referral.referrer)
+        || !can_earn_referral_rewards(&referral.referee)
+    {
+        return;
+    }
+
+    // Buying from the referrer would let them reward themselves
+    if referral.referrer != order.farmer {
+        let rewards = REFERRAL_REWARDS.with(|cell| cell.borrow().get().clone());
+        grant_referral_points(referral.referrer, order.id, rewards.referrer_points);
+        grant_referral_points(referral.referee, order.id, rewards.referee_points);
+    }
+
+    referral.rewarded_order_id = Some(order.id);
+    REFERRALS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(order.payer()), referral)
+    });
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_referral_rewards(
+    referrer_points: u64,
+    referee_points: u64,
+) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set referral rewards".to_string());
+    }
+
+    REFERRAL_REWARDS
+        .with(|cell| {
+            cell.borrow_mut().set(ReferralRewards {
+                referrer_points,
+                referee_points,
+            })
+        })
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_referral_rewards() -> ReferralRewards {
+    REFERRAL_REWARDS.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_referral_stats(referrer: Principal) -> ReferralStats {
+    let (referred, converted) = REFERRALS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, referral)| referral)
+            .filter(|referral| referral.referrer == referrer)
+            .fold((0, 0), |(referred, converted), referral| {
+                (
+                    referred + 1,
+                    converted + referral.rewarded_order_id.is_some() as u64,
+                )
+            })
+    });
+
+    ReferralStats {
+        code: referral_code_of(&referrer),
+        referred,
+        converted,
+    }
+}
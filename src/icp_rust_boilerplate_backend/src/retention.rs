@@ -0,0 +1,203 @@
+use crate::archive::archived_at;
+use crate::events::log_event;
+use crate::notifications::{read_notifications_before, remove_notifications};
+use crate::orders::{get_order_record, OrderStatus};
+use crate::tracking::{remove_track, tracked_order_ids};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Records removed per target on each maintenance run
+const MAX_PURGE_BATCH: usize = 500;
+const MAX_REPORT_SAMPLE: usize = 20;
+
+// RetentionTarget Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RetentionTarget {
+    // Read notifications, by when they were sent
+    ReadNotifications,
+    // Delivery checkpoints of orders, by when the order closed
+    DeliveryTracks,
+}
+
+// RetentionRule Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RetentionRule {
+    target: RetentionTarget,
+    max_age_days: u64,
+}
+
+// RetentionPolicy Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+}
+
+// RetentionReport Struct
+// What a rule would delete if it ran now
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RetentionReport {
+    target: RetentionTarget,
+    max_age_days: u64,
+    matching: u64,
+    // The first few matching notification or order IDs
+    sample_ids: Vec<u64>,
+}
+
+// Storable implementation for RetentionPolicy
+impl Storable for RetentionPolicy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static RETENTION_POLICY: RefCell<Cell<RetentionPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(170))), RetentionPolicy::default())
+            .expect("Cannot create the retention policy")
+    );
+}
+
+fn retention_policy() -> RetentionPolicy {
+    RETENTION_POLICY.with(|cell| cell.borrow().get().clone())
+}
+
+fn save_policy(policy: RetentionPolicy) -> Result<(), String> {
+    RETENTION_POLICY
+        .with(|cell| cell.borrow_mut().set(policy))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// When an order reached a terminal status: its last update while it is live, or when it
+// moved to the archive
+fn order_closed_at(order_id: u64) -> Option<u64> {
+    match get_order_record(order_id) {
+        Ok(order) => matches!(
+            order.status,
+            OrderStatus::Completed | OrderStatus::Refunded | OrderStatus::Cancelled
+        )
+        .then_some(order.updated_at),
+        Err(_) => archived_at(order_id),
+    }
+}
+
+// IDs of the records a rule covers at this moment
+fn matching_ids(rule: &RetentionRule, now: u64) -> Vec<u64> {
+    let cutoff = now.saturating_sub(rule.max_age_days.saturating_mul(DAY_NANOS));
+    match rule.target {
+        RetentionTarget::ReadNotifications => read_notifications_before(cutoff),
+        RetentionTarget::DeliveryTracks => tracked_order_ids()
+            .into_iter()
+            .filter(|order_id| matches!(order_closed_at(*order_id), Some(at) if at < cutoff))
+            .collect(),
+    }
+}
+
+fn purge(target: RetentionTarget, ids: &[u64]) {
+    match target {
+        RetentionTarget::ReadNotifications => remove_notifications(ids),
+        RetentionTarget::DeliveryTracks => {
+            for order_id in ids {
+                remove_track(*order_id);
+            }
+        }
+    }
+}
+
+// Deletes a batch of expired records under each rule, logging what was removed
+pub(crate) fn apply_retention_rules() {
+    let now = ic_cdk::api::time();
+    for rule in retention_policy().rules {
+        let mut ids = matching_ids(&rule, now);
+        ids.truncate(MAX_PURGE_BATCH);
+        if ids.is_empty() {
+            continue;
+        }
+        purge(rule.target, &ids);
+        log_event(
+            "retention_purge",
+            format!(
+                "{:?} older than {} days: {} removed",
+                rule.target,
+                rule.max_age_days,
+                ids.len()
+            ),
+        );
+    }
+}
+
+// Function for an administrator to keep a kind of record only for so many days, replacing
+// any earlier rule for it
+#[ic_cdk::update]
+fn set_retention_rule(
+    target: RetentionTarget,
+    max_age_days: u64,
+) -> Result<RetentionPolicy, String> {
+    if !is_admin() {
+        return Err("Only an administrator can set retention rules".to_string());
+    }
+    if max_age_days == 0 {
+        return Err("Keep records for at least a day".to_string());
+    }
+
+    let mut policy = retention_policy();
+    policy.rules.retain(|rule| rule.target != target);
+    policy.rules.push(RetentionRule {
+        target,
+        max_age_days,
+    });
+    save_policy(policy.clone())?;
+    Ok(policy)
+}
+
+#[ic_cdk::update]
+fn remove_retention_rule(target: RetentionTarget) -> Result<RetentionPolicy, String> {
+    if !is_admin() {
+        return Err("Only an administrator can remove retention rules".to_string());
+    }
+
+    let mut policy = retention_policy();
+    let before = policy.rules.len();
+    policy.rules.retain(|rule| rule.target != target);
+    if policy.rules.len() == before {
+        return Err("No rule for that target".to_string());
+    }
+    save_policy(policy.clone())?;
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_retention_policy() -> RetentionPolicy {
+    retention_policy()
+}
+
+// Dry run: what each rule would delete if it ran now, without deleting anything
+#[ic_cdk::query]
+fn preview_retention() -> Result<Vec<RetentionReport>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can preview retention".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    Ok(retention_policy()
+        .rules
+        .into_iter()
+        .map(|rule| {
+            let ids = matching_ids(&rule, now);
+            RetentionReport {
+                target: rule.target,
+                max_age_days: rule.max_age_days,
+                matching: ids.len() as u64,
+                sample_ids: ids.into_iter().take(MAX_REPORT_SAMPLE).collect(),
+            }
+        })
+        .collect())
+}
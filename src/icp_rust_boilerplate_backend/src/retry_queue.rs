@@ -0,0 +1,455 @@
+use crate::events::log_event;
+use crate::ledger::{
+    asset_subaccount_balance, ledger_fee, release_asset_escrow_to_account, Account, Asset,
+    TransferDedup,
+};
+use crate::payout_schedules::credit_held_balance;
+use crate::reconciliation::EscrowExpectation;
+use crate::sales_history::record_payout_block;
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_ATTEMPTS: u32 = 8;
+const RETRY_BASE_NANOS: u64 = 60 * 1_000_000_000;
+// A call is pushed this far out while in flight so overlapping runs skip it
+const IN_FLIGHT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+// Calls that went through are kept this long for the record
+const SUCCEEDED_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_PROCESS_BATCH: usize = 20;
+const MAX_ERROR_LEN: usize = 200;
+// Ledgers deduplicate transfers created within the last day and reject older ones as too old
+const LEDGER_DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// OutboundCall Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum OutboundCall {
+    // Pays `amount` out of one of this canister's escrow subaccounts, less the ledger fee
+    EscrowPayout {
+        subaccount: Vec<u8>,
+        to: Principal,
+        amount: u64,
+        // Ledger the subaccount is on; None for the default ledger
+        asset: Option<Asset>,
+    },
+    // Same as `EscrowPayout`, to an account that may have a subaccount
+    AccountPayout {
+        subaccount: Vec<u8>,
+        to: Account,
+        amount: u64,
+        asset: Option<Asset>,
+    },
+    // Pays whatever one of this canister's escrow subaccounts holds, less the ledger fee, e.g. a
+    // payment whose outcome was lost when its callback trapped. Nothing is paid if nothing arrived.
+    BalanceRefund {
+        subaccount: Vec<u8>,
+        to: Principal,
+        asset: Option<Asset>,
+    },
+    // One-way call with candid-encoded arguments, e.g. a domain event for a subscriber
+    Notify {
+        canister: Principal,
+        method: String,
+        args: Vec<u8>,
+    },
+}
+
+// CallEffect Enum
+// What to record once a queued call has gone through
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum CallEffect {
+    // Notes the payout's ledger block on the order's sale
+    SalePayout { order_id: u64 },
+    // Credits what arrived in the balances subaccount to the farmer whose sale it holds
+    HeldSale { order_id: u64, farmer: Principal },
+}
+
+// CallStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CallStatus {
+    Pending,
+    Succeeded,
+    // Gave up after the last attempt; waits for an administrator to requeue it
+    DeadLetter,
+}
+
+// QueuedCall Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct QueuedCall {
+    id: u64,
+    call: OutboundCall,
+    // What the call is for, e.g. "Bid #4 deposit Refunded"
+    purpose: String,
+    status: CallStatus,
+    attempts: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    block_index: Option<u64>,
+    on_success: Option<CallEffect>,
+    // Sent with every attempt's transfer, with the call ID as memo, so the ledger pays it once.
+    // None on calls queued before it was recorded, which use `created_at`.
+    created_at_time: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Storable and BoundedStorable implementations for QueuedCall
+impl Storable for QueuedCall {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for QueuedCall {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static QUEUED_CALL_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(188))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static RETRY_QUEUE_STORAGE: RefCell<StableBTreeMap<u64, QueuedCall, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(189)))
+    ));
+}
+
+fn queued_call(call_id: u64) -> Option<QueuedCall> {
+    RETRY_QUEUE_STORAGE.with(|storage| storage.borrow().get(&call_id))
+}
+
+fn save_call(mut call: QueuedCall) {
+    call.updated_at = ic_cdk::api::time();
+    RETRY_QUEUE_STORAGE.with(|storage| storage.borrow_mut().insert(call.id, call));
+}
+
+fn new_call(call: OutboundCall, purpose: String, status: CallStatus) -> QueuedCall {
+    let now = ic_cdk::api::time();
+    QueuedCall {
+        id: next_id(&QUEUED_CALL_ID_COUNTER),
+        call,
+        purpose,
+        status,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        block_index: None,
+        on_success: None,
+        created_at_time: Some(now),
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+// Queues a call for the maintenance timer, which retries it with exponential backoff
+pub(crate) fn enqueue(call: OutboundCall, purpose: String) -> u64 {
+    let call = new_call(call, purpose, CallStatus::Pending);
+    let id = call.id;
+    save_call(call);
+    id
+}
+
+// Same as `enqueue`, with something to record once the call has gone through
+pub(crate) fn enqueue_with_effect(call: OutboundCall, purpose: String, effect: CallEffect) -> u64 {
+    let mut call = new_call(call, purpose, CallStatus::Pending);
+    call.on_success = Some(effect);
+    let id = call.id;
+    save_call(call);
+    id
+}
+
+fn apply_effect(effect: &CallEffect, block_index: u64, delivered: u64) {
+    match effect {
+        CallEffect::SalePayout { order_id } => record_payout_block(*order_id, block_index),
+        CallEffect::HeldSale { order_id, farmer } => {
+            credit_held_balance(farmer, delivered);
+            log_event(
+                "sale_held",
+                format!("Order #{} credited {} to {}", order_id, delivered, farmer),
+            );
+        }
+    }
+}
+
+// Records a call another retry loop gave up on, so an administrator can requeue it
+pub(crate) fn dead_letter(call: OutboundCall, purpose: String, error: String) {
+    let mut call = new_call(call, purpose, CallStatus::DeadLetter);
+    call.last_error = Some(error);
+    log_event(
+        "call_dead_lettered",
+        format!("#{} {}", call.id, call.purpose),
+    );
+    save_call(call);
+}
+
+fn transfer_dedup(queued: &QueuedCall) -> TransferDedup {
+    TransferDedup {
+        created_at: queued.created_at_time.unwrap_or(queued.created_at),
+        memo: queued.id,
+    }
+}
+
+// Gives a call a new transfer time once the ledger would reject its first one as too old.
+// Until then a retry keeps it, so a transfer that went through on an attempt whose outcome was
+// unknown is not paid again.
+fn refresh_transfer_time(queued: &mut QueuedCall, now: u64) {
+    let created_at = transfer_dedup(queued).created_at;
+    if now.saturating_sub(created_at) >= LEDGER_DEDUP_WINDOW_NANOS {
+        queued.created_at_time = Some(now);
+    }
+}
+
+// Makes one call; a transfer returns its block index and the amount that arrived
+async fn execute(queued: &QueuedCall) -> Result<Option<(u64, u64)>, String> {
+    let dedup = Some(transfer_dedup(queued));
+    match &queued.call {
+        OutboundCall::EscrowPayout {
+            subaccount,
+            to,
+            amount,
+            asset,
+        } => release_asset_escrow_to_account(
+            asset.unwrap_or(Asset::Icp),
+            subaccount.clone(),
+            (*to).into(),
+            *amount,
+            dedup,
+        )
+        .await
+        .map(Some),
+        OutboundCall::AccountPayout {
+            subaccount,
+            to,
+            amount,
+            asset,
+        } => release_asset_escrow_to_account(
+            asset.unwrap_or(Asset::Icp),
+            subaccount.clone(),
+            to.clone(),
+            *amount,
+            dedup,
+        )
+        .await
+        .map(Some),
+        OutboundCall::BalanceRefund {
+            subaccount,
+            to,
+            asset,
+        } => {
+            let asset = asset.unwrap_or(Asset::Icp);
+            let balance = asset_subaccount_balance(asset, subaccount.clone()).await?;
+            if balance <= ledger_fee(asset).await? {
+                return Ok(None);
+            }
+            release_asset_escrow_to_account(asset, subaccount.clone(), (*to).into(), balance, dedup)
+                .await
+                .map(Some)
+        }
+        OutboundCall::Notify {
+            canister,
+            method,
+            args,
+        } => ic_cdk::api::call::notify_raw(*canister, method, args, 0)
+            .map(|_| None)
+            .map_err(|code| format!("Notify failed: {:?}", code)),
+    }
+}
+
+// Makes the calls that are due, oldest first, and drops old successful ones
+pub(crate) async fn process_retry_queue() {
+    let now = ic_cdk::api::time();
+    let cutoff = now.saturating_sub(SUCCEEDED_RETENTION_NANOS);
+    let finished: Vec<u64> = RETRY_QUEUE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, call)| call.status == CallStatus::Succeeded && call.updated_at < cutoff)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    RETRY_QUEUE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in finished {
+            storage.remove(&id);
+        }
+    });
+
+    let due: Vec<u64> = RETRY_QUEUE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, call)| call.status == CallStatus::Pending && call.next_attempt_at <= now)
+            .map(|(id, _)| id)
+            .take(MAX_PROCESS_BATCH)
+            .collect()
+    });
+
+    for call_id in due {
+        // Claim the call before awaiting so overlapping runs cannot make it twice
+        let mut queued = match queued_call(call_id) {
+            Some(queued) if queued.status == CallStatus::Pending => queued,
+            _ => continue,
+        };
+        queued.next_attempt_at = ic_cdk::api::time() + IN_FLIGHT_NANOS;
+        save_call(queued.clone());
+
+        let result = execute(&queued).await;
+        queued.attempts += 1;
+        match result {
+            Ok(transfer) => {
+                queued.status = CallStatus::Succeeded;
+                queued.block_index = transfer.map(|(block_index, _)| block_index);
+                queued.last_error = None;
+                if let (Some(effect), Some((block_index, delivered))) =
+                    (&queued.on_success, transfer)
+                {
+                    apply_effect(effect, block_index, delivered);
+                }
+            }
+            Err(e) => {
+                queued.last_error = Some(e.chars().take(MAX_ERROR_LEN).collect());
+                if queued.attempts >= MAX_ATTEMPTS {
+                    queued.status = CallStatus::DeadLetter;
+                    log_event(
+                        "call_dead_lettered",
+                        format!("#{} {}", queued.id, queued.purpose),
+                    );
+                } else {
+                    queued.next_attempt_at = ic_cdk::api::time()
+                        .saturating_add(RETRY_BASE_NANOS << queued.attempts.min(16));
+                }
+            }
+        }
+        save_call(queued);
+    }
+}
+
+// Escrow payouts not yet made, whose funds are still in their subaccounts, for escrow
+// reconciliation. Refunds from the main account are left out; it is not reconciled.
+pub(crate) fn expected_queued_escrow() -> Vec<EscrowExpectation> {
+    RETRY_QUEUE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, queued)| queued.status != CallStatus::Succeeded)
+            .filter_map(|(id, queued)| match queued.call {
+                OutboundCall::EscrowPayout {
+                    subaccount,
+                    amount,
+                    asset,
+                    ..
+                }
+                | OutboundCall::AccountPayout {
+                    subaccount,
+                    amount,
+                    asset,
+                    ..
+                } if subaccount.iter().any(|byte| *byte != 0) => Some(EscrowExpectation {
+                    asset: asset.unwrap_or(Asset::Icp),
+                    source: format!("Queued call #{}", id),
+                    subaccount,
+                    expected: amount,
+                }),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+// Function for an administrator to look at queued calls, optionally of one status
+#[ic_cdk::query]
+fn get_retry_queue(status: Option<CallStatus>) -> Result<Vec<QueuedCall>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view the retry queue".to_string());
+    }
+
+    Ok(RETRY_QUEUE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, call)| call)
+            .filter(|call| status.map_or(true, |status| call.status == status))
+            .collect()
+    }))
+}
+
+// Function for an administrator to give a dead-lettered call a fresh set of attempts
+#[ic_cdk::update]
+fn requeue_call(call_id: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can requeue calls".to_string());
+    }
+    let mut queued = queued_call(call_id).ok_or("Call not found".to_string())?;
+    if queued.status != CallStatus::DeadLetter {
+        return Err("Only dead-lettered calls can be requeued".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    queued.status = CallStatus::Pending;
+    queued.attempts = 0;
+    queued.next_attempt_at = now;
+    refresh_transfer_time(&mut queued, now);
+    log_event(
+        "call_requeued",
+        format!("#{} {}", queued.id, queued.purpose),
+    );
+    save_call(queued);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued_payout(id: u64, created_at_time: Option<u64>) -> QueuedCall {
+        QueuedCall {
+            id,
+            call: OutboundCall::EscrowPayout {
+                subaccount: vec![1u8; 32],
+                to: Principal::from_slice(&[1]),
+                amount: 100,
+                asset: None,
+            },
+            purpose: "Test payout".to_string(),
+            status: CallStatus::DeadLetter,
+            attempts: MAX_ATTEMPTS,
+            next_attempt_at: 0,
+            last_error: None,
+            block_index: None,
+            on_success: None,
+            created_at_time,
+            created_at: 5,
+            updated_at: 5,
+        }
+    }
+
+    #[test]
+    fn every_attempt_sends_the_same_transfer_time_and_memo() {
+        let dedup = transfer_dedup(&queued_payout(7, Some(10)));
+        assert_eq!((dedup.created_at, dedup.memo), (10, 7));
+
+        // Queued before the time was recorded
+        let dedup = transfer_dedup(&queued_payout(8, None));
+        assert_eq!((dedup.created_at, dedup.memo), (5, 8));
+    }
+
+    #[test]
+    fn a_requeued_call_keeps_its_transfer_time_within_the_ledger_window() {
+        let mut queued = queued_payout(9, Some(10));
+        refresh_transfer_time(&mut queued, 10 + LEDGER_DEDUP_WINDOW_NANOS - 1);
+        assert_eq!(queued.created_at_time, Some(10));
+
+        let now = 10 + LEDGER_DEDUP_WINDOW_NANOS;
+        refresh_transfer_time(&mut queued, now);
+        assert_eq!(queued.created_at_time, Some(now));
+    }
+}
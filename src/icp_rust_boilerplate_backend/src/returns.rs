@@ -0,0 +1,256 @@
+use crate::deliveries::{get_delivery_record, is_sha256_hex};
+use crate::devices::caller_account;
+use crate::disputes::open_dispute;
+use crate::inventory::release_stock;
+use crate::notifications::notify;
+use crate::order_escrow::refund_order_escrow;
+use crate::orders::{get_order_record, save_order, OrderStatus};
+use crate::supplies::release_supply_stock;
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REASON_LEN: usize = 500;
+const MAX_CARRIER_LEN: usize = 100;
+// Goods can be rejected up to two days after they were delivered
+const REJECTION_WINDOW_NANOS: u64 = 2 * 24 * 60 * 60 * 1_000_000_000;
+
+// ReturnStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReturnStatus {
+    Requested,
+    Accepted,
+    InTransit,
+    Refunded,
+    Disputed,
+}
+
+// ReturnRequest Struct
+// A consumer's rejection of delivered goods and the return delivery that follows
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ReturnRequest {
+    order_id: u64,
+    consumer: Principal,
+    reason: String,
+    evidence_hash: Option<String>,
+    status: ReturnStatus,
+    carrier: Option<String>,
+    shipped_at: Option<u64>,
+    received_at: Option<u64>,
+    dispute_id: Option<u64>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for ReturnRequest
+impl Storable for ReturnRequest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReturnRequest {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // At most one return per order, keyed by the order id
+    static RETURNS_STORAGE: RefCell<StableBTreeMap<u64, ReturnRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+}
+
+fn get_return_record(order_id: u64) -> Result<ReturnRequest, String> {
+    RETURNS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("Return not found".to_string())
+}
+
+fn save_return(request: ReturnRequest) {
+    RETURNS_STORAGE.with(|storage| storage.borrow_mut().insert(request.order_id, request));
+}
+
+// Loads a return the caller, as the order's farmer, may act on
+fn farmer_return(order_id: u64) -> Result<ReturnRequest, String> {
+    let order = get_order_record(order_id)?;
+    if order.farmer != caller_account() {
+        return Err("Only the farmer can handle this return".to_string());
+    }
+    get_return_record(order_id)
+}
+
+// Function for the consumer to reject goods on delivery, or within two days of it
+#[ic_cdk::update]
+fn reject_delivery(
+    order_id: u64,
+    reason: String,
+    evidence_hash: Option<String>,
+) -> Result<ReturnRequest, String> {
+    let mut order = get_order_record(order_id)?;
+    let consumer = caller_account();
+
+    if order.consumer != consumer {
+        return Err("Only the consumer can reject this delivery".to_string());
+    }
+    if order.status != OrderStatus::Shipped {
+        return Err("Only shipped, unconfirmed orders can be rejected".to_string());
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err("Invalid reason".to_string());
+    }
+    if evidence_hash
+        .as_ref()
+        .map_or(false, |hash| !is_sha256_hex(hash))
+    {
+        return Err("Evidence must be a hex SHA-256 digest".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    let delivered_at = get_delivery_record(order_id).and_then(|delivery| delivery.delivered_at);
+    if delivered_at.map_or(false, |delivered_at| {
+        delivered_at.saturating_add(REJECTION_WINDOW_NANOS) < now
+    }) {
+        return Err("The rejection window has passed".to_string());
+    }
+
+    let request = ReturnRequest {
+        order_id,
+        consumer,
+        reason,
+        evidence_hash: evidence_hash.map(|hash| hash.to_lowercase()),
+        status: ReturnStatus::Requested,
+        carrier: None,
+        shipped_at: None,
+        received_at: None,
+        dispute_id: None,
+        created_at: now,
+    };
+
+    // Holding the order in Returning keeps auto-release from completing it
+    order.status = OrderStatus::Returning;
+    save_order(order.clone());
+    save_return(request.clone());
+    notify(
+        order.farmer,
+        format!(
+            "The consumer rejected order #{}: accept the return or contest it",
+            order_id
+        ),
+    );
+    Ok(request)
+}
+
+// Function for the farmer to accept a rejection and have the goods sent back
+#[ic_cdk::update]
+fn accept_return(order_id: u64) -> Result<ReturnRequest, String> {
+    let mut request = farmer_return(order_id)?;
+
+    if request.status != ReturnStatus::Requested {
+        return Err("Return is not awaiting a decision".to_string());
+    }
+
+    request.status = ReturnStatus::Accepted;
+    save_return(request.clone());
+    notify(
+        request.consumer,
+        format!(
+            "Your return of order #{} was accepted; please send the goods back",
+            order_id
+        ),
+    );
+    Ok(request)
+}
+
+// Function for the consumer to record the return shipment
+#[ic_cdk::update]
+fn ship_return(order_id: u64, carrier: String) -> Result<ReturnRequest, String> {
+    let mut request = get_return_record(order_id)?;
+
+    if request.consumer != caller_account() {
+        return Err("Only the consumer can ship this return".to_string());
+    }
+    if request.status != ReturnStatus::Accepted {
+        return Err("Return has not been accepted".to_string());
+    }
+    if carrier.trim().is_empty() || carrier.len() > MAX_CARRIER_LEN {
+        return Err("Invalid carrier".to_string());
+    }
+
+    request.status = ReturnStatus::InTransit;
+    request.carrier = Some(carrier);
+    request.shipped_at = Some(ic_cdk::api::time());
+    save_return(request.clone());
+    Ok(request)
+}
+
+// Function for the farmer to confirm the goods came back, refunding the order
+#[ic_cdk::update]
+fn confirm_return_received(order_id: u64) -> Result<ReturnRequest, String> {
+    let mut request = farmer_return(order_id)?;
+
+    if request.status != ReturnStatus::InTransit {
+        return Err("Return has not been shipped".to_string());
+    }
+
+    let mut order = get_order_record(order_id)?;
+    if let Some(farmer_id) = order.product_id {
+        release_stock(farmer_id, order.quantity);
+    }
+    release_supply_stock(order.id, order.quantity);
+    order.status = OrderStatus::Refunded;
+    save_order(order.clone());
+    refund_order_escrow(&order);
+
+    request.status = ReturnStatus::Refunded;
+    request.received_at = Some(ic_cdk::api::time());
+    save_return(request.clone());
+    notify(
+        order.payer(),
+        format!("Order #{} was refunded after its return", order_id),
+    );
+    Ok(request)
+}
+
+// Function for the farmer to contest a rejection, or a returned shipment, through a dispute
+#[ic_cdk::update]
+fn contest_return(order_id: u64, reason: String) -> Result<ReturnRequest, String> {
+    let mut request = farmer_return(order_id)?;
+
+    if request.status != ReturnStatus::Requested && request.status != ReturnStatus::InTransit {
+        return Err("Return cannot be contested now".to_string());
+    }
+
+    let dispute_reason = format!(
+        "Return contested: {} (consumer's reason: {})",
+        reason, request.reason
+    );
+    let dispute_id = open_dispute(order_id, caller_account(), dispute_reason)?;
+
+    request.status = ReturnStatus::Disputed;
+    request.dispute_id = Some(dispute_id);
+    save_return(request.clone());
+    notify(
+        request.consumer,
+        format!("The farmer contested your return of order #{}", order_id),
+    );
+    Ok(request)
+}
+
+#[ic_cdk::query]
+fn get_return(order_id: u64) -> Result<ReturnRequest, String> {
+    let request = get_return_record(order_id)?;
+    let order = get_order_record(order_id)?;
+    let caller = caller_account();
+
+    if caller != order.farmer && caller != order.consumer && !is_admin() {
+        return Err("Return not found".to_string());
+    }
+    Ok(request)
+}
@@ -0,0 +1,421 @@
+use crate::consumers::is_registered_consumer;
+use crate::devices::caller_account;
+use crate::identity::check_can_rate;
+use crate::leaderboard::record_rating;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount, Asset};
+use crate::orders::{get_order_record, OrderStatus};
+use crate::reconciliation::EscrowExpectation;
+use crate::risk::{flag_risk, RiskKind};
+use crate::{is_admin, next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_COMMENT_LEN: usize = 500;
+const MAX_REVIEWS_PER_PAGE: usize = 50;
+// Mutual ratings this high or above look like a rating ring
+const MIN_RING_RATING: u8 = 4;
+// Labels the escrow subaccount that holds the review rewards pool
+const SUBACCOUNT_TAG: &[u8] = b"reviews";
+const REWARD_WINDOW_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_REWARDS_PER_WINDOW: u32 = 5;
+// Each account is rewarded at most once per farmer, and for this many farmers in all
+const MAX_REWARDED_FARMERS: usize = 50;
+
+// Review Struct
+// A review left by the buyer of a completed order
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Review {
+    id: u64,
+    order_id: u64,
+    listing_id: Option<u64>,
+    farmer: Principal,
+    reviewer: Principal,
+    rating: u8,
+    comment: String,
+    created_at: u64,
+    // Tokens paid for the review, if it earned a reward
+    reward: Option<u64>,
+    reward_block: Option<u64>,
+}
+
+// ReviewRewardPool Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct ReviewRewardPool {
+    balance: u64,
+    // Paid per rewarded review; 0 turns rewards off
+    reward_amount: u64,
+    // Orders below this total earn no reward, so cheap self-purchases do not pay
+    min_order_total: u64,
+    total_paid: u64,
+}
+
+// RatingSummary Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct RatingSummary {
+    review_count: u64,
+    // Average rating in hundredths, e.g. 450 for 4.5 stars; 0 without reviews
+    average_rating: u64,
+    // Reviews with 1 to 5 stars
+    rating_counts: Vec<u64>,
+}
+
+// RewardAccount Struct
+// A reviewer's reward history, for the per-account caps
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct RewardAccount {
+    window_started_at: u64,
+    window_rewards: u32,
+    farmers_rewarded: Vec<Principal>,
+}
+
+// Storable and BoundedStorable implementations for Review
+impl Storable for Review {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Review {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable implementation for ReviewRewardPool
+impl Storable for ReviewRewardPool {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Storable and BoundedStorable implementations for RewardAccount
+impl Storable for RewardAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RewardAccount {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static REVIEW_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(150))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static REVIEWS_STORAGE: RefCell<StableBTreeMap<u64, Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(151)))
+    ));
+
+    // Order ID to the ID of its review
+    static ORDER_REVIEWS_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(152)))
+    ));
+
+    static REWARD_ACCOUNTS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, RewardAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(153)))
+    ));
+
+    static REVIEW_REWARD_POOL: RefCell<Cell<ReviewRewardPool, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(154))), ReviewRewardPool::default())
+            .expect("Cannot create the review reward pool")
+    );
+}
+
+fn reward_pool() -> ReviewRewardPool {
+    REVIEW_REWARD_POOL.with(|cell| cell.borrow().get().clone())
+}
+
+fn update_pool(update: impl FnOnce(&mut ReviewRewardPool)) {
+    REVIEW_REWARD_POOL.with(|cell| {
+        let mut pool = cell.borrow().get().clone();
+        update(&mut pool);
+        let _ = cell.borrow_mut().set(pool);
+    });
+}
+
+fn save_review(review: Review) {
+    REVIEWS_STORAGE.with(|storage| storage.borrow_mut().insert(review.id, review));
+}
+
+// Flags both accounts when a high rating answers one the farmer gave the reviewer
+fn check_rating_ring(review: &Review) {
+    if review.rating < MIN_RING_RATING {
+        return;
+    }
+    let reciprocal = REVIEWS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, other)| {
+            other.reviewer == review.farmer
+                && other.farmer == review.reviewer
+                && other.rating >= MIN_RING_RATING
+        })
+    });
+    if reciprocal {
+        let detail = format!(
+            "{} and {} rated each other highly",
+            review.reviewer, review.farmer
+        );
+        flag_risk(review.reviewer, RiskKind::RatingRing, detail.clone());
+        flag_risk(review.farmer, RiskKind::RatingRing, detail);
+    }
+}
+
+fn reward_account(reviewer: &Principal) -> RewardAccount {
+    REWARD_ACCOUNTS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(*reviewer)))
+        .unwrap_or_default()
+}
+
+fn save_reward_account(reviewer: Principal, account: RewardAccount) {
+    REWARD_ACCOUNTS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(PrincipalKey(reviewer), account));
+}
+
+// Sybil checks: the reviewer is a registered consumer, the order is large enough, and the
+// reviewer is under the per-window cap and has not been rewarded for this farmer before
+fn reward_allowed(review: &Review, order_total: u64, pool: &ReviewRewardPool, now: u64) -> bool {
+    if pool.reward_amount == 0
+        || pool.balance < pool.reward_amount
+        || order_total < pool.min_order_total
+        || !is_registered_consumer(&review.reviewer)
+    {
+        return false;
+    }
+
+    let account = reward_account(&review.reviewer);
+    let window_open = now.saturating_sub(account.window_started_at) < REWARD_WINDOW_NANOS;
+    if window_open && account.window_rewards >= MAX_REWARDS_PER_WINDOW {
+        return false;
+    }
+    account.farmers_rewarded.len() < MAX_REWARDED_FARMERS
+        && !account.farmers_rewarded.contains(&review.farmer)
+}
+
+fn record_reward(reviewer: Principal, farmer: Principal, now: u64) {
+    let mut account = reward_account(&reviewer);
+    if now.saturating_sub(account.window_started_at) >= REWARD_WINDOW_NANOS {
+        account.window_started_at = now;
+        account.window_rewards = 0;
+    }
+    account.window_rewards += 1;
+    account.farmers_rewarded.push(farmer);
+    save_reward_account(reviewer, account);
+}
+
+// Undoes record_reward after a failed payment
+fn revert_reward(reviewer: Principal, farmer: Principal) {
+    let mut account = reward_account(&reviewer);
+    account.window_rewards = account.window_rewards.saturating_sub(1);
+    account.farmers_rewarded.retain(|f| *f != farmer);
+    save_reward_account(reviewer, account);
+}
+
+// Blanks the comments of a deleted account's reviews; ratings and rewards stay on record
+pub(crate) fn anonymize_reviews(reviewer: &Principal) {
+    let reviews: Vec<Review> = REVIEWS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| review.reviewer == *reviewer)
+            .collect()
+    });
+    for mut review in reviews {
+        review.comment = String::new();
+        save_review(review);
+    }
+}
+
+// The rewards pool's subaccount should hold the pool balance, for escrow reconciliation
+pub(crate) fn expected_review_escrow() -> Vec<EscrowExpectation> {
+    vec![EscrowExpectation {
+        asset: Asset::Icp,
+        source: "Review rewards pool".to_string(),
+        subaccount: escrow_subaccount(SUBACCOUNT_TAG, 0),
+        expected: reward_pool().balance,
+    }]
+}
+
+// Function for the buyer of a completed order to review it, once per order. Reviews of large
+// enough orders earn a token reward from the pool while it lasts, within per-account caps.
+#[ic_cdk::update]
+async fn submit_review(order_id: u64, rating: u8, comment: String) -> Result<Review, String> {
+    let reviewer = caller_account();
+    let order = get_order_record(order_id)?;
+    if reviewer != order.consumer && reviewer != order.payer() {
+        return Err("Only the buyer of an order can review it".to_string());
+    }
+    if reviewer == order.farmer {
+        return Err("Farmers cannot review their own sales".to_string());
+    }
+    if order.status != OrderStatus::Completed {
+        return Err("Only completed orders can be reviewed".to_string());
+    }
+    if !(1..=5).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+    check_can_rate(&reviewer)?;
+    if comment.len() > MAX_COMMENT_LEN {
+        return Err("Comment is too long".to_string());
+    }
+    if ORDER_REVIEWS_STORAGE.with(|storage| storage.borrow().contains_key(&order_id)) {
+        return Err("Order has already been reviewed".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    let mut review = Review {
+        id: next_id(&REVIEW_ID_COUNTER),
+        order_id,
+        listing_id: order.product_id,
+        farmer: order.farmer,
+        reviewer,
+        rating,
+        comment,
+        created_at: now,
+        reward: None,
+        reward_block: None,
+    };
+    save_review(review.clone());
+    ORDER_REVIEWS_STORAGE.with(|storage| storage.borrow_mut().insert(order_id, review.id));
+    record_rating(order.farmer, rating);
+    check_rating_ring(&review);
+
+    let pool = reward_pool();
+    if !reward_allowed(&review, order.total, &pool, now) {
+        return Ok(review);
+    }
+
+    // Take the reward from the pool and count it against the caps before awaiting
+    let amount = pool.reward_amount;
+    update_pool(|pool| pool.balance -= amount);
+    record_reward(reviewer, review.farmer, now);
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, 0);
+    match release_escrow(subaccount, reviewer, amount).await {
+        Ok(block) => {
+            update_pool(|pool| pool.total_paid += amount);
+            review.reward = Some(amount);
+            review.reward_block = Some(block);
+            save_review(review.clone());
+        }
+        // The review stands without a reward
+        Err(_) => {
+            update_pool(|pool| pool.balance += amount);
+            revert_reward(reviewer, review.farmer);
+        }
+    }
+    Ok(review)
+}
+
+// Rating counts and average over all of a farmer's reviews
+pub(crate) fn rating_summary(farmer: Principal) -> RatingSummary {
+    let mut summary = RatingSummary {
+        rating_counts: vec![0; 5],
+        ..Default::default()
+    };
+    let mut total: u64 = 0;
+    REVIEWS_STORAGE.with(|storage| {
+        for (_, review) in storage.borrow().iter() {
+            if review.farmer == farmer && (1..=5).contains(&review.rating) {
+                summary.review_count += 1;
+                summary.rating_counts[review.rating as usize - 1] += 1;
+                total += review.rating as u64;
+            }
+        }
+    });
+    if summary.review_count > 0 {
+        summary.average_rating = total * 100 / summary.review_count;
+    }
+    summary
+}
+
+// A farmer's latest reviews, newest first
+pub(crate) fn recent_reviews(farmer: Principal, limit: usize) -> Vec<Review> {
+    REVIEWS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, review)| review)
+            .filter(|review| review.farmer == farmer)
+            .take(limit)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_review(review_id: u64) -> Result<Review, String> {
+    REVIEWS_STORAGE
+        .with(|storage| storage.borrow().get(&review_id))
+        .ok_or("Review not found".to_string())
+}
+
+// A farmer's reviews, newest first, at most 50 per page
+#[ic_cdk::query]
+fn get_farmer_reviews(farmer: Principal, offset: u64) -> Vec<Review> {
+    REVIEWS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .rev()
+            .map(|(_, review)| review)
+            .filter(|review| review.farmer == farmer)
+            .skip(offset as usize)
+            .take(MAX_REVIEWS_PER_PAGE)
+            .collect()
+    })
+}
+
+// Function for an administrator to add funds to the review rewards pool from their own
+// pre-approved ledger balance
+#[ic_cdk::update]
+async fn fund_review_rewards(amount: u64) -> Result<ReviewRewardPool, String> {
+    if !is_admin() {
+        return Err("Only an administrator can fund review rewards".to_string());
+    }
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, 0);
+    transfer_into_subaccount(caller_account(), subaccount, amount).await?;
+    update_pool(|pool| pool.balance = pool.balance.saturating_add(amount));
+    Ok(reward_pool())
+}
+
+#[ic_cdk::update]
+fn set_review_reward(reward_amount: u64, min_order_total: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set review rewards".to_string());
+    }
+
+    update_pool(|pool| {
+        pool.reward_amount = reward_amount;
+        pool.min_order_total = min_order_total;
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_review_reward_pool() -> ReviewRewardPool {
+    reward_pool()
+}
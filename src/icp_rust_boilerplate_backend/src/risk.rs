@@ -0,0 +1,201 @@
+use crate::duplicates::fingerprint_of;
+use crate::events::log_event;
+use crate::guard::blacklist;
+use crate::traceability::last_activity_at;
+use crate::{is_admin, Farmer, Memory, PrincipalKey, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_SCORE: u64 = 100;
+// Only the latest flags are kept; the score still counts the older ones
+const MAX_FLAGS: usize = 20;
+const MAX_NOTE_LEN: usize = 200;
+// This many identical listings by one farmer within the window look automated
+const RAPID_LISTING_COUNT: usize = 3;
+const RAPID_LISTING_WINDOW_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+// RiskKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RiskKind {
+    // The same account on both sides of a sale
+    SelfDealing,
+    // Identical listings created in quick succession
+    RapidListings,
+    // Accounts rating each other highly
+    RatingRing,
+}
+
+impl RiskKind {
+    fn weight(self) -> u64 {
+        match self {
+            RiskKind::SelfDealing => 40,
+            RiskKind::RapidListings => 20,
+            RiskKind::RatingRing => 30,
+        }
+    }
+}
+
+// RiskAction Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RiskAction {
+    // The flags were harmless; the score goes back to zero
+    Dismiss,
+    // Bar the account from bidding, listing and messaging
+    Blacklist,
+}
+
+// RiskFlag Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RiskFlag {
+    kind: RiskKind,
+    detail: String,
+    raised_at: u64,
+}
+
+// RiskProfile Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct RiskProfile {
+    account: Principal,
+    score: u64,
+    flags: Vec<RiskFlag>,
+    updated_at: u64,
+    reviewed_at: Option<u64>,
+    review_note: Option<String>,
+}
+
+// Storable and BoundedStorable implementations for RiskProfile
+impl Storable for RiskProfile {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RiskProfile {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RISK_PROFILES_STORAGE: RefCell<StableBTreeMap<PrincipalKey, RiskProfile, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(186)))
+    ));
+}
+
+fn risk_profile(account: Principal) -> Option<RiskProfile> {
+    RISK_PROFILES_STORAGE.with(|storage| storage.borrow().get(&PrincipalKey(account)))
+}
+
+fn save_profile(profile: RiskProfile) {
+    RISK_PROFILES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(profile.account), profile)
+    });
+}
+
+// Records a suspicious pattern against an account and raises its score
+pub(crate) fn flag_risk(account: Principal, kind: RiskKind, detail: String) {
+    let now = ic_cdk::api::time();
+    let mut profile = risk_profile(account).unwrap_or(RiskProfile {
+        account,
+        score: 0,
+        flags: Vec::new(),
+        updated_at: now,
+        reviewed_at: None,
+        review_note: None,
+    });
+    profile.score = (profile.score + kind.weight()).min(MAX_SCORE);
+    profile.flags.push(RiskFlag {
+        kind,
+        detail,
+        raised_at: now,
+    });
+    if profile.flags.len() > MAX_FLAGS {
+        profile.flags.remove(0);
+    }
+    profile.updated_at = now;
+    save_profile(profile);
+}
+
+// Flags a farmer whose new listing repeats ones they created moments ago
+pub(crate) fn check_new_listing(farmer: &Farmer, owner: Principal) {
+    let cutoff = ic_cdk::api::time().saturating_sub(RAPID_LISTING_WINDOW_NANOS);
+    let fingerprint = fingerprint_of(farmer);
+    let recent = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(id, other)| {
+                fingerprint_of(other) == fingerprint && last_activity_at(*id).unwrap_or(0) >= cutoff
+            })
+            .count()
+    });
+    if recent >= RAPID_LISTING_COUNT {
+        flag_risk(
+            owner,
+            RiskKind::RapidListings,
+            format!(
+                "{} identical listings within an hour, latest #{}",
+                recent, farmer.id
+            ),
+        );
+    }
+}
+
+// Function for an administrator to list accounts at or above a risk score, riskiest first
+#[ic_cdk::query]
+fn get_risk_profiles(min_score: u64) -> Result<Vec<RiskProfile>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can review risk profiles".to_string());
+    }
+
+    let mut profiles: Vec<RiskProfile> = RISK_PROFILES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| profile.score >= min_score)
+            .collect()
+    });
+    profiles.sort_by(|a, b| b.score.cmp(&a.score));
+    Ok(profiles)
+}
+
+#[ic_cdk::query]
+fn get_risk_profile(account: Principal) -> Result<RiskProfile, String> {
+    if !is_admin() {
+        return Err("Only an administrator can review risk profiles".to_string());
+    }
+    risk_profile(account).ok_or("Account has no risk flags".to_string())
+}
+
+// Function for an administrator to close a review: dismiss the flags or blacklist the account
+#[ic_cdk::update]
+fn review_risk_profile(account: Principal, action: RiskAction, note: String) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can review risk profiles".to_string());
+    }
+    if note.trim().is_empty() || note.len() > MAX_NOTE_LEN {
+        return Err("A note of up to 200 characters is required".to_string());
+    }
+    let mut profile = risk_profile(account).ok_or("Account has no risk flags".to_string())?;
+
+    match action {
+        RiskAction::Dismiss => profile.score = 0,
+        RiskAction::Blacklist => {
+            blacklist(account, note.clone())?;
+        }
+    }
+    log_event("risk_review", format!("{} {:?}: {}", account, action, note));
+    profile.reviewed_at = Some(ic_cdk::api::time());
+    profile.review_note = Some(note);
+    save_profile(profile);
+    Ok(())
+}
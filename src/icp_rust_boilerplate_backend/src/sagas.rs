@@ -0,0 +1,337 @@
+use crate::events::log_event;
+use crate::inventory::release_stock;
+use crate::orders::{get_order_record, save_order, OrderStatus};
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::{is_admin, next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// A saga still running after this long, well past any ledger round trip, trapped mid-way;
+// its recorded steps are undone
+const STALLED_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// Finished sagas are kept this long for the record
+const FINISHED_RETENTION_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_SWEEP_BATCH: usize = 50;
+const MAX_FAILURE_LEN: usize = 200;
+
+// SagaStep Enum
+// A step that took effect, carrying what is needed to undo it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum SagaStep {
+    // Undone by putting the units back
+    StockReserved {
+        farmer_id: u64,
+        quantity: u64,
+    },
+    // Recorded before a payment into an escrow subaccount is awaited. If the payment is not
+    // confirmed by a PaymentTaken step, e.g. because the callback trapped, it is undone by
+    // refunding whatever reached the subaccount, less the ledger fee.
+    PaymentPending {
+        payer: Principal,
+        amount: u64,
+        subaccount: Vec<u8>,
+    },
+    // Undone by refunding the payer from where the payment landed, less the ledger fee
+    PaymentTaken {
+        payer: Principal,
+        amount: u64,
+        block_index: u64,
+        // Escrow subaccount the payment went into; None for the canister's main account
+        subaccount: Option<Vec<u8>>,
+    },
+    // Undone by cancelling the order
+    OrderCreated {
+        order_id: u64,
+    },
+}
+
+// SagaStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SagaStatus {
+    Running,
+    Completed,
+    // A step failed and the earlier ones were undone
+    Compensated,
+}
+
+// Saga Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Saga {
+    id: u64,
+    // What the saga settles, e.g. "checkout"
+    kind: String,
+    // ID of the record it settles, e.g. the checkout
+    reference_id: u64,
+    steps: Vec<SagaStep>,
+    status: SagaStatus,
+    failure: Option<String>,
+    // Retry queue entry that carries the refund, if a payment was undone
+    refund_call_id: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Saga
+impl Storable for Saga {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Saga {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SAGA_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(190))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SAGAS_STORAGE: RefCell<StableBTreeMap<u64, Saga, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(191)))
+    ));
+}
+
+fn saga_record(saga_id: u64) -> Option<Saga> {
+    SAGAS_STORAGE.with(|storage| storage.borrow().get(&saga_id))
+}
+
+fn save_saga(mut saga: Saga) {
+    saga.updated_at = ic_cdk::api::time();
+    SAGAS_STORAGE.with(|storage| storage.borrow_mut().insert(saga.id, saga));
+}
+
+// Opens a saga for a multi-step settlement; each step is recorded as soon as it takes effect
+pub(crate) fn start_saga(kind: &str, reference_id: u64) -> u64 {
+    let now = ic_cdk::api::time();
+    let saga = Saga {
+        id: next_id(&SAGA_ID_COUNTER),
+        kind: kind.to_string(),
+        reference_id,
+        steps: Vec::new(),
+        status: SagaStatus::Running,
+        failure: None,
+        refund_call_id: None,
+        created_at: now,
+        updated_at: now,
+    };
+    let id = saga.id;
+    save_saga(saga);
+    id
+}
+
+pub(crate) fn record_step(saga_id: u64, step: SagaStep) {
+    if let Some(mut saga) = saga_record(saga_id).filter(|s| s.status == SagaStatus::Running) {
+        saga.steps.push(step);
+        save_saga(saga);
+    }
+}
+
+pub(crate) fn complete_saga(saga_id: u64) {
+    if let Some(mut saga) = saga_record(saga_id).filter(|s| s.status == SagaStatus::Running) {
+        saga.status = SagaStatus::Completed;
+        save_saga(saga);
+    }
+}
+
+// The retry queue call that returns a saga's payment: the amount taken, or whatever reached the
+// subaccount of a payment that was never confirmed
+fn payment_refund(saga_id: u64, steps: &[SagaStep]) -> Option<(OutboundCall, String)> {
+    let taken = steps.iter().find_map(|step| match step {
+        SagaStep::PaymentTaken {
+            payer,
+            amount,
+            block_index,
+            subaccount,
+        } => Some((
+            OutboundCall::EscrowPayout {
+                subaccount: subaccount.clone().unwrap_or_else(|| vec![0u8; 32]),
+                to: *payer,
+                amount: *amount,
+                asset: None,
+            },
+            format!(
+                "Saga #{} refund of block {} to {}",
+                saga_id, block_index, payer
+            ),
+        )),
+        _ => None,
+    });
+    taken.or_else(|| {
+        steps.iter().find_map(|step| match step {
+            SagaStep::PaymentPending {
+                payer, subaccount, ..
+            } => Some((
+                OutboundCall::BalanceRefund {
+                    subaccount: subaccount.clone(),
+                    to: *payer,
+                    asset: None,
+                },
+                format!(
+                    "Saga #{} refund of an unconfirmed payment to {}",
+                    saga_id, payer
+                ),
+            )),
+            _ => None,
+        })
+    })
+}
+
+// Undoes the recorded steps, latest first. Refunds go through the retry queue, so this never
+// awaits and cannot be left half-done itself.
+pub(crate) fn compensate(saga_id: u64, failure: &str) {
+    let mut saga = match saga_record(saga_id).filter(|s| s.status == SagaStatus::Running) {
+        Some(saga) => saga,
+        None => return,
+    };
+
+    for step in saga.steps.iter().rev() {
+        match step {
+            SagaStep::StockReserved {
+                farmer_id,
+                quantity,
+            } => release_stock(*farmer_id, *quantity),
+            // Both payment steps are undone by one refund below
+            SagaStep::PaymentPending { .. } | SagaStep::PaymentTaken { .. } => {}
+            SagaStep::OrderCreated { order_id } => {
+                if let Ok(mut order) = get_order_record(*order_id) {
+                    order.escrowed = 0;
+                    order.status = OrderStatus::Cancelled;
+                    save_order(order);
+                }
+            }
+        }
+    }
+
+    if let Some((call, purpose)) = payment_refund(saga.id, &saga.steps) {
+        saga.refund_call_id = Some(enqueue(call, purpose));
+    }
+
+    saga.status = SagaStatus::Compensated;
+    saga.failure = Some(failure.chars().take(MAX_FAILURE_LEN).collect());
+    log_event(
+        "saga_compensated",
+        format!(
+            "#{} {} #{}: {}",
+            saga.id, saga.kind, saga.reference_id, failure
+        ),
+    );
+    save_saga(saga);
+}
+
+// Undoes sagas that stalled, e.g. because a callback trapped, and drops old finished ones
+pub(crate) fn sweep_sagas() {
+    let now = ic_cdk::api::time();
+    let stalled_before = now.saturating_sub(STALLED_NANOS);
+    let finished_before = now.saturating_sub(FINISHED_RETENTION_NANOS);
+
+    let (stalled, finished): (Vec<Saga>, Vec<Saga>) = SAGAS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, saga)| saga)
+            .filter(|saga| match saga.status {
+                SagaStatus::Running => saga.updated_at < stalled_before,
+                _ => saga.updated_at < finished_before,
+            })
+            .take(MAX_SWEEP_BATCH)
+            .partition(|saga| saga.status == SagaStatus::Running)
+    });
+
+    for saga in stalled {
+        compensate(saga.id, "Stalled before finishing");
+    }
+    SAGAS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for saga in finished {
+            storage.remove(&saga.id);
+        }
+    });
+}
+
+// Function for an administrator to look at sagas, optionally of one status
+#[ic_cdk::query]
+fn get_sagas(status: Option<SagaStatus>) -> Result<Vec<Saga>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view sagas".to_string());
+    }
+
+    Ok(SAGAS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, saga)| saga)
+            .filter(|saga| status.map_or(true, |status| saga.status == status))
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(subaccount: Vec<u8>) -> SagaStep {
+        SagaStep::PaymentPending {
+            payer: Principal::from_slice(&[2]),
+            amount: 500,
+            subaccount,
+        }
+    }
+
+    #[test]
+    fn a_saga_without_a_payment_refunds_nothing() {
+        let steps = vec![SagaStep::StockReserved {
+            farmer_id: 1,
+            quantity: 3,
+        }];
+        assert!(payment_refund(1, &steps).is_none());
+    }
+
+    #[test]
+    fn an_unconfirmed_payment_is_refunded_from_the_subaccount_balance() {
+        let steps = vec![
+            SagaStep::StockReserved {
+                farmer_id: 1,
+                quantity: 3,
+            },
+            pending(vec![7u8; 32]),
+        ];
+        match payment_refund(2, &steps) {
+            Some((OutboundCall::BalanceRefund { subaccount, to, .. }, _)) => {
+                assert_eq!(subaccount, vec![7u8; 32]);
+                assert_eq!(to, Principal::from_slice(&[2]));
+            }
+            other => panic!("unexpected refund {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_confirmed_payment_is_refunded_once_for_the_amount_taken() {
+        let steps = vec![
+            pending(vec![7u8; 32]),
+            SagaStep::PaymentTaken {
+                payer: Principal::from_slice(&[2]),
+                amount: 500,
+                block_index: 42,
+                subaccount: Some(vec![7u8; 32]),
+            },
+            SagaStep::OrderCreated { order_id: 9 },
+        ];
+        match payment_refund(3, &steps) {
+            Some((OutboundCall::EscrowPayout { amount, to, .. }, _)) => {
+                assert_eq!(amount, 500);
+                assert_eq!(to, Principal::from_slice(&[2]));
+            }
+            other => panic!("unexpected refund {:?}", other),
+        }
+    }
+}
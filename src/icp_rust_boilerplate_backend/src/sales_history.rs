@@ -0,0 +1,153 @@
+use crate::devices::caller_account;
+use crate::ledger::Asset;
+use crate::orders::Order;
+use crate::price_history::TimeRange;
+use crate::taxes::{order_taxes, tax_total};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const SALES_PER_PAGE: usize = 50;
+
+// SaleRecord Struct
+// A completed order as the farmer's books see it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SaleRecord {
+    order_id: u64,
+    farmer: Principal,
+    listing_id: Option<u64>,
+    consumer: Principal,
+    quantity: u64,
+    unit_price: u64,
+    // What the buyer paid, after any coupon
+    gross: u64,
+    // Delivery charge passed on to the shipping partner
+    shipping_fee: u64,
+    // Tax included in `gross`, which the farmer remits
+    tax: Option<u64>,
+    // What the farmer keeps: gross less the delivery charge and tax
+    net: u64,
+    // Asset the amounts are in; None for the default ledger
+    currency: Option<Asset>,
+    // Ledger block of the transfer that paid the farmer; none until the payout goes through
+    payout_block: Option<u64>,
+    completed_at: u64,
+}
+
+// SalesHistory Struct
+// One page of sales plus totals over every sale in the range
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SalesHistory {
+    sales: Vec<SaleRecord>,
+    sale_count: u64,
+    total_gross: u64,
+    total_fees: u64,
+    total_net: u64,
+}
+
+// Storable and BoundedStorable implementations for SaleRecord
+impl Storable for SaleRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SaleRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by order ID
+    static SALES_STORAGE: RefCell<StableBTreeMap<u64, SaleRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(193)))
+    ));
+}
+
+// Books a completed order into its farmer's sales history
+pub(crate) fn book_sale(order: &Order) {
+    let shipping_fee = order.shipping.as_ref().map_or(0, |line| line.cost);
+    let tax = order_taxes(order.id).as_deref().map_or(0, tax_total);
+    let sale = SaleRecord {
+        order_id: order.id,
+        farmer: order.farmer,
+        listing_id: order.product_id,
+        consumer: order.consumer,
+        quantity: order.quantity,
+        unit_price: order.unit_price,
+        gross: order.total,
+        shipping_fee,
+        tax: Some(tax),
+        net: order.total.saturating_sub(shipping_fee).saturating_sub(tax),
+        currency: order.currency,
+        payout_block: None,
+        completed_at: ic_cdk::api::time(),
+    };
+    SALES_STORAGE.with(|storage| storage.borrow_mut().insert(sale.order_id, sale));
+}
+
+// Notes the ledger block of the transfer that paid a sale out to the farmer
+pub(crate) fn record_payout_block(order_id: u64, block_index: u64) {
+    SALES_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut sale) = storage.get(&order_id) {
+            sale.payout_block = Some(block_index);
+            storage.insert(order_id, sale);
+        }
+    });
+}
+
+// Function for a farmer, or an administrator, to page through completed sales, newest first,
+// optionally within a time range
+#[ic_cdk::query]
+fn get_sales_history(
+    farmer: Principal,
+    range: Option<TimeRange>,
+    page: u64,
+) -> Result<SalesHistory, String> {
+    if farmer != caller_account() && !is_admin() {
+        return Err("Only the farmer can view their sales history".to_string());
+    }
+
+    let mut sales: Vec<SaleRecord> = SALES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, sale)| sale)
+            .filter(|sale| {
+                sale.farmer == farmer
+                    && range.map_or(true, |range| {
+                        range.from <= sale.completed_at && sale.completed_at <= range.to
+                    })
+            })
+            .collect()
+    });
+    sales.sort_by(|a, b| b.completed_at.cmp(&a.completed_at));
+
+    let mut history = SalesHistory {
+        sales: Vec::new(),
+        sale_count: sales.len() as u64,
+        total_gross: 0,
+        total_fees: 0,
+        total_net: 0,
+    };
+    // Totals are in the default currency; sales in other assets are listed but not summed
+    for sale in sales.iter().filter(|sale| sale.currency.is_none()) {
+        history.total_gross = history.total_gross.saturating_add(sale.gross);
+        history.total_fees = history.total_fees.saturating_add(sale.shipping_fee);
+        history.total_net = history.total_net.saturating_add(sale.net);
+    }
+    history.sales = sales
+        .into_iter()
+        .skip((page as usize).saturating_mul(SALES_PER_PAGE))
+        .take(SALES_PER_PAGE)
+        .collect();
+    Ok(history)
+}
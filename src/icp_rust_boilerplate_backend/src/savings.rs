@@ -0,0 +1,615 @@
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount, Asset};
+use crate::notifications::notify;
+use crate::reconciliation::EscrowExpectation;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_MEMBERS: usize = 30;
+const MAX_PERIOD_DAYS: u16 = 90;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Labels the escrow subaccounts that hold savings group funds
+const SUBACCOUNT_TAG: &[u8] = b"chama";
+
+// PayoutMode Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PayoutMode {
+    // Members take the pot in the order they joined
+    Rotating,
+    // Members vote each round on who takes the pot
+    Voted,
+}
+
+// SavingsGroupStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SavingsGroupStatus {
+    Forming,
+    Active,
+    Completed,
+}
+
+// PayoutVote Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PayoutVote {
+    voter: Principal,
+    candidate: Principal,
+}
+
+// SavingsGroup Struct
+// A savings circle (chama) whose pot is escrowed in its own ledger subaccount
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SavingsGroup {
+    id: u64,
+    name: String,
+    organizer: Principal,
+    members: Vec<Principal>,
+    contribution_amount: u64,
+    period_days: u16,
+    payout_mode: PayoutMode,
+    status: SavingsGroupStatus,
+    round: u64,
+    round_deadline: Option<u64>,
+    // Members who have paid, or are paying, into the current round
+    round_contributors: Vec<Principal>,
+    votes: Vec<PayoutVote>,
+    // Members who have already taken a pot, in payout order
+    paid_out: Vec<Principal>,
+    created_at: u64,
+}
+
+// SavingsEntryKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SavingsEntryKind {
+    Contribution,
+    Payout,
+    MissedContribution,
+}
+
+// SavingsEntry Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SavingsEntry {
+    id: u64,
+    group_id: u64,
+    round: u64,
+    member: Principal,
+    kind: SavingsEntryKind,
+    amount: u64,
+    block_index: Option<u64>,
+    // Set on a payout whose transfer failed and can be claimed again
+    pending: bool,
+    created_at: u64,
+}
+
+// SavingsGroupPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SavingsGroupPayload {
+    name: String,
+    contribution_amount: u64,
+    period_days: u16,
+    payout_mode: PayoutMode,
+}
+
+impl SavingsGroup {
+    fn is_member(&self, principal: &Principal) -> bool {
+        self.members.contains(principal)
+    }
+
+    // Next member in joining order still waiting for a pot
+    fn next_in_rotation(&self) -> Option<Principal> {
+        self.members
+            .iter()
+            .find(|member| !self.paid_out.contains(member))
+            .copied()
+    }
+
+    // Whoever takes this round's pot: the most voted eligible member in a voted
+    // group, falling back to the rotation when no one has voted
+    fn payout_recipient(&self) -> Option<Principal> {
+        if self.payout_mode == PayoutMode::Voted {
+            let mut best: Option<(Principal, usize)> = None;
+            for member in self.members.iter().filter(|m| !self.paid_out.contains(m)) {
+                let count = self
+                    .votes
+                    .iter()
+                    .filter(|vote| vote.candidate == *member)
+                    .count();
+                if count > 0 && best.map_or(true, |(_, most)| count > most) {
+                    best = Some((*member, count));
+                }
+            }
+            if let Some((member, _)) = best {
+                return Some(member);
+            }
+        }
+        self.next_in_rotation()
+    }
+}
+
+// Storable and BoundedStorable implementations for SavingsGroup
+impl Storable for SavingsGroup {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SavingsGroup {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for SavingsEntry
+impl Storable for SavingsEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SavingsEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SAVINGS_GROUP_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SAVINGS_GROUPS_STORAGE: RefCell<StableBTreeMap<u64, SavingsGroup, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    static SAVINGS_ENTRY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SAVINGS_ENTRIES_STORAGE: RefCell<StableBTreeMap<u64, SavingsEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+}
+
+fn get_group_record(group_id: u64) -> Result<SavingsGroup, String> {
+    SAVINGS_GROUPS_STORAGE
+        .with(|storage| storage.borrow().get(&group_id))
+        .ok_or("Savings group not found".to_string())
+}
+
+// Forming groups are open to anyone looking to join; after that only members see them
+fn get_visible_group(group_id: u64) -> Result<SavingsGroup, String> {
+    let group = get_group_record(group_id)?;
+    if group.status != SavingsGroupStatus::Forming && !group.is_member(&caller_account()) {
+        return Err("Savings group not found".to_string());
+    }
+    Ok(group)
+}
+
+fn save_group(group: SavingsGroup) {
+    SAVINGS_GROUPS_STORAGE.with(|storage| storage.borrow_mut().insert(group.id, group));
+}
+
+fn save_entry(entry: SavingsEntry) {
+    SAVINGS_ENTRIES_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+}
+
+fn record_entry(
+    group_id: u64,
+    round: u64,
+    member: Principal,
+    kind: SavingsEntryKind,
+    amount: u64,
+    block_index: Option<u64>,
+) -> SavingsEntry {
+    let entry = SavingsEntry {
+        id: next_id(&SAVINGS_ENTRY_ID_COUNTER),
+        group_id,
+        round,
+        member,
+        kind,
+        amount,
+        block_index,
+        pending: false,
+        created_at: ic_cdk::api::time(),
+    };
+    save_entry(entry.clone());
+    entry
+}
+
+fn group_entries(group_id: u64) -> Vec<SavingsEntry> {
+    SAVINGS_ENTRIES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.group_id == group_id)
+            .collect()
+    })
+}
+
+// Sends a recorded payout from the group's subaccount, leaving it claimable if the transfer fails
+async fn send_payout(mut entry: SavingsEntry) -> Result<SavingsEntry, String> {
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, entry.group_id);
+    match release_escrow(subaccount, entry.member, entry.amount).await {
+        Ok(block) => {
+            entry.block_index = Some(block);
+            save_entry(entry.clone());
+            notify(
+                entry.member,
+                format!(
+                    "You received the round {} pot of savings group #{}",
+                    entry.round, entry.group_id
+                ),
+            );
+            Ok(entry)
+        }
+        Err(e) => {
+            entry.pending = true;
+            save_entry(entry);
+            Err(e)
+        }
+    }
+}
+
+// Closes the group's current round: records missed contributions, pays the pot out and
+// moves on to the next round, completing the group once everyone has been paid
+async fn close_round(mut group: SavingsGroup) {
+    let now = ic_cdk::api::time();
+    let round = group.round;
+    // Only contributions whose transfer went through make up the pot
+    let paid: Vec<Principal> = group_entries(group.id)
+        .into_iter()
+        .filter(|entry| entry.round == round && entry.kind == SavingsEntryKind::Contribution)
+        .map(|entry| entry.member)
+        .collect();
+
+    for member in group.members.clone() {
+        if !paid.contains(&member) {
+            record_entry(
+                group.id,
+                round,
+                member,
+                SavingsEntryKind::MissedContribution,
+                group.contribution_amount,
+                None,
+            );
+            notify(
+                member,
+                format!(
+                    "You missed the round {} contribution to savings group #{}",
+                    round, group.id
+                ),
+            );
+        }
+    }
+
+    // A round nobody paid into passes without using up anyone's turn
+    let pot = paid.len() as u64 * group.contribution_amount;
+    let recipient = if pot > 0 {
+        group.payout_recipient()
+    } else {
+        None
+    };
+    if let Some(recipient) = recipient {
+        group.paid_out.push(recipient);
+    }
+
+    group.round_contributors.clear();
+    group.votes.clear();
+    if group.paid_out.len() == group.members.len() {
+        group.status = SavingsGroupStatus::Completed;
+        group.round_deadline = None;
+    } else {
+        group.round += 1;
+        group.round_deadline = Some(now + group.period_days as u64 * NANOS_PER_DAY);
+    }
+    // Save before awaiting so the round cannot be closed twice
+    save_group(group.clone());
+
+    if let Some(recipient) = recipient {
+        let entry = record_entry(
+            group.id,
+            round,
+            recipient,
+            SavingsEntryKind::Payout,
+            pot,
+            None,
+        );
+        if let Err(e) = send_payout(entry).await {
+            notify(
+                recipient,
+                format!(
+                    "Payout from savings group #{} failed ({}); claim it again later",
+                    group.id, e
+                ),
+            );
+        }
+    }
+}
+
+// Closes every active round whose contribution deadline has passed
+pub(crate) async fn close_due_rounds() {
+    let now = ic_cdk::api::time();
+    let due: Vec<SavingsGroup> = SAVINGS_GROUPS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, group)| group)
+            .filter(|group| {
+                group.status == SavingsGroupStatus::Active
+                    && group
+                        .round_deadline
+                        .map_or(false, |deadline| deadline <= now)
+            })
+            .collect()
+    });
+
+    for group in due {
+        close_round(group).await;
+    }
+}
+
+// What each running group's pot should hold: contributions received less payouts sent, for
+// escrow reconciliation
+pub(crate) fn expected_savings_escrow() -> Vec<EscrowExpectation> {
+    let mut pots: BTreeMap<u64, u64> = SAVINGS_GROUPS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, group)| group.status != SavingsGroupStatus::Completed)
+            .map(|(id, _)| (id, 0))
+            .collect()
+    });
+    SAVINGS_ENTRIES_STORAGE.with(|storage| {
+        for (_, entry) in storage.borrow().iter() {
+            let pot = match pots.get_mut(&entry.group_id) {
+                Some(pot) => pot,
+                None => continue,
+            };
+            match (entry.kind, entry.block_index) {
+                (SavingsEntryKind::Contribution, Some(_)) => {
+                    *pot = pot.saturating_add(entry.amount)
+                }
+                (SavingsEntryKind::Payout, Some(_)) => *pot = pot.saturating_sub(entry.amount),
+                _ => {}
+            }
+        }
+    });
+    pots.into_iter()
+        .map(|(group_id, expected)| EscrowExpectation {
+            asset: Asset::Icp,
+            source: format!("Savings group #{} pot", group_id),
+            subaccount: escrow_subaccount(SUBACCOUNT_TAG, group_id),
+            expected,
+        })
+        .collect()
+}
+
+// Function for a user to start a savings group, joining it as its first member
+#[ic_cdk::update]
+fn create_savings_group(payload: SavingsGroupPayload) -> Result<SavingsGroup, String> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err("Invalid group name".to_string());
+    }
+    if payload.contribution_amount == 0 {
+        return Err("Contribution must be positive".to_string());
+    }
+    if payload.period_days == 0 || payload.period_days > MAX_PERIOD_DAYS {
+        return Err("Invalid contribution period".to_string());
+    }
+
+    let organizer = caller_account();
+    let group = SavingsGroup {
+        id: next_id(&SAVINGS_GROUP_ID_COUNTER),
+        name: payload.name,
+        organizer,
+        members: vec![organizer],
+        contribution_amount: payload.contribution_amount,
+        period_days: payload.period_days,
+        payout_mode: payload.payout_mode,
+        status: SavingsGroupStatus::Forming,
+        round: 0,
+        round_deadline: None,
+        round_contributors: Vec::new(),
+        votes: Vec::new(),
+        paid_out: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_group(group.clone());
+    Ok(group)
+}
+
+#[ic_cdk::update]
+fn join_savings_group(group_id: u64) -> Result<SavingsGroup, String> {
+    let mut group = get_group_record(group_id)?;
+    let caller = caller_account();
+
+    if group.status != SavingsGroupStatus::Forming {
+        return Err("Group is no longer taking members".to_string());
+    }
+    if group.is_member(&caller) {
+        return Err("Already a member".to_string());
+    }
+    if group.members.len() >= MAX_MEMBERS {
+        return Err("Group is full".to_string());
+    }
+
+    group.members.push(caller);
+    save_group(group.clone());
+    Ok(group)
+}
+
+// Function for the organizer to close membership and open the first round
+#[ic_cdk::update]
+fn start_savings_group(group_id: u64) -> Result<SavingsGroup, String> {
+    let mut group = get_group_record(group_id)?;
+
+    if group.organizer != caller_account() {
+        return Err("Only the organizer can start the group".to_string());
+    }
+    if group.status != SavingsGroupStatus::Forming {
+        return Err("Group has already started".to_string());
+    }
+    if group.members.len() < 2 {
+        return Err("A group needs at least two members".to_string());
+    }
+
+    group.status = SavingsGroupStatus::Active;
+    group.round = 1;
+    group.round_deadline = Some(ic_cdk::api::time() + group.period_days as u64 * NANOS_PER_DAY);
+    save_group(group.clone());
+
+    for member in group.members.iter().filter(|m| **m != group.organizer) {
+        notify(
+            *member,
+            format!("Savings group #{} has started its first round", group.id),
+        );
+    }
+    Ok(group)
+}
+
+// Function for a member to pay this round's contribution into the group's escrow subaccount
+#[ic_cdk::update]
+async fn contribute_to_savings_group(group_id: u64) -> Result<SavingsEntry, String> {
+    let mut group = get_group_record(group_id)?;
+    let member = caller_account();
+
+    if !group.is_member(&member) {
+        return Err("Only members can contribute".to_string());
+    }
+    if group.status != SavingsGroupStatus::Active
+        || group
+            .round_deadline
+            .map_or(true, |deadline| deadline <= ic_cdk::api::time())
+    {
+        return Err("Group is not taking contributions".to_string());
+    }
+    if group.round_contributors.contains(&member) {
+        return Err("Already contributed this round".to_string());
+    }
+
+    // Claim the member's slot before awaiting so a second call cannot pay twice
+    let round = group.round;
+    group.round_contributors.push(member);
+    save_group(group.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, group_id);
+    let result = transfer_into_subaccount(member, subaccount, group.contribution_amount).await;
+
+    let mut group = get_group_record(group_id)?;
+    let block = match result {
+        Ok(block) => block,
+        Err(e) => {
+            if group.round == round {
+                group.round_contributors.retain(|m| *m != member);
+                save_group(group);
+            }
+            return Err(e);
+        }
+    };
+
+    let entry = record_entry(
+        group_id,
+        round,
+        member,
+        SavingsEntryKind::Contribution,
+        group.contribution_amount,
+        Some(block),
+    );
+
+    // A rotating group pays out as soon as every member is in
+    let paid_count = group_entries(group_id)
+        .iter()
+        .filter(|e| e.round == round && e.kind == SavingsEntryKind::Contribution)
+        .count();
+    let everyone_paid = group.round == round && paid_count == group.members.len();
+    if group.payout_mode == PayoutMode::Rotating && everyone_paid {
+        close_round(group).await;
+    }
+    Ok(entry)
+}
+
+// Function for a member of a voted group to choose who takes this round's pot
+#[ic_cdk::update]
+fn vote_savings_payout(group_id: u64, candidate: Principal) -> Result<SavingsGroup, String> {
+    let mut group = get_group_record(group_id)?;
+    let voter = caller_account();
+
+    if !group.is_member(&voter) {
+        return Err("Only members can vote".to_string());
+    }
+    if group.payout_mode != PayoutMode::Voted {
+        return Err("Group pays out in rotation".to_string());
+    }
+    if group.status != SavingsGroupStatus::Active {
+        return Err("Group is not active".to_string());
+    }
+    if !group.is_member(&candidate) || group.paid_out.contains(&candidate) {
+        return Err("Candidate cannot receive a payout".to_string());
+    }
+
+    group.votes.retain(|vote| vote.voter != voter);
+    group.votes.push(PayoutVote { voter, candidate });
+    save_group(group.clone());
+    Ok(group)
+}
+
+// Function for a member to retry a payout of theirs whose transfer failed
+#[ic_cdk::update]
+async fn claim_savings_payout(entry_id: u64) -> Result<SavingsEntry, String> {
+    let mut entry = SAVINGS_ENTRIES_STORAGE
+        .with(|storage| storage.borrow().get(&entry_id))
+        .ok_or("Savings entry not found".to_string())?;
+
+    if entry.member != caller_account() {
+        return Err("Only the recipient can claim this payout".to_string());
+    }
+    if entry.kind != SavingsEntryKind::Payout || !entry.pending {
+        return Err("No payout to claim".to_string());
+    }
+
+    // Clear the flag before awaiting so the payout cannot be claimed twice
+    entry.pending = false;
+    save_entry(entry.clone());
+    send_payout(entry).await
+}
+
+#[ic_cdk::query]
+fn get_savings_group(group_id: u64) -> Result<SavingsGroup, String> {
+    get_visible_group(group_id)
+}
+
+// Lists every contribution, missed contribution and payout of a group for its members
+#[ic_cdk::query]
+fn get_savings_group_history(group_id: u64) -> Result<Vec<SavingsEntry>, String> {
+    get_visible_group(group_id)?;
+    Ok(group_entries(group_id))
+}
+
+#[ic_cdk::query]
+fn get_my_savings_groups() -> Vec<SavingsGroup> {
+    let caller = caller_account();
+    SAVINGS_GROUPS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, group)| group)
+            .filter(|group| group.is_member(&caller))
+            .collect()
+    })
+}
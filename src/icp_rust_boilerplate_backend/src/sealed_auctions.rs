@@ -0,0 +1,624 @@
+use crate::bid_deposits::{bid_deposit_amount, DepositStatus};
+use crate::bids::{accept_bid_record, place_bid, ListingKind};
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, transfer_into_subaccount, Asset};
+use crate::notifications::notify;
+use crate::reconciliation::EscrowExpectation;
+use crate::retry_queue::{enqueue, OutboundCall};
+use crate::traceability::record_provenance;
+use crate::{
+    farmer_principal, is_farmer_owner, is_off_market, next_id, reopen_product, IdCell, Memory,
+    FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+// Labels the escrow subaccounts that hold sealed bid deposits
+const SUBACCOUNT_TAG: &[u8] = b"sealed";
+const HOUR_NANOS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_BIDDING_HOURS: u64 = 30 * 24;
+const MAX_REVEAL_HOURS: u64 = 7 * 24;
+// A winner who has not completed the purchase this long after the award has abandoned it
+const ABANDON_AFTER_NANOS: u64 = 14 * 24 * HOUR_NANOS;
+const MAX_ADDRESS_LEN: usize = 200;
+const MAX_SALT_LEN: usize = 64;
+const MAX_SETTLE_BATCH: usize = 20;
+
+// AuctionPhase Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AuctionPhase {
+    // Bidders submit commitments
+    Bidding,
+    // Bidders open their commitments
+    Revealing,
+    // The highest revealed bid won the listing
+    Awarded,
+    // Nobody revealed a bid
+    Unsold,
+}
+
+// SealedAuction Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SealedAuction {
+    id: u64,
+    listing_id: u64,
+    farmer: Principal,
+    // Deposit every commitment locks, fixed when the auction opens
+    deposit: u64,
+    bidding_ends_at: u64,
+    reveal_ends_at: u64,
+    phase: AuctionPhase,
+    winning_bid_id: Option<u64>,
+    winning_amount: Option<u64>,
+    created_at: u64,
+}
+
+// SealedBid Struct
+// A commitment to SHA-256(amount as 8 big-endian bytes ++ salt), opened after bidding closes
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SealedBid {
+    id: u64,
+    auction_id: u64,
+    bidder: Principal,
+    commitment: Vec<u8>,
+    consumer_address: String,
+    revealed_amount: Option<u64>,
+    deposit_status: DepositStatus,
+    // Set once the payout is handed to the retry queue
+    paid_out: bool,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Storable and BoundedStorable implementations for SealedAuction
+impl Storable for SealedAuction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SealedAuction {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for SealedBid
+impl Storable for SealedBid {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SealedBid {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static AUCTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(181))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static AUCTIONS_STORAGE: RefCell<StableBTreeMap<u64, SealedAuction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(182)))
+    ));
+
+    static SEALED_BID_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(183))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SEALED_BIDS_STORAGE: RefCell<StableBTreeMap<u64, SealedBid, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(184)))
+    ));
+}
+
+fn auction_record(auction_id: u64) -> Result<SealedAuction, String> {
+    AUCTIONS_STORAGE
+        .with(|storage| storage.borrow().get(&auction_id))
+        .ok_or("Auction not found".to_string())
+}
+
+fn save_auction(auction: SealedAuction) {
+    AUCTIONS_STORAGE.with(|storage| storage.borrow_mut().insert(auction.id, auction));
+}
+
+fn save_sealed_bid(mut bid: SealedBid) {
+    bid.updated_at = ic_cdk::api::time();
+    SEALED_BIDS_STORAGE.with(|storage| storage.borrow_mut().insert(bid.id, bid));
+}
+
+fn auction_bids(auction_id: u64) -> Vec<SealedBid> {
+    SEALED_BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| bid.auction_id == auction_id)
+            .collect()
+    })
+}
+
+fn commitment_for(amount: u64, salt: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(amount.to_be_bytes());
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+// Listings in a bidding or reveal phase take no open bids or orders
+pub(crate) fn is_in_auction(listing_id: u64) -> bool {
+    AUCTIONS_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, auction)| {
+            auction.listing_id == listing_id
+                && matches!(
+                    auction.phase,
+                    AuctionPhase::Bidding | AuctionPhase::Revealing
+                )
+        })
+    })
+}
+
+// Makes the highest revealed bid the listing's accepted bid; ties go to the earlier commitment
+fn award(auction: &mut SealedAuction, bids: &mut [SealedBid]) {
+    let winner = bids
+        .iter()
+        .enumerate()
+        .filter_map(|(index, bid)| bid.revealed_amount.map(|amount| (index, amount)))
+        .max_by(|(a, amount_a), (b, amount_b)| {
+            amount_a.cmp(amount_b).then(bids[*b].id.cmp(&bids[*a].id))
+        })
+        .map(|(index, _)| index);
+
+    // A listing sold, withdrawn or deleted meanwhile is left as it is
+    let mut farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&auction.listing_id))
+        .filter(|farmer| !farmer.is_sold && !is_off_market(farmer));
+    let accepted = match (winner, farmer.as_mut()) {
+        (Some(index), Some(farmer)) => {
+            let winning = &bids[index];
+            let amount = winning.revealed_amount.unwrap_or(0);
+            place_bid(
+                ListingKind::Produce,
+                auction.listing_id,
+                winning.bidder,
+                amount,
+                "Sealed auction".to_string(),
+            )
+            .and_then(accept_bid_record)
+            .ok()
+            .map(|bid| {
+                farmer.consumer_address = Some(winning.consumer_address.clone());
+                farmer.product_status = "Bid Accepted".to_string();
+                // The winner's deposit counts towards what they owe
+                farmer.escrow_balance += auction.deposit;
+                (index, bid.id, amount)
+            })
+        }
+        _ => None,
+    };
+
+    for (index, bid) in bids.iter_mut().enumerate() {
+        bid.deposit_status = match (bid.revealed_amount, accepted) {
+            (None, _) => DepositStatus::Forfeited,
+            (Some(_), Some((winner, _, _))) if winner == index => DepositStatus::Applied,
+            _ => DepositStatus::Refunded,
+        };
+        let message = match bid.deposit_status {
+            DepositStatus::Forfeited => format!(
+                "Your sealed bid #{} was never revealed and its deposit was forfeited",
+                bid.id
+            ),
+            DepositStatus::Applied => format!("Your sealed bid #{} won the auction", bid.id),
+            _ => format!("Your sealed bid #{} did not win the auction", bid.id),
+        };
+        notify(bid.bidder, message);
+    }
+
+    match (accepted, farmer) {
+        (Some((_, bid_id, amount)), Some(farmer)) => {
+            FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer.id, farmer));
+            record_provenance(auction.listing_id, "Sealed Auction Won");
+            auction.phase = AuctionPhase::Awarded;
+            auction.winning_bid_id = Some(bid_id);
+            auction.winning_amount = Some(amount);
+            notify(
+                auction.farmer,
+                format!(
+                    "Sealed auction #{} on listing #{} closed at {}",
+                    auction.id, auction.listing_id, amount
+                ),
+            );
+        }
+        (_, farmer) => {
+            if farmer.is_some() {
+                reopen_product(auction.listing_id, "Sealed Auction Unsold");
+            }
+            auction.phase = AuctionPhase::Unsold;
+            notify(
+                auction.farmer,
+                format!(
+                    "Sealed auction #{} on listing #{} closed without a winner",
+                    auction.id, auction.listing_id
+                ),
+            );
+        }
+    }
+}
+
+// Moves auctions from bidding to reveal when bidding closes, and settles them when the reveal
+// phase ends
+pub(crate) fn advance_sealed_auctions() {
+    let now = ic_cdk::api::time();
+    let due: Vec<SealedAuction> = AUCTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, auction)| auction)
+            .filter(|auction| match auction.phase {
+                AuctionPhase::Bidding => auction.bidding_ends_at <= now,
+                AuctionPhase::Revealing => auction.reveal_ends_at <= now,
+                _ => false,
+            })
+            .collect()
+    });
+
+    for mut auction in due {
+        let mut bids = auction_bids(auction.id);
+        if auction.phase == AuctionPhase::Bidding {
+            auction.phase = AuctionPhase::Revealing;
+            for bid in &bids {
+                notify(
+                    bid.bidder,
+                    format!(
+                        "Bidding on sealed auction #{} closed. Reveal your bid before the reveal phase ends or lose your deposit",
+                        auction.id
+                    ),
+                );
+            }
+        }
+        if auction.phase == AuctionPhase::Revealing && auction.reveal_ends_at <= now {
+            award(&mut auction, &mut bids);
+            for bid in bids {
+                save_sealed_bid(bid);
+            }
+        }
+        save_auction(auction);
+    }
+
+    forfeit_abandoned_wins();
+}
+
+// The winning deposit counted towards a listing's escrow, if any
+fn applied_deposit(listing_id: u64) -> Option<(SealedAuction, SealedBid)> {
+    let auction = AUCTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, auction)| auction)
+            .find(|auction| {
+                auction.listing_id == listing_id && auction.phase == AuctionPhase::Awarded
+            })
+    })?;
+    let bid = auction_bids(auction.id)
+        .into_iter()
+        .find(|bid| bid.deposit_status == DepositStatus::Applied)?;
+    Some((auction, bid))
+}
+
+// Settles the winning deposit when the listing's sale ends: to the farmer once payment is
+// released, back to the winner if a dispute went their way
+pub(crate) fn close_listing_deposit(listing_id: u64, to_farmer: bool) {
+    if let Some((_, mut bid)) = applied_deposit(listing_id) {
+        bid.deposit_status = if to_farmer {
+            DepositStatus::Released
+        } else {
+            DepositStatus::Refunded
+        };
+        save_sealed_bid(bid);
+    }
+}
+
+// Forfeits the deposits of winners who left the purchase unfinished, and reopens their
+// listings
+fn forfeit_abandoned_wins() {
+    let cutoff = ic_cdk::api::time().saturating_sub(ABANDON_AFTER_NANOS);
+    let winners: Vec<SealedBid> = SEALED_BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| bid.deposit_status == DepositStatus::Applied && bid.updated_at < cutoff)
+            .collect()
+    });
+
+    for mut bid in winners {
+        let auction = match auction_record(bid.auction_id) {
+            Ok(auction) => auction,
+            Err(_) => continue,
+        };
+        let mut farmer =
+            match FARMERS_STORAGE.with(|storage| storage.borrow().get(&auction.listing_id)) {
+                Some(farmer) => farmer,
+                None => continue,
+            };
+        if farmer.is_sold || farmer.dispute_status {
+            continue;
+        }
+
+        farmer.escrow_balance = farmer.escrow_balance.saturating_sub(auction.deposit);
+        FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer.id, farmer));
+        reopen_product(auction.listing_id, "Bid Abandoned");
+
+        bid.deposit_status = DepositStatus::Forfeited;
+        notify(
+            bid.bidder,
+            format!(
+                "Your deposit on sealed bid #{} was forfeited to the farmer",
+                bid.id
+            ),
+        );
+        save_sealed_bid(bid);
+    }
+}
+
+// Hands settled sealed bid deposits to the retry queue to be paid out of their subaccounts
+pub(crate) fn queue_sealed_payouts() {
+    let pending: Vec<u64> = SEALED_BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, bid)| {
+                !bid.paid_out
+                    && matches!(
+                        bid.deposit_status,
+                        DepositStatus::Refunded
+                            | DepositStatus::Released
+                            | DepositStatus::Forfeited
+                    )
+            })
+            .map(|(bid_id, _)| bid_id)
+            .take(MAX_SETTLE_BATCH)
+            .collect()
+    });
+
+    for bid_id in pending {
+        let mut bid = match SEALED_BIDS_STORAGE.with(|storage| storage.borrow().get(&bid_id)) {
+            Some(bid) => bid,
+            None => continue,
+        };
+        let auction = match auction_record(bid.auction_id) {
+            Ok(auction) => auction,
+            Err(_) => continue,
+        };
+        let to = if bid.deposit_status == DepositStatus::Refunded {
+            bid.bidder
+        } else {
+            auction.farmer
+        };
+        enqueue(
+            OutboundCall::EscrowPayout {
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, bid_id),
+                to,
+                amount: auction.deposit,
+                asset: None,
+            },
+            format!("Sealed bid #{} deposit {:?}", bid_id, bid.deposit_status),
+        );
+        bid.paid_out = true;
+        save_sealed_bid(bid);
+    }
+}
+
+// Sealed-bid deposits still sitting in their subaccounts, for escrow reconciliation
+pub(crate) fn expected_sealed_escrow() -> Vec<EscrowExpectation> {
+    let unpaid: Vec<SealedBid> = SEALED_BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| !bid.paid_out)
+            .collect()
+    });
+    unpaid
+        .into_iter()
+        .filter_map(|bid| {
+            let auction = auction_record(bid.auction_id).ok()?;
+            Some(EscrowExpectation {
+                asset: Asset::Icp,
+                source: format!("Sealed bid #{} deposit", bid.id),
+                subaccount: escrow_subaccount(SUBACCOUNT_TAG, bid.id),
+                expected: auction.deposit,
+            })
+        })
+        .collect()
+}
+
+// Function for a farmer to sell a listing by sealed-bid auction. Bids are committed for
+// `bidding_hours`, then opened during `reveal_hours`.
+#[ic_cdk::update]
+fn open_sealed_auction(
+    listing_id: u64,
+    bidding_hours: u64,
+    reveal_hours: u64,
+) -> Result<SealedAuction, String> {
+    let mut farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can auction this listing".to_string());
+    }
+    if farmer.is_sold || is_off_market(&farmer) {
+        return Err("Listing is no longer available".to_string());
+    }
+    if farmer.consumer_address.is_some() || farmer.dispute_status {
+        return Err("Listing has a bid in progress".to_string());
+    }
+    if is_in_auction(listing_id) {
+        return Err("Listing is already in a sealed-bid auction".to_string());
+    }
+    if bidding_hours == 0 || bidding_hours > MAX_BIDDING_HOURS {
+        return Err(format!(
+            "Bidding must last 1 to {} hours",
+            MAX_BIDDING_HOURS
+        ));
+    }
+    if reveal_hours == 0 || reveal_hours > MAX_REVEAL_HOURS {
+        return Err(format!(
+            "The reveal phase must last 1 to {} hours",
+            MAX_REVEAL_HOURS
+        ));
+    }
+
+    let now = ic_cdk::api::time();
+    let bidding_ends_at = now + bidding_hours * HOUR_NANOS;
+    let auction = SealedAuction {
+        id: next_id(&AUCTION_ID_COUNTER),
+        listing_id,
+        farmer: farmer_principal(&farmer)?,
+        deposit: bid_deposit_amount(),
+        bidding_ends_at,
+        reveal_ends_at: bidding_ends_at + reveal_hours * HOUR_NANOS,
+        phase: AuctionPhase::Bidding,
+        winning_bid_id: None,
+        winning_amount: None,
+        created_at: now,
+    };
+    save_auction(auction.clone());
+    farmer.product_status = "Sealed Auction".to_string();
+    FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(listing_id, farmer));
+    record_provenance(listing_id, "Sealed Auction Opened");
+    Ok(auction)
+}
+
+// Function for a consumer to commit a sealed bid while bidding is open, locking the auction's
+// deposit
+#[ic_cdk::update]
+async fn commit_sealed_bid(
+    auction_id: u64,
+    commitment: Vec<u8>,
+    consumer_address: String,
+) -> Result<SealedBid, String> {
+    let auction = auction_record(auction_id)?;
+    let bidder = caller_account();
+    let now = ic_cdk::api::time();
+    if auction.phase != AuctionPhase::Bidding || now >= auction.bidding_ends_at {
+        return Err("Bidding on this auction is closed".to_string());
+    }
+    if bidder == auction.farmer {
+        return Err("Farmers cannot bid on their own listings".to_string());
+    }
+    if commitment.len() != 32 {
+        return Err("Commitment must be a 32-byte SHA-256 hash".to_string());
+    }
+    if consumer_address.trim().is_empty() || consumer_address.len() > MAX_ADDRESS_LEN {
+        return Err("Invalid consumer address".to_string());
+    }
+    if auction_bids(auction_id)
+        .iter()
+        .any(|bid| bid.bidder == bidder)
+    {
+        return Err("You already committed a bid on this auction".to_string());
+    }
+
+    // Record the commitment before awaiting the deposit so a second call cannot slip in
+    let bid = SealedBid {
+        id: next_id(&SEALED_BID_ID_COUNTER),
+        auction_id,
+        bidder,
+        commitment,
+        consumer_address,
+        revealed_amount: None,
+        deposit_status: DepositStatus::Held,
+        paid_out: auction.deposit == 0,
+        created_at: now,
+        updated_at: now,
+    };
+    save_sealed_bid(bid.clone());
+
+    if auction.deposit > 0 {
+        if let Err(e) = transfer_into_subaccount(
+            bidder,
+            escrow_subaccount(SUBACCOUNT_TAG, bid.id),
+            auction.deposit,
+        )
+        .await
+        {
+            SEALED_BIDS_STORAGE.with(|storage| storage.borrow_mut().remove(&bid.id));
+            return Err(e);
+        }
+    }
+    Ok(bid)
+}
+
+// Function for a bidder to open their commitment during the reveal phase
+#[ic_cdk::update]
+fn reveal_sealed_bid(auction_id: u64, amount: u64, salt: Vec<u8>) -> Result<(), String> {
+    let auction = auction_record(auction_id)?;
+    let now = ic_cdk::api::time();
+    if now < auction.bidding_ends_at {
+        return Err("Bidding is still open".to_string());
+    }
+    if !matches!(
+        auction.phase,
+        AuctionPhase::Bidding | AuctionPhase::Revealing
+    ) || now >= auction.reveal_ends_at
+    {
+        return Err("The reveal phase is over".to_string());
+    }
+    if salt.len() > MAX_SALT_LEN {
+        return Err("Salt is too long".to_string());
+    }
+
+    let caller = caller_account();
+    let mut bid = auction_bids(auction_id)
+        .into_iter()
+        .find(|bid| bid.bidder == caller)
+        .ok_or("You have no sealed bid on this auction".to_string())?;
+    if bid.revealed_amount.is_some() {
+        return Err("Bid already revealed".to_string());
+    }
+    if amount == 0 {
+        return Err("Bid amount must be greater than zero".to_string());
+    }
+    if commitment_for(amount, &salt) != bid.commitment {
+        return Err("Amount and salt do not match the commitment".to_string());
+    }
+
+    bid.revealed_amount = Some(amount);
+    save_sealed_bid(bid);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_sealed_auction(auction_id: u64) -> Result<SealedAuction, String> {
+    auction_record(auction_id)
+}
+
+// Sealed bids the caller committed
+#[ic_cdk::query]
+fn get_my_sealed_bids() -> Vec<SealedBid> {
+    let caller = caller_account();
+    SEALED_BIDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, bid)| bid)
+            .filter(|bid| bid.bidder == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,211 @@
+use crate::shipping::{listing_region, normalise_region};
+use crate::{is_admin, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CROP_LEN: usize = 50;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// SeasonKey Struct
+// "region|crop", so that a region's crops sort together
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SeasonKey(String);
+
+// CropSeason Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct CropSeason {
+    region: String,
+    crop: String,
+    // Months of the year, 1 = January
+    months: Vec<u8>,
+}
+
+// SeasonCheck Struct
+// Whether a listing's crop is in season in its region this month
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SeasonCheck {
+    farmer_id: u64,
+    month: u8,
+    // None when the listing's region or crop is not in the table
+    in_season: Option<bool>,
+    warning: Option<String>,
+}
+
+// Storable and BoundedStorable implementations for SeasonKey
+impl Storable for SeasonKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        SeasonKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for SeasonKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Bit m - 1 is set when the crop is in season in month m
+    static SEASONS_STORAGE: RefCell<StableBTreeMap<SeasonKey, u16, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+}
+
+fn normalise_crop(crop: &str) -> Result<String, String> {
+    let crop = crop.trim().to_lowercase();
+    if crop.is_empty() || crop.len() > MAX_CROP_LEN || crop.contains('|') {
+        return Err("Invalid crop".to_string());
+    }
+    Ok(crop)
+}
+
+fn validate_month(month: u8) -> Result<(), String> {
+    if !(1..=12).contains(&month) {
+        return Err("Month must be between 1 and 12".to_string());
+    }
+    Ok(())
+}
+
+fn season_key(region: &str, crop: &str) -> SeasonKey {
+    SeasonKey(format!("{}|{}", region, crop))
+}
+
+fn months_of(mask: u16) -> Vec<u8> {
+    (1..=12)
+        .filter(|month| mask & (1 << (month - 1)) != 0)
+        .collect()
+}
+
+// Calendar year and month (1-12, UTC) of a timestamp in nanoseconds
+pub(crate) fn year_month_of(nanos: u64) -> (u32, u8) {
+    // Civil-from-days conversion for the proleptic Gregorian calendar
+    let days = (nanos / NANOS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    if month_index < 10 {
+        (year as u32, (month_index + 3) as u8)
+    } else {
+        (year as u32 + 1, (month_index - 9) as u8)
+    }
+}
+
+// Calendar month (1-12, UTC) of a timestamp in nanoseconds
+pub(crate) fn month_of(nanos: u64) -> u8 {
+    year_month_of(nanos).1
+}
+
+// Function for an administrator to set the months a crop is in season in a region
+#[ic_cdk::update]
+fn set_crop_season(region: String, crop: String, months: Vec<u8>) -> Result<CropSeason, String> {
+    if !is_admin() {
+        return Err("Only an administrator can maintain the seasonal calendar".to_string());
+    }
+
+    let region = normalise_region(&region)?;
+    let crop = normalise_crop(&crop)?;
+    if months.is_empty() {
+        return Err("A crop needs at least one month in season".to_string());
+    }
+    let mut mask: u16 = 0;
+    for month in months {
+        validate_month(month)?;
+        mask |= 1 << (month - 1);
+    }
+
+    SEASONS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(season_key(&region, &crop), mask)
+    });
+    Ok(CropSeason {
+        region,
+        crop,
+        months: months_of(mask),
+    })
+}
+
+// Function for an administrator to drop a crop from a region's calendar
+#[ic_cdk::update]
+fn remove_crop_season(region: String, crop: String) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can maintain the seasonal calendar".to_string());
+    }
+
+    let key = season_key(&normalise_region(&region)?, &normalise_crop(&crop)?);
+    SEASONS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&key))
+        .map(|_| ())
+        .ok_or("Crop season not found".to_string())
+}
+
+// Every crop in a region's calendar with its months
+#[ic_cdk::query]
+fn get_region_seasons(region: String) -> Result<Vec<CropSeason>, String> {
+    let region = normalise_region(&region)?;
+    let prefix = format!("{}|", region);
+
+    Ok(SEASONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(SeasonKey(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .map(|(key, mask)| CropSeason {
+                region: region.clone(),
+                crop: key.0[prefix.len()..].to_string(),
+                months: months_of(mask),
+            })
+            .collect()
+    }))
+}
+
+// Crops in season in a region during a month (1-12)
+#[ic_cdk::query]
+fn get_in_season(region: String, month: u8) -> Result<Vec<String>, String> {
+    validate_month(month)?;
+
+    Ok(get_region_seasons(region)?
+        .into_iter()
+        .filter(|season| season.months.contains(&month))
+        .map(|season| season.crop)
+        .collect())
+}
+
+// Compares a listing's category against the calendar for its region and the current month,
+// so frontends can warn about produce listed well out of season
+#[ic_cdk::query]
+fn check_listing_season(farmer_id: u64) -> Result<SeasonCheck, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+    let month = month_of(ic_cdk::api::time());
+
+    let mask = listing_region(farmer_id).ok().and_then(|region| {
+        let crop = normalise_crop(&farmer.category).ok()?;
+        SEASONS_STORAGE.with(|storage| storage.borrow().get(&season_key(&region, &crop)))
+    });
+    let in_season = mask.map(|mask| mask & (1 << (month - 1)) != 0);
+    let warning = match in_season {
+        Some(false) => Some(format!(
+            "{} is not normally in season in this region in month {}",
+            farmer.category, month
+        )),
+        _ => None,
+    };
+
+    Ok(SeasonCheck {
+        farmer_id,
+        month,
+        in_season,
+        warning,
+    })
+}
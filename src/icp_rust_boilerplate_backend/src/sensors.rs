@@ -0,0 +1,351 @@
+use crate::devices::caller_account;
+use crate::{is_farmer_owner, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Readings kept per farm; older ones are overwritten ring-buffer style
+const MAX_READINGS_PER_FARM: u64 = 1_000;
+// Upper bounds on what a single call may push or register
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_DEVICES_PER_FARM: usize = 20;
+// Longest lot label, in bytes; it is copied into every reading of the batch
+const MAX_LOT_LEN: usize = 64;
+
+// SensorKind Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) enum SensorKind {
+    SoilMoisture,
+    StorageTemperature,
+}
+
+// SensorReading Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SensorReading {
+    farmer_id: u64,
+    lot: Option<String>,
+    device: Principal,
+    kind: SensorKind,
+    value: f64,
+    recorded_at: u64,
+    received_at: u64,
+}
+
+// DeviceList Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct DeviceList {
+    devices: Vec<Principal>,
+}
+
+// Key of a reading: the farm it belongs to and its position in the farm's buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ReadingKey {
+    farmer_id: u64,
+    seq: u64,
+}
+
+// Storable and BoundedStorable implementations for SensorReading
+impl Storable for SensorReading {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SensorReading {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for DeviceList
+impl Storable for DeviceList {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeviceList {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ReadingKey
+// Big-endian so that keys of one farm sort by sequence number
+impl Storable for ReadingKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.farmer_id.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (farmer_id, seq) = bytes.split_at(8);
+        ReadingKey {
+            farmer_id: u64::from_be_bytes(farmer_id.try_into().unwrap()),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ReadingKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static DEVICES_STORAGE: RefCell<StableBTreeMap<u64, DeviceList, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static READINGS_STORAGE: RefCell<StableBTreeMap<ReadingKey, SensorReading, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    // Next sequence number to write, per farm
+    static READING_SEQ_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+}
+
+// Sensor_Reading Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SensorReadingPayload {
+    kind: SensorKind,
+    value: f64,
+    recorded_at: u64,
+}
+
+// Sensor_Batch Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SensorBatchPayload {
+    farmer_id: u64,
+    lot: Option<String>,
+    readings: Vec<SensorReadingPayload>,
+}
+
+// Function for a farmer to register a sensor device on their farm
+#[ic_cdk::update]
+fn register_device(farmer_id: u64, device: Principal) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can register devices".to_string());
+    }
+
+    let mut list = DEVICES_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .unwrap_or_default();
+
+    if list.devices.contains(&device) {
+        return Err("Device already registered".to_string());
+    }
+    if list.devices.len() >= MAX_DEVICES_PER_FARM {
+        return Err("Device limit reached".to_string());
+    }
+
+    list.devices.push(device);
+    DEVICES_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, list));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_device(farmer_id: u64, device: Principal) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can remove devices".to_string());
+    }
+
+    let mut list = DEVICES_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Device not registered".to_string())?;
+
+    let before = list.devices.len();
+    list.devices.retain(|d| *d != device);
+    if list.devices.len() == before {
+        return Err("Device not registered".to_string());
+    }
+
+    DEVICES_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, list));
+    Ok(())
+}
+
+// Checks a batch before any of it is written
+fn check_batch(payload: &SensorBatchPayload) -> Result<(), String> {
+    if payload.readings.is_empty() {
+        return Err("No readings provided".to_string());
+    }
+    if payload.readings.len() > MAX_BATCH_SIZE {
+        return Err("Too many readings in batch".to_string());
+    }
+    if payload
+        .lot
+        .as_ref()
+        .map_or(false, |lot| lot.len() > MAX_LOT_LEN)
+    {
+        return Err("Lot label is too long".to_string());
+    }
+    Ok(())
+}
+
+// Function for a registered device to push a batch of readings
+#[ic_cdk::update]
+fn ingest_sensor_readings(payload: SensorBatchPayload) -> Result<u64, String> {
+    let device = caller_account();
+
+    let registered = DEVICES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&payload.farmer_id)
+            .map_or(false, |list| list.devices.contains(&device))
+    });
+    if !registered {
+        return Err("Device not registered for this farm".to_string());
+    }
+
+    check_batch(&payload)?;
+
+    let received_at = ic_cdk::api::time();
+    let first_seq = READING_SEQ_STORAGE
+        .with(|storage| storage.borrow().get(&payload.farmer_id))
+        .unwrap_or(0);
+    let mut seq = first_seq;
+
+    READINGS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for reading in payload.readings {
+            // Overwrite the oldest slot once the farm's buffer is full
+            if seq >= MAX_READINGS_PER_FARM {
+                storage.remove(&ReadingKey {
+                    farmer_id: payload.farmer_id,
+                    seq: seq - MAX_READINGS_PER_FARM,
+                });
+            }
+
+            storage.insert(
+                ReadingKey {
+                    farmer_id: payload.farmer_id,
+                    seq,
+                },
+                SensorReading {
+                    farmer_id: payload.farmer_id,
+                    lot: payload.lot.clone(),
+                    device,
+                    kind: reading.kind,
+                    value: reading.value,
+                    recorded_at: reading.recorded_at,
+                    received_at,
+                },
+            );
+            seq += 1;
+        }
+    });
+
+    READING_SEQ_STORAGE.with(|storage| storage.borrow_mut().insert(payload.farmer_id, seq));
+
+    Ok(seq - first_seq)
+}
+
+// Returns the most recent readings for a farm, oldest first, optionally for a single lot
+#[ic_cdk::query]
+fn get_sensor_readings(farmer_id: u64, lot: Option<String>, limit: u64) -> Vec<SensorReading> {
+    let readings: Vec<SensorReading> = READINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(
+                ReadingKey { farmer_id, seq: 0 }..=ReadingKey {
+                    farmer_id,
+                    seq: u64::MAX,
+                },
+            )
+            .map(|(_, reading)| reading)
+            .filter(|reading| lot.is_none() || reading.lot == lot)
+            .collect()
+    });
+
+    let skip = readings.len().saturating_sub(limit as usize);
+    readings.into_iter().skip(skip).collect()
+}
+
+#[ic_cdk::query]
+fn get_devices(farmer_id: u64) -> Vec<Principal> {
+    DEVICES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&farmer_id)
+            .map(|list| list.devices)
+            .unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(lot: Option<String>, readings: usize) -> SensorBatchPayload {
+        SensorBatchPayload {
+            farmer_id: 1,
+            lot,
+            readings: (0..readings)
+                .map(|index| SensorReadingPayload {
+                    kind: SensorKind::SoilMoisture,
+                    value: 0.3,
+                    recorded_at: index as u64,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn batches_need_between_one_and_the_maximum_readings() {
+        assert!(check_batch(&batch(None, 1)).is_ok());
+        assert!(check_batch(&batch(None, MAX_BATCH_SIZE)).is_ok());
+        assert_eq!(
+            check_batch(&batch(None, 0)),
+            Err("No readings provided".to_string())
+        );
+        assert_eq!(
+            check_batch(&batch(None, MAX_BATCH_SIZE + 1)),
+            Err("Too many readings in batch".to_string())
+        );
+    }
+
+    #[test]
+    fn long_lot_labels_are_rejected() {
+        assert!(check_batch(&batch(Some("a".repeat(MAX_LOT_LEN)), 1)).is_ok());
+        assert_eq!(
+            check_batch(&batch(Some("a".repeat(MAX_LOT_LEN + 1)), 1)),
+            Err("Lot label is too long".to_string())
+        );
+    }
+
+    #[test]
+    fn the_longest_reading_fits_in_storage() {
+        let reading = SensorReading {
+            farmer_id: u64::MAX,
+            lot: Some("a".repeat(MAX_LOT_LEN)),
+            device: Principal::from_slice(&[0xff; 29]),
+            kind: SensorKind::StorageTemperature,
+            value: f64::MAX,
+            recorded_at: u64::MAX,
+            received_at: u64::MAX,
+        };
+        assert!(reading.to_bytes().len() <= SensorReading::MAX_SIZE as usize);
+    }
+}
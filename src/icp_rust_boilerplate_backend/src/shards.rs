@@ -0,0 +1,276 @@
+use crate::governance::is_governor;
+use crate::wasm_store::{create_child_canister, install_module};
+use crate::{
+    is_admin, FarmerPayload, IdCell, ListedProduct, Memory, FARMERS_STORAGE, ID_COUNTER,
+    MEMORY_MANAGER,
+};
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::management_canister::main::CanisterInstallMode;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+};
+
+// Listing IDs of shard N start at N times this, leaving the router's own IDs below it
+const SHARD_ID_STRIDE: u64 = 1 << 40;
+const SHARD_CYCLES: u128 = 2_000_000_000_000;
+const DEFAULT_LISTING_CAPACITY: u64 = 1_000_000;
+
+// Shard Struct
+// A child canister holding listings this canister no longer has room for
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Shard {
+    index: u64,
+    canister: Principal,
+    id_base: u64,
+    // Listings routed to the shard
+    listings: u64,
+    // Set once the module is installed and the shard has its ID range
+    ready: bool,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Shard
+impl Storable for Shard {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Shard {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SHARDS_STORAGE: RefCell<StableBTreeMap<u64, Shard, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(139)))
+    ));
+
+    // Listings one canister holds before new ones go to a shard
+    static LISTING_CAPACITY: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(140))), DEFAULT_LISTING_CAPACITY)
+            .expect("Cannot create the listing capacity cell")
+    );
+
+    // First listing ID of this canister when it is itself a shard; 0 on the router
+    static SHARD_ID_BASE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(141))), 0)
+            .expect("Cannot create the shard ID cell")
+    );
+
+    static SPAWNING: Cell<bool> = Cell::new(false);
+}
+
+fn listing_capacity() -> u64 {
+    LISTING_CAPACITY.with(|cell| *cell.borrow().get())
+}
+
+fn is_shard() -> bool {
+    SHARD_ID_BASE.with(|cell| *cell.borrow().get()) != 0
+}
+
+pub(crate) fn has_local_capacity() -> bool {
+    FARMERS_STORAGE.with(|storage| storage.borrow().len()) < listing_capacity()
+}
+
+// New listings go to shards once the router itself is full; shards never route further
+pub(crate) fn routes_new_listings() -> bool {
+    !is_shard() && !has_local_capacity()
+}
+
+fn save_shard(shard: Shard) {
+    SHARDS_STORAGE.with(|storage| storage.borrow_mut().insert(shard.index, shard));
+}
+
+// Shard holding a listing, if the ID is outside this canister's own range
+pub(crate) fn listing_shard(listing_id: u64) -> Option<Shard> {
+    if is_shard() || listing_id < SHARD_ID_STRIDE {
+        return None;
+    }
+    SHARDS_STORAGE.with(|storage| storage.borrow().get(&(listing_id / SHARD_ID_STRIDE)))
+}
+
+// Shards that finished setting up, in creation order
+pub(crate) fn ready_shards() -> Vec<Shard> {
+    SHARDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, shard)| shard)
+            .filter(|shard| shard.ready)
+            .collect()
+    })
+}
+
+// Calls a shard method that returns Result<R, String>
+pub(crate) async fn call_shard<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+    shard: &Shard,
+    method: &str,
+    args: A,
+) -> Result<R, String> {
+    let (result,): (Result<R, String>,) = ic_cdk::call(shard.canister, method, args)
+        .await
+        .map_err(|(code, message)| format!("Shard call failed: {:?} {}", code, message))?;
+    result
+}
+
+// Creates the next shard, or finishes setting up one whose earlier setup failed part way
+async fn spawn_shard() -> Result<Shard, String> {
+    if SPAWNING.with(|flag| flag.replace(true)) {
+        return Err("A shard is already being created; try again shortly".to_string());
+    }
+    let result = set_up_shard().await;
+    SPAWNING.with(|flag| flag.set(false));
+    result
+}
+
+async fn set_up_shard() -> Result<Shard, String> {
+    let pending = SHARDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, shard)| shard)
+            .find(|shard| !shard.ready)
+    });
+
+    let (mut shard, mode) = match pending {
+        Some(shard) => (shard, CanisterInstallMode::Reinstall),
+        None => {
+            let index = SHARDS_STORAGE.with(|storage| storage.borrow().len()) + 1;
+            let canister = create_child_canister(Vec::new(), SHARD_CYCLES).await?;
+            let shard = Shard {
+                index,
+                canister,
+                id_base: index * SHARD_ID_STRIDE,
+                listings: 0,
+                ready: false,
+                created_at: ic_cdk::api::time(),
+            };
+            // Recorded before installing so a failed setup resumes on this canister
+            save_shard(shard.clone());
+            (shard, CanisterInstallMode::Install)
+        }
+    };
+
+    install_module(shard.canister, mode).await?;
+    call_shard::<_, ()>(&shard, "configure_shard", (shard.id_base,)).await?;
+
+    shard.ready = true;
+    save_shard(shard.clone());
+    Ok(shard)
+}
+
+// Lists the product on the newest shard with room, creating a shard when none has any
+pub(crate) async fn route_listing(payload: FarmerPayload) -> Result<ListedProduct, String> {
+    let capacity = listing_capacity();
+    let open = SHARDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, shard)| shard)
+            .filter(|shard| shard.ready && shard.listings < capacity)
+            .last()
+    });
+    let shard = match open {
+        Some(shard) => shard,
+        None => spawn_shard().await?,
+    };
+
+    let listed: ListedProduct = call_shard(&shard, "add_product", (payload,)).await?;
+    // Re-read the shard: other listings may have been routed during the call
+    if let Some(mut shard) = SHARDS_STORAGE.with(|storage| storage.borrow().get(&shard.index)) {
+        shard.listings += 1;
+        save_shard(shard);
+    }
+    Ok(listed)
+}
+
+// Function for the router, as a controller, to give a new shard its listing ID range
+#[ic_cdk::update]
+fn configure_shard(id_base: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only a controller can configure a shard".to_string());
+    }
+    if id_base == 0 || id_base % SHARD_ID_STRIDE != 0 {
+        return Err("Invalid shard ID base".to_string());
+    }
+
+    SHARD_ID_BASE
+        .with(|cell| cell.borrow_mut().set(id_base))
+        .map_err(|e| format!("{:?}", e))?;
+    ID_COUNTER
+        .with(|cell| {
+            let current = *cell.borrow().get();
+            cell.borrow_mut().set(current.max(id_base))
+        })
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::update]
+pub(crate) fn set_listing_capacity(capacity: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can set the listing capacity".to_string());
+    }
+    if capacity == 0 {
+        return Err("Capacity must be positive".to_string());
+    }
+
+    LISTING_CAPACITY
+        .with(|cell| cell.borrow_mut().set(capacity))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// Function for an administrator to create a shard ahead of need, or finish one whose
+// setup failed
+#[ic_cdk::update]
+async fn create_shard() -> Result<Shard, String> {
+    if !is_admin() {
+        return Err("Only an administrator can create shards".to_string());
+    }
+    spawn_shard().await
+}
+
+// Function for an administrator to upgrade every ready shard to the uploaded module
+#[ic_cdk::update]
+async fn upgrade_shards() -> Result<Vec<Result<(), String>>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can upgrade shards".to_string());
+    }
+    let shards: Vec<Shard> = SHARDS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, shard)| shard)
+            .filter(|shard| shard.ready)
+            .collect()
+    });
+
+    let mut results = Vec::new();
+    for shard in shards {
+        results.push(install_module(shard.canister, CanisterInstallMode::Upgrade).await);
+    }
+    Ok(results)
+}
+
+#[ic_cdk::query]
+fn list_shards() -> Vec<Shard> {
+    SHARDS_STORAGE.with(|storage| storage.borrow().iter().map(|(_, shard)| shard).collect())
+}
+
+// Canister to send a listing's update calls to; None if this canister holds it
+#[ic_cdk::query]
+fn get_listing_canister(listing_id: u64) -> Option<Principal> {
+    listing_shard(listing_id).map(|shard| shard.canister)
+}
@@ -0,0 +1,242 @@
+use crate::devices::caller_account;
+use crate::installments::has_installment_plan;
+use crate::ledger::Asset;
+use crate::logistics::{get_partner_record, partners, LogisticsPartner};
+use crate::orders::{get_order_record, save_order, Order, OrderStatus};
+use crate::{is_admin, is_farmer_owner, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REGION_LEN: usize = 50;
+
+// RegionKey Struct
+// A normalised region name, or a pair of them for a route
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RegionKey(String);
+
+// ShippingQuote Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ShippingQuote {
+    partner: Principal,
+    partner_name: String,
+    distance_km: u64,
+    cost: u64,
+}
+
+// ShippingLine Struct
+// The delivery charge a payer chose for an order
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ShippingLine {
+    pub(crate) partner: Principal,
+    pub(crate) destination: String,
+    pub(crate) distance_km: u64,
+    pub(crate) cost: u64,
+}
+
+// Storable and BoundedStorable implementations for RegionKey
+impl Storable for RegionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RegionKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for RegionKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LISTING_REGIONS_STORAGE: RefCell<StableBTreeMap<u64, RegionKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // Road distance in km between two regions, keyed by the sorted pair
+    static REGION_DISTANCES_STORAGE: RefCell<StableBTreeMap<RegionKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+}
+
+pub(crate) fn normalise_region(region: &str) -> Result<String, String> {
+    let region = region.trim().to_lowercase();
+    if region.is_empty() || region.len() > MAX_REGION_LEN || region.contains('|') {
+        return Err("Invalid region".to_string());
+    }
+    Ok(region)
+}
+
+fn route_key(a: &str, b: &str) -> RegionKey {
+    if a <= b {
+        RegionKey(format!("{}|{}", a, b))
+    } else {
+        RegionKey(format!("{}|{}", b, a))
+    }
+}
+
+pub(crate) fn route_distance(origin: &str, destination: &str) -> Result<u64, String> {
+    if origin == destination {
+        return Ok(0);
+    }
+    REGION_DISTANCES_STORAGE
+        .with(|storage| storage.borrow().get(&route_key(origin, destination)))
+        .ok_or("No known distance between these regions".to_string())
+}
+
+pub(crate) fn listing_region(farmer_id: u64) -> Result<String, String> {
+    LISTING_REGIONS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .map(|region| region.0)
+        .ok_or("Listing has no region set".to_string())
+}
+
+pub(crate) fn save_listing_region(farmer_id: u64, region: String) {
+    LISTING_REGIONS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(farmer_id, RegionKey(region)));
+}
+
+// What the partner charges to carry `quantity` units over the route, if it serves it
+fn partner_cost(
+    partner: &LogisticsPartner,
+    origin: &str,
+    destination: &str,
+    distance_km: u64,
+    quantity: u64,
+) -> Option<u64> {
+    if !partner.covers(origin) || !partner.covers(destination) {
+        return None;
+    }
+    if quantity > partner.vehicle_capacity {
+        return None;
+    }
+    partner
+        .rates
+        .per_km
+        .checked_mul(distance_km)?
+        .checked_add(partner.rates.per_unit.checked_mul(quantity)?)?
+        .checked_add(partner.rates.base_fee)
+}
+
+// Function for a farmer to set the region a listing ships from
+#[ic_cdk::update]
+fn set_listing_region(farmer_id: u64, region: String) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can set the listing region".to_string());
+    }
+
+    save_listing_region(farmer_id, normalise_region(&region)?);
+    Ok(())
+}
+
+// Function for an administrator to record the distance between two regions
+#[ic_cdk::update]
+fn set_region_distance(region_a: String, region_b: String, km: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set region distances".to_string());
+    }
+
+    let (region_a, region_b) = (normalise_region(&region_a)?, normalise_region(&region_b)?);
+    if region_a == region_b {
+        return Err("Regions must differ".to_string());
+    }
+
+    REGION_DISTANCES_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(route_key(&region_a, &region_b), km)
+    });
+    Ok(())
+}
+
+// Quotes every logistics partner able to carry the quantity from the listing to the destination,
+// cheapest first
+#[ic_cdk::query]
+fn estimate_delivery_cost(
+    product_id: u64,
+    quantity: u64,
+    destination: String,
+) -> Result<Vec<ShippingQuote>, String> {
+    let origin = listing_region(product_id)?;
+    let destination = normalise_region(&destination)?;
+    let distance_km = route_distance(&origin, &destination)?;
+
+    let mut quotes: Vec<ShippingQuote> = partners()
+        .into_iter()
+        .filter_map(|partner| {
+            let cost = partner_cost(&partner, &origin, &destination, distance_km, quantity)?;
+            Some(ShippingQuote {
+                partner: partner.principal,
+                partner_name: partner.name,
+                distance_km,
+                cost,
+            })
+        })
+        .collect();
+    quotes.sort_by_key(|quote| quote.cost);
+    Ok(quotes)
+}
+
+// Function for the payer to add a chosen partner's delivery charge to an unfunded order
+#[ic_cdk::update]
+fn add_order_shipping(
+    order_id: u64,
+    partner: Principal,
+    destination: String,
+) -> Result<Order, String> {
+    let mut order = get_order_record(order_id)?;
+
+    if order.payer() != caller_account() {
+        return Err("Only the payer can choose shipping".to_string());
+    }
+    if order.status != OrderStatus::Pending || order.escrowed > 0 {
+        return Err("Shipping can only be added before funding".to_string());
+    }
+    if order.currency() != Asset::Icp {
+        return Err("Shipping can only be added to orders in the default currency".to_string());
+    }
+    if has_installment_plan(order.id) {
+        return Err("Order is paid in installments".to_string());
+    }
+    if order.shipping.is_some() {
+        return Err("Order already has shipping".to_string());
+    }
+
+    let product_id = order.product_id.ok_or("Order has no listing".to_string())?;
+    let origin = listing_region(product_id)?;
+    let destination = normalise_region(&destination)?;
+    let distance_km = route_distance(&origin, &destination)?;
+    let partner_record =
+        get_partner_record(&partner).ok_or("Logistics partner not found".to_string())?;
+    let cost = partner_cost(
+        &partner_record,
+        &origin,
+        &destination,
+        distance_km,
+        order.quantity,
+    )
+    .ok_or("Partner cannot serve this delivery".to_string())?;
+
+    order.total = order
+        .total
+        .checked_add(cost)
+        .ok_or("Order total overflows".to_string())?;
+    order.shipping = Some(ShippingLine {
+        partner,
+        destination,
+        distance_km,
+        cost,
+    });
+
+    save_order(order.clone());
+    Ok(order)
+}
@@ -0,0 +1,374 @@
+use crate::guard::{is_paused, require_upgrade, PAUSE_MEMORY_ID};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Memory as _, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+// Highest memory ID the memory manager hands out
+const MAX_MEMORY_ID: u8 = 254;
+const SNAPSHOT_MANIFEST_MEMORY_ID: u8 = 133;
+const SNAPSHOT_DATA_MEMORY_ID: u8 = 134;
+const RESTORE_MANIFEST_MEMORY_ID: u8 = 136;
+const MAX_CHUNK_SIZE: u64 = 1_048_576;
+
+// SnapshotSegment Struct
+// One virtual memory captured in a snapshot
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SnapshotSegment {
+    pub(crate) memory_id: u8,
+    // Where the memory's bytes start in the snapshot data
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) sha256: Vec<u8>,
+}
+
+// SnapshotManifest Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct SnapshotManifest {
+    pub(crate) id: u64,
+    pub(crate) created_at: u64,
+    pub(crate) segments: Vec<SnapshotSegment>,
+    pub(crate) total_length: u64,
+    // sha256 over every segment's memory ID and hash, in order
+    pub(crate) sha256: Vec<u8>,
+}
+
+// Storable implementation for SnapshotManifest
+impl Storable for SnapshotManifest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    // Manifest of the latest snapshot; ID 0 means none has been taken
+    static SNAPSHOT_MANIFEST: RefCell<Cell<SnapshotManifest, Memory>> = RefCell::new(
+        Cell::init(snapshot_memory(SNAPSHOT_MANIFEST_MEMORY_ID), SnapshotManifest::default())
+            .expect("Cannot create the snapshot manifest")
+    );
+
+    // Manifest of the restore in progress; ID 0 means none
+    static RESTORE_MANIFEST: RefCell<Cell<SnapshotManifest, Memory>> = RefCell::new(
+        Cell::init(snapshot_memory(RESTORE_MANIFEST_MEMORY_ID), SnapshotManifest::default())
+            .expect("Cannot create the restore manifest")
+    );
+}
+
+fn snapshot_memory(memory_id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
+
+// The snapshot machinery's own memories and the pause switch are never captured or restored
+pub(crate) fn is_snapshot_memory(memory_id: u8) -> bool {
+    memory_id == SNAPSHOT_MANIFEST_MEMORY_ID
+        || memory_id == SNAPSHOT_DATA_MEMORY_ID
+        || memory_id == RESTORE_MANIFEST_MEMORY_ID
+        || memory_id == PAUSE_MEMORY_ID
+}
+
+pub(crate) fn data_memory() -> Memory {
+    snapshot_memory(SNAPSHOT_DATA_MEMORY_ID)
+}
+
+pub(crate) fn latest_manifest() -> SnapshotManifest {
+    SNAPSHOT_MANIFEST.with(|cell| cell.borrow().get().clone())
+}
+
+// Grows the memory, if needed, so that it holds at least `length` bytes
+pub(crate) fn ensure_capacity(memory: &Memory, length: u64) -> Result<(), String> {
+    let pages = (length + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    if memory.size() < pages && memory.grow(pages - memory.size()) < 0 {
+        return Err("Out of stable memory".to_string());
+    }
+    Ok(())
+}
+
+// Copies `length` bytes between memories page by page, returning their sha256
+pub(crate) fn copy_memory(
+    from: &Memory,
+    from_offset: u64,
+    to: &Memory,
+    to_offset: u64,
+    length: u64,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; WASM_PAGE_SIZE as usize];
+    let mut copied = 0;
+
+    while copied < length {
+        let size = (length - copied).min(WASM_PAGE_SIZE) as usize;
+        from.read(from_offset + copied, &mut buffer[..size]);
+        to.write(to_offset + copied, &buffer[..size]);
+        hasher.update(&buffer[..size]);
+        copied += size as u64;
+    }
+    hasher.finalize().to_vec()
+}
+
+pub(crate) fn hash_memory(memory: &Memory, offset: u64, length: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; WASM_PAGE_SIZE as usize];
+    let mut read = 0;
+
+    while read < length {
+        let size = (length - read).min(WASM_PAGE_SIZE) as usize;
+        memory.read(offset + read, &mut buffer[..size]);
+        hasher.update(&buffer[..size]);
+        read += size as u64;
+    }
+    hasher.finalize().to_vec()
+}
+
+// Overwrites the memory with zeros from `offset` to its end
+fn zero_memory(memory: &Memory, offset: u64) {
+    let end = memory.size() * WASM_PAGE_SIZE;
+    let zeros = vec![0u8; WASM_PAGE_SIZE as usize];
+    let mut at = offset;
+
+    while at < end {
+        let size = (end - at).min(WASM_PAGE_SIZE) as usize;
+        memory.write(at, &zeros[..size]);
+        at += size as u64;
+    }
+}
+
+pub(crate) fn manifest_hash(segments: &[SnapshotSegment]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for segment in segments {
+        hasher.update([segment.memory_id]);
+        hasher.update(&segment.sha256);
+    }
+    hasher.finalize().to_vec()
+}
+
+// Function for a controller to snapshot every stable map, cell and counter. All memories are
+// copied into the snapshot area within this one message, so the snapshot is consistent and
+// stays downloadable while the marketplace keeps running. Only the latest snapshot is kept.
+#[ic_cdk::update]
+fn create_snapshot() -> Result<SnapshotManifest, String> {
+    if !is_admin() {
+        return Err("Only a controller can take snapshots".to_string());
+    }
+
+    let data = data_memory();
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    for memory_id in 0..=MAX_MEMORY_ID {
+        if is_snapshot_memory(memory_id) {
+            continue;
+        }
+        let memory = snapshot_memory(memory_id);
+        let length = memory.size() * WASM_PAGE_SIZE;
+        if length == 0 {
+            continue;
+        }
+
+        ensure_capacity(&data, offset + length)?;
+        let sha256 = copy_memory(&memory, 0, &data, offset, length);
+        segments.push(SnapshotSegment {
+            memory_id,
+            offset,
+            length,
+            sha256,
+        });
+        offset += length;
+    }
+
+    let manifest = SnapshotManifest {
+        id: latest_manifest().id + 1,
+        created_at: ic_cdk::api::time(),
+        sha256: manifest_hash(&segments),
+        segments,
+        total_length: offset,
+    };
+    SNAPSHOT_MANIFEST
+        .with(|cell| cell.borrow_mut().set(manifest.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(manifest)
+}
+
+#[ic_cdk::query]
+fn get_snapshot_manifest() -> Result<SnapshotManifest, String> {
+    if !is_admin() {
+        return Err("Only a controller can read snapshots".to_string());
+    }
+    let manifest = latest_manifest();
+    if manifest.id == 0 {
+        return Err("No snapshot has been taken".to_string());
+    }
+    Ok(manifest)
+}
+
+// Function for a controller to download a snapshot's data, at most 1 MiB per call. The
+// snapshot ID guards against reading a newer snapshot halfway through a download.
+#[ic_cdk::query]
+fn get_snapshot_chunk(snapshot_id: u64, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+    if !is_admin() {
+        return Err("Only a controller can read snapshots".to_string());
+    }
+    let manifest = latest_manifest();
+    if manifest.id == 0 || manifest.id != snapshot_id {
+        return Err("Snapshot not found".to_string());
+    }
+    if length > MAX_CHUNK_SIZE {
+        return Err("Chunks are at most 1 MiB".to_string());
+    }
+    if offset > manifest.total_length {
+        return Err("Offset is past the end of the snapshot".to_string());
+    }
+
+    let mut chunk = vec![0u8; length.min(manifest.total_length - offset) as usize];
+    data_memory().read(offset, &mut chunk);
+    Ok(chunk)
+}
+
+fn pending_restore() -> Result<SnapshotManifest, String> {
+    let manifest = RESTORE_MANIFEST.with(|cell| cell.borrow().get().clone());
+    if manifest.id == 0 {
+        return Err("No restore is in progress".to_string());
+    }
+    Ok(manifest)
+}
+
+fn check_restore_allowed() -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only a controller can restore snapshots".to_string());
+    }
+    if !is_paused() {
+        return Err("Pause the marketplace before restoring".to_string());
+    }
+    Ok(())
+}
+
+// Checks that the segments are laid out back to back, cover whole pages of distinct
+// memories and match the manifest hash
+fn validate_manifest(manifest: &SnapshotManifest) -> Result<(), String> {
+    if manifest.id == 0 {
+        return Err("Snapshot ID must not be 0".to_string());
+    }
+
+    let mut offset = 0;
+    let mut previous_id = None;
+    for segment in manifest.segments.iter() {
+        if is_snapshot_memory(segment.memory_id) || segment.memory_id > MAX_MEMORY_ID {
+            return Err(format!("Memory {} cannot be restored", segment.memory_id));
+        }
+        if previous_id.map_or(false, |id| segment.memory_id <= id) {
+            return Err("Segments must be in increasing memory ID order".to_string());
+        }
+        if segment.offset != offset || segment.length % WASM_PAGE_SIZE != 0 {
+            return Err(format!(
+                "Segment for memory {} is misplaced",
+                segment.memory_id
+            ));
+        }
+        previous_id = Some(segment.memory_id);
+        offset += segment.length;
+    }
+
+    if offset != manifest.total_length {
+        return Err("Total length does not match the segments".to_string());
+    }
+    if manifest_hash(&manifest.segments) != manifest.sha256 {
+        return Err("Manifest hash does not match the segments".to_string());
+    }
+    Ok(())
+}
+
+// Function for a controller to start restoring a snapshot while the marketplace is paused.
+// Passing the latest snapshot's own manifest restores it as is; any other manifest replaces
+// the latest snapshot and its data must be uploaded with `upload_restore_chunk` first.
+#[ic_cdk::update]
+fn begin_restore(manifest: SnapshotManifest) -> Result<(), String> {
+    check_restore_allowed()?;
+    validate_manifest(&manifest)?;
+
+    let latest = latest_manifest();
+    if latest.id != manifest.id || latest.sha256 != manifest.sha256 {
+        // The upload overwrites the snapshot area, so the latest snapshot is gone
+        SNAPSHOT_MANIFEST
+            .with(|cell| cell.borrow_mut().set(SnapshotManifest::default()))
+            .map_err(|e| format!("{:?}", e))?;
+    }
+    RESTORE_MANIFEST
+        .with(|cell| cell.borrow_mut().set(manifest))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// Function for a controller to upload snapshot data for the restore in progress, at most
+// 1 MiB per call
+#[ic_cdk::update]
+fn upload_restore_chunk(offset: u64, data: Vec<u8>) -> Result<(), String> {
+    check_restore_allowed()?;
+    let manifest = pending_restore()?;
+    if data.len() as u64 > MAX_CHUNK_SIZE {
+        return Err("Chunks are at most 1 MiB".to_string());
+    }
+    if offset + data.len() as u64 > manifest.total_length {
+        return Err("Chunk runs past the end of the snapshot".to_string());
+    }
+
+    let memory = data_memory();
+    ensure_capacity(&memory, offset + data.len() as u64)?;
+    memory.write(offset, &data);
+    Ok(())
+}
+
+// Function for a controller to finish a restore. Every segment is checked against its hash
+// before anything is touched; then each live memory is overwritten with its snapshot copy and
+// the rest of it zeroed. The maps cached on the heap are stale afterwards, so the marketplace
+// refuses all calls until the canister is upgraded, which reloads them from stable memory.
+#[ic_cdk::update]
+fn commit_restore() -> Result<SnapshotManifest, String> {
+    check_restore_allowed()?;
+    let manifest = pending_restore()?;
+
+    let data = data_memory();
+    if data.size() * WASM_PAGE_SIZE < manifest.total_length {
+        return Err("Snapshot data has not been fully uploaded".to_string());
+    }
+    for segment in manifest.segments.iter() {
+        if hash_memory(&data, segment.offset, segment.length) != segment.sha256 {
+            return Err(format!(
+                "Data for memory {} does not match its hash",
+                segment.memory_id
+            ));
+        }
+    }
+    // Memories cannot shrink, so one created after the snapshot cannot be emptied again
+    for memory_id in 0..=MAX_MEMORY_ID {
+        if !is_snapshot_memory(memory_id)
+            && snapshot_memory(memory_id).size() > 0
+            && !manifest.segments.iter().any(|s| s.memory_id == memory_id)
+        {
+            return Err(format!(
+                "Memory {} holds data missing from the snapshot",
+                memory_id
+            ));
+        }
+    }
+
+    for segment in manifest.segments.iter() {
+        let memory = snapshot_memory(segment.memory_id);
+        ensure_capacity(&memory, segment.length)?;
+        copy_memory(&data, segment.offset, &memory, 0, segment.length);
+        zero_memory(&memory, segment.length);
+    }
+
+    SNAPSHOT_MANIFEST
+        .with(|cell| cell.borrow_mut().set(manifest.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+    RESTORE_MANIFEST
+        .with(|cell| cell.borrow_mut().set(SnapshotManifest::default()))
+        .map_err(|e| format!("{:?}", e))?;
+    require_upgrade();
+    Ok(manifest)
+}
@@ -0,0 +1,207 @@
+use crate::devices::caller_account;
+use crate::inventory::{available_stock, reserve_stock};
+use crate::orders::{create_order, get_order_record, save_order, Order, OrderDraft, OrderStatus};
+use crate::pricing::unit_price_for;
+use crate::taxes::apply_taxes;
+use crate::{
+    farmer_principal, is_off_market, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CHILD_ORDERS: usize = 10;
+
+// SplitOrder Struct
+// Parent of the child orders a large request was split into
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SplitOrder {
+    id: u64,
+    consumer: Principal,
+    category: String,
+    requested_quantity: u64,
+    order_ids: Vec<u64>,
+    created_at: u64,
+}
+
+// SplitOrderSummary Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SplitOrderSummary {
+    split: SplitOrder,
+    orders: Vec<Order>,
+    total: u64,
+    escrowed: u64,
+    delivered_quantity: u64,
+}
+
+// Storable and BoundedStorable implementations for SplitOrder
+impl Storable for SplitOrder {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SplitOrder {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SPLIT_ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SPLIT_ORDERS_STORAGE: RefCell<StableBTreeMap<u64, SplitOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+}
+
+// Allocation Struct
+// One listing's share of a split request
+struct Allocation {
+    farmer_id: u64,
+    farmer: Principal,
+    quantity: u64,
+    unit_price: u64,
+}
+
+// Fills `quantity` from stocked listings of the category, cheapest first
+fn allocate(
+    consumer: Principal,
+    category: &str,
+    quantity: u64,
+    max_unit_price: Option<u64>,
+) -> Result<Vec<Allocation>, String> {
+    let mut candidates: Vec<(u64, u64, Principal, u64)> = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .filter(|farmer| !farmer.is_sold && !is_off_market(farmer))
+            .filter(|farmer| farmer.category.eq_ignore_ascii_case(category))
+            .filter_map(|farmer| {
+                let owner = farmer_principal(&farmer).ok()?;
+                let stock = available_stock(farmer.id)?;
+                let base_price = unit_price_for(&farmer, 1).ok()?;
+                if owner == consumer || stock == 0 {
+                    return None;
+                }
+                Some((base_price, farmer.id, owner, stock))
+            })
+            .collect()
+    });
+    candidates.sort();
+
+    let mut allocations = Vec::new();
+    let mut remaining = quantity;
+    for (_, farmer_id, owner, stock) in candidates {
+        if remaining == 0 || allocations.len() >= MAX_CHILD_ORDERS {
+            break;
+        }
+
+        let share = remaining.min(stock);
+        let listing = FARMERS_STORAGE.with(|storage| storage.borrow().get(&farmer_id));
+        let unit_price = match listing.map(|farmer| unit_price_for(&farmer, share)) {
+            Some(Ok(unit_price)) => unit_price,
+            _ => continue,
+        };
+        if max_unit_price.map_or(false, |max| unit_price > max) {
+            continue;
+        }
+        unit_price
+            .checked_mul(share)
+            .ok_or("Order total overflows".to_string())?;
+
+        allocations.push(Allocation {
+            farmer_id,
+            farmer: owner,
+            quantity: share,
+            unit_price,
+        });
+        remaining -= share;
+    }
+
+    if remaining > 0 {
+        return Err("Not enough stock across matching listings".to_string());
+    }
+    Ok(allocations)
+}
+
+// Function for a consumer to order a quantity of a category that no single listing can cover;
+// it is split over the cheapest stocked listings, one child order each
+#[ic_cdk::update]
+fn place_split_order(
+    category: String,
+    quantity: u64,
+    max_unit_price: Option<u64>,
+) -> Result<SplitOrder, String> {
+    let consumer = caller_account();
+    if quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+
+    let allocations = allocate(consumer, &category, quantity, max_unit_price)?;
+
+    let mut order_ids = Vec::with_capacity(allocations.len());
+    for allocation in allocations {
+        reserve_stock(allocation.farmer_id, allocation.quantity)?;
+        let mut order = create_order(OrderDraft {
+            product_id: Some(allocation.farmer_id),
+            contract_id: None,
+            farmer: allocation.farmer,
+            consumer,
+            quantity: allocation.quantity,
+            unit_price: allocation.unit_price,
+            due_at: None,
+        })?;
+        apply_taxes(&mut order)?;
+        order_ids.push(order.id);
+        save_order(order);
+    }
+
+    let split = SplitOrder {
+        id: next_id(&SPLIT_ORDER_ID_COUNTER),
+        consumer,
+        category,
+        requested_quantity: quantity,
+        order_ids,
+        created_at: ic_cdk::api::time(),
+    };
+    SPLIT_ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(split.id, split.clone()));
+    Ok(split)
+}
+
+#[ic_cdk::query]
+fn get_split_order(split_id: u64) -> Result<SplitOrderSummary, String> {
+    let split = SPLIT_ORDERS_STORAGE
+        .with(|storage| storage.borrow().get(&split_id))
+        .ok_or("Split order not found".to_string())?;
+
+    let orders: Vec<Order> = split
+        .order_ids
+        .iter()
+        .filter_map(|order_id| get_order_record(*order_id).ok())
+        .collect();
+    let total = orders.iter().map(|order| order.total).sum();
+    let escrowed = orders.iter().map(|order| order.escrowed).sum();
+    let delivered_quantity = orders
+        .iter()
+        .filter(|order| order.status == OrderStatus::Completed)
+        .map(|order| order.quantity)
+        .sum();
+
+    Ok(SplitOrderSummary {
+        split,
+        orders,
+        total,
+        escrowed,
+        delivered_quantity,
+    })
+}
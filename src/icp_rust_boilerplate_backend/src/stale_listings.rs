@@ -0,0 +1,324 @@
+use crate::archive::archive_canister;
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::tombstones::is_deleted;
+use crate::traceability::{last_activity_at, record_provenance};
+use crate::{
+    farmer_principal, is_admin, is_farmer_owner, Farmer, IdCell, Memory, FARMERS_STORAGE,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Listings untouched for about six months are flagged unless an administrator sets otherwise
+const DEFAULT_STALE_AFTER_DAYS: u64 = 180;
+const MIN_STALE_AFTER_DAYS: u64 = 30;
+// Time the owner has to confirm a flagged listing before it is archived
+const CONFIRM_WINDOW_NANOS: u64 = 14 * DAY_NANOS;
+const MAX_STALE_BATCH: usize = 100;
+
+// StaleListing Struct
+// A listing flagged for having no activity, waiting for its owner to confirm it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct StaleListing {
+    listing_id: u64,
+    owner: Principal,
+    flagged_at: u64,
+    archive_after: u64,
+}
+
+// ArchivedListing Struct
+// What the marketplace keeps of a listing once the full record is in the archive canister
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ArchivedListing {
+    listing_id: u64,
+    owner: Principal,
+    name: String,
+    archived_at: u64,
+}
+
+// Storable and BoundedStorable implementations for StaleListing
+impl Storable for StaleListing {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaleListing {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ArchivedListing
+impl Storable for ArchivedListing {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchivedListing {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static STALE_AFTER_DAYS: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(175))), DEFAULT_STALE_AFTER_DAYS)
+            .expect("Cannot create the stale listing cell")
+    );
+
+    static STALE_LISTINGS_STORAGE: RefCell<StableBTreeMap<u64, StaleListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(176)))
+    ));
+
+    static ARCHIVED_LISTINGS_STORAGE: RefCell<StableBTreeMap<u64, ArchivedListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(177)))
+    ));
+}
+
+fn stale_after_days() -> u64 {
+    STALE_AFTER_DAYS.with(|cell| *cell.borrow().get())
+}
+
+fn stale_flag(listing_id: u64) -> Option<StaleListing> {
+    STALE_LISTINGS_STORAGE.with(|storage| storage.borrow().get(&listing_id))
+}
+
+fn remove_flag(listing_id: u64) {
+    STALE_LISTINGS_STORAGE.with(|storage| storage.borrow_mut().remove(&listing_id));
+}
+
+// Unsold listings nobody is buying or disputing, with no lifecycle event since the cutoff
+fn is_stale(farmer: &Farmer, cutoff: u64) -> bool {
+    !farmer.is_sold
+        && farmer.consumer_address.is_none()
+        && !farmer.dispute_status
+        && !is_deleted(farmer.id)
+        && last_activity_at(farmer.id).unwrap_or(0) < cutoff
+}
+
+// A flag lapses once the listing sees activity again or stops qualifying
+fn flag_holds(flag: &StaleListing) -> Option<Farmer> {
+    let farmer = FARMERS_STORAGE.with(|storage| storage.borrow().get(&flag.listing_id))?;
+    is_stale(&farmer, flag.flagged_at).then_some(farmer)
+}
+
+// Flags listings untouched for the configured period and tells their owners, and drops
+// flags on listings that were touched since
+pub(crate) fn flag_stale_listings() {
+    let flags: Vec<StaleListing> = STALE_LISTINGS_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, flag)| flag).collect());
+    for flag in flags {
+        if flag_holds(&flag).is_none() {
+            remove_flag(flag.listing_id);
+        }
+    }
+
+    let now = ic_cdk::api::time();
+    let cutoff = now.saturating_sub(stale_after_days().saturating_mul(DAY_NANOS));
+    let candidates: Vec<Farmer> = FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, farmer)| farmer)
+            .filter(|farmer| is_stale(farmer, cutoff) && stale_flag(farmer.id).is_none())
+            .take(MAX_STALE_BATCH)
+            .collect()
+    });
+
+    for farmer in candidates {
+        let owner = match farmer_principal(&farmer) {
+            Ok(owner) => owner,
+            Err(_) => continue,
+        };
+        let flag = StaleListing {
+            listing_id: farmer.id,
+            owner,
+            flagged_at: now,
+            archive_after: now + CONFIRM_WINDOW_NANOS,
+        };
+        STALE_LISTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer.id, flag));
+        notify(
+            owner,
+            format!(
+                "Your listing #{} looks stale. Confirm it within 14 days or it will be archived",
+                farmer.id
+            ),
+        );
+    }
+}
+
+// Moves flagged listings whose owners did not confirm them to the archive canister and
+// returns how many moved. A listing is only removed if it was not touched while the call
+// was in flight.
+pub(crate) async fn archive_stale_listings() -> Result<u64, String> {
+    let archive = archive_canister()?;
+    let now = ic_cdk::api::time();
+    let due: Vec<(StaleListing, Farmer)> = STALE_LISTINGS_STORAGE
+        .with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, flag)| flag)
+                .filter(|flag| flag.archive_after <= now)
+                .take(MAX_STALE_BATCH)
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .filter_map(|flag| flag_holds(&flag).map(|farmer| (flag, farmer)))
+        .collect();
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let records: Vec<(u64, Vec<u8>)> = due
+        .iter()
+        .map(|(_, farmer)| (farmer.id, farmer.to_bytes().into_owned()))
+        .collect();
+    let (result,): (Result<(), String>,) = ic_cdk::call(archive, "append_listings", (records,))
+        .await
+        .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    result?;
+
+    let archived_at = ic_cdk::api::time();
+    let mut moved = 0;
+    for (flag, farmer) in due {
+        match flag_holds(&flag) {
+            Some(current) if current.to_bytes() == farmer.to_bytes() => {}
+            _ => continue,
+        }
+        let summary = ArchivedListing {
+            listing_id: farmer.id,
+            owner: flag.owner,
+            name: farmer.name,
+            archived_at,
+        };
+        FARMERS_STORAGE.with(|storage| storage.borrow_mut().remove(&farmer.id));
+        ARCHIVED_LISTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer.id, summary));
+        remove_flag(farmer.id);
+        notify(
+            flag.owner,
+            format!("Your listing #{} was archived", farmer.id),
+        );
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+// Timer job; does nothing until an archive canister is configured
+pub(crate) async fn archive_due_listings() {
+    let _ = archive_stale_listings().await;
+}
+
+// Function for the owner to keep a flagged listing on the market
+#[ic_cdk::update]
+fn confirm_listing(listing_id: u64) -> Result<(), String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Farmer not found".to_string())?;
+    if !is_farmer_owner(&farmer) {
+        return Err("Only the farmer can confirm this listing".to_string());
+    }
+    if stale_flag(listing_id).is_none() {
+        return Err("Listing is not flagged as stale".to_string());
+    }
+
+    remove_flag(listing_id);
+    record_provenance(listing_id, "Listing Confirmed");
+    Ok(())
+}
+
+// Function for the owner or an administrator to bring an archived listing back
+#[ic_cdk::update]
+async fn restore_archived_listing(listing_id: u64) -> Result<(), String> {
+    let summary = ARCHIVED_LISTINGS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Listing is not archived".to_string())?;
+    if summary.owner != caller_account() && !is_admin() {
+        return Err("Only the farmer or an administrator can restore this listing".to_string());
+    }
+
+    let (result,): (Result<Option<Vec<u8>>, String>,) =
+        ic_cdk::call(archive_canister()?, "get_listing", (listing_id,))
+            .await
+            .map_err(|(code, message)| format!("Archive call failed: {:?} {}", code, message))?;
+    let bytes = result?.ok_or("Listing is missing from the archive".to_string())?;
+    let farmer = Decode!(&bytes, Farmer)
+        .map_err(|e| format!("Cannot decode the archived listing: {}", e))?;
+
+    // Another call may have restored it while this one was waiting
+    if ARCHIVED_LISTINGS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&listing_id))
+        .is_none()
+    {
+        return Err("Listing is not archived".to_string());
+    }
+    FARMERS_STORAGE.with(|storage| storage.borrow_mut().insert(listing_id, farmer));
+    record_provenance(listing_id, "Restored");
+    Ok(())
+}
+
+// Function for an administrator to set how many days without activity make a listing stale
+#[ic_cdk::update]
+fn set_stale_after_days(days: u64) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can set the stale listing period".to_string());
+    }
+    if days < MIN_STALE_AFTER_DAYS {
+        return Err(format!(
+            "Listings must be untouched for at least {} days",
+            MIN_STALE_AFTER_DAYS
+        ));
+    }
+
+    STALE_AFTER_DAYS
+        .with(|cell| cell.borrow_mut().set(days))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[ic_cdk::query]
+fn get_stale_after_days() -> u64 {
+    stale_after_days()
+}
+
+// The caller's listings that are flagged as stale
+#[ic_cdk::query]
+fn get_my_stale_listings() -> Vec<StaleListing> {
+    let caller = caller_account();
+    STALE_LISTINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, flag)| flag)
+            .filter(|flag| flag.owner == caller)
+            .collect()
+    })
+}
+
+// The caller's listings that were moved to the archive
+#[ic_cdk::query]
+fn get_my_archived_listings() -> Vec<ArchivedListing> {
+    let caller = caller_account();
+    ARCHIVED_LISTINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| listing.owner == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,210 @@
+use crate::archive::archived_orders;
+use crate::bids::open_bid_count;
+use crate::credit::farmer_count;
+use crate::disputes::dispute_counts;
+use crate::ledger::{add_asset_amount, sub_asset_amount, Asset, AssetAmount};
+use crate::orders::{Order, OrderStatus, ORDERS_STORAGE};
+use crate::{is_admin, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// OrderCounts Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct OrderCounts {
+    pending: u64,
+    funded: u64,
+    shipped: u64,
+    returning: u64,
+    completed: u64,
+    disputed: u64,
+    refunded: u64,
+    cancelled: u64,
+}
+
+impl OrderCounts {
+    fn count_mut(&mut self, status: OrderStatus) -> &mut u64 {
+        match status {
+            OrderStatus::Pending => &mut self.pending,
+            OrderStatus::Funded => &mut self.funded,
+            OrderStatus::Shipped => &mut self.shipped,
+            OrderStatus::Returning => &mut self.returning,
+            OrderStatus::Completed => &mut self.completed,
+            OrderStatus::Disputed => &mut self.disputed,
+            OrderStatus::Refunded => &mut self.refunded,
+            OrderStatus::Cancelled => &mut self.cancelled,
+        }
+    }
+}
+
+// MarketplaceStats Struct
+// Counters kept up to date as records change, for the operator dashboard
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub(crate) struct MarketplaceStats {
+    farmers: u64,
+    listings: u64,
+    // Listings not yet sold
+    active_listings: u64,
+    open_bids: u64,
+    orders: OrderCounts,
+    // Funds escrowed on default-currency orders not yet released or refunded
+    escrow_held: u64,
+    // The same for orders in other assets, per asset
+    asset_escrow_held: Option<Vec<AssetAmount>>,
+    disputes_open: u64,
+    disputes_resolved: u64,
+}
+
+// Storable implementation for MarketplaceStats
+impl Storable for MarketplaceStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static MARKETPLACE_STATS: RefCell<Cell<MarketplaceStats, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(132))), MarketplaceStats::default())
+            .expect("Cannot create the marketplace stats")
+    );
+}
+
+fn update_stats(update: impl FnOnce(&mut MarketplaceStats)) {
+    MARKETPLACE_STATS.with(|cell| {
+        let mut stats = cell.borrow().get().clone();
+        update(&mut stats);
+        let _ = cell.borrow_mut().set(stats);
+    });
+}
+
+// Escrow an order holds while the funds are neither released nor refunded
+fn held_escrow(order: &Order) -> u64 {
+    match order.status {
+        OrderStatus::Funded
+        | OrderStatus::Shipped
+        | OrderStatus::Returning
+        | OrderStatus::Disputed => order.escrowed,
+        _ => 0,
+    }
+}
+
+fn add_held_escrow(stats: &mut MarketplaceStats, order: &Order) {
+    match order.currency() {
+        Asset::Icp => stats.escrow_held = stats.escrow_held.saturating_add(held_escrow(order)),
+        asset => add_asset_amount(
+            stats.asset_escrow_held.get_or_insert_with(Vec::new),
+            asset,
+            held_escrow(order),
+        ),
+    }
+}
+
+fn sub_held_escrow(stats: &mut MarketplaceStats, order: &Order) {
+    match order.currency() {
+        Asset::Icp => stats.escrow_held = stats.escrow_held.saturating_sub(held_escrow(order)),
+        asset => sub_asset_amount(
+            stats.asset_escrow_held.get_or_insert_with(Vec::new),
+            asset,
+            held_escrow(order),
+        ),
+    }
+}
+
+pub(crate) fn record_farmer_counted() {
+    update_stats(|stats| stats.farmers += 1);
+}
+
+pub(crate) fn record_listing_created() {
+    update_stats(|stats| {
+        stats.listings += 1;
+        stats.active_listings += 1;
+    });
+}
+
+pub(crate) fn record_listing_sold() {
+    update_stats(|stats| stats.active_listings = stats.active_listings.saturating_sub(1));
+}
+
+// Moves the open bid count when a bid is placed or decided
+pub(crate) fn record_bid_change(was_open: bool, is_open: bool) {
+    update_stats(|stats| {
+        if is_open && !was_open {
+            stats.open_bids += 1;
+        } else if was_open && !is_open {
+            stats.open_bids = stats.open_bids.saturating_sub(1);
+        }
+    });
+}
+
+// Moves the order and escrow counters from an order's previous state to its new one
+pub(crate) fn record_order_change(previous: Option<&Order>, order: &Order) {
+    update_stats(|stats| {
+        if let Some(previous) = previous {
+            let count = stats.orders.count_mut(previous.status);
+            *count = count.saturating_sub(1);
+            sub_held_escrow(stats, previous);
+        }
+        *stats.orders.count_mut(order.status) += 1;
+        add_held_escrow(stats, order);
+    });
+}
+
+pub(crate) fn record_dispute_opened() {
+    update_stats(|stats| stats.disputes_open += 1);
+}
+
+pub(crate) fn record_dispute_resolved() {
+    update_stats(|stats| {
+        stats.disputes_open = stats.disputes_open.saturating_sub(1);
+        stats.disputes_resolved += 1;
+    });
+}
+
+// Dashboard totals, read from the maintained counters
+#[ic_cdk::query]
+fn get_marketplace_stats() -> MarketplaceStats {
+    MARKETPLACE_STATS.with(|cell| cell.borrow().get().clone())
+}
+
+// Function for an administrator to rebuild the counters from the stored records, e.g. after
+// the upgrade that introduced them
+#[ic_cdk::update]
+fn recount_marketplace_stats() -> Result<MarketplaceStats, String> {
+    if !is_admin() {
+        return Err("Only an administrator can recount the marketplace stats".to_string());
+    }
+
+    let mut stats = MarketplaceStats {
+        farmers: farmer_count(),
+        open_bids: open_bid_count(),
+        ..Default::default()
+    };
+    FARMERS_STORAGE.with(|storage| {
+        for (_, farmer) in storage.borrow().iter() {
+            stats.listings += 1;
+            if !farmer.is_sold {
+                stats.active_listings += 1;
+            }
+        }
+    });
+    ORDERS_STORAGE.with(|storage| {
+        for (_, order) in storage.borrow().iter() {
+            *stats.orders.count_mut(order.status) += 1;
+            add_held_escrow(&mut stats, &order);
+        }
+    });
+    for order in archived_orders() {
+        *stats.orders.count_mut(order.status) += 1;
+    }
+    (stats.disputes_open, stats.disputes_resolved) = dispute_counts();
+
+    MARKETPLACE_STATS
+        .with(|cell| cell.borrow_mut().set(stats.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(stats)
+}
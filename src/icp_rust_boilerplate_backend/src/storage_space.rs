@@ -0,0 +1,393 @@
+use crate::devices::caller_account;
+use crate::ledger::{escrow_subaccount, release_escrow, transfer_into_subaccount};
+use crate::notifications::notify;
+use crate::warehouses::is_operator;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TEXT_LEN: usize = 100;
+const MAX_BOOKING_WEEKS: u64 = 52;
+const NANOS_PER_WEEK: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+// Labels the escrow subaccounts that hold storage booking payments
+const SUBACCOUNT_TAG: &[u8] = b"storage";
+
+// StorageListing Struct
+// Warehouse capacity an operator rents out by the tonne-week
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct StorageListing {
+    id: u64,
+    operator: Principal,
+    location: String,
+    temperature_controlled: bool,
+    capacity_tonnes: u64,
+    available_tonnes: u64,
+    price_per_tonne_week: u64,
+    active: bool,
+    created_at: u64,
+}
+
+// StorageListingPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct StorageListingPayload {
+    location: String,
+    temperature_controlled: bool,
+    capacity_tonnes: u64,
+    price_per_tonne_week: u64,
+}
+
+// StorageBookingStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StorageBookingStatus {
+    AwaitingPayment,
+    Active,
+    Completed,
+    Cancelled,
+}
+
+// StorageBooking Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct StorageBooking {
+    id: u64,
+    listing_id: u64,
+    farmer: Principal,
+    operator: Principal,
+    tonnes: u64,
+    weeks: u64,
+    starts_at: u64,
+    ends_at: u64,
+    total_price: u64,
+    status: StorageBookingStatus,
+    created_at: u64,
+    closed_at: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for StorageListing
+impl Storable for StorageListing {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StorageListing {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for StorageBooking
+impl Storable for StorageBooking {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StorageBooking {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static STORAGE_LISTING_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE_LISTINGS_STORAGE: RefCell<StableBTreeMap<u64, StorageListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+
+    static STORAGE_BOOKING_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE_BOOKINGS_STORAGE: RefCell<StableBTreeMap<u64, StorageBooking, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+}
+
+fn get_listing_record(listing_id: u64) -> Result<StorageListing, String> {
+    STORAGE_LISTINGS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Storage listing not found".to_string())
+}
+
+fn save_listing(listing: StorageListing) {
+    STORAGE_LISTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(listing.id, listing));
+}
+
+fn get_booking_record(booking_id: u64) -> Result<StorageBooking, String> {
+    STORAGE_BOOKINGS_STORAGE
+        .with(|storage| storage.borrow().get(&booking_id))
+        .ok_or("Storage booking not found".to_string())
+}
+
+fn save_booking(booking: StorageBooking) {
+    STORAGE_BOOKINGS_STORAGE.with(|storage| storage.borrow_mut().insert(booking.id, booking));
+}
+
+// Puts a booking's tonnes back on its listing
+fn restore_capacity(booking: &StorageBooking) {
+    if let Ok(mut listing) = get_listing_record(booking.listing_id) {
+        listing.available_tonnes =
+            (listing.available_tonnes + booking.tonnes).min(listing.capacity_tonnes);
+        save_listing(listing);
+    }
+}
+
+// Function for a warehouse operator to list storage space for rent
+#[ic_cdk::update]
+fn create_storage_listing(payload: StorageListingPayload) -> Result<StorageListing, String> {
+    let operator = caller_account();
+
+    if !is_operator(&operator) {
+        return Err("Only a registered warehouse operator can list storage".to_string());
+    }
+    if payload.location.trim().is_empty() || payload.location.len() > MAX_TEXT_LEN {
+        return Err("Invalid location".to_string());
+    }
+    if payload.capacity_tonnes == 0 || payload.price_per_tonne_week == 0 {
+        return Err("Capacity and price must be positive".to_string());
+    }
+
+    let listing = StorageListing {
+        id: next_id(&STORAGE_LISTING_ID_COUNTER),
+        operator,
+        location: payload.location,
+        temperature_controlled: payload.temperature_controlled,
+        capacity_tonnes: payload.capacity_tonnes,
+        available_tonnes: payload.capacity_tonnes,
+        price_per_tonne_week: payload.price_per_tonne_week,
+        active: true,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_listing(listing.clone());
+    Ok(listing)
+}
+
+// Function for the operator to stop or resume taking bookings; existing bookings are kept
+#[ic_cdk::update]
+fn set_storage_listing_active(listing_id: u64, active: bool) -> Result<(), String> {
+    let mut listing = get_listing_record(listing_id)?;
+
+    if listing.operator != caller_account() {
+        return Err("Only the operator can manage this listing".to_string());
+    }
+
+    listing.active = active;
+    save_listing(listing);
+    Ok(())
+}
+
+// Function for a farmer to book space, paying the full price into escrow up front
+#[ic_cdk::update]
+async fn book_storage(
+    listing_id: u64,
+    tonnes: u64,
+    weeks: u64,
+    starts_at: u64,
+) -> Result<StorageBooking, String> {
+    let mut listing = get_listing_record(listing_id)?;
+    let farmer = caller_account();
+    let now = ic_cdk::api::time();
+
+    if !listing.active {
+        return Err("Listing is not taking bookings".to_string());
+    }
+    if farmer == listing.operator {
+        return Err("Operators cannot book their own space".to_string());
+    }
+    if tonnes == 0 || tonnes > listing.available_tonnes {
+        return Err("Not enough space available".to_string());
+    }
+    if weeks == 0 || weeks > MAX_BOOKING_WEEKS {
+        return Err("Invalid booking length".to_string());
+    }
+    if starts_at < now {
+        return Err("Booking cannot start in the past".to_string());
+    }
+    let total_price = tonnes
+        .checked_mul(weeks)
+        .and_then(|tonne_weeks| tonne_weeks.checked_mul(listing.price_per_tonne_week))
+        .ok_or("Booking price overflows".to_string())?;
+
+    // Take the space before awaiting so it cannot be booked twice
+    listing.available_tonnes -= tonnes;
+    save_listing(listing.clone());
+    let mut booking = StorageBooking {
+        id: next_id(&STORAGE_BOOKING_ID_COUNTER),
+        listing_id,
+        farmer,
+        operator: listing.operator,
+        tonnes,
+        weeks,
+        starts_at,
+        ends_at: starts_at.saturating_add(weeks * NANOS_PER_WEEK),
+        total_price,
+        status: StorageBookingStatus::AwaitingPayment,
+        created_at: now,
+        closed_at: None,
+    };
+    save_booking(booking.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, booking.id);
+    if let Err(e) = transfer_into_subaccount(farmer, subaccount, total_price).await {
+        restore_capacity(&booking);
+        booking.status = StorageBookingStatus::Cancelled;
+        booking.closed_at = Some(ic_cdk::api::time());
+        save_booking(booking);
+        return Err(e);
+    }
+
+    booking.status = StorageBookingStatus::Active;
+    save_booking(booking.clone());
+    notify(
+        listing.operator,
+        format!(
+            "Storage booking #{}: {} tonnes for {} weeks",
+            booking.id, tonnes, weeks
+        ),
+    );
+    Ok(booking)
+}
+
+// Function for the farmer, or the operator once the term is over, to close a booking and
+// release the escrowed payment to the operator
+#[ic_cdk::update]
+async fn complete_storage_booking(booking_id: u64) -> Result<StorageBooking, String> {
+    let mut booking = get_booking_record(booking_id)?;
+    let caller = caller_account();
+
+    let term_over = booking.ends_at <= ic_cdk::api::time();
+    if caller != booking.farmer && !(caller == booking.operator && term_over) {
+        return Err(
+            "Only the farmer, or the operator after the term, can complete this booking"
+                .to_string(),
+        );
+    }
+    if booking.status != StorageBookingStatus::Active {
+        return Err("Booking is not active".to_string());
+    }
+
+    // Close before awaiting so the escrow cannot be released twice
+    booking.status = StorageBookingStatus::Completed;
+    booking.closed_at = Some(ic_cdk::api::time());
+    save_booking(booking.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, booking.id);
+    if let Err(e) = release_escrow(subaccount, booking.operator, booking.total_price).await {
+        booking.status = StorageBookingStatus::Active;
+        booking.closed_at = None;
+        save_booking(booking);
+        return Err(e);
+    }
+
+    restore_capacity(&booking);
+    notify(
+        booking.operator,
+        format!("Payment for storage booking #{} was released", booking.id),
+    );
+    Ok(booking)
+}
+
+// Function for the farmer to cancel a booking before it starts, refunding the escrow
+#[ic_cdk::update]
+async fn cancel_storage_booking(booking_id: u64) -> Result<StorageBooking, String> {
+    let mut booking = get_booking_record(booking_id)?;
+
+    if booking.farmer != caller_account() {
+        return Err("Only the farmer can cancel this booking".to_string());
+    }
+    if booking.status != StorageBookingStatus::Active {
+        return Err("Booking is not active".to_string());
+    }
+    if booking.starts_at <= ic_cdk::api::time() {
+        return Err("Booking has already started".to_string());
+    }
+
+    booking.status = StorageBookingStatus::Cancelled;
+    booking.closed_at = Some(ic_cdk::api::time());
+    save_booking(booking.clone());
+
+    let subaccount = escrow_subaccount(SUBACCOUNT_TAG, booking.id);
+    if let Err(e) = release_escrow(subaccount, booking.farmer, booking.total_price).await {
+        booking.status = StorageBookingStatus::Active;
+        booking.closed_at = None;
+        save_booking(booking);
+        return Err(e);
+    }
+
+    restore_capacity(&booking);
+    notify(
+        booking.operator,
+        format!("Storage booking #{} was cancelled", booking.id),
+    );
+    Ok(booking)
+}
+
+#[ic_cdk::query]
+fn get_storage_listing(listing_id: u64) -> Result<StorageListing, String> {
+    get_listing_record(listing_id)
+}
+
+// Lists active listings with space left, optionally filtered by location and cold storage
+#[ic_cdk::query]
+fn list_storage_listings(
+    location: Option<String>,
+    temperature_controlled: Option<bool>,
+) -> Vec<StorageListing> {
+    STORAGE_LISTINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| listing.active && listing.available_tonnes > 0)
+            .filter(|listing| {
+                location.as_ref().map_or(true, |location| {
+                    listing.location.eq_ignore_ascii_case(location)
+                })
+            })
+            .filter(|listing| {
+                temperature_controlled.map_or(true, |cold| listing.temperature_controlled == cold)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_storage_booking(booking_id: u64) -> Result<StorageBooking, String> {
+    let booking = get_booking_record(booking_id)?;
+    let caller = caller_account();
+
+    if caller != booking.farmer && caller != booking.operator {
+        return Err("Storage booking not found".to_string());
+    }
+    Ok(booking)
+}
+
+// Lists bookings the caller made or hosts
+#[ic_cdk::query]
+fn get_my_storage_bookings() -> Vec<StorageBooking> {
+    let caller = caller_account();
+    STORAGE_BOOKINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, booking)| booking)
+            .filter(|booking| booking.farmer == caller || booking.operator == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,323 @@
+use crate::contracts::sync_contract_status;
+use crate::devices::caller_account;
+use crate::ledger::{ledger_fee, Asset};
+use crate::notifications::notify;
+use crate::order_escrow::{refund_order_escrow, release_partial_order_escrow};
+use crate::orders::{get_order_record, save_order, Order, OrderStatus};
+use crate::{is_admin, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// The timer only releases once at least this share of the total has accrued, in basis points
+const MIN_TICK_RELEASE_BPS: u64 = 100;
+
+// StreamStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum StreamStatus {
+    Streaming,
+    // The order completed; whatever had not streamed was released with it
+    Completed,
+    // Stopped early; the payee keeps what had accrued and the rest went back to the payer
+    Cancelled,
+}
+
+// PaymentStream Struct
+// Releases an order's escrow to the payee pro rata between two times instead of all at
+// completion, e.g. a worker's wage over the days of the job
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct PaymentStream {
+    order_id: u64,
+    payer: Principal,
+    payee: Principal,
+    starts_at: u64,
+    ends_at: u64,
+    // Released to the payee so far
+    released: u64,
+    status: StreamStatus,
+    updated_at: u64,
+}
+
+// Storable and BoundedStorable implementations for PaymentStream
+impl Storable for PaymentStream {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PaymentStream {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Keyed by order ID
+    static STREAMS_STORAGE: RefCell<StableBTreeMap<u64, PaymentStream, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(225)))
+    ));
+}
+
+fn get_stream_record(order_id: u64) -> Result<PaymentStream, String> {
+    STREAMS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .ok_or("Payment stream not found".to_string())
+}
+
+fn save_stream(mut stream: PaymentStream) {
+    stream.updated_at = ic_cdk::api::time();
+    STREAMS_STORAGE.with(|storage| storage.borrow_mut().insert(stream.order_id, stream));
+}
+
+// Streams a new order's escrow between two times; orders with no time to stream over are
+// paid at completion as usual
+pub(crate) fn open_stream(order: &Order, starts_at: u64, ends_at: u64) {
+    if ends_at <= starts_at {
+        return;
+    }
+    save_stream(PaymentStream {
+        order_id: order.id,
+        payer: order.payer(),
+        payee: order.farmer,
+        starts_at,
+        ends_at,
+        released: 0,
+        status: StreamStatus::Streaming,
+        updated_at: 0,
+    });
+}
+
+// What has accrued to the payee by `now` out of the order total
+fn accrued(stream: &PaymentStream, total: u64, now: u64) -> u64 {
+    let elapsed = now.clamp(stream.starts_at, stream.ends_at) - stream.starts_at;
+    let duration = stream.ends_at - stream.starts_at;
+    (total as u128 * elapsed as u128 / duration as u128) as u64
+}
+
+// What is released to the payee at `now`: what accrued and was not released yet, as far as the
+// escrow covers it, or nothing while that is below `min_release`
+fn due_release(stream: &PaymentStream, order: &Order, now: u64, min_release: u64) -> u64 {
+    let due = accrued(stream, order.total, now)
+        .saturating_sub(stream.released)
+        .min(order.escrowed);
+    if due < min_release {
+        0
+    } else {
+        due
+    }
+}
+
+// Brings a stream up to date: pays out what accrued while the order is funded, and closes the
+// stream once the order has settled another way. Releases smaller than `min_release` wait; what
+// is left when the order completes is paid with it.
+fn accrue(mut stream: PaymentStream, min_release: u64) -> Result<PaymentStream, String> {
+    if stream.status != StreamStatus::Streaming {
+        return Ok(stream);
+    }
+    let mut order = get_order_record(stream.order_id)?;
+
+    match order.status {
+        OrderStatus::Funded | OrderStatus::Shipped => {
+            let due = due_release(&stream, &order, ic_cdk::api::time(), min_release);
+            if due == 0 {
+                return Ok(stream);
+            }
+            release_partial_order_escrow(&order, due)?;
+            order.escrowed -= due;
+            stream.released += due;
+            save_order(order);
+        }
+        // Not paid for yet, or held by a dispute or return
+        OrderStatus::Pending | OrderStatus::Disputed | OrderStatus::Returning => return Ok(stream),
+        OrderStatus::Completed => stream.status = StreamStatus::Completed,
+        OrderStatus::Refunded | OrderStatus::Cancelled => stream.status = StreamStatus::Cancelled,
+    }
+    save_stream(stream.clone());
+    Ok(stream)
+}
+
+// Smallest release worth a transfer: anything up to the ledger fee would arrive as nothing
+async fn min_release_for(order: &Order) -> Result<u64, String> {
+    Ok(ledger_fee(order.currency()).await? + 1)
+}
+
+// Releases what accrued on every running stream
+pub(crate) async fn accrue_payment_streams() {
+    let streams: Vec<PaymentStream> = STREAMS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, stream)| stream)
+            .filter(|stream| stream.status == StreamStatus::Streaming)
+            .collect()
+    });
+
+    // Each ledger is asked for its fee once per run
+    let mut fees: BTreeMap<Asset, u64> = BTreeMap::new();
+    for stream in streams {
+        let order = match get_order_record(stream.order_id) {
+            Ok(order) => order,
+            Err(_) => continue,
+        };
+        let asset = order.currency();
+        let fee = match fees.get(&asset) {
+            Some(fee) => *fee,
+            None => match ledger_fee(asset).await {
+                Ok(fee) => *fees.entry(asset).or_insert(fee),
+                Err(_) => continue,
+            },
+        };
+        // Read again; the stream may have been claimed while the fee was fetched
+        if let Ok(stream) = get_stream_record(stream.order_id) {
+            let _ = accrue(
+                stream,
+                (order.total * MIN_TICK_RELEASE_BPS / 10_000).max(fee + 1),
+            );
+        }
+    }
+}
+
+// Function for a party to an order to see its payment stream and what has accrued but not
+// been released yet
+#[ic_cdk::query]
+fn get_payment_stream(order_id: u64) -> Result<(PaymentStream, u64), String> {
+    let stream = get_stream_record(order_id)?;
+    let caller = caller_account();
+    if caller != stream.payer && caller != stream.payee && !is_admin() {
+        return Err("Only the parties to an order can view its payment stream".to_string());
+    }
+
+    let order = get_order_record(order_id)?;
+    let pending = if stream.status == StreamStatus::Streaming
+        && matches!(order.status, OrderStatus::Funded | OrderStatus::Shipped)
+    {
+        due_release(&stream, &order, ic_cdk::api::time(), 0)
+    } else {
+        0
+    };
+    Ok((stream, pending))
+}
+
+// Function for the payee to release everything that has accrued so far, as long as it is more
+// than the ledger fee
+#[ic_cdk::update]
+async fn claim_payment_stream(order_id: u64) -> Result<PaymentStream, String> {
+    let stream = get_stream_record(order_id)?;
+    if stream.payee != caller_account() {
+        return Err("Only the payee can claim a payment stream".to_string());
+    }
+    let min_release = min_release_for(&get_order_record(order_id)?).await?;
+    accrue(get_stream_record(order_id)?, min_release)
+}
+
+// Function for either party to stop a stream early. What accrued up to now goes to the payee,
+// unless it is no more than the ledger fee, and the rest of the escrow back to the payer,
+// closing the order.
+#[ic_cdk::update]
+async fn cancel_payment_stream(order_id: u64) -> Result<PaymentStream, String> {
+    let stream = get_stream_record(order_id)?;
+    let caller = caller_account();
+    if caller != stream.payer && caller != stream.payee {
+        return Err("Only the parties to an order can cancel its payment stream".to_string());
+    }
+
+    let min_release = min_release_for(&get_order_record(order_id)?).await?;
+    let mut stream = accrue(get_stream_record(order_id)?, min_release)?;
+    if stream.status != StreamStatus::Streaming {
+        return Err("Payment stream has already ended".to_string());
+    }
+    let mut order = get_order_record(order_id)?;
+    if !matches!(order.status, OrderStatus::Funded | OrderStatus::Shipped) {
+        return Err("Order is not funded".to_string());
+    }
+
+    let refunded = order.escrowed;
+    order.status = OrderStatus::Refunded;
+    refund_order_escrow(&order);
+    let contract_id = order.contract_id;
+    save_order(order);
+    stream.status = StreamStatus::Cancelled;
+    save_stream(stream.clone());
+
+    let other = if caller == stream.payer {
+        stream.payee
+    } else {
+        stream.payer
+    };
+    notify(
+        other,
+        format!(
+            "Payment stream on order #{} was cancelled: {} released, {} refunded",
+            order_id, stream.released, refunded
+        ),
+    );
+    if let Some(contract_id) = contract_id {
+        sync_contract_status(contract_id);
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::test_order;
+
+    fn streaming(order: &Order, starts_at: u64, ends_at: u64) -> PaymentStream {
+        PaymentStream {
+            order_id: order.id,
+            payer: order.consumer,
+            payee: order.farmer,
+            starts_at,
+            ends_at,
+            released: 0,
+            status: StreamStatus::Streaming,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn escrow_accrues_pro_rata_between_start_and_end() {
+        let order = test_order(1, 1_000);
+        let stream = streaming(&order, 100, 200);
+        assert_eq!(accrued(&stream, order.total, 50), 0);
+        assert_eq!(accrued(&stream, order.total, 100), 0);
+        assert_eq!(accrued(&stream, order.total, 125), 250);
+        assert_eq!(accrued(&stream, order.total, 200), 1_000);
+        assert_eq!(accrued(&stream, order.total, 10_000), 1_000);
+    }
+
+    #[test]
+    fn only_the_unreleased_part_is_due() {
+        let mut order = test_order(2, 1_000);
+        order.escrowed = 700;
+        let mut stream = streaming(&order, 0, 100);
+        stream.released = 300;
+
+        assert_eq!(due_release(&stream, &order, 50, 0), 200);
+        assert_eq!(due_release(&stream, &order, 20, 0), 0);
+        assert_eq!(due_release(&stream, &order, 100, 0), 700);
+    }
+
+    #[test]
+    fn releases_never_exceed_the_escrow() {
+        let mut order = test_order(3, 1_000);
+        order.escrowed = 400;
+        let stream = streaming(&order, 0, 100);
+        assert_eq!(due_release(&stream, &order, 100, 0), 400);
+    }
+
+    #[test]
+    fn small_releases_wait_for_the_minimum() {
+        let mut order = test_order(4, 1_000);
+        order.escrowed = order.total;
+        let stream = streaming(&order, 0, 100);
+        assert_eq!(due_release(&stream, &order, 5, 100), 0);
+        assert_eq!(due_release(&stream, &order, 10, 100), 100);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::devices::caller_account;
+use crate::inventory::{release_stock, reserve_stock};
+use crate::notifications::notify;
+use crate::order_escrow::{collect_order_payment, refund_order_escrow};
+use crate::orders::{create_order, get_order_record, save_order, OrderDraft, OrderStatus};
+use crate::pricing::unit_price_for;
+use crate::{
+    farmer_principal, is_off_market, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_INTERVAL_DAYS: u16 = 365;
+
+// SubscriptionStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SubscriptionStatus {
+    Active,
+    Paused,
+    Cancelled,
+}
+
+// Subscription Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Subscription {
+    id: u64,
+    farmer_id: u64,
+    farmer: Principal,
+    consumer: Principal,
+    quantity: u64,
+    interval_days: u16,
+    next_run_at: u64,
+    status: SubscriptionStatus,
+    orders_created: u64,
+    last_order_id: Option<u64>,
+    last_error: Option<String>,
+    created_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Subscription
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SUBSCRIPTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SUBSCRIPTIONS_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+}
+
+// Subscription Payload
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SubscriptionPayload {
+    farmer_id: u64,
+    quantity: u64,
+    interval_days: u16,
+    starts_at: Option<u64>,
+}
+
+fn get_subscription_record(subscription_id: u64) -> Result<Subscription, String> {
+    SUBSCRIPTIONS_STORAGE
+        .with(|storage| storage.borrow().get(&subscription_id))
+        .ok_or("Subscription not found".to_string())
+}
+
+fn save_subscription(subscription: Subscription) {
+    SUBSCRIPTIONS_STORAGE
+        .with(|storage| storage.borrow_mut().insert(subscription.id, subscription));
+}
+
+// Loads a subscription the caller owns
+fn get_own_subscription(subscription_id: u64) -> Result<Subscription, String> {
+    let subscription = get_subscription_record(subscription_id)?;
+    if subscription.consumer != caller_account() {
+        return Err("Only the subscriber can manage this subscription".to_string());
+    }
+    Ok(subscription)
+}
+
+// Function for a consumer to subscribe to a recurring order; each cycle is paid from
+// an ICRC-2 allowance the consumer grants this canister
+#[ic_cdk::update]
+fn subscribe(payload: SubscriptionPayload) -> Result<Subscription, String> {
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    let consumer = caller_account();
+    let owner = farmer_principal(&farmer)?;
+    let now = ic_cdk::api::time();
+
+    if farmer.is_sold {
+        return Err("Product already sold".to_string());
+    }
+    if is_off_market(&farmer) {
+        return Err("Listing is no longer available".to_string());
+    }
+    if consumer == owner {
+        return Err("Farmers cannot subscribe to their own products".to_string());
+    }
+    if payload.interval_days == 0 || payload.interval_days > MAX_INTERVAL_DAYS {
+        return Err("Invalid subscription interval".to_string());
+    }
+    // Validates the quantity against the listing's price tiers up front
+    unit_price_for(&farmer, payload.quantity)?;
+
+    let subscription = Subscription {
+        id: next_id(&SUBSCRIPTION_ID_COUNTER),
+        farmer_id: payload.farmer_id,
+        farmer: owner,
+        consumer,
+        quantity: payload.quantity,
+        interval_days: payload.interval_days,
+        next_run_at: payload.starts_at.unwrap_or(now).max(now),
+        status: SubscriptionStatus::Active,
+        orders_created: 0,
+        last_order_id: None,
+        last_error: None,
+        created_at: now,
+    };
+
+    save_subscription(subscription.clone());
+    Ok(subscription)
+}
+
+#[ic_cdk::update]
+fn pause_subscription(subscription_id: u64) -> Result<(), String> {
+    let mut subscription = get_own_subscription(subscription_id)?;
+    if subscription.status != SubscriptionStatus::Active {
+        return Err("Subscription is not active".to_string());
+    }
+
+    subscription.status = SubscriptionStatus::Paused;
+    save_subscription(subscription);
+    Ok(())
+}
+
+// Function for the subscriber to resume a paused subscription, e.g. after topping up the allowance
+#[ic_cdk::update]
+fn resume_subscription(subscription_id: u64) -> Result<(), String> {
+    let mut subscription = get_own_subscription(subscription_id)?;
+    if subscription.status != SubscriptionStatus::Paused {
+        return Err("Subscription is not paused".to_string());
+    }
+
+    // Missed cycles are skipped rather than billed all at once
+    subscription.next_run_at = subscription.next_run_at.max(ic_cdk::api::time());
+    subscription.status = SubscriptionStatus::Active;
+    subscription.last_error = None;
+    save_subscription(subscription);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn cancel_subscription(subscription_id: u64) -> Result<(), String> {
+    let mut subscription = get_own_subscription(subscription_id)?;
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err("Subscription is already cancelled".to_string());
+    }
+
+    subscription.status = SubscriptionStatus::Cancelled;
+    save_subscription(subscription);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_subscription(subscription_id: u64) -> Result<Subscription, String> {
+    get_subscription_record(subscription_id)
+}
+
+#[ic_cdk::query]
+fn get_my_subscriptions() -> Vec<Subscription> {
+    let caller = caller_account();
+    SUBSCRIPTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.consumer == caller)
+            .collect()
+    })
+}
+
+// CycleOutcome Enum
+// What a cycle does with its order once the payment call returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CycleOutcome {
+    Fund,
+    // The order was cancelled while the payment was in flight, so the payment goes back
+    Refund,
+    // The payment failed, so the order is cancelled and its stock released
+    Cancel,
+    // The payment failed after the subscriber cancelled the order, which released its stock
+    Leave,
+}
+
+fn cycle_outcome(paid: bool, status: OrderStatus) -> CycleOutcome {
+    match (paid, status == OrderStatus::Pending) {
+        (true, true) => CycleOutcome::Fund,
+        (true, false) => CycleOutcome::Refund,
+        (false, true) => CycleOutcome::Cancel,
+        (false, false) => CycleOutcome::Leave,
+    }
+}
+
+// Pauses a subscription whose cycle could not be billed and tells the subscriber why
+fn suspend(mut subscription: Subscription, reason: String) {
+    notify(
+        subscription.consumer,
+        format!("Subscription #{} was paused: {}", subscription.id, reason),
+    );
+    subscription.status = SubscriptionStatus::Paused;
+    subscription.last_error = Some(reason);
+    save_subscription(subscription);
+}
+
+// Places and funds one order for a due subscription
+async fn run_cycle(mut subscription: Subscription) {
+    // Move the schedule forward before awaiting so overlapping runs skip this cycle
+    subscription.next_run_at += subscription.interval_days as u64 * NANOS_PER_DAY;
+    save_subscription(subscription.clone());
+
+    let listing = FARMERS_STORAGE.with(|storage| storage.borrow().get(&subscription.farmer_id));
+    let farmer = match listing {
+        Some(farmer) if !farmer.is_sold && !is_off_market(&farmer) => farmer,
+        _ => {
+            let reason = "the listing is no longer available".to_string();
+            return suspend(subscription, reason);
+        }
+    };
+
+    let draft = unit_price_for(&farmer, subscription.quantity).map(|unit_price| OrderDraft {
+        product_id: Some(subscription.farmer_id),
+        contract_id: None,
+        farmer: subscription.farmer,
+        consumer: subscription.consumer,
+        quantity: subscription.quantity,
+        unit_price,
+        due_at: None,
+    });
+    let mut order = match draft.and_then(create_order) {
+        Ok(order) => order,
+        Err(e) => return suspend(subscription, e),
+    };
+    if let Err(e) = reserve_stock(subscription.farmer_id, subscription.quantity) {
+        order.status = OrderStatus::Cancelled;
+        save_order(order);
+        return suspend(subscription, e);
+    }
+
+    let payment = collect_order_payment(&order, order.total).await;
+
+    // Re-read so a pause or cancel made while the transfer was in flight is kept
+    let mut subscription = get_subscription_record(subscription.id).unwrap_or(subscription);
+    let mut order = get_order_record(order.id).unwrap_or(order);
+    match cycle_outcome(payment.is_ok(), order.status) {
+        CycleOutcome::Fund => {
+            order.escrowed = order.total;
+            order.status = OrderStatus::Funded;
+            notify(
+                subscription.farmer,
+                format!(
+                    "New subscription order #{} for {} units",
+                    order.id, order.quantity
+                ),
+            );
+            subscription.orders_created += 1;
+            subscription.last_order_id = Some(order.id);
+            save_order(order);
+            save_subscription(subscription);
+        }
+        CycleOutcome::Refund => {
+            refund_order_escrow(&order);
+            notify(
+                subscription.consumer,
+                format!(
+                    "Order #{} was cancelled while it was being paid; the payment is refunded",
+                    order.id
+                ),
+            );
+        }
+        outcome => {
+            if outcome == CycleOutcome::Cancel {
+                release_stock(subscription.farmer_id, subscription.quantity);
+                order.status = OrderStatus::Cancelled;
+                save_order(order);
+            }
+            if subscription.status == SubscriptionStatus::Cancelled {
+                return;
+            }
+            if let Err(e) = payment {
+                suspend(subscription, e);
+            }
+        }
+    }
+}
+
+// Bills every active subscription whose next cycle is due
+pub(crate) async fn run_subscriptions() {
+    let now = ic_cdk::api::time();
+    let due: Vec<Subscription> = SUBSCRIPTIONS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.status == SubscriptionStatus::Active && subscription.next_run_at <= now
+            })
+            .collect()
+    });
+
+    for subscription in due {
+        run_cycle(subscription).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_paid_cycle_funds_its_pending_order() {
+        assert_eq!(
+            cycle_outcome(true, OrderStatus::Pending),
+            CycleOutcome::Fund
+        );
+    }
+
+    #[test]
+    fn a_payment_for_a_cancelled_order_is_refunded() {
+        assert_eq!(
+            cycle_outcome(true, OrderStatus::Cancelled),
+            CycleOutcome::Refund
+        );
+    }
+
+    #[test]
+    fn a_failed_payment_cancels_the_order_once() {
+        assert_eq!(
+            cycle_outcome(false, OrderStatus::Pending),
+            CycleOutcome::Cancel
+        );
+        // Already cancelled by the subscriber, so its stock is not released twice
+        assert_eq!(
+            cycle_outcome(false, OrderStatus::Cancelled),
+            CycleOutcome::Leave
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::orders::{create_order, Order, OrderDraft, ORDERS_STORAGE};
+use crate::{next_id, IdCell, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_BUSINESS_NAME_LEN: usize = 100;
+const MAX_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const MAX_UNIT_LEN: usize = 20;
+
+// InputSupplier Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct InputSupplier {
+    principal: Principal,
+    business_name: String,
+    registered_at: u64,
+}
+
+// InputCategory Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum InputCategory {
+    Seeds,
+    Fertilizer,
+    Feed,
+    Agrochemicals,
+    Tools,
+    Other,
+}
+
+// SupplyListing Struct
+// Farm inputs a supplier sells to farmers, priced per unit
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SupplyListing {
+    id: u64,
+    supplier: Principal,
+    category: InputCategory,
+    name: String,
+    description: String,
+    // e.g. "kg" or "50kg bag"
+    unit: String,
+    unit_price: u64,
+    stock: u64,
+    active: bool,
+    created_at: u64,
+}
+
+// SupplyListingPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct SupplyListingPayload {
+    category: InputCategory,
+    name: String,
+    description: String,
+    unit: String,
+    unit_price: u64,
+    stock: u64,
+}
+
+// Storable and BoundedStorable implementations for InputSupplier
+impl Storable for InputSupplier {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for InputSupplier {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for SupplyListing
+impl Storable for SupplyListing {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SupplyListing {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static SUPPLIERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, InputSupplier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    static SUPPLY_LISTING_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static SUPPLY_LISTINGS_STORAGE: RefCell<StableBTreeMap<u64, SupplyListing, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    // Order id -> the supply listing it was placed on
+    static SUPPLY_ORDERS_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+}
+
+fn get_listing_record(listing_id: u64) -> Result<SupplyListing, String> {
+    SUPPLY_LISTINGS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Supply listing not found".to_string())
+}
+
+fn save_listing(listing: SupplyListing) {
+    SUPPLY_LISTINGS_STORAGE.with(|storage| storage.borrow_mut().insert(listing.id, listing));
+}
+
+fn is_supplier(principal: &Principal) -> bool {
+    SUPPLIERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Puts the units of a cancelled or returned supply order back on its listing
+pub(crate) fn release_supply_stock(order_id: u64, quantity: u64) {
+    let listing_id = SUPPLY_ORDERS_STORAGE.with(|storage| storage.borrow().get(&order_id));
+    if let Some(Ok(mut listing)) = listing_id.map(get_listing_record) {
+        listing.stock = listing.stock.saturating_add(quantity);
+        save_listing(listing);
+    }
+}
+
+// Function for a business to register as an input supplier; farmers may register too
+#[ic_cdk::update]
+fn register_input_supplier(business_name: String) -> Result<InputSupplier, String> {
+    if business_name.trim().is_empty() || business_name.len() > MAX_BUSINESS_NAME_LEN {
+        return Err("Invalid business name".to_string());
+    }
+
+    let supplier = InputSupplier {
+        principal: caller_account(),
+        business_name,
+        registered_at: ic_cdk::api::time(),
+    };
+
+    SUPPLIERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(supplier.principal), supplier.clone())
+    });
+    Ok(supplier)
+}
+
+#[ic_cdk::query]
+fn get_input_supplier(principal: Principal) -> Result<InputSupplier, String> {
+    SUPPLIERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Input supplier not found".to_string())
+}
+
+// Function for a registered supplier to list seeds, fertilizer, feed or other inputs
+#[ic_cdk::update]
+fn create_supply_listing(payload: SupplyListingPayload) -> Result<SupplyListing, String> {
+    let supplier = caller_account();
+
+    if !is_supplier(&supplier) {
+        return Err("Only registered input suppliers can list inputs".to_string());
+    }
+    if payload.name.trim().is_empty()
+        || payload.name.len() > MAX_NAME_LEN
+        || payload.description.len() > MAX_DESCRIPTION_LEN
+        || payload.unit.trim().is_empty()
+        || payload.unit.len() > MAX_UNIT_LEN
+    {
+        return Err("Invalid listing details".to_string());
+    }
+    if payload.unit_price == 0 {
+        return Err("Unit price must be positive".to_string());
+    }
+
+    let listing = SupplyListing {
+        id: next_id(&SUPPLY_LISTING_ID_COUNTER),
+        supplier,
+        category: payload.category,
+        name: payload.name,
+        description: payload.description,
+        unit: payload.unit,
+        unit_price: payload.unit_price,
+        stock: payload.stock,
+        active: true,
+        created_at: ic_cdk::api::time(),
+    };
+
+    save_listing(listing.clone());
+    Ok(listing)
+}
+
+// Function for the supplier to reprice, restock or pause a listing; open orders keep their price
+#[ic_cdk::update]
+fn update_supply_listing(
+    listing_id: u64,
+    unit_price: u64,
+    stock: u64,
+    active: bool,
+) -> Result<SupplyListing, String> {
+    let mut listing = get_listing_record(listing_id)?;
+
+    if listing.supplier != caller_account() {
+        return Err("Only the supplier can update this listing".to_string());
+    }
+    if unit_price == 0 {
+        return Err("Unit price must be positive".to_string());
+    }
+
+    listing.unit_price = unit_price;
+    listing.stock = stock;
+    listing.active = active;
+    save_listing(listing.clone());
+    Ok(listing)
+}
+
+// Function for a farmer to order inputs. The supplier takes the seller's side of the order,
+// which is then funded, shipped, confirmed or disputed like any produce order.
+#[ic_cdk::update]
+fn order_supplies(listing_id: u64, quantity: u64) -> Result<Order, String> {
+    let mut listing = get_listing_record(listing_id)?;
+    let buyer = caller_account();
+
+    if !listing.active {
+        return Err("Listing is not available".to_string());
+    }
+    if buyer == listing.supplier {
+        return Err("Suppliers cannot order their own inputs".to_string());
+    }
+    if quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    if listing.stock < quantity {
+        return Err("Not enough stock".to_string());
+    }
+
+    let order = create_order(OrderDraft {
+        product_id: None,
+        contract_id: None,
+        farmer: listing.supplier,
+        consumer: buyer,
+        quantity,
+        unit_price: listing.unit_price,
+        due_at: None,
+    })?;
+
+    listing.stock -= quantity;
+    save_listing(listing.clone());
+    SUPPLY_ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, listing_id));
+    notify(
+        listing.supplier,
+        format!(
+            "New order #{} for {} {} of {}",
+            order.id, quantity, listing.unit, listing.name
+        ),
+    );
+    Ok(order)
+}
+
+#[ic_cdk::query]
+fn get_supply_listing(listing_id: u64) -> Result<SupplyListing, String> {
+    get_listing_record(listing_id)
+}
+
+// Lists inputs on sale, optionally of one category
+#[ic_cdk::query]
+fn list_supply_listings(category: Option<InputCategory>) -> Vec<SupplyListing> {
+    SUPPLY_LISTINGS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, listing)| listing)
+            .filter(|listing| listing.active && listing.stock > 0)
+            .filter(|listing| category.map_or(true, |category| listing.category == category))
+            .collect()
+    })
+}
+
+// Lists supply orders the caller placed or, as a supplier, received
+#[ic_cdk::query]
+fn get_my_supply_orders() -> Vec<Order> {
+    let caller = caller_account();
+    let order_ids: Vec<u64> = SUPPLY_ORDERS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(order_id, _)| order_id)
+            .collect()
+    });
+
+    ORDERS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        order_ids
+            .into_iter()
+            .filter_map(|order_id| storage.get(&order_id))
+            .filter(|order| order.farmer == caller || order.consumer == caller)
+            .collect()
+    })
+}
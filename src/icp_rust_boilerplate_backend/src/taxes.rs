@@ -0,0 +1,411 @@
+use crate::governance::is_governor;
+use crate::ledger::Asset;
+use crate::orders::Order;
+use crate::price_history::category_key;
+use crate::seasons::year_month_of;
+use crate::shipping::{listing_region, normalise_region};
+use crate::{is_admin, next_id, IdCell, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TAX_RULES: usize = 100;
+// Distinct tax names, which bounds the tax lines stored on each order
+const MAX_TAX_NAMES: usize = 3;
+const MAX_TAX_NAME_LEN: usize = 16;
+const MAX_TAX_RATE_BPS: u64 = 5_000;
+
+// TaxRule Struct
+// A tax charged on listing orders, in basis points of the order subtotal. Rules with the same
+// name are alternatives: an order gets the most specific one that matches its listing, so a
+// regional or category rule overrides a broader one, and a rate of zero exempts.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TaxRule {
+    id: u64,
+    // e.g. "VAT"
+    name: String,
+    // None matches every region or category
+    region: Option<String>,
+    category: Option<String>,
+    rate_bps: u64,
+    updated_at: u64,
+}
+
+// TaxRule Payload
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TaxRulePayload {
+    name: String,
+    region: Option<String>,
+    category: Option<String>,
+    rate_bps: u64,
+}
+
+// TaxLine Struct
+// One tax charged on an order
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TaxLine {
+    rule_id: u64,
+    name: String,
+    rate_bps: u64,
+    taxable: u64,
+    amount: u64,
+}
+
+// OrderTaxes Struct
+// Taxes included in an order's total, kept out of the order so it stays within its size bound
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct OrderTaxes {
+    lines: Vec<TaxLine>,
+}
+
+// TaxTotals Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct TaxTotals {
+    orders: u64,
+    taxable: u64,
+    collected: u64,
+}
+
+// TaxPeriodTotal Struct
+// Tax collected on completed orders in one month, per tax and asset
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TaxPeriodTotal {
+    year: u32,
+    month: u8,
+    name: String,
+    asset: Asset,
+    orders: u64,
+    taxable: u64,
+    collected: u64,
+}
+
+// TaxKey Struct
+// "year|month|asset|name", zero padded to sort by period
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TaxKey(String);
+
+// Storable and BoundedStorable implementations for TaxRule
+impl Storable for TaxRule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TaxRule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for OrderTaxes
+impl Storable for OrderTaxes {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderTaxes {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for TaxTotals
+impl Storable for TaxTotals {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TaxTotals {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for TaxKey
+impl Storable for TaxKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TaxKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for TaxKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TAX_RULE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(217))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static TAX_RULES_STORAGE: RefCell<StableBTreeMap<u64, TaxRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(218)))
+    ));
+
+    static TAX_TOTALS_STORAGE: RefCell<StableBTreeMap<TaxKey, TaxTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(219)))
+    ));
+
+    // Keyed by order ID
+    static ORDER_TAXES_STORAGE: RefCell<StableBTreeMap<u64, OrderTaxes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(236)))
+    ));
+}
+
+fn asset_code(asset: Asset) -> &'static str {
+    match asset {
+        Asset::Icp => "icp",
+        Asset::CkBtc => "ckbtc",
+        Asset::CkUsdc => "ckusdc",
+    }
+}
+
+fn tax_key(year: u32, month: u8, asset: Asset, name: &str) -> TaxKey {
+    TaxKey(format!(
+        "{:04}|{:02}|{}|{}",
+        year,
+        month,
+        asset_code(asset),
+        name
+    ))
+}
+
+// How narrowly a rule targets a listing; None if it does not apply to it
+fn specificity(rule: &TaxRule, region: Option<&str>, category: &str) -> Option<u8> {
+    let mut score = 0;
+    if let Some(rule_region) = &rule.region {
+        if region != Some(rule_region.as_str()) {
+            return None;
+        }
+        score += 2;
+    }
+    if let Some(rule_category) = &rule.category {
+        if rule_category != category {
+            return None;
+        }
+        score += 1;
+    }
+    Some(score)
+}
+
+// The taxes a listing's orders carry on a subtotal, one line per tax name
+pub(crate) fn tax_lines(product_id: u64, subtotal: u64) -> Vec<TaxLine> {
+    let category = match FARMERS_STORAGE.with(|storage| storage.borrow().get(&product_id)) {
+        Some(farmer) => category_key(&farmer.category),
+        None => return Vec::new(),
+    };
+    let region = listing_region(product_id).ok();
+
+    let mut chosen: Vec<(u8, TaxRule)> = Vec::new();
+    TAX_RULES_STORAGE.with(|storage| {
+        for (_, rule) in storage.borrow().iter() {
+            let score = match specificity(&rule, region.as_deref(), &category) {
+                Some(score) => score,
+                None => continue,
+            };
+            match chosen.iter_mut().find(|(_, other)| other.name == rule.name) {
+                Some(entry) if entry.0 < score => *entry = (score, rule),
+                Some(_) => {}
+                None => chosen.push((score, rule)),
+            }
+        }
+    });
+
+    chosen
+        .into_iter()
+        .filter(|(_, rule)| rule.rate_bps > 0)
+        .map(|(_, rule)| TaxLine {
+            rule_id: rule.id,
+            amount: (subtotal as u128 * rule.rate_bps as u128 / 10_000) as u64,
+            name: rule.name,
+            rate_bps: rule.rate_bps,
+            taxable: subtotal,
+        })
+        .collect()
+}
+
+pub(crate) fn tax_total(lines: &[TaxLine]) -> u64 {
+    lines
+        .iter()
+        .fold(0u64, |total, line| total.saturating_add(line.amount))
+}
+
+// Adds the taxes of a new listing order to its total; the subtotal is taxed before any
+// coupon discount or delivery charge
+pub(crate) fn apply_taxes(order: &mut Order) -> Result<(), String> {
+    let product_id = match order.product_id {
+        Some(product_id) => product_id,
+        None => return Ok(()),
+    };
+    let lines = tax_lines(product_id, order.quantity.saturating_mul(order.unit_price));
+    if lines.is_empty() {
+        return Ok(());
+    }
+    order.total = order
+        .total
+        .checked_add(tax_total(&lines))
+        .ok_or("Order total overflows".to_string())?;
+    save_order_taxes(order.id, lines);
+    Ok(())
+}
+
+// Taxes included in an order's total, if any were charged
+pub(crate) fn order_taxes(order_id: u64) -> Option<Vec<TaxLine>> {
+    ORDER_TAXES_STORAGE.with(|storage| storage.borrow().get(&order_id).map(|taxes| taxes.lines))
+}
+
+fn save_order_taxes(order_id: u64, lines: Vec<TaxLine>) {
+    ORDER_TAXES_STORAGE.with(|storage| storage.borrow_mut().insert(order_id, OrderTaxes { lines }));
+}
+
+// Books the tax on a completed order into its month's totals
+pub(crate) fn record_collected_tax(order: &Order) {
+    let lines = match order_taxes(order.id) {
+        Some(lines) => lines,
+        None => return,
+    };
+    let (year, month) = year_month_of(ic_cdk::api::time());
+    TAX_TOTALS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for line in lines {
+            let key = tax_key(year, month, order.currency(), &line.name);
+            let mut totals = storage.get(&key).unwrap_or_default();
+            totals.orders += 1;
+            totals.taxable = totals.taxable.saturating_add(line.taxable);
+            totals.collected = totals.collected.saturating_add(line.amount);
+            storage.insert(key, totals);
+        }
+    });
+}
+
+// Function for governance to add a tax rule, or change the rate of the rule with the same
+// name, region and category
+#[ic_cdk::update]
+fn set_tax_rule(payload: TaxRulePayload) -> Result<TaxRule, String> {
+    if !is_governor() {
+        return Err("Only governance can set tax rules".to_string());
+    }
+
+    let name = payload.name.trim().to_uppercase();
+    if name.is_empty() || name.len() > MAX_TAX_NAME_LEN || name.contains('|') {
+        return Err("Invalid tax name".to_string());
+    }
+    if payload.rate_bps > MAX_TAX_RATE_BPS {
+        return Err("Tax rate is too high".to_string());
+    }
+    let region = payload
+        .region
+        .map(|region| normalise_region(&region))
+        .transpose()?;
+    let category = payload.category.map(|category| category_key(&category));
+
+    let existing =
+        TAX_RULES_STORAGE.with(|storage| {
+            storage.borrow().iter().map(|(_, rule)| rule).find(|rule| {
+                rule.name == name && rule.region == region && rule.category == category
+            })
+        });
+    if existing.is_none() {
+        let rules = list_tax_rules();
+        if rules.len() >= MAX_TAX_RULES {
+            return Err("Too many tax rules".to_string());
+        }
+        let mut names: Vec<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        if !names.contains(&name.as_str()) && names.len() >= MAX_TAX_NAMES {
+            return Err("Too many distinct taxes".to_string());
+        }
+    }
+
+    let rule = TaxRule {
+        id: existing
+            .map(|rule| rule.id)
+            .unwrap_or_else(|| next_id(&TAX_RULE_ID_COUNTER)),
+        name,
+        region,
+        category,
+        rate_bps: payload.rate_bps,
+        updated_at: ic_cdk::api::time(),
+    };
+    TAX_RULES_STORAGE.with(|storage| storage.borrow_mut().insert(rule.id, rule.clone()));
+    Ok(rule)
+}
+
+// Function for governance to delete a tax rule; orders already placed keep their tax lines
+#[ic_cdk::update]
+fn remove_tax_rule(rule_id: u64) -> Result<(), String> {
+    if !is_governor() {
+        return Err("Only governance can remove tax rules".to_string());
+    }
+    TAX_RULES_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&rule_id))
+        .map(|_| ())
+        .ok_or("Tax rule not found".to_string())
+}
+
+#[ic_cdk::query]
+fn list_tax_rules() -> Vec<TaxRule> {
+    TAX_RULES_STORAGE.with(|storage| storage.borrow().iter().map(|(_, rule)| rule).collect())
+}
+
+// Function for an administrator to see the tax collected on completed orders per month of a
+// year, or in one month of it
+#[ic_cdk::query]
+fn get_tax_report(year: u32, month: Option<u8>) -> Result<Vec<TaxPeriodTotal>, String> {
+    if !is_admin() {
+        return Err("Only an administrator can view the tax report".to_string());
+    }
+    if month.map_or(false, |month| !(1..=12).contains(&month)) {
+        return Err("Month must be between 1 and 12".to_string());
+    }
+
+    let prefix = match month {
+        Some(month) => format!("{:04}|{:02}|", year, month),
+        None => format!("{:04}|", year),
+    };
+    Ok(TAX_TOTALS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(TaxKey(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .filter_map(|(key, totals)| {
+                let parts: Vec<&str> = key.0.splitn(4, '|').collect();
+                let asset = match parts[2] {
+                    "ckbtc" => Asset::CkBtc,
+                    "ckusdc" => Asset::CkUsdc,
+                    _ => Asset::Icp,
+                };
+                Some(TaxPeriodTotal {
+                    year: parts[0].parse().ok()?,
+                    month: parts[1].parse().ok()?,
+                    name: parts[3].to_string(),
+                    asset,
+                    orders: totals.orders,
+                    taxable: totals.taxable,
+                    collected: totals.collected,
+                })
+            })
+            .collect()
+    }))
+}
@@ -0,0 +1,139 @@
+use crate::devices::caller_account;
+use crate::traceability::record_provenance;
+use crate::{is_admin, is_farmer_owner, Farmer, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// How long the owner has to restore a deleted listing
+const RESTORE_WINDOW_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// Tombstone Struct
+// Marks a listing as deleted. The listing itself is kept so its owner and administrators can
+// still read it, and restore it within the window.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Tombstone {
+    listing_id: u64,
+    deleted_by: Principal,
+    deleted_at: u64,
+    restore_until: u64,
+}
+
+// DeletedListing Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct DeletedListing {
+    listing: Farmer,
+    tombstone: Tombstone,
+}
+
+// Storable and BoundedStorable implementations for Tombstone
+impl Storable for Tombstone {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Tombstone {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TOMBSTONES_STORAGE: RefCell<StableBTreeMap<u64, Tombstone, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(173)))
+    ));
+}
+
+// Deleted listings are left out of browsing, search and new purchases
+pub(crate) fn is_deleted(listing_id: u64) -> bool {
+    TOMBSTONES_STORAGE.with(|storage| storage.borrow().contains_key(&listing_id))
+}
+
+// Whether the caller may see a listing: anyone while it is live, only its owner or an
+// administrator once deleted
+pub(crate) fn is_visible(farmer: &Farmer) -> bool {
+    !is_deleted(farmer.id) || is_farmer_owner(farmer) || is_admin()
+}
+
+fn listing(listing_id: u64) -> Result<Farmer, String> {
+    FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Farmer not found".to_string())
+}
+
+// Function for the owner or an administrator to delete a listing. It can be restored for
+// 30 days.
+#[ic_cdk::update]
+fn delete_listing(listing_id: u64) -> Result<Tombstone, String> {
+    let farmer = listing(listing_id)?;
+    if !is_farmer_owner(&farmer) && !is_admin() {
+        return Err("Only the farmer or an administrator can delete a listing".to_string());
+    }
+    if is_deleted(listing_id) {
+        return Err("Listing already deleted".to_string());
+    }
+    if farmer.consumer_address.is_some() && !farmer.is_sold {
+        return Err("Listing has a bid in progress".to_string());
+    }
+    if farmer.dispute_status {
+        return Err("Listing is under dispute".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    let tombstone = Tombstone {
+        listing_id,
+        deleted_by: caller_account(),
+        deleted_at: now,
+        restore_until: now + RESTORE_WINDOW_NANOS,
+    };
+    TOMBSTONES_STORAGE.with(|storage| storage.borrow_mut().insert(listing_id, tombstone.clone()));
+    record_provenance(listing_id, "Deleted");
+    Ok(tombstone)
+}
+
+#[ic_cdk::update]
+fn restore_listing(listing_id: u64) -> Result<(), String> {
+    let farmer = listing(listing_id)?;
+    if !is_farmer_owner(&farmer) && !is_admin() {
+        return Err("Only the farmer or an administrator can restore a listing".to_string());
+    }
+    let tombstone = TOMBSTONES_STORAGE
+        .with(|storage| storage.borrow().get(&listing_id))
+        .ok_or("Listing is not deleted".to_string())?;
+    if ic_cdk::api::time() > tombstone.restore_until {
+        return Err("The restore window has passed".to_string());
+    }
+
+    TOMBSTONES_STORAGE.with(|storage| storage.borrow_mut().remove(&listing_id));
+    record_provenance(listing_id, "Restored");
+    Ok(())
+}
+
+// Deleted listings of the caller, or every deleted listing for an administrator
+#[ic_cdk::query]
+fn get_deleted_listings() -> Vec<DeletedListing> {
+    let admin = is_admin();
+    let tombstones: Vec<Tombstone> = TOMBSTONES_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, tombstone)| tombstone)
+            .collect()
+    });
+    tombstones
+        .into_iter()
+        .filter_map(|tombstone| {
+            let listing = listing(tombstone.listing_id).ok()?;
+            if !admin && !is_farmer_owner(&listing) {
+                return None;
+            }
+            Some(DeletedListing { listing, tombstone })
+        })
+        .collect()
+}
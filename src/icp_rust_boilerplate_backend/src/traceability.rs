@@ -0,0 +1,319 @@
+use crate::{Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use hmac::{Hmac, Mac};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// ProvenanceRoot Struct
+// Head of a hash chain over every lifecycle event of a product
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct ProvenanceRoot {
+    root: Vec<u8>,
+    event_count: u64,
+    updated_at: u64,
+}
+
+// ProvenanceLink Struct
+// Where a root that was once the head of a product's chain sits in it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ProvenanceLink {
+    product_id: u64,
+    event_count: u64,
+}
+
+// RootKey Struct
+// Lets provenance roots key stable maps
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RootKey(Vec<u8>);
+
+// QrPayload Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct QrPayload {
+    product_id: u64,
+    // Head of the provenance chain when the payload was generated
+    provenance_root: Vec<u8>,
+    canister_id: Principal,
+    signature: Vec<u8>,
+}
+
+// ProductProvenance Struct
+// What anyone scanning a product's QR code is shown about it
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ProductProvenance {
+    product_id: u64,
+    name: String,
+    category: String,
+    product_status: String,
+    provenance_root: Vec<u8>,
+    event_count: u64,
+    // Lifecycle events recorded after the payload was generated
+    events_since_issue: u64,
+    last_event_at: u64,
+}
+
+// Storable and BoundedStorable implementations for ProvenanceRoot
+impl Storable for ProvenanceRoot {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProvenanceRoot {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for ProvenanceLink
+impl Storable for ProvenanceLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProvenanceLink {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for RootKey
+impl Storable for RootKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RootKey(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for RootKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PROVENANCE_STORAGE: RefCell<StableBTreeMap<u64, ProvenanceRoot, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // Secret used to sign QR payloads, seeded from raw_rand on first use
+    static SIGNING_SECRET: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))), Vec::new())
+            .expect("Cannot create the signing secret")
+    );
+
+    // Every root a product's chain has had, so a payload stays valid as the chain grows
+    static PROVENANCE_HISTORY: RefCell<StableBTreeMap<RootKey, ProvenanceLink, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(234)))
+    ));
+}
+
+fn record_link(product_id: u64, provenance: &ProvenanceRoot) {
+    let link = ProvenanceLink {
+        product_id,
+        event_count: provenance.event_count,
+    };
+    PROVENANCE_HISTORY.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(RootKey(provenance.root.clone()), link)
+    });
+}
+
+// Extends the product's provenance chain with a new lifecycle event
+pub(crate) fn record_provenance(product_id: u64, event: &str) {
+    let now = ic_cdk::api::time();
+    let mut provenance = PROVENANCE_STORAGE
+        .with(|storage| storage.borrow().get(&product_id))
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(&provenance.root);
+    hasher.update(product_id.to_be_bytes());
+    hasher.update(event.as_bytes());
+    hasher.update(now.to_be_bytes());
+
+    provenance.root = hasher.finalize().to_vec();
+    provenance.event_count += 1;
+    provenance.updated_at = now;
+    record_link(product_id, &provenance);
+    PROVENANCE_STORAGE.with(|storage| storage.borrow_mut().insert(product_id, provenance));
+}
+
+// When the product's last lifecycle event was recorded
+pub(crate) fn last_activity_at(product_id: u64) -> Option<u64> {
+    PROVENANCE_STORAGE.with(|storage| storage.borrow().get(&product_id).map(|p| p.updated_at))
+}
+
+pub(crate) fn provenance_root(product_id: u64) -> Option<Vec<u8>> {
+    PROVENANCE_STORAGE.with(|storage| storage.borrow().get(&product_id).map(|p| p.root))
+}
+
+// HMAC-SHA256 keyed with the signing secret over what a QR payload vouches for
+fn payload_mac(secret: &[u8], product_id: u64, root: &[u8], canister_id: &Principal) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&product_id.to_be_bytes());
+    mac.update(&(root.len() as u64).to_be_bytes());
+    mac.update(root);
+    mac.update(canister_id.as_slice());
+    mac
+}
+
+fn sign(secret: &[u8], product_id: u64, root: &[u8], canister_id: &Principal) -> Vec<u8> {
+    payload_mac(secret, product_id, root, canister_id)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+// Compares in constant time, so the check leaks nothing about the expected signature
+fn verify_signature(secret: &[u8], payload: &QrPayload) -> bool {
+    payload_mac(
+        secret,
+        payload.product_id,
+        &payload.provenance_root,
+        &payload.canister_id,
+    )
+    .verify_slice(&payload.signature)
+    .is_ok()
+}
+
+async fn signing_secret() -> Result<Vec<u8>, String> {
+    let secret = SIGNING_SECRET.with(|cell| cell.borrow().get().clone());
+    if !secret.is_empty() {
+        return Ok(secret);
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, msg)| format!("Cannot generate signing secret: {}", msg))?;
+
+    // Another call may have seeded the secret while we were waiting
+    SIGNING_SECRET.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if cell.get().is_empty() {
+            cell.set(random)
+                .map_err(|_| "Cannot store signing secret".to_string())?;
+        }
+        Ok(cell.get().clone())
+    })
+}
+
+// Produces the payload to be encoded in the QR code printed on a product's packaging
+#[ic_cdk::update]
+async fn generate_qr_payload(product_id: u64) -> Result<QrPayload, String> {
+    if !FARMERS_STORAGE.with(|storage| storage.borrow().contains_key(&product_id)) {
+        return Err("Farmer not found".to_string());
+    }
+
+    let secret = signing_secret().await?;
+    let provenance_root =
+        provenance_root(product_id).ok_or("No provenance recorded for product".to_string())?;
+    let canister_id = ic_cdk::id();
+
+    Ok(QrPayload {
+        product_id,
+        signature: sign(&secret, product_id, &provenance_root, &canister_id),
+        provenance_root,
+        canister_id,
+    })
+}
+
+// Validates a scanned payload and returns the product's provenance. The payload signs the
+// chain's root when it was generated, so it stays valid while later events extend the chain.
+#[ic_cdk::query]
+fn verify_qr_payload(payload: QrPayload) -> Result<ProductProvenance, String> {
+    if payload.canister_id != ic_cdk::id() {
+        return Err("Payload issued by another canister".to_string());
+    }
+
+    let secret = SIGNING_SECRET.with(|cell| cell.borrow().get().clone());
+    if secret.is_empty() || !verify_signature(&secret, &payload) {
+        return Err("Invalid signature".to_string());
+    }
+
+    let current = PROVENANCE_STORAGE
+        .with(|storage| storage.borrow().get(&payload.product_id))
+        .ok_or("No provenance recorded for product".to_string())?;
+    let issued_at_count = if current.root == payload.provenance_root {
+        current.event_count
+    } else {
+        match PROVENANCE_HISTORY.with(|storage| {
+            storage
+                .borrow()
+                .get(&RootKey(payload.provenance_root.clone()))
+        }) {
+            Some(link) if link.product_id == payload.product_id => link.event_count,
+            _ => return Err("Provenance chain does not extend the payload's root".to_string()),
+        }
+    };
+
+    let product = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&payload.product_id))
+        .ok_or("Farmer not found".to_string())?;
+    Ok(ProductProvenance {
+        product_id: product.id,
+        name: product.name,
+        category: product.category,
+        product_status: product.product_status,
+        provenance_root: current.root,
+        event_count: current.event_count,
+        events_since_issue: current.event_count.saturating_sub(issued_at_count),
+        last_event_at: current.updated_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_payload(secret: &[u8]) -> QrPayload {
+        let canister_id = Principal::from_slice(&[9]);
+        let provenance_root = vec![3u8; 32];
+        QrPayload {
+            product_id: 4,
+            signature: sign(secret, 4, &provenance_root, &canister_id),
+            provenance_root,
+            canister_id,
+        }
+    }
+
+    #[test]
+    fn a_signed_payload_verifies() {
+        let payload = signed_payload(b"secret");
+        assert_eq!(payload.signature.len(), 32);
+        assert!(verify_signature(b"secret", &payload));
+        assert!(!verify_signature(b"another secret", &payload));
+    }
+
+    #[test]
+    fn a_changed_payload_does_not_verify() {
+        let mut payload = signed_payload(b"secret");
+        payload.product_id = 5;
+        assert!(!verify_signature(b"secret", &payload));
+
+        let mut payload = signed_payload(b"secret");
+        payload.provenance_root[0] ^= 1;
+        assert!(!verify_signature(b"secret", &payload));
+
+        // Moving bytes between the root and the canister ID changes the signed message
+        let mut payload = signed_payload(b"secret");
+        payload.provenance_root.push(9);
+        payload.canister_id = Principal::from_slice(&[]);
+        assert!(!verify_signature(b"secret", &payload));
+    }
+}
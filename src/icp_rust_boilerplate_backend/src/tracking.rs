@@ -0,0 +1,166 @@
+use crate::deliveries::{can_view, get_delivery_record, in_transit_deliveries};
+use crate::devices::caller_account;
+use crate::notifications::notify;
+use crate::orders::get_order_record;
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CHECKPOINTS: usize = 100;
+const MAX_NOTE_LEN: usize = 100;
+// A delivery in transit with no checkpoint for six hours is flagged as stalled
+const STALL_THRESHOLD_NANOS: u64 = 6 * 60 * 60 * 1_000_000_000;
+
+// Checkpoint Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Checkpoint {
+    latitude: f64,
+    longitude: f64,
+    note: String,
+    recorded_at: u64,
+}
+
+// DeliveryTrack Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct DeliveryTrack {
+    order_id: u64,
+    checkpoints: Vec<Checkpoint>,
+    // Set while no checkpoint has arrived within the stall threshold
+    stalled_since: Option<u64>,
+}
+
+// Storable and BoundedStorable implementations for DeliveryTrack
+impl Storable for DeliveryTrack {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeliveryTrack {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TRACKS_STORAGE: RefCell<StableBTreeMap<u64, DeliveryTrack, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+}
+
+fn get_track_record(order_id: u64) -> DeliveryTrack {
+    TRACKS_STORAGE
+        .with(|storage| storage.borrow().get(&order_id))
+        .unwrap_or(DeliveryTrack {
+            order_id,
+            ..Default::default()
+        })
+}
+
+fn save_track(track: DeliveryTrack) {
+    TRACKS_STORAGE.with(|storage| storage.borrow_mut().insert(track.order_id, track));
+}
+
+// Orders with a recorded delivery track
+pub(crate) fn tracked_order_ids() -> Vec<u64> {
+    TRACKS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(order_id, _)| order_id)
+            .collect()
+    })
+}
+
+pub(crate) fn remove_track(order_id: u64) {
+    TRACKS_STORAGE.with(|storage| storage.borrow_mut().remove(&order_id));
+}
+
+// Flags deliveries in transit whose last checkpoint, or pickup, is older than the threshold
+pub(crate) fn flag_stalled_deliveries() {
+    let now = ic_cdk::api::time();
+
+    for delivery in in_transit_deliveries() {
+        let mut track = get_track_record(delivery.order_id);
+        if track.stalled_since.is_some() {
+            continue;
+        }
+
+        let last_seen = track
+            .checkpoints
+            .last()
+            .map(|checkpoint| checkpoint.recorded_at)
+            .or(delivery.pickup_at);
+        let last_seen = match last_seen {
+            Some(last_seen) => last_seen,
+            None => continue,
+        };
+        if last_seen.saturating_add(STALL_THRESHOLD_NANOS) > now {
+            continue;
+        }
+
+        track.stalled_since = Some(now);
+        save_track(track);
+        if let Ok(order) = get_order_record(delivery.order_id) {
+            let message = format!("Delivery of order #{} has stalled", order.id);
+            notify(order.farmer, message.clone());
+            notify(order.consumer, message);
+        }
+    }
+}
+
+// Function for the assigned logistics partner to record where a delivery is
+#[ic_cdk::update]
+fn add_delivery_checkpoint(
+    order_id: u64,
+    latitude: f64,
+    longitude: f64,
+    note: String,
+) -> Result<DeliveryTrack, String> {
+    let delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+
+    if delivery.partner != Some(caller_account()) {
+        return Err("Only the assigned logistics partner can add checkpoints".to_string());
+    }
+    if delivery.is_closed() {
+        return Err("Delivery is already closed".to_string());
+    }
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err("Invalid coordinates".to_string());
+    }
+    if note.len() > MAX_NOTE_LEN {
+        return Err("Note is too long".to_string());
+    }
+
+    let mut track = get_track_record(order_id);
+    if track.checkpoints.len() >= MAX_CHECKPOINTS {
+        return Err("Too many checkpoints".to_string());
+    }
+
+    track.checkpoints.push(Checkpoint {
+        latitude,
+        longitude,
+        note,
+        recorded_at: ic_cdk::api::time(),
+    });
+    track.stalled_since = None;
+    save_track(track.clone());
+    Ok(track)
+}
+
+#[ic_cdk::query]
+fn get_delivery_track(order_id: u64) -> Result<DeliveryTrack, String> {
+    let delivery = get_delivery_record(order_id).ok_or("Delivery not found".to_string())?;
+    let order = get_order_record(order_id)?;
+
+    if !can_view(&delivery, &order, caller_account()) {
+        return Err("Delivery not found".to_string());
+    }
+    Ok(get_track_record(order_id))
+}
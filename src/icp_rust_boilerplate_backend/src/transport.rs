@@ -0,0 +1,256 @@
+use crate::bids::{
+    accept_bid_record, get_bid_record, listing_bids, place_bid, reject_open_bids, Bid, ListingKind,
+};
+use crate::devices::caller_account;
+use crate::logistics::get_partner_record;
+use crate::notifications::notify;
+use crate::shipping::normalise_region;
+use crate::{next_id, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_LOAD_DESCRIPTION_LEN: usize = 200;
+
+// TransportJobStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TransportJobStatus {
+    Open,
+    Awarded,
+    Completed,
+    Cancelled,
+}
+
+// TransportJob Struct
+// A load a farmer needs moved, put out for logistics partners to bid on
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TransportJob {
+    id: u64,
+    farmer: Principal,
+    origin: String,
+    destination: String,
+    pickup_at: u64,
+    load_description: String,
+    load_quantity: u64,
+    status: TransportJobStatus,
+    awarded_bid: Option<u64>,
+    partner: Option<Principal>,
+    price: Option<u64>,
+    created_at: u64,
+}
+
+// TransportJobPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct TransportJobPayload {
+    origin: String,
+    destination: String,
+    pickup_at: u64,
+    load_description: String,
+    load_quantity: u64,
+}
+
+// Storable and BoundedStorable implementations for TransportJob
+impl Storable for TransportJob {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TransportJob {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TRANSPORT_JOB_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static TRANSPORT_JOBS_STORAGE: RefCell<StableBTreeMap<u64, TransportJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+}
+
+fn get_job_record(job_id: u64) -> Result<TransportJob, String> {
+    TRANSPORT_JOBS_STORAGE
+        .with(|storage| storage.borrow().get(&job_id))
+        .ok_or("Transport job not found".to_string())
+}
+
+fn save_job(job: TransportJob) {
+    TRANSPORT_JOBS_STORAGE.with(|storage| storage.borrow_mut().insert(job.id, job));
+}
+
+// Loads a job the caller, as the farmer who posted it, may manage
+fn own_job(job_id: u64) -> Result<TransportJob, String> {
+    let job = get_job_record(job_id)?;
+    if job.farmer != caller_account() {
+        return Err("Only the farmer who posted this job can manage it".to_string());
+    }
+    Ok(job)
+}
+
+// Function for a farmer to post a load they need moved
+#[ic_cdk::update]
+fn post_transport_job(payload: TransportJobPayload) -> Result<TransportJob, String> {
+    let origin = normalise_region(&payload.origin)?;
+    let destination = normalise_region(&payload.destination)?;
+    let now = ic_cdk::api::time();
+
+    if payload.pickup_at <= now {
+        return Err("Pickup must be in the future".to_string());
+    }
+    if payload.load_description.trim().is_empty()
+        || payload.load_description.len() > MAX_LOAD_DESCRIPTION_LEN
+    {
+        return Err("Invalid load description".to_string());
+    }
+    if payload.load_quantity == 0 {
+        return Err("Load must be positive".to_string());
+    }
+
+    let job = TransportJob {
+        id: next_id(&TRANSPORT_JOB_ID_COUNTER),
+        farmer: caller_account(),
+        origin,
+        destination,
+        pickup_at: payload.pickup_at,
+        load_description: payload.load_description,
+        load_quantity: payload.load_quantity,
+        status: TransportJobStatus::Open,
+        awarded_bid: None,
+        partner: None,
+        price: None,
+        created_at: now,
+    };
+
+    save_job(job.clone());
+    Ok(job)
+}
+
+// Function for a logistics partner serving the route to quote for an open job
+#[ic_cdk::update]
+fn bid_on_transport_job(job_id: u64, amount: u64, note: String) -> Result<Bid, String> {
+    let job = get_job_record(job_id)?;
+    let bidder = caller_account();
+    let partner =
+        get_partner_record(&bidder).ok_or("Only logistics partners can bid on jobs".to_string())?;
+
+    if job.status != TransportJobStatus::Open || job.pickup_at <= ic_cdk::api::time() {
+        return Err("Job is not open for bids".to_string());
+    }
+    if !partner.covers(&job.origin) || !partner.covers(&job.destination) {
+        return Err("You do not cover this route".to_string());
+    }
+    if job.load_quantity > partner.vehicle_capacity {
+        return Err("Load exceeds your vehicle capacity".to_string());
+    }
+    if amount == 0 {
+        return Err("Bid must be positive".to_string());
+    }
+
+    let bid = place_bid(ListingKind::Transport, job_id, bidder, amount, note)?;
+    notify(
+        job.farmer,
+        format!("New bid of {} on transport job #{}", amount, job.id),
+    );
+    Ok(bid)
+}
+
+// Function for the farmer to award a job to one of its bids
+#[ic_cdk::update]
+fn accept_transport_bid(bid_id: u64) -> Result<TransportJob, String> {
+    let bid = get_bid_record(bid_id)?;
+    if bid.kind != ListingKind::Transport {
+        return Err("Not a transport bid".to_string());
+    }
+    let mut job = own_job(bid.listing_id)?;
+
+    if job.status != TransportJobStatus::Open {
+        return Err("Job is not open for bids".to_string());
+    }
+
+    let bid = accept_bid_record(bid)?;
+    job.status = TransportJobStatus::Awarded;
+    job.awarded_bid = Some(bid.id);
+    job.partner = Some(bid.bidder);
+    job.price = Some(bid.amount);
+    save_job(job.clone());
+    Ok(job)
+}
+
+// Function for the farmer to withdraw a job that has not been completed
+#[ic_cdk::update]
+fn cancel_transport_job(job_id: u64) -> Result<(), String> {
+    let mut job = own_job(job_id)?;
+
+    match job.status {
+        TransportJobStatus::Open => reject_open_bids(ListingKind::Transport, job_id),
+        TransportJobStatus::Awarded => {
+            if let Some(partner) = job.partner {
+                notify(
+                    partner,
+                    format!("Transport job #{} was cancelled by the farmer", job.id),
+                );
+            }
+        }
+        _ => return Err("Job is already closed".to_string()),
+    }
+
+    job.status = TransportJobStatus::Cancelled;
+    save_job(job);
+    Ok(())
+}
+
+// Function for the farmer to confirm the awarded partner moved the load
+#[ic_cdk::update]
+fn complete_transport_job(job_id: u64) -> Result<TransportJob, String> {
+    let mut job = own_job(job_id)?;
+
+    if job.status != TransportJobStatus::Awarded {
+        return Err("Job has not been awarded".to_string());
+    }
+
+    job.status = TransportJobStatus::Completed;
+    save_job(job.clone());
+    Ok(job)
+}
+
+#[ic_cdk::query]
+fn get_transport_job(job_id: u64) -> Result<TransportJob, String> {
+    get_job_record(job_id)
+}
+
+// Lists jobs still taking bids, optionally only those starting from a region
+#[ic_cdk::query]
+fn list_open_transport_jobs(origin: Option<String>) -> Vec<TransportJob> {
+    let origin = origin.map(|region| region.trim().to_lowercase());
+    TRANSPORT_JOBS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| job.status == TransportJobStatus::Open)
+            .filter(|job| origin.as_ref().map_or(true, |origin| job.origin == *origin))
+            .collect()
+    })
+}
+
+// The farmer sees every bid on their job; a partner sees only their own
+#[ic_cdk::query]
+fn get_transport_job_bids(job_id: u64) -> Result<Vec<Bid>, String> {
+    let job = get_job_record(job_id)?;
+    let caller = caller_account();
+
+    Ok(listing_bids(ListingKind::Transport, job_id)
+        .into_iter()
+        .filter(|bid| job.farmer == caller || bid.bidder == caller)
+        .collect())
+}
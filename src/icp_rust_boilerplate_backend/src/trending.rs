@@ -0,0 +1,223 @@
+use crate::devices::caller_account;
+use crate::{is_off_market, Memory, FARMERS_STORAGE, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const TRENDING_WINDOW_DAYS: u64 = 7;
+// A bid shows far more intent than a view
+const BID_WEIGHT: u64 = 5;
+const MAX_TRENDING: u64 = 50;
+
+// TrendingListing Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TrendingListing {
+    farmer_id: u64,
+    views: u64,
+    bids: u64,
+    score: u64,
+}
+
+// ListingActivity Struct
+// Views and bids a listing received on one day
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+struct ListingActivity {
+    views: u64,
+    bids: u64,
+}
+
+// DayListingKey Struct
+// Day number first, so the window is one contiguous range; the viewer is set for the
+// de-duplication set and empty for activity counts
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DayListingKey {
+    day: u64,
+    listing_id: u64,
+    viewer: Option<Principal>,
+}
+
+// Storable and BoundedStorable implementations for ListingActivity
+impl Storable for ListingActivity {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ListingActivity {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for DayListingKey
+impl Storable for DayListingKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16 + 29);
+        bytes.extend_from_slice(&self.day.to_be_bytes());
+        bytes.extend_from_slice(&self.listing_id.to_be_bytes());
+        if let Some(viewer) = self.viewer {
+            bytes.extend_from_slice(viewer.as_slice());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (day, rest) = bytes.split_at(8);
+        let (listing_id, viewer) = rest.split_at(8);
+        DayListingKey {
+            day: u64::from_be_bytes(day.try_into().unwrap()),
+            listing_id: u64::from_be_bytes(listing_id.try_into().unwrap()),
+            viewer: if viewer.is_empty() {
+                None
+            } else {
+                Some(Principal::from_slice(viewer))
+            },
+        }
+    }
+}
+
+impl BoundedStorable for DayListingKey {
+    const MAX_SIZE: u32 = 45;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LISTING_ACTIVITY_STORAGE: RefCell<StableBTreeMap<DayListingKey, ListingActivity, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(124)))
+    ));
+
+    // Who viewed which listing on which day, and when, so repeat views are counted once
+    static LISTING_VIEWERS: RefCell<StableBTreeMap<DayListingKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(125)))
+    ));
+}
+
+fn today() -> u64 {
+    ic_cdk::api::time() / NANOS_PER_DAY
+}
+
+fn update_today(listing_id: u64, update: impl FnOnce(&mut ListingActivity)) {
+    let key = DayListingKey {
+        day: today(),
+        listing_id,
+        viewer: None,
+    };
+    LISTING_ACTIVITY_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut activity = storage.get(&key).unwrap_or_default();
+        update(&mut activity);
+        storage.insert(key, activity);
+    });
+}
+
+pub(crate) fn record_bid(listing_id: u64) {
+    update_today(listing_id, |activity| activity.bids += 1);
+}
+
+// Drops activity that has aged out of the trending window
+pub(crate) fn prune_trending() {
+    let cutoff = today().saturating_sub(TRENDING_WINDOW_DAYS);
+    let expired = |key: &DayListingKey| key.day < cutoff;
+
+    LISTING_ACTIVITY_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<DayListingKey> = storage
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(expired)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+    });
+    LISTING_VIEWERS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<DayListingKey> = storage
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(expired)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+    });
+}
+
+// Function for the frontend to count a signed-in user's view of a listing, once per day
+#[ic_cdk::update]
+fn record_listing_view(farmer_id: u64) -> Result<(), String> {
+    let viewer = caller_account();
+
+    if viewer == Principal::anonymous() {
+        return Err("Sign in to record views".to_string());
+    }
+    if FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .map_or(true, |farmer| is_off_market(&farmer))
+    {
+        return Err("Farmer not found".to_string());
+    }
+
+    let seen = DayListingKey {
+        day: today(),
+        listing_id: farmer_id,
+        viewer: Some(viewer),
+    };
+    if LISTING_VIEWERS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(seen, ic_cdk::api::time())
+            .is_none()
+    }) {
+        update_today(farmer_id, |activity| activity.views += 1);
+    }
+    Ok(())
+}
+
+// Unsold listings with the most views and bids over the last week, hottest first
+#[ic_cdk::query]
+fn get_trending(limit: u64) -> Vec<TrendingListing> {
+    let start = DayListingKey {
+        day: (today() + 1).saturating_sub(TRENDING_WINDOW_DAYS),
+        listing_id: 0,
+        viewer: None,
+    };
+
+    let mut totals: BTreeMap<u64, ListingActivity> = BTreeMap::new();
+    LISTING_ACTIVITY_STORAGE.with(|storage| {
+        for (key, activity) in storage.borrow().range(start..) {
+            let total = totals.entry(key.listing_id).or_default();
+            total.views += activity.views;
+            total.bids += activity.bids;
+        }
+    });
+
+    let mut trending: Vec<TrendingListing> = FARMERS_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        totals
+            .into_iter()
+            .filter(|(farmer_id, _)| {
+                storage
+                    .get(farmer_id)
+                    .map_or(false, |farmer| !farmer.is_sold && !is_off_market(&farmer))
+            })
+            .map(|(farmer_id, total)| TrendingListing {
+                farmer_id,
+                views: total.views,
+                bids: total.bids,
+                score: total.views + total.bids * BID_WEIGHT,
+            })
+            .collect()
+    });
+    trending.sort_by(|a, b| b.score.cmp(&a.score).then(b.bids.cmp(&a.bids)));
+    trending.truncate(limit.min(MAX_TRENDING) as usize);
+    trending
+}
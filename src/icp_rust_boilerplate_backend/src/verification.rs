@@ -0,0 +1,79 @@
+use crate::devices::caller_account;
+use crate::{is_admin, Memory, PrincipalKey, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Verification Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Verification {
+    farmer: Principal,
+    verified_by: Principal,
+    verified_at: u64,
+}
+
+// Storable and BoundedStorable implementations for Verification
+impl Storable for Verification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Verification {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static VERIFIED_FARMERS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Verification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+}
+
+pub(crate) fn is_verified_farmer(farmer: &Principal) -> bool {
+    VERIFIED_FARMERS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*farmer)))
+}
+
+#[ic_cdk::update]
+fn verify_farmer(farmer: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can verify farmers".to_string());
+    }
+
+    let verification = Verification {
+        farmer,
+        verified_by: caller_account(),
+        verified_at: ic_cdk::api::time(),
+    };
+    VERIFIED_FARMERS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(farmer), verification)
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn revoke_farmer_verification(farmer: Principal) -> Result<(), String> {
+    if !is_admin() {
+        return Err("Only an administrator can revoke verification".to_string());
+    }
+
+    VERIFIED_FARMERS_STORAGE
+        .with(|storage| storage.borrow_mut().remove(&PrincipalKey(farmer)))
+        .map(|_| ())
+        .ok_or("Farmer is not verified".to_string())
+}
+
+#[ic_cdk::query]
+fn get_verification(farmer: Principal) -> Result<Verification, String> {
+    VERIFIED_FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(farmer)))
+        .ok_or("Farmer is not verified".to_string())
+}
@@ -0,0 +1,329 @@
+use crate::devices::caller_account;
+use crate::inventory::set_stock;
+use crate::notifications::notify;
+use crate::{
+    is_admin, is_farmer_owner, next_id, IdCell, Memory, PrincipalKey, FARMERS_STORAGE,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_TEXT_LEN: usize = 100;
+
+// WarehouseOperator Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct WarehouseOperator {
+    principal: Principal,
+    name: String,
+    location: String,
+    registered_at: u64,
+}
+
+// ReceiptStatus Enum
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReceiptStatus {
+    Active,
+    Redeemed,
+}
+
+// WarehouseReceipt Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct WarehouseReceipt {
+    pub(crate) id: u64,
+    pub(crate) operator: Principal,
+    pub(crate) holder: Principal,
+    pub(crate) commodity: String,
+    pub(crate) quantity: u64,
+    pub(crate) grade: String,
+    pub(crate) storage_location: String,
+    pub(crate) issued_at: u64,
+    pub(crate) expires_at: u64,
+    pub(crate) status: ReceiptStatus,
+    // Listing whose stock this receipt backs
+    pub(crate) backed_listing: Option<u64>,
+    // Loan this receipt is pledged to as collateral
+    pub(crate) pledged_loan: Option<u64>,
+}
+
+// ReceiptPayload Struct
+#[derive(candid::CandidType, Deserialize, Serialize)]
+pub(crate) struct ReceiptPayload {
+    holder: Principal,
+    commodity: String,
+    quantity: u64,
+    grade: String,
+    storage_location: String,
+    expires_at: u64,
+}
+
+impl WarehouseReceipt {
+    // Active receipts can be traded and used until they expire
+    pub(crate) fn is_usable(&self) -> bool {
+        self.status == ReceiptStatus::Active && self.expires_at > ic_cdk::api::time()
+    }
+}
+
+// Storable and BoundedStorable implementations for WarehouseOperator
+impl Storable for WarehouseOperator {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WarehouseOperator {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storable and BoundedStorable implementations for WarehouseReceipt
+impl Storable for WarehouseReceipt {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WarehouseReceipt {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static OPERATORS_STORAGE: RefCell<StableBTreeMap<PrincipalKey, WarehouseOperator, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    static RECEIPT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static RECEIPTS_STORAGE: RefCell<StableBTreeMap<u64, WarehouseReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    // Listing id to the receipt backing it
+    static LISTING_RECEIPTS_STORAGE: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+}
+
+pub(crate) fn get_receipt_record(receipt_id: u64) -> Result<WarehouseReceipt, String> {
+    RECEIPTS_STORAGE
+        .with(|storage| storage.borrow().get(&receipt_id))
+        .ok_or("Warehouse receipt not found".to_string())
+}
+
+pub(crate) fn save_receipt(receipt: WarehouseReceipt) {
+    RECEIPTS_STORAGE.with(|storage| storage.borrow_mut().insert(receipt.id, receipt));
+}
+
+pub(crate) fn backing_receipt(farmer_id: u64) -> Option<u64> {
+    LISTING_RECEIPTS_STORAGE.with(|storage| storage.borrow().get(&farmer_id))
+}
+
+pub(crate) fn is_operator(principal: &Principal) -> bool {
+    OPERATORS_STORAGE.with(|storage| storage.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+fn valid_text(text: &str) -> bool {
+    !text.trim().is_empty() && text.len() <= MAX_TEXT_LEN
+}
+
+// Function for an administrator to register a warehouse operator
+#[ic_cdk::update]
+fn register_warehouse_operator(
+    principal: Principal,
+    name: String,
+    location: String,
+) -> Result<WarehouseOperator, String> {
+    if !is_admin() {
+        return Err("Only an administrator can register warehouse operators".to_string());
+    }
+    if !valid_text(&name) || !valid_text(&location) {
+        return Err("Invalid operator details".to_string());
+    }
+
+    let operator = WarehouseOperator {
+        principal,
+        name,
+        location,
+        registered_at: ic_cdk::api::time(),
+    };
+    OPERATORS_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(PrincipalKey(principal), operator.clone())
+    });
+    Ok(operator)
+}
+
+#[ic_cdk::query]
+fn get_warehouse_operator(principal: Principal) -> Result<WarehouseOperator, String> {
+    OPERATORS_STORAGE
+        .with(|storage| storage.borrow().get(&PrincipalKey(principal)))
+        .ok_or("Warehouse operator not found".to_string())
+}
+
+// Function for an operator to issue a receipt for goods deposited by a farmer
+#[ic_cdk::update]
+fn issue_warehouse_receipt(payload: ReceiptPayload) -> Result<WarehouseReceipt, String> {
+    let operator = caller_account();
+
+    if !is_operator(&operator) {
+        return Err("Only a registered warehouse operator can issue receipts".to_string());
+    }
+    if !valid_text(&payload.commodity)
+        || !valid_text(&payload.grade)
+        || !valid_text(&payload.storage_location)
+    {
+        return Err("Invalid receipt details".to_string());
+    }
+    if payload.quantity == 0 {
+        return Err("Quantity must be positive".to_string());
+    }
+    let now = ic_cdk::api::time();
+    if payload.expires_at <= now {
+        return Err("Expiry must be in the future".to_string());
+    }
+
+    let receipt = WarehouseReceipt {
+        id: next_id(&RECEIPT_ID_COUNTER),
+        operator,
+        holder: payload.holder,
+        commodity: payload.commodity,
+        quantity: payload.quantity,
+        grade: payload.grade,
+        storage_location: payload.storage_location,
+        issued_at: now,
+        expires_at: payload.expires_at,
+        status: ReceiptStatus::Active,
+        backed_listing: None,
+        pledged_loan: None,
+    };
+
+    save_receipt(receipt.clone());
+    notify(
+        receipt.holder,
+        format!(
+            "You were issued warehouse receipt #{} for {} {}",
+            receipt.id, receipt.quantity, receipt.commodity
+        ),
+    );
+    Ok(receipt)
+}
+
+// Function for the holder to transfer a receipt to someone else
+#[ic_cdk::update]
+fn transfer_warehouse_receipt(receipt_id: u64, to: Principal) -> Result<WarehouseReceipt, String> {
+    let mut receipt = get_receipt_record(receipt_id)?;
+
+    if receipt.holder != caller_account() {
+        return Err("Only the holder can transfer this receipt".to_string());
+    }
+    if !receipt.is_usable() {
+        return Err("Receipt is redeemed or expired".to_string());
+    }
+    if receipt.backed_listing.is_some() {
+        return Err("Receipt backs a listing".to_string());
+    }
+    if receipt.pledged_loan.is_some() {
+        return Err("Receipt is pledged to a loan".to_string());
+    }
+
+    receipt.holder = to;
+    save_receipt(receipt.clone());
+    notify(
+        to,
+        format!("Warehouse receipt #{} was transferred to you", receipt_id),
+    );
+    Ok(receipt)
+}
+
+// Function for the holder to back one of their listings with the stored goods; the listing's
+// stock becomes the receipt quantity
+#[ic_cdk::update]
+fn back_listing_with_receipt(receipt_id: u64, farmer_id: u64) -> Result<WarehouseReceipt, String> {
+    let mut receipt = get_receipt_record(receipt_id)?;
+    let farmer = FARMERS_STORAGE
+        .with(|storage| storage.borrow().get(&farmer_id))
+        .ok_or("Farmer not found".to_string())?;
+
+    if receipt.holder != caller_account() || !is_farmer_owner(&farmer) {
+        return Err("Only the holder can back their own listing".to_string());
+    }
+    if !receipt.is_usable() {
+        return Err("Receipt is redeemed or expired".to_string());
+    }
+    if receipt.backed_listing.is_some() || backing_receipt(farmer_id).is_some() {
+        return Err("Receipt or listing is already backed".to_string());
+    }
+    if receipt.pledged_loan.is_some() {
+        return Err("Receipt is pledged to a loan".to_string());
+    }
+
+    receipt.backed_listing = Some(farmer_id);
+    set_stock(farmer_id, receipt.quantity);
+    LISTING_RECEIPTS_STORAGE.with(|storage| storage.borrow_mut().insert(farmer_id, receipt_id));
+    save_receipt(receipt.clone());
+    Ok(receipt)
+}
+
+// Function for the issuing operator to redeem a receipt when the goods are withdrawn;
+// a listing it backed is left without stock
+#[ic_cdk::update]
+fn redeem_warehouse_receipt(receipt_id: u64) -> Result<WarehouseReceipt, String> {
+    let mut receipt = get_receipt_record(receipt_id)?;
+
+    if receipt.operator != caller_account() {
+        return Err("Only the issuing operator can redeem this receipt".to_string());
+    }
+    if receipt.status != ReceiptStatus::Active {
+        return Err("Receipt already redeemed".to_string());
+    }
+    if receipt.pledged_loan.is_some() {
+        return Err("Receipt is pledged to a loan".to_string());
+    }
+
+    if let Some(farmer_id) = receipt.backed_listing.take() {
+        set_stock(farmer_id, 0);
+        LISTING_RECEIPTS_STORAGE.with(|storage| storage.borrow_mut().remove(&farmer_id));
+    }
+    receipt.status = ReceiptStatus::Redeemed;
+    save_receipt(receipt.clone());
+    notify(
+        receipt.holder,
+        format!("Warehouse receipt #{} was redeemed", receipt_id),
+    );
+    Ok(receipt)
+}
+
+#[ic_cdk::query]
+fn get_warehouse_receipt(receipt_id: u64) -> Result<WarehouseReceipt, String> {
+    get_receipt_record(receipt_id)
+}
+
+#[ic_cdk::query]
+fn get_my_warehouse_receipts() -> Vec<WarehouseReceipt> {
+    let caller = caller_account();
+    RECEIPTS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, receipt)| receipt)
+            .filter(|receipt| receipt.holder == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,126 @@
+use crate::snapshots::{ensure_capacity, hash_memory};
+use crate::{is_admin, IdCell, Memory, MEMORY_MANAGER};
+use candid::{Encode, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+use std::cell::RefCell;
+
+const MAX_CHUNK_SIZE: usize = 1_048_576;
+// Largest module the management canister accepts in one install_code call
+const MAX_MODULE_SIZE: u64 = 2_000_000;
+
+// WasmModuleInfo Struct
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct WasmModuleInfo {
+    length: u64,
+    sha256: Vec<u8>,
+}
+
+thread_local! {
+    // Bytes of the uploaded module held in the data memory
+    static WASM_LENGTH: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(142))), 0)
+            .expect("Cannot create the wasm length cell")
+    );
+}
+
+fn data_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(143)))
+}
+
+fn wasm_length() -> u64 {
+    WASM_LENGTH.with(|cell| *cell.borrow().get())
+}
+
+fn set_wasm_length(length: u64) -> Result<(), String> {
+    WASM_LENGTH
+        .with(|cell| cell.borrow_mut().set(length))
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// The uploaded copy of this canister's own module, installed on the canisters it creates
+fn wasm_module() -> Result<Vec<u8>, String> {
+    let length = wasm_length();
+    if length == 0 {
+        return Err("No canister module has been uploaded".to_string());
+    }
+    let mut module = vec![0u8; length as usize];
+    data_memory().read(0, &mut module);
+    Ok(module)
+}
+
+// Creates an empty canister controlled by this one and the given principals
+pub(crate) async fn create_child_canister(
+    controllers: Vec<Principal>,
+    cycles: u128,
+) -> Result<Principal, String> {
+    let mut all_controllers = vec![ic_cdk::id()];
+    all_controllers.extend(controllers);
+    let argument = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(all_controllers),
+            ..Default::default()
+        }),
+    };
+    let (record,) = create_canister(argument, cycles)
+        .await
+        .map_err(|(code, message)| format!("Cannot create canister: {:?} {}", code, message))?;
+    Ok(record.canister_id)
+}
+
+// Installs the uploaded module on a canister this one controls
+pub(crate) async fn install_module(
+    canister_id: Principal,
+    mode: CanisterInstallMode,
+) -> Result<(), String> {
+    install_code(InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module: wasm_module()?,
+        arg: Encode!().unwrap(),
+    })
+    .await
+    .map_err(|(code, message)| format!("Cannot install canister: {:?} {}", code, message))
+}
+
+// Function for a controller to upload this canister's module, in chunks of up to 1 MiB.
+// Pass `reset` on the first chunk to discard a previous upload.
+#[ic_cdk::update]
+fn upload_wasm_chunk(chunk: Vec<u8>, reset: bool) -> Result<WasmModuleInfo, String> {
+    if !is_admin() {
+        return Err("Only a controller can upload the canister module".to_string());
+    }
+    if chunk.len() > MAX_CHUNK_SIZE {
+        return Err("Chunks are at most 1 MiB".to_string());
+    }
+
+    let offset = if reset { 0 } else { wasm_length() };
+    let length = offset + chunk.len() as u64;
+    if length > MAX_MODULE_SIZE {
+        return Err("Module is larger than install_code accepts".to_string());
+    }
+    let memory = data_memory();
+    ensure_capacity(&memory, length)?;
+    memory.write(offset, &chunk);
+    set_wasm_length(length)?;
+    Ok(wasm_info())
+}
+
+fn wasm_info() -> WasmModuleInfo {
+    let length = wasm_length();
+    WasmModuleInfo {
+        length,
+        sha256: hash_memory(&data_memory(), 0, length),
+    }
+}
+
+// Length and hash of the uploaded module, to check it against the build before use
+#[ic_cdk::query]
+fn get_wasm_info() -> WasmModuleInfo {
+    wasm_info()
+}